@@ -0,0 +1,116 @@
+//! Counts the heap allocations of compiling and rendering small templates.
+//!
+//! Run with `cargo bench --bench alloc_bench`, and again with
+//! `--features smallvec` to compare the token storage. Allocations are
+//! counted by a global allocator, which would skew the timings of
+//! `template_bench`, so they are measured here on their own.
+//!
+//! Each case has a ceiling, and the bench fails when a change makes it
+//! allocate more, so regressions show up before the timings do.
+
+use figura::{Context, Template, Value};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+type CBTemplate = Template<'{', '}'>;
+
+/// The system allocator, counting every allocation and reallocation.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Returns the allocations `f` makes, averaged over enough runs to hide any
+/// allocation made only once, such as lazily initialized statics.
+fn allocations<T>(mut f: impl FnMut() -> T) -> f64 {
+    const RUNS: usize = 1000;
+
+    black_box(f());
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..RUNS {
+        black_box(f());
+    }
+
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / RUNS as f64
+}
+
+fn main() {
+    let single_var = "Hello, {name}!";
+    let literal_only = "This is a plain string with no variables.";
+    let filtered = "{a} {b} {c:d} literal text {e} {{escaped}} {f:10}";
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("World"));
+
+    let cases: [(&str, f64, f64); 5] = [
+        (
+            "compile/single_var",
+            allocations(|| CBTemplate::compile(single_var).unwrap()),
+            11.0,
+        ),
+        (
+            "compile/literal_only",
+            allocations(|| CBTemplate::compile(literal_only).unwrap()),
+            3.0,
+        ),
+        (
+            "compile/filtered",
+            allocations(|| CBTemplate::compile(filtered).unwrap()),
+            50.0,
+        ),
+        (
+            "format/single_var",
+            {
+                let template = CBTemplate::compile(single_var).unwrap();
+                allocations(|| template.format(&ctx).unwrap())
+            },
+            3.0,
+        ),
+        (
+            "format/literal_only",
+            {
+                let template = CBTemplate::compile(literal_only).unwrap();
+                allocations(|| template.format(&ctx).unwrap())
+            },
+            1.0,
+        ),
+    ];
+
+    let features = if cfg!(feature = "smallvec") {
+        "smallvec"
+    } else {
+        "default"
+    };
+    println!("allocations per call ({features} features)");
+    let mut regressed = Vec::new();
+    for (name, count, ceiling) in cases {
+        println!("  {name:<24} {count:>6.1}  (at most {ceiling})");
+        if count > ceiling {
+            regressed.push(name);
+        }
+    }
+
+    assert!(
+        regressed.is_empty(),
+        "allocations went over their ceiling in {regressed:?}"
+    );
+}
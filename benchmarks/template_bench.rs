@@ -1,5 +1,7 @@
-use criterion::{Criterion, criterion_group, criterion_main};
-use figura::{Context, Template, Value};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use figura::{Context, FormatOptions, Template, Value};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
 use std::hint::black_box;
 
 type CBTemplate = Template<'{', '}'>;
@@ -108,6 +110,24 @@ fn complex_pattern_benchmarks(c: &mut Criterion) {
         b.iter(|| black_box(template.format(&ctx).unwrap()));
     });
 
+    // Benchmark: Directives without context, before and after folding
+    let source = "{'=':20} {name} {1 < 2 ? 'on' : 'off'} {max(3, 7)} {'-':20}";
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Alice"));
+
+    group.bench_function("constant_directives", |b| {
+        let template = CBTemplate::compile(source).unwrap();
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
+    group.bench_function("constant_directives_optimized", |b| {
+        let mut template = CBTemplate::compile(source).unwrap();
+        template.optimize();
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
     group.finish();
 }
 
@@ -133,6 +153,45 @@ fn very_long_template_benchmarks(c: &mut Criterion) {
         b.iter(|| black_box(template.format(&ctx).unwrap()));
     });
 
+    // Benchmark: The same variables substituted many times
+    group.bench_function("repeated_variables", |b| {
+        let template_str = (0..100)
+            .map(|i| format!("Item {}: {{val{}}} of {{total}}", i, i % 5))
+            .collect::<Vec<_>>()
+            .join(" | ");
+
+        let template = CBTemplate::compile(&template_str).unwrap();
+        let mut ctx = Context::new();
+        for i in 0..5 {
+            ctx.insert(
+                Box::leak(format!("val{}", i).into_boxed_str()),
+                Value::Float(i as f64 * 1.5),
+            );
+        }
+        ctx.insert("total", Value::Int(123_456));
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
+    // Benchmark: 100 variables, each substituted three times
+    group.bench_function("large_100_vars", |b| {
+        let template_str = (0..300)
+            .map(|i| format!("{{var{}}}", i % 100))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let template = CBTemplate::compile(&template_str).unwrap();
+        let mut ctx = Context::new();
+        for i in 0..100 {
+            ctx.insert(
+                Box::leak(format!("var{}", i).into_boxed_str()),
+                Value::Int(i as i64),
+            );
+        }
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
     // Benchmark: Very long literal string
     group.bench_function("long_literal", |b| {
         let long_text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(100);
@@ -184,6 +243,12 @@ fn compilation_benchmarks(c: &mut Criterion) {
         b.iter(|| black_box(CBTemplate::compile(&template_str).unwrap()));
     });
 
+    // Benchmark: Tokenizing without compiling
+    group.bench_function("tokenize_complex", |b| {
+        let template_str = "{a} {b} {c:d} literal text {e} {{escaped}} {f:10}";
+        b.iter(|| black_box(CBTemplate::tokenize(template_str)));
+    });
+
     group.finish();
 }
 
@@ -424,6 +489,23 @@ fn conditional_benchmarks(c: &mut Criterion) {
         b.iter(|| black_box(template.format(&ctx).unwrap()));
     });
 
+    // Benchmark: The same variables in conditions, branches and text
+    group.bench_function("conditional_template", |b| {
+        let template = CBTemplate::compile(
+            "Dear {name}, {vip ? 'as a VIP, {name}' : 'dear {name}'}, your {plan} plan \
+             {plan == 'pro' ? 'includes' : 'lacks'} support. {name}, {plan}: {count} \
+             {count > 1 ? 'seats' : 'seat'}. Thanks, {name}!",
+        )
+        .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::static_str("Alice"));
+        ctx.insert("vip", Value::Bool(true));
+        ctx.insert("plan", Value::static_str("pro"));
+        ctx.insert("count", Value::Int(3));
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
     group.finish();
 }
 
@@ -468,6 +550,149 @@ fn conditional_compilation_benchmarks(c: &mut Criterion) {
     group.finish();
 }
 
+/// FNV-1a, a minimal non-cryptographic hasher for the custom hasher benchmark.
+#[derive(Default)]
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut hash = if self.0 == 0 { 0xcbf29ce484222325 } else { self.0 };
+
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        self.0 = hash;
+    }
+}
+
+fn context_hasher_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("context_hashers");
+    let template = CBTemplate::compile(
+        "Hello, {name}! You are {age} years old and live in {city}.",
+    )
+    .unwrap();
+
+    // Benchmark: Multiple variables with the default SipHash context
+    group.bench_function("multiple_variables_siphash", |b| {
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::static_str("Alice"));
+        ctx.insert("age", Value::Int(30));
+        ctx.insert("city", Value::static_str("New York"));
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
+    // Benchmark: Multiple variables with an FNV-hashed context
+    group.bench_function("multiple_variables_fnv", |b| {
+        let mut ctx: HashMap<&str, Value, BuildHasherDefault<FnvHasher>> = HashMap::default();
+        ctx.insert("name", Value::static_str("Alice"));
+        ctx.insert("age", Value::Int(30));
+        ctx.insert("city", Value::static_str("New York"));
+
+        b.iter(|| black_box(template.format(&ctx).unwrap()));
+    });
+
+    group.finish();
+}
+
+fn batch_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    let template = CBTemplate::compile(
+        "Dear {name}, your order #{order} of {count} items ships to {city}. {vip ? 'Thanks for being a VIP!' : ''}",
+    )
+    .unwrap();
+
+    let contexts: Vec<Context> = (0..1000)
+        .map(|i| {
+            let mut ctx = Context::new();
+            ctx.insert("name", Value::owned_str(format!("Customer {}", i)));
+            ctx.insert("order", Value::Int(100_000 + i));
+            ctx.insert("count", Value::Int(i % 7 + 1));
+            ctx.insert("city", Value::static_str("Springfield"));
+            ctx.insert("vip", Value::Bool(i % 3 == 0));
+            ctx
+        })
+        .collect();
+
+    // Benchmark: Calling format once per context
+    group.bench_function("naive_loop_1000", |b| {
+        b.iter(|| {
+            let outputs: Vec<_> = contexts.iter().map(|ctx| template.format(ctx)).collect();
+            black_box(outputs)
+        });
+    });
+
+    // Benchmark: format_all with shared capacity hints
+    group.bench_function("format_all_1000", |b| {
+        b.iter(|| black_box(template.format_all(&contexts)));
+    });
+
+    // Benchmark: Calling format once per context, dropping each output
+    group.bench_function("format_each_1000", |b| {
+        b.iter(|| {
+            for ctx in &contexts {
+                black_box(template.format(ctx).unwrap());
+            }
+        });
+    });
+
+    // Benchmark: A Renderer reusing one output buffer for every context
+    group.bench_function("renderer_1000", |b| {
+        let mut renderer = template.renderer(FormatOptions::default());
+
+        b.iter(|| {
+            for ctx in &contexts {
+                black_box(renderer.render(ctx).unwrap());
+            }
+        });
+    });
+
+    // Benchmark: format_all_par across the rayon thread pool
+    #[cfg(feature = "rayon")]
+    group.bench_function("format_all_par_1000", |b| {
+        b.iter(|| black_box(template.format_all_par(&contexts)));
+    });
+
+    group.finish();
+}
+
+fn nesting_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nesting");
+
+    // Compile time should grow linearly with depth for each shape
+    for depth in [8, 16, 32, 64] {
+        let ladder: String = (0..depth)
+            .map(|i| format!("v == {} ? 'r{}' : ", i, i))
+            .collect();
+        let ladder = format!("{{{}'rest'}}", ladder);
+        let braced = format!("{}x{}", "{a ? {".repeat(depth / 2), "}}".repeat(depth / 2));
+        let parens = format!("{{{}a{}}}", "(".repeat(depth), ")".repeat(depth));
+
+        // Benchmark: An else-if ladder with `depth` conditions
+        group.bench_with_input(BenchmarkId::new("ladder", depth), &ladder, |b, input| {
+            b.iter(|| black_box(CBTemplate::compile(input).unwrap()));
+        });
+
+        // Benchmark: Conditionals nested in braced sub-templates
+        group.bench_with_input(BenchmarkId::new("braced", depth), &braced, |b, input| {
+            b.iter(|| black_box(CBTemplate::compile(input).unwrap()));
+        });
+
+        // Benchmark: A variable inside `depth` parentheses
+        group.bench_with_input(BenchmarkId::new("parens", depth), &parens, |b, input| {
+            b.iter(|| black_box(CBTemplate::compile(input)));
+        });
+    }
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     simple_string_benchmarks,
@@ -476,6 +701,9 @@ criterion_group!(
     compilation_benchmarks,
     realistic_use_cases,
     conditional_benchmarks,
-    conditional_compilation_benchmarks
+    conditional_compilation_benchmarks,
+    context_hasher_benchmarks,
+    batch_benchmarks,
+    nesting_benchmarks
 );
 criterion_main!(benches);
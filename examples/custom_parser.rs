@@ -1,5 +1,6 @@
 use figura::{
-    Argument, Context, Directive, EmptyDirective, Parser, ReplaceDirective, Template, Token, Value,
+    Argument, Context, Directive, EmptyDirective, Parser, RenderContext, ReplaceDirective,
+    Template, Token, Value,
 };
 use std::borrow::Cow;
 
@@ -65,7 +66,7 @@ struct AddDirective {
 }
 
 impl Directive for AddDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, figura::DirectiveError> {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, figura::DirectiveError> {
         let left_val = ctx
             .get(self.left.as_ref())
             .and_then(|v| match v {
@@ -94,7 +95,7 @@ struct SubtractDirective {
 }
 
 impl Directive for SubtractDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, figura::DirectiveError> {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, figura::DirectiveError> {
         let left_val = ctx
             .get(self.left.as_ref())
             .and_then(|v| match v {
@@ -123,7 +124,7 @@ struct MultiplyDirective {
 }
 
 impl Directive for MultiplyDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, figura::DirectiveError> {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, figura::DirectiveError> {
         let left_val = ctx
             .get(self.left.as_ref())
             .and_then(|v| match v {
@@ -152,7 +153,7 @@ struct DivideDirective {
 }
 
 impl Directive for DivideDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, figura::DirectiveError> {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, figura::DirectiveError> {
         let left_val = ctx
             .get(self.left.as_ref())
             .and_then(|v| match v {
@@ -181,7 +182,7 @@ struct MultiplyByLiteralDirective {
 }
 
 impl Directive for MultiplyByLiteralDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, figura::DirectiveError> {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, figura::DirectiveError> {
         let val = ctx
             .get(self.var.as_ref())
             .and_then(|v| match v {
@@ -201,7 +202,7 @@ struct AddLiteralDirective {
 }
 
 impl Directive for AddLiteralDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, figura::DirectiveError> {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, figura::DirectiveError> {
         let val = ctx
             .get(self.var.as_ref())
             .and_then(|v| match v {
@@ -222,28 +223,37 @@ fn main() {
     ctx.insert("a", Value::Int(100));
     ctx.insert("b", Value::Int(25));
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("x = {x}, y = {y}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("x = {x}, y = {y}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("x + y = {x + y}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("x + y = {x + y}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("x - y = {x - y}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("x - y = {x - y}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("x * y = {x * y}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("x * y = {x * y}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("a / b = {a / b}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("a / b = {a / b}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("x * 3 = {x * 3}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("x * 3 = {x * 3}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>("y + 10 = {y + 10}").unwrap();
+    let template =
+        Template::<'{', '}'>::compile_with_parser::<MathParser>("y + 10 = {y + 10}").unwrap();
     println!("{}", template.format(&ctx).unwrap());
 
-    let template =
-        Template::<'{', '}'>::compile_with_parser::<MathParser>("Result: {x + y} + {a - b} = {x * 2}").unwrap();
+    let template = Template::<'{', '}'>::compile_with_parser::<MathParser>(
+        "Result: {x + y} + {a - b} = {x * 2}",
+    )
+    .unwrap();
     println!("{}", template.format(&ctx).unwrap());
 }
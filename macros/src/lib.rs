@@ -0,0 +1,206 @@
+//! Compile-time checked formatting macros for
+//! [figura](https://docs.rs/figura) templates.
+//!
+//! `f!` works like `format!` with figura's syntax: the template is compiled
+//! while the crate using it is, with figura's own parser, and every variable
+//! it references must be passed as a named argument.
+//!
+//! ```rust
+//! use figura_macros::f;
+//!
+//! let name = "Ada";
+//! let greeting = f!("Hello, {name}! {n > 1 ? '{n} messages' : 'One message'}", name = name, n = 3);
+//! assert_eq!(greeting.unwrap(), "Hello, Ada! 3 messages");
+//! ```
+//!
+//! A missing argument is a compile error:
+//!
+//! ```compile_fail
+//! use figura_macros::f;
+//!
+//! let greeting = f!("Hello, {name}!");
+//! ```
+
+#![warn(clippy::use_self)]
+
+use figura::{Template, TemplateMetrics};
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    Expr, Ident, LitStr, Token,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+};
+
+/// The arguments of `f!`: a template literal and named values.
+struct FormatInput {
+    template: LitStr,
+    args: Punctuated<NamedArg, Token![,]>,
+}
+
+/// A named argument, `name = value`.
+struct NamedArg {
+    name: Ident,
+    value: Expr,
+}
+
+impl Parse for FormatInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let template = input.parse()?;
+        let args = match input.parse::<Option<Token![,]>>()? {
+            Some(_) => Punctuated::parse_terminated(input)?,
+            None => Punctuated::new(),
+        };
+
+        Ok(Self { template, args })
+    }
+}
+
+impl Parse for NamedArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = input.parse()?;
+
+        Ok(Self { name, value })
+    }
+}
+
+/// Returns why a template can't be used by `f!`, if it can't: it uses
+/// directives that depend on more than the values passed, such as includes,
+/// or that a missing value would be needed for, such as fallbacks.
+fn unsupported(metrics: &TemplateMetrics) -> Option<&'static str> {
+    let kinds = [
+        (metrics.repeats, "repeats"),
+        (metrics.includes, "includes"),
+        (metrics.fallbacks, "fallbacks"),
+        (metrics.error_messages, "custom error messages"),
+        (metrics.unknown, "unrecognized directives"),
+        (metrics.other, "directives the macro can't verify"),
+    ];
+
+    kinds
+        .into_iter()
+        .find_map(|(count, kind)| (count > 0).then_some(kind))
+}
+
+/// Formats a figura template checked at compile time, like `format!`.
+///
+/// The first argument is a template literal with `{` and `}` as
+/// delimiters, followed by `name = value` pairs. Each value is converted
+/// with `figura::Value::from`. The template may hold substitutions, filters,
+/// conditionals and switches; compiling fails if it doesn't parse, uses
+/// other directives, references a variable that isn't passed or is passed a
+/// value it never uses.
+///
+/// Expands to a `Result<String, figura::DirectiveError>`, since values can
+/// still be of the wrong type, e.g. text where a comparison needs a number.
+/// The template is compiled once, on first use, and the values are looked
+/// up in an array rather than a map.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura_macros::f;
+///
+/// let line = f!("{item:<8}{[qty](1:one)(*:{qty})}", item = "apple", qty = 2);
+/// assert_eq!(line.unwrap(), "apple   2");
+/// ```
+///
+/// An argument the template doesn't use is an error too:
+///
+/// ```compile_fail
+/// use figura_macros::f;
+///
+/// let line = f!("{item}", item = "apple", qty = 2);
+/// ```
+///
+/// So is a directive outside the supported subset:
+///
+/// ```compile_fail
+/// use figura_macros::f;
+///
+/// let page = f!("{>header} {title}", title = "Home");
+/// ```
+///
+/// Columns and regions are outside it as well, and fail with a message
+/// naming them as directives the macro can't verify:
+///
+/// ```compile_fail
+/// use figura_macros::f;
+///
+/// // error: f! supports substitutions, filters, conditionals and switches,
+/// // not directives the macro can't verify
+/// let line = f!("{name}{@col(10)}", name = "Ada");
+/// ```
+#[proc_macro]
+pub fn f(input: TokenStream) -> TokenStream {
+    let FormatInput { template, args } = parse_macro_input!(input as FormatInput);
+    let source = template.value();
+
+    let compiled = match Template::<'{', '}'>::compile(&source) {
+        Ok(compiled) => compiled,
+        Err(error) => {
+            return syn::Error::new(template.span(), format!("invalid template: {}", error))
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    if let Some(kind) = unsupported(&compiled.metrics()) {
+        let message = format!(
+            "f! supports substitutions, filters, conditionals and switches, not {}",
+            kind
+        );
+        return syn::Error::new(template.span(), message)
+            .to_compile_error()
+            .into();
+    }
+
+    let mut errors = Vec::new();
+    let variables = compiled.variables();
+
+    for variable in &variables {
+        if !args.iter().any(|arg| arg.name == variable) {
+            errors.push(syn::Error::new(
+                template.span(),
+                format!("missing argument `{}`, used by the template", variable),
+            ));
+        }
+    }
+
+    for (i, arg) in args.iter().enumerate() {
+        if !variables.iter().any(|variable| arg.name == variable) {
+            errors.push(syn::Error::new(
+                arg.name.span(),
+                format!("argument `{}` is never used by the template", arg.name),
+            ));
+        } else if args.iter().take(i).any(|earlier| earlier.name == arg.name) {
+            errors.push(syn::Error::new(
+                arg.name.span(),
+                format!("duplicate argument `{}`", arg.name),
+            ));
+        }
+    }
+
+    if let Some(error) = errors.into_iter().reduce(|mut all, error| {
+        all.combine(error);
+        all
+    }) {
+        return error.to_compile_error().into();
+    }
+
+    let names = args.iter().map(|arg| arg.name.to_string());
+    let values = args.iter().map(|arg| &arg.value);
+
+    quote! {{
+        static TEMPLATE: ::std::sync::LazyLock<::figura::Template<'{', '}'>> =
+            ::std::sync::LazyLock::new(|| {
+                ::figura::Template::compile(#template).expect("checked by f!")
+            });
+
+        TEMPLATE.format(&[#((#names, ::figura::Value::from(#values))),*])
+    }}
+    .into()
+}
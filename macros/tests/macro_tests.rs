@@ -0,0 +1,56 @@
+use figura::DirectiveError;
+use figura_macros::f;
+
+#[test]
+fn test_f_replace() {
+    let name = "World";
+    assert_eq!(f!("Hello, {name}!", name = name).unwrap(), "Hello, World!");
+}
+
+#[test]
+fn test_f_no_arguments() {
+    assert_eq!(
+        f!("No {{directives}} here").unwrap(),
+        "No {directives} here"
+    );
+}
+
+#[test]
+fn test_f_expressions_as_values() {
+    let items = ["a", "b", "c"];
+    let result = f!("{count} items, {ok}", count = items.len() as u32, ok = true,);
+    assert_eq!(result.unwrap(), "3 items, true");
+}
+
+#[test]
+fn test_f_filter() {
+    assert_eq!(
+        f!("[{price:round(2) | >6}]", price = 4.256).unwrap(),
+        "[  4.26]"
+    );
+}
+
+#[test]
+fn test_f_conditional() {
+    for (n, expected) in [(1, "1 file"), (4, "4 files")] {
+        let result = f!("{n} {n == 1 ? 'file' : 'files'}", n = n);
+        assert_eq!(result.unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_f_switch() {
+    let status = |code: i32| f!("{[code](200:ok)(404:missing)(*:error {code})}", code = code);
+    assert_eq!(status(200).unwrap(), "ok");
+    assert_eq!(status(404).unwrap(), "missing");
+    assert_eq!(status(500).unwrap(), "error 500");
+}
+
+#[test]
+fn test_f_type_error_at_runtime() {
+    let result = f!("{n:len}", n = 1000);
+    assert!(matches!(
+        result,
+        Err(DirectiveError::FilterTypeError { filter: "len", .. })
+    ));
+}
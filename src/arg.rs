@@ -1,5 +1,14 @@
-use crate::{Context, Value, err::DirectiveError, traits::ToAstring};
+use crate::{
+    Directive, Fragment, MissingPolicy, NumberFormat, RenderContext, SwitchDirective, Value,
+    compare_numbers,
+    context::{Matching, is_scoped},
+    err::DirectiveError,
+    traits::ToAstring,
+};
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
 
 /// An argument that can be resolved to a value at runtime.
 ///
@@ -7,6 +16,7 @@ use std::borrow::Cow;
 /// - **Variables**: Values looked up from the context by name (e.g., `{name}`)
 /// - **Literals**: Constant values embedded in the template (e.g., `{"hello"}`)
 /// - **Expressions**: Complex expressions that evaluate to values (e.g., comparisons)
+/// - **Templates**: Nested sub-templates rendered against the same context
 ///
 /// # Examples
 ///
@@ -20,7 +30,8 @@ use std::borrow::Cow;
 /// // Create a literal value
 /// let lit = Argument::literal(Cow::Borrowed("42"));
 /// ```
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Argument {
     /// A variable name to be looked up in the context.
     ///
@@ -39,6 +50,14 @@ pub enum Argument {
     /// Expressions include comparisons and logical operations that
     /// compute a result based on other arguments.
     Expression(Box<Expression>),
+
+    /// A nested sub-template.
+    ///
+    /// Produced when literal text that may hold directives, such as a
+    /// conditional branch `'Welcome {name}!'`, contains the opening
+    /// delimiter. When resolved, the sub-template is rendered against the
+    /// same context and its output is used like a literal.
+    Template(Arc<Fragment>),
 }
 
 /// Comparison operators for use in conditional expressions.
@@ -46,12 +65,25 @@ pub enum Argument {
 /// These operators compare two values and produce a boolean result.
 /// Numeric comparisons are performed when both operands can be parsed as numbers;
 /// otherwise, string comparison is used.
-#[derive(Debug, Clone)]
+///
+/// The membership operators (`*=`, `^=`, `$=`) always work on the string form
+/// of both operands, so numbers and booleans are stringified first. They are
+/// case-sensitive and match exact chars, e.g. `"Admin" *= "admin"` is false.
+///
+/// Case-insensitive equality (`~=`) also compares string forms, so on numbers
+/// and booleans it behaves like `==` on their text (`1 ~= 1.0` is false).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ComparisonOp {
     /// Equality: `==`
     Equals,
     /// Inequality: `!=`
     NotEquals,
+    /// Case-insensitive string equality: `~=`
+    ///
+    /// ASCII strings are compared with `eq_ignore_ascii_case`; anything else
+    /// is compared after Unicode lowercasing with `str::to_lowercase`.
+    EqualsIgnoreCase,
     /// Greater than: `>`
     GreaterThan,
     /// Less than: `<`
@@ -60,6 +92,12 @@ pub enum ComparisonOp {
     GreaterThanEquals,
     /// Less than or equal: `<=`
     LessThanEquals,
+    /// The left operand contains the right one: `*=`
+    Contains,
+    /// The left operand starts with the right one: `^=`
+    StartsWith,
+    /// The left operand ends with the right one: `$=`
+    EndsWith,
 }
 
 /// An expression that can be evaluated to produce a value.
@@ -73,8 +111,16 @@ pub enum ComparisonOp {
 /// ```text
 /// {x == 5 ? "yes" : "no"}      // Comparison expression
 /// {!active ? "inactive" : "active"}  // NOT expression
+/// {min(score, 100)}            // Function call
+/// {age in 13..19 ? "teen"}     // Range test
+/// {first + " " + last}         // Concatenation
+/// {defined(coupon) ? "saved"}  // Presence test
+/// {is_number(x) ? "n" : "s"}   // Type test
+/// {(a || b) && !c ? "yes"}     // Logical operators
+/// {[tier](gold:2)(*:0) >= 1 ? "member"}  // Switch as a value
 /// ```
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expression {
     /// A binary comparison between two arguments.
     ///
@@ -90,9 +136,440 @@ pub enum Expression {
     },
     /// Logical NOT operation.
     ///
-    /// Negates the boolean value of the argument. The argument must
-    /// resolve to a boolean or truthy/falsy value.
+    /// Negates the truthiness of the argument, see `Value::is_truthy`.
     Not(Argument),
+    /// A regular expression match: `left =~ 'pattern'`.
+    ///
+    /// Evaluates to whether the pattern matches anywhere in the string form
+    /// of `left`; use `^` and `$` to anchor it. The pattern is compiled once,
+    /// when the template is. Only available with the `regex` feature.
+    #[cfg(feature = "regex")]
+    Matches {
+        /// The argument whose string form is matched
+        left: Argument,
+        /// The compiled pattern
+        #[cfg_attr(feature = "serde", serde(with = "crate::serial::regex_serde"))]
+        pattern: regex::Regex,
+    },
+    /// A call to a built-in function: `max(a, b)`.
+    ///
+    /// Evaluates to the function applied to the values of the arguments.
+    Call {
+        /// The function called
+        function: Function,
+        /// The arguments, in order
+        args: Vec<Argument>,
+    },
+    /// An addition or concatenation: `left + right`.
+    ///
+    /// Two numbers are added: integers give an integer, failing with
+    /// `DirectiveError::ArithmeticOverflow` on overflow, and anything involving a float gives a float. When either
+    /// side is a string, both sides are converted to strings and joined, so
+    /// `"n" + 1` is `"n1"`. Any other combination, such as a boolean plus a
+    /// number, is a `TypeError`. Numeric literals count as numbers, even
+    /// quoted ones.
+    Add {
+        /// Left-hand side of the sum
+        left: Argument,
+        /// Right-hand side of the sum
+        right: Argument,
+    },
+    /// A range test: `value in low..high`.
+    ///
+    /// Evaluates to whether `value` lies between `low` and `high`, both
+    /// bounds included. All three must be numbers, integers and floats
+    /// alike; any other type is a `TypeError`.
+    InRange {
+        /// The argument tested
+        value: Argument,
+        /// The lower bound, included
+        low: Argument,
+        /// The upper bound, included
+        high: Argument,
+    },
+    /// A list membership test: `value in [a, b, c]`.
+    ///
+    /// Evaluates to whether `value` equals any of `items`, each compared as
+    /// `==` compares them, so a variable item holding another type than a
+    /// variable `value` is a `TypeError`. Items are tested in order,
+    /// stopping at the first match, and an empty list holds nothing.
+    InList {
+        /// The argument tested
+        value: Argument,
+        /// The items `value` is looked for among
+        items: Vec<Argument>,
+    },
+    /// A presence test: `defined(name)`.
+    ///
+    /// Evaluates to whether the context holds the variable `name` with a
+    /// value other than `Value::Null`, whatever that value is, so a
+    /// variable holding `false` or `0` is defined. Never fails, even with
+    /// `MissingPolicy::Error`.
+    Defined(Cow<'static, str>),
+    /// A type test: `is_number(value)`.
+    ///
+    /// Evaluates to whether the value of `value` has the type `test` checks
+    /// for. A missing variable counts as `Value::Null`, so `is_null` holds
+    /// for it and the other tests don't, and it is never an error, like in
+    /// `defined`.
+    IsType {
+        /// The type checked for
+        test: TypeTest,
+        /// The argument whose value is tested
+        value: Argument,
+    },
+    /// A coalescing chain: `nick ?? name ?? 'anonymous'`.
+    ///
+    /// Evaluates to the value of the first argument that is neither
+    /// `Value::Null` nor a missing variable, trying them from left to right,
+    /// or to `Value::Null` if there is none. Missing variables are never an
+    /// error inside a chain.
+    Coalesce(Vec<Argument>),
+    /// A conjunction: `a && b && ..`.
+    ///
+    /// Evaluates to whether every argument is truthy, see
+    /// `Value::is_truthy`, trying them from left to right and stopping at
+    /// the first falsy one, whose later arguments are never resolved.
+    And(Vec<Argument>),
+    /// A disjunction: `a || b || ..`.
+    ///
+    /// Evaluates to whether any argument is truthy, trying them from left to
+    /// right and stopping at the first truthy one.
+    Or(Vec<Argument>),
+    /// A switch used as a value: `[tier](gold:2)(*:0) + 1`.
+    ///
+    /// Evaluates to the text of the selected case, rendered like
+    /// `SwitchDirective` renders it and then typed like a literal with
+    /// `Value::from_str`, so a case `(gold:2)` gives `Value::Int(2)`. When
+    /// no case matches, it evaluates to `Value::Null`.
+    Switch(SwitchDirective),
+    /// An item of an array: `items[0]`.
+    ///
+    /// Evaluates to the item of the array `value` at the integer `index`,
+    /// where negative indices count from the end, so `items[-1]` is the
+    /// last item. An index past either end fails with
+    /// `DirectiveError::IndexOutOfRange`, to which `MissingPolicy` applies
+    /// as to a missing variable.
+    Index {
+        /// The array indexed
+        value: Argument,
+        /// The index of the item
+        index: Argument,
+    },
+}
+
+/// Built-in tests of the type of a value, called like functions in
+/// expressions: `is_number(x)`.
+///
+/// Each test matches one or more `Value` variants. `Value` has no maps, so
+/// there is no test for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TypeTest {
+    /// A string: `is_string(x)`
+    String,
+    /// An integer or a float: `is_number(x)`
+    Number,
+    /// An integer: `is_int(x)`
+    Int,
+    /// A float: `is_float(x)`
+    Float,
+    /// A boolean: `is_bool(x)`
+    Bool,
+    /// Binary data: `is_bytes(x)`
+    Bytes,
+    /// A list of values: `is_array(x)`
+    Array,
+    /// `Value::Null` or a missing variable: `is_null(x)`
+    Null,
+}
+
+impl TypeTest {
+    /// Returns the test named `name` in templates, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "is_string" => Some(Self::String),
+            "is_number" => Some(Self::Number),
+            "is_int" => Some(Self::Int),
+            "is_float" => Some(Self::Float),
+            "is_bool" => Some(Self::Bool),
+            "is_bytes" => Some(Self::Bytes),
+            "is_array" => Some(Self::Array),
+            "is_null" => Some(Self::Null),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the test in templates, e.g. `is_number`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::String => "is_string",
+            Self::Number => "is_number",
+            Self::Int => "is_int",
+            Self::Float => "is_float",
+            Self::Bool => "is_bool",
+            Self::Bytes => "is_bytes",
+            Self::Array => "is_array",
+            Self::Null => "is_null",
+        }
+    }
+
+    /// Returns whether `value` has the type this test checks for.
+    pub fn holds(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Self::String, Value::Str(_))
+                | (Self::Number, Value::Int(_) | Value::Float(_))
+                | (Self::Int, Value::Int(_))
+                | (Self::Float, Value::Float(_))
+                | (Self::Bool, Value::Bool(_))
+                | (Self::Bytes, Value::Bytes(_))
+                | (Self::Array, Value::Array(_))
+                | (Self::Null, Value::Null)
+        )
+    }
+}
+
+/// Built-in functions that can be called in expressions.
+///
+/// Functions other than `len` take numbers, `Value::Int` or `Value::Float`,
+/// and return a number. The result is an integer when every argument is one,
+/// and a float otherwise. Numeric literals such as the `100` in
+/// `min(score, 100)` count as numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Function {
+    /// The smallest of one or more numbers: `min(a, b)`
+    Min,
+    /// The largest of one or more numbers: `max(a, b)`
+    Max,
+    /// The absolute value of a number: `abs(x)`. The absolute value of
+    /// `i64::MIN` doesn't fit an `i64` and fails with
+    /// `DirectiveError::ArithmeticOverflow`.
+    Abs,
+    /// The length of a string, bytes or an array, as the `len` filter counts
+    /// it: `len(items)`. Lets conditions compare it, as in
+    /// `{len(items) > 0 ? 'some' : 'none'}`.
+    Len,
+}
+
+impl Function {
+    /// Returns the function named `name` in templates, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Self::Min),
+            "max" => Some(Self::Max),
+            "abs" => Some(Self::Abs),
+            "len" => Some(Self::Len),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the function in templates, e.g. `max`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Abs => "abs",
+            Self::Len => "len",
+        }
+    }
+
+    /// Returns whether the function can be called with `count` arguments.
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Self::Min | Self::Max => count >= 1,
+            Self::Abs | Self::Len => count == 1,
+        }
+    }
+
+    /// Applies the function to the values of its arguments.
+    fn call(&self, args: &[Value]) -> Result<Value<'static>, DirectiveError> {
+        if !self.accepts(args.len()) {
+            return Err(DirectiveError::FunctionArgumentError {
+                function: self.name(),
+                expected: match self {
+                    Self::Min | Self::Max => "at least 1 argument",
+                    Self::Abs | Self::Len => "1 argument",
+                },
+                found: format!("{} arguments", args.len()),
+            });
+        }
+
+        if let Self::Len = self {
+            return args[0]
+                .length()
+                .map(|len| Value::Int(i64::try_from(len).unwrap_or(i64::MAX)))
+                .ok_or_else(|| DirectiveError::FunctionArgumentError {
+                    function: self.name(),
+                    expected: "a string, bytes or an array",
+                    found: format!("a value of type '{}'", args[0].type_name()),
+                });
+        }
+
+        if let Some(arg) = args
+            .iter()
+            .find(|arg| !matches!(arg, Value::Int(_) | Value::Float(_)))
+        {
+            return Err(DirectiveError::FunctionArgumentError {
+                function: self.name(),
+                expected: "a number",
+                found: format!("a value of type '{}'", arg.type_name()),
+            });
+        }
+
+        let ints: Option<Vec<i64>> = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Int(i) => Some(*i),
+                _ => None,
+            })
+            .collect();
+
+        let floats = args.iter().map(|arg| match arg {
+            Value::Int(i) => *i as f64,
+            Value::Float(f) => *f,
+            _ => unreachable!(),
+        });
+
+        Ok(match (self, ints) {
+            (Self::Min, Some(ints)) => Value::Int(ints.into_iter().min().unwrap_or_default()),
+            (Self::Max, Some(ints)) => Value::Int(ints.into_iter().max().unwrap_or_default()),
+            (Self::Abs, Some(ints)) => Value::Int(ints[0].checked_abs().ok_or_else(|| {
+                DirectiveError::ArithmeticOverflow {
+                    expression: format!("abs({})", ints[0]),
+                }
+            })?),
+            (Self::Min, None) => Value::Float(floats.fold(f64::INFINITY, f64::min)),
+            (Self::Max, None) => Value::Float(floats.fold(f64::NEG_INFINITY, f64::max)),
+            (Self::Abs, None) => Value::Float(floats.sum::<f64>().abs()),
+            (Self::Len, _) => unreachable!(),
+        })
+    }
+}
+
+/// Compares expressions structurally; regex matches compare their patterns.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Comparison { left, op, right },
+                Self::Comparison {
+                    left: other_left,
+                    op: other_op,
+                    right: other_right,
+                },
+            ) => left == other_left && op == other_op && right == other_right,
+            (Self::Not(arg), Self::Not(other_arg)) => arg == other_arg,
+            #[cfg(feature = "regex")]
+            (
+                Self::Matches { left, pattern },
+                Self::Matches {
+                    left: other_left,
+                    pattern: other_pattern,
+                },
+            ) => left == other_left && pattern.as_str() == other_pattern.as_str(),
+            (
+                Self::Call { function, args },
+                Self::Call {
+                    function: other_function,
+                    args: other_args,
+                },
+            ) => function == other_function && args == other_args,
+            (
+                Self::Add { left, right },
+                Self::Add {
+                    left: other_left,
+                    right: other_right,
+                },
+            ) => left == other_left && right == other_right,
+            (
+                Self::InRange { value, low, high },
+                Self::InRange {
+                    value: other_value,
+                    low: other_low,
+                    high: other_high,
+                },
+            ) => value == other_value && low == other_low && high == other_high,
+            (
+                Self::InList { value, items },
+                Self::InList {
+                    value: other_value,
+                    items: other_items,
+                },
+            ) => value == other_value && items == other_items,
+            (Self::Defined(name), Self::Defined(other_name)) => name == other_name,
+            (
+                Self::IsType { test, value },
+                Self::IsType {
+                    test: other_test,
+                    value: other_value,
+                },
+            ) => test == other_test && value == other_value,
+            (Self::Coalesce(args), Self::Coalesce(other_args))
+            | (Self::And(args), Self::And(other_args))
+            | (Self::Or(args), Self::Or(other_args)) => args == other_args,
+            (Self::Switch(switch), Self::Switch(other_switch)) => switch == other_switch,
+            (
+                Self::Index { value, index },
+                Self::Index {
+                    value: other_value,
+                    index: other_index,
+                },
+            ) => value == other_value && index == other_index,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Expression {}
+
+impl std::hash::Hash for Expression {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+
+        match self {
+            Self::Comparison { left, op, right } => {
+                left.hash(state);
+                op.hash(state);
+                right.hash(state);
+            }
+            Self::Not(arg) => arg.hash(state),
+            #[cfg(feature = "regex")]
+            Self::Matches { left, pattern } => {
+                left.hash(state);
+                pattern.as_str().hash(state);
+            }
+            Self::Call { function, args } => {
+                function.hash(state);
+                args.hash(state);
+            }
+            Self::Add { left, right } => {
+                left.hash(state);
+                right.hash(state);
+            }
+            Self::InRange { value, low, high } => {
+                value.hash(state);
+                low.hash(state);
+                high.hash(state);
+            }
+            Self::InList { value, items } => {
+                value.hash(state);
+                items.hash(state);
+            }
+            Self::Defined(name) => name.hash(state),
+            Self::IsType { test, value } => {
+                test.hash(state);
+                value.hash(state);
+            }
+            Self::Coalesce(args) | Self::And(args) | Self::Or(args) => args.hash(state),
+            Self::Switch(switch) => switch.hash(state),
+            Self::Index { value, index } => {
+                value.hash(state);
+                index.hash(state);
+            }
+        }
+    }
 }
 
 impl Argument {
@@ -147,6 +624,356 @@ impl Argument {
     pub fn not(arg: Self) -> Self {
         Self::Expression(Box::new(Expression::Not(arg)))
     }
+
+    /// Creates a regular expression match argument.
+    ///
+    /// # Arguments
+    ///
+    /// * `left` - The argument whose string form is matched
+    /// * `pattern` - The compiled pattern
+    #[cfg(feature = "regex")]
+    pub fn matches(left: Self, pattern: regex::Regex) -> Self {
+        Self::Expression(Box::new(Expression::Matches { left, pattern }))
+    }
+
+    /// Creates an addition or concatenation, `left + right`.
+    ///
+    /// # Arguments
+    ///
+    /// * `left` - Left-hand side argument
+    /// * `right` - Right-hand side argument
+    pub fn add(left: Self, right: Self) -> Self {
+        Self::Expression(Box::new(Expression::Add { left, right }))
+    }
+
+    /// Creates a range test, `value in low..high`, with both bounds included.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The argument tested
+    /// * `low` - The lower bound
+    /// * `high` - The upper bound
+    pub fn in_range(value: Self, low: Self, high: Self) -> Self {
+        Self::Expression(Box::new(Expression::InRange { value, low, high }))
+    }
+
+    /// Creates a list membership test, `value in [a, b, c]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The argument tested
+    /// * `items` - The items `value` is compared with
+    pub fn in_list(value: Self, items: Vec<Self>) -> Self {
+        Self::Expression(Box::new(Expression::InList { value, items }))
+    }
+
+    /// Creates a presence test, `defined(name)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The variable whose presence is tested
+    pub fn defined(name: Cow<'static, str>) -> Self {
+        Self::Expression(Box::new(Expression::Defined(name)))
+    }
+
+    /// Creates a type test, such as `is_number(value)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `test` - The type checked for
+    /// * `value` - The argument whose value is tested
+    pub fn is_type(test: TypeTest, value: Self) -> Self {
+        Self::Expression(Box::new(Expression::IsType { test, value }))
+    }
+
+    /// Creates a call to a built-in function.
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - The function to call
+    /// * `args` - The arguments, in order
+    pub fn call(function: Function, args: Vec<Self>) -> Self {
+        Self::Expression(Box::new(Expression::Call { function, args }))
+    }
+
+    /// Creates a coalescing chain, `first ?? second ?? ..`.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The arguments tried, in order
+    pub fn coalesce(args: Vec<Self>) -> Self {
+        Self::Expression(Box::new(Expression::Coalesce(args)))
+    }
+
+    /// Creates a conjunction, `first && second && ..`.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The arguments tested, in order
+    pub fn and(args: Vec<Self>) -> Self {
+        Self::Expression(Box::new(Expression::And(args)))
+    }
+
+    /// Creates a disjunction, `first || second || ..`.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The arguments tested, in order
+    pub fn or(args: Vec<Self>) -> Self {
+        Self::Expression(Box::new(Expression::Or(args)))
+    }
+
+    /// Creates an index into an array, `value[index]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The array indexed
+    /// * `index` - The index of the item
+    pub fn index(value: Self, index: Self) -> Self {
+        Self::Expression(Box::new(Expression::Index { value, index }))
+    }
+
+    /// Creates a switch used as a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `switch` - The switch whose selected case is the value
+    pub fn switch(switch: SwitchDirective) -> Self {
+        Self::Expression(Box::new(Expression::Switch(switch)))
+    }
+
+    /// Returns whether this argument resolves to the same value for every
+    /// context: literals, and expressions and sub-templates built only from
+    /// constant parts.
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Self::Variable(_) => false,
+            Self::Literal(_) => true,
+            Self::Expression(expr) => match expr.as_ref() {
+                Expression::Comparison { left, right, .. }
+                | Expression::Add { left, right }
+                | Expression::Index {
+                    value: left,
+                    index: right,
+                } => left.is_constant() && right.is_constant(),
+                Expression::Not(arg) | Expression::IsType { value: arg, .. } => arg.is_constant(),
+                #[cfg(feature = "regex")]
+                Expression::Matches { left, .. } => left.is_constant(),
+                Expression::Call { args, .. }
+                | Expression::Coalesce(args)
+                | Expression::And(args)
+                | Expression::Or(args) => args.iter().all(Self::is_constant),
+                Expression::InRange { value, low, high } => {
+                    value.is_constant() && low.is_constant() && high.is_constant()
+                }
+                Expression::InList { value, items } => {
+                    value.is_constant() && items.iter().all(Self::is_constant)
+                }
+                Expression::Defined(_) => false,
+                Expression::Switch(switch) => switch.is_constant(),
+            },
+            Self::Template(fragment) => fragment.directives().all(Directive::is_constant),
+        }
+    }
+
+    /// Returns the case bodies of the switches used as values in this
+    /// argument, for compiling those that hold directives as sub-templates.
+    pub(crate) fn case_bodies_mut(&mut self) -> Vec<&mut Self> {
+        let Self::Expression(expr) = self else {
+            return Vec::new();
+        };
+
+        match expr.as_mut() {
+            Expression::Comparison { left, right, .. }
+            | Expression::Add { left, right }
+            | Expression::Index {
+                value: left,
+                index: right,
+            } => {
+                let mut bodies = left.case_bodies_mut();
+                bodies.extend(right.case_bodies_mut());
+                bodies
+            }
+            Expression::Not(arg) | Expression::IsType { value: arg, .. } => arg.case_bodies_mut(),
+            #[cfg(feature = "regex")]
+            Expression::Matches { left, .. } => left.case_bodies_mut(),
+            Expression::Call { args, .. }
+            | Expression::Coalesce(args)
+            | Expression::And(args)
+            | Expression::Or(args) => args.iter_mut().flat_map(Self::case_bodies_mut).collect(),
+            Expression::InRange { value, low, high } => {
+                let mut bodies = value.case_bodies_mut();
+                bodies.extend(low.case_bodies_mut());
+                bodies.extend(high.case_bodies_mut());
+                bodies
+            }
+            Expression::InList { value, items } => {
+                let mut bodies = value.case_bodies_mut();
+                bodies.extend(items.iter_mut().flat_map(Self::case_bodies_mut));
+                bodies
+            }
+            Expression::Defined(_) => Vec::new(),
+            Expression::Switch(switch) => switch.nested_arguments_mut(),
+        }
+    }
+
+    /// Calls `visit` with this argument and every argument nested in it, in
+    /// source order, including the arguments of sub-templates' directives.
+    pub(crate) fn walk<'a>(&'a self, visit: &mut dyn FnMut(&'a Self)) {
+        visit(self);
+
+        match self {
+            Self::Variable(_) | Self::Literal(_) => {}
+            Self::Expression(expr) => match expr.as_ref() {
+                Expression::Comparison { left, right, .. }
+                | Expression::Add { left, right }
+                | Expression::Index {
+                    value: left,
+                    index: right,
+                } => {
+                    left.walk(visit);
+                    right.walk(visit);
+                }
+                Expression::Not(arg) | Expression::IsType { value: arg, .. } => arg.walk(visit),
+                #[cfg(feature = "regex")]
+                Expression::Matches { left, .. } => left.walk(visit),
+                Expression::Call { args, .. }
+                | Expression::Coalesce(args)
+                | Expression::And(args)
+                | Expression::Or(args) => {
+                    for arg in args {
+                        arg.walk(visit);
+                    }
+                }
+                Expression::InRange { value, low, high } => {
+                    value.walk(visit);
+                    low.walk(visit);
+                    high.walk(visit);
+                }
+                Expression::InList { value, items } => {
+                    value.walk(visit);
+                    for item in items {
+                        item.walk(visit);
+                    }
+                }
+                Expression::Defined(_) => {}
+                Expression::Switch(switch) => {
+                    for arg in switch.arguments() {
+                        arg.walk(visit);
+                    }
+                }
+            },
+            Self::Template(fragment) => {
+                for directive in fragment.directives() {
+                    for arg in directive.arguments() {
+                        arg.walk(visit);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Appends the names of all variables referenced by this argument to `out`,
+    /// including those nested inside expressions.
+    pub(crate) fn collect_variables<'a>(&'a self, out: &mut Vec<&'a str>) {
+        self.walk(&mut |arg| match arg {
+            // The value a switch matched and the item of a loop are not
+            // variables of the context
+            Self::Variable(name) if is_scoped(name) => {}
+            Self::Variable(name) => out.push(name),
+            Self::Expression(expr) => {
+                if let Expression::Defined(name) = expr.as_ref() {
+                    out.push(name);
+                }
+            }
+            _ => {}
+        });
+    }
+
+    /// Checks that this argument renders, like `render`, but checks
+    /// sub-templates directive by directive instead of rendering them. See
+    /// `Directive::validate`.
+    pub(crate) fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        match self {
+            Self::Template(fragment) => fragment.validate(ctx),
+            _ => self.render(ctx).map(drop),
+        }
+    }
+
+    /// Resolves this argument as a string and writes it to `out`.
+    ///
+    /// Sub-templates are rendered straight into `out` instead of being
+    /// collected into a string first.
+    pub(crate) fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        match self {
+            Self::Template(fragment) => fragment.render(ctx, &MissingPolicy::Error, out),
+            _ => {
+                out.write_str(&self.render(ctx)?)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolves this argument to the text it renders as.
+    ///
+    /// Numbers from the context or from expressions are written with
+    /// `RenderContext::number_format`, while literals are rendered as
+    /// written.
+    pub(crate) fn render(
+        &self,
+        ctx: &RenderContext<'_>,
+    ) -> Result<Cow<'static, str>, DirectiveError> {
+        // Context values are borrowed rather than copied before rendering
+        let value = match self {
+            Self::Variable(name) => ctx.resolve(name).ok_or_else(|| DirectiveError::NotFound {
+                name: name.to_string(),
+                type_name: Value::TYPE_NAME,
+            })?,
+            Self::Expression(expr) => Cow::Owned(expr.evaluate(ctx)?),
+            Self::Literal(_) | Self::Template(_) => return self.resolve_as(ctx),
+        };
+
+        ctx.number_format()
+            .render(&value)
+            .map(|text| Cow::Owned(text.into_owned()))
+            .ok_or_else(|| DirectiveError::TypeError {
+                name: match self {
+                    Self::Variable(name) => name.to_string(),
+                    _ => "expression".to_string(),
+                },
+                expected: <Cow<'static, str>>::TYPE_NAME,
+                found: value.type_name().to_string(),
+            })
+    }
+
+    /// Resolves this argument as a condition, like `resolve_as::<bool>`,
+    /// except that strings from the context or from expressions that the
+    /// context has a keyword for, see `FormatOptions::bool_keywords`, are read
+    /// as the boolean it stands for.
+    pub(crate) fn resolve_condition(
+        &self,
+        ctx: &RenderContext<'_>,
+    ) -> Result<bool, DirectiveError> {
+        let value = match self {
+            Self::Variable(name) => ctx.resolve(name).ok_or_else(|| DirectiveError::NotFound {
+                name: name.to_string(),
+                type_name: bool::TYPE_NAME,
+            })?,
+            Self::Expression(expr) => Cow::Owned(expr.evaluate(ctx)?),
+            // Literals are typed as written, so they stay constant
+            Self::Literal(_) | Self::Template(_) => return self.resolve_as(ctx),
+        };
+
+        Ok(match &*value {
+            Value::Str(text) => ctx.bool_keyword(text),
+            _ => None,
+        }
+        .unwrap_or_else(|| value.is_truthy()))
+    }
 }
 
 /// Types that can be resolved from template arguments.
@@ -165,6 +992,7 @@ impl Argument {
 /// # Implementations
 ///
 /// Built-in implementations exist for:
+/// - `Value` (the raw value, without conversion)
 /// - `Cow<'static, str>` (strings)
 /// - `i64` (integers)
 /// - `f64` (floats)
@@ -203,6 +1031,7 @@ impl Argument {
     /// - Looking up variables in the context
     /// - Parsing literal values
     /// - Evaluating expressions
+    /// - Rendering sub-templates, whose output is then parsed like a literal
     ///
     /// # Type Parameters
     ///
@@ -210,7 +1039,7 @@ impl Argument {
     ///
     /// # Arguments
     ///
-    /// * `ctx` - The template context containing variable values
+    /// * `ctx` - The context of the render, as passed to `Directive::exec`
     ///
     /// # Returns
     ///
@@ -227,22 +1056,31 @@ impl Argument {
     /// # Examples
     ///
     /// ```rust
-    /// use figura::{Argument, Context, Value};
+    /// use figura::{Argument, Context, Directive, DirectiveError, RenderContext, Template};
+    /// use figura::{TemplateBuilder, Value};
     /// use std::borrow::Cow;
-    /// use std::collections::HashMap;
     ///
-    /// let mut ctx = HashMap::new();
-    /// ctx.insert("count", Value::Int(42));
+    /// struct Double(Argument);
+    ///
+    /// impl Directive for Double {
+    ///     fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+    ///         let value: i64 = self.0.resolve_as(ctx)?;
+    ///         Ok(Cow::Owned((value * 2).to_string()))
+    ///     }
+    /// }
     ///
-    /// let arg = Argument::variable(Cow::Borrowed("count"));
-    /// let value: i64 = arg.resolve_as(&ctx).unwrap();
-    /// assert_eq!(value, 42);
+    /// let tmpl: Template<'{', '}'> = TemplateBuilder::new()
+    ///     .directive(Double(Argument::variable(Cow::Borrowed("count"))))
+    ///     .build();
+    ///
+    /// let ctx = Context::from([("count", Value::Int(42))]);
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "84");
     /// ```
-    pub fn resolve_as<T: Resolvable>(&self, ctx: &Context) -> Result<T, DirectiveError> {
+    pub fn resolve_as<T: Resolvable>(&self, ctx: &RenderContext<'_>) -> Result<T, DirectiveError> {
         match self {
             Self::Variable(name) => {
-                if let Some(value) = ctx.get(name.as_ref()) {
-                    if let Some(parsed) = T::from_value(value) {
+                if let Some(value) = ctx.resolve(name.as_ref()) {
+                    if let Some(parsed) = T::from_value(&value) {
                         return Ok(parsed);
                     }
 
@@ -280,6 +1118,109 @@ impl Argument {
                     found: result.type_name().to_string(),
                 })
             }
+
+            Self::Template(fragment) => {
+                let capacity = fragment.estimated_len().min(ctx.max_output_len());
+                let mut output = String::with_capacity(capacity);
+                fragment.render(ctx, &MissingPolicy::Error, &mut output)?;
+
+                T::from_string_slice(&output).map_err(|err| DirectiveError::ParseError {
+                    value: output,
+                    type_name: T::TYPE_NAME,
+                    message: err,
+                })
+            }
+        }
+    }
+}
+
+impl Argument {
+    /// Returns this argument as a number, if it is a numeric literal, a
+    /// variable holding an integer or a float, or an expression that
+    /// evaluated to one, along with whether it is a `Value::Float`.
+    ///
+    /// `evaluated` is the value of an expression argument, see
+    /// `evaluate_expression`, so that it isn't evaluated again.
+    fn as_number(&self, evaluated: Option<&Value>, ctx: &RenderContext<'_>) -> Option<(f64, bool)> {
+        let number = |value: &Value| match *value {
+            Value::Float(f) => Some((f, true)),
+            Value::Int(i) => Some((i as f64, false)),
+            _ => None,
+        };
+
+        match (self, evaluated) {
+            (_, Some(value)) => number(value),
+            (Self::Variable(name), None) => number(ctx.resolve(name)?.as_ref()),
+            (Self::Literal(text), None) => {
+                let Ok(value) = text.parse::<Value>();
+                number(&value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Evaluates this argument if it is an expression, such as a sum, a
+    /// function call or an index, or returns `None` for any other argument.
+    fn evaluate_expression<'c>(
+        &self,
+        ctx: &RenderContext<'c>,
+    ) -> Result<Option<Value<'c>>, DirectiveError> {
+        match self {
+            Self::Expression(expr) => expr.evaluate(ctx).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolves this argument as a string like `resolve_as`, using
+    /// `evaluated` as the value of an expression argument.
+    fn resolve_str(
+        &self,
+        evaluated: Option<&Value>,
+        ctx: &RenderContext<'_>,
+    ) -> Result<Cow<'static, str>, DirectiveError> {
+        let Some(value) = evaluated else {
+            return self.resolve_as(ctx);
+        };
+
+        <Cow<'static, str>>::from_value(value).ok_or_else(|| DirectiveError::TypeError {
+            name: "expression".to_string(),
+            expected: <Cow<'static, str>>::TYPE_NAME,
+            found: value.type_name().to_string(),
+        })
+    }
+}
+
+impl Argument {
+    /// Resolves this argument as an operand of a function or a sum. Literals
+    /// are typed with `Value::from_str`, so numeric literals count as numbers.
+    fn resolve_operand<'c>(&self, ctx: &RenderContext<'c>) -> Result<Value<'c>, DirectiveError> {
+        match self {
+            Self::Literal(text) => {
+                let Ok(value) = text.parse::<Value>();
+                Ok(value)
+            }
+            _ => self.resolve_value(ctx),
+        }
+    }
+
+    /// Resolves this argument to a value like `resolve_as::<Value>`, except
+    /// that a string from the context keeps borrowing it instead of being
+    /// copied.
+    pub(crate) fn resolve_value<'c>(
+        &self,
+        ctx: &RenderContext<'c>,
+    ) -> Result<Value<'c>, DirectiveError> {
+        match self {
+            Self::Variable(name) => {
+                ctx.resolve(name)
+                    .map(Cow::into_owned)
+                    .ok_or_else(|| DirectiveError::NotFound {
+                        name: name.to_string(),
+                        type_name: Value::TYPE_NAME,
+                    })
+            }
+            Self::Expression(expr) => expr.evaluate(ctx),
+            _ => self.resolve_as(ctx),
         }
     }
 }
@@ -289,6 +1230,11 @@ impl Expression {
     ///
     /// Comparison expressions attempt numeric comparison when both sides
     /// can be parsed as floats; otherwise, they fall back to string comparison.
+    /// When both sides are variables, their context values are compared by
+    /// type instead, so `a == b` holds for `a = 1` and `b = 1.0`. `==` and
+    /// `!=` compare a float with another number within
+    /// `FormatOptions::float_tolerance`, including floats computed by a sum,
+    /// a function or an index, as in `{a + b == c}`.
     ///
     /// # Arguments
     ///
@@ -301,77 +1247,467 @@ impl Expression {
     ///
     /// # Errors
     ///
-    /// Returns an error if any sub-argument fails to resolve.
-    pub fn evaluate(&self, ctx: &Context) -> Result<crate::Value, DirectiveError> {
+    /// Returns an error if any sub-argument fails to resolve, or if two
+    /// variables with incomparable types (e.g. a string and an integer) are
+    /// compared.
+    pub fn evaluate<'c>(
+        &self,
+        ctx: &RenderContext<'c>,
+    ) -> Result<crate::Value<'c>, DirectiveError> {
         match self {
             Self::Comparison { left, op, right } => {
-                // Try to resolve both sides as strings first, then try numeric comparison
-                let left_str = left.resolve_as::<Cow<'static, str>>(ctx)?;
-                let right_str = right.resolve_as::<Cow<'static, str>>(ctx)?;
-
-                let result = match op {
-                    ComparisonOp::Equals => left_str == right_str,
-                    ComparisonOp::NotEquals => left_str != right_str,
-                    ComparisonOp::GreaterThan => {
-                        // Try numeric comparison
-                        // Treating anything as a float
-                        if let (Ok(l), Ok(r)) = (left_str.parse::<f64>(), right_str.parse::<f64>())
-                        {
-                            l > r
-                        } else {
-                            left_str > right_str
-                        }
+                Ok(Value::Bool(compare_arguments(left, op, right, ctx)?))
+            }
+            Self::Not(arg) => {
+                let value = arg.resolve_condition(ctx)?;
+
+                Ok(Value::Bool(!value))
+            }
+            #[cfg(feature = "regex")]
+            Self::Matches { left, pattern } => {
+                let text = left.resolve_as::<Cow<'static, str>>(ctx)?;
+
+                Ok(Value::Bool(pattern.is_match(&text)))
+            }
+            Self::Call { function, args } => {
+                let values = args
+                    .iter()
+                    .map(|arg| arg.resolve_operand(ctx))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                function.call(&values)
+            }
+            Self::Add { left, right } => {
+                let left_value = left.resolve_operand(ctx)?;
+                let right_value = right.resolve_operand(ctx)?;
+
+                add_values(left, &left_value, right, &right_value, ctx.number_format())
+            }
+            Self::InRange { value, low, high } => {
+                let value = value.resolve_as::<Number>(ctx)?;
+                let low = low.resolve_as::<Number>(ctx)?;
+                let high = high.resolve_as::<Number>(ctx)?;
+
+                Ok(Value::Bool(low.le(&value) && value.le(&high)))
+            }
+            Self::InList { value, items } => {
+                for item in items {
+                    if compare_arguments(value, &ComparisonOp::Equals, item, ctx)? {
+                        return Ok(Value::Bool(true));
+                    }
+                }
+
+                Ok(Value::Bool(false))
+            }
+            Self::Defined(name) => Ok(Value::Bool(
+                ctx.resolve(name)
+                    .is_some_and(|value| !matches!(*value, Value::Null)),
+            )),
+            Self::IsType { test, value } => {
+                let value = match value {
+                    Argument::Variable(name) => ctx
+                        .resolve(name)
+                        .map_or(Value::Null, |value| value.into_owned()),
+                    _ => value.resolve_operand(ctx)?,
+                };
+
+                Ok(Value::Bool(test.holds(&value)))
+            }
+            Self::Coalesce(args) => {
+                for arg in args {
+                    let value = match arg {
+                        Argument::Variable(name) => match ctx.resolve(name) {
+                            Some(value) => value.into_owned(),
+                            None => continue,
+                        },
+                        _ => arg.resolve_operand(ctx)?,
+                    };
+
+                    if !matches!(value, Value::Null) {
+                        return Ok(value);
                     }
-                    ComparisonOp::LessThan => {
-                        if let (Ok(l), Ok(r)) = (left_str.parse::<f64>(), right_str.parse::<f64>())
-                        {
-                            l < r
-                        } else {
-                            left_str < right_str
-                        }
+                }
+
+                Ok(Value::Null)
+            }
+            Self::And(args) => {
+                for arg in args {
+                    if !arg.resolve_condition(ctx)? {
+                        return Ok(Value::Bool(false));
                     }
-                    ComparisonOp::GreaterThanEquals => {
-                        if let (Ok(l), Ok(r)) = (left_str.parse::<f64>(), right_str.parse::<f64>())
-                        {
-                            l >= r
-                        } else {
-                            left_str >= right_str
-                        }
+                }
+
+                Ok(Value::Bool(true))
+            }
+            Self::Or(args) => {
+                for arg in args {
+                    if arg.resolve_condition(ctx)? {
+                        return Ok(Value::Bool(true));
                     }
-                    ComparisonOp::LessThanEquals => {
-                        if let (Ok(l), Ok(r)) = (left_str.parse::<f64>(), right_str.parse::<f64>())
-                        {
-                            l <= r
-                        } else {
-                            left_str <= right_str
-                        }
+                }
+
+                Ok(Value::Bool(false))
+            }
+            Self::Switch(switch) => match switch.select(ctx)? {
+                Some((case, value)) => {
+                    let matching = Matching {
+                        ctx: ctx.variables(),
+                        value: &value,
+                    };
+                    let Ok(value) = case
+                        .body
+                        .render(&ctx.with_variables(&matching))?
+                        .parse::<Value>();
+                    Ok(value)
+                }
+                None => Ok(Value::Null),
+            },
+            Self::Index { value, index } => {
+                // Only the item is copied out of an array from the context
+                let array = match value {
+                    Argument::Variable(name) => {
+                        ctx.resolve(name).ok_or_else(|| DirectiveError::NotFound {
+                            name: name.to_string(),
+                            type_name: "array",
+                        })?
                     }
+                    _ => Cow::Owned(value.resolve_value(ctx)?),
                 };
+                let position = index.resolve_operand(ctx)?;
 
-                Ok(Value::Bool(result))
+                index_value(value, &array, index, &position)
             }
-            Self::Not(arg) => {
-                let value = arg.resolve_as::<bool>(ctx)?;
+        }
+    }
+}
 
-                Ok(Value::Bool(!value))
+impl ComparisonOp {
+    /// Returns the operator as written in a template, e.g. `>=`.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Equals => "==",
+            Self::NotEquals => "!=",
+            Self::EqualsIgnoreCase => "~=",
+            Self::GreaterThan => ">",
+            Self::LessThan => "<",
+            Self::GreaterThanEquals => ">=",
+            Self::LessThanEquals => "<=",
+            Self::Contains => "*=",
+            Self::StartsWith => "^=",
+            Self::EndsWith => "$=",
+        }
+    }
+
+    /// Returns the test performed by an operator that always compares string
+    /// forms, or `None` for the ordering-based operators.
+    fn string_test(&self) -> Option<fn(&str, &str) -> bool> {
+        match self {
+            Self::EqualsIgnoreCase => Some(eq_ignore_case),
+            Self::Contains => Some(|l, r| l.contains(r)),
+            Self::StartsWith => Some(|l, r| l.starts_with(r)),
+            Self::EndsWith => Some(|l, r| l.ends_with(r)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether two operands with the given ordering satisfy this operator.
+    ///
+    /// `None` means the operands are unordered (e.g. a NaN float), which only
+    /// satisfies `!=`.
+    pub(crate) fn holds(&self, ordering: Option<Ordering>) -> bool {
+        match self {
+            Self::Equals => ordering == Some(Ordering::Equal),
+            Self::NotEquals => ordering != Some(Ordering::Equal),
+            Self::GreaterThan => ordering == Some(Ordering::Greater),
+            Self::LessThan => ordering == Some(Ordering::Less),
+            Self::GreaterThanEquals => {
+                matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+            }
+            Self::LessThanEquals => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            // String operators don't depend on ordering, see `string_test`
+            Self::EqualsIgnoreCase | Self::Contains | Self::StartsWith | Self::EndsWith => false,
+        }
+    }
+}
+
+/// Compares two strings ignoring case, skipping allocation for ASCII input.
+pub(crate) fn eq_ignore_case(left: &str, right: &str) -> bool {
+    if left.is_ascii() && right.is_ascii() {
+        left.eq_ignore_ascii_case(right)
+    } else {
+        left.to_lowercase() == right.to_lowercase()
+    }
+}
+
+/// Orders two strings for a comparison.
+///
+/// Equality is always textual. Ordering operators treat both sides as floats
+/// when they both parse as one, and fall back to lexicographic order otherwise.
+fn compare_strings(op: &ComparisonOp, left: &str, right: &str) -> Option<Ordering> {
+    if !matches!(op, ComparisonOp::Equals | ComparisonOp::NotEquals)
+        && let (Ok(l), Ok(r)) = (left.parse::<f64>(), right.parse::<f64>())
+    {
+        return l.partial_cmp(&r);
+    }
+
+    Some(left.cmp(right))
+}
+
+/// Evaluates the comparison `left op right`, as `Expression::Comparison`
+/// and each item of `Expression::InList` do.
+fn compare_arguments(
+    left: &Argument,
+    op: &ComparisonOp,
+    right: &Argument,
+    ctx: &RenderContext<'_>,
+) -> Result<bool, DirectiveError> {
+    // Expressions, function calls and indices are evaluated once, however
+    // their values end up compared
+    let left_value = left.evaluate_expression(ctx)?;
+    let right_value = right.evaluate_expression(ctx)?;
+
+    if let Some(test) = op.string_test() {
+        let left_str = left.resolve_str(left_value.as_ref(), ctx)?;
+        let right_str = right.resolve_str(right_value.as_ref(), ctx)?;
+
+        return Ok(test(&left_str, &right_str));
+    }
+
+    // Floats compare for equality within the context's tolerance, whether
+    // they come from the context, a literal or a computation
+    if matches!(op, ComparisonOp::Equals | ComparisonOp::NotEquals)
+        && let (Some((l, l_float)), Some((r, r_float))) = (
+            left.as_number(left_value.as_ref(), ctx),
+            right.as_number(right_value.as_ref(), ctx),
+        )
+        && (l_float || r_float)
+    {
+        let equal = ctx.float_tolerance().equal(l, r);
+
+        return Ok(equal == matches!(op, ComparisonOp::Equals));
+    }
+
+    // Two variables carry their own types, so compare them as values
+    if let (Argument::Variable(_), Argument::Variable(name)) = (left, right) {
+        let left_value = left.resolve_value(ctx)?;
+        let right_value = right.resolve_value(ctx)?;
+        let ordering = compare_values(op, name, &left_value, &right_value)?;
+
+        return Ok(op.holds(ordering));
+    }
+
+    // Otherwise resolve both sides as strings, comparing numerically when possible
+    let left_str = left.resolve_str(left_value.as_ref(), ctx)?;
+    let right_str = right.resolve_str(right_value.as_ref(), ctx)?;
+
+    Ok(op.holds(compare_strings(op, &left_str, &right_str)))
+}
+
+/// Orders two values resolved from the context.
+///
+/// Integers and floats compare numerically with each other, exactly, and
+/// strings compare like `compare_strings`. Booleans, bytes, arrays and `Null`
+/// compare with their own type like `Value::total_cmp`. `Null` is unordered relative
+/// to anything else, and so is `NaN`.
+///
+/// # Errors
+///
+/// Returns `DirectiveError::TypeError` for any other combination, such as a
+/// string compared to an integer. `right_name` is the variable reported.
+fn compare_values(
+    op: &ComparisonOp,
+    right_name: &str,
+    left: &Value,
+    right: &Value,
+) -> Result<Option<Ordering>, DirectiveError> {
+    let ordering = match (left, right) {
+        (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+            compare_numbers(left, right)
+        }
+        (Value::Str(l), Value::Str(r)) => compare_strings(op, l, r),
+        (Value::Bool(_), Value::Bool(_))
+        | (Value::Bytes(_), Value::Bytes(_))
+        | (Value::Array(_), Value::Array(_))
+        | (Value::Null, Value::Null) => Some(left.total_cmp(right)),
+        (Value::Null, _) | (_, Value::Null) => None,
+        _ => {
+            return Err(DirectiveError::TypeError {
+                name: right_name.to_string(),
+                expected: left.type_name(),
+                found: right.type_name().to_string(),
+            });
+        }
+    };
+
+    Ok(ordering)
+}
+
+/// Adds two numbers or, when either is a string, joins their string forms.
+fn add_values(
+    left: &Argument,
+    left_value: &Value,
+    right: &Argument,
+    right_value: &Value,
+    format: NumberFormat,
+) -> Result<Value<'static>, DirectiveError> {
+    let sum = match (left_value, right_value) {
+        (Value::Int(l), Value::Int(r)) => {
+            Value::Int(
+                l.checked_add(*r)
+                    .ok_or_else(|| DirectiveError::ArithmeticOverflow {
+                        expression: format!("{} + {}", l, r),
+                    })?,
+            )
+        }
+        (Value::Int(l), Value::Float(r)) => Value::Float(*l as f64 + r),
+        (Value::Float(l), Value::Int(r)) => Value::Float(l + *r as f64),
+        (Value::Float(l), Value::Float(r)) => Value::Float(l + r),
+        (Value::Str(_), _) | (_, Value::Str(_)) => {
+            let mut joined = String::new();
+
+            for (arg, value) in [(left, left_value), (right, right_value)] {
+                let text = format
+                    .render(value)
+                    .ok_or_else(|| operand_type_error(arg, value))?;
+                joined.push_str(&text);
             }
+
+            Value::owned_str(joined)
+        }
+        (Value::Int(_) | Value::Float(_), _) => return Err(operand_type_error(right, right_value)),
+        _ => return Err(operand_type_error(left, left_value)),
+    };
+
+    Ok(sum)
+}
+
+/// Returns the item of `array` at `position`, counting negative positions
+/// from the end, for `Expression::Index`.
+fn index_value<'c>(
+    value: &Argument,
+    array: &Value<'c>,
+    index: &Argument,
+    position: &Value,
+) -> Result<Value<'c>, DirectiveError> {
+    let name = |arg: &Argument| match arg {
+        Argument::Variable(name) | Argument::Literal(name) => name.to_string(),
+        _ => "expression".to_string(),
+    };
+
+    let Value::Int(position) = *position else {
+        return Err(DirectiveError::TypeError {
+            name: name(index),
+            expected: "integer",
+            found: position.type_name().to_string(),
+        });
+    };
+
+    let Value::Array(items) = array else {
+        return Err(DirectiveError::TypeError {
+            name: name(value),
+            expected: "array",
+            found: array.type_name().to_string(),
+        });
+    };
+
+    let slot = match usize::try_from(position) {
+        Ok(slot) => Some(slot),
+        Err(_) => usize::try_from(position.unsigned_abs())
+            .ok()
+            .and_then(|back| items.len().checked_sub(back)),
+    };
+
+    slot.and_then(|slot| items.get(slot))
+        .cloned()
+        .ok_or_else(|| DirectiveError::IndexOutOfRange {
+            name: name(value),
+            index: position,
+            len: items.len(),
+        })
+}
+
+/// The error for an operand of `+` that is neither a number nor a string.
+fn operand_type_error(arg: &Argument, value: &Value) -> DirectiveError {
+    DirectiveError::TypeError {
+        name: match arg {
+            Argument::Variable(name) | Argument::Literal(name) => name.to_string(),
+            _ => "expression".to_string(),
+        },
+        expected: "number or string",
+        found: value.type_name().to_string(),
+    }
+}
+
+/// A number resolved for a range test, keeping integers exact.
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// Returns whether `self <= other`, comparing integers exactly and
+    /// anything involving a float as floats. NaN is never in a range.
+    fn le(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Int(l), Self::Int(r)) => l <= r,
+            (Self::Int(l), Self::Float(r)) => (*l as f64) <= *r,
+            (Self::Float(l), Self::Int(r)) => *l <= *r as f64,
+            (Self::Float(l), Self::Float(r)) => l <= r,
+        }
+    }
+}
+
+impl Resolvable for Number {
+    const TYPE_NAME: &'static str = "number";
+
+    /// Accepts integers and floats only: strings are not parsed, so a
+    /// string in a range test is a type error.
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Int(i) => Some(Self::Int(*i)),
+            Value::Float(f) => Some(Self::Float(*f)),
+            _ => None,
+        }
+    }
+
+    fn from_string_slice(s: &str) -> Result<Self, String> {
+        match s.parse::<Value>() {
+            Ok(Value::Int(i)) => Ok(Self::Int(i)),
+            Ok(Value::Float(f)) => Ok(Self::Float(f)),
+            _ => Err("not a number".to_string()),
         }
     }
 }
 
+impl Resolvable for Value<'_> {
+    const TYPE_NAME: &'static str = "value";
+
+    /// Returns a copy of the value as-is, owning its data.
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone().into_owned())
+    }
+
+    fn from_string_slice(s: &str) -> Result<Self, String> {
+        Ok(Self::owned_str(s.to_string()))
+    }
+}
+
 impl Resolvable for Cow<'static, str> {
     const TYPE_NAME: &'static str = "string";
 
     /// Converts any Value type to a string representation.
     ///
-    /// All value types can be converted to strings, making this
-    /// conversion infallible.
+    /// Every value type except `Bytes` and `Array` converts to a string;
+    /// bytes must be encoded with a filter first. `Null` converts to an empty
+    /// string.
     fn from_value(value: &Value) -> Option<Self> {
         match value {
-            Value::Str(v) => Some(v.clone()),
+            Value::Str(v) => Some(Cow::Owned(v.to_string())),
             Value::Int(v) => Some(Cow::Owned(v.to_astring())),
             Value::Float(v) => Some(Cow::Owned(v.to_astring())),
             Value::Bool(v) => Some(Cow::Owned(v.to_string())),
+            Value::Bytes(_) | Value::Array(_) => None,
+            Value::Null => Some(Cow::Borrowed("")),
         }
     }
 
@@ -383,19 +1719,9 @@ impl Resolvable for Cow<'static, str> {
 impl Resolvable for i64 {
     const TYPE_NAME: &'static str = "i64";
 
-    /// Converts a Value to a 64-bit integer.
-    ///
-    /// - Strings are parsed
-    /// - Floats are truncated
-    /// - Booleans become 0 or 1
+    /// Converts a Value to a 64-bit integer with `Value::to_int_lossy`.
     fn from_value(value: &Value) -> Option<Self> {
-        match value {
-            Value::Str(v) => v.parse().ok(),
-
-            Value::Int(v) => Some(*v),
-            Value::Float(v) => Some(*v as Self),
-            Value::Bool(v) => Some(*v as Self),
-        }
+        value.to_int_lossy()
     }
 
     fn from_string_slice(s: &str) -> Result<Self, String> {
@@ -406,19 +1732,9 @@ impl Resolvable for i64 {
 impl Resolvable for f64 {
     const TYPE_NAME: &'static str = "float";
 
-    /// Converts a Value to a 64-bit float.
-    ///
-    /// - Strings are parsed
-    /// - Integers are converted
-    /// - Booleans become 0.0 or 1.0
+    /// Converts a Value to a 64-bit float with `Value::to_float_lossy`.
     fn from_value(value: &Value) -> Option<Self> {
-        match value {
-            Value::Str(v) => v.parse().ok(),
-
-            Value::Int(v) => Some(*v as Self),
-            Value::Float(v) => Some(*v),
-            Value::Bool(v) => Some(if *v { 1.0 } else { 0.0 }),
-        }
+        value.to_float_lossy()
     }
 
     fn from_string_slice(s: &str) -> Result<Self, String> {
@@ -429,22 +1745,18 @@ impl Resolvable for f64 {
 impl Resolvable for bool {
     const TYPE_NAME: &'static str = "bool";
 
-    /// Converts a Value to a boolean.
+    /// Converts a Value to a boolean with `Value::is_truthy`.
     ///
-    /// - Strings are parsed ("true"/"false")
-    /// - Integers use zero/non-zero semantics
-    /// - Floats use zero/non-zero semantics
+    /// Every value converts, so conditions never fail with a type error.
     fn from_value(value: &Value) -> Option<Self> {
-        match value {
-            Value::Str(v) => v.parse().ok(),
-
-            Value::Int(v) => Some(*v != 0),
-            Value::Float(v) => Some(*v != 0.0),
-            Value::Bool(v) => Some(*v),
-        }
+        Some(value.is_truthy())
     }
 
+    /// Types the literal with `Value::from_str`, then applies
+    /// `Value::is_truthy`: `"0"` and `"false"` are false, `"no"` is true.
     fn from_string_slice(s: &str) -> Result<Self, String> {
-        s.parse::<Self>().map_err(|e| e.to_string())
+        let Ok(value) = s.parse::<Value>();
+
+        Ok(value.is_truthy())
     }
 }
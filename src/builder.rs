@@ -0,0 +1,168 @@
+//! Building templates programmatically, without parsing.
+//!
+//! `TemplateBuilder` assembles the same directives the parser produces, so a
+//! template built from code renders exactly like its source equivalent. Text
+//! is never parsed, which means literal delimiters need no escaping.
+
+use crate::fragment::{Fragment, Node, SegmentKind};
+use crate::{
+    Argument, ConditionalDirective, Directive, Filter, FilterDirective, IncludeDirective,
+    LiteralDirective, RepeatDirective, ReplaceDirective, SwitchCase, SwitchDirective, Template,
+};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Builds a template directive by directive, without a source string.
+///
+/// Each method appends one piece to the template, in order. Consecutive
+/// literals are merged into a single text segment, as the parser does.
+/// Nothing is parsed: `literal("{name}")` renders the braces as-is, and so do
+/// literal conditional branches.
+///
+/// Nested sub-templates, such as a conditional branch greeting a variable,
+/// are built with another builder converted into an `Argument`.
+///
+/// Built templates have no source, so their segments have empty spans and
+/// `MissingPolicy::Keep` renders missing variables as nothing.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Argument, ComparisonOp, Context, Template, TemplateBuilder, Value};
+/// use std::borrow::Cow;
+///
+/// let tmpl: Template<'{', '}'> = TemplateBuilder::new()
+///     .literal("Hello, ")
+///     .var("name")
+///     .conditional(
+///         Argument::comparison(
+///             Argument::variable(Cow::Borrowed("age")),
+///             ComparisonOp::GreaterThanEquals,
+///             Argument::literal(Cow::Borrowed("18")),
+///         ),
+///         TemplateBuilder::new().literal(" (adult, {verified})"),
+///         None,
+///     )
+///     .build();
+///
+/// let mut ctx = Context::new();
+/// ctx.insert("name", Value::static_str("Ada"));
+/// ctx.insert("age", Value::Int(36));
+///
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello, Ada (adult, {verified})");
+/// ```
+#[derive(Default)]
+pub struct TemplateBuilder {
+    nodes: Vec<Node>,
+    /// Literal text not yet pushed as a node, so that consecutive literals
+    /// form a single segment
+    text: String,
+}
+
+impl TemplateBuilder {
+    /// Creates an empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends literal text, rendered as-is.
+    pub fn literal(mut self, text: impl AsRef<str>) -> Self {
+        self.text.push_str(text.as_ref());
+        self
+    }
+
+    /// Appends a variable replacement, like `{name}`.
+    pub fn var(self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.replace(Argument::variable(name.into()))
+    }
+
+    /// Appends the value of an argument, like `{name}` or `{'text'}`.
+    pub fn replace(self, value: Argument) -> Self {
+        self.directive(ReplaceDirective(value))
+    }
+
+    /// Appends a repeated pattern, like `{pattern:count}`.
+    pub fn repeat(self, pattern: Argument, count: Argument) -> Self {
+        self.directive(RepeatDirective(pattern, count))
+    }
+
+    /// Appends a value passed through filters, like `{name | trunc(20) | >24}`.
+    pub fn filter(self, value: Argument, filters: Vec<Box<dyn Filter>>) -> Self {
+        self.directive(FilterDirective(value, filters))
+    }
+
+    /// Appends a conditional, like `{cond ? if_true : if_false}`.
+    ///
+    /// Branches are any `Argument`, including a nested `TemplateBuilder`.
+    pub fn conditional(
+        self,
+        cond: Argument,
+        if_true: impl Into<Argument>,
+        if_false: Option<Argument>,
+    ) -> Self {
+        self.directive(ConditionalDirective {
+            branches: vec![(cond, if_true.into())],
+            if_false,
+        })
+    }
+
+    /// Appends a switch, like `{[scrutinee](label:body)..}`.
+    pub fn switch(self, scrutinee: Argument, cases: Vec<SwitchCase>) -> Self {
+        self.directive(SwitchDirective {
+            scrutinee,
+            cases,
+            default: None,
+        })
+    }
+
+    /// Appends an include of a partial, like `{>name}`.
+    pub fn include(self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.directive(IncludeDirective(name.into()))
+    }
+
+    /// Appends any directive, including custom ones.
+    pub fn directive(mut self, directive: impl Directive + 'static) -> Self {
+        self.flush_text();
+        self.nodes
+            .push(Node::built(Box::new(directive), SegmentKind::Directive));
+        self
+    }
+
+    /// Finishes the template.
+    ///
+    /// The delimiters only matter for serialization and debugging, since
+    /// nothing is parsed; they are usually inferred from the annotated type.
+    pub fn build<const O: char, const C: char>(self) -> Template<O, C> {
+        Template::from_body(self.into_fragment(), None, None)
+    }
+
+    /// Pushes the pending literal text as a node.
+    fn flush_text(&mut self) {
+        if !self.text.is_empty() {
+            let text = std::mem::take(&mut self.text);
+            self.nodes.push(Node::built(
+                Box::new(LiteralDirective(Cow::Owned(text))),
+                SegmentKind::Text,
+            ));
+        }
+    }
+
+    fn into_fragment(mut self) -> Fragment {
+        self.flush_text();
+        Fragment::new(self.nodes)
+    }
+}
+
+/// Converts a builder into a sub-template argument, such as a conditional
+/// branch. A builder holding only literal text becomes an
+/// `Argument::Literal`, as the parser produces for branches without
+/// directives.
+impl From<TemplateBuilder> for Argument {
+    fn from(builder: TemplateBuilder) -> Self {
+        if builder.nodes.is_empty() {
+            return Self::literal(Cow::Owned(builder.text));
+        }
+
+        Self::Template(Arc::new(builder.into_fragment()))
+    }
+}
@@ -0,0 +1,171 @@
+//! A bounded cache of compiled templates, keyed by their source.
+//!
+//! Applications that render many templates written at runtime, such as
+//! user-defined notifications, see the same strings again and again. A
+//! `TemplateCache` compiles each of them once and hands out shared templates.
+
+use crate::{CompileOptions, Template, TemplateError};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A thread-safe cache mapping template sources to their compiled templates,
+/// which keeps at most `capacity` templates and evicts the least recently
+/// used one to make room for another.
+///
+/// Templates own their source, so cached templates never borrow the strings
+/// they were compiled from. Sources that fail to compile aren't cached.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, TemplateCache, Value};
+///
+/// let cache = TemplateCache::<'{', '}'>::new(100);
+/// let ctx = Context::from([("name", Value::static_str("Ann"))]);
+///
+/// for _ in 0..3 {
+///     let tmpl = cache.get_or_parse("Hi {name}!").unwrap();
+///     assert_eq!(tmpl.format(&ctx).unwrap(), "Hi Ann!");
+/// }
+///
+/// assert_eq!(cache.len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct TemplateCache<const O: char, const C: char> {
+    capacity: usize,
+    options: CompileOptions,
+    state: Mutex<CacheState<O, C>>,
+}
+
+/// The templates of a `TemplateCache`, each with the tick it was last used
+/// at.
+#[derive(Debug)]
+struct CacheState<const O: char, const C: char> {
+    entries: HashMap<Arc<str>, (Arc<Template<O, C>>, u64)>,
+    tick: u64,
+}
+
+impl<const O: char, const C: char> TemplateCache<O, C> {
+    /// Creates an empty cache holding at most `capacity` templates, compiled
+    /// with the default options. A capacity of `0` caches nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_options(capacity, CompileOptions::default())
+    }
+
+    /// Creates an empty cache holding at most `capacity` templates, compiled
+    /// with `options`.
+    pub fn with_options(capacity: usize, options: CompileOptions) -> Self {
+        Self {
+            capacity,
+            options,
+            state: Mutex::new(CacheState {
+                entries: HashMap::with_capacity(capacity.min(1024)),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Returns the template compiled from `src`, compiling and caching it if
+    /// it isn't cached yet.
+    ///
+    /// When the cache is full, the least recently used template is evicted
+    /// to make room. Templates handed out before stay valid after they are
+    /// evicted.
+    ///
+    /// # Errors
+    ///
+    /// Returns the `TemplateError` `src` fails to compile with. Failures
+    /// aren't cached, so the next call compiles `src` again.
+    pub fn get_or_parse(&self, src: &str) -> Result<Arc<Template<O, C>>, TemplateError> {
+        if let Some(template) = self.lock().touch(src) {
+            return Ok(template);
+        }
+
+        // Compiled without holding the lock, so that other threads can use
+        // the cache meanwhile
+        let template = Arc::new(Template::compile_with_options(src, &self.options)?);
+
+        if self.capacity > 0 {
+            let mut state = self.lock();
+
+            // Another thread may have cached it first
+            if let Some(cached) = state.touch(src) {
+                return Ok(cached);
+            }
+
+            if state.entries.len() >= self.capacity {
+                state.evict_least_recent();
+            }
+
+            let tick = state.next_tick();
+            state
+                .entries
+                .insert(Arc::from(src), (Arc::clone(&template), tick));
+        }
+
+        Ok(template)
+    }
+
+    /// Returns whether a template compiled from `src` is cached, without
+    /// marking it as used.
+    pub fn contains(&self, src: &str) -> bool {
+        self.lock().entries.contains_key(src)
+    }
+
+    /// Returns the number of cached templates.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Returns whether the cache holds no templates.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of templates the cache holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Removes every cached template.
+    pub fn clear(&self) {
+        self.lock().entries.clear();
+    }
+
+    /// Locks the cached templates. A thread panicking while holding the lock
+    /// can't leave them inconsistent, so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, CacheState<O, C>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<const O: char, const C: char> CacheState<O, C> {
+    /// Returns the next tick, which is later than every tick before it.
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Returns the template cached for `src`, marking it as the most
+    /// recently used.
+    fn touch(&mut self, src: &str) -> Option<Arc<Template<O, C>>> {
+        let tick = self.next_tick();
+        let (template, last_used) = self.entries.get_mut(src)?;
+        *last_used = tick;
+
+        Some(Arc::clone(template))
+    }
+
+    /// Removes the least recently used template.
+    fn evict_least_recent(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(src, _)| Arc::clone(src));
+
+        if let Some(src) = oldest {
+            self.entries.remove(&src);
+        }
+    }
+}
@@ -0,0 +1,1054 @@
+//! Variable storage that templates are rendered against.
+//!
+//! Templates never depend on a concrete map type: rendering goes through the
+//! `ContextLookup` trait, which is implemented for the `Context` and
+//! `OwnedContext` aliases and can be implemented for any other storage,
+//! including sources that compute values on demand.
+//!
+//! The `HashMap` implementations are generic over the hasher, so a faster
+//! non-cryptographic hasher, such as `rustc_hash::FxBuildHasher`, can be
+//! used for small, trusted key sets:
+//!
+//! ```rust
+//! use figura::{Template, Value};
+//! use std::collections::HashMap;
+//! use std::hash::{BuildHasherDefault, DefaultHasher};
+//!
+//! let mut ctx: HashMap<&str, Value, BuildHasherDefault<DefaultHasher>> = HashMap::default();
+//! ctx.insert("name", Value::static_str("World"));
+//!
+//! let tmpl = Template::<'{', '}'>::compile("Hello {name}!").unwrap();
+//! assert_eq!(tmpl.format(&ctx).unwrap(), "Hello World!");
+//! ```
+//!
+//! Output never depends on a map's iteration order: rendering only looks
+//! variables up by name, so the same template and values render the same
+//! text whatever the hasher. Methods that list keys, such as
+//! `Template::unused_keys`, sort them. Where a map itself must iterate
+//! reproducibly, e.g. when it is also serialized or logged, `SortedContext`
+//! keeps its keys in order.
+
+use std::borrow::{Borrow, Cow};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::OnceLock;
+
+use crate::{
+    BoolKeywords, DirectiveError, Filter, FilterMismatch, FloatTolerance, FormatOptions, Fragment,
+    NumberFormat, Schema, TemplateRegistry, UnknownPolicy, Value,
+};
+
+/// The context passed to templates during rendering.
+///
+/// Maps variable names to their runtime values. Variable names must be
+/// static strings for zero-copy efficiency.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, Value};
+/// use std::collections::HashMap;
+///
+/// let mut ctx = HashMap::new();
+/// ctx.insert("user", Value::static_str("Alice"));
+/// ctx.insert("age", Value::Int(30));
+/// ```
+pub type Context = HashMap<&'static str, Value<'static>>;
+
+/// A context whose names and values borrow data that outlives it.
+///
+/// Strings such as slices of a larger buffer are inserted without being
+/// copied, with `Value::borrowed_str` or `.into()`, and the context can't
+/// outlive the buffer. Rendering borrows the context for the duration of
+/// the call only.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{BorrowedContext, Template, Value};
+///
+/// let record = String::from("Ada Lovelace,1815");
+/// let (name, born) = record.split_once(',').unwrap();
+///
+/// let mut ctx = BorrowedContext::new();
+/// ctx.insert("name", Value::borrowed_str(name));
+/// ctx.insert("born", born.into());
+///
+/// let tmpl = Template::<'{', '}'>::compile("{name} ({born})").unwrap();
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "Ada Lovelace (1815)");
+/// ```
+pub type BorrowedContext<'a> = HashMap<&'a str, Value<'a>>;
+
+/// A context with owned keys.
+///
+/// Useful when variable names are computed at runtime (e.g. read from a
+/// config file or built in a loop) and cannot be `&'static str`.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{OwnedContext, Template, Value};
+///
+/// let mut ctx = OwnedContext::new();
+///
+/// for i in 0..3 {
+///     ctx.insert(format!("item{}", i), Value::Int(i));
+/// }
+///
+/// let tmpl = Template::<'{', '}'>::compile("{item0}, {item2}").unwrap();
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "0, 2");
+/// ```
+pub type OwnedContext = HashMap<String, Value<'static>>;
+
+/// A context that iterates its keys in sorted order.
+///
+/// Renders like `OwnedContext`, for contexts whose own iteration must be
+/// reproducible, e.g. when they are logged or compared in snapshot tests
+/// next to the output. Any `BTreeMap` of values keyed by `String` or `&str`
+/// works as a context too.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{SortedContext, Template, Value};
+///
+/// let mut ctx = SortedContext::new();
+/// ctx.insert("b".to_string(), Value::Int(2));
+/// ctx.insert("a".to_string(), Value::Int(1));
+///
+/// let tmpl = Template::<'{', '}'>::compile("{a}{b}").unwrap();
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "12");
+/// assert_eq!(ctx.keys().collect::<Vec<_>>(), ["a", "b"]);
+/// ```
+pub type SortedContext = BTreeMap<String, Value<'static>>;
+
+/// Builds a `Context` fluently, with a method per value type.
+///
+/// The typed methods spare wrapping every value in its `Value` variant, and
+/// state which variant is meant: `int` and `float` keep `30` and `30.0`
+/// apart. `set` takes anything convertible into a `Value`. Setting a name
+/// twice keeps the last value.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{ContextBuilder, Template};
+///
+/// let ctx = ContextBuilder::new()
+///     .str("name", "World")
+///     .int("age", 30)
+///     .float("balance", 1.5)
+///     .bool("admin", true)
+///     .set("nickname", None::<&str>)
+///     .build();
+///
+/// let tmpl = Template::<'{', '}'>::compile("{name}, {age}: {balance}").unwrap();
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "World, 30: 1.5");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ContextBuilder {
+    ctx: Context,
+}
+
+impl ContextBuilder {
+    /// Creates an empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a variable to a string, borrowed if it is `&'static str`.
+    pub fn str(self, key: &'static str, value: impl Into<Cow<'static, str>>) -> Self {
+        self.set(key, Value::Str(value.into()))
+    }
+
+    /// Sets a variable to `Value::Int`.
+    pub fn int(self, key: &'static str, value: i64) -> Self {
+        self.set(key, Value::Int(value))
+    }
+
+    /// Sets a variable to `Value::Float`, even for whole numbers.
+    pub fn float(self, key: &'static str, value: f64) -> Self {
+        self.set(key, Value::Float(value))
+    }
+
+    /// Sets a variable to `Value::Bool`.
+    pub fn bool(self, key: &'static str, value: bool) -> Self {
+        self.set(key, Value::Bool(value))
+    }
+
+    /// Sets a variable to any value convertible into a `Value`, such as an
+    /// `Option`, which is `Value::Null` when `None`.
+    pub fn set(mut self, key: &'static str, value: impl Into<Value<'static>>) -> Self {
+        self.ctx.insert(key, value.into());
+        self
+    }
+
+    /// Returns the built context.
+    pub fn build(self) -> Context {
+        self.ctx
+    }
+}
+
+/// A source of variable values for rendering.
+///
+/// Implement this trait to render templates against your own storage instead
+/// of copying it into a `HashMap` first. Storage that holds `Value`s
+/// implements `get`; a source that builds values on demand, such as a
+/// database row or a parsed JSON document, implements `resolve` instead.
+///
+/// # Laziness
+///
+/// Templates only look up the variables they render: `resolve` is called
+/// while formatting, never when compiling, and only for the directives that
+/// are evaluated, so the variables of a conditional branch that isn't taken
+/// or a switch case that isn't selected are never looked up. Nothing is
+/// cached for you: a variable may be looked up several times in one render,
+/// even by a single directive such as a comparison, so sources that are
+/// expensive to query should cache their values. Only a variable substituted
+/// as `{name}` several times in a template is looked up once per render.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{ContextLookup, Value};
+///
+/// struct Single(Value<'static>);
+///
+/// impl ContextLookup for Single {
+///     fn get(&self, key: &str) -> Option<&Value<'_>> {
+///         (key == "value").then_some(&self.0)
+///     }
+/// }
+/// ```
+///
+/// A source computing its values:
+///
+/// ```rust
+/// use figura::{ContextLookup, Template, Value};
+/// use std::borrow::Cow;
+///
+/// struct Squares;
+///
+/// impl ContextLookup for Squares {
+///     fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+///         let n: i64 = key.strip_prefix('n')?.parse().ok()?;
+///         Some(Cow::Owned(Value::Int(n * n)))
+///     }
+/// }
+///
+/// let tmpl = Template::<'{', '}'>::compile("{n3} {n12}").unwrap();
+/// assert_eq!(tmpl.format(&Squares).unwrap(), "9 144");
+/// ```
+pub trait ContextLookup {
+    /// Looks up the value of a variable stored in the context.
+    ///
+    /// Returns `None` by default, for contexts that implement `resolve`
+    /// instead. Templates look variables up through `resolve`, which falls
+    /// back to this method.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The variable name
+    ///
+    /// # Returns
+    ///
+    /// `Some(&Value)` if the variable is defined, `None` otherwise.
+    fn get(&self, _key: &str) -> Option<&Value<'_>> {
+        None
+    }
+
+    /// Looks up the value of a variable, borrowed from the context or
+    /// computed when asked for.
+    ///
+    /// The default implementation borrows the value returned by `get`.
+    /// Override it to produce values on demand; see the trait documentation
+    /// for when it is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The variable name
+    ///
+    /// # Returns
+    ///
+    /// `Some` value if the variable is defined, `None` otherwise.
+    fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+        self.get(key).map(Cow::Borrowed)
+    }
+}
+
+impl<S: BuildHasher> ContextLookup for HashMap<&str, Value<'_>, S> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        Self::get(self, key)
+    }
+}
+
+impl<S: BuildHasher> ContextLookup for HashMap<String, Value<'_>, S> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        Self::get(self, key)
+    }
+}
+
+impl<K: Borrow<str> + Ord> ContextLookup for BTreeMap<K, Value<'_>> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        Self::get(self, key)
+    }
+}
+
+/// A fixed list of named values, searched in order, as built by
+/// `figura_macros::f!`. Suits a handful of values known up front, without
+/// building a map.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Template, Value};
+///
+/// let tmpl = Template::<'{', '}'>::compile("{a}-{b}").unwrap();
+/// let ctx = [("a", Value::Int(1)), ("b", Value::static_str("x"))];
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "1-x");
+/// ```
+impl<const N: usize> ContextLookup for [(&str, Value<'_>); N] {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        self.iter()
+            .find_map(|(name, value)| (*name == key).then_some(value))
+    }
+}
+
+/// A stack of contexts searched from the top down.
+///
+/// Looking up a variable checks the most recently pushed layer first and falls
+/// back to the layers below it, so the first hit wins. This lets shared
+/// defaults live in one context while each render only supplies overrides.
+/// Lookups take O(layers) map lookups; nothing is copied.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, ContextStack, Template, Value};
+///
+/// let mut defaults = Context::new();
+/// defaults.insert("brand", Value::static_str("Figura"));
+/// defaults.insert("greeting", Value::static_str("Hello"));
+///
+/// let mut overrides = Context::new();
+/// overrides.insert("greeting", Value::static_str("Ciao"));
+///
+/// let ctx = ContextStack::new(&defaults).with(&overrides);
+///
+/// let tmpl = Template::<'{', '}'>::compile("{greeting} from {brand}").unwrap();
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "Ciao from Figura");
+/// ```
+pub struct ContextStack<'a> {
+    /// Layers from the bottom (checked last) to the top (checked first)
+    layers: Vec<&'a dyn ContextLookup>,
+}
+
+impl<'a> ContextStack<'a> {
+    /// Creates a stack with a single base layer.
+    pub fn new(base: &'a dyn ContextLookup) -> Self {
+        Self { layers: vec![base] }
+    }
+
+    /// Pushes a layer on top of the stack. Its values shadow those below it.
+    pub fn push(&mut self, layer: &'a dyn ContextLookup) {
+        self.layers.push(layer);
+    }
+
+    /// Returns the stack with `layer` pushed on top.
+    pub fn with(mut self, layer: &'a dyn ContextLookup) -> Self {
+        self.push(layer);
+        self
+    }
+
+    /// Removes and returns the top layer, if any.
+    pub fn pop(&mut self) -> Option<&'a dyn ContextLookup> {
+        self.layers.pop()
+    }
+
+    /// Returns the number of layers.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns `true` if the stack has no layers.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+}
+
+impl ContextLookup for ContextStack<'_> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        self.layers.iter().rev().find_map(|layer| layer.get(key))
+    }
+
+    fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+        self.layers
+            .iter()
+            .rev()
+            .find_map(|layer| layer.resolve(key))
+    }
+}
+
+/// A context holding the process environment variables, as strings.
+///
+/// The environment is read once, when the context is created, so later
+/// changes to it are not seen. Variables whose name or value is not valid
+/// UTF-8 are skipped.
+///
+/// Templates never read the environment on their own: it is only consulted
+/// when an `EnvContext` is passed in, usually as the lowest layer of a
+/// `ContextStack` so that it serves as a fallback.
+///
+/// # Security
+///
+/// The environment often holds secrets such as API tokens. A template that
+/// is rendered against an `EnvContext` can print any variable it names, so
+/// don't combine the two when templates come from untrusted sources, or
+/// use `EnvContext::with_prefix` to expose only the variables meant for
+/// templates.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, ContextStack, EnvContext, Template, Value};
+///
+/// let env = EnvContext::capture();
+///
+/// let mut ctx = Context::new();
+/// ctx.insert("USER", Value::static_str("ada"));
+///
+/// // The context shadows the environment; other names fall back to it
+/// let stack = ContextStack::new(&env).with(&ctx);
+///
+/// let tmpl = Template::<'{', '}'>::compile("{USER}").unwrap();
+/// assert_eq!(tmpl.format(&stack).unwrap(), "ada");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EnvContext {
+    vars: OwnedContext,
+}
+
+impl EnvContext {
+    /// Captures every environment variable of the current process.
+    pub fn capture() -> Self {
+        Self::with_prefix("")
+    }
+
+    /// Captures the environment variables whose name starts with `prefix`,
+    /// keyed by their name without it: with the prefix `APP_`, `APP_NAME` is
+    /// available as `{NAME}`.
+    pub fn with_prefix(prefix: &str) -> Self {
+        let vars = std::env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix(prefix)?;
+                Some((name.to_string(), Value::owned_str(value)))
+            })
+            .collect();
+
+        Self { vars }
+    }
+}
+
+impl ContextLookup for EnvContext {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        self.vars.get(key)
+    }
+}
+
+/// The function computing a value of a `LazyContext`.
+type Thunk = Box<dyn Fn() -> Value<'static> + Send + Sync>;
+
+/// A variable of a `LazyContext`: a value, or the function computing it and
+/// the value once computed.
+struct LazyValue {
+    value: OnceLock<Value<'static>>,
+    compute: Option<Thunk>,
+}
+
+/// A context whose values can be computed when a template first uses them.
+///
+/// Values added with `insert_lazy` are computed at most once, the first time
+/// a rendered directive looks them up, and cached in the context for every
+/// later lookup and render. A value that only appears in a branch that isn't
+/// taken is never computed. Values added with `insert` are stored as-is.
+///
+/// The functions are `Fn + Send + Sync` so that the context itself is `Send +
+/// Sync` and can be rendered from several threads, e.g. with
+/// `Template::format_all_par`; concurrent lookups of the same variable still
+/// compute it once. If a function panics, nothing is cached and the next
+/// lookup calls it again, which is why `FnOnce` is not accepted.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{LazyContext, Template, Value};
+///
+/// let mut ctx = LazyContext::new();
+/// ctx.insert("admin", Value::Bool(false));
+/// ctx.insert_lazy("summary", || Value::owned_str(expensive_report()));
+/// # fn expensive_report() -> String { String::new() }
+///
+/// let tmpl = Template::<'{', '}'>::compile("{admin ? {summary} : 'Welcome'}").unwrap();
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "Welcome");
+/// assert!(!ctx.is_computed("summary"));
+/// ```
+#[derive(Default)]
+pub struct LazyContext {
+    vars: HashMap<String, LazyValue>,
+}
+
+impl LazyContext {
+    /// Creates an empty context.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value, replacing any variable with the same name.
+    pub fn insert(&mut self, key: impl Into<String>, value: Value<'static>) {
+        self.vars.insert(
+            key.into(),
+            LazyValue {
+                value: OnceLock::from(value),
+                compute: None,
+            },
+        );
+    }
+
+    /// Adds a value computed by `compute` when it is first looked up,
+    /// replacing any variable with the same name.
+    pub fn insert_lazy(
+        &mut self,
+        key: impl Into<String>,
+        compute: impl Fn() -> Value<'static> + Send + Sync + 'static,
+    ) {
+        self.vars.insert(
+            key.into(),
+            LazyValue {
+                value: OnceLock::new(),
+                compute: Some(Box::new(compute)),
+            },
+        );
+    }
+
+    /// Returns whether the variable has a value, either because it was
+    /// inserted directly or because it has been computed. Returns `false`
+    /// for names that aren't in the context.
+    pub fn is_computed(&self, key: &str) -> bool {
+        self.vars
+            .get(key)
+            .is_some_and(|var| var.value.get().is_some())
+    }
+
+    /// Returns the number of variables.
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    /// Returns `true` if the context has no variables.
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+}
+
+impl fmt::Debug for LazyContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.vars.iter().map(|(key, var)| (key, var.value.get())))
+            .finish()
+    }
+}
+
+impl ContextLookup for LazyContext {
+    /// Returns the value of a variable, computing it first if needed.
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        let var = self.vars.get(key)?;
+
+        Some(var.value.get_or_init(|| match &var.compute {
+            Some(compute) => compute(),
+            None => Value::Null,
+        }))
+    }
+}
+
+/// Contexts searched in order, the first hit winning. Used by
+/// `Template::format_layered`.
+pub(crate) struct Layers<'a, 'b>(pub(crate) &'b [&'a dyn ContextLookup]);
+
+impl ContextLookup for Layers<'_, '_> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        self.0.iter().find_map(|layer| layer.get(key))
+    }
+
+    fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+        self.0.iter().find_map(|layer| layer.resolve(key))
+    }
+}
+
+/// Values named by their index, `"0"` being the first. Used by
+/// `Template::format_args`.
+pub(crate) struct Positional<'a>(pub(crate) &'a [Value<'a>]);
+
+impl ContextLookup for Positional<'_> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        // `parse` would also accept a leading `+`
+        if key.is_empty() || !key.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        self.0.get(key.parse::<usize>().ok()?)
+    }
+}
+
+/// The options and state of one render, shared by every directive it
+/// evaluates, nested and included ones alike: the settings taken from
+/// `FormatOptions`, the partials it can include, the column the output is at
+/// and how many more directives it may evaluate.
+pub(crate) struct RenderState<'a> {
+    pub(crate) float_tolerance: FloatTolerance,
+    pub(crate) number_format: NumberFormat,
+    pub(crate) trim_blocks: bool,
+    pub(crate) unknown_policy: UnknownPolicy,
+    pub(crate) filter_mismatch: FilterMismatch,
+    pub(crate) empty_placeholder: Option<&'a str>,
+    pub(crate) registry: Option<&'a TemplateRegistry>,
+    pub(crate) schema: Option<&'a Schema>,
+    pub(crate) bool_keywords: Option<&'a BoolKeywords>,
+    /// Updated by the writer the template renders into
+    pub(crate) column: Cell<usize>,
+    pub(crate) max_directives: usize,
+    /// How many more directives may be evaluated
+    pub(crate) directives_left: Cell<usize>,
+    pub(crate) max_output_len: usize,
+    /// Set by `Template::format_bytes` for substituted `Bytes` values to be
+    /// written as raw bytes rather than failing with a type error
+    pub(crate) raw_bytes: Option<&'a RawBytes>,
+}
+
+impl<'a> RenderState<'a> {
+    /// Creates the state of a render with `options`, including the partials
+    /// of `registry`.
+    pub(crate) fn new(options: &'a FormatOptions, registry: Option<&'a TemplateRegistry>) -> Self {
+        Self {
+            float_tolerance: options.float_tolerance,
+            number_format: options.number_format,
+            trim_blocks: options.trim_blocks,
+            unknown_policy: options.unknown,
+            filter_mismatch: options.filter_mismatch,
+            empty_placeholder: options.empty_placeholder.as_deref(),
+            registry,
+            schema: Some(&options.schema),
+            bool_keywords: Some(&options.bool_keywords),
+            column: Cell::new(0),
+            max_directives: options.max_directives,
+            directives_left: Cell::new(options.max_directives),
+            max_output_len: options.max_output_len,
+            raw_bytes: None,
+        }
+    }
+
+    /// Creates the state of a render with the default options and no limit
+    /// on the number of directives, as used by `Template::chunks`.
+    pub(crate) fn unlimited() -> Self {
+        Self {
+            float_tolerance: FloatTolerance::default(),
+            number_format: NumberFormat::default(),
+            trim_blocks: false,
+            unknown_policy: UnknownPolicy::default(),
+            filter_mismatch: FilterMismatch::default(),
+            empty_placeholder: None,
+            registry: None,
+            schema: None,
+            bool_keywords: None,
+            column: Cell::new(0),
+            max_directives: usize::MAX,
+            directives_left: Cell::new(usize::MAX),
+            max_output_len: FormatOptions::DEFAULT_MAX_OUTPUT_LEN,
+            raw_bytes: None,
+        }
+    }
+}
+
+/// The partials a render is nested in, innermost first, which tell cyclic
+/// includes apart.
+#[derive(Clone, Copy)]
+pub(crate) struct Including<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) outer: Option<&'a Self>,
+}
+
+/// What directives are evaluated against: the variables of the context
+/// being rendered, along with the options and state of the render.
+///
+/// Directives receive one in `Directive::exec` and pass it on to the
+/// arguments they resolve, e.g. with `Argument::resolve_as`. Variables are
+/// looked up with `get` and `resolve`, which ask the `ContextLookup` the
+/// template was rendered against, while the options the template was
+/// rendered with, such as `FormatOptions::number_format`, stay with the
+/// render, so wrapping a context never loses them.
+#[derive(Clone, Copy)]
+pub struct RenderContext<'a> {
+    vars: &'a dyn ContextLookup,
+    state: &'a RenderState<'a>,
+    including: Option<&'a Including<'a>>,
+}
+
+impl<'a> RenderContext<'a> {
+    /// Creates the context of a render of `vars` with `state`.
+    pub(crate) fn new(vars: &'a dyn ContextLookup, state: &'a RenderState<'a>) -> Self {
+        Self {
+            vars,
+            state,
+            including: None,
+        }
+    }
+
+    /// Looks up the value of a variable stored in the context, see
+    /// `ContextLookup::get`.
+    pub fn get(&self, key: &str) -> Option<&'a Value<'a>> {
+        self.vars.get(key)
+    }
+
+    /// Looks up the value of a variable, borrowed from the context or
+    /// computed when asked for, see `ContextLookup::resolve`.
+    pub fn resolve(&self, key: &str) -> Option<Cow<'a, Value<'a>>> {
+        self.vars.resolve(key)
+    }
+
+    /// Returns the context the variables are looked up in.
+    pub fn variables(&self) -> &'a dyn ContextLookup {
+        self.vars
+    }
+
+    /// Returns the separators numbers are rendered with, from
+    /// `FormatOptions::number_format`.
+    pub fn number_format(&self) -> NumberFormat {
+        self.state.number_format
+    }
+
+    /// Returns the most bytes the render may output, from
+    /// `FormatOptions::max_output_len`.
+    ///
+    /// Directives and filters that build their output in memory check it
+    /// against this limit before allocating, rather than leaving it to the
+    /// writer, which only sees the output once it is built.
+    pub fn max_output_len(&self) -> usize {
+        self.state.max_output_len
+    }
+
+    /// Returns the same render with its variables looked up in `vars`, such
+    /// as a wrapper of `variables` that adds some of its own.
+    pub(crate) fn with_variables<'b>(&self, vars: &'b dyn ContextLookup) -> RenderContext<'b>
+    where
+        'a: 'b,
+    {
+        RenderContext {
+            vars,
+            state: self.state,
+            including: self.including,
+        }
+    }
+
+    /// Returns the same render nested in the partial `including`.
+    pub(crate) fn with_including<'b>(&self, including: &'b Including<'b>) -> RenderContext<'b>
+    where
+        'a: 'b,
+    {
+        RenderContext {
+            vars: self.vars,
+            state: self.state,
+            including: Some(including),
+        }
+    }
+
+    /// Returns the partials the render is nested in, innermost first.
+    pub(crate) fn including(&self) -> Option<&'a Including<'a>> {
+        self.including
+    }
+
+    /// Returns the tolerance `==` and `!=` use when comparing floats.
+    pub(crate) fn float_tolerance(&self) -> FloatTolerance {
+        self.state.float_tolerance
+    }
+
+    /// Returns whether directives that render nothing on a line of their own
+    /// remove the whole line.
+    pub(crate) fn trim_blocks(&self) -> bool {
+        self.state.trim_blocks
+    }
+
+    /// Returns what directives the parser didn't recognize render.
+    pub(crate) fn unknown_policy(&self) -> UnknownPolicy {
+        self.state.unknown_policy
+    }
+
+    /// Returns what filters do with values of a type they don't accept.
+    pub(crate) fn filter_mismatch(&self) -> FilterMismatch {
+        self.state.filter_mismatch
+    }
+
+    /// Returns the text substituted variables render when they are `Null` or
+    /// an empty string, if any.
+    pub(crate) fn empty_placeholder(&self) -> Option<&'a str> {
+        self.state.empty_placeholder
+    }
+
+    /// Returns the filters the variable `name` renders with when substituted
+    /// without a spec of its own, such as `{name}`, if any.
+    pub(crate) fn default_filters(&self, name: &str) -> Option<&'a [Box<dyn Filter>]> {
+        self.state.schema?.get(name)
+    }
+
+    /// Returns the boolean a string stands for in conditions, such as
+    /// `true` for `"yes"`, or `None` to coerce it with `Value::is_truthy`.
+    pub(crate) fn bool_keyword(&self, text: &str) -> Option<bool> {
+        self.state.bool_keywords?.get(text)
+    }
+
+    /// Returns the column the output is at, for `{@col(n)}`: the number of
+    /// chars written since the last line break, with tabs moving to the next
+    /// multiple of 8. Only tracked for templates that need it.
+    pub(crate) fn column(&self) -> usize {
+        self.state.column.get()
+    }
+
+    /// Moves the column the output is at, for output that is held back
+    /// before it is written.
+    pub(crate) fn set_column(&self, column: usize) {
+        self.state.column.set(column);
+    }
+
+    /// Returns the partial template included by `{>name}`.
+    pub(crate) fn partial(&self, name: &str) -> Option<&'a Fragment> {
+        self.state.registry?.get(name)
+    }
+
+    /// Returns whether the partial `name` is being rendered, in which case
+    /// including it again would never end.
+    pub(crate) fn is_including(&self, name: &str) -> bool {
+        std::iter::successors(self.including, |including| including.outer)
+            .any(|including| including.name == name)
+    }
+
+    /// Counts a directive about to be evaluated, nested ones included, and
+    /// fails once the render has evaluated as many as it may.
+    pub(crate) fn count_directive(&self) -> Result<(), DirectiveError> {
+        match self.state.directives_left.get().checked_sub(1) {
+            Some(left) => {
+                self.state.directives_left.set(left);
+                Ok(())
+            }
+            None => Err(DirectiveError::DirectiveLimitExceeded {
+                limit: self.state.max_directives,
+            }),
+        }
+    }
+
+    /// Returns how substituted `Bytes` values are written as raw bytes, if
+    /// they are rather than failing with a type error.
+    pub(crate) fn raw_bytes(&self) -> Option<&RawBytes> {
+        self.state.raw_bytes
+    }
+}
+
+/// The name of the value a switch matched, `{@value}`, within the body of
+/// the case it selected.
+pub(crate) const MATCHED_VALUE: &str = "@value";
+
+/// The context of a switch case body, in which `{@value}` is the value the
+/// switch matched. Shadows any outer switch's value.
+pub(crate) struct Matching<'a> {
+    pub(crate) ctx: &'a dyn ContextLookup,
+    pub(crate) value: &'a Value<'a>,
+}
+
+impl ContextLookup for Matching<'_> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        match key {
+            MATCHED_VALUE => Some(self.value),
+            _ => self.ctx.get(key),
+        }
+    }
+
+    fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+        match key {
+            MATCHED_VALUE => Some(Cow::Borrowed(self.value)),
+            _ => self.ctx.resolve(key),
+        }
+    }
+}
+
+/// The name of the current item of a loop, `{.}`, within its body.
+pub(crate) const LOOP_ITEM: &str = ".";
+
+/// The names of the position of the current item of a loop within its body:
+/// `{@index}`, counting from 0, `{@count}`, the number of items, and
+/// `{@first}` and `{@last}`, whether the item is the first or the last one.
+pub(crate) const LOOP_INDEX: &str = "@index";
+pub(crate) const LOOP_COUNT: &str = "@count";
+pub(crate) const LOOP_FIRST: &str = "@first";
+pub(crate) const LOOP_LAST: &str = "@last";
+
+/// Returns whether `name` is one of the values a switch case or loop body
+/// sees, rather than a variable of the context.
+pub(crate) fn is_scoped(name: &str) -> bool {
+    matches!(
+        name,
+        MATCHED_VALUE | LOOP_ITEM | LOOP_INDEX | LOOP_COUNT | LOOP_FIRST | LOOP_LAST
+    )
+}
+
+/// The context of a loop body, in which `{.}` is the current item and
+/// `{@index}`, `{@count}`, `{@first}` and `{@last}` its position. Shadows
+/// those of any outer loop.
+pub(crate) struct Looping<'a> {
+    ctx: &'a dyn ContextLookup,
+    item: &'a Value<'a>,
+    index: Value<'static>,
+    count: Value<'static>,
+    first: Value<'static>,
+    last: Value<'static>,
+}
+
+impl<'a> Looping<'a> {
+    /// Creates the context of the body for the item at `index` of `count`.
+    pub(crate) fn new(
+        ctx: &'a dyn ContextLookup,
+        item: &'a Value<'a>,
+        index: usize,
+        count: usize,
+    ) -> Self {
+        let int = |n: usize| Value::Int(i64::try_from(n).unwrap_or(i64::MAX));
+
+        Self {
+            ctx,
+            item,
+            index: int(index),
+            count: int(count),
+            first: Value::Bool(index == 0),
+            last: Value::Bool(index + 1 == count),
+        }
+    }
+
+    fn scoped(&self, key: &str) -> Option<&Value<'_>> {
+        match key {
+            LOOP_ITEM => Some(self.item),
+            LOOP_INDEX => Some(&self.index),
+            LOOP_COUNT => Some(&self.count),
+            LOOP_FIRST => Some(&self.first),
+            LOOP_LAST => Some(&self.last),
+            _ => None,
+        }
+    }
+}
+
+impl ContextLookup for Looping<'_> {
+    fn get(&self, key: &str) -> Option<&Value<'_>> {
+        match self.scoped(key) {
+            Some(value) => Some(value),
+            None => self.ctx.get(key),
+        }
+    }
+
+    fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+        match self.scoped(key) {
+            Some(value) => Some(Cow::Borrowed(value)),
+            None => self.ctx.resolve(key),
+        }
+    }
+}
+
+/// The first of the 256 chars that stand for raw bytes in the writes of
+/// `RawBytes`, at the end of the last private use area.
+const RAW_BYTES_START: u32 = 0x10FF00;
+
+/// Returns the char that stands for the raw byte `byte` in a write of
+/// `RawBytes`.
+pub(crate) fn raw_byte_char(byte: u8) -> char {
+    char::from_u32(RAW_BYTES_START + u32::from(byte)).unwrap_or_default()
+}
+
+/// Returns the raw byte `c` stands for in a write of `RawBytes`, if it
+/// stands for one.
+pub(crate) fn char_raw_byte(c: char) -> Option<u8> {
+    u32::from(c)
+        .checked_sub(RAW_BYTES_START)
+        .and_then(|byte| u8::try_from(byte).ok())
+}
+
+/// Writes raw bytes through the writers of a `Template::format_bytes` render,
+/// in order with the text around them.
+///
+/// The bytes travel as the chars `raw_byte_char` returns, in writes marked as
+/// raw: only those are turned back into bytes, so text from the context that
+/// happens to contain the same chars comes out as written. A write is taken
+/// by the byte sink, or by a buffer that writes it on to the sink later;
+/// writers in between pass it through.
+pub(crate) struct RawBytes {
+    writing: Cell<bool>,
+    taken: Cell<bool>,
+    /// The variable the bytes being written come from, for the error of a
+    /// buffer that can't write them on
+    name: RefCell<String>,
+}
+
+impl RawBytes {
+    pub(crate) fn new() -> Self {
+        Self {
+            writing: Cell::new(false),
+            taken: Cell::new(false),
+            name: RefCell::new(String::new()),
+        }
+    }
+
+    /// Writes the bytes of the variable `name` to `out` with `write`, which
+    /// writes them as the chars `raw_byte_char` returns. Returns whether a
+    /// writer took them as raw bytes rather than as text.
+    pub(crate) fn write(
+        &self,
+        name: &str,
+        out: &mut dyn fmt::Write,
+        write: impl FnOnce(&mut dyn fmt::Write) -> fmt::Result,
+    ) -> Result<bool, fmt::Error> {
+        let (writing, taken) = (self.writing.replace(true), self.taken.replace(false));
+        if !writing {
+            let mut current = self.name.borrow_mut();
+            current.clear();
+            current.push_str(name);
+        }
+
+        let result = write(out);
+        let was_taken = self.taken.replace(taken);
+        self.writing.set(writing);
+
+        result.map(|()| was_taken)
+    }
+
+    /// Returns whether the write in progress is of raw bytes.
+    pub(crate) fn is_writing(&self) -> bool {
+        self.writing.get()
+    }
+
+    /// Returns whether the write in progress is of raw bytes, taking it if
+    /// so.
+    pub(crate) fn take(&self) -> bool {
+        if self.writing.get() {
+            self.taken.set(true);
+        }
+
+        self.writing.get()
+    }
+
+    /// Returns the variable the bytes being written come from.
+    pub(crate) fn name(&self) -> String {
+        self.name.borrow().clone()
+    }
+}
+
+/// Returns the number of bytes `text` stands for in a write of `RawBytes`:
+/// one per char.
+pub(crate) fn raw_len(text: &str) -> usize {
+    text.chars().count()
+}
@@ -1,7 +1,18 @@
-use crate::Context;
-use crate::arg::Argument;
+#[cfg(feature = "serde")]
+use crate::SerializedDirective;
+use crate::arg::{Argument, ComparisonOp, Resolvable};
+use crate::context::{Including, Looping, Matching, raw_byte_char, raw_len};
 use crate::err::DirectiveError;
+use crate::filter::Filter;
+use crate::{
+    DebugTree, FilterMismatch, FloatTolerance, FormatOptions, MissingPolicy, RenderContext,
+    RenderState, SourceWriter, TemplateError, UnknownPolicy, Value,
+};
+use std::any::Any;
 use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
 
 /// A template directive that can be executed to produce output.
 ///
@@ -13,13 +24,16 @@ use std::borrow::Cow;
 /// - Conditional branching
 ///
 /// Directives are trait objects stored in the compiled template and executed
-/// sequentially during the `format` operation.
-pub trait Directive {
+/// sequentially during the `format` operation. They must be `Send + Sync` so
+/// that a compiled template can be shared across threads, and `'static` so
+/// that templates can be compared, see `Directive::same_as`.
+pub trait Directive: Any + Send + Sync {
     /// Executes this directive with the given context.
     ///
     /// # Arguments
     ///
-    /// * `ctx` - The template context containing variable values
+    /// * `ctx` - The context of the render, holding the variable values and
+    ///   the options the template is rendered with
     ///
     /// # Returns
     ///
@@ -30,23 +44,414 @@ pub trait Directive {
     ///
     /// Returns an error if the directive cannot be executed, such as when
     /// a required variable is missing or has an incompatible type.
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, DirectiveError>;
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError>;
+
+    /// Executes this directive and writes its output to `out`.
+    ///
+    /// The default implementation writes the result of `exec`. Directives that
+    /// can write their output without building a string first, such as numbers
+    /// taken straight from the context, override this to skip the allocation.
+    ///
+    /// Implementations should fail before writing anything when possible, so
+    /// that a failed directive leaves no partial output behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if execution fails, or `DirectiveError::WriteError` if
+    /// `out` rejects the output.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        out.write_str(&self.exec(ctx)?)?;
+        Ok(())
+    }
+
+    /// Checks that this directive would execute against `ctx`, without
+    /// keeping its output, for `Template::validate`.
+    ///
+    /// The default implementation writes the output to a sink that discards
+    /// it. Repeats override this to resolve their pattern and count without
+    /// writing every repetition, and conditionals and switches to check the
+    /// selected branch directive by directive.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error executing this directive fails with.
+    fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        self.write_to(ctx, &mut Discard)
+    }
+
+    /// Returns the text of this directive if it renders the same text for
+    /// every context, such as literal text.
+    ///
+    /// `Template::chunks` borrows this text from the template instead of
+    /// executing the directive. The default implementation returns `None`.
+    fn literal(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns the arguments this directive resolves when executed.
+    ///
+    /// Used for read-only analysis of a compiled template, such as listing the
+    /// variables it references. Directives without arguments can rely on the
+    /// default implementation, which returns an empty list.
+    fn arguments(&self) -> Vec<&Argument> {
+        Vec::new()
+    }
+
+    /// Returns the arguments whose literal text may contain nested directives.
+    ///
+    /// When a template is compiled, every `Argument::Literal` returned here
+    /// whose text contains the template's opening delimiter is compiled as a
+    /// sub-template and replaced by an `Argument::Template`. The default
+    /// implementation returns an empty list, so literals are kept as-is.
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        Vec::new()
+    }
+
+    /// Returns whether this directive renders the same output for every
+    /// context, so that `Template::optimize` can render it once and replace
+    /// it with its text.
+    ///
+    /// The default implementation returns `false`. The built-in directives
+    /// are constant when all their arguments are, see
+    /// `Argument::is_constant`, except includes, whose partials are only
+    /// known when rendering.
+    fn is_constant(&self) -> bool {
+        false
+    }
+
+    /// Returns an estimate of the length of this directive's output, in bytes.
+    ///
+    /// Used to size the output buffer before rendering. The estimate doesn't
+    /// have to be exact; the default implementation assumes a short value.
+    fn estimated_len(&self) -> usize {
+        DEFAULT_ESTIMATED_LEN
+    }
+
+    /// Writes this directive as a node of the tree returned by
+    /// `Template::debug_tree`.
+    ///
+    /// The default implementation writes a single `<directive>` node, so
+    /// custom directives only need to implement this to show their structure.
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf("<directive>");
+    }
+
+    /// Returns the source of this directive, without its delimiters, for
+    /// `Template::to_source`, or `None` if it can't be written back.
+    ///
+    /// The default implementation returns `None`, so templates holding custom
+    /// directives can only be written back if they implement this.
+    fn to_source(&self, _source: &SourceWriter) -> Option<String> {
+        None
+    }
+
+    /// Returns whether this directive is structurally equal to `other`, which
+    /// backs `PartialEq` for templates.
+    ///
+    /// The default implementation compares directives of the same type by
+    /// the trees they write with `write_tree`, so custom directives should
+    /// implement either method. The built-in directives compare their fields.
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        if Any::type_id(self) != (other as &dyn Any).type_id() {
+            return false;
+        }
+
+        let (mut tree, mut other_tree) = (DebugTree::new(), DebugTree::new());
+        self.write_tree(&mut tree);
+        other.write_tree(&mut other_tree);
+
+        tree.finish() == other_tree.finish()
+    }
+
+    /// Converts this directive into its serializable form, or `None` if it
+    /// can't be serialized. Only available with the `serde` feature.
+    ///
+    /// Templates containing directives that return `None` fail to serialize.
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        None
+    }
+}
+
+impl PartialEq for dyn Directive {
+    fn eq(&self, other: &Self) -> bool {
+        self.same_as(other)
+    }
+}
+
+impl Eq for dyn Directive {}
+
+/// Returns whether `other` is a `T` equal to `directive`, for
+/// `Directive::same_as`.
+fn same_directive<T: Directive + PartialEq>(directive: &T, other: &dyn Directive) -> bool {
+    (other as &dyn Any).downcast_ref::<T>() == Some(directive)
+}
+
+/// Returns whether every argument of `directive` is constant, for
+/// `Directive::is_constant`.
+fn constant_arguments(directive: &impl Directive) -> bool {
+    directive.arguments().into_iter().all(Argument::is_constant)
+}
+
+/// A `fmt::Write` that discards everything written to it, for rendering a
+/// template only to find out whether it fails.
+pub(crate) struct Discard;
+
+impl fmt::Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// Returns the error of a `Bytes` value written as text.
+fn bytes_type_error(name: &str) -> DirectiveError {
+    DirectiveError::TypeError {
+        name: name.to_string(),
+        expected: <Cow<'static, str>>::TYPE_NAME,
+        found: "bytes".to_string(),
+    }
+}
+
+/// Renders with `render` into a buffer and returns the output only if
+/// rendering succeeds, so that a failure leaves no partial output behind,
+/// such as the text before a missing variable in a conditional branch.
+///
+/// The buffer fails with `DirectiveError::OutputLimitExceeded` past the
+/// output limit, and the column advances while rendering as if the output
+/// were written, then moves back for it to be written for real.
+pub(crate) fn render_held(
+    ctx: &RenderContext<'_>,
+    render: impl FnOnce(&mut dyn fmt::Write) -> Result<(), DirectiveError>,
+) -> Result<Held, DirectiveError> {
+    let column = ctx.column();
+    let mut held = HeldWriter {
+        held: Held {
+            text: String::new(),
+            raw: Vec::new(),
+        },
+        len: 0,
+        ctx,
+        exceeded: false,
+    };
+    let result = render(&mut held);
+    ctx.set_column(column);
+
+    match result {
+        Ok(()) => Ok(held.held),
+        Err(_) if held.exceeded => Err(DirectiveError::OutputLimitExceeded {
+            limit: ctx.max_output_len(),
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+/// The output of `render_held`.
+pub(crate) struct Held {
+    text: String,
+    /// The ranges of `text` written as raw bytes with `RawBytes`, and the
+    /// variables they come from
+    raw: Vec<(Range<usize>, String)>,
+}
+
+impl Held {
+    /// Writes the output to `out`, raw bytes included.
+    pub(crate) fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        let Some(raw) = ctx.raw_bytes().filter(|_| !self.raw.is_empty()) else {
+            out.write_str(&self.text)?;
+            return Ok(());
+        };
+
+        let mut start = 0;
+        for (range, name) in &self.raw {
+            out.write_str(&self.text[start..range.start])?;
+            if !raw.write(name, out, |out| out.write_str(&self.text[range.clone()]))? {
+                return Err(bytes_type_error(name));
+            }
+            start = range.end;
+        }
+
+        out.write_str(&self.text[start..])?;
+        Ok(())
+    }
+}
+
+/// The buffer of `render_held`.
+struct HeldWriter<'a, 'c> {
+    held: Held,
+    /// The length of the output, with raw bytes counted as such
+    len: usize,
+    ctx: &'a RenderContext<'c>,
+    exceeded: bool,
+}
+
+impl fmt::Write for HeldWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let raw = self.ctx.raw_bytes().filter(|raw| raw.take());
+        let len = if raw.is_some() { raw_len(s) } else { s.len() };
+
+        if len > self.ctx.max_output_len() - self.len {
+            self.exceeded = true;
+            return Err(fmt::Error);
+        }
+
+        self.ctx.set_column(advance_column(self.ctx.column(), s));
+        let start = self.held.text.len();
+        self.held.text.push_str(s);
+        self.len += len;
+
+        if let Some(raw) = raw {
+            match self.held.raw.last_mut() {
+                Some((range, _)) if range.end == start => range.end = self.held.text.len(),
+                _ => self
+                    .held
+                    .raw
+                    .push((start..self.held.text.len(), raw.name())),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The output length assumed for directives whose output depends on the context.
+pub(crate) const DEFAULT_ESTIMATED_LEN: usize = 8;
+
+/// Returns the output length of an argument if it is known at compile time:
+/// exact for literals, estimated for sub-templates.
+fn literal_len(arg: &Argument) -> Option<usize> {
+    match arg {
+        Argument::Literal(s) => Some(s.len()),
+        Argument::Template(fragment) => Some(fragment.estimated_len()),
+        _ => None,
+    }
+}
+
+/// Returns the placeholder `value` renders as if it is `Null` or an empty
+/// string and the context sets one, for `FormatOptions::empty_placeholder`.
+pub(crate) fn empty_placeholder<'a>(value: &Value, ctx: &RenderContext<'a>) -> Option<&'a str> {
+    match value {
+        Value::Null => ctx.empty_placeholder(),
+        Value::Str(s) if s.is_empty() => ctx.empty_placeholder(),
+        _ => None,
+    }
+}
+
+/// Returns `arg` for compiling, or the arguments of the directives in it if
+/// it is a sub-template built by the parser, such as a braced branch, whose
+/// literals are not compiled yet.
+fn parsed_arguments_mut(arg: &mut Argument) -> Vec<&mut Argument> {
+    match arg {
+        Argument::Template(fragment) => match Arc::get_mut(fragment) {
+            Some(fragment) => fragment.nested_arguments_mut(),
+            None => Vec::new(),
+        },
+        arg => vec![arg],
+    }
 }
 
 /// A directive that produces no output.
 ///
 /// Used as a placeholder when parsing encounters an empty or invalid expression
 /// that should be silently ignored rather than causing a compilation error.
+/// `DefaultParser` returns it for `{}` when `CompileOptions::empty_directives`
+/// is `EmptyPolicy::Empty`; by default such directives fail to compile.
 ///
 /// # Examples
 ///
 /// An empty directive always returns an empty string regardless of context.
+#[derive(PartialEq, Eq)]
 pub struct EmptyDirective;
 
 impl Directive for EmptyDirective {
-    fn exec(&self, _ctx: &Context) -> Result<Cow<'static, str>, DirectiveError> {
+    fn exec(&self, _ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
         Ok(Cow::Borrowed(""))
     }
+
+    fn is_constant(&self) -> bool {
+        true
+    }
+
+    fn estimated_len(&self) -> usize {
+        0
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf("Empty");
+    }
+
+    fn to_source(&self, _source: &SourceWriter) -> Option<String> {
+        Some(String::new())
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Empty)
+    }
+}
+
+/// A directive the parser didn't recognize, such as `{name:bogus(1)}`.
+///
+/// What it renders depends on `FormatOptions::unknown`: by default rendering
+/// fails with `DirectiveError::UnknownDirective`, but the directive can also
+/// render its source text untouched or nothing at all. The source text, delimiters
+/// included, is filled in when the template is compiled, so a parser only
+/// needs to return `UnknownDirective::default()`.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "Hi {% if admin %}!"
+/// With UnknownPolicy::Keep
+/// Produces: "Hi {% if admin %}!"
+/// ```
+#[derive(Default, PartialEq, Eq)]
+pub struct UnknownDirective(pub Cow<'static, str>);
+
+impl Directive for UnknownDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        match ctx.unknown_policy() {
+            UnknownPolicy::Error => Err(DirectiveError::UnknownDirective {
+                directive: self.0.to_string(),
+            }),
+            UnknownPolicy::Keep => Ok(self.0.clone()),
+            UnknownPolicy::Empty => Ok(Cow::Borrowed("")),
+        }
+    }
+
+    fn estimated_len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf(format!("Unknown {:?}", self.0));
+    }
+
+    /// Unknown directives nested in other directives have no source text,
+    /// so only those at the top level of a template can be written back.
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        source.contents(&self.0).map(str::to_string)
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Unknown(self.0.clone()))
+    }
 }
 
 /// A directive that outputs a literal string.
@@ -64,18 +469,46 @@ impl Directive for EmptyDirective {
 ///   2. ReplaceDirective(name)
 ///   3. LiteralDirective("!")
 /// ```
+#[derive(PartialEq, Eq)]
 pub struct LiteralDirective(pub Cow<'static, str>);
 
 impl Directive for LiteralDirective {
-    fn exec(&self, _ctx: &Context) -> Result<Cow<'static, str>, DirectiveError> {
+    fn exec(&self, _ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
         Ok(self.0.clone())
     }
+
+    fn is_constant(&self) -> bool {
+        true
+    }
+
+    fn literal(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn estimated_len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf(format!("Literal {:?}", self.0));
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Literal(self.0.clone()))
+    }
 }
 
 /// A directive that substitutes a variable or evaluates an expression.
 ///
 /// This is the most common directive type, used for simple variable replacement
-/// like `{name}` or literal values like `{"hello"}`.
+/// like `{name}` or literal values like `{"hello"}`. A variable that the
+/// `Schema` of the render sets filters for renders through them, as if they
+/// were written as its spec.
 ///
 /// # Examples
 ///
@@ -88,11 +521,248 @@ impl Directive for LiteralDirective {
 /// # Errors
 ///
 /// Returns an error if the argument cannot be resolved (e.g., variable not found).
+#[derive(PartialEq, Eq)]
 pub struct ReplaceDirective(pub Argument);
 
 impl Directive for ReplaceDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, DirectiveError> {
-        self.0.resolve_as::<Cow<'static, str>>(ctx)
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        if let Argument::Variable(name) = &self.0
+            && let Some(value) = ctx.resolve(name)
+        {
+            if let Some(filters) = ctx.default_filters(name) {
+                return render_filtered(&self.0, value.into_owned(), filters, ctx)
+                    .map(|text| Cow::Owned(text.into_owned()));
+            }
+
+            if let Some(placeholder) = empty_placeholder(&value, ctx) {
+                return Ok(Cow::Owned(placeholder.to_string()));
+            }
+        }
+
+        self.0.render(ctx)
+    }
+
+    /// Writes context values through their `Display` impl, without allocating.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        let Argument::Variable(name) = &self.0 else {
+            out.write_str(&self.exec(ctx)?)?;
+            return Ok(());
+        };
+
+        if ctx.default_filters(name).is_some() {
+            out.write_str(&self.exec(ctx)?)?;
+            return Ok(());
+        }
+
+        let value = ctx.resolve(name).ok_or_else(|| DirectiveError::NotFound {
+            name: name.to_string(),
+            type_name: <Cow<'static, str>>::TYPE_NAME,
+        })?;
+
+        if let Value::Bytes(bytes) = &*value {
+            // Only taken as bytes when `out` leads to the output of
+            // `Template::format_bytes`, rather than into a string
+            let taken = match ctx.raw_bytes() {
+                Some(raw) => raw.write(name, out, |out| {
+                    bytes
+                        .iter()
+                        .try_for_each(|&b| out.write_char(raw_byte_char(b)))
+                })?,
+                None => false,
+            };
+
+            return if taken {
+                Ok(())
+            } else {
+                Err(bytes_type_error(name))
+            };
+        }
+
+        if let Value::Array(_) = &*value {
+            return Err(DirectiveError::TypeError {
+                name: name.to_string(),
+                expected: <Cow<'static, str>>::TYPE_NAME,
+                found: value.type_name().to_string(),
+            });
+        }
+
+        if let Some(placeholder) = empty_placeholder(&value, ctx) {
+            out.write_str(placeholder)?;
+            return Ok(());
+        }
+
+        ctx.number_format().write(&value, out)?;
+        Ok(())
+    }
+
+    /// The case bodies of switches used as values, as in
+    /// `{[tier](gold:2)(*:0) + 1}`.
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        self.0.case_bodies_mut()
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        vec![&self.0]
+    }
+
+    fn is_constant(&self) -> bool {
+        constant_arguments(self)
+    }
+
+    fn estimated_len(&self) -> usize {
+        literal_len(&self.0).unwrap_or(DEFAULT_ESTIMATED_LEN)
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.argument("Replace", &self.0);
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        source.subject(&self.0)
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Replace(self.0.clone()))
+    }
+}
+
+/// A directive that passes a value through a chain of filters before output.
+///
+/// Syntax: `{value:spec}` where `spec` describes the filter, such as the
+/// alignment spec in `{name:<10}`. Filters are chained with pipes, as in
+/// `{name | trunc(20) | >24}` or `{name:trunc(20) | >24}`, and applied from
+/// left to right, each one receiving the output of the previous.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{name:>6}"
+/// With context: name = "Bob"
+/// Produces: "   Bob"
+///
+/// Template: "{name | slice(0, 2) | >4}"
+/// With context: name = "Bob"
+/// Produces: "  Bo"
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The argument cannot be resolved
+/// - Any filter fails to transform the value. In a chain of several filters
+///   the error is wrapped in `DirectiveError::FilterChainError`, which names
+///   the failing filter and its position
+#[derive(PartialEq, Eq)]
+pub struct FilterDirective(pub Argument, pub Vec<Box<dyn Filter>>);
+
+/// Renders `value`, resolved from `argument`, after passing it through
+/// `filters` in order, as `{argument:filters}` does.
+///
+/// Filters that reject the type of their input follow the context's
+/// `FilterMismatch` policy. Errors in a chain of more than one filter are
+/// wrapped in `DirectiveError::FilterChainError`.
+pub(crate) fn render_filtered<'v>(
+    argument: &Argument,
+    mut value: Value<'v>,
+    filters: &[Box<dyn Filter>],
+    ctx: &RenderContext<'_>,
+) -> Result<Cow<'v, str>, DirectiveError> {
+    let format = ctx.number_format();
+    let mismatch = ctx.filter_mismatch();
+
+    for (i, filter) in filters.iter().enumerate() {
+        // Lenient policies need the value a filter rejected
+        let input = (mismatch != FilterMismatch::Error).then(|| value.clone());
+
+        let output = filter.apply_in(value, ctx);
+
+        value = match (output, input) {
+            (Err(DirectiveError::FilterTypeError { .. }), Some(input)) => {
+                mismatch.recover(filter.as_ref(), input, ctx)
+            }
+            (result, _) => result.map_err(|e| match filters.len() {
+                1 => e,
+                _ => DirectiveError::FilterChainError {
+                    position: i + 1,
+                    filter: filter.describe(),
+                    source: Box::new(e),
+                },
+            })?,
+        };
+    }
+
+    format
+        .render(&value)
+        .ok_or_else(|| DirectiveError::TypeError {
+            name: match argument {
+                Argument::Variable(name) => name.to_string(),
+                _ => "value".to_string(),
+            },
+            expected: <Cow<'static, str>>::TYPE_NAME,
+            found: value.type_name().to_string(),
+        })
+}
+
+impl Directive for FilterDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        let value = self.0.resolve_value(ctx)?;
+        render_filtered(&self.0, value, &self.1, ctx).map(|text| Cow::Owned(text.into_owned()))
+    }
+
+    /// The case bodies of switches used as values, as in
+    /// `{[tier](gold:2)(*:0) + 1:05}`.
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        self.0.case_bodies_mut()
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        vec![&self.0]
+    }
+
+    /// Filters are assumed to depend only on the value they receive.
+    fn is_constant(&self) -> bool {
+        constant_arguments(self)
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch("Filter", |tree| {
+            tree.argument("value", &self.0);
+
+            for filter in &self.1 {
+                tree.leaf(format!("filter: {}", filter.describe()));
+            }
+        });
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        Some(format!(
+            "{}:{}",
+            source.subject(&self.0)?,
+            source.filters(&self.1)
+        ))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        let filters = self.1.iter().map(|filter| filter.to_serialized());
+
+        Some(SerializedDirective::Filter(
+            self.0.clone(),
+            filters.collect::<Option<_>>()?,
+        ))
     }
 }
 
@@ -100,7 +770,9 @@ impl Directive for ReplaceDirective {
 ///
 /// Syntax: `{pattern:count}` where:
 /// - `pattern` is the string to repeat (variable or literal)
-/// - `count` is the number of repetitions (variable or literal integer)
+/// - `count` is the number of repetitions (variable or literal integer);
+///   negative counts produce no output, and counts too large for a `usize`
+///   fail with `DirectiveError::ArithmeticOverflow`
 ///
 /// # Examples
 ///
@@ -113,33 +785,174 @@ impl Directive for ReplaceDirective {
 /// Produces: "-----"
 /// ```
 ///
+/// A quoted pattern may contain `:`, and the delimiters escaped with a
+/// backslash, as in `{': ':3}` or `{'a\}b':2}`. The pattern repeated is the
+/// unescaped text.
+///
+/// The pattern is resolved once, whatever the count, and its text repeated.
+/// When rendering, the output is written in chunks of at most 64 KiB rather
+/// than built as a whole, so `Template::format_to` streams a huge repeat
+/// into its writer and only the output limit bounds its length.
+///
 /// # Errors
 ///
 /// Returns an error if:
 /// - The pattern argument cannot be resolved to a string
 /// - The count argument cannot be resolved to an integer
+/// - `exec` would build an output longer than
+///   `FormatOptions::DEFAULT_MAX_OUTPUT_LEN`
+#[derive(PartialEq, Eq)]
 pub struct RepeatDirective(pub Argument, pub Argument);
 
-impl Directive for RepeatDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, DirectiveError> {
-        let pattern = self.0.resolve_as::<Cow<'static, str>>(ctx)?;
+/// The largest chunk a repeat directive builds at once while writing.
+const REPEAT_CHUNK_LEN: usize = 64 * 1024;
+
+impl RepeatDirective {
+    /// Resolves the repetition count. Negative counts repeat zero times,
+    /// while counts too large for an integer or a `usize` overflow.
+    fn count(&self, ctx: &RenderContext<'_>) -> Result<usize, DirectiveError> {
+        let overflow = |count: &dyn fmt::Display| DirectiveError::ArithmeticOverflow {
+            expression: format!("repeat count {}", count),
+        };
+
+        if let Argument::Literal(count) = &self.1
+            && !count.is_empty()
+            && count.bytes().all(|b| b.is_ascii_digit())
+            && count.parse::<i64>().is_err()
+        {
+            return Err(overflow(count));
+        }
+
         let count = self.1.resolve_as::<i64>(ctx)?;
 
-        Ok(Cow::Owned(pattern.repeat(count as usize)))
+        if count < 0 {
+            return Ok(0);
+        }
+
+        usize::try_from(count).map_err(|_| overflow(&count))
+    }
+}
+
+impl Directive for RepeatDirective {
+    /// Builds the whole output in a single allocation of its exact length.
+    /// Since nothing can stop it once it is built, an output longer than
+    /// the default limit fails whatever the options.
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        let pattern = self.0.render(ctx)?;
+        let count = self.count(ctx)?;
+        let limit = FormatOptions::DEFAULT_MAX_OUTPUT_LEN;
+
+        if pattern.len().saturating_mul(count) > limit {
+            return Err(DirectiveError::OutputLimitExceeded { limit });
+        }
+
+        Ok(Cow::Owned(pattern.repeat(count)))
+    }
+
+    /// Writes the output in chunks of at most `REPEAT_CHUNK_LEN` bytes, so
+    /// that an output limit on `out` stops a huge count before it is fully
+    /// allocated.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        let pattern = self.0.render(ctx)?;
+        let count = self.count(ctx)?;
+
+        if pattern.is_empty() || count == 0 {
+            return Ok(());
+        }
+
+        let per_chunk = (REPEAT_CHUNK_LEN / pattern.len()).clamp(1, count);
+        let chunk = pattern.repeat(per_chunk);
+        let mut left = count;
+
+        while left >= per_chunk {
+            out.write_str(&chunk)?;
+            left -= per_chunk;
+        }
+
+        out.write_str(&chunk[..left * pattern.len()])?;
+        Ok(())
+    }
+
+    /// Resolves the pattern, once, and the count without repeating.
+    fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        self.0.validate(ctx)?;
+        self.count(ctx)?;
+        Ok(())
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        vec![&self.0, &self.1]
+    }
+
+    fn is_constant(&self) -> bool {
+        constant_arguments(self)
+    }
+
+    /// Exact when both the pattern and the count are literals.
+    fn estimated_len(&self) -> usize {
+        let pattern = literal_len(&self.0).unwrap_or(DEFAULT_ESTIMATED_LEN);
+
+        match &self.1 {
+            Argument::Literal(count) => count
+                .parse::<usize>()
+                .map_or(0, |count| pattern.saturating_mul(count)),
+            _ => pattern,
+        }
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch("Repeat", |tree| {
+            tree.argument("pattern", &self.0);
+            tree.argument("count", &self.1);
+        });
+    }
+
+    /// Counts are written as numbers, since a quoted count isn't one.
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        let count = match &self.1 {
+            Argument::Variable(name) => source.name(name)?,
+            Argument::Literal(count) if count.bytes().all(|b| b.is_ascii_digit()) => {
+                count.to_string()
+            }
+            _ => return None,
+        };
+
+        Some(format!("{}:{}", source.branch(&self.0)?, count))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Repeat(self.0.clone(), self.1.clone()))
     }
 }
 
 /// A directive that performs conditional branching (ternary operator).
 ///
 /// Evaluates a condition and returns one of two values based on the result.
-/// Syntax: `{condition ? true_value : false_value}`
+/// Syntax: `{condition ? true_value : false_value}`, or `{condition ? true_value}`
+/// to render nothing when the condition is false.
 ///
 /// The condition can be:
-/// - A boolean variable
-/// - A boolean literal
+/// - A variable, e.g. `{count ? 'items' : 'empty'}`
+/// - A literal
 /// - A comparison expression (e.g., `x == 5`, `a > b`)
 /// - A NOT expression (e.g., `!active`)
 ///
+/// Variables and literals that are not booleans are coerced with
+/// `Value::is_truthy`: numbers are true when non-zero, strings when
+/// non-empty and `Null` is false.
+/// Strings from the context set as keywords by
+/// `FormatOptions::bool_keywords`, such as `"off"`, are read as the boolean
+/// they stand for instead.
+///
 /// # Examples
 ///
 /// ```text
@@ -150,30 +963,1250 @@ impl Directive for RepeatDirective {
 /// Template: "{count > 0 ? 'items' : 'empty'}"
 /// With context: count = 5
 /// Produces: "items"
+///
+/// Template: "{is_admin ? 'Admin'}"
+/// With context: is_admin = false
+/// Produces: ""
+///
+/// Template: "{is_vip ? 'Welcome {name}!' : 'Hello stranger'}"
+/// With context: is_vip = true, name = "Ada"
+/// Produces: "Welcome Ada!"
 /// ```
 ///
-/// # Errors
+/// Branch literals that contain the opening delimiter are compiled as nested
+/// sub-templates, so directives inside them are rendered against the same
+/// context. Escaped delimiters (`{{`, `}}`) work inside branches as well.
 ///
-/// Returns an error if:
-/// - The condition cannot be resolved to a boolean
+/// An else branch starting another condition forms an else-if ladder,
+/// `{a ? x : b ? y : z}`. Its steps are kept side by side in `branches`,
+/// rather than nested, so compiling, rendering and dropping a ladder take
+/// time linear in its length and constant stack.
+///
+/// Only the selected branch is resolved and rendered. The other ones are
+/// never evaluated, so they may reference missing variables or hold
+/// expressions that would fail, as in `{have_data ? {data} : 'No data'}`
+/// without `data`. Conditions are tested in order, up to the first that
+/// holds.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - A tested condition references a missing variable
 /// - The selected branch argument cannot be resolved
+#[derive(PartialEq, Eq)]
 pub struct ConditionalDirective {
-    /// The condition to evaluate
-    pub cond: Argument,
-    /// The value to return if the condition is true
-    pub if_true: Argument,
-    /// The value to return if the condition is false
-    pub if_false: Argument,
+    /// The conditions to evaluate in order, each with the value to return
+    /// if it is the first that holds
+    pub branches: Vec<(Argument, Argument)>,
+    /// The value to return if no condition holds, or `None` to return an
+    /// empty string
+    pub if_false: Option<Argument>,
+}
+
+impl ConditionalDirective {
+    /// Returns the conditions that don't depend on the context, with the
+    /// value each always has, such as `1 < 2` or `!'yes'`, in order.
+    ///
+    /// A condition is constant when `Argument::is_constant` holds for it,
+    /// and its value is the one it resolves to without a context. Conditions
+    /// that fail to resolve, such as `9223372036854775807 + 1 > 0`, which
+    /// overflows, have none.
+    pub fn constant_conditions(&self) -> Vec<ConstantCondition> {
+        let empty = crate::Context::new();
+        let state = RenderState::unlimited();
+        let ctx = RenderContext::new(&empty, &state);
+
+        self.branches
+            .iter()
+            .filter(|(cond, _)| cond.is_constant())
+            .filter_map(|(cond, _)| {
+                Some(ConstantCondition {
+                    condition: cond.clone(),
+                    value: cond.resolve_condition(&ctx).ok()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the branch to render: the value of the first condition that
+    /// holds, or else `if_false`.
+    fn select(&self, ctx: &RenderContext<'_>) -> Result<Option<&Argument>, DirectiveError> {
+        for (cond, if_true) in &self.branches {
+            if cond.resolve_condition(ctx)? {
+                return Ok(Some(if_true));
+            }
+        }
+
+        Ok(self.if_false.as_ref())
+    }
+}
+
+/// A conditional whose condition doesn't depend on the context, as found by
+/// `Template::const_conditions`.
+///
+/// Such a conditional always renders the same branch, which usually means
+/// the condition is a leftover from debugging or a typo, as in
+/// `{'admin' ? ...}` where `admin` was meant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstantCondition {
+    /// The condition, as parsed
+    pub condition: Argument,
+    /// The value the condition always has
+    pub value: bool,
 }
 
 impl Directive for ConditionalDirective {
-    fn exec(&self, ctx: &Context) -> Result<Cow<'static, str>, DirectiveError> {
-        let cond_value = self.cond.resolve_as::<bool>(ctx)?;
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        match self.select(ctx)? {
+            Some(branch) => branch.render(ctx),
+            None => Ok(Cow::Borrowed("")),
+        }
+    }
+
+    /// Writes the selected branch, rendering sub-templates straight into `out`.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        match self.select(ctx)? {
+            Some(branch) => branch.write_to(ctx, out),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the selected branch only, as rendering does.
+    fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        match self.select(ctx)? {
+            Some(branch) => branch.validate(ctx),
+            None => Ok(()),
+        }
+    }
+
+    /// The branches, and those of braced branches, which the parser wraps in
+    /// sub-templates before any literal is compiled, and the case bodies of
+    /// switches used as values in the conditions.
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        let mut args = Vec::new();
+
+        for (cond, if_true) in &mut self.branches {
+            args.extend(cond.case_bodies_mut());
+            args.extend(parsed_arguments_mut(if_true));
+        }
+
+        if let Some(if_false) = &mut self.if_false {
+            args.extend(parsed_arguments_mut(if_false));
+        }
+
+        args
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        let mut args: Vec<_> = self
+            .branches
+            .iter()
+            .flat_map(|(cond, if_true)| [cond, if_true])
+            .collect();
+        args.extend(&self.if_false);
+        args
+    }
+
+    fn is_constant(&self) -> bool {
+        constant_arguments(self)
+    }
+
+    /// The longest of the branches.
+    fn estimated_len(&self) -> usize {
+        let if_false = match &self.if_false {
+            Some(arg) => literal_len(arg).unwrap_or(DEFAULT_ESTIMATED_LEN),
+            None => 0,
+        };
+
+        self.branches
+            .iter()
+            .map(|(_, if_true)| literal_len(if_true).unwrap_or(DEFAULT_ESTIMATED_LEN))
+            .fold(if_false, usize::max)
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch("Conditional", |tree| {
+            for (i, (cond, if_true)) in self.branches.iter().enumerate() {
+                tree.argument(if i == 0 { "if" } else { "else if" }, cond);
+                tree.argument("then", if_true);
+            }
+
+            if let Some(if_false) = &self.if_false {
+                tree.argument("else", if_false);
+            }
+        });
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        let mut steps = Vec::with_capacity(self.branches.len());
+
+        for (cond, if_true) in &self.branches {
+            steps.push(format!(
+                "{} ? {}",
+                source.condition(cond)?,
+                source.branch(if_true)?
+            ));
+        }
+
+        if let Some(if_false) = &self.if_false {
+            steps.push(source.branch(if_false)?);
+        }
+
+        Some(steps.join(" : "))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Conditional {
+            branches: self.branches.clone(),
+            if_false: self.if_false.clone(),
+        })
+    }
+}
+
+/// The label a switch case is matched by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CaseLabel {
+    /// Matches a scrutinee equal to this text, or to this number for numeric
+    /// scrutinees, see `CaseLabel::matches_value`
+    Value(Cow<'static, str>),
+    /// Matches a scrutinee whose text starts with this text, written with a
+    /// trailing `*` as in `ERROR*`
+    Prefix(Cow<'static, str>),
+    /// Matches a numeric scrutinee that compares with this number as the
+    /// operator requires, written as in `>=90` or `< -5`
+    ///
+    /// The parser only accepts the ordering operators `>`, `>=`, `<` and
+    /// `<=`. See `CaseLabel::matches_value` for which scrutinees are numeric.
+    Comparison(ComparisonOp, Cow<'static, str>),
+    /// Matches any scrutinee, written `*`
+    Default,
+}
+
+impl CaseLabel {
+    /// Returns whether this label matches the rendered scrutinee.
+    pub fn matches(&self, scrutinee: &str) -> bool {
+        match self {
+            Self::Value(label) => label == scrutinee,
+            Self::Prefix(prefix) => scrutinee.starts_with(&**prefix),
+            Self::Comparison(op, bound) => compare_bound(op, scrutinee.parse().ok(), bound),
+            Self::Default => true,
+        }
+    }
+
+    /// Returns whether this label matches a scrutinee value.
+    ///
+    /// Numbers are compared as numbers with labels that parse as one, so
+    /// `Int(404)` matches `404` and `404.0`, and `Float(2.0)` matches `2`.
+    /// Integers compare exactly, while a float scrutinee is equal to a label
+    /// within `tolerance`, as with `==`. Any other value is compared as the
+    /// text it renders as, so `Str("404")` matches `404` but `Str("404.0")`
+    /// doesn't. Prefixes are always matched against the rendered text.
+    ///
+    /// Comparisons compare numbers, and strings that parse as one, with
+    /// their bound exactly, like `<` and the other ordering operators do.
+    /// They never match any other scrutinee, nor a bound that isn't a number.
+    pub fn matches_value(&self, scrutinee: &Value, tolerance: FloatTolerance) -> bool {
+        let label = match self {
+            Self::Value(label) => label,
+            Self::Prefix(prefix) => {
+                return Cow::<'static, str>::from_value(scrutinee)
+                    .is_some_and(|text| text.starts_with(&**prefix));
+            }
+            Self::Comparison(op, bound) => {
+                let number = match scrutinee {
+                    Value::Int(n) => Some(*n as f64),
+                    Value::Float(f) => Some(*f),
+                    Value::Str(s) => s.parse().ok(),
+                    _ => None,
+                };
+
+                return compare_bound(op, number, bound);
+            }
+            Self::Default => return true,
+        };
+
+        match scrutinee {
+            Value::Int(n) => match label.parse::<i64>() {
+                Ok(label) => label == *n,
+                Err(_) => label.parse::<f64>().is_ok_and(|label| label == *n as f64),
+            },
+            Value::Float(f) => label
+                .parse::<f64>()
+                .is_ok_and(|label| tolerance.equal(*f, label)),
+            _ => Cow::<'static, str>::from_value(scrutinee).is_some_and(|text| text == *label),
+        }
+    }
+}
+
+/// Writes the label as it is written in a template, e.g. `gold`, `ERROR*`,
+/// `>=90` or `*`.
+impl fmt::Display for CaseLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(label) => f.write_str(label),
+            Self::Prefix(prefix) => write!(f, "{prefix}*"),
+            Self::Comparison(op, bound) => write!(f, "{}{bound}", op.symbol()),
+            Self::Default => f.write_str("*"),
+        }
+    }
+}
+
+/// Returns whether `number` compares with the numeric `bound` as `op`
+/// requires, for `CaseLabel::Comparison`.
+fn compare_bound(op: &ComparisonOp, number: Option<f64>, bound: &str) -> bool {
+    match (number, bound.parse::<f64>()) {
+        (Some(number), Ok(bound)) => op.holds(number.partial_cmp(&bound)),
+        _ => false,
+    }
+}
+
+/// A single `(label:body)` case of a switch directive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SwitchCase {
+    /// The label this case is matched by
+    pub label: CaseLabel,
+    /// The text rendered when this case is selected
+    pub body: Argument,
+}
+
+/// A directive that renders the first case whose label matches a value.
+///
+/// Syntax: `{[scrutinee](label:body)(label:body)..}`, where a `*` label
+/// matches any value, a label with a trailing `*`, such as `ERROR*`,
+/// matches values starting with it, and a comparison such as `>=90` matches
+/// numbers it holds for. Cases are tried in order, except that a label equal
+/// to the value takes priority over a prefix matched before it; if none
+/// matches, the directive renders nothing.
+///
+/// Numeric scrutinees are compared with labels as numbers, so `Int(404)`
+/// matches the case `404`; other scrutinees are compared as text, see
+/// `CaseLabel::matches_value`. Case bodies are raw text up to the case's closing `)`:
+/// whitespace is kept, parentheses must balance unless escaped as `\(` and
+/// `\)`, and bodies that contain the opening delimiter are compiled as nested
+/// sub-templates rendered against the same context.
+///
+/// A default after the scrutinee, as in `{[status|unknown](active:On)(unknown:?)}`,
+/// is matched in its place when the scrutinee is a missing variable or
+/// `Null`. The default is a label, not a variable: `unknown` above is the
+/// text `unknown`.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{[tier](gold:Welcome {name}, gold member)(silver:Hi {name})(*:Hello)}"
+/// With context: tier = "gold", name = "Ada"
+/// Produces: "Welcome Ada, gold member"
+///
+/// With context: tier = "bronze"
+/// Produces: "Hello"
+///
+/// Template: "{[score](>=90:A)(>=80:B)(>=70:C)(*:F)}"
+/// With context: score = 85
+/// Produces: "B"
+///
+/// Template: "{[status|unknown](active:On)(unknown:?)}"
+/// With context: (empty)
+/// Produces: "?"
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The scrutinee cannot be resolved, and is not a missing variable with a
+///   default
+/// - The selected case body cannot be rendered
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SwitchDirective {
+    /// The value the case labels are matched against
+    pub scrutinee: Argument,
+    /// The cases, in the order they are tried
+    pub cases: Vec<SwitchCase>,
+    /// The value matched in place of a scrutinee that is missing or `Null`
+    pub default: Option<Argument>,
+}
+
+impl SwitchDirective {
+    /// Returns the first case that matches the scrutinee, if any, preferring
+    /// an exact label over a prefix, with the value it matched.
+    pub(crate) fn select<'c>(
+        &self,
+        ctx: &RenderContext<'c>,
+    ) -> Result<Option<(&SwitchCase, Value<'c>)>, DirectiveError> {
+        let scrutinee = match (self.scrutinee.resolve_value(ctx), &self.default) {
+            (Ok(Value::Null) | Err(DirectiveError::NotFound { .. }), Some(default)) => {
+                default.resolve_value(ctx)?
+            }
+            (scrutinee, _) => scrutinee?,
+        };
 
-        if cond_value {
-            self.if_true.resolve_as::<Cow<'static, str>>(ctx)
-        } else {
-            self.if_false.resolve_as::<Cow<'static, str>>(ctx)
+        if let Value::Bytes(_) | Value::Array(_) = scrutinee {
+            return Err(DirectiveError::TypeError {
+                name: match &self.scrutinee {
+                    Argument::Variable(name) => name.to_string(),
+                    _ => "value".to_string(),
+                },
+                expected: <Cow<'static, str>>::TYPE_NAME,
+                found: scrutinee.type_name().to_string(),
+            });
         }
+
+        let tolerance = ctx.float_tolerance();
+
+        let mut matching = self
+            .cases
+            .iter()
+            .filter(|case| case.label.matches_value(&scrutinee, tolerance));
+        let first = matching.next();
+
+        if let Some(SwitchCase {
+            label: CaseLabel::Prefix(_),
+            ..
+        }) = first
+            && let Some(exact) = matching.find(|case| matches!(case.label, CaseLabel::Value(_)))
+        {
+            return Ok(Some((exact, scrutinee)));
+        }
+
+        Ok(first.map(|case| (case, scrutinee)))
+    }
+
+    /// Returns the cases that can never be selected, because an earlier case
+    /// has the same label or is a default, `*`, which matches every value.
+    ///
+    /// Templates compiled with `CompileOptions::deny_unreachable_cases` fail
+    /// on such cases; `Template::unreachable_cases` lists them for a whole
+    /// template.
+    pub fn unreachable_cases(&self) -> Vec<UnreachableCase> {
+        self.cases
+            .iter()
+            .enumerate()
+            .filter_map(|(i, case)| {
+                let shadowing = self.cases[..i].iter().position(|earlier| {
+                    earlier.label == case.label || earlier.label == CaseLabel::Default
+                })?;
+
+                Some(UnreachableCase {
+                    label: case.label.clone(),
+                    position: i + 1,
+                    shadowed_by: shadowing + 1,
+                    after_default: self.cases[shadowing].label == CaseLabel::Default,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A switch case that can never be selected, as found by
+/// `SwitchDirective::unreachable_cases`.
+///
+/// Cases are tried in order, so a case repeating the label of an earlier
+/// one, as the second case of `{[x](a:1)(a:2)}` does, is never reached, and
+/// neither is any case after a default, as in `{[x](*:any)(a:1)}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnreachableCase {
+    /// The label of the unreachable case
+    pub label: CaseLabel,
+    /// The position of the unreachable case in its switch, counting from 1
+    pub position: usize,
+    /// The position of the earlier case that is selected instead
+    pub shadowed_by: usize,
+    /// Whether the earlier case is a default, `*`, rather than a case with
+    /// the same label
+    pub after_default: bool,
+}
+
+impl From<UnreachableCase> for TemplateError {
+    fn from(case: UnreachableCase) -> Self {
+        Self::UnreachableCase {
+            label: case.label.to_string(),
+            position: case.position,
+            shadowed_by: case.shadowed_by,
+            reason: match case.after_default {
+                true => "is a default, which matches every value",
+                false => "has the same label",
+            },
+        }
+    }
+}
+
+impl Directive for SwitchDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        match self.select(ctx)? {
+            Some((case, value)) => {
+                let matching = Matching {
+                    ctx: ctx.variables(),
+                    value: &value,
+                };
+                case.body.render(&ctx.with_variables(&matching))
+            }
+            None => Ok(Cow::Borrowed("")),
+        }
+    }
+
+    /// Writes the selected case, rendering sub-templates straight into `out`.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        match self.select(ctx)? {
+            Some((case, value)) => {
+                let matching = Matching {
+                    ctx: ctx.variables(),
+                    value: &value,
+                };
+                case.body.write_to(&ctx.with_variables(&matching), out)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the selected case only, as rendering does.
+    fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        match self.select(ctx)? {
+            Some((case, value)) => {
+                let matching = Matching {
+                    ctx: ctx.variables(),
+                    value: &value,
+                };
+                case.body.validate(&ctx.with_variables(&matching))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        self.cases.iter_mut().map(|case| &mut case.body).collect()
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        let mut args = vec![&self.scrutinee];
+        args.extend(&self.default);
+        args.extend(self.cases.iter().map(|case| &case.body));
+        args
+    }
+
+    fn is_constant(&self) -> bool {
+        constant_arguments(self)
+    }
+
+    /// The longest case body.
+    fn estimated_len(&self) -> usize {
+        self.cases
+            .iter()
+            .map(|case| literal_len(&case.body).unwrap_or(DEFAULT_ESTIMATED_LEN))
+            .max()
+            .unwrap_or(0)
+    }
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch("Switch", |tree| {
+            tree.argument("on", &self.scrutinee);
+            if let Some(default) = &self.default {
+                tree.argument("default", default);
+            }
+
+            for case in &self.cases {
+                let label = match &case.label {
+                    CaseLabel::Value(label) => format!("case {:?}", label),
+                    CaseLabel::Prefix(prefix) => format!("case {:?}*", prefix),
+                    CaseLabel::Comparison(op, bound) => {
+                        format!("case {}{}", op.symbol(), bound)
+                    }
+                    CaseLabel::Default => "case *".to_string(),
+                };
+
+                tree.argument(&label, &case.body);
+            }
+        });
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        source.switch(self)
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Switch {
+            scrutinee: self.scrutinee.clone(),
+            default: self.default.clone(),
+            cases: self
+                .cases
+                .iter()
+                .map(|case| (case.label.clone(), case.body.clone()))
+                .collect(),
+        })
+    }
+}
+
+/// A directive that renders its body once for every item of an array.
+///
+/// Syntax: `{items*'body'}` or `{items*{directive}}`, where `items` is any
+/// term, such as a variable, an index or `.`, and the body is a literal that
+/// may contain directives or a single braced directive, as in a fallback.
+/// Within the body, `{.}` is the current item and `{@index}`, `{@count}`,
+/// `{@first}` and `{@last}` are its position: its index counting from 0, the
+/// number of items, and whether it is the first or the last one. They can be
+/// filtered, as in `{.:upper}`, and used in conditions, as in
+/// `{@last ? '' : ', '}`. An inner loop shadows them.
+///
+/// A body whose directives are all constant, as in `{items*'{\'-\':3} '}`,
+/// references no variable, not even `.` or `@index`, so it is rendered once
+/// and its output written for every item.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{items*'{@index}: {.} '}"
+/// With context: items = ["apple", "pear"]
+/// Produces: "0: apple 1: pear "
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::NotFound` if the variable is missing,
+/// `DirectiveError::TypeError` if it isn't a `Value::Array`, and the error of
+/// the body for any item it fails on.
+#[derive(PartialEq, Eq)]
+pub struct LoopDirective {
+    /// The array to loop over
+    pub items: Argument,
+    /// What to render for every item, a literal or a sub-template
+    pub body: Argument,
+}
+
+impl LoopDirective {
+    /// Resolves the array to loop over.
+    fn items<'c>(&self, ctx: &RenderContext<'c>) -> Result<Cow<'c, Value<'c>>, DirectiveError> {
+        let value = match &self.items {
+            Argument::Variable(name) => {
+                ctx.resolve(name).ok_or_else(|| DirectiveError::NotFound {
+                    name: name.to_string(),
+                    type_name: "array",
+                })?
+            }
+            items => Cow::Owned(items.resolve_value(ctx)?),
+        };
+
+        match &*value {
+            Value::Array(_) => Ok(value),
+            _ => Err(DirectiveError::TypeError {
+                name: match &self.items {
+                    Argument::Variable(name) => name.to_string(),
+                    _ => "value".to_string(),
+                },
+                expected: "array",
+                found: value.type_name().to_string(),
+            }),
+        }
+    }
+
+    /// Calls `each` with the context of the body for every item.
+    fn for_each(
+        &self,
+        ctx: &RenderContext<'_>,
+        mut each: impl FnMut(&RenderContext<'_>) -> Result<(), DirectiveError>,
+    ) -> Result<(), DirectiveError> {
+        let items = self.items(ctx)?;
+        let items = items.as_array().unwrap_or_default();
+
+        for (index, item) in items.iter().enumerate() {
+            let looping = Looping::new(ctx.variables(), item, index, items.len());
+            each(&ctx.with_variables(&looping))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Directive for LoopDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        let mut output = String::new();
+        self.write_to(ctx, &mut output)?;
+
+        Ok(Cow::Owned(output))
+    }
+
+    /// Writes the body for every item, rendering sub-templates straight into
+    /// `out`, except constant ones, which are rendered once.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        if let Argument::Template(_) = &self.body
+            && self.body.is_constant()
+        {
+            let items = self.items(ctx)?;
+            let count = items.as_array().unwrap_or_default().len();
+
+            if count > 0 {
+                let body = render_held(ctx, |held| self.body.write_to(ctx, held))?;
+
+                for _ in 0..count {
+                    body.write_to(ctx, out)?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        self.for_each(ctx, |ctx| self.body.write_to(ctx, out))
+    }
+
+    /// Checks the body for every item, as rendering does.
+    fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        self.for_each(ctx, |ctx| self.body.validate(ctx))
+    }
+
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        vec![&mut self.body]
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        vec![&self.items, &self.body]
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch("Loop", |tree| {
+            tree.argument("over", &self.items);
+            tree.argument("body", &self.body);
+        });
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        source.loop_over(self)
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Loop {
+            items: self.items.clone(),
+            body: self.body.clone(),
+        })
+    }
+}
+
+/// A directive that renders another template, looked up by name when
+/// formatting.
+///
+/// Syntax: `{>name}` or `{>'site/header'}`. The partial is taken from the
+/// `TemplateRegistry` passed to `Template::format_with` and rendered against
+/// the same context.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{>header} Welcome!"
+/// With partial: header = "Hello {name}!"
+/// With context: name = "Ada"
+/// Produces: "Hello Ada! Welcome!"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::PartialNotFound` if no partial has the name, and
+/// `DirectiveError::CyclicInclude` if the partial is already being rendered,
+/// e.g. because it includes itself.
+#[derive(PartialEq, Eq)]
+pub struct IncludeDirective(pub Cow<'static, str>);
+
+impl Directive for IncludeDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        let mut output = String::new();
+        self.write_to(ctx, &mut output)?;
+
+        Ok(Cow::Owned(output))
+    }
+
+    /// Renders the partial straight into `out`.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        if ctx.is_including(&self.0) {
+            return Err(DirectiveError::CyclicInclude {
+                name: self.0.to_string(),
+            });
+        }
+
+        let partial = ctx
+            .partial(&self.0)
+            .ok_or_else(|| DirectiveError::PartialNotFound {
+                name: self.0.to_string(),
+            })?;
+
+        let including = Including {
+            name: &self.0,
+            outer: ctx.including(),
+        };
+
+        partial.render(&ctx.with_including(&including), &MissingPolicy::Error, out)
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf(format!("Include {:?}", self.0));
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        Some(format!(">{}", source.word(&self.0)))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Include(self.0.clone()))
+    }
+}
+
+/// The width of a tab stop, for `advance_column`.
+const TAB_WIDTH: usize = 8;
+
+/// Returns the column the output is at after writing `text` at `column`:
+/// the number of chars since the last line break, with tabs moving to the
+/// next multiple of 8.
+pub(crate) fn advance_column(column: usize, text: &str) -> usize {
+    let (column, line) = match text.rfind('\n') {
+        Some(newline) => (0, &text[newline + 1..]),
+        None => (column, text),
+    };
+
+    if !line.bytes().any(|b| b == b'\t' || !b.is_ascii()) {
+        return column + line.len();
+    }
+
+    line.chars().fold(column, |column, c| match c {
+        '\t' => (column / TAB_WIDTH + 1) * TAB_WIDTH,
+        _ => column + 1,
+    })
+}
+
+/// A directive that pads the current output line with spaces up to a column.
+///
+/// Syntax: `{@col(n)}`. The column is the number of chars written since the
+/// last line break, counting a tab as reaching the next multiple of 8, so
+/// text after `{@col(20)}` starts at column 20 whatever was written before
+/// it on the line. Nothing is written if the line is already that long.
+/// Every char counts as one column, including wide ones such as CJK.
+///
+/// The column is tracked across the whole output, conditional branches and
+/// partials included, starting at column 0 even when rendering into a writer
+/// that already holds text. Output that is not written straight to the
+/// template's output, such as the input of a filter, starts at column 0.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{name}{@col(10)}{role}"
+/// With context: name = "Ada", role = "admin"
+/// Produces: "Ada       admin"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::OutputLimitExceeded` if the padding would be
+/// longer than `FormatOptions::max_output_len`.
+#[derive(PartialEq, Eq)]
+pub struct ColumnDirective(pub usize);
+
+impl ColumnDirective {
+    /// Returns how many spaces pad the line to the column, failing if that
+    /// is more than the output limit.
+    fn padding(&self, ctx: &RenderContext<'_>) -> Result<usize, DirectiveError> {
+        let padding = self.0.saturating_sub(ctx.column());
+        let limit = ctx.max_output_len();
+
+        if padding > limit {
+            return Err(DirectiveError::OutputLimitExceeded { limit });
+        }
+
+        Ok(padding)
+    }
+}
+
+impl Directive for ColumnDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        Ok(Cow::Owned(" ".repeat(self.padding(ctx)?)))
+    }
+
+    /// Writes the padding without allocating.
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        const SPACES: &str = "                                ";
+        let mut padding = self.padding(ctx)?;
+
+        while padding > 0 {
+            let len = padding.min(SPACES.len());
+            out.write_str(&SPACES[..len])?;
+            padding -= len;
+        }
+
+        Ok(())
+    }
+
+    /// The padding is at most the column, and longer padding than the
+    /// default output limit is not worth reserving for.
+    fn estimated_len(&self) -> usize {
+        self.0.min(FormatOptions::DEFAULT_MAX_OUTPUT_LEN)
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf(format!("Column {}", self.0));
+    }
+
+    fn to_source(&self, _source: &SourceWriter) -> Option<String> {
+        Some(format!("@col({})", self.0))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Column(self.0))
+    }
+}
+
+/// A directive that marks the start of a named region of a template.
+///
+/// Syntax: `{@region:name}`, closed by `{@end}` or `{@end:name}`. Regions
+/// render nothing themselves; they delimit the part of a template that
+/// `Template::format_region` renders on its own. Names are identifiers or
+/// quoted literals, `{@region:'side bar'}`.
+///
+/// Regions can nest but not overlap, and each name can only be used once
+/// per template: compiling fails otherwise. A closing `{@end:name}` states
+/// which region it closes, so `{@region:a}{@region:b}{@end:a}` is an error.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "<body>{@region:header}<h1>{title}</h1>{@end}</body>"
+/// format_region("header") with: title = "News"
+/// Produces: "<h1>News</h1>"
+/// ```
+#[derive(PartialEq, Eq)]
+pub struct RegionDirective(pub Cow<'static, str>);
+
+impl Directive for RegionDirective {
+    fn exec(&self, _ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        Ok(Cow::Borrowed(""))
+    }
+
+    fn estimated_len(&self) -> usize {
+        0
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.leaf(format!("Region {:?}", self.0));
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        Some(format!("@region:{}", source.word(&self.0)))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Region(self.0.clone()))
+    }
+}
+
+/// A directive that marks the end of the innermost open region, see
+/// `RegionDirective`.
+///
+/// Syntax: `{@end}`, or `{@end:name}` to name the region it closes.
+#[derive(PartialEq, Eq)]
+pub struct RegionEndDirective(pub Option<Cow<'static, str>>);
+
+impl Directive for RegionEndDirective {
+    fn exec(&self, _ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        Ok(Cow::Borrowed(""))
+    }
+
+    fn estimated_len(&self) -> usize {
+        0
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        match &self.0 {
+            Some(name) => tree.leaf(format!("End {:?}", name)),
+            None => tree.leaf("End"),
+        }
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        match &self.0 {
+            Some(name) => Some(format!("@end:{}", source.word(name))),
+            None => Some(String::from("@end")),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::RegionEnd(self.0.clone()))
+    }
+}
+
+/// A directive that replaces the error of another directive with a message
+/// written by the template's author.
+///
+/// Syntax: `{directive !! "message"}`, where `directive` is anything that can
+/// appear between the delimiters, e.g. `{price !! "price is required"}` or
+/// `{total:currency(USD) !! 'total must be a number'}`. The wrapped
+/// directive renders unchanged; only its errors are replaced.
+///
+/// Missing variables still follow `FormatOptions::missing`, so with a
+/// lenient `MissingPolicy` the message only shows for other errors.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{price !! 'price is required'}"
+/// With context: (empty)
+/// Error: price is required
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::CustomMessage`, holding the message and the
+/// wrapped directive's error, if the wrapped directive fails.
+pub struct ErrorMessageDirective {
+    /// The directive whose errors are replaced
+    pub directive: Box<dyn Directive>,
+    /// The message reported instead
+    pub message: Cow<'static, str>,
+}
+
+impl PartialEq for ErrorMessageDirective {
+    fn eq(&self, other: &Self) -> bool {
+        *self.directive == *other.directive && self.message == other.message
+    }
+}
+
+impl Eq for ErrorMessageDirective {}
+
+impl ErrorMessageDirective {
+    /// Wraps an error of the inner directive with the message.
+    fn wrap(&self, error: DirectiveError) -> DirectiveError {
+        DirectiveError::CustomMessage {
+            message: self.message.to_string(),
+            source: Box::new(error),
+        }
+    }
+}
+
+impl Directive for ErrorMessageDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        self.directive.exec(ctx).map_err(|e| self.wrap(e))
+    }
+
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        match self.directive.write_to(ctx, out) {
+            // Failing to write is the sink's problem, not the template's
+            Err(DirectiveError::WriteError(e)) => Err(DirectiveError::WriteError(e)),
+            result => result.map_err(|e| self.wrap(e)),
+        }
+    }
+
+    fn literal(&self) -> Option<&str> {
+        self.directive.literal()
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        self.directive.arguments()
+    }
+
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        self.directive.nested_arguments_mut()
+    }
+
+    fn is_constant(&self) -> bool {
+        self.directive.is_constant()
+    }
+
+    fn estimated_len(&self) -> usize {
+        self.directive.estimated_len()
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch(format!("ErrorMessage {:?}", self.message), |tree| {
+            self.directive.write_tree(tree);
+        });
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        Some(format!(
+            "{} !! {}",
+            self.directive.to_source(source)?,
+            source.quote(&self.message)
+        ))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::ErrorMessage {
+            directive: Box::new(self.directive.to_serialized()?),
+            message: self.message.clone(),
+        })
+    }
+}
+
+/// A directive that renders a fallback when another directive references a
+/// missing variable.
+///
+/// Syntax: `{directive | fallback}`, where the fallback is a literal or a
+/// directive in braces, e.g. `{nick | 'anonymous'}` or `{nick | {name}}`.
+/// Braced fallbacks can have fallbacks of their own, and a chain such as
+/// `{nick | {name} | 'anonymous'}` tries each in turn. Filters before the
+/// fallback only apply to the wrapped directive:
+/// `{bio | trunc(20) | 'No bio'}`.
+///
+/// Only missing variables fall back; other errors, such as type errors, are
+/// returned as they are. A variable set to `Null` is not missing.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "Hi {nick | {name}}!"
+/// With context: name = "Ada"
+/// Produces: "Hi Ada!"
+/// ```
+///
+/// # Errors
+///
+/// Returns the wrapped directive's error if it is not a missing variable,
+/// or the fallback's error.
+pub struct FallbackDirective {
+    /// The directive rendered if it doesn't reference a missing variable
+    pub directive: Box<dyn Directive>,
+    /// The value rendered otherwise. Braced fallbacks are sub-templates
+    /// holding a single directive.
+    pub fallback: Argument,
+}
+
+impl PartialEq for FallbackDirective {
+    fn eq(&self, other: &Self) -> bool {
+        *self.directive == *other.directive && self.fallback == other.fallback
+    }
+}
+
+impl Eq for FallbackDirective {}
+
+impl Directive for FallbackDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        match self.directive.exec(ctx) {
+            Err(e) if e.is_not_found() => self.fallback.render(ctx),
+            result => result,
+        }
+    }
+
+    fn write_to(
+        &self,
+        ctx: &RenderContext<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        // Held back, so a variable missing part way through doesn't leave
+        // the output written before it in front of the fallback.
+        match render_held(ctx, |held| self.directive.write_to(ctx, held)) {
+            Ok(held) => held.write_to(ctx, out),
+            Err(e) if e.is_not_found() => self.fallback.write_to(ctx, out),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn literal(&self) -> Option<&str> {
+        self.directive.literal()
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        let mut args = self.directive.arguments();
+        args.push(&self.fallback);
+        args
+    }
+
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        let mut args = self.directive.nested_arguments_mut();
+        args.extend(parsed_arguments_mut(&mut self.fallback));
+        args
+    }
+
+    /// Constant directives never reference a variable, so the fallback is
+    /// never rendered.
+    fn is_constant(&self) -> bool {
+        self.directive.is_constant()
+    }
+
+    fn estimated_len(&self) -> usize {
+        self.directive.estimated_len()
+    }
+
+    fn write_tree(&self, tree: &mut DebugTree) {
+        tree.branch("Fallback", |tree| {
+            self.directive.write_tree(tree);
+            tree.argument("or", &self.fallback);
+        });
+    }
+
+    fn to_source(&self, source: &SourceWriter) -> Option<String> {
+        Some(format!(
+            "{} | {}",
+            self.directive.to_source(source)?,
+            source.branch(&self.fallback)?
+        ))
+    }
+
+    fn same_as(&self, other: &dyn Directive) -> bool {
+        same_directive(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedDirective> {
+        Some(SerializedDirective::Fallback {
+            directive: Box::new(self.directive.to_serialized()?),
+            fallback: self.fallback.clone(),
+        })
     }
 }
@@ -1,8 +1,12 @@
-//! Error types for template directive execution.
+//! Error types for template compilation and directive execution.
 //!
-//! This module defines the errors that can occur during template rendering,
-//! particularly when resolving arguments and executing directives.
+//! This module defines the errors that can occur while compiling a template,
+//! and during template rendering, particularly when resolving arguments and
+//! executing directives.
 
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// Errors that can occur during directive execution.
@@ -17,8 +21,8 @@ use thiserror::Error;
 /// use figura::{Template, DefaultParser, Context, Value, DirectiveError};
 /// use std::collections::HashMap;
 ///
-/// let tmpl = Template::<'{', '}'>::compile::<DefaultParser>("{missing}").unwrap();
-/// let ctx = HashMap::new();
+/// let tmpl = Template::<'{', '}'>::compile_with_parser::<DefaultParser>("{missing}").unwrap();
+/// let ctx: HashMap<&str, Value> = HashMap::new();
 ///
 /// match tmpl.format(&ctx) {
 ///     Err(DirectiveError::NotFound { name, .. }) => {
@@ -29,6 +33,7 @@ use thiserror::Error;
 /// ```
 #[derive(Debug)]
 #[derive(Error)]
+#[non_exhaustive]
 pub enum DirectiveError {
     /// A variable was not found in the template context.
     ///
@@ -104,13 +109,544 @@ pub enum DirectiveError {
         type_name: &'static str,
         message: String,
     },
+
+    /// A filter was applied to a value of a type it does not support.
+    ///
+    /// This error occurs when a filter that only makes sense for some types,
+    /// such as a number base filter, receives a value of another type.
+    ///
+    /// # Fields
+    ///
+    /// * `filter` - The name of the filter (e.g., "hex")
+    /// * `expected` - The type the filter accepts (e.g., "integer")
+    /// * `found` - The type of the value it received (e.g., "float")
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{ratio:hex}"
+    /// Context: ratio = 0.5
+    /// Error: Filter 'hex' expected a value of type 'integer' but got 'float'
+    /// ```
+    #[error("Filter '{filter}' expected a value of type '{expected}' but got '{found}'")]
+    FilterTypeError {
+        filter: &'static str,
+        expected: &'static str,
+        found: &'static str,
+    },
+
+    /// A built-in function was called with arguments it does not accept.
+    ///
+    /// This error occurs when a function such as `max` receives a value that
+    /// is not a number, or the wrong number of arguments.
+    ///
+    /// # Fields
+    ///
+    /// * `function` - The name of the function (e.g., "max")
+    /// * `expected` - What the function accepts (e.g., "a number")
+    /// * `found` - What it received (e.g., "a value of type 'string'")
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{max(a, b)}"
+    /// Context: a = 1, b = "two"
+    /// Error: Function 'max' expected a number but got a value of type 'string'
+    /// ```
+    #[error("Function '{function}' expected {expected} but got {found}")]
+    FunctionArgumentError {
+        function: &'static str,
+        expected: &'static str,
+        found: String,
+    },
+
+    /// A filter in a chain of several filters failed.
+    ///
+    /// This error wraps the failure of one stage of a pipeline such as
+    /// `{name | trunc(20) | hex}`, so that a filter used more than once can
+    /// be told apart. A directive with a single filter returns the filter's
+    /// error as-is.
+    ///
+    /// # Fields
+    ///
+    /// * `position` - The position of the failing filter, starting at 1
+    /// * `filter` - The description of the failing filter (e.g., "hex")
+    /// * `source` - The error returned by the filter
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{name | trunc(20) | hex}"
+    /// Context: name = "Alice"
+    /// Error: Filter 2 in the chain (hex) failed: Filter 'hex' expected a value of type 'integer' but got 'string'
+    /// ```
+    #[error("Filter {position} in the chain ({filter}) failed: {source}")]
+    FilterChainError {
+        position: usize,
+        filter: String,
+        source: Box<Self>,
+    },
+
+    /// A directive with a custom error message failed.
+    ///
+    /// This error replaces the error of a directive written with a message,
+    /// such as `{price !! "price is required"}`. It displays only the
+    /// message; the original error is kept as its source.
+    ///
+    /// # Fields
+    ///
+    /// * `message` - The message written in the template
+    /// * `source` - The error returned by the directive
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{price !! 'price is required'}"
+    /// Context: (empty)
+    /// Error: price is required
+    /// ```
+    #[error("{message}")]
+    CustomMessage { message: String, source: Box<Self> },
+
+    /// An include referenced a partial that is not in the registry.
+    ///
+    /// This error occurs when rendering `{>name}` without a partial called
+    /// `name`, including when the template is rendered without a registry.
+    ///
+    /// # Fields
+    ///
+    /// * `name` - The name of the missing partial
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{>header}"
+    /// Registry: (empty)
+    /// Error: Partial 'header' was not found in the registry
+    /// ```
+    #[error("Partial '{name}' was not found in the registry")]
+    PartialNotFound { name: String },
+
+    /// `Template::format_region` was asked for a region the template doesn't
+    /// mark.
+    ///
+    /// # Fields
+    ///
+    /// * `name` - The name of the missing region
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{@region:header}{title}{@end}"
+    /// Region: "footer"
+    /// Error: Region 'footer' was not found in the template
+    /// ```
+    #[error("Region '{name}' was not found in the template")]
+    RegionNotFound { name: String },
+
+    /// A partial includes itself, directly or through other partials.
+    ///
+    /// # Fields
+    ///
+    /// * `name` - The partial that was included while already being rendered
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Partials: a = "{>b}", b = "{>a}"
+    /// Template: "{>a}"
+    /// Error: Partial 'a' includes itself
+    /// ```
+    #[error("Partial '{name}' includes itself")]
+    CyclicInclude { name: String },
+
+    /// A directive the parser didn't recognize was rendered with
+    /// `UnknownPolicy::Error`, the default.
+    ///
+    /// # Fields
+    ///
+    /// * `directive` - The directive's source text, delimiters included
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{name:bogus(1)}"
+    /// Error: Unknown directive '{name:bogus(1)}'
+    /// ```
+    #[error("Unknown directive '{directive}'")]
+    UnknownDirective { directive: String },
+
+    /// The rendered output could not be written to its destination.
+    ///
+    /// This error only occurs when rendering into a sink that can fail, such
+    /// as a writer passed to `Template::format_to`. Rendering into a `String`
+    /// never fails this way.
+    #[error("Failed to write the rendered output")]
+    WriteError(#[from] std::fmt::Error),
+
+    /// The rendered output grew past the configured limit.
+    ///
+    /// This error occurs when rendering would produce more than
+    /// `FormatOptions::max_output_len` bytes, e.g. because of a huge repeat
+    /// count. Rendering stops before the limit is crossed.
+    ///
+    /// # Fields
+    ///
+    /// * `limit` - The maximum output length, in bytes
+    #[error("The rendered output exceeds the limit of {limit} bytes")]
+    OutputLimitExceeded { limit: usize },
+
+    /// Rendering evaluated more directives than the configured limit.
+    ///
+    /// This error occurs when rendering would evaluate more than
+    /// `FormatOptions::max_directives` directives, counting those nested in
+    /// branches and included partials. Rendering stops before evaluating
+    /// the directive past the limit.
+    ///
+    /// # Fields
+    ///
+    /// * `limit` - The maximum number of directives
+    #[error("Rendering evaluates more than the limit of {limit} directives")]
+    DirectiveLimitExceeded { limit: usize },
+
+    /// Integer arithmetic produced a result out of range.
+    ///
+    /// This error occurs when a sum of integers or `abs` doesn't fit an
+    /// `i64`, as in `{n + 1}` with `n = i64::MAX`, or when a repeat count
+    /// doesn't fit a `usize`. Results are never wrapped around or clamped.
+    ///
+    /// # Fields
+    ///
+    /// * `expression` - The operation that overflowed, with its operands
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{n + 1}"
+    /// Context: n = 9223372036854775807
+    /// Error: Arithmetic overflow in '9223372036854775807 + 1'
+    /// ```
+    #[error("Arithmetic overflow in '{expression}'")]
+    ArithmeticOverflow { expression: String },
+
+    /// An array was indexed past its end.
+    ///
+    /// This error occurs when `items[i]` is rendered with `i` at least the
+    /// number of items, or with a negative `i` counting back past the first
+    /// item. Like a missing variable, it is rendered as `MissingPolicy`
+    /// says and replaced by fallbacks.
+    ///
+    /// # Fields
+    ///
+    /// * `name` - The name of the array
+    /// * `index` - The index, as written or resolved
+    /// * `len` - The number of items in the array
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// Template: "{items[3]}"
+    /// Context: items = ["a", "b"]
+    /// Error: Index 3 is out of range for 'items', which has 2 items
+    /// ```
+    #[error("Index {index} is out of range for '{name}', which has {len} items")]
+    IndexOutOfRange {
+        name: String,
+        index: i64,
+        len: usize,
+    },
+}
+
+impl DirectiveError {
+    /// Returns whether this error is a missing variable or array item,
+    /// possibly behind a custom message, to which `MissingPolicy` applies.
+    pub(crate) fn is_not_found(&self) -> bool {
+        match self {
+            Self::NotFound { .. } | Self::IndexOutOfRange { .. } => true,
+            Self::CustomMessage { source, .. } => source.is_not_found(),
+            _ => false,
+        }
+    }
 }
 
+/// Errors that can occur while compiling a template.
+///
+/// These errors report problems with the template source itself, such as an
+/// unclosed directive or one the parser rejects, as opposed to the
+/// `DirectiveError`s reported while rendering. `Template::compile_all_errors`
+/// pairs each with the span of the offending directive.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum TemplateError {
-    #[error("Unclosed delimiter '{0}'")]
-    MissingDelimiter(char),
+    /// A directive is opened but never closed, counting the directives
+    /// nested in it, as in `{a ? {b} : c`. `position` is the byte offset of
+    /// its opening delimiter in the template, nested sub-templates included,
+    /// and `delimiter` the missing closing delimiter.
+    #[error("Unclosed delimiter '{delimiter}' for the directive at byte {position}")]
+    MissingDelimiter { position: usize, delimiter: String },
 
+    /// The parser rejected a directive. Holds the directive's contents,
+    /// without its delimiters.
     #[error("Failed to parse directive: {0}")]
     DirectiveParsing(String),
+
+    /// Directives are nested deeper than `CompileOptions::max_depth`, which
+    /// this holds, or a directive holds more `?` than that, as a long
+    /// else-if ladder does, or nests its parentheses deeper.
+    #[error("Directives are nested deeper than the limit of {0}")]
+    NestingTooDeep(usize),
+
+    /// A delimiter can't be told apart from the contents of directives,
+    /// e.g. a letter or a digit. See `Template::validate_delimiters`.
+    #[error("'{delimiter}' can't be used as a delimiter: {reason}")]
+    InvalidDelimiter {
+        delimiter: String,
+        reason: &'static str,
+    },
+
+    /// A character set in `CompileOptions::markers` can't mark conditionals
+    /// and switches, e.g. a letter or a marker used twice.
+    #[error("'{marker}' can't be used as a marker: {reason}")]
+    InvalidMarker { marker: char, reason: &'static str },
+
+    /// A region, `{@region:name}`, is never closed, reuses the name of
+    /// another region, or is closed inside another region, which would make
+    /// them overlap. A closing `{@end:name}` of a region that was never
+    /// opened is reported the same way.
+    #[error("Region '{name}' {reason}")]
+    InvalidRegion { name: String, reason: &'static str },
+
+    /// A `{@end}` closes no region, since none is open.
+    #[error("'@end' without an open region")]
+    UnopenedRegionEnd,
+
+    /// A switch case can never be selected, because an earlier case has the
+    /// same label or is a default. Only reported when
+    /// `CompileOptions::deny_unreachable_cases` is on. Positions count the
+    /// cases of the switch from 1.
+    #[error("Switch case {position}, '{label}', is unreachable: case {shadowed_by} {reason}")]
+    UnreachableCase {
+        label: String,
+        position: usize,
+        shadowed_by: usize,
+        reason: &'static str,
+    },
+
+    /// A `\u{...}` escape in literal text is not a valid Unicode scalar
+    /// value, e.g. `\u{}`, `\u{zz}` or `\u{d800}`. Only reported when
+    /// `CompileOptions::text_escapes` is on. `position` is the byte offset of
+    /// the backslash.
+    #[error("Invalid escape '{escape}' at byte {position}")]
+    InvalidEscape { position: usize, escape: String },
+
+    /// A directive holds nothing but whitespace, as in `{}` or `{   }`. Only
+    /// reported with the default `CompileOptions::empty_directives`,
+    /// `EmptyPolicy::Error`. `position` is the byte offset of its opening
+    /// delimiter in the template, nested sub-templates included.
+    #[error("Empty directive at byte {position}")]
+    EmptyDirective { position: usize },
+
+    /// A parenthesis in the condition of a directive is never closed, or
+    /// closes none that is open, as in `{(a || b ? x}`. `position` is the byte offset of the
+    /// parenthesis in the template, nested sub-templates included.
+    #[error("Unmatched '{paren}' at byte {position}")]
+    UnbalancedParenthesis { position: usize, paren: char },
+
+    /// Template source given as bytes, as to `Template::compile_bytes`, is
+    /// not valid UTF-8. `offset` is the byte offset of the first invalid
+    /// sequence.
+    #[error("Invalid UTF-8 at byte {offset}")]
+    InvalidUtf8 { offset: usize },
+
+    /// The pattern of a regex match (`=~`) is not a valid regular expression.
+    /// `position` is the byte offset of the directive in the template, nested
+    /// sub-templates included. Only available with the `regex` feature.
+    #[cfg(feature = "regex")]
+    #[error("Invalid regex '{pattern}' in the directive at byte {position}: {message}")]
+    InvalidRegex {
+        position: usize,
+        pattern: String,
+        message: String,
+    },
+}
+
+impl TemplateError {
+    /// Moves the byte offset this error reports to `position(offset)`, to
+    /// report the errors of a sub-template at their place in the template.
+    pub(crate) fn map_position(self, position: impl Fn(usize) -> usize) -> Self {
+        match self {
+            Self::MissingDelimiter {
+                position: p,
+                delimiter,
+            } => Self::MissingDelimiter {
+                position: position(p),
+                delimiter,
+            },
+            Self::InvalidEscape {
+                position: p,
+                escape,
+            } => Self::InvalidEscape {
+                position: position(p),
+                escape,
+            },
+            Self::EmptyDirective { position: p } => Self::EmptyDirective {
+                position: position(p),
+            },
+            Self::UnbalancedParenthesis { position: p, paren } => Self::UnbalancedParenthesis {
+                position: position(p),
+                paren,
+            },
+            #[cfg(feature = "regex")]
+            Self::InvalidRegex {
+                position: p,
+                pattern,
+                message,
+            } => Self::InvalidRegex {
+                position: position(p),
+                pattern,
+                message,
+            },
+            error => error,
+        }
+    }
+}
+
+/// Any error of this crate, either from compiling or from rendering a
+/// template.
+///
+/// Both steps return their own error type, so call sites can tell syntax
+/// errors from rendering failures such as missing variables. This enum lets
+/// code that does both propagate either with `?` and still match on the
+/// specific variant.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, DirectiveError, Error, Template};
+///
+/// fn render(source: &str, ctx: &Context) -> Result<String, Error> {
+///     Ok(Template::<'{', '}'>::compile(source)?.format(ctx)?)
+/// }
+///
+/// match render("Hi {name}", &Context::new()) {
+///     Err(Error::Directive(DirectiveError::NotFound { name, .. })) => assert_eq!(name, "name"),
+///     other => panic!("unexpected {:?}", other),
+/// }
+/// ```
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The template failed to compile
+    #[error(transparent)]
+    Template(#[from] TemplateError),
+    /// The template failed to render
+    #[error(transparent)]
+    Directive(#[from] DirectiveError),
+}
+
+/// The error of converting a `Value` into a Rust primitive with `TryFrom`,
+/// when the value has another type.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Value, ValueTypeError};
+///
+/// let err = i64::try_from(Value::static_str("42")).unwrap_err();
+/// assert_eq!(err, ValueTypeError { expected: "integer", found: "string" });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Expected a value of type '{expected}' but got '{found}'")]
+pub struct ValueTypeError {
+    /// The type converted into, named as by `Value::type_name`
+    pub expected: &'static str,
+    /// The type of the value, from `Value::type_name`
+    pub found: &'static str,
+}
+
+/// A compile error together with the part of the source it occurred in, as
+/// returned by `Template::compile_all_errors`.
+#[derive(Debug, Error)]
+#[error("{error} (at bytes {}..{})", span.start, span.end)]
+pub struct SpannedError {
+    /// The byte range of the offending directive, delimiters included. For
+    /// errors inside a nested sub-template, the enclosing directive.
+    pub span: Range<usize>,
+    /// The error itself
+    pub error: TemplateError,
+}
+
+/// A rendering error together with the part of the source it occurred in, as
+/// returned by `Template::format_spanned`.
+#[derive(Debug, Error)]
+#[error("{error} (at bytes {}..{})", span.start, span.end)]
+pub struct FormatError {
+    /// The byte range of the directive that failed, delimiters included. For
+    /// errors inside a nested sub-template or a partial, the enclosing
+    /// directive. Empty for templates without a source, such as built ones.
+    pub span: Range<usize>,
+    /// The error itself
+    pub error: DirectiveError,
+    /// The source of the template, if it was compiled from one
+    pub(crate) input: Option<Arc<str>>,
+}
+
+impl FormatError {
+    /// Renders the error as a report pointing at the directive that failed.
+    ///
+    /// The report shows the line of the source the directive starts on, with
+    /// carets under the directive:
+    ///
+    /// ```text
+    /// error: Variable 'name' was not found in the context while being used as 'string'
+    ///  --> 2:7
+    ///   |
+    /// 2 | Hello {name}!
+    ///   |       ^^^^^^
+    /// ```
+    ///
+    /// Templates without a source, such as deserialized ones, only report the
+    /// error and its byte span.
+    pub fn diagnostic(&self) -> String {
+        let mut report = format!("error: {}", self.error);
+        let span = &self.span;
+
+        let Some(input) = self
+            .input
+            .as_deref()
+            .filter(|input| !span.is_empty() && input.get(span.clone()).is_some())
+        else {
+            if !span.is_empty() {
+                let _ = write!(report, "\n --> bytes {}..{}", span.start, span.end);
+            }
+            return report;
+        };
+
+        let line_start = input[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[span.start..]
+            .find('\n')
+            .map_or(input.len(), |i| span.start + i);
+        let line = input[line_start..line_end].trim_end_matches('\r');
+        let line_number = input[..line_start].matches('\n').count() + 1;
+
+        // Tabs are kept under the line, so that the carets stay aligned
+        let padding: String = input[line_start..span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = input[span.start..span.end.min(line_start + line.len())]
+            .chars()
+            .count()
+            .max(1);
+
+        let gutter = " ".repeat(line_number.to_string().len());
+        let _ = write!(
+            report,
+            "\n{gutter}--> {line_number}:{}\n{gutter} |\n{line_number} | {line}\n{gutter} | {padding}{}",
+            padding.chars().count() + 1,
+            "^".repeat(width),
+        );
+
+        report
+    }
 }
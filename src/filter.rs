@@ -0,0 +1,2408 @@
+//! Filters that transform resolved values before they are rendered.
+//!
+//! A filter is attached to a directive argument with a `:` spec, e.g. the
+//! alignment in `{name:<10}`. When the directive is executed, the argument is
+//! resolved to a `Value` and passed through each filter in order.
+
+#[cfg(feature = "serde")]
+use crate::SerializedFilter;
+use crate::{FormatOptions, NumberFormat, RenderContext, Value, err::DirectiveError};
+use std::any::Any;
+use std::borrow::Cow;
+
+/// A transformation applied to a resolved value.
+///
+/// Filters receive the value produced by the directive argument (or by the
+/// previous filter) and return the value that should be rendered instead.
+/// Like directives, filters must be `Send + Sync`, and `'static` so that
+/// they can be compared, see `Filter::same_as`. A filter returns a value
+/// of the lifetime it was given, so it can pass its input on, or text
+/// borrowed from it, without copying.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Filter, DirectiveError, Value};
+///
+/// struct Exclaim;
+///
+/// impl Filter for Exclaim {
+///     fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+///         match value {
+///             Value::Str(s) => Ok(Value::owned_str(format!("{}!", s))),
+///             other => Ok(other),
+///         }
+///     }
+/// }
+/// ```
+pub trait Filter: Any + Send + Sync {
+    /// Applies this filter to a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to transform
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Value)` - The transformed value
+    /// * `Err(DirectiveError)` - If the value cannot be transformed
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError>;
+
+    /// Transforms a value, writing numbers it turns into text with `format`.
+    ///
+    /// Templates call this method with `FormatOptions::number_format`. The
+    /// default implementation ignores the format and calls `apply`, which
+    /// suits filters that never render numbers.
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let _ = format;
+        self.apply(value)
+    }
+
+    /// Transforms a value while rendering a template, with the options of
+    /// the render in `ctx`.
+    ///
+    /// Templates call this method. The default implementation calls
+    /// `apply_with_format` with `RenderContext::number_format`. Filters whose
+    /// output can grow far beyond their input, such as padding to a width,
+    /// override it to fail with `DirectiveError::OutputLimitExceeded` past
+    /// `RenderContext::max_output_len` before building the output.
+    fn apply_in<'v>(
+        &self,
+        value: Value<'v>,
+        ctx: &RenderContext<'_>,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, ctx.number_format())
+    }
+
+    /// Returns the name of this filter, the one its errors report, such as
+    /// `"trunc"` for `trunc(80)` or `"align"` for an alignment spec such as
+    /// `>8`. `Template::filters_used` lists templates' filters by it.
+    ///
+    /// The default implementation returns `"<filter>"`, like `describe`.
+    fn name(&self) -> &str {
+        "<filter>"
+    }
+
+    /// Describes this filter for `Template::debug_tree`, ideally as the spec
+    /// it is written with in a template, e.g. `trunc(80, "…")`.
+    fn describe(&self) -> String {
+        String::from("<filter>")
+    }
+
+    /// Returns whether this filter is structurally equal to `other`, which
+    /// backs `PartialEq` for templates.
+    ///
+    /// The default implementation compares filters of the same type by their
+    /// `describe` output. The built-in filters compare their fields.
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        Any::type_id(self) == (other as &dyn Any).type_id() && self.describe() == other.describe()
+    }
+
+    /// Converts this filter into its serializable form, or `None` if it can't
+    /// be serialized. Only available with the `serde` feature.
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        None
+    }
+}
+
+impl PartialEq for dyn Filter {
+    fn eq(&self, other: &Self) -> bool {
+        self.same_as(other)
+    }
+}
+
+impl Eq for dyn Filter {}
+
+/// Returns whether `other` is a `T` equal to `filter`, for `Filter::same_as`.
+fn same_filter<T: Filter + PartialEq>(filter: &T, other: &dyn Filter) -> bool {
+    (other as &dyn Any).downcast_ref::<T>() == Some(filter)
+}
+
+/// Converts a value to the string that would be rendered for it, with the
+/// separators of `format`.
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes`, which have
+/// no string form until they are encoded, and `Value::Array`.
+fn stringify<'v>(
+    value: &Value<'v>,
+    filter: &'static str,
+    format: NumberFormat,
+) -> Result<Cow<'v, str>, DirectiveError> {
+    format
+        .render(value)
+        .ok_or_else(|| DirectiveError::FilterTypeError {
+            filter,
+            expected: "string",
+            found: value.type_name(),
+        })
+}
+
+/// The error of a filter that only renders finite numbers, such as
+/// `currency`, applied to `value`. NaN and infinite floats are reported as
+/// needing a finite number rather than as the wrong type.
+fn not_a_finite_number(filter: &'static str, value: &Value<'_>) -> DirectiveError {
+    match value {
+        Value::Float(_) => DirectiveError::FilterTypeError {
+            filter,
+            expected: "finite number",
+            found: "non-finite float",
+        },
+        _ => DirectiveError::FilterTypeError {
+            filter,
+            expected: "number",
+            found: value.type_name(),
+        },
+    }
+}
+
+/// The side a value is aligned to when padded to a fixed width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Alignment {
+    /// Left alignment: `<`
+    Left,
+    /// Right alignment: `>`
+    Right,
+    /// Centered alignment: `^`
+    ///
+    /// When the padding cannot be split evenly, the extra fill character
+    /// goes on the right.
+    Center,
+}
+
+/// A filter that pads a value to a fixed width.
+///
+/// Syntax: `{value:[fill]align width}` where `align` is one of `<`, `>` or `^`
+/// and `fill` is an optional single character (a space by default). The
+/// named forms `{value:pad_start(width, fill)}` and
+/// `{value:pad_end(width, fill)}`, where the fill is optional too, pad the
+/// start or the end like `>` and `<` do.
+///
+/// The width is measured in `char`s of the rendered value, not in bytes or
+/// grapheme clusters. Values that are already as wide as (or wider than) the
+/// requested width are rendered unchanged; they are never truncated.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "[{name:<8}]"   With: name = "Bob"   Produces: "[Bob     ]"
+/// Template: "[{name:>8}]"   With: name = "Bob"   Produces: "[     Bob]"
+/// Template: "[{name:*^8}]"  With: name = "Bob"   Produces: "[**Bob***]"
+/// Template: "{id:pad_start(6, 0)}"   With: id = 42   Produces: "000042"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`, and `DirectiveError::OutputLimitExceeded` if the padded
+/// value would be longer than `FormatOptions::max_output_len` bytes while
+/// rendering, or `FormatOptions::DEFAULT_MAX_OUTPUT_LEN` when applied on its
+/// own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlignFilter {
+    /// Where the value is placed within the padded field
+    pub align: Alignment,
+    /// The minimum width of the field, in chars
+    pub width: usize,
+    /// The character used for padding
+    pub fill: char,
+}
+
+impl AlignFilter {
+    /// Pads a value, failing if the result would be longer than `limit`
+    /// bytes.
+    fn pad<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+        limit: usize,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "align", format)?;
+        let len = s.chars().count();
+
+        if len >= self.width {
+            return Ok(Value::Str(s));
+        }
+
+        let padding = self.width - len;
+        let padded_len = padding
+            .checked_mul(self.fill.len_utf8())
+            .and_then(|fill| fill.checked_add(s.len()))
+            .filter(|&padded_len| padded_len <= limit)
+            .ok_or(DirectiveError::OutputLimitExceeded { limit })?;
+
+        let (left, right) = match self.align {
+            Alignment::Left => (0, padding),
+            Alignment::Right => (padding, 0),
+            Alignment::Center => (padding / 2, padding - padding / 2),
+        };
+
+        let mut out = String::with_capacity(padded_len);
+
+        out.extend(std::iter::repeat_n(self.fill, left));
+        out.push_str(&s);
+        out.extend(std::iter::repeat_n(self.fill, right));
+
+        Ok(Value::owned_str(out))
+    }
+}
+
+impl Filter for AlignFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.pad(value, format, FormatOptions::DEFAULT_MAX_OUTPUT_LEN)
+    }
+
+    fn apply_in<'v>(
+        &self,
+        value: Value<'v>,
+        ctx: &RenderContext<'_>,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.pad(value, ctx.number_format(), ctx.max_output_len())
+    }
+
+    fn name(&self) -> &str {
+        "align"
+    }
+
+    fn describe(&self) -> String {
+        let align = match self.align {
+            Alignment::Left => '<',
+            Alignment::Right => '>',
+            Alignment::Center => '^',
+        };
+
+        match self.fill {
+            ' ' => format!("{}{}", align, self.width),
+            fill => format!("{}{}{}", fill, align, self.width),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Align(self.clone()))
+    }
+}
+
+/// A filter that shortens a value to a maximum number of chars.
+///
+/// Syntax: `{value:trunc(width)}` or `{value:trunc(width, 'ellipsis')}`.
+///
+/// Values that fit within `width` chars are rendered unchanged. Longer values
+/// are cut and the ellipsis (`…` by default) is appended. The ellipsis counts
+/// toward the width, so the output is never longer than `width` chars; if the
+/// ellipsis itself is wider than `width`, it is cut to fit.
+///
+/// Lengths are measured in `char`s, so a multi-byte character is never split.
+/// Grapheme clusters made of several chars (e.g. combining accents) may be.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{bio:trunc(8)}"          With: bio = "Hello, world"   Produces: "Hello, …"
+/// Template: "{bio:trunc(8, '...')}"   With: bio = "Hello, world"   Produces: "Hello..."
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TruncateFilter {
+    /// The maximum width of the output, in chars
+    pub width: usize,
+    /// The text appended when the value is truncated
+    pub ellipsis: Cow<'static, str>,
+}
+
+impl TruncateFilter {
+    /// The ellipsis used when none is specified.
+    pub const DEFAULT_ELLIPSIS: &'static str = "…";
+}
+
+impl Filter for TruncateFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "trunc", format)?;
+
+        if s.chars().count() <= self.width {
+            return Ok(Value::Str(s));
+        }
+
+        let ellipsis_len = self.ellipsis.chars().count().min(self.width);
+        let keep = self.width - ellipsis_len;
+
+        let mut out: String = s.chars().take(keep).collect();
+        out.extend(self.ellipsis.chars().take(ellipsis_len));
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "trunc"
+    }
+
+    fn describe(&self) -> String {
+        format!("trunc({}, {:?})", self.width, self.ellipsis)
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Truncate(self.clone()))
+    }
+}
+
+/// A filter that extracts a range of chars from a value.
+///
+/// Syntax: `{value:slice(start)}` or `{value:slice(start, end)}`. The range is
+/// half-open, so `slice(0, 4)` keeps the first four chars. Negative indices
+/// count from the end: `slice(-3)` keeps the last three.
+///
+/// Indices are measured in `char`s, so a multi-byte character is never split.
+/// Out-of-range indices are clamped to the value, and a range whose start is
+/// not before its end renders nothing.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{code:slice(0, 4)}"   With: code = "ORD-2024-17"   Produces: "ORD-"
+/// Template: "{code:slice(-2)}"     With: code = "ORD-2024-17"   Produces: "17"
+/// Template: "{code:slice(4, -3)}"  With: code = "ORD-2024-17"   Produces: "2024"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SliceFilter {
+    /// The index of the first char kept
+    pub start: i64,
+    /// The index one past the last char kept, or `None` for the end of the value
+    pub end: Option<i64>,
+}
+
+impl SliceFilter {
+    /// Converts a possibly negative index into a char position within `len`.
+    fn position(index: i64, len: usize) -> usize {
+        if index < 0 {
+            len.saturating_sub(index.unsigned_abs().try_into().unwrap_or(usize::MAX))
+        } else {
+            usize::try_from(index).unwrap_or(usize::MAX).min(len)
+        }
+    }
+}
+
+impl Filter for SliceFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "slice", format)?;
+        let len = s.chars().count();
+
+        let start = Self::position(self.start, len);
+        let end = self.end.map_or(len, |end| Self::position(end, len));
+
+        if start >= end {
+            return Ok(Value::static_str(""));
+        }
+
+        Ok(Value::owned_str(
+            s.chars().skip(start).take(end - start).collect(),
+        ))
+    }
+
+    fn name(&self) -> &str {
+        "slice"
+    }
+
+    fn describe(&self) -> String {
+        match self.end {
+            Some(end) => format!("slice({}, {})", self.start, end),
+            None => format!("slice({})", self.start),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Slice(self.clone()))
+    }
+}
+
+/// A filter that renders the length of a string, of binary data or of an
+/// array.
+///
+/// Syntax: `{value:len}`. The length of a string is the number of `char`s, so
+/// `"héllo"` has length 5 even though it takes 6 bytes. Grapheme clusters made
+/// of several chars (e.g. combining accents) count once per char. The length
+/// of `Value::Bytes` is the number of bytes, and that of `Value::Array` the
+/// number of items.
+///
+/// The result is a `Value::Int`, so it renders as a number.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{name:len}"   With: name = "Ada"     Produces: "3"
+/// Template: "{name:len}"   With: name = "🦀🦀"    Produces: "2"
+/// Template: "{items:len}"  With: items = [1, 2, 3]  Produces: "3"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` if the value is not a
+/// `Value::Str`, `Value::Bytes` or `Value::Array`. Scalars are not stringified first: the
+/// length of the number `1000` is more likely a mistake than a request for `4`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LengthFilter;
+
+impl Filter for LengthFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        let len = value
+            .length()
+            .ok_or_else(|| DirectiveError::FilterTypeError {
+                filter: "len",
+                expected: "string",
+                found: value.type_name(),
+            })?;
+
+        Ok(Value::Int(i64::try_from(len).unwrap_or(i64::MAX)))
+    }
+
+    fn name(&self) -> &str {
+        "len"
+    }
+
+    fn describe(&self) -> String {
+        String::from("len")
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Length(self.clone()))
+    }
+}
+
+/// A filter that reverses a string.
+///
+/// Syntax: `{value:reverse}`. The value is reversed by grapheme clusters
+/// rather than by `char`s, so combining accents stay on their letter and
+/// emoji sequences joined with zero-width joiners, skin tone modifiers or
+/// variation selectors, as well as flags and `\r\n`, are kept whole.
+/// Clusters are recognized with a simplified version of the Unicode rules
+/// that covers these cases, not with the full segmentation tables.
+///
+/// Other values are stringified first, so `{n:reverse}` with `n = 123`
+/// renders `321`.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{name:reverse}"   With: name = "Ada"        Produces: "adA"
+/// Template: "{name:reverse}"   With: name = "Zoe\u{301}"  Produces: "e\u{301}oZ"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReverseFilter;
+
+impl ReverseFilter {
+    /// Returns whether `c` extends the grapheme cluster before it: combining
+    /// marks, joiners, variation selectors, emoji modifiers and tags.
+    fn is_extending(c: char) -> bool {
+        matches!(
+            c,
+            '\u{300}'..='\u{36F}'
+                | '\u{483}'..='\u{489}'
+                | '\u{591}'..='\u{5BD}'
+                | '\u{610}'..='\u{61A}'
+                | '\u{64B}'..='\u{65F}'
+                | '\u{900}'..='\u{903}'
+                | '\u{93A}'..='\u{94F}'
+                | '\u{1AB0}'..='\u{1AFF}'
+                | '\u{1DC0}'..='\u{1DFF}'
+                | '\u{200C}'..='\u{200D}'
+                | '\u{20D0}'..='\u{20FF}'
+                | '\u{302A}'..='\u{302F}'
+                | '\u{3099}'..='\u{309A}'
+                | '\u{FE00}'..='\u{FE0F}'
+                | '\u{FE20}'..='\u{FE2F}'
+                | '\u{1F3FB}'..='\u{1F3FF}'
+                | '\u{E0020}'..='\u{E007F}'
+                | '\u{E0100}'..='\u{E01EF}'
+        )
+    }
+
+    fn is_regional_indicator(c: char) -> bool {
+        matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+    }
+
+    /// Splits `s` into grapheme clusters, in order.
+    fn clusters(s: &str) -> Vec<&str> {
+        let mut clusters = Vec::new();
+        let mut start = 0;
+        // Whether the previous char joins the next one to its cluster
+        let mut joined = false;
+        // The number of regional indicators at the end of the current cluster
+        let mut indicators = 0;
+        let mut prev = None;
+
+        for (i, c) in s.char_indices() {
+            let extends = i > 0
+                && (joined
+                    || Self::is_extending(c)
+                    || (prev == Some('\r') && c == '\n')
+                    || (Self::is_regional_indicator(c) && indicators % 2 == 1));
+
+            if !extends && i > 0 {
+                clusters.push(&s[start..i]);
+                start = i;
+                indicators = 0;
+            }
+
+            joined = c == '\u{200D}';
+            indicators = if Self::is_regional_indicator(c) {
+                indicators + 1
+            } else {
+                0
+            };
+            prev = Some(c);
+        }
+
+        if start < s.len() {
+            clusters.push(&s[start..]);
+        }
+
+        clusters
+    }
+}
+
+impl Filter for ReverseFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "reverse", format)?;
+
+        Ok(Value::owned_str(
+            Self::clusters(&s).into_iter().rev().collect(),
+        ))
+    }
+
+    fn name(&self) -> &str {
+        "reverse"
+    }
+
+    fn describe(&self) -> String {
+        String::from("reverse")
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Reverse(self.clone()))
+    }
+}
+
+/// A filter that repeats a value a fixed number of times.
+///
+/// Syntax: `{value:repeat(count)}`, the filter form of `{value:count}`, which
+/// can be chained with other filters. The count must be a non-negative
+/// integer literal; `repeat(-1)` is an invalid spec.
+///
+/// Other values are stringified first, so `{n:repeat(2)}` with `n = 7`
+/// renders `77`.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{sep:repeat(3)}"         With: sep = "-="   Produces: "-=-=-="
+/// Template: "{sep | repeat(3) | >8}"  With: sep = "-"    Produces: "     ---"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`. Since the output is built in memory before it is
+/// written, a result longer than `FormatOptions::DEFAULT_MAX_OUTPUT_LEN` fails with
+/// `DirectiveError::OutputLimitExceeded` whatever the options; a lower
+/// `FormatOptions::max_output_len` still applies when it is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RepeatFilter {
+    /// The number of times the value is repeated
+    pub count: usize,
+}
+
+impl Filter for RepeatFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "repeat", format)?;
+        let limit = FormatOptions::DEFAULT_MAX_OUTPUT_LEN;
+
+        if s.len().saturating_mul(self.count) > limit {
+            return Err(DirectiveError::OutputLimitExceeded { limit });
+        }
+
+        Ok(Value::owned_str(s.repeat(self.count)))
+    }
+
+    fn name(&self) -> &str {
+        "repeat"
+    }
+
+    fn describe(&self) -> String {
+        format!("repeat({})", self.count)
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Repeat(self.clone()))
+    }
+}
+
+/// A filter that trims a value and collapses its runs of whitespace.
+///
+/// Syntax: `{value:squish}`. Leading and trailing whitespace is removed, and
+/// every run of whitespace inside the value, including line breaks and tabs,
+/// becomes a single space. Whitespace is anything Unicode considers white
+/// space, such as no-break spaces and the ideographic space, not only ASCII.
+///
+/// Other values are stringified first.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "[{comment:squish}]"   With: comment = "  too\t many\n\n spaces "
+/// Produces: "[too many spaces]"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SquishFilter;
+
+impl Filter for SquishFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "squish", format)?;
+        let mut out = String::with_capacity(s.len());
+
+        for word in s.split_whitespace() {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            out.push_str(word);
+        }
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "squish"
+    }
+
+    fn describe(&self) -> String {
+        String::from("squish")
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Squish(self.clone()))
+    }
+}
+
+/// A filter that replaces every occurrence of a substring in a value.
+///
+/// Syntax: `{value:replace(from, to)}`. Arguments are read as text up to the
+/// next `,` or `)` and trimmed, so they need no quotes; quote them to keep
+/// surrounding whitespace, and escape punctuation such as `,`, `)` or the
+/// delimiters with a backslash. An empty `to` removes `from`.
+///
+/// Occurrences are replaced from left to right without overlapping, like
+/// `str::replace`, so replacing `aa` in `aaa` leaves the last `a`. Numbers and
+/// other non-string values are replaced in the text they render as.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{path:replace(\\, /)}"      With: path = "C:\dir\file"   Produces: "C:/dir/file"
+/// Template: "{text:replace(foo, bar)}"   With: text = "foo foo"       Produces: "bar bar"
+/// Template: "{csv:replace(\,, ' | ')}"   With: csv = "a,b"            Produces: "a | b"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReplaceFilter {
+    /// The text to replace, never empty
+    pub from: Cow<'static, str>,
+    /// The text written in its place
+    pub to: Cow<'static, str>,
+}
+
+impl Filter for ReplaceFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "replace", format)?;
+
+        if !s.contains(&*self.from) {
+            return Ok(Value::Str(s));
+        }
+
+        Ok(Value::owned_str(s.replace(&*self.from, &self.to)))
+    }
+
+    fn name(&self) -> &str {
+        "replace"
+    }
+
+    fn describe(&self) -> String {
+        format!("replace({:?}, {:?})", self.from, self.to)
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Replace(self.clone()))
+    }
+}
+
+/// Which ends of a value a `StripFilter` strips.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StripSide {
+    /// The start only, like `str::trim_start_matches`.
+    Start,
+    /// The end only, like `str::trim_end_matches`.
+    End,
+    /// Both ends, like `str::trim_matches`.
+    Both,
+}
+
+impl StripSide {
+    /// Returns the filter name of the side, e.g. `"rstrip"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Start => "lstrip",
+            Self::End => "rstrip",
+            Self::Both => "strip",
+        }
+    }
+}
+
+/// A filter that strips a set of characters from the ends of a value.
+///
+/// Syntax: `{value:strip(chars)}` strips both ends, `{value:lstrip(chars)}`
+/// the start and `{value:rstrip(chars)}` the end. Like Python's `str.strip`,
+/// the argument is a set: every char in it is stripped, in any order and any
+/// number of times, so `rstrip(/.)` turns `"dir/./"` into `"dir"`. It is read
+/// as text like the arguments of `replace`, so it needs no quotes; quote it
+/// to strip whitespace, and escape `,`, `)` or the delimiters with a
+/// backslash.
+///
+/// Numbers and other non-string values are stripped in the text they render
+/// as.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{path:rstrip(/)}"   With: path = "/srv/www//"   Produces: "/srv/www"
+/// Template: "{code:lstrip(0)}"   With: code = "000420"       Produces: "420"
+/// Template: "{s:strip(*-)}"      With: s = "**-note-**"      Produces: "note"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StripFilter {
+    /// The ends to strip
+    pub side: StripSide,
+    /// The chars to strip, never empty
+    pub chars: Cow<'static, str>,
+}
+
+impl Filter for StripFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, self.side.name(), format)?;
+        let set = |c: char| self.chars.contains(c);
+
+        let stripped = match self.side {
+            StripSide::Start => s.trim_start_matches(set),
+            StripSide::End => s.trim_end_matches(set),
+            StripSide::Both => s.trim_matches(set),
+        };
+
+        if stripped.len() == s.len() {
+            return Ok(Value::Str(s));
+        }
+
+        Ok(Value::owned_str(stripped.to_string()))
+    }
+
+    fn name(&self) -> &str {
+        self.side.name()
+    }
+
+    fn describe(&self) -> String {
+        format!("{}({:?})", self.side.name(), self.chars)
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Strip(self.clone()))
+    }
+}
+
+/// A filter that indents the lines of a multi-line value, so that it stays
+/// aligned when substituted into an indented block, such as a nested YAML
+/// mapping.
+///
+/// Syntax: `{value:indent(n)}` prefixes every line but the first with `n`
+/// spaces, since the first one continues the line the directive is on, and
+/// `{value:indent(n, first)}` prefixes the first line too. `{value:indent}`
+/// indents by the column the directive starts at, so every line of the value
+/// starts below its first one. Empty lines are left empty.
+///
+/// The column is counted like `{@col(n)}` counts it. Outside a template, as
+/// with `Filter::apply`, it is 0, so `indent` without a width changes
+/// nothing.
+///
+/// Numbers and other non-string values are indented in the text they render
+/// as.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "  script: {cmd:indent(4)}"   With: cmd = "a\nb"     Produces: "  script: a\n    b"
+/// Template: "{body:indent(2, first)}"     With: body = "a\n\nb"  Produces: "  a\n\n  b"
+/// Template: "- {item:indent}"             With: item = "a\nb"    Produces: "- a\n  b"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for `Value::Bytes` and
+/// `Value::Array`, and `DirectiveError::OutputLimitExceeded` if the
+/// indented value would be longer than `FormatOptions::max_output_len` bytes
+/// while rendering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndentFilter {
+    /// The number of spaces to indent by, or `None` for the column the
+    /// directive starts at
+    pub width: Option<usize>,
+    /// Whether the first line is indented too
+    pub first: bool,
+}
+
+impl IndentFilter {
+    /// Indents the lines of `value`, by `column` spaces if the filter has no
+    /// width, failing if the indented value would be longer than `limit`
+    /// bytes.
+    fn indent<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+        column: usize,
+        limit: usize,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "indent", format)?;
+        let width = self.width.unwrap_or(column);
+
+        if width == 0 || !(self.first || s.contains('\n')) {
+            return Ok(Value::Str(s));
+        }
+
+        // Empty lines are left empty
+        let indented_line =
+            |i: usize, line: &str| (i > 0 || self.first) && !matches!(line, "" | "\r");
+        let lines = s
+            .split('\n')
+            .enumerate()
+            .filter(|&(i, line)| indented_line(i, line))
+            .count();
+        let indented_len = lines
+            .checked_mul(width)
+            .and_then(|indents| indents.checked_add(s.len()))
+            .filter(|&indented_len| indented_len <= limit)
+            .ok_or(DirectiveError::OutputLimitExceeded { limit })?;
+
+        let mut indented = String::with_capacity(indented_len);
+
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                indented.push('\n');
+            }
+            if indented_line(i, line) {
+                indented.extend(std::iter::repeat_n(' ', width));
+            }
+            indented.push_str(line);
+        }
+
+        Ok(Value::owned_str(indented))
+    }
+}
+
+impl Filter for IndentFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.indent(value, format, 0, FormatOptions::DEFAULT_MAX_OUTPUT_LEN)
+    }
+
+    /// Indents to the column the directive starts at if the filter has no
+    /// width.
+    fn apply_in<'v>(
+        &self,
+        value: Value<'v>,
+        ctx: &RenderContext<'_>,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.indent(
+            value,
+            ctx.number_format(),
+            ctx.column(),
+            ctx.max_output_len(),
+        )
+    }
+
+    fn name(&self) -> &str {
+        "indent"
+    }
+
+    fn describe(&self) -> String {
+        match (self.width, self.first) {
+            (None, _) => String::from("indent"),
+            (Some(width), false) => format!("indent({})", width),
+            (Some(width), true) => format!("indent({}, first)", width),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Indent(*self))
+    }
+}
+
+/// The set of characters a `UrlEncodeFilter` leaves unescaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UrlEncoding {
+    /// RFC 3986: only the unreserved characters `A-Z a-z 0-9 - . _ ~` are
+    /// kept, everything else is percent-encoded, spaces as `%20`. Safe in any
+    /// part of a URL.
+    Rfc3986,
+    /// `application/x-www-form-urlencoded`, as used by HTML forms: `A-Z a-z
+    /// 0-9 * - . _` are kept, spaces become `+` and everything else is
+    /// percent-encoded.
+    Form,
+}
+
+/// A filter that percent-encodes a value for use in a URL.
+///
+/// Syntax: `{value:urlenc}` encodes with `UrlEncoding::Rfc3986` and
+/// `{value:urlenc(form)}` with `UrlEncoding::Form`. The value is encoded as
+/// UTF-8, each escaped byte written as `%` and two uppercase hex digits.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "?q={q:urlenc}"         With: q = "a&b c"   Produces: "?q=a%26b%20c"
+/// Template: "?q={q:urlenc(form)}"   With: q = "a&b c"   Produces: "?q=a%26b+c"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UrlEncodeFilter {
+    /// Which characters are left unescaped
+    pub encoding: UrlEncoding,
+}
+
+impl Filter for UrlEncodeFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+        let s = stringify(&value, "urlenc", format)?;
+        let mut out = String::with_capacity(s.len());
+
+        for &byte in s.as_bytes() {
+            let keep = match self.encoding {
+                UrlEncoding::Rfc3986 => {
+                    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+                }
+                UrlEncoding::Form => {
+                    byte.is_ascii_alphanumeric() || matches!(byte, b'*' | b'-' | b'.' | b'_')
+                }
+            };
+
+            if keep {
+                out.push(byte as char);
+            } else if byte == b' ' && self.encoding == UrlEncoding::Form {
+                out.push('+');
+            } else {
+                out.push('%');
+                out.push(HEX[usize::from(byte >> 4)] as char);
+                out.push(HEX[usize::from(byte & 0xf)] as char);
+            }
+        }
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "urlenc"
+    }
+
+    fn describe(&self) -> String {
+        match self.encoding {
+            UrlEncoding::Rfc3986 => String::from("urlenc"),
+            UrlEncoding::Form => String::from("urlenc(form)"),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::UrlEncode(self.clone()))
+    }
+}
+
+/// A filter that escapes Markdown syntax in a value, so that it renders as
+/// plain text.
+///
+/// Syntax: `{value:md}`. Every character that can start or end Markdown
+/// formatting, ``\ ` * _ { } [ ] ( ) < > # + - = . ! | ~ &``, is escaped
+/// with a backslash, which CommonMark allows before any ASCII punctuation.
+/// Other characters, including line breaks, are kept as they are: escaping
+/// `#`, `-`, `=`, `+` and `.` is enough to stop a line of the value from
+/// becoming a heading or a list item.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "**{title:md}**"   With: title = "*new* [link](x)"
+/// Produces: "**\*new\* \[link\]\(x\)**"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MarkdownEscapeFilter;
+
+/// The characters a `MarkdownEscapeFilter` escapes.
+const MARKDOWN_SPECIAL: &str = "\\`*_{}[]()<>#+-=.!|~&";
+
+impl Filter for MarkdownEscapeFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "md", format)?;
+        let mut out = String::with_capacity(s.len() + s.len() / 8);
+
+        for c in s.chars() {
+            if MARKDOWN_SPECIAL.contains(c) {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "md"
+    }
+
+    fn describe(&self) -> String {
+        String::from("md")
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::MarkdownEscape(self.clone()))
+    }
+}
+
+/// A filter that quotes a value as a single word for a POSIX shell.
+///
+/// Syntax: `{value:sh}`. The value is wrapped in single quotes, inside which
+/// the shell interprets nothing, and each single quote in it is written as
+/// `'\''`: close the quotes, an escaped quote, reopen them. The result is
+/// always quoted, even when the value is empty or has no special
+/// characters, so it is always exactly one argument.
+///
+/// Shell arguments can't hold NUL characters, so a value containing one
+/// can't be passed faithfully; most shells drop it.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "rm -- {file:sh}"   With: file = "it's $HOME; rm -rf /"
+/// Produces: "rm -- 'it'\''s $HOME; rm -rf /'"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ShellEscapeFilter;
+
+impl Filter for ShellEscapeFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let s = stringify(&value, "sh", format)?;
+        let mut out = String::with_capacity(s.len() + 2);
+
+        out.push('\'');
+        for c in s.chars() {
+            match c {
+                '\'' => out.push_str("'\\''"),
+                c => out.push(c),
+            }
+        }
+        out.push('\'');
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "sh"
+    }
+
+    fn describe(&self) -> String {
+        String::from("sh")
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::ShellEscape(self.clone()))
+    }
+}
+
+/// The alphabet a `Base64Filter` encodes with.
+#[cfg(feature = "base64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Base64Alphabet {
+    /// The standard alphabet of RFC 4648 section 4, ending in `+` and `/`,
+    /// padded with `=`.
+    Standard,
+    /// The URL and filename safe alphabet of RFC 4648 section 5, ending in
+    /// `-` and `_`, without padding.
+    UrlSafe,
+}
+
+/// A filter that encodes a value as Base64.
+///
+/// Syntax: `{value:base64}` encodes with `Base64Alphabet::Standard` and
+/// `{value:base64(url)}` with `Base64Alphabet::UrlSafe`. `Value::Bytes` are
+/// encoded as-is and other values as UTF-8 text. Only available with the
+/// `base64` feature.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{data:base64}"        With: data = "hi?>"   Produces: "aGk/Pg=="
+/// Template: "{data:base64(url)}"   With: data = "hi?>"   Produces: "aGk_Pg"
+/// ```
+#[cfg(feature = "base64")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Base64Filter {
+    /// The alphabet to encode with
+    pub alphabet: Base64Alphabet,
+}
+
+#[cfg(feature = "base64")]
+impl Filter for Base64Filter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        const STANDARD: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        const URL_SAFE: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+        let (alphabet, pad) = match self.alphabet {
+            Base64Alphabet::Standard => (STANDARD, true),
+            Base64Alphabet::UrlSafe => (URL_SAFE, false),
+        };
+
+        let s;
+        let bytes = match &value {
+            Value::Bytes(bytes) => bytes.as_slice(),
+            _ => {
+                s = stringify(&value, "base64", format)?;
+                s.as_bytes()
+            }
+        };
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+        for chunk in bytes.chunks(3) {
+            let group = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |acc, (i, &b)| acc | u32::from(b) << (16 - 8 * i));
+
+            // n input bytes produce n + 1 output chars
+            for i in 0..=chunk.len() {
+                out.push(alphabet[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+
+            if pad {
+                out.extend(std::iter::repeat_n('=', 3 - chunk.len()));
+            }
+        }
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "base64"
+    }
+
+    fn describe(&self) -> String {
+        match self.alphabet {
+            Base64Alphabet::Standard => String::from("base64"),
+            Base64Alphabet::UrlSafe => String::from("base64(url)"),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Base64(self.clone()))
+    }
+}
+
+/// The hash function a `DigestFilter` computes.
+#[cfg(feature = "digest")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DigestAlgorithm {
+    /// SHA-256, a 32-byte cryptographic hash, rendered as 64 hex digits.
+    Sha256,
+    /// CRC-32, the 4-byte checksum of zlib and PNG, rendered as 8 hex
+    /// digits. Fast but not collision-resistant.
+    Crc32,
+}
+
+#[cfg(feature = "digest")]
+impl DigestAlgorithm {
+    /// Returns the filter name of the algorithm, e.g. `"sha256"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Crc32 => "crc32",
+        }
+    }
+}
+
+/// A filter that renders the hash of a value as lowercase hex.
+///
+/// Syntax: `{value:sha256}` or `{value:crc32}`. A length keeps only the
+/// first hex digits, for short cache-busting tokens: `{value:sha256(8)}`,
+/// also written `{value:sha256:8}`. `Value::Bytes` are hashed as-is and
+/// other values as UTF-8 text, so `{n:crc32}` hashes the digits of `n`.
+/// Only available with the `digest` feature.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{s:sha256(8)}"   With: s = "abc"   Produces: "ba7816bf"
+/// Template: "{s:crc32}"       With: s = "abc"   Produces: "352441c2"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` for values without a text form.
+#[cfg(feature = "digest")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DigestFilter {
+    /// The hash function to compute
+    pub algorithm: DigestAlgorithm,
+    /// How many hex digits to keep, `None` for the whole digest
+    pub length: Option<usize>,
+}
+
+#[cfg(feature = "digest")]
+impl Filter for DigestFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+
+        let s;
+        let bytes = match &value {
+            Value::Bytes(bytes) => bytes.as_slice(),
+            _ => {
+                s = stringify(&value, self.algorithm.name(), format)?;
+                s.as_bytes()
+            }
+        };
+
+        let sha;
+        let crc;
+        let digest: &[u8] = match self.algorithm {
+            DigestAlgorithm::Sha256 => {
+                sha = crate::digest::sha256(bytes);
+                &sha
+            }
+            DigestAlgorithm::Crc32 => {
+                crc = crate::digest::crc32(bytes).to_be_bytes();
+                &crc
+            }
+        };
+
+        let mut out: String = digest
+            .iter()
+            .flat_map(|&b| [HEX[usize::from(b >> 4)], HEX[usize::from(b & 0xf)]])
+            .map(char::from)
+            .collect();
+
+        if let Some(length) = self.length {
+            out.truncate(length);
+        }
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        self.algorithm.name()
+    }
+
+    fn describe(&self) -> String {
+        match self.length {
+            Some(length) => format!("{}({})", self.algorithm.name(), length),
+            None => String::from(self.algorithm.name()),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Digest(self.clone()))
+    }
+}
+
+/// The base an integer is rendered in by a `RadixFilter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Radix {
+    /// Base 2: `bin`, prefix `0b`
+    Binary,
+    /// Base 8: `oct`, prefix `0o`
+    Octal,
+    /// Base 16: `hex` or `HEX`, prefix `0x`
+    Hexadecimal,
+}
+
+impl Radix {
+    /// The name of the filter that renders in this base.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Binary => "bin",
+            Self::Octal => "oct",
+            Self::Hexadecimal => "hex",
+        }
+    }
+
+    /// The prefix written before the digits when requested.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::Binary => "0b",
+            Self::Octal => "0o",
+            Self::Hexadecimal => "0x",
+        }
+    }
+}
+
+/// A filter that renders an integer in binary, octal or hexadecimal.
+///
+/// Syntax: `{value:bin}`, `{value:oct}`, `{value:hex}` or `{value:HEX}` for
+/// uppercase hex digits. A leading `#` adds the base prefix (`0b`, `0o` or
+/// `0x`), e.g. `{value:#hex}`. The prefix itself is always lowercase.
+///
+/// Negative integers are rendered sign-prefixed, as a `-` followed by the
+/// digits of the absolute value (`-255` renders as `-ff` or `-0xff`), rather
+/// than as a two's complement bit pattern.
+///
+/// `hex` and `HEX` also encode `Value::Bytes`, as two digits per byte.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{mask:hex}"    With: mask = 255   Produces: "ff"
+/// Template: "{mask:#HEX}"   With: mask = 255   Produces: "0xFF"
+/// Template: "{perm:oct}"    With: perm = 493   Produces: "755"
+/// Template: "{bits:#bin}"   With: bits = 5     Produces: "0b101"
+/// Template: "{sig:hex}"     With: sig = [0xde, 0xad, 0x01]   Produces: "dead01"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` if the value is not a
+/// `Value::Int`, or `Value::Bytes` for hex. Strings are not parsed, so `'255'`
+/// is rejected as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RadixFilter {
+    /// The base to render in
+    pub radix: Radix,
+    /// Whether hex digits are uppercase
+    pub uppercase: bool,
+    /// Whether the base prefix is written before the digits
+    pub prefix: bool,
+}
+
+impl RadixFilter {
+    /// Writes binary data as two hex digits per byte.
+    fn hex_bytes(&self, bytes: &[u8]) -> String {
+        let digits: &[u8; 16] = if self.uppercase {
+            b"0123456789ABCDEF"
+        } else {
+            b"0123456789abcdef"
+        };
+
+        let mut out = String::with_capacity(2 + bytes.len() * 2);
+
+        if self.prefix {
+            out.push_str(self.radix.prefix());
+        }
+
+        for &byte in bytes {
+            out.push(digits[usize::from(byte >> 4)] as char);
+            out.push(digits[usize::from(byte & 0xf)] as char);
+        }
+
+        out
+    }
+}
+
+impl Filter for RadixFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        let n = match value {
+            Value::Int(n) => n,
+            Value::Bytes(bytes) if self.radix == Radix::Hexadecimal => {
+                return Ok(Value::owned_str(self.hex_bytes(&bytes)));
+            }
+            _ => {
+                return Err(DirectiveError::FilterTypeError {
+                    filter: self.radix.name(),
+                    expected: "integer",
+                    found: value.type_name(),
+                });
+            }
+        };
+
+        let abs = n.unsigned_abs();
+        let digits = match (self.radix, self.uppercase) {
+            (Radix::Binary, _) => format!("{:b}", abs),
+            (Radix::Octal, _) => format!("{:o}", abs),
+            (Radix::Hexadecimal, false) => format!("{:x}", abs),
+            (Radix::Hexadecimal, true) => format!("{:X}", abs),
+        };
+
+        let sign = if n < 0 { "-" } else { "" };
+        let prefix = if self.prefix { self.radix.prefix() } else { "" };
+
+        Ok(Value::owned_str(format!("{}{}{}", sign, prefix, digits)))
+    }
+
+    fn name(&self) -> &str {
+        self.radix.name()
+    }
+
+    fn describe(&self) -> String {
+        let name = if self.uppercase {
+            "HEX"
+        } else {
+            self.radix.name()
+        };
+        let flag = if self.prefix { "#" } else { "" };
+
+        format!("{}{}", flag, name)
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Radix(self.clone()))
+    }
+}
+
+/// A filter that forces a sign on numbers and pads them with zeros.
+///
+/// Syntax: `{value:+}` writes a `+` before non-negative numbers, `{value:05}`
+/// pads with zeros to a width of 5 and `{value:+05}` does both. As with
+/// Rust's `format!`, the width includes the sign and the zeros go between the
+/// sign and the digits, so `-42` padded to 5 renders as `-0042`.
+///
+/// A width needs its leading `0`: `{value:5}` is a repeat count. Numbers that
+/// are already as wide as the width are rendered unchanged, and non-finite
+/// floats such as `inf` are never zero-padded.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{id:05}"      With: id = 42      Produces: "00042"
+/// Template: "{delta:+}"    With: delta = 3    Produces: "+3"
+/// Template: "{delta:+05}"  With: delta = -3   Produces: "-0003"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` if the value is not a
+/// `Value::Int` or a `Value::Float`, and
+/// `DirectiveError::OutputLimitExceeded` if the padded number would be
+/// longer than `FormatOptions::max_output_len` bytes while rendering.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NumberFilter {
+    /// Whether non-negative numbers are written with a `+`
+    pub plus: bool,
+    /// The minimum width to pad to with zeros, sign included
+    pub zero_pad: usize,
+}
+
+impl NumberFilter {
+    /// Signs and pads `value`, failing if the padded number would be longer
+    /// than `limit` bytes.
+    fn pad<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+        limit: usize,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let finite = match value {
+            Value::Int(_) => true,
+            Value::Float(f) => f.is_finite(),
+            _ => {
+                return Err(DirectiveError::FilterTypeError {
+                    filter: "number format",
+                    expected: "number",
+                    found: value.type_name(),
+                });
+            }
+        };
+
+        let rendered = format.render(&value).unwrap_or_default();
+        let (negative, digits) = match rendered.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, rendered.as_ref()),
+        };
+
+        let sign = match (negative, self.plus) {
+            (true, _) => "-",
+            (false, true) => "+",
+            (false, false) => "",
+        };
+
+        let zeros = if finite {
+            self.zero_pad.saturating_sub(sign.len() + digits.len())
+        } else {
+            0
+        };
+
+        let padded_len = sign.len() + zeros + digits.len();
+        if padded_len > limit {
+            return Err(DirectiveError::OutputLimitExceeded { limit });
+        }
+
+        let mut out = String::with_capacity(padded_len);
+
+        out.push_str(sign);
+        out.extend(std::iter::repeat_n('0', zeros));
+        out.push_str(digits);
+
+        Ok(Value::owned_str(out))
+    }
+}
+
+impl Filter for NumberFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.pad(value, format, FormatOptions::DEFAULT_MAX_OUTPUT_LEN)
+    }
+
+    fn apply_in<'v>(
+        &self,
+        value: Value<'v>,
+        ctx: &RenderContext<'_>,
+    ) -> Result<Value<'v>, DirectiveError> {
+        self.pad(value, ctx.number_format(), ctx.max_output_len())
+    }
+
+    fn name(&self) -> &str {
+        "number format"
+    }
+
+    fn describe(&self) -> String {
+        let plus = if self.plus { "+" } else { "" };
+
+        match self.zero_pad {
+            0 => plus.to_string(),
+            width => format!("{}0{}", plus, width),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Number(self.clone()))
+    }
+}
+
+/// How a `RoundFilter` rounds a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RoundingMode {
+    /// To the nearest number, with halfway cases rounded away from zero
+    /// like `f64::round`: `2.5` rounds to `3` and `-2.5` to `-3`.
+    Round,
+    /// Toward negative infinity, like `f64::floor`: `-2.5` rounds to `-3`.
+    Floor,
+    /// Toward positive infinity, like `f64::ceil`: `-2.5` rounds to `-2`.
+    Ceil,
+}
+
+impl RoundingMode {
+    /// Returns the filter name of the mode, e.g. `"floor"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Round => "round",
+            Self::Floor => "floor",
+            Self::Ceil => "ceil",
+        }
+    }
+
+    /// Rounds `x` to an integral value.
+    fn apply(self, x: f64) -> f64 {
+        match self {
+            Self::Round => x.round(),
+            Self::Floor => x.floor(),
+            Self::Ceil => x.ceil(),
+        }
+    }
+}
+
+/// A filter that rounds a number to a number of decimal places.
+///
+/// Syntax: `{value:round}`, `{value:floor}` and `{value:ceil}` round to an
+/// integer, which renders without a fractional part. `{value:round(2)}` and
+/// the like keep that many decimal places and still render as a float, with
+/// the trailing zeros dropped: `2.5` rounded to 2 places renders `2.5`.
+/// See `RoundingMode` for how halfway cases are rounded.
+///
+/// Rounding to decimal places scales the float by a power of ten, so it
+/// works on the binary value rather than the decimal one written in the
+/// source: `1.005`, stored as slightly less, rounds to `1.0` with
+/// `round(2)`. Integers, non-finite floats and floats that already have no
+/// more decimals than the places kept are passed through unchanged, so the
+/// filter composes with the number format and further filters.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{x:round}"      With: x = 2.5      Produces: "3"
+/// Template: "{x:floor}"      With: x = -2.5     Produces: "-3"
+/// Template: "{x:ceil(1)}"    With: x = 1.21     Produces: "1.3"
+/// Template: "{x:round(2)}"   With: x = 3.14159  Produces: "3.14"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` if the value is not a
+/// `Value::Int` or a `Value::Float`. Strings are not parsed, so `'2.5'` is
+/// rejected as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RoundFilter {
+    /// How to round
+    pub mode: RoundingMode,
+    /// The number of decimal places to keep
+    pub places: usize,
+}
+
+impl Filter for RoundFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        let x = match &value {
+            Value::Int(_) => return Ok(value),
+            Value::Float(x) if x.is_finite() => *x,
+            Value::Float(_) => return Ok(value),
+            _ => {
+                return Err(DirectiveError::FilterTypeError {
+                    filter: self.mode.name(),
+                    expected: "number",
+                    found: value.type_name(),
+                });
+            }
+        };
+
+        if self.places == 0 {
+            let rounded = self.mode.apply(x);
+
+            // The upper bound is exclusive, as i64::MAX rounds up to 2^63
+            return Ok(if (i64::MIN as f64..i64::MAX as f64).contains(&rounded) {
+                Value::Int(rounded as i64)
+            } else {
+                Value::Float(rounded)
+            });
+        }
+
+        let scale = 10f64.powi(i32::try_from(self.places).unwrap_or(i32::MAX));
+        let scaled = x * scale;
+
+        // From 2^53 on, every float is an integer, so there is nothing left
+        // to round. This also covers scales that overflow to infinity, which
+        // make zero NaN
+        if scaled.is_nan() || scaled.abs() >= 9_007_199_254_740_992.0 {
+            return Ok(value);
+        }
+
+        // Adding zero turns -0.0, e.g. from rounding -0.001, into 0.0
+        Ok(Value::Float(self.mode.apply(scaled) / scale + 0.0))
+    }
+
+    fn name(&self) -> &str {
+        self.mode.name()
+    }
+
+    fn describe(&self) -> String {
+        match self.places {
+            0 => String::from(self.mode.name()),
+            places => format!("{}({})", self.mode.name(), places),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Round(self.clone()))
+    }
+}
+
+/// A currency known to `CurrencyFilter`, with its symbol, usual symbol
+/// position and number of decimal places.
+///
+/// Only a handful of common currencies are built in, so the table stays
+/// small enough not to need a feature flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Currency {
+    /// US dollar: `$`, 2 decimals
+    Usd,
+    /// Euro: `€` after the amount, 2 decimals
+    Eur,
+    /// Pound sterling: `£`, 2 decimals
+    Gbp,
+    /// Japanese yen: `¥`, no decimals
+    Jpy,
+    /// Swiss franc: `CHF`, 2 decimals
+    Chf,
+    /// Canadian dollar: `CA$`, 2 decimals
+    Cad,
+    /// Australian dollar: `A$`, 2 decimals
+    Aud,
+    /// Indian rupee: `₹`, 2 decimals
+    Inr,
+}
+
+impl Currency {
+    /// Looks up a currency by its ISO 4217 code, ignoring case.
+    pub fn from_code(code: &str) -> Option<Self> {
+        [
+            Self::Usd,
+            Self::Eur,
+            Self::Gbp,
+            Self::Jpy,
+            Self::Chf,
+            Self::Cad,
+            Self::Aud,
+            Self::Inr,
+        ]
+        .into_iter()
+        .find(|currency| currency.code().eq_ignore_ascii_case(code))
+    }
+
+    /// The ISO 4217 code, e.g. `USD`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Usd => "USD",
+            Self::Eur => "EUR",
+            Self::Gbp => "GBP",
+            Self::Jpy => "JPY",
+            Self::Chf => "CHF",
+            Self::Cad => "CAD",
+            Self::Aud => "AUD",
+            Self::Inr => "INR",
+        }
+    }
+
+    /// The symbol written next to amounts, e.g. `$`.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Usd => "$",
+            Self::Eur => "€",
+            Self::Gbp => "£",
+            Self::Jpy => "¥",
+            Self::Chf => "CHF",
+            Self::Cad => "CA$",
+            Self::Aud => "A$",
+            Self::Inr => "₹",
+        }
+    }
+
+    /// The number of decimal places amounts are rounded to.
+    pub fn decimals(&self) -> usize {
+        match self {
+            Self::Jpy => 0,
+            _ => 2,
+        }
+    }
+
+    /// Where the symbol is written unless a `CurrencyFilter` says otherwise.
+    pub fn default_position(&self) -> SymbolPosition {
+        match self {
+            Self::Eur => SymbolPosition::Suffix,
+            _ => SymbolPosition::Prefix,
+        }
+    }
+}
+
+/// Where a `CurrencyFilter` writes the currency symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SymbolPosition {
+    /// Before the amount: `$1.00`
+    Prefix,
+    /// After the amount, separated by a space: `1.00 €`
+    Suffix,
+}
+
+/// How a `CurrencyFilter` writes negative amounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NegativeStyle {
+    /// A leading minus sign: `-$1.00`
+    Minus,
+    /// Parentheses around the amount and symbol, as in accounting: `($1.00)`
+    Parentheses,
+}
+
+/// A filter that renders a number as an amount of money.
+///
+/// Syntax: `{value:currency(CODE)}` with an ISO 4217 code from `Currency`,
+/// optionally followed by `prefix` or `suffix` to move the symbol and by
+/// `parens` to write negative amounts in parentheses instead of with a minus
+/// sign, e.g. `{total:currency(EUR, prefix, parens)}`.
+///
+/// Amounts are rounded to the currency's decimal places and the integer part
+/// is grouped by thousands. Separators come from `FormatOptions::number_format`,
+/// `,` for thousands and `.` for decimals by default, whatever the currency. A symbol made of letters, such as
+/// `CHF`, is separated from the amount by a space. Amounts that round to zero
+/// are never negative.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{total:currency(USD)}"            With: total = 1234.56   Produces: "$1,234.56"
+/// Template: "{total:currency(EUR)}"            With: total = 1234.5    Produces: "1,234.50 €"
+/// Template: "{total:currency(JPY)}"            With: total = 1500      Produces: "¥1,500"
+/// Template: "{total:currency(USD)}"            With: total = -5        Produces: "-$5.00"
+/// Template: "{total:currency(USD, parens)}"    With: total = -5        Produces: "($5.00)"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` if the value is not a
+/// `Value::Int` or a `Value::Float`, or is a NaN or infinite float, which is
+/// reported as expecting a finite number. Strings are not parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CurrencyFilter {
+    /// The currency the amount is in
+    pub currency: Currency,
+    /// Where the symbol is written
+    pub position: SymbolPosition,
+    /// How negative amounts are written
+    pub negative: NegativeStyle,
+}
+
+impl CurrencyFilter {
+    /// Creates a filter for `currency` with its usual symbol position and
+    /// negative amounts written with a minus sign.
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            currency,
+            position: currency.default_position(),
+            negative: NegativeStyle::Minus,
+        }
+    }
+}
+
+impl Filter for CurrencyFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let decimals = self.currency.decimals();
+
+        // The rounded absolute amount, with `decimals` digits after the point
+        let (negative, amount) = match value {
+            Value::Int(n) if decimals == 0 => (n < 0, n.unsigned_abs().to_string()),
+            Value::Int(n) => (
+                n < 0,
+                format!("{}.{}", n.unsigned_abs(), "0".repeat(decimals)),
+            ),
+            Value::Float(f) if f.is_finite() => {
+                let amount = format!("{:.*}", decimals, f.abs());
+                let zero = amount.bytes().all(|b| b == b'0' || b == b'.');
+
+                (f < 0.0 && !zero, amount)
+            }
+            _ => return Err(not_a_finite_number("currency", &value)),
+        };
+
+        let (integer, fraction) = match amount.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (amount.as_str(), None),
+        };
+
+        let mut number = format.group(integer);
+
+        if let Some(fraction) = fraction {
+            number.push(format.decimal_sep);
+            number.push_str(fraction);
+        }
+
+        let symbol = self.currency.symbol();
+        let space = if symbol.chars().all(char::is_alphabetic) {
+            " "
+        } else {
+            ""
+        };
+
+        let money = match self.position {
+            SymbolPosition::Prefix => format!("{}{}{}", symbol, space, number),
+            SymbolPosition::Suffix => format!("{} {}", number, symbol),
+        };
+
+        Ok(Value::owned_str(match (negative, self.negative) {
+            (false, _) => money,
+            (true, NegativeStyle::Minus) => format!("-{}", money),
+            (true, NegativeStyle::Parentheses) => format!("({})", money),
+        }))
+    }
+
+    fn name(&self) -> &str {
+        "currency"
+    }
+
+    fn describe(&self) -> String {
+        let mut spec = format!("currency({}", self.currency.code());
+
+        if self.position != self.currency.default_position() {
+            spec.push_str(match self.position {
+                SymbolPosition::Prefix => ", prefix",
+                SymbolPosition::Suffix => ", suffix",
+            });
+        }
+
+        if self.negative == NegativeStyle::Parentheses {
+            spec.push_str(", parens");
+        }
+
+        spec.push(')');
+        spec
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Currency(self.clone()))
+    }
+}
+
+/// A filter that renders a ratio as a percentage.
+///
+/// Syntax: `{value:percent}` multiplies the value by 100 and appends `%`,
+/// with 2 decimal places; `{value:percent(1)}` keeps 1. `{value:percent(scaled)}`
+/// and `{value:percent(1, scaled)}` take the value as already multiplied,
+/// for integers such as `42` meaning 42%.
+///
+/// The value is scaled and rounded on the digits of its shortest decimal
+/// form rather than as a binary float, which would be off by a little: `0.1234`
+/// renders `12.34%` and `0.005` with `percent(0)` renders `1%`, halfway cases
+/// rounding away from zero. Values that round to zero are never negative.
+/// The decimal separator comes from `FormatOptions::number_format`.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{ratio:percent}"             With: ratio = 0.1234   Produces: "12.34%"
+/// Template: "{ratio:percent(1)}"          With: ratio = 0.1234   Produces: "12.3%"
+/// Template: "{ratio:percent(0)}"          With: ratio = -1.5     Produces: "-150%"
+/// Template: "{share:percent(0, scaled)}"  With: share = 42       Produces: "42%"
+/// ```
+///
+/// # Errors
+///
+/// Returns `DirectiveError::FilterTypeError` if the value is not a
+/// `Value::Int` or a `Value::Float`, or is a NaN or infinite float, which is
+/// reported as expecting a finite number. Strings are not parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PercentFilter {
+    /// The number of decimal places to render
+    pub places: usize,
+    /// Whether the value is already a percentage rather than a ratio
+    pub scaled: bool,
+}
+
+impl PercentFilter {
+    /// The decimal places rendered by `{value:percent}`.
+    pub const DEFAULT_PLACES: usize = 2;
+}
+
+impl Default for PercentFilter {
+    fn default() -> Self {
+        Self {
+            places: Self::DEFAULT_PLACES,
+            scaled: false,
+        }
+    }
+}
+
+impl Filter for PercentFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        self.apply_with_format(value, NumberFormat::default())
+    }
+
+    fn apply_with_format<'v>(
+        &self,
+        value: Value<'v>,
+        format: NumberFormat,
+    ) -> Result<Value<'v>, DirectiveError> {
+        let (negative, digits) = match value {
+            Value::Int(n) => (n < 0, n.unsigned_abs().to_string()),
+            Value::Float(f) if f.is_finite() => (f < 0.0, f.abs().to_string()),
+            _ => return Err(not_a_finite_number("percent", &value)),
+        };
+
+        let (integer, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+        let shift = if self.scaled { 0 } else { 2 };
+        let (integer, fraction) = round_decimal(integer, fraction, shift, self.places);
+        let zero = integer == "0" && fraction.bytes().all(|b| b == b'0');
+
+        let mut out = String::with_capacity(integer.len() + fraction.len() + 3);
+        if negative && !zero {
+            out.push('-');
+        }
+        out.push_str(&integer);
+        if !fraction.is_empty() {
+            out.push(format.decimal_sep);
+            out.push_str(&fraction);
+        }
+        out.push('%');
+
+        Ok(Value::owned_str(out))
+    }
+
+    fn name(&self) -> &str {
+        "percent"
+    }
+
+    fn describe(&self) -> String {
+        match (self.places, self.scaled) {
+            (Self::DEFAULT_PLACES, false) => String::from("percent"),
+            (places, false) => format!("percent({})", places),
+            (places, true) => format!("percent({}, scaled)", places),
+        }
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Percent(self.clone()))
+    }
+}
+
+/// Multiplies the decimal number `integer.fraction` by `10^shift` and rounds
+/// it to `places` fractional digits, halfway cases away from zero, returning
+/// the integer and fractional digits of the result.
+fn round_decimal(integer: &str, fraction: &str, shift: usize, places: usize) -> (String, String) {
+    let mut digits: Vec<u8> = integer.bytes().chain(fraction.bytes()).collect();
+    let point = integer.len() + shift;
+
+    // Enough digits for the point and every kept place, plus the one rounded on
+    digits.resize(digits.len().max(point + places + 1), b'0');
+
+    let round_up = digits[point + places] >= b'5';
+    digits.truncate(point + places);
+
+    let mut point = point;
+    if round_up {
+        let carry = digits.iter_mut().rev().all(|digit| {
+            if *digit == b'9' {
+                *digit = b'0';
+                true
+            } else {
+                *digit += 1;
+                false
+            }
+        });
+
+        if carry {
+            digits.insert(0, b'1');
+            point += 1;
+        }
+    }
+
+    let fraction = String::from_utf8(digits.split_off(point)).unwrap_or_default();
+    let integer = String::from_utf8(digits).unwrap_or_default();
+    let integer = match integer.trim_start_matches('0') {
+        "" => String::from("0"),
+        trimmed => trimmed.to_string(),
+    };
+
+    (integer, fraction)
+}
+
+/// A filter that renders one of two texts depending on a boolean value.
+///
+/// Syntax: `{value:bool(yes, no)}`. Each text is a quoted literal, or a bare
+/// word or number for short texts without spaces, e.g. `{active:bool(Yes, No)}`
+/// or `{active:bool('✓ on', '✗ off')}`.
+///
+/// Non-boolean values are coerced like conditions are, with
+/// `Value::is_truthy`: numbers are true when non-zero, strings when non-empty
+/// and `Null` is false.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{active:bool(Yes, No)}"   With: active = true    Produces: "Yes"
+/// Template: "{active:bool(Yes, No)}"   With: active = false   Produces: "No"
+/// Template: "{name:bool(Yes, No)}"     With: name = ""        Produces: "No"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BoolFilter {
+    /// The text rendered for true values
+    pub if_true: Cow<'static, str>,
+    /// The text rendered for false values
+    pub if_false: Cow<'static, str>,
+}
+
+impl Filter for BoolFilter {
+    fn apply<'v>(&self, value: Value<'v>) -> Result<Value<'v>, DirectiveError> {
+        let text = if value.is_truthy() {
+            &self.if_true
+        } else {
+            &self.if_false
+        };
+
+        Ok(Value::Str(text.clone()))
+    }
+
+    fn name(&self) -> &str {
+        "bool"
+    }
+
+    fn describe(&self) -> String {
+        format!("bool({:?}, {:?})", self.if_true, self.if_false)
+    }
+
+    fn same_as(&self, other: &dyn Filter) -> bool {
+        same_filter(self, other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn to_serialized(&self) -> Option<SerializedFilter> {
+        Some(SerializedFilter::Bool(self.clone()))
+    }
+}
@@ -0,0 +1,1081 @@
+//! Compiled sequences of directives.
+//!
+//! A `Fragment` is the delimiter-independent body of a compiled template. The
+//! top level of every `Template` is a fragment, and so is every sub-template
+//! nested inside a directive, such as a conditional branch containing
+//! `{name}`.
+
+use crate::{
+    Argument, ColumnDirective, ContextLookup, DebugTree, Directive, DirectiveError,
+    ErrorMessageDirective, FallbackDirective, FilterDirective, IncludeDirective, IndentFilter,
+    LiteralDirective, MissingPolicy, NumberFormat, RegionDirective, RegionEndDirective,
+    RenderContext, RenderState, ReplaceDirective, Value, advance_column, empty_placeholder,
+    render_held,
+};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+/// The longest output `Fragment::optimize` replaces a directive with.
+const MAX_FOLDED_LEN: usize = 4 * 1024;
+
+/// Returns whether rendering `directive` may render a `ColumnDirective` or
+/// an `IndentFilter` indenting to the column the directive starts at.
+///
+/// Includes count too, because their partials are only known when
+/// rendering.
+fn tracks_columns(directive: &dyn Directive) -> bool {
+    let any: &dyn Any = directive;
+
+    if any.is::<ColumnDirective>() || any.is::<IncludeDirective>() {
+        return true;
+    }
+    if let Some(filtered) = any.downcast_ref::<FilterDirective>()
+        && filtered.1.iter().any(|filter| {
+            (filter.as_ref() as &dyn Any)
+                .downcast_ref::<IndentFilter>()
+                .is_some_and(|indent| indent.width.is_none())
+        })
+    {
+        return true;
+    }
+    if let Some(fallback) = any.downcast_ref::<FallbackDirective>()
+        && tracks_columns(fallback.directive.as_ref())
+    {
+        return true;
+    }
+    if let Some(message) = any.downcast_ref::<ErrorMessageDirective>()
+        && tracks_columns(message.directive.as_ref())
+    {
+        return true;
+    }
+
+    directive.arguments().into_iter().any(|arg| match arg {
+        Argument::Template(fragment) => fragment.tracks_columns(),
+        _ => false,
+    })
+}
+
+/// A compiled directive together with where it came from in the source.
+pub(crate) struct Node {
+    pub(crate) directive: Box<dyn Directive>,
+    kind: SegmentKind,
+    span: Range<usize>,
+    /// The directive's source, delimiters included. `None` for literal text.
+    pub(crate) source: Option<Box<str>>,
+}
+
+impl Node {
+    /// A run of literal text, `input[span]`.
+    pub(crate) fn text(input: &str, span: Range<usize>) -> Self {
+        Self {
+            directive: Box::new(LiteralDirective(Cow::Owned(
+                input[span.clone()].to_string(),
+            ))),
+            kind: SegmentKind::Text,
+            span,
+            source: None,
+        }
+    }
+
+    /// A raw block, `{@raw}...{@endraw}`, rendering as `text`, the source
+    /// between its markers.
+    pub(crate) fn raw(text: &str, span: Range<usize>) -> Self {
+        Self {
+            directive: Box::new(LiteralDirective(Cow::Owned(text.to_string()))),
+            kind: SegmentKind::Text,
+            span,
+            source: None,
+        }
+    }
+
+    /// An escape, such as `{{` or `\n`, rendering as `text`.
+    pub(crate) fn escape(text: &str, span: Range<usize>) -> Self {
+        Self {
+            directive: Box::new(LiteralDirective(Cow::Owned(text.to_string()))),
+            kind: SegmentKind::Escape,
+            span,
+            source: None,
+        }
+    }
+
+    /// A parsed directive, `input[span]` with delimiters included.
+    pub(crate) fn directive(
+        directive: Box<dyn Directive>,
+        input: &str,
+        span: Range<usize>,
+    ) -> Self {
+        Self {
+            directive,
+            kind: SegmentKind::Directive,
+            source: Some(input[span.clone()].into()),
+            span,
+        }
+    }
+}
+
+impl Node {
+    /// A node built without source, as by `TemplateBuilder`.
+    pub(crate) fn built(directive: Box<dyn Directive>, kind: SegmentKind) -> Self {
+        Self {
+            directive,
+            kind,
+            span: 0..0,
+            source: None,
+        }
+    }
+
+    /// Reassembles a node from the parts of a `Segment`.
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_parts(
+        directive: Box<dyn Directive>,
+        kind: SegmentKind,
+        span: Range<usize>,
+        source: Option<Box<str>>,
+    ) -> Self {
+        Self {
+            directive,
+            kind,
+            span,
+            source,
+        }
+    }
+
+    /// Whether this node has the same kind and directive as `other`,
+    /// regardless of where either came from.
+    fn same_as(&self, other: &Self) -> bool {
+        self.kind == other.kind && *self.directive == *other.directive
+    }
+
+    /// Whether this node is literal text or an escape, which render the same
+    /// for every context.
+    fn is_text(&self) -> bool {
+        matches!(self.kind, SegmentKind::Text | SegmentKind::Escape)
+    }
+
+    /// Merges each run of adjacent text and escape nodes into a single text
+    /// node spanning the whole run, so that it is written at once.
+    fn merge_text(nodes: Vec<Self>) -> Vec<Self> {
+        // Most templates have no adjacent text, and keep their nodes as is
+        if !nodes
+            .windows(2)
+            .any(|pair| pair[0].is_text() && pair[1].is_text())
+        {
+            return nodes;
+        }
+
+        let mut merged = Vec::with_capacity(nodes.len());
+        let mut run: Vec<Self> = Vec::new();
+
+        for node in nodes {
+            if node.is_text() {
+                run.push(node);
+                continue;
+            }
+
+            Self::push_run(&mut merged, &mut run);
+            merged.push(node);
+        }
+
+        Self::push_run(&mut merged, &mut run);
+        merged
+    }
+
+    /// Pushes a run of text nodes onto `nodes` as one node, leaving `run`
+    /// empty. A run of a single node is pushed as-is.
+    fn push_run(nodes: &mut Vec<Self>, run: &mut Vec<Self>) {
+        if run.len() < 2 {
+            nodes.append(run);
+            return;
+        }
+
+        let text: String = run
+            .iter()
+            .filter_map(|node| node.directive.literal())
+            .collect();
+
+        nodes.push(Self {
+            directive: Box::new(LiteralDirective(Cow::Owned(text))),
+            kind: SegmentKind::Text,
+            span: run[0].span.start..run[run.len() - 1].span.end,
+            source: None,
+        });
+        run.clear();
+    }
+
+    /// Returns the variable this node substitutes as-is, as in `{name}`.
+    fn substituted_variable(&self) -> Option<&str> {
+        let directive: &dyn Any = self.directive.as_ref();
+
+        match directive.downcast_ref::<ReplaceDirective>()? {
+            ReplaceDirective(Argument::Variable(name)) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of a top-level piece of a template's source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SegmentKind {
+    /// Literal text, rendered as-is, including any escaped delimiters and
+    /// raw blocks within it
+    Text,
+    /// An escaped delimiter such as `{{` with no text around it, rendered as
+    /// a single delimiter, or a text escape such as `\n` when
+    /// `CompileOptions::text_escapes` is on
+    Escape,
+    /// A directive such as `{name}`, delimiters included
+    Directive,
+}
+
+/// A top-level piece of a compiled template, as yielded by `Template::iter`.
+///
+/// Segments cover the template source without gaps or overlaps, in source
+/// order: concatenating the source of each segment's span reproduces the
+/// template that was compiled.
+/// After `Template::optimize`, a text segment may span folded directives,
+/// and folded directives that render nothing leave gaps.
+#[derive(Clone, Copy)]
+pub struct Segment<'a> {
+    node: &'a Node,
+}
+
+impl<'a> Segment<'a> {
+    /// Returns what kind of source this segment was compiled from.
+    pub fn kind(&self) -> SegmentKind {
+        self.node.kind
+    }
+
+    /// Returns the byte range of this segment in the compiled source.
+    ///
+    /// For sub-templates, such as the fragments nested in conditional
+    /// branches, spans are relative to the branch text.
+    pub fn span(&self) -> Range<usize> {
+        self.node.span.clone()
+    }
+
+    /// Returns the source of a directive, delimiters included, or `None` for
+    /// text and escapes.
+    pub fn source(&self) -> Option<&'a str> {
+        self.node.source.as_deref()
+    }
+
+    /// Returns the compiled directive. Text and escapes are `LiteralDirective`s.
+    pub fn directive(&self) -> &'a dyn Directive {
+        self.node.directive.as_ref()
+    }
+
+    /// Returns the names of the variables this segment references, including
+    /// those in nested sub-templates. Each name appears once, in order of first
+    /// reference.
+    pub fn variables(&self) -> Vec<&'a str> {
+        let mut names = Vec::new();
+
+        for arg in self.directive().arguments() {
+            arg.collect_variables(&mut names);
+        }
+
+        let mut seen = HashSet::with_capacity(names.len());
+        names.retain(|name| seen.insert(*name));
+
+        names
+    }
+}
+
+impl fmt::Debug for Segment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Segment")
+            .field("kind", &self.kind())
+            .field("span", &self.span())
+            .field("variables", &self.variables())
+            .finish()
+    }
+}
+
+/// A difference between two templates, as returned by `Template::diff`.
+///
+/// Indices count segments, as yielded by `Template::iter`, and spans are byte
+/// ranges into the source each template was compiled from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TemplateChange {
+    /// A segment of the new template with no counterpart in the old one
+    Added { index: usize, span: Range<usize> },
+    /// A segment of the old template with no counterpart in the new one
+    Removed { index: usize, span: Range<usize> },
+    /// A segment of the old template replaced by one of the new template at
+    /// the same position
+    Modified {
+        old_index: usize,
+        old_span: Range<usize>,
+        new_index: usize,
+        new_span: Range<usize>,
+    },
+}
+
+/// An iterator over the segments of a template, created by `Template::iter`.
+#[derive(Clone)]
+pub struct Segments<'a> {
+    nodes: std::slice::Iter<'a, Node>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.nodes.next().map(|node| Segment { node })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.nodes.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Segments<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.nodes.next_back().map(|node| Segment { node })
+    }
+}
+
+impl ExactSizeIterator for Segments<'_> {}
+
+/// An iterator over the rendered pieces of a template, created by
+/// `Template::chunks`.
+///
+/// Literal text is borrowed from the template, while every other directive is
+/// executed when its chunk is requested. Directives that render nothing yield
+/// no chunk. After an error, the iterator yields nothing more.
+pub struct Chunks<'a> {
+    nodes: std::slice::Iter<'a, Node>,
+    vars: &'a dyn ContextLookup,
+    state: RenderState<'a>,
+    tracks_columns: bool,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Cow<'a, str>, DirectiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for node in self.nodes.by_ref() {
+            let chunk = match node.directive.literal() {
+                Some(text) => Cow::Borrowed(text),
+                None => match node
+                    .directive
+                    .exec(&RenderContext::new(self.vars, &self.state))
+                {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        self.nodes = [].iter();
+                        return Some(Err(e));
+                    }
+                },
+            };
+
+            if !chunk.is_empty() {
+                if self.tracks_columns {
+                    self.state
+                        .column
+                        .set(advance_column(self.state.column.get(), &chunk));
+                }
+                return Some(Ok(chunk));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.nodes.len()))
+    }
+}
+
+impl std::iter::FusedIterator for Chunks<'_> {}
+
+/// A compiled sequence of directives, rendered one after the other.
+///
+/// Fragments are produced by compiling a template. They appear in the public
+/// API as `Argument::Template`, the form a conditional branch takes when its
+/// text contains nested directives.
+pub struct Fragment {
+    nodes: Vec<Node>,
+    /// Sum of the directives' estimated output lengths
+    estimated_len: usize,
+    /// For each node substituting a variable that is substituted more than
+    /// once, the interned id of the variable, which indexes the memo of its
+    /// value while rendering. Empty if no variable is substituted twice.
+    memo_slots: Vec<Option<u32>>,
+    /// The number of distinct variables with an interned id
+    memo_len: usize,
+}
+
+impl Fragment {
+    /// Creates a fragment from its nodes, merging adjacent text and escapes.
+    pub(crate) fn new(nodes: Vec<Node>) -> Self {
+        let nodes = Node::merge_text(nodes);
+        let estimated_len = nodes
+            .iter()
+            .map(|node| node.directive.estimated_len())
+            .fold(0, usize::saturating_add);
+
+        let (memo_slots, memo_len) = Self::intern_variables(&nodes);
+
+        Self {
+            nodes,
+            estimated_len,
+            memo_slots,
+            memo_len,
+        }
+    }
+
+    /// Interns the variables substituted more than once, giving each a small
+    /// id, and returns the id of every node along with the number of ids.
+    fn intern_variables(nodes: &[Node]) -> (Vec<Option<u32>>, usize) {
+        // A single substitution has nothing to share
+        let substituted = nodes.iter().filter_map(Node::substituted_variable);
+        if substituted.take(2).count() < 2 {
+            return (Vec::new(), 0);
+        }
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for name in nodes.iter().filter_map(Node::substituted_variable) {
+            *counts.entry(name).or_default() += 1;
+        }
+
+        if counts.values().all(|&count| count < 2) {
+            return (Vec::new(), 0);
+        }
+
+        let mut ids: HashMap<&str, u32> = HashMap::new();
+        let slots = nodes
+            .iter()
+            .map(|node| {
+                let name = node
+                    .substituted_variable()
+                    .filter(|name| counts[name] > 1)?;
+                let next = ids.len() as u32;
+
+                Some(*ids.entry(name).or_insert(next))
+            })
+            .collect();
+
+        (slots, ids.len())
+    }
+
+    /// Wraps a single directive without a source, such as a braced branch.
+    pub(crate) fn single(directive: Box<dyn Directive>) -> Self {
+        Self::new(vec![Node::built(directive, SegmentKind::Directive)])
+    }
+
+    /// Renders the constant directives of this fragment once, replacing them
+    /// with text that is then merged with the text around it.
+    ///
+    /// Directives that fail or would render more than `MAX_FOLDED_LEN` bytes
+    /// are kept, so that they still fail or stream when rendering, and so are
+    /// directives rendering numbers, such as `{1.5 + 1}`, whose text depends
+    /// on `FormatOptions::number_format`.
+    pub(crate) fn optimize(&mut self) {
+        let empty = crate::Context::new();
+        let state = RenderState::unlimited();
+        let ctx = RenderContext::new(&empty, &state);
+
+        // Rendering numbers with European separators tells apart the
+        // constant directives whose output depends on the number format
+        let european = RenderState {
+            number_format: NumberFormat::EUROPEAN,
+            ..RenderState::unlimited()
+        };
+        let european = RenderContext::new(&empty, &european);
+
+        let nodes = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .filter_map(|node| {
+                if node.kind != SegmentKind::Directive {
+                    return Some(node);
+                }
+
+                let text = match node.directive.literal() {
+                    Some(text) => Cow::Owned(text.to_string()),
+                    None if node.directive.is_constant()
+                        && node.directive.estimated_len() <= MAX_FOLDED_LEN =>
+                    {
+                        match node.directive.exec(&ctx) {
+                            Ok(text)
+                                if text.len() <= MAX_FOLDED_LEN
+                                    && node.directive.exec(&european).ok()
+                                        == Some(text.clone()) =>
+                            {
+                                text
+                            }
+                            _ => return Some(node),
+                        }
+                    }
+                    None => return Some(node),
+                };
+
+                (!text.is_empty()).then(|| Node {
+                    directive: Box::new(LiteralDirective(text)),
+                    kind: SegmentKind::Text,
+                    span: node.span,
+                    source: None,
+                })
+            })
+            .collect();
+
+        *self = Self::new(nodes);
+    }
+
+    /// Returns the nested arguments of every directive in this fragment.
+    pub(crate) fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        self.nodes
+            .iter_mut()
+            .flat_map(|node| node.directive.nested_arguments_mut())
+            .collect()
+    }
+
+    /// Returns the segments of this fragment, in source order.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            nodes: self.nodes.iter(),
+        }
+    }
+
+    /// Returns the segments that differ between this fragment and `other`,
+    /// in source order.
+    ///
+    /// Segments are compared like `PartialEq` compares fragments, and matched
+    /// up with a longest common subsequence, after skipping the common prefix
+    /// and suffix.
+    pub(crate) fn diff(&self, other: &Self) -> Vec<TemplateChange> {
+        let (old, new) = (&self.nodes, &other.nodes);
+
+        let prefix = old
+            .iter()
+            .zip(new)
+            .take_while(|(a, b)| a.same_as(b))
+            .count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a.same_as(b))
+            .count();
+
+        let old_mid = &old[prefix..old.len() - suffix];
+        let new_mid = &new[prefix..new.len() - suffix];
+        let (n, m) = (old_mid.len(), new_mid.len());
+
+        // lengths[i][j] is the length of the longest common subsequence of
+        // old_mid[i..] and new_mid[j..]
+        let mut lengths = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i][j] = if old_mid[i].same_as(&new_mid[j]) {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+
+        let mut changes = Vec::new();
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let (mut i, mut j) = (0, 0);
+
+        while i < n || j < m {
+            if i < n && j < m && old_mid[i].same_as(&new_mid[j]) {
+                Self::push_gap(&mut changes, old, new, &mut removed, &mut added);
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && lengths[i + 1][j] >= lengths[i][j + 1]) {
+                removed.push(prefix + i);
+                i += 1;
+            } else {
+                added.push(prefix + j);
+                j += 1;
+            }
+        }
+
+        Self::push_gap(&mut changes, old, new, &mut removed, &mut added);
+        changes
+    }
+
+    /// Pushes the changes for a run of removed and added segments between two
+    /// matching ones, leaving both runs empty. Removed and added segments are
+    /// paired up in order as modifications; the rest are pushed as they are.
+    fn push_gap(
+        changes: &mut Vec<TemplateChange>,
+        old: &[Node],
+        new: &[Node],
+        removed: &mut Vec<usize>,
+        added: &mut Vec<usize>,
+    ) {
+        let paired = removed.len().min(added.len());
+
+        for (&old_index, &new_index) in removed.iter().zip(added.iter()) {
+            changes.push(TemplateChange::Modified {
+                old_index,
+                old_span: old[old_index].span.clone(),
+                new_index,
+                new_span: new[new_index].span.clone(),
+            });
+        }
+
+        for &index in &removed[paired..] {
+            changes.push(TemplateChange::Removed {
+                index,
+                span: old[index].span.clone(),
+            });
+        }
+
+        for &index in &added[paired..] {
+            changes.push(TemplateChange::Added {
+                index,
+                span: new[index].span.clone(),
+            });
+        }
+
+        removed.clear();
+        added.clear();
+    }
+
+    /// Returns the directives of this fragment, in source order.
+    pub fn directives(&self) -> impl Iterator<Item = &dyn Directive> {
+        self.nodes.iter().map(|node| node.directive.as_ref())
+    }
+
+    /// Returns the text of a fragment holding only literal text, which is
+    /// all merged into one node, or `None` if it holds any directive.
+    pub(crate) fn literal_text(&self) -> Option<&str> {
+        match self.nodes.as_slice() {
+            [] => Some(""),
+            [node] if node.is_text() => node.directive.literal(),
+            _ => None,
+        }
+    }
+
+    /// Returns the estimated length of the rendered output, in bytes.
+    pub fn estimated_len(&self) -> usize {
+        self.estimated_len
+    }
+
+    /// Returns whether a column directive can be rendered, directly or
+    /// through an include, so the output column has to be tracked while
+    /// rendering, see `ColumnDirective`.
+    ///
+    /// This walks the whole fragment, so templates ask once, when created.
+    /// Fragments don't keep the answer, since the branches of an else-if
+    /// ladder are compiled after their fragment is created.
+    pub(crate) fn tracks_columns(&self) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| tracks_columns(node.directive.as_ref()))
+    }
+
+    /// Returns an iterator rendering one directive at a time, which tracks
+    /// the output column if `tracks_columns`.
+    pub(crate) fn chunks<'a>(
+        &'a self,
+        vars: &'a dyn ContextLookup,
+        state: RenderState<'a>,
+        tracks_columns: bool,
+    ) -> Chunks<'a> {
+        Chunks {
+            nodes: self.nodes.iter(),
+            vars,
+            state,
+            tracks_columns,
+        }
+    }
+
+    /// Executes every directive and writes the results to `output`.
+    ///
+    /// Directives that reference missing variables are rendered according to
+    /// `missing`; any other error stops rendering.
+    pub(crate) fn render(
+        &self,
+        ctx: &RenderContext<'_>,
+        missing: &MissingPolicy,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        self.render_lines(ctx, missing, false, 0..self.nodes.len(), output)
+            .map_err(|(_, e)| e)
+    }
+
+    /// Checks that every directive of this fragment would execute against
+    /// `ctx`, stopping at the first that fails, as `render` does with
+    /// `MissingPolicy::Error`. See `Directive::validate`.
+    pub(crate) fn validate(&self, ctx: &RenderContext<'_>) -> Result<(), DirectiveError> {
+        self.nodes
+            .iter()
+            .filter(|node| !node.is_text())
+            .try_for_each(|node| node.directive.validate(ctx))
+    }
+
+    /// Checks every directive of this fragment as a whole template and
+    /// returns the error of each that fails, with its span. Missing
+    /// variables only count with `MissingPolicy::Error`.
+    pub(crate) fn validate_template(
+        &self,
+        ctx: &RenderContext<'_>,
+        missing: &MissingPolicy,
+    ) -> Vec<(Range<usize>, DirectiveError)> {
+        self.nodes
+            .iter()
+            .filter(|node| !node.is_text())
+            .filter_map(|node| match node.directive.validate(ctx) {
+                Err(e) if !e.is_not_found() || *missing == MissingPolicy::Error => {
+                    Some((node.span.clone(), e))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Renders the nodes `nodes` of this fragment as a whole template, whose
+    /// start and end are line boundaries for `FormatOptions::trim_blocks`.
+    /// On failure, also returns the span of the segment that failed.
+    pub(crate) fn render_template(
+        &self,
+        ctx: &RenderContext<'_>,
+        missing: &MissingPolicy,
+        nodes: Range<usize>,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), (Range<usize>, DirectiveError)> {
+        self.render_lines(ctx, missing, true, nodes, output)
+            .map_err(|(index, e)| (self.nodes[index].span.clone(), e))
+    }
+
+    /// Returns the nodes of the region `name`, its markers included, if
+    /// this fragment marks one. See `Template::format_region`.
+    pub(crate) fn region(&self, name: &str) -> Option<Range<usize>> {
+        let start = self.nodes.iter().position(|node| {
+            let any = node.directive.as_ref() as &dyn Any;
+            any.downcast_ref::<RegionDirective>()
+                .is_some_and(|region| region.0 == name)
+        })?;
+
+        let mut depth = 0usize;
+        for (i, node) in self.nodes.iter().enumerate().skip(start) {
+            let any = node.directive.as_ref() as &dyn Any;
+
+            if any.is::<RegionDirective>() {
+                depth += 1;
+            } else if any.is::<RegionEndDirective>() {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some(start..i + 1);
+                }
+            }
+        }
+
+        // Compiling rejects unclosed regions, but built templates may have
+        // them, which run to the end
+        Some(start..self.nodes.len())
+    }
+
+    /// Returns the indices of all the nodes of this fragment.
+    pub(crate) fn all_nodes(&self) -> Range<usize> {
+        0..self.nodes.len()
+    }
+
+    /// Renders the nodes `nodes`, removing the lines of blocks that render
+    /// nothing if `ctx` trims blocks. A nested fragment starts and ends in
+    /// the middle of a line, so only a whole template treats its ends as
+    /// line boundaries. On failure, returns the index of the node that
+    /// failed.
+    fn render_lines(
+        &self,
+        ctx: &RenderContext<'_>,
+        missing: &MissingPolicy,
+        whole: bool,
+        nodes: Range<usize>,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), (usize, DirectiveError)> {
+        // The text of each variable substituted more than once, indexed by
+        // its interned id, resolved and converted on first use. Filtered
+        // values are never cached, since only the raw value is the same
+        // everywhere.
+        let mut memo: Vec<Option<Memoized<'_>>> = Vec::new();
+        memo.resize_with(self.memo_len, || None);
+
+        if !ctx.trim_blocks() {
+            for i in nodes {
+                self.render_node(i, &mut memo, ctx, missing, output)
+                    .map_err(|e| (i, e))?;
+            }
+
+            return Ok(());
+        }
+
+        // Bytes at the start of the next text node that belong to the line
+        // of a block which rendered nothing
+        let mut skip = 0;
+
+        for (i, node) in self
+            .nodes
+            .iter()
+            .enumerate()
+            .take(nodes.end)
+            .skip(nodes.start)
+        {
+            if let Some(text) = node.directive.literal().filter(|_| node.is_text()) {
+                let indent = if i + 1 < self.nodes.len() {
+                    self.block_line(i + 1, whole)
+                        .map_or(0, |(indent, _)| indent)
+                } else {
+                    0
+                };
+                let end = text.len() - indent;
+                output
+                    .write_str(&text[skip.min(end)..end])
+                    .map_err(|e| (i, e.into()))?;
+                skip = 0;
+                continue;
+            }
+
+            let Some((indent, newline)) = self.block_line(i, whole) else {
+                self.render_node(i, &mut memo, ctx, missing, output)
+                    .map_err(|e| (i, e))?;
+                skip = 0;
+                continue;
+            };
+
+            let indent = match i
+                .checked_sub(1)
+                .and_then(|prev| self.nodes[prev].directive.literal())
+            {
+                Some(text) => &text[text.len() - indent..],
+                None => "",
+            };
+            let mut line = LineWriter {
+                indent,
+                written: false,
+                inner: output,
+            };
+            self.render_node(i, &mut memo, ctx, missing, &mut line)
+                .map_err(|e| (i, e))?;
+            skip = if line.written { 0 } else { newline };
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the directive at `index` is a block: the only thing
+    /// on its line but whitespace. If so, returns the length of the
+    /// whitespace before it and of the whitespace and line break after it,
+    /// which are removed when it renders nothing.
+    fn block_line(&self, index: usize, whole: bool) -> Option<(usize, usize)> {
+        if self.nodes[index].is_text() {
+            return None;
+        }
+
+        let text_at = |i: usize| {
+            let node = &self.nodes[i];
+            node.directive.literal().filter(|_| node.is_text())
+        };
+
+        let indent = match index.checked_sub(1) {
+            None if whole => 0,
+            None => return None,
+            Some(prev) => {
+                let text = text_at(prev)?;
+                let line_start = match text.rfind('\n') {
+                    Some(newline) => newline + 1,
+                    None if prev == 0 && whole => 0,
+                    None => return None,
+                };
+                let indent = &text[line_start..];
+                if !indent.bytes().all(|b| b == b' ' || b == b'\t') {
+                    return None;
+                }
+                indent.len()
+            }
+        };
+
+        let newline = match self.nodes.get(index + 1) {
+            None if whole => 0,
+            None => return None,
+            Some(_) => {
+                let text = text_at(index + 1)?;
+                let rest = text.trim_start_matches([' ', '\t']);
+                let spaces = text.len() - rest.len();
+                if rest.starts_with("\r\n") {
+                    spaces + 2
+                } else if rest.starts_with('\n') {
+                    spaces + 1
+                } else if rest.is_empty() && index + 2 == self.nodes.len() && whole {
+                    spaces
+                } else {
+                    return None;
+                }
+            }
+        };
+
+        Some((indent, newline))
+    }
+
+    /// Executes the node at `index`, rendering a missing variable according
+    /// to `missing`.
+    fn render_node<'a>(
+        &'a self,
+        index: usize,
+        memo: &mut [Option<Memoized<'a>>],
+        ctx: &RenderContext<'a>,
+        missing: &MissingPolicy,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        let node = &self.nodes[index];
+        let slot = self.memo_slots.get(index).copied().flatten();
+
+        if !node.is_text() {
+            ctx.count_directive()?;
+        }
+
+        let result = match (slot, node.substituted_variable()) {
+            (Some(slot), Some(name)) => write_memoized(
+                &mut memo[slot as usize],
+                name,
+                node.directive.as_ref(),
+                ctx,
+                output,
+            ),
+            // A variable missing inside the directive, such as in a
+            // conditional branch, can fail after part of the output is
+            // written, which the policy has to replace as a whole
+            (_, None) if *missing != MissingPolicy::Error && !node.is_text() => {
+                render_held(ctx, |out| node.directive.write_to(ctx, out))
+                    .and_then(|held| held.write_to(ctx, output))
+            }
+            _ => node.directive.write_to(ctx, output),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_not_found() && *missing != MissingPolicy::Error => {
+                match missing {
+                    MissingPolicy::Keep => {
+                        output.write_str(node.source.as_deref().unwrap_or(""))?
+                    }
+                    MissingPolicy::Placeholder(text) => output.write_str(text)?,
+                    MissingPolicy::Error | MissingPolicy::Empty => {}
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A writer for a block on a line of its own, which writes the line's
+/// indentation before the block's first output. If the block writes nothing,
+/// neither is the indentation.
+struct LineWriter<'a> {
+    indent: &'a str,
+    written: bool,
+    inner: &'a mut dyn fmt::Write,
+}
+
+impl fmt::Write for LineWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.is_empty() {
+            return Ok(());
+        }
+
+        if !self.written {
+            self.written = true;
+            self.inner.write_str(self.indent)?;
+        }
+
+        self.inner.write_str(s)
+    }
+}
+
+/// The memoized text of a substituted variable.
+enum Memoized<'a> {
+    /// A string, written straight from the context
+    Str(&'a str),
+    /// The text of a value that needed converting, such as a number
+    Text(String),
+}
+
+/// Writes a substituted variable, resolving and converting it only if its
+/// `memo` slot is still empty. Values that fail to render, such as missing
+/// ones, are left to `directive` so that it reports the error.
+fn write_memoized<'a>(
+    memo: &mut Option<Memoized<'a>>,
+    name: &'a str,
+    directive: &dyn Directive,
+    ctx: &RenderContext<'a>,
+    output: &mut dyn fmt::Write,
+) -> Result<(), DirectiveError> {
+    if memo.is_none() {
+        let value = ctx.resolve(name);
+        let placeholder = value
+            .as_deref()
+            .and_then(|value| empty_placeholder(value, ctx));
+        let memoized = match (value, placeholder) {
+            (_, Some(placeholder)) => Memoized::Str(placeholder),
+            (Some(Cow::Borrowed(Value::Str(s))), None) => Memoized::Str(s),
+            (Some(Cow::Owned(Value::Str(s))), None) => Memoized::Text(s.into_owned()),
+            (Some(value), None) if !matches!(*value, Value::Bytes(_) | Value::Array(_)) => {
+                Memoized::Text(
+                    ctx.number_format()
+                        .render(&value)
+                        .unwrap_or_default()
+                        .into_owned(),
+                )
+            }
+            (Some(_) | None, None) => return directive.write_to(ctx, output),
+        };
+
+        *memo = Some(memoized);
+    }
+
+    match memo {
+        Some(Memoized::Str(s)) => output.write_str(s)?,
+        Some(Memoized::Text(text)) => output.write_str(text)?,
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Compares fragments by structure: the kind and directive of every segment,
+/// but not their spans or source. Literal text is compared exactly, so
+/// whitespace differences count.
+impl PartialEq for Fragment {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes.len() == other.nodes.len()
+            && self
+                .nodes
+                .iter()
+                .zip(&other.nodes)
+                .all(|(node, other)| node.same_as(other))
+    }
+}
+
+impl Eq for Fragment {}
+
+/// Hashes the tree written by `Template::debug_tree`, which is equal for
+/// equal fragments.
+impl Hash for Fragment {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut tree = DebugTree::new();
+        tree.fragment(self);
+        tree.finish().hash(state);
+    }
+}
+
+impl fmt::Debug for Fragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fragment")
+            .field("directives", &self.nodes.len())
+            .finish()
+    }
+}
@@ -0,0 +1,192 @@
+//! Classification of template source for syntax highlighting.
+//!
+//! `Template::highlight` splits a template string into spans of literal
+//! text, escapes, delimiters and the tokens of each directive, for editor
+//! integrations that highlight templates without compiling them.
+
+use crate::{DefaultParser, Parser, TemplateLexer, Token, UnknownDirective, closing_delimiter};
+use std::any::Any;
+use std::ops::Range;
+
+/// What a `Highlight` span of template source is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum HighlightKind {
+    /// Literal text, rendered as-is, including a closing delimiter with no
+    /// directive to close
+    Text,
+    /// An escaped delimiter or backslash, such as `{{`, `}}` or `\{`
+    Escape,
+    /// The opening delimiter of a directive
+    Open,
+    /// The closing delimiter of a directive
+    Close,
+    /// An identifier, such as a variable, filter or function name
+    Ident,
+    /// A quoted string literal, quotes included
+    String,
+    /// An integer or float literal
+    Number,
+    /// An operator, such as `==`, `?`, `|`, `+` or `..`
+    Operator,
+    /// Brackets, parentheses, braces and separators such as `:` and `,`
+    Punctuation,
+    /// The raw text of a switch case body, such as `Welcome {name}` in
+    /// `{[tier](gold:Welcome {name})}`
+    CaseBody,
+    /// Something that keeps the template from compiling: an unterminated
+    /// string, the opening delimiter of an unclosed directive, or the
+    /// delimiters and unknown characters of a directive the parser doesn't
+    /// recognize
+    Error,
+}
+
+/// A span of template source together with what it is. Produced by
+/// `Template::highlight`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Highlight {
+    /// What the span is
+    pub kind: HighlightKind,
+    /// The bytes of the source the span covers
+    pub span: Range<usize>,
+}
+
+impl Highlight {
+    fn new(kind: HighlightKind, span: Range<usize>) -> Self {
+        Self { kind, span }
+    }
+}
+
+/// Classifies `input`, a template with the delimiters `open` and `close`,
+/// for `Template::highlight`.
+pub(crate) fn highlight(input: &str, (open, close): (&str, &str)) -> Vec<Highlight> {
+    let first = |s: &str| s.chars().next().unwrap_or_default();
+
+    let mut spans = Vec::new();
+    let mut cursor = 0;
+    let mut pos = 0;
+
+    let text = |spans: &mut Vec<Highlight>, cursor: usize, idx: usize| {
+        if idx > cursor {
+            spans.push(Highlight::new(HighlightKind::Text, cursor..idx));
+        }
+    };
+
+    // Walks text and escapes the same way compiling does
+    while let Some(offset) = input[pos..].find([first(open), first(close), '\\']) {
+        let idx = pos + offset;
+        let rest = &input[idx..];
+
+        let escape_len = if rest.starts_with(open) && rest[open.len()..].starts_with(open) {
+            Some(open.len() * 2)
+        } else if rest.starts_with(open) {
+            None
+        } else if rest.starts_with(close) && rest[close.len()..].starts_with(close) {
+            Some(close.len() * 2)
+        } else if let Some(escaped) = [open, close, "\\"]
+            .into_iter()
+            .find(|escaped| rest.starts_with('\\') && rest[1..].starts_with(escaped))
+        {
+            Some(1 + escaped.len())
+        } else {
+            pos = idx + first(rest).len_utf8();
+            continue;
+        };
+
+        text(&mut spans, cursor, idx);
+
+        if let Some(len) = escape_len {
+            spans.push(Highlight::new(HighlightKind::Escape, idx..idx + len));
+            cursor = idx + len;
+            pos = cursor;
+            continue;
+        }
+
+        let start = idx + open.len();
+        let Ok(Some(end)) = closing_delimiter(input, start, (open, close), usize::MAX) else {
+            // An unclosed directive runs to the end of the input
+            spans.push(Highlight::new(HighlightKind::Error, idx..start));
+            directive(input, start, input.len(), &mut spans);
+            return spans;
+        };
+
+        let open_index = spans.len();
+        spans.push(Highlight::new(HighlightKind::Open, idx..start));
+
+        let delimiter = if directive(input, start, end, &mut spans) {
+            HighlightKind::Close
+        } else {
+            spans[open_index].kind = HighlightKind::Error;
+            HighlightKind::Error
+        };
+        spans.push(Highlight::new(delimiter, end..end + close.len()));
+
+        cursor = end + close.len();
+        pos = cursor;
+    }
+
+    text(&mut spans, cursor, input.len());
+    spans
+}
+
+/// Classifies the tokens of the directive whose contents span `start..end`
+/// of `input`, returning whether the default parser recognizes it.
+fn directive(input: &str, start: usize, end: usize, spans: &mut Vec<Highlight>) -> bool {
+    let mut tokens = Vec::new();
+    let mut unknown = Vec::new();
+
+    for token in TemplateLexer::new(&input[start..end]).spanned() {
+        let span = start + token.span.start..start + token.span.end;
+        let kind = match &token.token {
+            Token::Ident(_) => HighlightKind::Ident,
+            Token::Int(_) | Token::Float(_) => HighlightKind::Number,
+            Token::Literal(_) if !terminated(&input[span.clone()]) => HighlightKind::Error,
+            Token::Literal(_) => HighlightKind::String,
+            Token::Text(_) => HighlightKind::CaseBody,
+            // An unknown character is an error unless the directive uses
+            // it, as in `{@col(30)}`
+            Token::Unknown(_) => {
+                unknown.push(spans.len());
+                HighlightKind::Operator
+            }
+            Token::LParen
+            | Token::RParen
+            | Token::LSquare
+            | Token::RSquare
+            | Token::LCurly
+            | Token::RCurly
+            | Token::Colon
+            | Token::Comma
+            | Token::Semicolon
+            | Token::Underscore => HighlightKind::Punctuation,
+            _ => HighlightKind::Operator,
+        };
+
+        spans.push(Highlight::new(kind, span));
+        tokens.push(token.token);
+    }
+
+    let parses = DefaultParser::parse(&tokens)
+        .is_some_and(|directive| !(directive.as_ref() as &dyn Any).is::<UnknownDirective>());
+    if !parses {
+        for index in unknown {
+            spans[index].kind = HighlightKind::Error;
+        }
+    }
+
+    parses
+}
+
+/// Returns whether the source of a string literal ends with its quote,
+/// rather than with an escaped quote.
+fn terminated(source: &str) -> bool {
+    let Some(quote) = source.chars().next() else {
+        return false;
+    };
+    let Some(inner) = source[quote.len_utf8()..].strip_suffix(quote) else {
+        return false;
+    };
+
+    let backslashes = inner.len() - inner.trim_end_matches('\\').len();
+    backslashes % 2 == 0
+}
@@ -1,16 +1,46 @@
+//! Tokenization of directive contents.
+//!
+//! `TemplateLexer` splits the text between a directive's delimiters into
+//! `Token`s, which parsers match against patterns. `Template::tokens` lexes
+//! every directive of a template as it is iterated, with spans into the
+//! source, and `Template::tokenize` collects them.
+
+use crate::{Markers, closing_delimiter};
 use std::borrow::Cow;
-
+use std::ops::Range;
+
+/// A lexical unit of a directive's contents.
+///
+/// Tokens borrow from the lexed input where possible. ASCII whitespace
+/// separates tokens but produces none, so `a>=1` and ` a >= 1 ` lex alike,
+/// though it can't split one: `> =` is two tokens. Only tokens holding text
+/// keep it: quoted strings, backticked identifiers and switch case bodies.
+/// The delimiters around a directive are not part of its contents. Characters without a meaning of their own become
+/// `Token::Unknown`, so lexing never fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{TemplateLexer, Token};
+///
+/// let tokens: Vec<Token> = TemplateLexer::new("age >= 18 ? 'adult'").collect();
+/// assert_eq!(tokens[0], Token::Ident("age"));
+/// assert_eq!(tokens[1], Token::GreaterThanEquals);
+/// assert_eq!(tokens[2], Token::Int("18"));
+/// ```
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Token<'a> {
     /// An identifier (variable name).
     ///
     /// Identifiers start with a letter or underscore and can contain
-    /// letters, digits, and underscores.
+    /// letters, digits, and underscores. Quoted in backticks, they can
+    /// contain any character but a backtick, such as `-`, `:` or spaces;
+    /// the token holds the name without its backticks.
     ///
-    /// Examples: `name`, `user_id`, `_temp`
+    /// Examples: `name`, `user_id`, `_temp`, `` `content-type` ``
     Ident(&'a str),
 
-    /// Assignment operator `=`.
+    /// Assignment operator `=`, a single `=` not followed by `=` or `~`.
     Assign,
 
     /// An integer literal.
@@ -40,6 +70,8 @@ pub enum Token<'a> {
     RCurly,
     /// Colon `:`.
     Colon,
+    /// Comma `,` (separates filter arguments).
+    Comma,
     /// Semicolon `;`.
     Semicolon,
 
@@ -49,6 +81,8 @@ pub enum Token<'a> {
     Pipe,
     /// Underscore `_`.
     Underscore,
+    /// Caret `^` (used for centered alignment).
+    Caret,
 
     /// A string literal enclosed in quotes.
     ///
@@ -58,6 +92,10 @@ pub enum Token<'a> {
     /// The `Cow` allows for zero-copy when there are no escape sequences,
     /// but allocates when escape processing is needed.
     ///
+    /// The unquoted arguments of the `replace` and `strip` filters are
+    /// literals too, and so are switch case labels with escapes such as
+    /// `a\:b`, see `TemplateLexer`.
+    ///
     /// Examples: `"hello"`, `'world'`, `"line\nbreak"`
     Literal(Cow<'a, str>),
 
@@ -78,6 +116,8 @@ pub enum Token<'a> {
     Equals,
     /// Inequality `!=`.
     NotEquals,
+    /// Case-insensitive equality `~=`.
+    EqualsIgnoreCase,
     /// Greater than `>`.
     GreaterThan,
     /// Greater than or equal `>=`.
@@ -86,11 +126,32 @@ pub enum Token<'a> {
     LessThan,
     /// Less than or equal `<=`.
     LessThanEquals,
+    /// Substring test `*=`.
+    Contains,
+    /// Prefix test `^=`.
+    StartsWith,
+    /// Suffix test `$=`.
+    EndsWith,
+    /// Regular expression match `=~`.
+    Matches,
+    /// Range `..` (used in `in` conditions).
+    Range,
+    /// Coalescing `??` (picks the first operand that is present).
+    Coalesce,
     /// Logical AND `&&`.
     And,
     /// Logical OR `||`.
     Or,
 
+    /// The raw text of a switch case body.
+    ///
+    /// Case bodies are not tokenized: everything after a case's `:` up to
+    /// its closing `)` is kept verbatim, whitespace included. Parentheses
+    /// must balance unless escaped as `\(` or `\)`.
+    ///
+    /// Example: in `[tier](gold:Welcome {name})`, the body `Welcome {name}`
+    Text(Cow<'a, str>),
+
     /// An unknown/unexpected character.
     ///
     /// Used when the lexer encounters a character it doesn't recognize.
@@ -98,19 +159,175 @@ pub enum Token<'a> {
     Unknown(char),
 }
 
+/// A token together with the byte range of the input it was read from.
+///
+/// Produced by `TemplateLexer::spanned`, with spans relative to the lexed
+/// input, and by `Template::tokenize`, with spans into the template source.
+/// String literals span their quotes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken<'a> {
+    /// The token itself
+    pub token: Token<'a>,
+    /// The bytes the token was read from
+    pub span: Range<usize>,
+}
+
+/// Where the lexer is within a switch directive, `[x](label:body)..`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaseState {
+    /// Not directly after a scrutinee or a case
+    Outside,
+    /// After `]` or a case's `)`: a `(` opens a case
+    Open,
+    /// Inside a case label: a `:` starts the body
+    Label,
+    /// The next token is the raw case body
+    Body,
+    /// After a case body: a `)` closes the case
+    Close,
+}
+
+/// Where the lexer is within a filter taking text arguments, such as
+/// `:replace(from, to)` or `:rstrip(chars)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplaceState {
+    /// Not directly after a filter separator or within such a filter
+    Outside,
+    /// After a `:` or `|`: a filter name may follow
+    Spec,
+    /// After the filter name: a `(` opens its arguments
+    Name,
+    /// The next token is an argument
+    Arg,
+    /// After an argument: a `,` starts the next one
+    Separator,
+}
+
+/// A lexer for the contents of a single directive, the text between its
+/// delimiters.
+///
+/// Iterating yields the tokens in order; `spanned` yields them with their
+/// spans.
+///
+/// The arguments of a filter named `replace`, `strip`, `lstrip` or `rstrip`,
+/// after a `:` or `|`, are read as text: each is a `Token::Literal` of
+/// everything up to the next `,` or closing `)`, trimmed, unless it is
+/// quoted. A backslash escapes any ASCII punctuation in them, such as `\,` or
+/// `\\`, and parentheses must balance unless escaped.
+///
+/// An unquoted switch case label containing `\:`, `\(`, `\)` or `\\`, as in
+/// `[x](a\:b:yes)`, is read as one `Token::Literal` of the unescaped label.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{TemplateLexer, Token};
+///
+/// let mut lexer = TemplateLexer::new("name:trunc(20)");
+/// assert_eq!(lexer.next(), Some(Token::Ident("name")));
+/// assert_eq!(lexer.next(), Some(Token::Colon));
+/// assert_eq!(lexer.next(), Some(Token::Ident("trunc")));
+/// ```
 pub struct TemplateLexer<'a> {
     input: &'a str,
     bytes: &'a [u8],
     cursor: usize,
+    /// Where the last token read starts
+    token_start: usize,
+    case: CaseState,
+    replace: ReplaceState,
+    /// The bytes that stand for `?`, `:`, `[`, `]`, `(` and `)`
+    markers: [u8; 6],
+}
+
+/// Returns whether `text` is an identifier that needs no backticks, such as
+/// `name` but not `` `content-type` ``.
+pub(crate) fn is_plain_ident(text: &str) -> bool {
+    let mut chars = text.chars();
+    let first = match chars.next() {
+        Some('_') => !chars.as_str().is_empty(),
+        Some(first) => first.is_ascii_alphabetic(),
+        None => false,
+    };
+
+    first && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
+/// The default markers, in the order of `Markers::chars`.
+const MARKERS: [u8; 6] = *b"?:[]()";
+
 impl<'a> TemplateLexer<'a> {
+    /// Creates a lexer for the contents of a directive.
     pub fn new(input: &'a str) -> Self {
         Self {
             input,
             bytes: input.as_bytes(),
             cursor: 0,
+            token_start: 0,
+            case: CaseState::Outside,
+            replace: ReplaceState::Outside,
+            markers: MARKERS,
+        }
+    }
+
+    /// Creates a lexer for the contents of a directive that marks
+    /// conditionals and switches with `markers`.
+    ///
+    /// Each marker lexes as the token of the character it replaces, and a
+    /// replaced character that is not a marker itself as `Token::Unknown`.
+    /// Markers that are not ASCII are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Markers, TemplateLexer, Token};
+    ///
+    /// let markers = Markers { question: '#', ..Default::default() };
+    /// let tokens: Vec<_> = TemplateLexer::with_markers("ok # a ?", &markers).collect();
+    /// assert_eq!(tokens[1], Token::Question);
+    /// assert_eq!(tokens[3], Token::Unknown('?'));
+    /// ```
+    pub fn with_markers(input: &'a str, markers: &Markers) -> Self {
+        let mut lexer = Self::new(input);
+
+        for (byte, marker) in lexer.markers.iter_mut().zip(markers.chars()) {
+            if marker.is_ascii() {
+                *byte = marker as u8;
+            }
         }
+
+        lexer
+    }
+
+    /// Returns the default marker that `b` stands for, `0` for a default
+    /// marker that was replaced, or else `b` itself.
+    #[inline]
+    fn marker(&self, b: u8) -> u8 {
+        if self.markers == MARKERS {
+            return b;
+        }
+
+        match self.markers.iter().position(|&m| m == b) {
+            Some(i) => MARKERS[i],
+            None if MARKERS.contains(&b) => 0,
+            None => b,
+        }
+    }
+
+    /// Turns the lexer into an iterator over tokens and their spans within
+    /// the input.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{TemplateLexer, Token};
+    ///
+    /// let tokens: Vec<_> = TemplateLexer::new("a ? 'yes'").spanned().collect();
+    /// assert_eq!(tokens[1].token, Token::Question);
+    /// assert_eq!(tokens[2].span, 4..9);
+    /// ```
+    pub fn spanned(mut self) -> impl Iterator<Item = SpannedToken<'a>> {
+        std::iter::from_fn(move || self.next_spanned())
     }
 
     #[inline]
@@ -123,7 +340,7 @@ impl<'a> TemplateLexer<'a> {
     }
 
     #[inline]
-    fn peek(&mut self) -> u8 {
+    fn peek(&self) -> u8 {
         if self.cursor + 1 < self.bytes.len() {
             self.bytes[self.cursor + 1]
         } else {
@@ -146,14 +363,13 @@ impl<'a> TemplateLexer<'a> {
     }
 
     fn read_literal(&mut self, del: char) -> Cow<'a, str> {
-        let del = del as u8;
         let start = self.cursor;
         let mut tmp_cursor = start;
         let mut escaped = false;
 
         while tmp_cursor < self.bytes.len() {
             match self.bytes[tmp_cursor] {
-                c if c == del => break,
+                c if c == del as u8 => break,
                 b'\\' => {
                     escaped = true;
                     tmp_cursor += 2;
@@ -169,35 +385,188 @@ impl<'a> TemplateLexer<'a> {
             return Cow::Borrowed(&self.input[start..start + len]);
         }
 
+        // Escapes are decoded by `char`, so multi-byte text around them and
+        // escaped multi-byte chars are copied whole
         let mut out = String::with_capacity(tmp_cursor - start);
+        let mut chars = self.input[start..].chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                c if c == del => break,
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('0') => out.push('\0'),
+                    Some(c) => out.push(c),
+                    None => {}
+                },
+                c => out.push(c),
+            }
+        }
+
+        self.cursor = self.input.len() - chars.as_str().len();
+
+        Cow::Owned(out)
+    }
+
+    /// Reads a switch case body up to, but not including, its closing `)`.
+    fn read_case_body(&mut self) -> Cow<'a, str> {
+        let start = self.cursor;
+        let mut depth = 0usize;
+        let mut escaped = false;
 
         while self.cursor < self.bytes.len() {
-            match self.current() {
-                c if c == del => {
-                    self.advance();
-                    break;
+            match self.marker(self.current()) {
+                b'\\' if matches!(self.marker(self.peek()), b'(' | b')' | b'\\') => {
+                    escaped = true;
+                    self.cursor += 2;
                 }
-                b'\\' => {
-                    self.advance();
-
-                    if self.cursor < self.bytes.len() {
-                        match self.current() {
-                            b'n' => out.push('\n'),
-                            b't' => out.push('\t'),
-                            b'r' => out.push('\r'),
-                            b'\\' => out.push('\\'),
-                            b'0' => out.push('\0'),
-                            c if c == del => out.push(del as char),
-                            c => out.push(c as char),
-                        }
-
-                        self.advance();
+                b'(' => {
+                    depth += 1;
+                    self.cursor += 1;
+                }
+                b')' if depth == 0 => break,
+                b')' => {
+                    depth -= 1;
+                    self.cursor += 1;
+                }
+                _ => self.cursor += 1,
+            }
+        }
+
+        let raw = &self.input[start..self.cursor];
+
+        if !escaped {
+            return Cow::Borrowed(raw);
+        }
+
+        let mut out = String::with_capacity(raw.len());
+        let mut chars = raw.chars();
+
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                // Only `\(`, `\)` and `\\` were escapes; others are kept
+                match chars.clone().next() {
+                    Some(next) if next.is_ascii() && b"()\\".contains(&self.marker(next as u8)) => {
+                        out.push(next);
+                        chars.next();
                     }
+                    _ => out.push(c),
                 }
-                c => {
-                    out.push(c as char);
-                    self.advance();
+            } else {
+                out.push(c);
+            }
+        }
+
+        Cow::Owned(out)
+    }
+
+    /// Reads an unquoted switch case label containing escapes, such as
+    /// `a\:b` or `x\)y`, up to, but not including, the `:` ending it or a
+    /// trailing `*` of a prefix. Returns `None`, without advancing, if no
+    /// escape comes before the label ends, leaving it to the usual tokens.
+    fn read_case_label(&mut self) -> Option<Cow<'a, str>> {
+        let start = self.cursor;
+        let mut end = start;
+        let mut escaped = false;
+
+        while end < self.bytes.len() {
+            match self.marker(self.bytes[end]) {
+                b'\\'
+                    if end + 1 < self.bytes.len()
+                        && matches!(
+                            self.marker(self.bytes[end + 1]),
+                            b':' | b'(' | b')' | b'\\'
+                        ) =>
+                {
+                    escaped = true;
+                    end += 2;
                 }
+                b':' | b')' => break,
+                _ => end += 1,
+            }
+        }
+
+        if !escaped {
+            return None;
+        }
+
+        let mut raw = self.input[start..end].trim_end();
+
+        // A trailing `*` makes the label a prefix
+        if let Some(prefix) =
+            raw.strip_suffix(|c: char| c.is_ascii() && self.marker(c as u8) == b'*')
+            && !prefix.ends_with('\\')
+        {
+            raw = prefix.trim_end();
+        }
+
+        self.cursor = start + raw.len();
+
+        let mut out = String::with_capacity(raw.len());
+        let mut chars = raw.chars();
+
+        while let Some(c) = chars.next() {
+            match chars.clone().next() {
+                Some(next)
+                    if c.is_ascii()
+                        && self.marker(c as u8) == b'\\'
+                        && next.is_ascii()
+                        && b":()\\".contains(&self.marker(next as u8)) =>
+                {
+                    out.push(next);
+                    chars.next();
+                }
+                _ => out.push(c),
+            }
+        }
+
+        Some(Cow::Owned(out))
+    }
+
+    /// Reads an unquoted text argument of a filter up to, but not including,
+    /// the next `,` or closing `)`, trimming whitespace around it.
+    fn read_filter_arg(&mut self) -> Cow<'a, str> {
+        let start = self.cursor;
+        let mut depth = 0usize;
+        let mut escaped = false;
+
+        while self.cursor < self.bytes.len() {
+            match self.marker(self.current()) {
+                b'\\' if self.peek().is_ascii_punctuation() => {
+                    escaped = true;
+                    self.cursor += 2;
+                }
+                b'(' => {
+                    depth += 1;
+                    self.cursor += 1;
+                }
+                b',' | b')' if depth == 0 => break,
+                b')' => {
+                    depth -= 1;
+                    self.cursor += 1;
+                }
+                _ => self.cursor += 1,
+            }
+        }
+
+        let raw = self.input[start..self.cursor].trim_end();
+
+        if !escaped {
+            return Cow::Borrowed(raw);
+        }
+
+        let mut out = String::with_capacity(raw.len());
+        let mut chars = raw.chars();
+
+        while let Some(c) = chars.next() {
+            match chars.clone().next() {
+                Some(next) if c == '\\' && next.is_ascii_punctuation() => {
+                    out.push(next);
+                    chars.next();
+                }
+                _ => out.push(c),
             }
         }
 
@@ -218,6 +587,20 @@ impl<'a> TemplateLexer<'a> {
         &self.input[start..self.cursor]
     }
 
+    /// Reads an identifier quoted in backticks, after the opening backtick.
+    /// An unclosed quote is an unknown token, so the directive fails to parse.
+    fn read_quoted_ident(&mut self) -> Token<'a> {
+        let start = self.cursor;
+
+        match self.input[start..].find('`') {
+            Some(len) => {
+                self.cursor = start + len + 1; // skip closing backtick
+                Token::Ident(&self.input[start..start + len])
+            }
+            None => Token::Unknown('`'),
+        }
+    }
+
     fn read_number(&mut self, start: usize) -> Token<'a> {
         let mut is_float = false;
 
@@ -250,7 +633,7 @@ impl<'a> TemplateLexer<'a> {
         matched: Token<'a>,
         unmatched: Token<'a>,
     ) -> Token<'a> {
-        if self.current() == expected {
+        if self.marker(self.current()) == expected {
             self.advance();
             matched
         } else {
@@ -258,7 +641,23 @@ impl<'a> TemplateLexer<'a> {
         }
     }
 
+    fn next_spanned(&mut self) -> Option<SpannedToken<'a>> {
+        let token = self.next_token()?;
+
+        // An unterminated literal leaves the cursor past the end
+        Some(SpannedToken {
+            token,
+            span: self.token_start..self.cursor.min(self.input.len()),
+        })
+    }
+
     fn next_token(&mut self) -> Option<Token<'a>> {
+        if self.case == CaseState::Body {
+            self.case = CaseState::Close;
+            self.token_start = self.cursor;
+            return Some(Token::Text(self.read_case_body()));
+        }
+
         self.skip_whitespace();
 
         if self.cursor >= self.bytes.len() {
@@ -266,10 +665,33 @@ impl<'a> TemplateLexer<'a> {
         }
 
         let start = self.cursor;
-        let ch = self.current();
+        self.token_start = start;
+
+        if self.replace == ReplaceState::Arg && !matches!(self.current(), b'\'' | b'"') {
+            self.replace = ReplaceState::Separator;
+            return Some(Token::Literal(self.read_filter_arg()));
+        }
+
+        if self.case == CaseState::Label
+            && !matches!(self.current(), b'\'' | b'"')
+            && let Some(label) = self.read_case_label()
+        {
+            self.replace = ReplaceState::Outside;
+            return Some(Token::Literal(label));
+        }
+
+        let ch = self.marker(self.current());
         self.advance();
 
-        match ch {
+        self.case = match (self.case, ch) {
+            (_, b']') | (CaseState::Close, b')') => CaseState::Open,
+            (CaseState::Open, b'(') => CaseState::Label,
+            (CaseState::Label, b':') => CaseState::Body,
+            (CaseState::Label, _) => CaseState::Label,
+            _ => CaseState::Outside,
+        };
+
+        let token = match ch {
             b'(' => Some(Token::LParen),
             b')' => Some(Token::RParen),
             b'[' => Some(Token::LSquare),
@@ -277,20 +699,30 @@ impl<'a> TemplateLexer<'a> {
             b'{' => Some(Token::LCurly),
             b'}' => Some(Token::RCurly),
             b':' => Some(Token::Colon),
+            b',' => Some(Token::Comma),
             b';' => Some(Token::Semicolon),
-            b'?' => Some(Token::Question),
+            b'?' => Some(self.check_double(b'?', Token::Coalesce, Token::Question)),
             b'+' => Some(Token::Plus),
             b'-' => Some(Token::Minus),
-            b'*' => Some(Token::Star),
+            b'*' => Some(self.check_double(b'=', Token::Contains, Token::Star)),
             b'/' => Some(Token::Slash),
+            b'^' => Some(self.check_double(b'=', Token::StartsWith, Token::Caret)),
+            b'~' => Some(self.check_double(b'=', Token::EqualsIgnoreCase, Token::Unknown('~'))),
+            b'$' => Some(self.check_double(b'=', Token::EndsWith, Token::Unknown('$'))),
+            b'=' if self.marker(self.current()) == b'~' => {
+                self.advance();
+                Some(Token::Matches)
+            }
             b'=' => Some(self.check_double(b'=', Token::Equals, Token::Assign)),
             b'!' => Some(self.check_double(b'=', Token::NotEquals, Token::Not)),
             b'<' => Some(self.check_double(b'=', Token::LessThanEquals, Token::LessThan)),
             b'>' => Some(self.check_double(b'=', Token::GreaterThanEquals, Token::GreaterThan)),
             b'&' => Some(self.check_double(b'&', Token::And, Token::Unknown('&'))),
             b'|' => Some(self.check_double(b'|', Token::Or, Token::Pipe)),
+            b'.' => Some(self.check_double(b'.', Token::Range, Token::Unknown('.'))),
             b'"' => Some(Token::Literal(self.read_literal('"'))),
             b'\'' => Some(Token::Literal(self.read_literal('\''))),
+            b'`' => Some(self.read_quoted_ident()),
             b'_' => {
                 let next = self.current();
                 if next.is_ascii_alphabetic() || next.is_ascii_digit() || next == b'_' {
@@ -301,8 +733,28 @@ impl<'a> TemplateLexer<'a> {
             }
             b if b.is_ascii_alphabetic() => Some(Token::Ident(self.read_ident(start))),
             b if b.is_ascii_digit() => Some(self.read_number(start)),
-            b => Some(Token::Unknown(b as char)),
-        }
+            _ => {
+                // Read the whole char, so that the next token starts on a
+                // char boundary even after a multi-byte char
+                let c = self.input[start..].chars().next().unwrap_or_default();
+                self.cursor = start + c.len_utf8();
+
+                Some(Token::Unknown(c))
+            }
+        };
+
+        self.replace = match (self.replace, &token) {
+            (_, Some(Token::Colon | Token::Pipe)) => ReplaceState::Spec,
+            (ReplaceState::Spec, Some(Token::Ident("replace" | "strip" | "lstrip" | "rstrip"))) => {
+                ReplaceState::Name
+            }
+            (ReplaceState::Name, Some(Token::LParen)) => ReplaceState::Arg,
+            (ReplaceState::Arg, Some(Token::Literal(_))) => ReplaceState::Separator,
+            (ReplaceState::Separator, Some(Token::Comma)) => ReplaceState::Arg,
+            _ => ReplaceState::Outside,
+        };
+
+        token
     }
 }
 
@@ -313,3 +765,117 @@ impl<'a> Iterator for TemplateLexer<'a> {
         self.next_token()
     }
 }
+
+/// An iterator over the tokens of every directive of a template string, with
+/// their spans in the source. Created by `Template::tokens`.
+///
+/// Directives are found and lexed one at a time as the iterator advances, so
+/// tooling that stops early never scans the rest of the input, and no vector
+/// of tokens is built. Like `Template::tokenize`, which collects it, this
+/// never fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Template, Token};
+///
+/// let mut tokens = Template::<'{', '}'>::tokens("Hi {name} and {{ {other}");
+///
+/// let first = tokens.next().unwrap();
+/// assert_eq!(first.token, Token::Ident("name"));
+/// assert_eq!(first.span, 4..8);
+/// assert_eq!(tokens.next().unwrap().token, Token::Ident("other"));
+/// assert_eq!(tokens.next(), None);
+/// ```
+pub struct Tokens<'a> {
+    input: &'a str,
+    open: char,
+    close: char,
+    /// Where the search for the next directive resumes
+    pos: usize,
+    /// The lexer of the current directive, with where its contents start
+    lexer: Option<(usize, TemplateLexer<'a>)>,
+}
+
+impl<'a> Tokens<'a> {
+    /// Creates an iterator over the directives of `input`, delimited by
+    /// `open` and `close`.
+    pub(crate) fn new(input: &'a str, open: char, close: char) -> Self {
+        Self {
+            input,
+            open,
+            close,
+            pos: 0,
+            lexer: None,
+        }
+    }
+
+    /// Finds the next directive, returning the span of its contents. Skips
+    /// text and escapes the same way compiling does.
+    fn next_directive(&mut self) -> Option<Range<usize>> {
+        let input = self.input;
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        let (open, close) = (
+            &*self.open.encode_utf8(&mut open),
+            &*self.close.encode_utf8(&mut close),
+        );
+
+        while let Some(offset) = input[self.pos..].find([self.open, self.close, '\\']) {
+            let idx = self.pos + offset;
+            let rest = &input[idx..];
+
+            let Some(after) = rest.strip_prefix(open) else {
+                let escaped = [open, close, "\\"]
+                    .into_iter()
+                    .find(|escaped| rest.starts_with('\\') && rest[1..].starts_with(escaped));
+
+                self.pos = match escaped {
+                    Some(escaped) => idx + 1 + escaped.len(),
+                    None if rest.starts_with(close) && rest[close.len()..].starts_with(close) => {
+                        idx + close.len() * 2
+                    }
+                    None => idx + rest.chars().next().map_or(1, char::len_utf8),
+                };
+                continue;
+            };
+
+            if after.starts_with(open) {
+                self.pos = idx + open.len() * 2;
+                continue;
+            }
+
+            // An unclosed directive is lexed up to the end of the input
+            let start = idx + open.len();
+            let end = closing_delimiter(input, start, (open, close), usize::MAX)
+                .ok()
+                .flatten()
+                .unwrap_or(input.len());
+
+            self.pos = (end + close.len()).min(input.len());
+            return Some(start..end);
+        }
+
+        self.pos = input.len();
+        None
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = SpannedToken<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((start, lexer)) = &mut self.lexer
+                && let Some(token) = lexer.next_spanned()
+            {
+                return Some(SpannedToken {
+                    token: token.token,
+                    span: *start + token.span.start..*start + token.span.end,
+                });
+            }
+
+            let contents = self.next_directive()?;
+            self.lexer = Some((contents.start, TemplateLexer::new(&self.input[contents])));
+        }
+    }
+}
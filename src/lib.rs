@@ -8,11 +8,82 @@
 //! ## Features
 //!
 //! - **Variable substitution**: `{name}` - Replace with context values
+//! - **Quoted names**: `` {`content-type`} `` - Reference keys containing `-`, `:`, spaces or other characters
 //! - **Repeating patterns**: `{pattern:count}` - Repeat a pattern N times
-//! - **Conditionals**: `{condition ? true_value : false_value}` - Ternary expressions
-//! - **Comparisons**: Support for `==`, `!=`, `>`, `<`, `>=`, `<=`
+//! - **Alignment**: `{name:<10}`, `{name:>10}`, `{name:^10}` - Pad a value to a fixed width
+//! - **Padding**: `{id:pad_start(6, 0)}`, `{name:pad_end(20)}` - Pad a value to a length with a fill char, in a filter chain
+//! - **Truncation**: `{bio:trunc(80)}` - Shorten long values, appending an ellipsis
+//! - **Slicing**: `{code:slice(0, 4)}`, `{code:slice(-2)}` - Extract a range of chars, counting negative indices from the end
+//! - **Length**: `{name:len}` - Render the number of chars in a string
+//! - **Reversing and repeating**: `{name:reverse}`, `{sep:repeat(3)}` - Reverse by grapheme clusters or repeat a value
+//! - **Squishing**: `{comment:squish}` - Trim a value and collapse its whitespace runs, Unicode-aware, to single spaces
+//! - **Replacing**: `{path:replace(\\, /)}` - Replace every occurrence of a substring, with unquoted arguments
+//! - **Stripping**: `{path:rstrip(/)}`, `{code:lstrip(0)}` - Strip a set of chars from the ends of a value
+//! - **Indenting**: `{block:indent(4)}`, `{block:indent}` - Indent the lines of a multi-line value by a width or to the directive's column
+//! - **Encoding**: `{q:urlenc}`, `{q:urlenc(form)}` and, with the `base64` feature, `{data:base64}` - Encode values for URLs and data
+//! - **Markdown and shell escaping**: `{text:md}`, `{arg:sh}` - Escape Markdown syntax or quote a value as one shell word
+//! - **Digests**: `{content:sha256}`, `{path:crc32(8)}` with the `digest` feature - Render hex hashes for cache-busting and integrity checks
+//! - **Number bases**: `{n:hex}`, `{n:#HEX}`, `{n:oct}`, `{n:bin}` - Render integers in another base
+//! - **Signs and zero-padding**: `{delta:+}`, `{id:05}`, `{delta:+05}` - Format numbers like `format!` does
+//! - **Rounding**: `{x:round}`, `{x:floor}`, `{x:ceil(2)}` - Round floats to integers or decimal places
+//! - **Currency**: `{total:currency(USD)}` - Render numbers as money, grouped by thousands
+//! - **Percentages**: `{ratio:percent}`, `{ratio:percent(1)}` - Render ratios such as `0.1234` as `12.34%`
+//! - **Booleans**: `{active:bool(Yes, No)}` - Render one of two texts for a boolean
+//! - **Filter chains**: `{name | trunc(20) | >24}` - Apply filters from left to right
+//! - **Conditionals**: `{condition ? true_value : false_value}` - Ternary expressions, with an optional else branch; branches may contain directives
+//! - **Logical operators**: `{(a || b) && !c ? 'yes' : 'no'}` - Combine conditions with short-circuiting `&&` and `||`, grouped by parentheses
+//! - **Else-if ladders**: `{a ? x : b ? y : z}` - Chained conditionals, tried from left to right
+//! - **Fallbacks**: `{nick | {name} | 'anonymous'}` - Render another variable or a literal when one is missing
+//! - **Coalescing**: `{nick ?? name ?? 'anonymous'}` - Render the first value that is present and not null
+//! - **Presence tests**: `{defined(coupon) ? 'saved'}` - Branch on whether a variable is set, whatever its value
+//! - **Type tests**: `{is_number(x) ? 'n' : 's'}` - Branch on the type of a value with `is_string`, `is_number`, `is_null` and the like
+//! - **Error messages**: `{price !! 'price is required'}` - Replace a directive's error with a friendlier message
+//! - **Empty placeholders**: `FormatOptions::empty_placeholder` - Render `N/A` or the like for null and empty variables
+//! - **Unknown directives**: `FormatOptions::unknown` - Fail on, pass through or drop directives the parser doesn't recognize
+//! - **Empty directives**: `CompileOptions::empty_directives` - Reject `{}` and `{   }`, keep them as text or render nothing
+//! - **Mismatched filters**: `FormatOptions::filter_mismatch` - Fail on, skip or coerce values of a type a filter doesn't accept
+//! - **Default filters**: `FormatOptions::schema` - Format a variable the same way wherever `{price}` substitutes it, unless a spec overrides it
+//! - **Boolean keywords**: `FormatOptions::bool_keywords` - Read strings such as `"yes"` and `"off"` as booleans in conditions
+//! - **Line endings**: `FormatOptions::newline` - Write every line break of the output as `\n` or `\r\n`, whatever the template was saved with
+//! - **Diagnostics**: `format_spanned` - Point rendering errors at the failing directive, with a caret under it in the source
+//! - **Validation**: `template.validate(&ctx, &options)` - Find every directive that would fail to render, without building the output
+//! - **Switches**: `{[tier](gold:Welcome {name})(*:Hello)}` - Render the first case matching a value, starting with a prefix as in `(ERROR*:...)` or satisfying a comparison as in `(>=90:A)`
+//! - **Loops**: `{items*'{@index}: {.} '}` - Render a body once per item of an array, with `.` as the item and `@index`, `@count`, `@first` and `@last` in scope
+//! - **Indexing**: `{items[0]}`, `{items[i]}`, `{items[-1]}` - Render an item of an array, counting negative indices from the end
+//! - **Switches as values**: `{[tier](gold:2)(*:0) + bonus}` - Use the selected case in conditions, sums and function calls
+//! - **Comparisons**: Support for `==`, `!=`, `>`, `<`, `>=`, `<=` and case-insensitive `~=`
+//! - **Addition and concatenation**: `{first + ' ' + last}`, `{count + 1}` - Join strings or add numbers
+//! - **Ranges**: `{age in 13..19 ? 'teen'}` - Test that a number lies between two inclusive bounds
+//! - **Lists**: `{role in ['admin', 'editor'] ? 'staff'}` - Test that a value equals one of the items of a list
+//! - **String membership**: `*=` (contains), `^=` (starts with), `$=` (ends with)
+//! - **Functions**: `{min(max(score, 0), 100)}`, `{abs(delta)}` - Compute numbers, keeping integers as integers
+//! - **Regex matches**: `{email =~ '^.+@.+$' ? 'valid'}` with the `regex` feature
 //! - **Custom Logic**: You can implement custom logic using the `Logic` and `Parser` traits
-//! - **Custom delimiters**: Use any characters as open/close delimiters
+//! - **Includes**: `{>header}` - Render a named partial from a `TemplateRegistry` with `format_with`
+//! - **Highlighting**: `Template::highlight(source)` - Classify template source into spans for editors, errors included
+//! - **Columns**: `{name}{@col(20)}{role}` - Pad the current output line to a column, to align tables
+//! - **Reusable renderers**: `template.renderer(options)` - Render many contexts into one reused buffer
+//! - **Template caches**: `TemplateCache::new(capacity)` - Compile each template string once, keeping the most recently used templates
+//! - **Infallible rendering**: `template.total()` - Render templates of plain substitutions to a `String` without a `Result`
+//! - **Display adapter**: `write!(f, "{}", template.display(&ctx)?)` - Render straight into a formatter, with errors reported up front
+//! - **Builder**: `TemplateBuilder::new().literal("Hi ").var("name").build()` - Assemble templates in code without parsing
+//! - **Custom delimiters**: Use any characters as open/close delimiters, or strings such as `${`/`}` chosen at runtime
+//! - **Custom markers**: `CompileOptions::markers` - Replace the `?`, `:`, `[`, `]`, `(` and `)` of conditionals and switches
+//! - **Lazy values**: `LazyContext::insert_lazy` - Compute expensive values only when a rendered directive uses them
+//! - **Raw blocks**: `{@raw}{name}{@endraw}` - Output text verbatim, delimiters included, without escaping them
+//! - **Regions**: `template.format_region("header", &ctx)` - Render the part of a template marked `{@region:header}...{@end}` on its own
+//! - **Writing back**: `template.to_source()` - Write a compiled template back as canonical source
+//! - **Diffing**: `old.diff(&new)` - List the segments that changed between two versions of a template
+//! - **Change detection**: `template.affected_by(&old, &new)` - Tell whether a context update changes any variable the template uses
+//! - **Metrics**: `template.metrics()` - Count nodes and directives by kind, nesting depth and the longest possible output
+//! - **Compile statistics**: `Template::compile_with_stats` - Count the bytes scanned, tokens lexed and token buffer growths of compiling a template
+//! - **Static templates**: `template.render_static()` - Get the output of a template that doesn't depend on the context, rendered once
+//! - **Condition analysis**: `template.const_conditions()` - Walk parsed conditions and find those that always select the same branch
+//! - **Filter and function listing**: `template.filters_used()` - List the filters and functions a template uses, to check them before formatting
+//! - **Positional arguments**: `template.format_args(&[Value::Int(1)])` - Render `{0}`, `{1}` from a slice of values
+//! - **Checked at compile time**: `figura_macros::f!("Hi {name}", name = "Ann")` - Reject unknown variables before running
+//! - **One-shot rendering**: `figura::render_default("Hi {name}", &ctx)` - Compile and render in one call
+//! - **Single values**: `figura::render_value(&value, &filters, &options)` - Render a value through filters exactly as a template would
 //! - **Zero-copy where possible**: Leverages `Cow` for efficiency
 //!
 //! ## Example
@@ -40,372 +111,3201 @@
 #![allow(clippy::should_implement_trait)]
 
 mod arg;
+mod builder;
+mod cache;
+mod context;
+#[cfg(feature = "digest")]
+mod digest;
 mod directive;
 mod err;
+mod filter;
+mod fragment;
+mod highlight;
 mod lexer;
+mod metrics;
+mod options;
 mod parser;
+mod registry;
+mod renderer;
+mod schema;
+#[cfg(feature = "serde")]
+mod serial;
+mod source;
+mod total;
 mod traits;
+mod tree;
+mod value;
 
+use fragment::Node;
+use std::any::Any;
 use std::borrow::Cow;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
 
 pub use arg::*;
+pub use builder::TemplateBuilder;
+pub use cache::TemplateCache;
+pub use context::*;
 pub use directive::*;
 pub use err::*;
+pub use filter::*;
+pub use fragment::{Chunks, Fragment, Segment, SegmentKind, Segments, TemplateChange};
+pub use highlight::{Highlight, HighlightKind};
 pub use lexer::*;
+pub use metrics::{CompileStats, TemplateMetrics};
+pub use options::*;
 pub use parser::*;
+pub use registry::TemplateRegistry;
+pub use renderer::Renderer;
+pub use schema::Schema;
+#[cfg(feature = "serde")]
+pub use serial::{SerializedDirective, SerializedFilter};
+pub use source::SourceWriter;
+pub use total::TotalTemplate;
+pub use tree::DebugTree;
+pub use value::*;
 
-/// A runtime value that can be stored in the template context.
+/// A compiled template ready for rendering.
+///
+/// Templates are parameterized by two characters representing the opening (`O`)
+/// and closing (`C`) delimiters. Common choices are `{`/`}` or `<`/`>`.
+///
+/// Templates are compiled once and can be rendered multiple times with different
+/// contexts, making them efficient for repeated use.
+///
+/// A compiled template owns everything it holds and rendering only borrows it,
+/// so templates are `Send + Sync` and `'static`. To render one template from
+/// several threads, share a reference with `std::thread::scope` or wrap it in
+/// an `Arc`, and give each thread its own context.
 ///
-/// Values can be strings, integers, floats, or booleans. The type system
-/// automatically handles conversions where appropriate (e.g., converting
-/// integers to strings for display).
+/// # Type Parameters
+///
+/// * `O` - The opening delimiter character (e.g., `'{'`)
+/// * `C` - The closing delimiter character (e.g., `'}'`)
 ///
 /// # Examples
 ///
 /// ```rust
-/// use figura::Value;
+/// use figura::{Template, Context, Value};
+/// use std::collections::HashMap;
+///
+/// // Using default delimiters
+/// let tmpl = Template::<'{', '}'>::compile("Hello {name}!").unwrap();
+///
+/// let mut ctx = HashMap::new();
+/// ctx.insert("name", Value::static_str("World"));
 ///
-/// let s = Value::static_str("hello");
-/// let i = Value::Int(42);
-/// let f = Value::Float(3.14);
-/// let b = Value::Bool(true);
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello World!");
 /// ```
-#[derive(Debug, Clone)]
-pub enum Value {
-    /// A string value (can be borrowed or owned)
-    Str(Cow<'static, str>),
-    /// A 64-bit signed integer
-    Int(i64),
-    /// A 64-bit floating point number
-    Float(f64),
-    /// A boolean value
-    Bool(bool),
+pub struct Template<const O: char, const C: char> {
+    body: Fragment,
+    /// Whether rendering has to track the output column, see
+    /// `Fragment::tracks_columns`
+    tracks_columns: bool,
+    /// Multi-char delimiters chosen at runtime, which take the place of `O`
+    /// and `C`. See `Template::with_delimiters`.
+    delimiters: Option<Delimiters>,
+    /// The string the template was compiled from, quoted by
+    /// `FormatError::diagnostic`. `None` for built and deserialized templates.
+    source: Option<Arc<str>>,
+    /// The output of a template whose directives are all constant, rendered
+    /// when it is created and returned by `Template::render_static`. `None`
+    /// for text alone, which is returned as is.
+    static_output: Option<String>,
 }
 
-impl Value {
-    /// Create a static string value (zero-cost)
-    pub fn static_str(s: &'static str) -> Self {
-        Self::Str(Cow::Borrowed(s))
-    }
-
-    /// Create an owned string value
-    pub fn owned_str(s: String) -> Self {
-        Self::Str(Cow::Owned(s))
-    }
-
-    /// Returns a human-readable name for the value's type.
-    ///
-    /// Used primarily in error messages to indicate type mismatches.
-    pub fn type_name(&self) -> &str {
-        match self {
-            Self::Str(_) => "string",
-            Self::Int(_) => "integer",
-            Self::Float(_) => "float",
-            Self::Bool(_) => "boolean",
-        }
-    }
-}
+// Directives and filters are `Send + Sync`, and so must every template be
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<Template<'{', '}'>>();
+};
 
-/// The context passed to templates during rendering.
+/// Compiles `source` and renders it against `ctx` in one call.
+///
+/// This suits scripts and tests that render a template once. To render the
+/// same template several times, compile it with `Template::compile` and call
+/// `Template::format` on it instead, so it is only parsed once.
+///
+/// # Errors
 ///
-/// Maps variable names to their runtime values. Variable names must be
-/// static strings for zero-copy efficiency.
+/// Returns `Error::Template` if the template fails to compile and
+/// `Error::Directive` if it fails to render.
 ///
 /// # Examples
 ///
 /// ```rust
 /// use figura::{Context, Value};
-/// use std::collections::HashMap;
 ///
-/// let mut ctx = HashMap::new();
-/// ctx.insert("user", Value::static_str("Alice"));
-/// ctx.insert("age", Value::Int(30));
+/// let mut ctx = Context::new();
+/// ctx.insert("name", Value::static_str("Ada"));
+///
+/// assert_eq!(figura::render::<'<', '>'>("Hi <name>!", &ctx).unwrap(), "Hi Ada!");
+/// assert_eq!(figura::render_default("Hi {name}!", &ctx).unwrap(), "Hi Ada!");
 /// ```
-pub type Context = HashMap<&'static str, Value>;
+pub fn render<const O: char, const C: char>(
+    source: &str,
+    ctx: &dyn ContextLookup,
+) -> Result<String, Error> {
+    Ok(Template::<O, C>::compile(source)?.format(ctx)?)
+}
 
-/// A compiled template ready for rendering.
+/// Compiles `source` with the `{` and `}` delimiters and renders it against
+/// `ctx` in one call, like `render`.
 ///
-/// Templates are parameterized by two characters representing the opening (`O`)
-/// and closing (`C`) delimiters. Common choices are `{`/`}` or `<`/`>`.
+/// # Errors
 ///
-/// Templates are compiled once and can be rendered multiple times with different
-/// contexts, making them efficient for repeated use.
+/// Returns `Error::Template` if the template fails to compile and
+/// `Error::Directive` if it fails to render.
+pub fn render_default(source: &str, ctx: &dyn ContextLookup) -> Result<String, Error> {
+    render::<'{', '}'>(source, ctx)
+}
+
+/// The name `render_value` renders its value under, which its errors refer
+/// to.
+const RENDERED_VALUE: &str = "value";
+
+/// Renders a single value through `filters` exactly as a template renders a
+/// variable holding it, for code that builds output alongside templates.
 ///
-/// # Type Parameters
+/// With no filters this is `{value}`, so `options.empty_placeholder` applies;
+/// otherwise it is `{value:filters}`, with `options.filter_mismatch` deciding
+/// what filters do with values they don't accept. Numbers use
+/// `options.number_format` either way, line breaks are converted to
+/// `options.newline` and the output is limited to
+/// `options.max_output_len` bytes, while `options.schema` is ignored, as the
+/// value has no name. Filters can be built in code or parsed from a spec
+/// with `Schema::field`.
 ///
-/// * `O` - The opening delimiter character (e.g., `'{'`)
-/// * `C` - The closing delimiter character (e.g., `'}'`)
+/// # Errors
+///
+/// Returns the error the template would fail with, such as
+/// `DirectiveError::TypeError` for `Bytes` and `Array` or
+/// `DirectiveError::FilterTypeError` for a filter rejecting the value, which
+/// refers to the value as `value`.
 ///
 /// # Examples
 ///
 /// ```rust
-/// use figura::{Template, Context, Value};
-/// use std::collections::HashMap;
-///
-/// // Using default delimiters
-/// let tmpl = Template::<'{', '}'>::compile("Hello {name}!").unwrap();
+/// use figura::{AlignFilter, Alignment, Filter, FormatOptions, NumberFormat, Value};
 ///
-/// let mut ctx = HashMap::new();
-/// ctx.insert("name", Value::static_str("World"));
+/// let filters: Vec<Box<dyn Filter>> = vec![Box::new(AlignFilter {
+///     align: Alignment::Right,
+///     width: 8,
+///     fill: '.',
+/// })];
+/// let options = FormatOptions {
+///     number_format: NumberFormat::EUROPEAN,
+///     ..Default::default()
+/// };
 ///
-/// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello World!");
+/// let cell = figura::render_value(&Value::Float(12.5), &filters, &options).unwrap();
+/// assert_eq!(cell, "....12,5");
 /// ```
-pub struct Template<const O: char, const C: char> {
-    directives: Vec<Box<dyn Directive>>,
+pub fn render_value(
+    value: &Value,
+    filters: &[Box<dyn Filter>],
+    options: &FormatOptions,
+) -> Result<String, DirectiveError> {
+    let value = [(RENDERED_VALUE, value.clone())];
+    let state = RenderState {
+        schema: None,
+        ..RenderState::new(options, None)
+    };
+    let ctx = RenderContext::new(&value, &state);
+    let argument = Argument::Variable(Cow::Borrowed(RENDERED_VALUE));
+
+    let output = if filters.is_empty() {
+        ReplaceDirective(argument).exec(&ctx)?
+    } else {
+        render_filtered(&argument, value[0].1.clone(), filters, &ctx)?
+    };
+
+    let output = match options.newline {
+        NewlineStyle::AsIs => output,
+        style => {
+            let mut converted = String::with_capacity(output.len());
+            let mut writer = NewlineWriter::new(&mut converted, style);
+            fmt::Write::write_str(&mut writer, &output)?;
+            writer.finish()?;
+            Cow::Owned(converted)
+        }
+    };
+
+    if output.len() > options.max_output_len {
+        return Err(DirectiveError::OutputLimitExceeded {
+            limit: options.max_output_len,
+        });
+    }
+
+    Ok(output.into_owned())
 }
 
-impl<const C: char, const O: char> fmt::Debug for Template<O, C> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Template<'{}', '{}'>", O, C)
+/// Adapts an `io::Write` into a `fmt::Write`, keeping the underlying I/O error
+/// that `fmt::Error` cannot carry.
+struct IoWriter<'a, W: io::Write> {
+    inner: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// Collects the output of `Template::format_bytes`, turning the writes of
+/// `raw` back into the bytes they stand for.
+struct ByteWriter<'a> {
+    bytes: Vec<u8>,
+    raw: &'a RawBytes,
+}
+
+impl fmt::Write for ByteWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.raw.take() {
+            self.bytes.extend_from_slice(s.as_bytes());
+            return Ok(());
+        }
+
+        for c in s.chars() {
+            match char_raw_byte(c) {
+                Some(byte) => self.bytes.push(byte),
+                None => self
+                    .bytes
+                    .extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Wraps a `fmt::Write`, rejecting writes once a byte budget is used up.
+/// The writes of `raw` count the bytes they stand for.
+struct LimitedWriter<'a> {
+    inner: &'a mut dyn fmt::Write,
+    remaining: usize,
+    exceeded: bool,
+    raw: Option<&'a RawBytes>,
+}
+
+impl fmt::Write for LimitedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = match self.raw {
+            Some(raw) if raw.is_writing() => raw_len(s),
+            _ => s.len(),
+        };
+
+        if len > self.remaining {
+            self.exceeded = true;
+            return Err(fmt::Error);
+        }
+
+        self.remaining -= len;
+        self.inner.write_str(s)
+    }
+}
+
+/// Wraps a `fmt::Write`, converting the line breaks written to it to
+/// `style`. A `\r` ending a write is held back until the next one shows
+/// whether a `\n` follows, so `finish` must be called once rendering is done.
+struct NewlineWriter<'a> {
+    inner: &'a mut dyn fmt::Write,
+    style: NewlineStyle,
+    pending_cr: bool,
+}
+
+impl<'a> NewlineWriter<'a> {
+    fn new(inner: &'a mut dyn fmt::Write, style: NewlineStyle) -> Self {
+        Self {
+            inner,
+            style,
+            pending_cr: false,
+        }
+    }
+
+    /// Writes the `\r` held back from the last write, if any.
+    fn finish(&mut self) -> fmt::Result {
+        if std::mem::take(&mut self.pending_cr) {
+            self.inner.write_str("\r")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Write for NewlineWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let newline = match self.style {
+            NewlineStyle::AsIs => return self.inner.write_str(s),
+            NewlineStyle::Lf => "\n",
+            NewlineStyle::CrLf => "\r\n",
+        };
+
+        if s.is_empty() {
+            return Ok(());
+        }
+
+        let mut rest = s;
+
+        if std::mem::take(&mut self.pending_cr) {
+            match rest.strip_prefix('\n') {
+                Some(after) => {
+                    self.inner.write_str(newline)?;
+                    rest = after;
+                }
+                None => self.inner.write_str("\r")?,
+            }
+        }
+
+        while let Some(i) = rest.find(['\r', '\n']) {
+            self.inner.write_str(&rest[..i])?;
+
+            let after = &rest[i + 1..];
+            rest = if rest.as_bytes()[i] == b'\n' {
+                self.inner.write_str(newline)?;
+                after
+            } else if let Some(after) = after.strip_prefix('\n') {
+                self.inner.write_str(newline)?;
+                after
+            } else if after.is_empty() {
+                self.pending_cr = true;
+                after
+            } else {
+                // A lone `\r` isn't a line break
+                self.inner.write_str("\r")?;
+                after
+            };
+        }
+
+        self.inner.write_str(rest)
+    }
+}
+
+/// Wraps a `fmt::Write`, keeping track of the column its output is at for
+/// `ColumnDirective`. Only used for templates that can render one, since
+/// scanning every write for line breaks slows rendering down.
+struct ColumnWriter<'a> {
+    inner: &'a mut dyn fmt::Write,
+    column: &'a Cell<usize>,
+}
+
+impl fmt::Write for ColumnWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.column.set(advance_column(self.column.get(), s));
+        self.inner.write_str(s)
+    }
+}
+
+/// The contents of the directive that starts a raw block, `{@raw}`.
+const RAW_START: &str = "@raw";
+
+/// The contents of the directive that ends a raw block, `{@endraw}`.
+const RAW_END: &str = "@endraw";
+
+/// Finds the directive ending a raw block in `text`, `{@endraw}` with
+/// optional whitespace inside its delimiters, and returns its byte range.
+fn find_raw_end(text: &str, (open, close): (&str, &str)) -> Option<Range<usize>> {
+    let is_space = |c: char| c.is_ascii_whitespace();
+
+    text.match_indices(open).find_map(|(i, _)| {
+        let inner = text[i + open.len()..].trim_start_matches(is_space);
+        let after = inner.strip_prefix(RAW_END)?.trim_start_matches(is_space);
+
+        after
+            .starts_with(close)
+            .then(|| i..text.len() - after.len() + close.len())
+    })
+}
+
+/// How many tokens the token buffer holds without allocating, with the
+/// `smallvec` feature: more than all but long conditionals and filter
+/// chains lex into.
+#[cfg(feature = "smallvec")]
+const INLINE_TOKENS: usize = 16;
+
+/// The buffer the tokens of each directive are lexed into while compiling.
+#[cfg(feature = "smallvec")]
+type TokenBuffer<'a> = smallvec::SmallVec<[Token<'a>; INLINE_TOKENS]>;
+
+/// The buffer the tokens of each directive are lexed into while compiling.
+#[cfg(not(feature = "smallvec"))]
+type TokenBuffer<'a> = Vec<Token<'a>>;
+
+/// Compiles template source into a fragment, including the sub-templates
+/// nested in its directives' arguments.
+///
+/// With `errors`, directives that fail to parse are recorded there and
+/// skipped, and compiling carries on after their closing delimiter. Errors
+/// that leave no closing delimiter to recover at are still returned.
+///
+/// With `stats`, the work done is added to them.
+fn compile_fragment<P: Parser>(
+    input: &str,
+    (open, close): (&str, &str),
+    options: &CompileOptions,
+    mut errors: Option<&mut Vec<SpannedError>>,
+    mut stats: Option<&mut CompileStats>,
+) -> Result<Fragment, TemplateError> {
+    options.markers.check()?;
+
+    let first = |s: &str| s.chars().next().unwrap_or_default();
+    let (open_first, close_first) = (first(open), first(close));
+
+    // A byte order mark is part of the file's encoding rather than its text,
+    // and only the template itself starts a file
+    let bom = match input.strip_prefix('\u{feff}') {
+        Some(rest) if options.strip_bom => input.len() - rest.len(),
+        _ => 0,
+    };
+    let nested_options = CompileOptions {
+        strip_bom: false,
+        ..options.clone()
+    };
+
+    if let Some(stats) = stats.as_deref_mut() {
+        stats.bytes_scanned += input.len() - bom;
+    }
+
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut cursor = bom;
+    let mut pos = bom;
+
+    // Shared across directives so that lexing only allocates when a
+    // directive has more tokens than any directive before it.
+    let mut tokens = TokenBuffer::new();
+    let mut regions = Regions::default();
+
+    // Jump between the only chars that can start a delimiter or an escape
+    while let Some(offset) = input[pos..].find([open_first, close_first, '\\']) {
+        let idx = pos + offset;
+        let rest = &input[idx..];
+
+        if let Some(after) = rest.strip_prefix(open) {
+            // Handle escaped opening delimiter (e.g. "{{")
+            if after.starts_with(open) {
+                if idx > cursor {
+                    nodes.push(Node::text(input, cursor..idx));
+                }
+
+                cursor = idx + open.len() * 2;
+                pos = cursor;
+                nodes.push(Node::escape(open, idx..cursor));
+                continue;
+            }
+
+            if idx > cursor {
+                nodes.push(Node::text(input, cursor..idx));
+            }
+
+            if options.max_depth == 0 {
+                let error = TemplateError::NestingTooDeep(0);
+                return give_up(errors, idx..input.len(), error, nodes);
+            }
+
+            let start = idx + open.len();
+            let end = match closing_delimiter(input, start, (open, close), options.max_depth) {
+                Ok(Some(end)) => end,
+                Ok(None) => {
+                    let error = TemplateError::MissingDelimiter {
+                        position: idx,
+                        delimiter: close.to_string(),
+                    };
+                    let Some(errors) = errors.as_deref_mut() else {
+                        return Err(error);
+                    };
+
+                    // Read the unclosed delimiter as text and carry on, so
+                    // the directives after it are checked too, unclosed
+                    // ones included
+                    errors.push(SpannedError {
+                        span: idx..input.len(),
+                        error,
+                    });
+                    cursor = idx;
+                    pos = start;
+                    continue;
+                }
+                Err(error) => return give_up(errors, idx..input.len(), error, nodes),
+            };
+
+            cursor = end + close.len();
+            pos = cursor;
+
+            let content = &input[start..end];
+
+            // A raw block is text up to its end marker, with no directives or
+            // escapes in it
+            if content.trim_matches(|c: char| c.is_ascii_whitespace()) == RAW_START {
+                let Some(marker) = find_raw_end(&input[cursor..], (open, close)) else {
+                    let error = TemplateError::MissingDelimiter {
+                        position: idx,
+                        delimiter: format!("{}{}{}", open, RAW_END, close),
+                    };
+                    return give_up(errors, idx..input.len(), error, nodes);
+                };
+
+                let text = &input[cursor..cursor + marker.start];
+                cursor += marker.end;
+                pos = cursor;
+                nodes.push(Node::raw(text, idx..cursor));
+                continue;
+            }
+
+            if content
+                .trim_matches(|c: char| c.is_ascii_whitespace())
+                .is_empty()
+            {
+                match options.empty_directives {
+                    EmptyPolicy::Keep => {
+                        nodes.push(Node::raw(&input[idx..cursor], idx..cursor));
+                        continue;
+                    }
+                    EmptyPolicy::Error => {
+                        let error = TemplateError::EmptyDirective { position: idx };
+
+                        match errors.as_deref_mut() {
+                            Some(errors) => {
+                                errors.push(SpannedError {
+                                    span: idx..cursor,
+                                    error,
+                                });
+                                continue;
+                            }
+                            None => return Err(error),
+                        }
+                    }
+                    EmptyPolicy::Empty => {}
+                }
+            }
+
+            let capacity = tokens.capacity();
+            tokens.clear();
+            tokens.extend(TemplateLexer::with_markers(content, &options.markers));
+
+            if let Some(stats) = stats.as_deref_mut() {
+                stats.directives += 1;
+                stats.tokens += tokens.len();
+                stats.token_buffer_growths += usize::from(tokens.capacity() > capacity);
+                stats.max_depth = stats.max_depth.max(1);
+            }
+
+            // Every `?` adds a step to a conditional, and every parenthesis a
+            // group of a condition, so they count against the nesting limit
+            // before anything is built
+            let depth = question_marks(&tokens).max(paren_depth(&tokens));
+            let parsed = if depth > options.max_depth {
+                Err(TemplateError::NestingTooDeep(options.max_depth))
+            } else {
+                P::parse(&tokens).ok_or_else(|| {
+                    let error = unbalanced_paren(content, start, &options.markers)
+                        .unwrap_or_else(|| TemplateError::DirectiveParsing(content.to_string()));
+                    #[cfg(feature = "regex")]
+                    let error = invalid_regex(&tokens, idx).unwrap_or(error);
+                    error
+                })
+            };
+
+            let mut directive = match parsed {
+                Ok(directive) => directive,
+                Err(error) => match errors.as_deref_mut() {
+                    Some(errors) => {
+                        errors.push(SpannedError {
+                            span: idx..cursor,
+                            error,
+                        });
+                        continue;
+                    }
+                    None => return Err(error),
+                },
+            };
+
+            // The parser only sees tokens, so unknown directives get their
+            // source text here
+            if let Some(unknown) =
+                (directive.as_mut() as &mut dyn Any).downcast_mut::<UnknownDirective>()
+            {
+                unknown.0 = Cow::Owned(input[idx..cursor].to_string());
+            }
+
+            // The literal tokens of the directive, lexed again only if one of
+            // them turns out to be a sub-template
+            let mut literals: Option<Vec<SpannedToken>> = None;
+
+            // Literal text containing the opening delimiter, or an escaped
+            // closing one as case bodies keep it, is a sub-template
+            for arg in directive.nested_arguments_mut() {
+                if let Argument::Literal(text) = arg
+                    && (text.contains(open) || escapes_delimiter(text, close))
+                {
+                    let literals = literals.get_or_insert_with(|| {
+                        TemplateLexer::with_markers(content, &options.markers)
+                            .spanned()
+                            .filter(|t| matches!(t.token, Token::Literal(_) | Token::Text(_)))
+                            .collect()
+                    });
+                    let branch = literals
+                        .iter()
+                        .position(
+                            |t| matches!(&t.token, Token::Literal(s) | Token::Text(s) if s == text),
+                        )
+                        .map(|i| literals.remove(i))
+                        .and_then(|literal| {
+                            BranchSource::new(content, literal, text, start, (open, close))
+                        });
+                    let source = branch.as_ref().map_or(text.as_ref(), |b| &b.text);
+
+                    // Nested errors are reported at their position in the
+                    // template, within the span of this directive
+                    let position = |pos| branch.as_ref().map_or(idx, |b| b.position(pos));
+                    let mut nested = errors.as_ref().map(|_| Vec::new());
+                    let mut nested_stats = stats.as_ref().map(|_| CompileStats::default());
+                    let fragment = compile_fragment::<P>(
+                        source,
+                        (open, close),
+                        &nested_options,
+                        nested.as_mut(),
+                        nested_stats.as_mut(),
+                    )
+                    .map_err(|e| e.map_position(position))?;
+
+                    if let (Some(stats), Some(nested)) = (stats.as_deref_mut(), nested_stats) {
+                        stats.add_nested(nested);
+                    }
+
+                    if let (Some(errors), Some(nested)) = (errors.as_deref_mut(), nested) {
+                        errors.extend(nested.into_iter().map(|nested| SpannedError {
+                            span: idx..cursor,
+                            error: nested.error.map_position(position),
+                        }));
+                    }
+
+                    *arg = Argument::Template(Arc::new(fragment));
+                }
+            }
+
+            if let Err(error) = regions.visit(directive.as_ref(), idx..cursor) {
+                match errors.as_deref_mut() {
+                    Some(errors) => errors.push(SpannedError {
+                        span: idx..cursor,
+                        error,
+                    }),
+                    None => return Err(error),
+                }
+            }
+
+            if options.deny_unreachable_cases
+                && let Some(switch) =
+                    (directive.as_ref() as &dyn Any).downcast_ref::<SwitchDirective>()
+                && let Some(case) = switch.unreachable_cases().into_iter().next()
+            {
+                match errors.as_deref_mut() {
+                    Some(errors) => errors.push(SpannedError {
+                        span: idx..cursor,
+                        error: case.into(),
+                    }),
+                    None => return Err(case.into()),
+                }
+            }
+
+            nodes.push(Node::directive(directive, input, idx..cursor));
+        } else if rest.starts_with(close) && rest[close.len()..].starts_with(close) {
+            // Handle escaped closing delimiter (e.g. "}}")
+            if idx > cursor {
+                nodes.push(Node::text(input, cursor..idx));
+            }
+
+            cursor = idx + close.len() * 2;
+            pos = cursor;
+            nodes.push(Node::escape(close, idx..cursor));
+        } else if rest.starts_with('\\')
+            && let Some(escaped) = [open, close, "\\"]
+                .into_iter()
+                .find(|escaped| rest[1..].starts_with(escaped))
+        {
+            // Handle backslash escapes (e.g. "\{", "\}" and "\\")
+            if idx > cursor {
+                nodes.push(Node::text(input, cursor..idx));
+            }
+
+            cursor = idx + 1 + escaped.len();
+            pos = cursor;
+            nodes.push(Node::escape(escaped, idx..cursor));
+        } else if options.text_escapes
+            && let Some(escape) = text_escape(&rest[1..])
+        {
+            // Handle text escapes (e.g. "\n" and "\u{e9}")
+            let (text, len) = match escape {
+                Ok(escape) => escape,
+                Err(len) => {
+                    let error = TemplateError::InvalidEscape {
+                        position: idx,
+                        escape: rest[..1 + len].to_string(),
+                    };
+
+                    match errors.as_deref_mut() {
+                        Some(errors) => {
+                            errors.push(SpannedError {
+                                span: idx..idx + 1 + len,
+                                error,
+                            });
+                            pos = idx + 1 + len;
+                            continue;
+                        }
+                        None => return Err(error),
+                    }
+                }
+            };
+
+            if idx > cursor {
+                nodes.push(Node::text(input, cursor..idx));
+            }
+
+            cursor = idx + 1 + len;
+            pos = cursor;
+            nodes.push(Node::escape(text.encode_utf8(&mut [0; 4]), idx..cursor));
+        } else {
+            pos = idx + first(rest).len_utf8();
+        }
+    }
+
+    if cursor < input.len() {
+        nodes.push(Node::text(input, cursor..input.len()));
+    }
+
+    for (name, span) in regions.open {
+        let error = TemplateError::InvalidRegion {
+            name: name.to_string(),
+            reason: "is never closed",
+        };
+
+        match errors.as_deref_mut() {
+            Some(errors) => errors.push(SpannedError { span, error }),
+            None => return Err(error),
+        }
+    }
+
+    Ok(Fragment::new(nodes))
+}
+
+/// The regions of a fragment seen so far while compiling it, see
+/// `RegionDirective`.
+#[derive(Default)]
+struct Regions {
+    /// The regions still open, innermost last, with the spans of their
+    /// markers
+    open: Vec<(Cow<'static, str>, Range<usize>)>,
+    /// The names of every region opened so far
+    names: HashSet<Cow<'static, str>>,
+}
+
+impl Regions {
+    /// Records `directive`, the next directive of the fragment found at
+    /// `span`, if it opens or closes a region.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TemplateError::InvalidRegion` for a name used twice or a
+    /// region closed inside another, and `TemplateError::UnopenedRegionEnd`
+    /// for an end with no open region.
+    fn visit(
+        &mut self,
+        directive: &dyn Directive,
+        span: Range<usize>,
+    ) -> Result<(), TemplateError> {
+        let any = directive as &dyn Any;
+
+        if let Some(RegionDirective(name)) = any.downcast_ref() {
+            let duplicate = !self.names.insert(name.clone());
+            self.open.push((name.clone(), span));
+
+            if duplicate {
+                return Err(TemplateError::InvalidRegion {
+                    name: name.to_string(),
+                    reason: "is already defined",
+                });
+            }
+        } else if let Some(RegionEndDirective(end)) = any.downcast_ref() {
+            let Some((name, _)) = self.open.pop() else {
+                return Err(match end {
+                    Some(end) => TemplateError::InvalidRegion {
+                        name: end.to_string(),
+                        reason: "is closed without being opened",
+                    },
+                    None => TemplateError::UnopenedRegionEnd,
+                });
+            };
+
+            if let Some(end) = end
+                && *end != name
+            {
+                return Err(TemplateError::InvalidRegion {
+                    name: end.to_string(),
+                    reason: "is closed inside another region, but regions can't overlap",
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds the unreachable switch cases of a fragment and of everything nested
+/// in it to `cases`, in source order.
+fn collect_unreachable_cases(fragment: &Fragment, cases: &mut Vec<UnreachableCase>) {
+    for directive in fragment.directives() {
+        if let Some(switch) = (directive as &dyn Any).downcast_ref::<SwitchDirective>() {
+            cases.extend(switch.unreachable_cases());
+        }
+
+        for arg in directive.arguments() {
+            if let Argument::Template(nested) = arg {
+                collect_unreachable_cases(nested, cases);
+            }
+        }
+    }
+}
+
+/// Calls `visit` with every conditional of a fragment and of everything
+/// nested in it, in source order.
+fn collect_conditionals<'a>(
+    fragment: &'a Fragment,
+    visit: &mut dyn FnMut(&'a ConditionalDirective),
+) {
+    for directive in fragment.directives() {
+        if let Some(conditional) = (directive as &dyn Any).downcast_ref::<ConditionalDirective>() {
+            visit(conditional);
+        }
+
+        for arg in directive.arguments() {
+            if let Argument::Template(nested) = arg {
+                collect_conditionals(nested, visit);
+            }
+        }
     }
 }
 
-impl<const O: char, const C: char> Template<O, C> {
-    /// Compiles a template string using the default parser.
+/// Calls `visit` with every directive of a fragment and of the sub-templates
+/// nested in its arguments, including those inside expressions.
+fn collect_directives<'a>(fragment: &'a Fragment, visit: &mut dyn FnMut(&'a dyn Directive)) {
+    for directive in fragment.directives() {
+        visit(directive);
+
+        for arg in directive.arguments() {
+            arg.walk(&mut |arg| {
+                if let Argument::Template(nested) = arg {
+                    nested.directives().for_each(&mut *visit);
+                }
+            });
+        }
+    }
+}
+
+/// Finds the closing delimiter of the directive whose contents start at
+/// `start`, counting nested directives and skipping delimiters escaped with
+/// a backslash. Returns its position, or `None` if the directive is unclosed.
+///
+/// Every opening delimiter nests, quoted or not, since branches and cases
+/// hold directives of their own: in `{a ? '{b}' : {c}}` the first `}` closes
+/// `{b}` and the last the whole directive. Doubled delimiters aren't escapes
+/// here, as `}}` may close two directives at once.
+///
+/// # Errors
+///
+/// Returns `TemplateError::NestingTooDeep` if directives nest deeper than
+/// `max_depth`, the directive itself included.
+fn closing_delimiter(
+    input: &str,
+    start: usize,
+    (open, close): (&str, &str),
+    max_depth: usize,
+) -> Result<Option<usize>, TemplateError> {
+    let mut depth = 1;
+    let mut scan = start;
+
+    while scan < input.len() {
+        let rest = &input[scan..];
+
+        if let Some(escaped) = rest.strip_prefix('\\')
+            && let Some(escaped) = [open, close, "\\"]
+                .into_iter()
+                .find(|delimiter| escaped.starts_with(delimiter))
+        {
+            scan += 1 + escaped.len();
+        } else if open != close && rest.starts_with(open) {
+            depth += 1;
+
+            if depth > max_depth {
+                return Err(TemplateError::NestingTooDeep(max_depth));
+            }
+
+            scan += open.len();
+        } else if rest.starts_with(close) {
+            depth -= 1;
+
+            if depth == 0 {
+                return Ok(Some(scan));
+            }
+
+            scan += close.len();
+        } else {
+            scan += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+
+    Ok(None)
+}
+
+/// The text of a literal compiled as a sub-template, such as the branch
+/// `'Dear {name}'` of a conditional, along with where it was read from.
+struct BranchSource {
+    /// The literal with its escapes decoded, except those of a delimiter,
+    /// which the sub-template decodes itself
+    text: String,
+    /// Offsets in `text` paired with the offsets in the template source
+    /// they were read from, at the start and after each escape
+    offsets: Vec<(usize, usize)>,
+}
+
+impl BranchSource {
+    /// Reads the sub-template of `literal`, a token of `content` lexed as
+    /// `decoded`, where `content` starts at `start` in the template source.
+    /// Returns `None` if the token doesn't read as `decoded`.
+    fn new(
+        content: &str,
+        literal: SpannedToken,
+        decoded: &str,
+        start: usize,
+        (open, close): (&str, &str),
+    ) -> Option<Self> {
+        let mut span = literal.span;
+        let quoted = content[span.clone()].starts_with(['\'', '"']);
+        if matches!(literal.token, Token::Literal(_)) && quoted && span.len() >= 2 {
+            span = span.start + 1..span.end - 1;
+        }
+
+        let raw = &content[span.clone()];
+        let base = start + span.start;
+        let mut text = String::with_capacity(raw.len());
+        let mut offsets = vec![(0, base)];
+        let mut raw_chars = raw.char_indices();
+        let mut decoded_chars = decoded.chars();
+
+        while let Some((i, c)) = raw_chars.next() {
+            let d = decoded_chars.next()?;
+
+            // A backslash read as one, as case bodies keep the one of `\}`
+            if c != '\\' || (d == '\\' && !raw[i + 1..].starts_with('\\')) {
+                if c != d {
+                    return None;
+                }
+                text.push(d);
+                continue;
+            }
+
+            // An escape, read as `d`
+            let (j, escaped) = raw_chars.next()?;
+            let rest = &raw[j..];
+            if rest.starts_with(open) || rest.starts_with(close) {
+                text.push('\\');
+            }
+            text.push(d);
+            offsets.push((text.len(), base + j + escaped.len_utf8()));
+        }
+
+        decoded_chars
+            .next()
+            .is_none()
+            .then_some(Self { text, offsets })
+    }
+
+    /// Returns the offset in the template source of `pos` in `text`.
+    fn position(&self, pos: usize) -> usize {
+        let (text, source) = self
+            .offsets
+            .iter()
+            .rev()
+            .find(|&&(text, _)| text <= pos)
+            .copied()
+            .unwrap_or(self.offsets[0]);
+
+        source + (pos - text)
+    }
+}
+
+/// Returns whether `text` holds a backslash escaping `close`.
+fn escapes_delimiter(text: &str, close: &str) -> bool {
+    text.match_indices('\\')
+        .any(|(idx, _)| text[idx + 1..].starts_with(close))
+}
+
+/// Interprets the text escape after a backslash, for
+/// `CompileOptions::text_escapes`.
+///
+/// Returns `None` if `rest` doesn't start an escape, or the escaped char and
+/// the escape's length without the backslash. An invalid `\u{...}` is an
+/// error holding the length of what was read of it.
+fn text_escape(rest: &str) -> Option<Result<(char, usize), usize>> {
+    let escaped = match rest.as_bytes().first()? {
+        b'n' => '\n',
+        b't' => '\t',
+        b'r' => '\r',
+        b'u' if rest[1..].starts_with('{') => {
+            let Some(close) = rest.find('}') else {
+                return Some(Err(rest.len()));
+            };
+
+            let digits = &rest[2..close];
+            let escaped = ((1..=6).contains(&digits.len())
+                && digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .then(|| u32::from_str_radix(digits, 16).ok())
+            .flatten()
+            .and_then(char::from_u32);
+
+            return Some(escaped.map(|c| (c, close + 1)).ok_or(close + 1));
+        }
+        _ => return None,
+    };
+
+    Some(Ok((escaped, 1)))
+}
+
+/// Counts the `?` tokens of a directive, an upper bound on how deeply its
+/// conditionals nest.
+fn question_marks(tokens: &[Token]) -> usize {
+    tokens.iter().filter(|t| **t == Token::Question).count()
+}
+
+/// Returns how deeply the parentheses of a directive nest.
+fn paren_depth(tokens: &[Token]) -> usize {
+    tokens
+        .iter()
+        .scan(0usize, |depth, token| {
+            match token {
+                Token::LParen => *depth += 1,
+                Token::RParen => *depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            Some(*depth)
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// Returns the error for the first parenthesis in the condition of
+/// `content`, before its first `?`, that closes none, or else for the first
+/// one left open, if any. `offset` is where `content` starts in the source.
+fn unbalanced_paren(content: &str, offset: usize, markers: &Markers) -> Option<TemplateError> {
+    let tokens: Vec<_> = TemplateLexer::with_markers(content, markers)
+        .spanned()
+        .collect();
+    let question = tokens.iter().position(|t| t.token == Token::Question)?;
+    let mut open = Vec::new();
+
+    for token in &tokens[..question] {
+        match token.token {
+            Token::LParen => open.push(token.span.start),
+            Token::RParen if open.pop().is_none() => {
+                return Some(TemplateError::UnbalancedParenthesis {
+                    position: offset + token.span.start,
+                    paren: markers.case_close,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    open.first()
+        .map(|start| TemplateError::UnbalancedParenthesis {
+            position: offset + start,
+            paren: markers.case_open,
+        })
+}
+
+/// Stops compiling at an error there is no recovering from, such as an
+/// unclosed delimiter: records it and keeps the nodes compiled so far when
+/// collecting errors, or returns it.
+fn give_up(
+    errors: Option<&mut Vec<SpannedError>>,
+    span: Range<usize>,
+    error: TemplateError,
+    nodes: Vec<Node>,
+) -> Result<Fragment, TemplateError> {
+    match errors {
+        Some(errors) => {
+            errors.push(SpannedError { span, error });
+            Ok(Fragment::new(nodes))
+        }
+        None => Err(error),
+    }
+}
+
+/// Returns the error for the first regex match (`=~`) among `tokens` whose
+/// pattern doesn't compile, if any. `position` is where the directive starts.
+#[cfg(feature = "regex")]
+fn invalid_regex(tokens: &[Token], position: usize) -> Option<TemplateError> {
+    tokens.windows(2).find_map(|pair| match pair {
+        [Token::Matches, Token::Literal(pattern)] => {
+            regex::Regex::new(pattern)
+                .err()
+                .map(|err| TemplateError::InvalidRegex {
+                    position,
+                    pattern: pattern.to_string(),
+                    message: err.to_string(),
+                })
+        }
+        _ => None,
+    })
+}
+
+/// Returns why a character can't be used as a delimiter, if it can't.
+fn delimiter_problem(c: char) -> Option<&'static str> {
+    match c {
+        c if c.is_ascii_alphanumeric() || c == '_' => {
+            Some("it collides with identifiers and numbers")
+        }
+        c if c.is_whitespace() || c.is_control() => Some("it is whitespace or a control character"),
+        '\'' | '"' => Some("it starts string literals"),
+        '\\' => Some("it escapes delimiters"),
+        _ => None,
+    }
+}
+
+/// The number of contexts each `rayon` task renders with `format_all`, so
+/// that capacity hints are shared within a chunk.
+#[cfg(feature = "rayon")]
+const PAR_CHUNK_SIZE: usize = 64;
+
+impl<const C: char, const O: char> fmt::Debug for Template<O, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.delimiters {
+            Some(delimiters) => write!(
+                f,
+                "Template<{:?}, {:?}>",
+                delimiters.open(),
+                delimiters.close()
+            ),
+            None => write!(f, "Template<'{}', '{}'>", O, C),
+        }
+    }
+}
+
+/// Compares templates by structure rather than source: `{ name }` equals
+/// `{name}`, since both compile to the same directive, while literal text,
+/// whitespace included, must match exactly. Runtime delimiters must match too.
+///
+/// ```rust
+/// use figura::Template;
+///
+/// let a = Template::<'{', '}'>::compile("Hi { name }!").unwrap();
+/// let b = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+/// let c = Template::<'{', '}'>::compile("Hi  {name}!").unwrap();
+///
+/// assert_eq!(a, b);
+/// assert_ne!(b, c);
+/// ```
+impl<const O: char, const C: char> PartialEq for Template<O, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.body == other.body && self.delimiters == other.delimiters
+    }
+}
+
+impl<const O: char, const C: char> Eq for Template<O, C> {}
+
+/// Hashes a template consistently with its `PartialEq`, so templates can be
+/// used as map keys.
+impl<const O: char, const C: char> Hash for Template<O, C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.body.hash(state);
+        self.delimiters.hash(state);
+    }
+}
+
+/// Compiles a template with the default parser, like `Template::compile`.
+///
+/// The delimiters can't be inferred from the string, so they come from the
+/// annotated type:
+///
+/// ```rust
+/// use figura::{Template, TemplateError};
+///
+/// fn greeting() -> Result<Template<'{', '}'>, TemplateError> {
+///     let tmpl: Template<'{', '}'> = "Hello {name}!".parse()?;
+///     Ok(tmpl)
+/// }
+///
+/// assert!(greeting().is_ok());
+/// ```
+impl<const O: char, const C: char> FromStr for Template<O, C> {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::compile(s)
+    }
+}
+
+/// Compiles a template with the default parser, like `Template::compile`.
+///
+/// ```rust
+/// use figura::Template;
+///
+/// let tmpl = Template::<'<', '>'>::try_from("Hello <name>!").unwrap();
+/// assert_eq!(tmpl.variables(), vec!["name"]);
+/// ```
+impl<const O: char, const C: char> TryFrom<&str> for Template<O, C> {
+    type Error = TemplateError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Self::compile(s)
+    }
+}
+
+impl<const O: char, const C: char> Template<O, C> {
+    /// Compiles a template string using the default parser.
+    ///
+    /// This is the most common way to create a template. It uses the `DefaultParser`
+    /// which supports variable substitution, repeating patterns, and conditional logic.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The template string to compile
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Template)` - A compiled template ready for rendering
+    /// * `Err(TemplateError)` - If the template syntax is invalid
+    ///
+    /// # Errors
+    ///
+    /// Returns a `TemplateError` if:
+    /// - The delimiters are unsuitable, see `Template::validate_delimiters`
+    /// - A delimiter is not properly closed
+    /// - A directive cannot be parsed
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Context, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// // Variable substitution
+    /// let tmpl = Template::<'{', '}'>::compile("Hello {name}!").unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("name", Value::static_str("World"));
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello World!");
+    ///
+    /// // Repeating patterns
+    /// let tmpl = Template::<'{', '}'>::compile("{'*':count}").unwrap();
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("count", Value::Int(3));
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "***");
+    ///
+    /// // Conditionals
+    /// let tmpl = Template::<'{', '}'>::compile("{x > 5 ? 'big' : 'small'}").unwrap();
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("x", Value::Int(10));
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "big");
+    /// ```
+    pub fn compile(input: impl AsRef<str>) -> Result<Self, TemplateError> {
+        Self::compile_with_parser::<DefaultParser>(input.as_ref())
+    }
+
+    /// Compiles a template string using a custom parser.
+    ///
+    /// This method allows you to use a custom parser implementation for specialized
+    /// template syntax or custom directives. The parser must implement the `Parser` trait.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `P` - A type implementing the `Parser` trait
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The template string to compile
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Template)` - A compiled template ready for rendering
+    /// * `Err(TemplateError)` - If the template syntax is invalid
+    ///
+    /// # Errors
+    ///
+    /// Returns a `TemplateError` if:
+    /// - The delimiters are unsuitable, see `Template::validate_delimiters`
+    /// - A delimiter is not properly closed
+    /// - The custom parser cannot parse a directive
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, DefaultParser, Context, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// // Using the default parser explicitly
+    /// let tmpl = Template::<'{', '}'>::compile_with_parser::<DefaultParser>(
+    ///     "Hello {name}!"
+    /// ).unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("name", Value::static_str("Alice"));
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello Alice!");
+    /// ```
+    ///
+    /// For custom parser implementations, implement the `Parser` trait:
+    ///
+    /// ```rust
+    /// use figura::{Parser, Token, Directive};
+    ///
+    /// struct MyCustomParser;
+    ///
+    /// impl Parser for MyCustomParser {
+    ///     fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+    ///         // Your custom parsing logic here
+    ///         None
+    ///     }
+    /// }
+    /// ```
+    pub fn compile_with_parser<P: Parser>(input: &str) -> Result<Self, TemplateError> {
+        Self::compile_with_parser_and_options::<P>(input, &CompileOptions::default())
+    }
+
+    /// Compiles a template string using the default parser and the given options.
+    ///
+    /// Use this to tighten or relax the limits applied while compiling, e.g.
+    /// when templates come from untrusted sources.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The template string to compile
+    /// * `options` - Options controlling how the template is compiled
+    ///
+    /// # Errors
+    ///
+    /// Returns a `TemplateError` if the template syntax is invalid or if
+    /// directives are nested deeper than `options.max_depth`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{CompileOptions, Template};
+    ///
+    /// let options = CompileOptions {
+    ///     max_depth: 4,
+    ///     ..Default::default()
+    /// };
+    /// let tmpl = Template::<'{', '}'>::compile_with_options("Hi {name}!", &options).unwrap();
+    /// ```
+    pub fn compile_with_options(
+        input: impl AsRef<str>,
+        options: &CompileOptions,
+    ) -> Result<Self, TemplateError> {
+        Self::compile_with_parser_and_options::<DefaultParser>(input.as_ref(), options)
+    }
+
+    /// Compiles a template from bytes, such as the contents of a file read
+    /// with `std::fs::read`, which must be valid UTF-8.
+    ///
+    /// The whole input is validated before anything is compiled, so invalid
+    /// bytes are never replaced or skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TemplateError::InvalidUtf8` with the byte offset of the
+    /// first invalid sequence if `input` is not valid UTF-8, and otherwise
+    /// the errors of `Template::compile`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, TemplateError};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile_bytes(b"Hi {name}!").unwrap();
+    /// assert_eq!(tmpl.variables(), vec!["name"]);
+    ///
+    /// let err = Template::<'{', '}'>::compile_bytes(b"Hi \xff{name}").unwrap_err();
+    /// assert!(matches!(err, TemplateError::InvalidUtf8 { offset: 3 }));
+    /// ```
+    pub fn compile_bytes(input: impl AsRef<[u8]>) -> Result<Self, TemplateError> {
+        let input =
+            std::str::from_utf8(input.as_ref()).map_err(|e| TemplateError::InvalidUtf8 {
+                offset: e.valid_up_to(),
+            })?;
+
+        Self::compile(input)
+    }
+
+    /// Compiles a template string with the given options, like
+    /// `compile_with_options`, also returning statistics about the work
+    /// compiling took: the bytes scanned, the directives and tokens lexed,
+    /// how often the token buffer grew and how deeply directives nest.
+    ///
+    /// This is meant for profiling which templates are expensive to compile.
+    /// Other ways of compiling don't collect the statistics, so they don't
+    /// pay for them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `Template::compile_with_options`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{CompileOptions, Template};
+    ///
+    /// let (tmpl, stats) =
+    ///     Template::<'{', '}'>::compile_with_stats("Hi {name}!", &CompileOptions::default()).unwrap();
+    ///
+    /// assert_eq!(tmpl.variables(), vec!["name"]);
+    /// assert_eq!(stats.bytes_scanned, 10);
+    /// assert_eq!(stats.directives, 1);
+    /// assert_eq!(stats.tokens, 1);
+    /// assert_eq!(stats.max_depth, 1);
+    /// ```
+    pub fn compile_with_stats(
+        input: impl AsRef<str>,
+        options: &CompileOptions,
+    ) -> Result<(Self, CompileStats), TemplateError> {
+        Self::validate_delimiters()?;
+
+        let input = input.as_ref();
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        let delimiters = (&*O.encode_utf8(&mut open), &*C.encode_utf8(&mut close));
+
+        let mut stats = CompileStats::default();
+        let body =
+            compile_fragment::<DefaultParser>(input, delimiters, options, None, Some(&mut stats))?;
+
+        Ok((Self::from_body(body, None, Some(input)), stats))
+    }
+
+    /// Compiles a template string using a custom parser and the given options.
+    ///
+    /// Combines `compile_with_parser` and `compile_with_options`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `TemplateError` if:
+    /// - The delimiters are unsuitable, see `Template::validate_delimiters`
+    /// - A delimiter is not properly closed
+    /// - The custom parser cannot parse a directive
+    /// - Directives are nested deeper than `options.max_depth`
+    pub fn compile_with_parser_and_options<P: Parser>(
+        input: &str,
+        options: &CompileOptions,
+    ) -> Result<Self, TemplateError> {
+        Self::validate_delimiters()?;
+
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        let delimiters = (&*O.encode_utf8(&mut open), &*C.encode_utf8(&mut close));
+
+        Ok(Self::from_body(
+            compile_fragment::<P>(input, delimiters, options, None, None)?,
+            None,
+            Some(input),
+        ))
+    }
+
+    /// Compiles a template string using the default parser, collecting every
+    /// error instead of stopping at the first.
+    ///
+    /// After a directive fails to parse, compiling resumes after its closing
+    /// delimiter, so one pass reports every bad directive. An opening
+    /// delimiter that is never closed is reported and read as text, so every
+    /// unclosed directive is reported too, each at its own position. This
+    /// suits editor and linter integrations; `compile` stays fail-fast.
+    /// Errors that leave nothing to resume from, such as directives nested
+    /// too deeply or an unterminated raw block, end the pass.
+    ///
+    /// # Errors
+    ///
+    /// Returns every error found, in source order, each with the byte span of
+    /// its directive. Unsuitable delimiters are reported alone, with an
+    /// empty span.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, TemplateError};
+    ///
+    /// let errors = Template::<'{', '}'>::compile_all_errors("{a =~ '('} ok {b =~ ')'} {c").unwrap_err();
+    ///
+    /// assert_eq!(errors.len(), 3);
+    /// assert_eq!(errors[1].span, 14..24);
+    /// assert!(matches!(errors[2].error, TemplateError::MissingDelimiter { .. }));
+    /// ```
+    pub fn compile_all_errors(input: impl AsRef<str>) -> Result<Self, Vec<SpannedError>> {
+        Self::validate_delimiters().map_err(|error| vec![SpannedError { span: 0..0, error }])?;
+
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        let delimiters = (&*O.encode_utf8(&mut open), &*C.encode_utf8(&mut close));
+
+        let mut errors = Vec::new();
+        let body = compile_fragment::<DefaultParser>(
+            input.as_ref(),
+            delimiters,
+            &CompileOptions::default(),
+            Some(&mut errors),
+            None,
+        )
+        .map_err(|error| vec![SpannedError { span: 0..0, error }])?;
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Self::from_body(body, None, Some(input.as_ref())))
+    }
+
+    /// Checks that the delimiters `O` and `C` can be told apart from the
+    /// contents of directives.
+    ///
+    /// Every compile function runs this check first. Rejected delimiters are:
+    /// - ASCII letters, digits and `_`, which collide with identifiers and numbers
+    /// - Whitespace and control characters
+    /// - Quotes (`'`, `"`), which start literals inside directives
+    /// - The backslash, which escapes delimiters
+    ///
+    /// Operator characters such as `<`/`>` are allowed but can't be used
+    /// inside directives, e.g. in comparisons, since they would be read as
+    /// delimiters. When `O` and `C` are the same character (e.g. `%`),
+    /// directives can't nest: the next `%` always closes the directive.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TemplateError::InvalidDelimiter` naming the first unsuitable
+    /// delimiter.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, TemplateError};
+    ///
+    /// assert!(Template::<'{', '}'>::validate_delimiters().is_ok());
+    /// assert!(matches!(
+    ///     Template::<'1', '2'>::compile("1name2"),
+    ///     Err(TemplateError::InvalidDelimiter { delimiter, .. }) if delimiter == "1"
+    /// ));
+    /// ```
+    pub fn validate_delimiters() -> Result<(), TemplateError> {
+        for delimiter in [O, C] {
+            if let Some(reason) = delimiter_problem(delimiter) {
+                return Err(TemplateError::InvalidDelimiter {
+                    delimiter: delimiter.to_string(),
+                    reason,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Splits the directives of a template string into tokens, with their
+    /// spans in `input`.
+    ///
+    /// Each directive's contents are lexed with `TemplateLexer`, nested
+    /// directives included, in source order. Literal text, escapes and the
+    /// delimiters themselves produce no tokens; they lie between the spans.
+    /// Tokenizing never fails: an unclosed directive is lexed up to the end
+    /// of the input, which suits highlighters and linters working on
+    /// templates as they are typed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Token};
+    ///
+    /// let tokens = Template::<'{', '}'>::tokenize("Hi {name:>10}!");
+    ///
+    /// for token in &tokens {
+    ///     println!("{:?} at {:?}", token.token, token.span);
+    /// }
+    ///
+    /// assert_eq!(tokens[0].token, Token::Ident("name"));
+    /// assert_eq!(tokens[0].span, 4..8);
+    /// assert_eq!(tokens[3].token, Token::Int("10"));
+    /// ```
+    pub fn tokenize(input: &str) -> Vec<SpannedToken<'_>> {
+        Self::tokens(input).collect()
+    }
+
+    /// Returns an iterator over the tokens of the directives of a template
+    /// string, with their spans in `input`, lexing each directive only when
+    /// the iterator reaches it.
+    ///
+    /// This is the lazy form of `tokenize`, yielding the same tokens, for
+    /// tooling that stops early or processes huge templates without holding
+    /// every token. See `Tokens`.
+    pub fn tokens(input: &str) -> Tokens<'_> {
+        Tokens::new(input, O, C)
+    }
+
+    /// Classifies every part of a template string for syntax highlighting:
+    /// literal text, escapes, the delimiters of each directive and the
+    /// tokens between them, with their spans in `input`.
+    ///
+    /// Spans are in source order and don't overlap. They cover the whole
+    /// input except whitespace inside directives. Like `tokenize`, this
+    /// never fails, and marks what keeps the template from compiling as
+    /// `HighlightKind::Error`: unterminated strings, the opening delimiter of
+    /// an unclosed directive, which then runs to the end of the input, and
+    /// the delimiters and unknown characters of directives the default
+    /// parser doesn't recognize, which compile as `UnknownDirective`. Nested directives inside strings and case bodies
+    /// are not split further.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{HighlightKind, Template};
+    ///
+    /// let spans = Template::<'{', '}'>::highlight("Hi {{ {name:>10}!");
+    /// let kinds: Vec<_> = spans.iter().map(|s| s.kind).collect();
+    ///
+    /// assert_eq!(kinds, [
+    ///     HighlightKind::Text,
+    ///     HighlightKind::Escape,
+    ///     HighlightKind::Text,
+    ///     HighlightKind::Open,
+    ///     HighlightKind::Ident,
+    ///     HighlightKind::Punctuation,
+    ///     HighlightKind::Operator,
+    ///     HighlightKind::Number,
+    ///     HighlightKind::Close,
+    ///     HighlightKind::Text,
+    /// ]);
+    /// assert_eq!(spans[4].span, 7..11);
+    /// ```
+    pub fn highlight(input: &str) -> Vec<Highlight> {
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        highlight::highlight(input, (O.encode_utf8(&mut open), C.encode_utf8(&mut close)))
+    }
+
+    /// Renders the template using the provided context.
+    ///
+    /// This method executes all directives in the template and concatenates their
+    /// results into a final string. The output is pre-allocated with
+    /// `estimated_len` bytes to minimize allocations during rendering.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The context containing variable values. Any type implementing
+    ///   `ContextLookup` works, such as `Context` or `OwnedContext`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The rendered template output
+    /// * `Err(DirectiveError)` - If any directive fails (e.g., missing variable, type mismatch)
+    ///
+    /// # Errors
+    ///
+    /// Returns a `DirectiveError` if:
+    /// - A referenced variable is not found in the context
+    /// - A variable has an incompatible type for the operation
+    /// - A literal value cannot be parsed as the required type
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Context, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("name", Value::static_str("Alice"));
+    ///
+    /// let output = tmpl.format(&ctx).unwrap();
+    /// assert_eq!(output, "Hi Alice!");
+    /// ```
+    pub fn format(&self, ctx: &dyn ContextLookup) -> Result<String, DirectiveError> {
+        self.format_with_options(ctx, &FormatOptions::default())
+    }
+
+    /// Renders the template against several contexts layered on each other.
+    ///
+    /// Each variable is looked up in `layers` in order and the first context
+    /// that defines it wins, so per-render overrides go first and shared
+    /// defaults last. See `ContextStack` for a reusable stack of layers.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format`; a variable is only missing if no
+    /// layer defines it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let mut defaults = Context::new();
+    /// defaults.insert("support", Value::static_str("help@example.com"));
+    /// defaults.insert("name", Value::static_str("there"));
+    ///
+    /// let mut user = Context::new();
+    /// user.insert("name", Value::static_str("Ada"));
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}, write to {support}").unwrap();
+    /// assert_eq!(
+    ///     tmpl.format_layered(&[&user, &defaults]).unwrap(),
+    ///     "Hi Ada, write to help@example.com"
+    /// );
+    /// ```
+    pub fn format_layered(&self, layers: &[&dyn ContextLookup]) -> Result<String, DirectiveError> {
+        self.format(&Layers(layers))
+    }
+
+    /// Renders the template against positional arguments, printf-style.
+    ///
+    /// A bare index such as `{0}` or `{1:>5}` names the variable `"0"` or
+    /// `"1"`, which this looks up in `args` by position. Expressions write
+    /// them as quoted names, as in `` {`0` > 1 ? 'many'} ``.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format`; an index past the end of `args`
+    /// is a missing variable. Use `format_args_with_options` to render it
+    /// according to a `MissingPolicy` instead.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{0} has {1} {`1` == 1 ? 'item' : 'items'}").unwrap();
+    /// let args = [Value::static_str("Ada"), Value::Int(2)];
+    /// assert_eq!(tmpl.format_args(&args).unwrap(), "Ada has 2 items");
+    /// ```
+    pub fn format_args(&self, args: &[Value]) -> Result<String, DirectiveError> {
+        self.format(&Positional(args))
+    }
+
+    /// Renders the template against positional arguments and options.
+    ///
+    /// Behaves like `format_args`, except that indices past the end of
+    /// `args` render according to `options.missing`, like other missing
+    /// variables in `format_with_options`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{FormatOptions, MissingPolicy, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("[{0}|{1}]").unwrap();
+    /// let options = FormatOptions {
+    ///     missing: MissingPolicy::Empty,
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(
+    ///     tmpl.format_args_with_options(&[Value::Int(7)], &options).unwrap(),
+    ///     "[7|]"
+    /// );
+    /// ```
+    pub fn format_args_with_options(
+        &self,
+        args: &[Value],
+        options: &FormatOptions,
+    ) -> Result<String, DirectiveError> {
+        self.format_with_options(&Positional(args), options)
+    }
+
+    /// Renders the template, including partials from a registry.
+    ///
+    /// Behaves like `format`, except that include directives such as
+    /// `{>header}` render the partial of that name from `registry` against
+    /// the same context. Partials can include other partials.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format`, as well as
+    /// `DirectiveError::PartialNotFound` for an include without a matching
+    /// partial and `DirectiveError::CyclicInclude` for a partial that includes
+    /// itself, directly or through other partials.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, TemplateRegistry, Value};
+    ///
+    /// let mut registry = TemplateRegistry::new();
+    /// registry.insert("footer", Template::<'{', '}'>::compile("-- {team}").unwrap());
+    ///
+    /// let mut ctx = Context::new();
+    /// ctx.insert("team", Value::static_str("The team"));
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Thanks! {>footer}").unwrap();
+    /// assert_eq!(tmpl.format_with(&ctx, &registry).unwrap(), "Thanks! -- The team");
+    /// ```
+    pub fn format_with(
+        &self,
+        ctx: &dyn ContextLookup,
+        registry: &TemplateRegistry,
+    ) -> Result<String, DirectiveError> {
+        let options = FormatOptions::default();
+        let mut output = String::with_capacity(self.output_capacity(&options));
+        self.render_into(ctx, &options, Some(registry), &mut output)?;
+
+        Ok(output)
+    }
+
+    /// Renders the template using the provided context and options.
+    ///
+    /// Behaves like `format`, except that directives referencing a variable
+    /// missing from the context are rendered according to `options.missing`
+    /// instead of always failing.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The context containing variable values
+    /// * `options` - Options controlling how the template is rendered
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The rendered template output
+    /// * `Err(DirectiveError)` - If any directive fails and the options don't cover the failure
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{FormatOptions, MissingPolicy, Template, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{greeting}, {name}!").unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("greeting", Value::static_str("Hi"));
+    ///
+    /// let options = FormatOptions {
+    ///     missing: MissingPolicy::Placeholder("???".to_string()),
+    ///     ..Default::default()
+    /// };
+    ///
+    /// assert_eq!(tmpl.format_with_options(&ctx, &options).unwrap(), "Hi, ???!");
+    /// ```
+    pub fn format_with_options(
+        &self,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+    ) -> Result<String, DirectiveError> {
+        // Text alone is its own output, unless the options would change it
+        if let Some(text) = self.body.literal_text()
+            && options.newline == NewlineStyle::AsIs
+            && text.len() <= options.max_output_len
+        {
+            return Ok(text.to_owned());
+        }
+
+        let mut output = String::with_capacity(self.output_capacity(options));
+        self.render_into(ctx, options, None, &mut output)?;
+
+        Ok(output)
+    }
+
+    /// Renders only the region `name` of the template, marked with
+    /// `{@region:name}` and closed by `{@end}`.
+    ///
+    /// The output is what the region contributes to the output of `format`,
+    /// so a server can render the part of a page that changed without
+    /// keeping it in a separate template. Only regions in the template's own
+    /// text are found, not those inside sub-templates such as conditional
+    /// branches. See `RegionDirective` for how regions nest.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DirectiveError::RegionNotFound` if the template has no region
+    /// `name`, or the same errors as `format` for the directives inside the
+    /// region. Directives outside it are not rendered, so they can't fail.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile(
+    ///     "<body>{@region:header}<h1>{title}</h1>{@end}<p>{body}</p></body>",
+    /// )
+    /// .unwrap();
+    ///
+    /// let ctx = Context::from([("title", Value::static_str("News"))]);
+    /// assert_eq!(tmpl.format_region("header", &ctx).unwrap(), "<h1>News</h1>");
+    /// ```
+    pub fn format_region(
+        &self,
+        name: &str,
+        ctx: &dyn ContextLookup,
+    ) -> Result<String, DirectiveError> {
+        self.format_region_with_options(name, ctx, &FormatOptions::default())
+    }
+
+    /// Renders only the region `name` of the template with custom options,
+    /// see `format_region`.
+    ///
+    /// With `FormatOptions::trim_blocks`, the lines of the region's markers
+    /// are removed like those of any other block.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format_region`.
+    pub fn format_region_with_options(
+        &self,
+        name: &str,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+    ) -> Result<String, DirectiveError> {
+        let nodes = self
+            .body
+            .region(name)
+            .ok_or_else(|| DirectiveError::RegionNotFound {
+                name: name.to_string(),
+            })?;
+
+        let mut output = String::new();
+        self.render_spanned(ctx, options, None, nodes, &mut output)
+            .map_err(|e| e.error)?;
+
+        Ok(output)
+    }
+
+    /// Returns a `Renderer` that renders the template with `options` into a
+    /// buffer it reuses between calls.
+    ///
+    /// Prefer it to calling `format_with_options` in a loop when the same
+    /// template is rendered for many contexts one at a time, such as once per
+    /// request, and the output doesn't need to outlive the next render.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, FormatOptions, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    /// let mut renderer = tmpl.renderer(FormatOptions::default());
+    ///
+    /// let ctx = Context::from([("name", Value::static_str("Ada"))]);
+    /// assert_eq!(renderer.render(&ctx).unwrap(), "Hi Ada!");
+    /// ```
+    pub fn renderer(&self, options: FormatOptions) -> Renderer<'_, O, C> {
+        Renderer::new(self, options)
+    }
+
+    /// Returns a view of the template whose `format` can't fail, if the
+    /// template only holds text, escaped delimiters and plain substitutions
+    /// such as `{name}`, or `None` otherwise.
+    ///
+    /// Substitutions of missing variables render nothing, or the text given
+    /// to `TotalTemplate::with_missing`. See `TotalTemplate` for the exact
+    /// conditions.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{greeting}, {name}!").unwrap();
+    /// let ctx = Context::from([("greeting", Value::static_str("Hello"))]);
+    ///
+    /// assert_eq!(tmpl.total().unwrap().format(&ctx), "Hello, !");
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{name:trunc(3)}").unwrap();
+    /// assert!(tmpl.total().is_none());
+    /// ```
+    pub fn total(&self) -> Option<TotalTemplate<'_, O, C>> {
+        TotalTemplate::new(self)
+    }
+
+    /// Renders the template, reporting which directive failed.
+    ///
+    /// Behaves like `format`, except that errors come with the byte span of
+    /// the directive that failed, and `FormatError::diagnostic` renders them
+    /// as a report quoting the source. Errors inside a conditional branch,
+    /// a switch case or a partial point at the enclosing directive.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format`, wrapped in a `FormatError`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Dear {title} {name},").unwrap();
+    /// let error = tmpl.format_spanned(&Context::new()).unwrap_err();
+    ///
+    /// assert_eq!(error.span, 5..12);
+    /// assert!(error.diagnostic().ends_with("1 | Dear {title} {name},\n  |      ^^^^^^^"));
+    /// ```
+    pub fn format_spanned(&self, ctx: &dyn ContextLookup) -> Result<String, FormatError> {
+        self.format_spanned_with_options(ctx, &FormatOptions::default())
+    }
+
+    /// Renders the template using the provided options, reporting which
+    /// directive failed.
+    ///
+    /// Combines `format_with_options` and `format_spanned`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format_with_options`, wrapped in a
+    /// `FormatError`.
+    pub fn format_spanned_with_options(
+        &self,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+    ) -> Result<String, FormatError> {
+        let mut output = String::with_capacity(self.output_capacity(options));
+        self.render_spanned(ctx, options, None, self.body.all_nodes(), &mut output)?;
+
+        Ok(output)
+    }
+
+    /// Renders the template directly into a writer.
+    ///
+    /// Each literal and directive output is written as soon as it is produced,
+    /// so the full output is never held in memory. Context values are written
+    /// without intermediate strings where possible. Wrap unbuffered writers,
+    /// such as a `File`, in a `BufWriter`.
+    ///
+    /// If a directive fails, everything rendered before it has already been
+    /// written. The default output limit applies; use `format_to_with_options`
+    /// to raise it for very large outputs.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The context containing variable values
+    /// * `w` - The writer that receives the output
+    ///
+    /// # Errors
+    ///
+    /// Returns the writer's own error if writing fails. Directive failures
+    /// are returned as an `io::Error` of kind `Other` wrapping the
+    /// `DirectiveError`, which can be recovered with `io::Error::into_inner`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{n} bottles").unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("n", Value::Int(99));
+    ///
+    /// let mut out = Vec::new();
+    /// tmpl.format_to(&ctx, &mut out).unwrap();
+    /// assert_eq!(out, b"99 bottles");
+    /// ```
+    pub fn format_to<W: io::Write>(&self, ctx: &dyn ContextLookup, w: &mut W) -> io::Result<()> {
+        self.format_to_with_options(ctx, w, &FormatOptions::default())
+    }
+
+    /// Renders the template directly into a writer, using the given options.
+    ///
+    /// Behaves like `format_to`, with missing variables and the output limit
+    /// handled according to `options`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `format_to`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, FormatOptions, Template};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{'x':100000000}").unwrap();
+    /// let options = FormatOptions {
+    ///     max_output_len: usize::MAX,
+    ///     ..Default::default()
+    /// };
+    ///
+    /// tmpl.format_to_with_options(&Context::new(), &mut std::io::sink(), &options).unwrap();
+    /// ```
+    pub fn format_to_with_options<W: io::Write>(
+        &self,
+        ctx: &dyn ContextLookup,
+        w: &mut W,
+        options: &FormatOptions,
+    ) -> io::Result<()> {
+        let mut writer = IoWriter {
+            inner: w,
+            error: None,
+        };
+
+        self.render_into(ctx, options, None, &mut writer)
+            .map_err(|e| writer.error.take().unwrap_or_else(|| io::Error::other(e)))
+    }
+
+    /// Renders the template into bytes, for byte-oriented sinks such as
+    /// hashers, compressors and sockets.
+    ///
+    /// Text is written as UTF-8, without building a `String` first. Unlike
+    /// `format`, which fails with `DirectiveError::TypeError` on a `Bytes`
+    /// value substituted bare, as in `{blob}`, this writes its bytes as they
+    /// are, so the output need not be valid UTF-8. Filters and expressions
+    /// still reject bytes, and encoding filters such as `{blob:hex}` render
+    /// them as text either way.
+    ///
+    /// While rendering, each raw byte stands for one of the chars U+10FF00
+    /// to U+10FFFF, in the last private use area, so the output limit counts
+    /// four bytes for each. Text holding those chars comes out as the bytes
+    /// they stand for.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format`, except for bare `Bytes` values.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{magic}v{version}").unwrap();
+    ///
+    /// let mut ctx = Context::new();
+    /// ctx.insert("magic", Value::Bytes(vec![0x89, b'P', b'N', b'G']));
+    /// ctx.insert("version", Value::Int(2));
+    ///
+    /// assert_eq!(tmpl.format_bytes(&ctx).unwrap(), b"\x89PNGv2");
+    /// assert!(tmpl.format(&ctx).is_err());
+    /// ```
+    pub fn format_bytes(&self, ctx: &dyn ContextLookup) -> Result<Vec<u8>, DirectiveError> {
+        self.format_bytes_with_options(ctx, &FormatOptions::default())
+    }
+
+    /// Renders the template into bytes, using the given options.
+    ///
+    /// Behaves like `format_bytes`, with the template rendered according to
+    /// `options` as in `format_with_options`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `format_bytes`.
+    pub fn format_bytes_with_options(
+        &self,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+    ) -> Result<Vec<u8>, DirectiveError> {
+        let raw = RawBytes::new();
+        let state = RenderState {
+            raw_bytes: Some(&raw),
+            ..RenderState::new(options, None)
+        };
+        let mut output = ByteWriter {
+            bytes: Vec::with_capacity(self.output_capacity(options)),
+            raw: &raw,
+        };
+        self.render_with_state(ctx, &state, options, self.body.all_nodes(), &mut output)
+            .map_err(|e| e.error)?;
+
+        Ok(output.bytes)
+    }
+
+    /// Returns an adapter that renders the template into a formatter, for
+    /// composing it with `format!`, `write!` and the like.
+    ///
+    /// The adapter implements `Display` by writing the output straight into
+    /// the formatter, without an intermediate `String`. Since `Display` can't
+    /// report a `DirectiveError`, and `format!` panics on any error, the
+    /// template is rendered once up front into a sink that discards the
+    /// output: errors are returned here, and the adapter only exists for
+    /// templates that render. Each use of the adapter renders the template
+    /// again, so the template is rendered at least twice.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `format`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("name", Value::static_str("Alice"));
+    ///
+    /// let greeting = tmpl.display(&ctx).unwrap();
+    /// assert_eq!(format!("[{}]", greeting), "[Hi Alice!]");
+    ///
+    /// assert!(tmpl.display(&HashMap::<&str, Value>::new()).is_err());
+    /// ```
+    pub fn display<'a>(
+        &'a self,
+        ctx: &'a dyn ContextLookup,
+    ) -> Result<TemplateDisplay<'a, O, C>, DirectiveError> {
+        self.render_into(ctx, &FormatOptions::default(), None, &mut Discard)?;
+
+        Ok(TemplateDisplay {
+            template: self,
+            ctx,
+        })
+    }
+
+    /// Checks that the template would render against `ctx` with `options`,
+    /// without building its output, and returns the errors of every
+    /// directive that would fail.
+    ///
+    /// Each directive is checked once: variables must resolve, unless
+    /// `options.missing` allows missing ones, and have types their filters
+    /// and operators accept. Repeats resolve their pattern and count without
+    /// repeating, and conditionals and switches check the branch they
+    /// select. Limits on the output, such as `options.max_output_len`, are
+    /// not checked, as no output is built, and include directives fail as
+    /// with `format_with_options`, which has no partials.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FormatError` for each directive that fails, in source
+    /// order, with the error `format_spanned_with_options` would report for it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, DirectiveError, FormatOptions, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{'=':width} {name} {id:hex}").unwrap();
+    /// let mut ctx = Context::new();
+    /// ctx.insert("width", Value::Int(1_000_000_000));
+    /// ctx.insert("id", Value::static_str("abc"));
+    ///
+    /// let errors = tmpl.validate(&ctx, &FormatOptions::default()).unwrap_err();
+    ///
+    /// assert_eq!(errors.len(), 2);
+    /// assert!(matches!(errors[0].error, DirectiveError::NotFound { .. }));
+    /// assert!(matches!(errors[1].error, DirectiveError::FilterTypeError { .. }));
+    /// ```
+    pub fn validate(
+        &self,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+    ) -> Result<(), Vec<FormatError>> {
+        let state = RenderState::new(options, None);
+        let ctx = RenderContext::new(ctx, &state);
+        let errors: Vec<_> = self
+            .body
+            .validate_template(&ctx, &options.missing)
+            .into_iter()
+            .map(|(span, error)| FormatError {
+                span,
+                error,
+                input: self.source.clone(),
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Renders the template lazily, one piece at a time.
+    ///
+    /// Every literal span and every directive's output is yielded as its own
+    /// chunk, in order, without building the full output. Literal text is
+    /// borrowed from the template; directive outputs are owned. This suits
+    /// sinks that apply backpressure or consumers that only hash or count
+    /// the output.
+    ///
+    /// Each directive is rendered as a whole, so a large repeat still yields
+    /// a single chunk, and fails with `DirectiveError::OutputLimitExceeded`
+    /// past `FormatOptions::DEFAULT_MAX_OUTPUT_LEN`. Missing variables are
+    /// errors, as with `format`, and no other output limit applies, since the
+    /// caller decides how much to consume.
+    ///
+    /// # Errors
+    ///
+    /// A failing directive yields an `Err` with the same errors as `format`,
+    /// after which the iterator ends.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("name", Value::static_str("Alice"));
+    ///
+    /// let chunks: Vec<_> = tmpl.chunks(&ctx).collect::<Result<_, _>>().unwrap();
+    /// assert_eq!(chunks, vec!["Hi ", "Alice", "!"]);
+    /// ```
+    pub fn chunks<'a>(&'a self, ctx: &'a dyn ContextLookup) -> Chunks<'a> {
+        self.body
+            .chunks(ctx, RenderState::unlimited(), self.tracks_columns)
+    }
+
+    /// Renders the template once for every context.
+    ///
+    /// Each context is rendered independently, so a failure only affects its
+    /// own entry. The length of every output is used as the capacity hint for
+    /// the next one, so that rendering many similar contexts reallocates far
+    /// less than calling `format` in a loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `contexts` - The contexts to render, e.g. a `&[Context]`
+    ///
+    /// # Returns
+    ///
+    /// One result per context, in the same order as `contexts`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    ///
+    /// let contexts: Vec<Context> = ["Alice", "Bob"]
+    ///     .into_iter()
+    ///     .map(|name| Context::from([("name", Value::static_str(name))]))
+    ///     .collect();
+    ///
+    /// let outputs: Vec<String> = tmpl
+    ///     .format_all(&contexts)
+    ///     .into_iter()
+    ///     .collect::<Result<_, _>>()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(outputs, vec!["Hi Alice!", "Hi Bob!"]);
+    /// ```
+    pub fn format_all<T: ContextLookup>(
+        &self,
+        contexts: &[T],
+    ) -> Vec<Result<String, DirectiveError>> {
+        let options = FormatOptions::default();
+        let mut hint = self.output_capacity(&options);
+
+        contexts
+            .iter()
+            .map(|ctx| {
+                let mut output = String::with_capacity(hint);
+                self.render_into(ctx, &options, None, &mut output)?;
+                hint = hint.max(output.len());
+
+                Ok(output)
+            })
+            .collect()
+    }
+
+    /// Renders the template once for every context, in parallel.
+    ///
+    /// The parallel counterpart of `format_all`, backed by `rayon`. Results
+    /// are returned in the same order as `contexts`.
+    ///
+    /// Only available with the `rayon` feature.
+    #[cfg(feature = "rayon")]
+    pub fn format_all_par<T: ContextLookup + Sync>(
+        &self,
+        contexts: &[T],
+    ) -> Vec<Result<String, DirectiveError>> {
+        use rayon::prelude::*;
+
+        contexts
+            .par_chunks(PAR_CHUNK_SIZE)
+            .flat_map_iter(|chunk| self.format_all(chunk))
+            .collect()
+    }
+
+    /// Creates a template from its compiled body and the string it was
+    /// compiled from, if any.
+    fn from_body(body: Fragment, delimiters: Option<Delimiters>, source: Option<&str>) -> Self {
+        // Text alone only fails to render with an empty span, which
+        // diagnostics never quote, so its source isn't copied
+        let source = source
+            .filter(|_| body.literal_text().is_none())
+            .map(Arc::from);
+
+        let mut template = Self {
+            tracks_columns: body.tracks_columns(),
+            body,
+            delimiters,
+            source,
+            static_output: None,
+        };
+
+        // Kept in a plain field rather than a lazy cell, so that templates
+        // stay free of interior mutability and usable as map keys. Text alone
+        // is its own output, so only constant directives are rendered here.
+        if template.body.literal_text().is_none()
+            && template.body.directives().all(Directive::is_constant)
+        {
+            template.static_output = template.format(&Context::new()).ok();
+        }
+
+        template
+    }
+
+    /// Executes every directive and writes the results to `output`, enforcing
+    /// the output limit from `options`. Include directives take their
+    /// partials from `registry`.
+    fn render_into(
+        &self,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+        registry: Option<&TemplateRegistry>,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        self.render_spanned(ctx, options, registry, self.body.all_nodes(), output)
+            .map_err(|e| e.error)
+    }
+
+    /// Renders the nodes `nodes` of the template like `render_into`,
+    /// returning errors together with the span of the directive that failed.
+    fn render_spanned(
+        &self,
+        ctx: &dyn ContextLookup,
+        options: &FormatOptions,
+        registry: Option<&TemplateRegistry>,
+        nodes: Range<usize>,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), FormatError> {
+        let state = RenderState::new(options, registry);
+        self.render_with_state(ctx, &state, options, nodes, output)
+    }
+
+    /// Renders the nodes `nodes` of the template like `render_spanned`, with
+    /// the render's options and state already set up in `state`.
+    fn render_with_state(
+        &self,
+        ctx: &dyn ContextLookup,
+        state: &RenderState<'_>,
+        options: &FormatOptions,
+        nodes: Range<usize>,
+        output: &mut dyn fmt::Write,
+    ) -> Result<(), FormatError> {
+        let mut limited = LimitedWriter {
+            inner: output,
+            remaining: options.max_output_len,
+            exceeded: false,
+            raw: state.raw_bytes,
+        };
+        let mut output = NewlineWriter::new(&mut limited, options.newline);
+
+        let ctx = RenderContext::new(ctx, state);
+        let result = if self.tracks_columns {
+            let mut output = ColumnWriter {
+                inner: &mut output,
+                column: &state.column,
+            };
+            self.body
+                .render_template(&ctx, &options.missing, nodes, &mut output)
+        } else {
+            self.body
+                .render_template(&ctx, &options.missing, nodes, &mut output)
+        };
+        let result = result.and_then(|()| output.finish().map_err(|e| (0..0, e.into())));
+
+        let (span, error) = match result {
+            Ok(()) if !limited.exceeded => return Ok(()),
+            Ok(()) => (0..0, fmt::Error.into()),
+            Err(failure) => failure,
+        };
+
+        let error = if limited.exceeded {
+            DirectiveError::OutputLimitExceeded {
+                limit: options.max_output_len,
+            }
+        } else {
+            error
+        };
+
+        Err(FormatError {
+            span,
+            error,
+            input: self.source.clone(),
+        })
+    }
+
+    /// Returns how many bytes to reserve for an output rendered with
+    /// `options`: its estimated length, which may be huge for a repeat with a
+    /// large count, but never more than the output may hold.
+    pub(crate) fn output_capacity(&self, options: &FormatOptions) -> usize {
+        self.body.estimated_len().min(options.max_output_len)
+    }
+
+    /// Returns the estimated length of a rendered output, in bytes.
+    ///
+    /// The estimate is computed once at compile time: literal text counts
+    /// exactly, repeats with a literal pattern and count are exact, and
+    /// context-dependent values are assumed to be short. Custom directives
+    /// contribute through `Directive::estimated_len`.
+    ///
+    /// Useful for sizing a buffer before rendering into it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hello {'-':5}!").unwrap();
+    /// assert_eq!(tmpl.estimated_len(), 12);
+    /// ```
+    pub fn estimated_len(&self) -> usize {
+        self.body.estimated_len()
+    }
+
+    /// Folds the directives that don't depend on the context into literal
+    /// text, so that they are rendered once instead of on every `format`.
+    ///
+    /// A directive is folded when `Directive::is_constant` holds, such as
+    /// `{1 < 2 ? 'yes' : 'no'}` or `{'*':5}`, and it renders without error into
+    /// at most 4 KiB. Folded directives are merged with the text and escapes
+    /// around them into a single text segment spanning all of them, and
+    /// those rendering nothing are dropped. Directives nested in
+    /// sub-templates are only folded along with their parent.
+    ///
+    /// The output of the template is unchanged, but its segments, debug tree
+    /// and equality with other templates reflect the folded form.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let mut tmpl = Template::<'{', '}'>::compile("{'=':3} {1 < 2 ? 'on'} {name}").unwrap();
+    /// tmpl.optimize();
+    ///
+    /// assert_eq!(tmpl.iter().count(), 2);
+    ///
+    /// let mut ctx = Context::new();
+    /// ctx.insert("name", Value::static_str("Ada"));
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "=== on Ada");
+    /// ```
+    pub fn optimize(&mut self) {
+        self.body.optimize();
+    }
+
+    /// Returns whether the template renders the same output whatever the
+    /// context, so that output can be cached and the template rendered
+    /// without a context with `render_static`.
+    ///
+    /// A template is static when all of its directives are constant, the
+    /// same ones `optimize` folds, such as `{'=':3}` or `{1 < 2 ? 'on'}`,
+    /// and it renders without error. A single variable, include or custom
+    /// directive that doesn't declare itself constant makes it dynamic.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// assert!(Template::<'{', '}'>::compile("Hello {'-':3}").unwrap().is_static());
+    /// assert!(!Template::<'{', '}'>::compile("Hello {name}").unwrap().is_static());
+    /// ```
+    pub fn is_static(&self) -> bool {
+        self.render_static().is_some()
+    }
+
+    /// Returns the output of a static template, see `is_static`, or `None`
+    /// if the template depends on the context or fails to render.
     ///
-    /// This is the most common way to create a template. It uses the `DefaultParser`
-    /// which supports variable substitution, repeating patterns, and conditional logic.
+    /// The output is what `format` returns for any context. The text of a
+    /// template without directives is returned as is, while the output of
+    /// constant directives is rendered with the default options when the
+    /// template is compiled and kept with it, so calls never render.
     ///
-    /// # Arguments
+    /// # Examples
     ///
-    /// * `input` - The template string to compile
+    /// ```rust
+    /// use figura::Template;
     ///
-    /// # Returns
+    /// let tmpl = Template::<'{', '}'>::compile("{'=':3} {2 > 1 ? 'ready'} {'=':3}").unwrap();
+    /// assert_eq!(tmpl.render_static(), Some("=== ready ==="));
     ///
-    /// * `Ok(Template)` - A compiled template ready for rendering
-    /// * `Err(TemplateError)` - If the template syntax is invalid
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}").unwrap();
+    /// assert_eq!(tmpl.render_static(), None);
+    /// ```
+    pub fn render_static(&self) -> Option<&str> {
+        match self.body.literal_text() {
+            Some(text) if text.len() <= FormatOptions::DEFAULT_MAX_OUTPUT_LEN => Some(text),
+            Some(_) => None,
+            None => self.static_output.as_deref(),
+        }
+    }
+
+    /// Returns the names of all variables referenced by the template.
     ///
-    /// # Errors
+    /// Every directive is inspected, including the condition and both branches
+    /// of conditionals and the pattern and count of repeats. Each name appears
+    /// once, in the order it is first referenced in the template source.
     ///
-    /// Returns a `TemplateError` if:
-    /// - A delimiter is not properly closed
-    /// - A directive cannot be parsed
+    /// Custom directives only contribute variables if they implement
+    /// `Directive::arguments`.
     ///
     /// # Examples
     ///
     /// ```rust
-    /// use figura::{Template, Context, Value};
-    /// use std::collections::HashMap;
+    /// use figura::Template;
     ///
-    /// // Variable substitution
-    /// let tmpl = Template::<'{', '}'>::compile("Hello {name}!").unwrap();
+    /// let tmpl = Template::<'{', '}'>::compile(
+    ///     "{name} is {age >= 18 ? 'an adult' : 'a minor'}, {name}"
+    /// ).unwrap();
     ///
-    /// let mut ctx = HashMap::new();
-    /// ctx.insert("name", Value::static_str("World"));
-    /// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello World!");
+    /// assert_eq!(tmpl.variables(), vec!["name", "age"]);
+    /// ```
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+
+        for directive in self.body.directives() {
+            for arg in directive.arguments() {
+                arg.collect_variables(&mut names);
+            }
+        }
+
+        let mut seen = HashSet::with_capacity(names.len());
+        names.retain(|name| seen.insert(*name));
+
+        names
+    }
+
+    /// Returns the names of the filters the template applies, in order of
+    /// first use and without duplicates, including filters inside
+    /// sub-templates such as branches and case bodies.
     ///
-    /// // Repeating patterns
-    /// let tmpl = Template::<'{', '}'>::compile("{'*':count}").unwrap();
-    /// let mut ctx = HashMap::new();
-    /// ctx.insert("count", Value::Int(3));
-    /// assert_eq!(tmpl.format(&ctx).unwrap(), "***");
+    /// Names are those of `Filter::name`, so alignment specs and the pad
+    /// filters are reported as `"align"`. This lets an application check a
+    /// template against the filters it allows before formatting it.
     ///
-    /// // Conditionals
-    /// let tmpl = Template::<'{', '}'>::compile("{x > 5 ? 'big' : 'small'}").unwrap();
-    /// let mut ctx = HashMap::new();
-    /// ctx.insert("x", Value::Int(10));
-    /// assert_eq!(tmpl.format(&ctx).unwrap(), "big");
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{name:squish | trunc(8)} {vip ? '{title:squish}'} {id:>6}").unwrap();
+    ///
+    /// assert_eq!(tmpl.filters_used(), vec!["squish", "trunc", "align"]);
     /// ```
-    pub fn compile(input: impl AsRef<str>) -> Result<Self, TemplateError> {
-        Self::compile_with_parser::<DefaultParser>(input.as_ref())
+    pub fn filters_used(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+
+        collect_directives(&self.body, &mut |directive| {
+            if let Some(FilterDirective(_, filters)) = (directive as &dyn Any).downcast_ref() {
+                names.extend(filters.iter().map(|filter| filter.name()));
+            }
+        });
+
+        let mut seen = HashSet::with_capacity(names.len());
+        names.retain(|name| seen.insert(*name));
+
+        names
     }
 
-    /// Compiles a template string using a custom parser.
+    /// Returns the names of the functions the template calls, such as
+    /// `max` in `{max(a, b) > 3 ? 'many'}`, in order of first use and
+    /// without duplicates.
     ///
-    /// This method allows you to use a custom parser implementation for specialized
-    /// template syntax or custom directives. The parser must implement the `Parser` trait.
+    /// # Examples
     ///
-    /// # Type Parameters
+    /// ```rust
+    /// use figura::Template;
     ///
-    /// * `P` - A type implementing the `Parser` trait
+    /// let tmpl = Template::<'{', '}'>::compile("{abs(delta) > max(4, limit) ? 'far'} {abs(skew) > 1 ? 'off'}").unwrap();
     ///
-    /// # Arguments
+    /// assert_eq!(tmpl.functions_used(), vec!["abs", "max"]);
+    /// ```
+    pub fn functions_used(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+
+        for directive in self.body.directives() {
+            for arg in directive.arguments() {
+                arg.walk(&mut |arg| {
+                    if let Argument::Expression(expr) = arg
+                        && let Expression::Call { function, .. } = expr.as_ref()
+                    {
+                        names.push(function.name());
+                    }
+                });
+            }
+        }
+
+        let mut seen = HashSet::with_capacity(names.len());
+        names.retain(|name| seen.insert(*name));
+
+        names
+    }
+
+    /// Returns the switch cases that can never be selected, because an
+    /// earlier case of their switch has the same label or is a default,
+    /// `*`. Switches inside sub-templates, such as conditional branches and
+    /// case bodies, are included.
     ///
-    /// * `input` - The template string to compile
+    /// Such templates compile unless `CompileOptions::deny_unreachable_cases`
+    /// is on, so this reports them as warnings. See `UnreachableCase`.
     ///
-    /// # Returns
+    /// # Examples
     ///
-    /// * `Ok(Template)` - A compiled template ready for rendering
-    /// * `Err(TemplateError)` - If the template syntax is invalid
+    /// ```rust
+    /// use figura::{CaseLabel, Template};
     ///
-    /// # Errors
+    /// let tmpl = Template::<'{', '}'>::compile("{[tier](gold:G)(silver:S)(gold:again)}").unwrap();
+    /// let cases = tmpl.unreachable_cases();
     ///
-    /// Returns a `TemplateError` if:
-    /// - A delimiter is not properly closed
-    /// - The custom parser cannot parse a directive
+    /// assert_eq!(cases.len(), 1);
+    /// assert_eq!(cases[0].label, CaseLabel::Value("gold".into()));
+    /// assert_eq!((cases[0].position, cases[0].shadowed_by), (3, 1));
+    /// ```
+    pub fn unreachable_cases(&self) -> Vec<UnreachableCase> {
+        let mut cases = Vec::new();
+        collect_unreachable_cases(&self.body, &mut cases);
+        cases
+    }
+
+    /// Returns the conditions of every conditional in the template, in
+    /// source order, including those inside sub-templates such as branches,
+    /// case bodies and the steps of else-if ladders.
+    ///
+    /// Each condition is an `Argument`, whose `Argument::Expression` holds
+    /// the parsed `Expression` tree for tools such as linters to walk.
     ///
     /// # Examples
     ///
     /// ```rust
-    /// use figura::{Template, DefaultParser, Context, Value};
-    /// use std::collections::HashMap;
+    /// use figura::{Argument, ComparisonOp, Expression, Template};
     ///
-    /// // Using the default parser explicitly
-    /// let tmpl = Template::<'{', '}'>::compile_with_parser::<DefaultParser>(
-    ///     "Hello {name}!"
-    /// ).unwrap();
+    /// let tmpl = Template::<'{', '}'>::compile("{age >= 18 && !banned ? 'in' : 'out'}").unwrap();
+    /// let conditions = tmpl.conditions();
     ///
-    /// let mut ctx = HashMap::new();
-    /// ctx.insert("name", Value::static_str("Alice"));
-    /// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello Alice!");
+    /// let Argument::Expression(expr) = conditions[0] else { panic!() };
+    /// let Expression::And(args) = expr.as_ref() else { panic!() };
+    ///
+    /// let Argument::Expression(left) = &args[0] else { panic!() };
+    /// assert!(matches!(
+    ///     left.as_ref(),
+    ///     Expression::Comparison { left: Argument::Variable(name), op: ComparisonOp::GreaterThanEquals, .. }
+    ///         if name == "age"
+    /// ));
+    ///
+    /// let Argument::Expression(right) = &args[1] else { panic!() };
+    /// assert!(matches!(right.as_ref(), Expression::Not(Argument::Variable(name)) if name == "banned"));
     /// ```
+    pub fn conditions(&self) -> Vec<&Argument> {
+        let mut conditions = Vec::new();
+        collect_conditionals(&self.body, &mut |conditional| {
+            conditions.extend(conditional.branches.iter().map(|(cond, _)| cond));
+        });
+        conditions
+    }
+
+    /// Returns the conditions that don't depend on the context, with the
+    /// value each always has, in source order. See `ConstantCondition` and
+    /// `ConditionalDirective::constant_conditions`.
     ///
-    /// For custom parser implementations, implement the `Parser` trait:
+    /// Conditionals with such a condition always render the same branch,
+    /// which a linter can flag. `Template::optimize` folds them away.
+    ///
+    /// # Examples
     ///
     /// ```rust
-    /// use figura::{Parser, Token, Directive};
+    /// use figura::Template;
     ///
-    /// struct MyCustomParser;
+    /// let tmpl = Template::<'{', '}'>::compile("{1 > 2 ? 'never'} {vip ? 'dear'} {'on' ? 'always'}").unwrap();
+    /// let conditions = tmpl.const_conditions();
     ///
-    /// impl Parser for MyCustomParser {
-    ///     fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
-    ///         // Your custom parsing logic here
-    ///         None
-    ///     }
-    /// }
+    /// assert_eq!(conditions.len(), 2);
+    /// assert!(!conditions[0].value);
+    /// assert!(conditions[1].value);
     /// ```
-    pub fn compile_with_parser<P: Parser>(input: &str) -> Result<Self, TemplateError> {
-        let mut directives: Vec<Box<dyn Directive>> = Vec::new();
-        let mut cursor = 0;
-        let mut chars = input.char_indices().peekable();
-
-        let arena = RefCell::new(String::new());
-
-        while let Some((idx, ch)) = chars.next() {
-            if ch == O {
-                // Handle escaped opening delimiter (e.g. "{{")
-                if let Some(&(_, next_char)) = chars.peek()
-                    && next_char == O
-                {
-                    if idx > cursor {
-                        directives.push(Box::new(LiteralDirective(Cow::Owned(
-                            input[cursor..idx].to_string(),
-                        ))));
-                    }
-
-                    directives.push(Box::new(LiteralDirective(Cow::Owned(O.to_string()))));
-                    chars.next();
-                    cursor = chars.peek().map(|(i, _)| *i).unwrap_or(input.len());
-                    continue;
-                }
-
-                if idx > cursor {
-                    directives.push(Box::new(LiteralDirective(Cow::Owned(
-                        input[cursor..idx].to_string(),
-                    ))));
-                }
+    pub fn const_conditions(&self) -> Vec<ConstantCondition> {
+        let mut conditions = Vec::new();
+        collect_conditionals(&self.body, &mut |conditional| {
+            conditions.extend(conditional.constant_conditions());
+        });
+        conditions
+    }
 
-                let start = idx + ch.len_utf8();
-                let mut depth = 1;
-                let mut end = start;
-                let mut found_close = false;
+    /// Returns metrics about the structure of the template: how many
+    /// directives of each kind it holds, how deeply they nest, how many
+    /// distinct variables they reference and how long the output can get.
+    ///
+    /// The metrics are computed in a single walk over the compiled template,
+    /// sub-templates included, without rendering anything. See
+    /// `TemplateMetrics` for the precise definition of each.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{vip ? 'Dear {name}' : 'Hi'} {'=':40}").unwrap();
+    /// let metrics = tmpl.metrics();
+    ///
+    /// assert_eq!(metrics.directives, 3);
+    /// assert_eq!(metrics.conditionals, 1);
+    /// assert_eq!(metrics.max_depth, 2);
+    /// assert_eq!(metrics.variables, 2);
+    /// assert_eq!(metrics.max_output_len, Some(5 + 8 + 1 + 40));
+    /// ```
+    pub fn metrics(&self) -> TemplateMetrics {
+        TemplateMetrics::of(&self.body)
+    }
 
-                for (c_idx, c_char) in chars.by_ref() {
-                    if O == C {
-                        if c_char == C {
-                            depth -= 1;
-                        }
-                    } else if c_char == O {
-                        depth += 1;
-                    } else if c_char == C {
-                        depth -= 1;
-                    }
+    /// Returns the keys of `ctx` that the template never references, sorted.
+    ///
+    /// Together with `variables`, this catches drift between a template and
+    /// its data: a variable missing from the context, or a key nothing uses,
+    /// is often a typo on either side. Works with `Context`, `OwnedContext`
+    /// and any other `HashMap` of values.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    ///
+    /// let mut ctx = Context::new();
+    /// ctx.insert("name", Value::static_str("Ada"));
+    /// ctx.insert("nmae", Value::static_str("Ada"));
+    ///
+    /// assert_eq!(tmpl.unused_keys(&ctx), vec!["nmae"]);
+    /// ```
+    pub fn unused_keys<'a, K: AsRef<str>, S>(&self, ctx: &'a HashMap<K, Value, S>) -> Vec<&'a str> {
+        let used: HashSet<&str> = self.variables().into_iter().collect();
 
-                    if depth == 0 {
-                        end = c_idx;
-                        found_close = true;
-                        cursor = c_idx + C.len_utf8();
-                        break;
-                    }
-                }
+        let mut unused: Vec<&str> = ctx
+            .keys()
+            .map(AsRef::as_ref)
+            .filter(|key| !used.contains(key))
+            .collect();
+        unused.sort_unstable();
 
-                if !found_close {
-                    return Err(TemplateError::MissingDelimiter(C));
-                }
+        unused
+    }
 
-                let content = &input[start..end];
+    /// Returns whether rendering against `new` may give different output
+    /// than against `old`, because a variable the template references, per
+    /// `variables`, differs between them.
+    ///
+    /// A variable differs if it is set in only one of the contexts, or set to
+    /// values of different types or data: `Int(1)` and `Float(1.0)` differ,
+    /// since they render differently. Keys the template never references are
+    /// ignored, so a caller can skip re-rendering when this returns `false`.
+    ///
+    /// Partials included with `{>name}` may reference any variable, so
+    /// templates with includes are always affected. Custom directives only
+    /// count if they implement `Directive::arguments`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Context, Template, Value};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("{user}: {count}").unwrap();
+    ///
+    /// let mut old = Context::new();
+    /// old.insert("user", Value::static_str("ada"));
+    /// old.insert("count", Value::Int(3));
+    /// old.insert("clock", Value::Int(100));
+    ///
+    /// let mut new = old.clone();
+    /// new.insert("clock", Value::Int(101));
+    /// assert!(!tmpl.affected_by(&old, &new));
+    ///
+    /// new.insert("count", Value::Int(4));
+    /// assert!(tmpl.affected_by(&old, &new));
+    /// ```
+    pub fn affected_by(&self, old: &dyn ContextLookup, new: &dyn ContextLookup) -> bool {
+        if self.metrics().includes > 0 {
+            return true;
+        }
 
-                arena.borrow_mut().clear();
+        self.variables()
+            .into_iter()
+            .any(|name| match (old.get(name), new.get(name)) {
+                (Some(old), Some(new)) => !old.identical(new),
+                (old, new) => old.is_some() != new.is_some(),
+            })
+    }
 
-                let tokens: Vec<Token> = TemplateLexer::new(content).collect();
+    /// Returns an iterator over the top-level segments of the template.
+    ///
+    /// Segments are yielded in source order and cover the whole source, each
+    /// with a byte span into the compiled string. Every directive forms its
+    /// own segment, and so does the literal text between two directives,
+    /// escaped delimiters included; only an escape with no text around it
+    /// forms an escape segment. This is meant for
+    /// tooling such as linters and syntax highlighters.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{SegmentKind, Template};
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}{{").unwrap();
+    /// let segments: Vec<_> = tmpl.iter().collect();
+    ///
+    /// assert_eq!(segments[0].kind(), SegmentKind::Text);
+    /// assert_eq!(segments[1].kind(), SegmentKind::Directive);
+    /// assert_eq!(segments[1].span(), 3..9);
+    /// assert_eq!(segments[1].source(), Some("{name}"));
+    /// assert_eq!(segments[1].variables(), vec!["name"]);
+    /// assert_eq!(segments[2].kind(), SegmentKind::Escape);
+    /// ```
+    pub fn iter(&self) -> Segments<'_> {
+        self.body.segments()
+    }
 
-                match P::parse(&tokens) {
-                    Some(directive) => directives.push(directive),
-                    None => return Err(TemplateError::DirectiveParsing(content.to_string())),
-                }
-            } else if ch == C
-                && let Some(&(_, next_char)) = chars.peek()
-                && next_char == C
-            {
-                if idx > cursor {
-                    directives.push(Box::new(LiteralDirective(Cow::Owned(
-                        input[cursor..idx].to_string(),
-                    ))));
-                }
+    /// Returns the structural differences between this template and `other`,
+    /// in source order.
+    ///
+    /// Segments are compared the way `PartialEq` compares templates, so
+    /// whitespace inside directives doesn't count as a change, and matched up
+    /// with a sequence diff. A run of removed segments directly followed by
+    /// added ones is reported as modifications, pairing them up in order.
+    /// Runtime delimiters are not compared. This is meant for live-reload
+    /// tooling that needs to know which parts of a template changed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, TemplateChange};
+    ///
+    /// let old = Template::<'{', '}'>::compile("Hi {name}, {greeting}").unwrap();
+    /// let new = Template::<'{', '}'>::compile("Hi { name }, {farewell}").unwrap();
+    ///
+    /// assert_eq!(
+    ///     old.diff(&new),
+    ///     vec![TemplateChange::Modified {
+    ///         old_index: 3,
+    ///         old_span: 11..21,
+    ///         new_index: 3,
+    ///         new_span: 13..23,
+    ///     }]
+    /// );
+    /// ```
+    pub fn diff(&self, other: &Self) -> Vec<TemplateChange> {
+        self.body.diff(&other.body)
+    }
 
-                directives.push(Box::new(LiteralDirective(Cow::Owned(C.to_string()))));
-                chars.next();
-                cursor = chars.peek().map(|(i, _)| *i).unwrap_or(input.len());
-                continue;
-            }
-        }
+    /// Renders the parsed representation of the template as an indented tree.
+    ///
+    /// Each directive is written on its own line, with its arguments and
+    /// nested sub-templates indented below it. This helps diagnose templates
+    /// that parse differently than expected and makes for stable snapshots.
+    /// Custom directives appear as `<directive>` unless they implement
+    /// `Directive::write_tree`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {vip ? 'dear {name}' : 'there'}").unwrap();
+    ///
+    /// assert_eq!(
+    ///     tmpl.debug_tree(),
+    ///     concat!(
+    ///         "Template\n",
+    ///         "  Literal \"Hi \"\n",
+    ///         "  Conditional\n",
+    ///         "    if: vip\n",
+    ///         "    then: Template\n",
+    ///         "      Literal \"dear \"\n",
+    ///         "      Replace: name\n",
+    ///         "    else: \"there\"\n",
+    ///     )
+    /// );
+    /// ```
+    pub fn debug_tree(&self) -> String {
+        let mut tree = DebugTree::new();
+        tree.branch("Template", |tree| tree.fragment(&self.body));
+        tree.finish()
+    }
 
-        if cursor < input.len() {
-            directives.push(Box::new(LiteralDirective(Cow::Owned(
-                input[cursor..].to_string(),
-            ))));
-        }
+    /// Writes the template back as source, in a canonical form that compiles
+    /// to an equal template.
+    ///
+    /// The source uses the syntax of `DefaultParser` and the template's
+    /// delimiters: spacing is normalized, literals are single-quoted,
+    /// delimiters in text are escaped with a backslash and nested
+    /// sub-templates are written as quoted literals. This suits formatters
+    /// and other tooling that edit compiled templates.
+    ///
+    /// Returns `None` if the template holds a directive that can't be written
+    /// back, such as a custom directive that doesn't implement
+    /// `Directive::to_source`, or if the source wouldn't compile back to an
+    /// equal template with `DefaultParser` and the default `CompileOptions`,
+    /// which is checked by compiling it. Empty directives are written as
+    /// `{}`, so their source only compiles back with
+    /// `CompileOptions::empty_directives` set to `EmptyPolicy::Empty`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile(r#"Hi {vip?"dear {name}":there} {{"#).unwrap();
+    /// let source = tmpl.to_source().unwrap();
+    ///
+    /// assert_eq!(source, r"Hi {vip ? 'dear {name}' : there} \{");
+    /// assert_eq!(Template::<'{', '}'>::compile(&source).unwrap(), tmpl);
+    /// ```
+    pub fn to_source(&self) -> Option<String> {
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        let delimiters = match &self.delimiters {
+            Some(delimiters) => (delimiters.open(), delimiters.close()),
+            None => (&*O.encode_utf8(&mut open), &*C.encode_utf8(&mut close)),
+        };
 
-        Ok(Self { directives })
+        self.source_with(delimiters)
     }
 
-    /// Renders the template using the provided context.
+    /// Writes the template back as source with the delimiters `O2` and `C2`
+    /// instead of its own, like `to_source`.
     ///
-    /// This method executes all directives in the template and concatenates their
-    /// results into a final string. It pre-allocates a reasonable capacity to minimize
-    /// allocations during rendering.
+    /// Directives, including those of sub-templates, are written with the
+    /// new delimiters, and text is escaped for them: a `<` in the text needs
+    /// escaping with `<` and `>` as delimiters, while a `{` no longer does.
+    /// This converts templates between dialects, such as `{name}` and
+    /// `<name>`, without editing their text by hand.
     ///
-    /// # Arguments
+    /// Returns `None` when `to_source` would, or if `O2` and `C2` are not
+    /// valid delimiters, see `Template::validate_delimiters`. The source is
+    /// checked by compiling it with the new delimiters.
     ///
-    /// * `ctx` - A reference to the context containing variable values
+    /// # Examples
     ///
-    /// # Returns
+    /// ```rust
+    /// use figura::Template;
     ///
-    /// * `Ok(String)` - The rendered template output
-    /// * `Err(DirectiveError)` - If any directive fails (e.g., missing variable, type mismatch)
+    /// let tmpl = Template::<'{', '}'>::compile("a < b: {ok ? 'yes, {name}'}").unwrap();
+    /// let source = tmpl.reserialize::<'<', '>'>().unwrap();
+    ///
+    /// assert_eq!(source, r"a \< b: <ok ? 'yes, <name>'>");
+    /// assert_eq!(
+    ///     Template::<'<', '>'>::compile(&source).unwrap().reserialize::<'{', '}'>().unwrap(),
+    ///     tmpl.to_source().unwrap()
+    /// );
+    /// ```
+    pub fn reserialize<const O2: char, const C2: char>(&self) -> Option<String> {
+        Template::<O2, C2>::validate_delimiters().ok()?;
+
+        let (mut open, mut close) = ([0; 4], [0; 4]);
+        self.source_with((&*O2.encode_utf8(&mut open), &*C2.encode_utf8(&mut close)))
+    }
+
+    /// Writes the template as source with the delimiters `open` and `close`,
+    /// checking that the source compiles back to an equal template.
+    fn source_with(&self, (open, close): (&str, &str)) -> Option<String> {
+        let source = SourceWriter::new(open, close).fragment(&self.body)?;
+        let options = CompileOptions {
+            empty_directives: EmptyPolicy::Empty,
+            ..Default::default()
+        };
+        let body =
+            compile_fragment::<DefaultParser>(&source, (open, close), &options, None, None).ok()?;
+
+        (body == self.body).then_some(source)
+    }
+}
+
+impl Template<'{', '}'> {
+    /// Compiles a template string with delimiters chosen at runtime, using
+    /// the default parser.
+    ///
+    /// Runtime delimiters can be several characters long, which makes
+    /// dialects such as `${name}` or `<% name %>` possible. The const-generic
+    /// constructors remain the faster choice for single-character delimiters.
     ///
     /// # Errors
     ///
-    /// Returns a `DirectiveError` if:
-    /// - A referenced variable is not found in the context
-    /// - A variable has an incompatible type for the operation
-    /// - A literal value cannot be parsed as the required type
+    /// Returns a `TemplateError` if a delimiter is not properly closed or a
+    /// directive cannot be parsed.
     ///
     /// # Examples
     ///
     /// ```rust
-    /// use figura::{Template, Context, Value};
+    /// use figura::{Delimiters, Template, Value};
     /// use std::collections::HashMap;
     ///
-    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+    /// let delimiters = Delimiters::new("${", "}").unwrap();
+    /// let tmpl = Template::with_delimiters("Hello ${name}!", delimiters).unwrap();
     ///
     /// let mut ctx = HashMap::new();
-    /// ctx.insert("name", Value::static_str("Alice"));
-    ///
-    /// let output = tmpl.format(&ctx).unwrap();
-    /// assert_eq!(output, "Hi Alice!");
+    /// ctx.insert("name", Value::static_str("World"));
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "Hello World!");
     /// ```
-    pub fn format(&self, ctx: &Context) -> Result<String, DirectiveError> {
-        let mut output = String::with_capacity(self.directives.len() * 8);
+    pub fn with_delimiters(
+        input: impl AsRef<str>,
+        delimiters: Delimiters,
+    ) -> Result<Self, TemplateError> {
+        Self::with_delimiters_parser_and_options::<DefaultParser>(
+            input.as_ref(),
+            delimiters,
+            &CompileOptions::default(),
+        )
+    }
 
-        for directive in &self.directives {
-            let result = directive.exec(ctx)?;
-            output.push_str(&result);
-        }
+    /// Compiles a template string with runtime delimiters, a custom parser
+    /// and the given options.
+    ///
+    /// Combines `with_delimiters` and `compile_with_parser_and_options`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `TemplateError` if:
+    /// - A delimiter is not properly closed
+    /// - The custom parser cannot parse a directive
+    /// - Directives are nested deeper than `options.max_depth`
+    pub fn with_delimiters_parser_and_options<P: Parser>(
+        input: &str,
+        delimiters: Delimiters,
+        options: &CompileOptions,
+    ) -> Result<Self, TemplateError> {
+        let body = compile_fragment::<P>(
+            input,
+            (delimiters.open(), delimiters.close()),
+            options,
+            None,
+            None,
+        )?;
 
-        Ok(output)
+        Ok(Self::from_body(body, Some(delimiters), Some(input)))
+    }
+}
+
+/// A template bound to a context, created by `Template::display`, that
+/// renders straight into a formatter.
+///
+/// Every use renders the template again, without building a `String` first.
+/// Formatting flags such as a width are ignored.
+pub struct TemplateDisplay<'a, const O: char, const C: char> {
+    template: &'a Template<O, C>,
+    ctx: &'a dyn ContextLookup,
+}
+
+/// Renders the template with the default options. Since `display` has
+/// already rendered it once, this only fails if the formatter does, or if
+/// the context changed in between so that a directive now fails.
+impl<const O: char, const C: char> fmt::Display for TemplateDisplay<'_, O, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.template
+            .render_into(self.ctx, &FormatOptions::default(), None, f)
+            .map_err(|_| fmt::Error)
+    }
+}
+
+impl<'a, const O: char, const C: char> IntoIterator for &'a Template<O, C> {
+    type Item = Segment<'a>;
+    type IntoIter = Segments<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
@@ -0,0 +1,294 @@
+//! Complexity metrics of compiled templates.
+//!
+//! `Template::metrics` walks the directives of a template once, sub-templates
+//! included, to count them by kind, measure how deeply they nest and bound
+//! how much output they can produce. This is meant for linters that flag
+//! overly complex or expensive templates before they are deployed.
+//!
+//! `Template::compile_with_stats` measures the work of compiling instead.
+
+use crate::directive::DEFAULT_ESTIMATED_LEN;
+use crate::{
+    Argument, ConditionalDirective, Directive, ErrorMessageDirective, FallbackDirective,
+    FilterDirective, Fragment, IncludeDirective, LoopDirective, RepeatDirective, RepeatFilter,
+    ReplaceDirective, SegmentKind, SwitchDirective, UnknownDirective,
+};
+use std::any::Any;
+use std::collections::HashSet;
+
+/// Metrics about the structure of a compiled template, as returned by
+/// `Template::metrics`.
+///
+/// Directives are counted wherever they appear, including inside
+/// sub-templates such as conditional branches. Each directive is counted
+/// once, by its outermost kind: `{nick | 'anon'}` is a fallback, not also a
+/// replacement. Literal text and escapes are not directives.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateMetrics {
+    /// The number of nodes in the compiled tree: every directive, run of
+    /// literal text and escape, including those of sub-templates
+    pub nodes: usize,
+    /// The number of directives of every kind
+    pub directives: usize,
+    /// Plain substitutions, such as `{name}`
+    pub replacements: usize,
+    /// Filtered values, such as `{name:trunc(5)}` or `{name | reverse | >8}`
+    pub filters: usize,
+    /// Repeats, such as `{'-':3}`
+    pub repeats: usize,
+    /// Conditionals, such as `{vip ? 'dear' : 'there'}`. Every step of an
+    /// else-if ladder counts as one
+    pub conditionals: usize,
+    /// Switches, such as `{[tier](gold:G)(*:-)}`
+    pub switches: usize,
+    /// Loops over arrays, such as `{items*'{.} '}`
+    pub loops: usize,
+    /// Includes of partials, such as `{>header}`
+    pub includes: usize,
+    /// Fallbacks for missing variables, such as `{nick | 'anon'}`
+    pub fallbacks: usize,
+    /// Directives with a custom error message
+    pub error_messages: usize,
+    /// Directives the parser didn't recognize
+    pub unknown: usize,
+    /// Empty directives, `{}`, columns, regions and custom directives
+    pub other: usize,
+    /// How deeply directives nest: 0 without directives, 1 when no
+    /// directive contains another, and one more for every level of
+    /// sub-templates
+    pub max_depth: usize,
+    /// The number of distinct variables referenced, as listed by
+    /// `Template::variables`
+    pub variables: usize,
+    /// An upper bound on the length of the rendered output, in bytes, or
+    /// `None` if a repeat count comes from a variable, so the output is
+    /// unbounded.
+    ///
+    /// Literal text counts its exact length and every value taken from the
+    /// context counts as a short value, while includes, unknown and custom
+    /// directives count their `Directive::estimated_len`.
+    /// Conditionals and switches count their longest branch, and repeats
+    /// multiply their pattern by their count, as do `repeat` filters. Other
+    /// filters are assumed not to change the length of their value.
+    pub max_output_len: Option<usize>,
+}
+
+/// Statistics about compiling a template, as returned by
+/// `Template::compile_with_stats`, for profiling which templates are
+/// expensive to compile.
+///
+/// Sub-templates, such as conditional branches holding directives, are
+/// compiled again from their text, so their bytes and tokens count on top of
+/// those of the directive holding them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompileStats {
+    /// The number of bytes of source scanned for directives, after a byte
+    /// order mark
+    pub bytes_scanned: usize,
+    /// The number of directives lexed and handed to the parser
+    pub directives: usize,
+    /// The number of tokens the lexer produced for them
+    pub tokens: usize,
+    /// How many times a buffer of tokens had to grow. Directives share one
+    /// buffer per sub-template, so this only grows for a directive with more
+    /// tokens than any before it, and with the `smallvec` feature only once
+    /// one has more than 16. Allocations made by parsers aren't counted
+    pub token_buffer_growths: usize,
+    /// How deeply directives nest: 0 without directives, 1 when no directive
+    /// holds a sub-template, and one more for every level of sub-templates
+    pub max_depth: usize,
+}
+
+impl CompileStats {
+    /// Adds the statistics of a sub-template nested in a directive.
+    pub(crate) fn add_nested(&mut self, nested: Self) {
+        self.bytes_scanned += nested.bytes_scanned;
+        self.directives += nested.directives;
+        self.tokens += nested.tokens;
+        self.token_buffer_growths += nested.token_buffer_growths;
+        self.max_depth = self.max_depth.max(nested.max_depth + 1);
+    }
+}
+
+impl TemplateMetrics {
+    /// Computes the metrics of a fragment and everything nested in it.
+    pub(crate) fn of(fragment: &Fragment) -> Self {
+        let mut walker = Walker::default();
+        let max_output_len = walker.fragment(fragment, 0);
+
+        Self {
+            variables: walker.variables.len(),
+            max_output_len,
+            ..walker.metrics
+        }
+    }
+}
+
+/// The state of a single walk over a template.
+#[derive(Default)]
+struct Walker<'a> {
+    metrics: TemplateMetrics,
+    variables: HashSet<&'a str>,
+}
+
+impl<'a> Walker<'a> {
+    /// Visits the segments of a fragment whose directives are nested `depth`
+    /// levels deep, returning the fragment's longest output.
+    fn fragment(&mut self, fragment: &'a Fragment, depth: usize) -> Option<usize> {
+        // Every segment is visited, even once the output is known to be
+        // unbounded, so that the counts cover the whole template
+        let mut len = Some(0usize);
+
+        for segment in fragment.segments() {
+            self.metrics.nodes += 1;
+
+            let segment_len = match segment.kind() {
+                SegmentKind::Directive => self.directive(segment.directive(), depth + 1),
+                SegmentKind::Text | SegmentKind::Escape => {
+                    Some(segment.directive().estimated_len())
+                }
+            };
+
+            len = len.zip(segment_len).map(|(a, b)| a.saturating_add(b));
+        }
+
+        len
+    }
+
+    /// Counts a directive at `depth` and visits its arguments, returning its
+    /// longest output.
+    fn directive(&mut self, directive: &'a dyn Directive, depth: usize) -> Option<usize> {
+        let metrics = &mut self.metrics;
+        let any: &dyn Any = directive;
+
+        let count = if any.is::<ReplaceDirective>() {
+            &mut metrics.replacements
+        } else if any.is::<FilterDirective>() {
+            &mut metrics.filters
+        } else if any.is::<RepeatDirective>() {
+            &mut metrics.repeats
+        } else if any.is::<ConditionalDirective>() {
+            &mut metrics.conditionals
+        } else if any.is::<SwitchDirective>() {
+            &mut metrics.switches
+        } else if any.is::<LoopDirective>() {
+            &mut metrics.loops
+        } else if any.is::<IncludeDirective>() {
+            &mut metrics.includes
+        } else if any.is::<FallbackDirective>() {
+            &mut metrics.fallbacks
+        } else if any.is::<ErrorMessageDirective>() {
+            &mut metrics.error_messages
+        } else if any.is::<UnknownDirective>() {
+            &mut metrics.unknown
+        } else {
+            &mut metrics.other
+        };
+
+        *count += 1;
+        metrics.directives += 1;
+        metrics.max_depth = metrics.max_depth.max(depth);
+
+        self.output_len(directive, depth)
+    }
+
+    /// Visits the arguments of a directive at `depth`, returning its longest
+    /// output.
+    fn output_len(&mut self, directive: &'a dyn Directive, depth: usize) -> Option<usize> {
+        let any: &dyn Any = directive;
+
+        if let Some(ReplaceDirective(arg)) = any.downcast_ref() {
+            self.argument(arg, depth)
+        } else if let Some(FilterDirective(arg, filters)) = any.downcast_ref() {
+            let len = self.argument(arg, depth);
+
+            filters.iter().fold(len, |len, filter| {
+                let filter: &dyn Any = filter.as_ref();
+
+                match filter.downcast_ref::<RepeatFilter>() {
+                    Some(repeat) => len.map(|len| len.saturating_mul(repeat.count)),
+                    None => len,
+                }
+            })
+        } else if let Some(RepeatDirective(pattern, count)) = any.downcast_ref() {
+            let pattern = self.argument(pattern, depth);
+            self.argument(count, depth);
+
+            match count {
+                Argument::Literal(count) => {
+                    let count = count.parse::<usize>().unwrap_or(0);
+                    pattern.map(|len| len.saturating_mul(count))
+                }
+                _ => None,
+            }
+        } else if let Some(conditional) = any.downcast_ref::<ConditionalDirective>() {
+            // The directive was counted once above; the other steps of its
+            // ladder count too
+            self.metrics.conditionals += conditional.branches.len().saturating_sub(1);
+
+            let mut len = match &conditional.if_false {
+                Some(arg) => self.argument(arg, depth),
+                None => Some(0),
+            };
+
+            for (cond, if_true) in &conditional.branches {
+                self.argument(cond, depth);
+                let if_true = self.argument(if_true, depth);
+                len = longest(len, if_true);
+            }
+
+            len
+        } else if let Some(switch) = any.downcast_ref::<SwitchDirective>() {
+            self.argument(&switch.scrutinee, depth);
+
+            let mut len = Some(0);
+            for case in &switch.cases {
+                let body = self.argument(&case.body, depth);
+                len = longest(len, body);
+            }
+
+            len
+        } else if let Some(looping) = any.downcast_ref::<LoopDirective>() {
+            self.argument(&looping.items, depth);
+            self.argument(&looping.body, depth);
+
+            // As long as the array is
+            None
+        } else if let Some(fallback) = any.downcast_ref::<FallbackDirective>() {
+            let len = self.output_len(fallback.directive.as_ref(), depth);
+            let fallback = self.argument(&fallback.fallback, depth);
+
+            longest(len, fallback)
+        } else if let Some(message) = any.downcast_ref::<ErrorMessageDirective>() {
+            self.output_len(message.directive.as_ref(), depth)
+        } else {
+            for arg in directive.arguments() {
+                self.argument(arg, depth);
+            }
+
+            Some(directive.estimated_len())
+        }
+    }
+
+    /// Visits an argument of a directive at `depth`, returning its longest
+    /// output.
+    fn argument(&mut self, arg: &'a Argument, depth: usize) -> Option<usize> {
+        match arg {
+            Argument::Literal(text) => Some(text.len()),
+            Argument::Template(fragment) => self.fragment(fragment, depth),
+            Argument::Variable(_) | Argument::Expression(_) => {
+                let mut names = Vec::new();
+                arg.collect_variables(&mut names);
+                self.variables.extend(names);
+
+                Some(DEFAULT_ESTIMATED_LEN)
+            }
+        }
+    }
+}
+
+/// Returns the longer of two output lengths, or `None` if either is
+/// unbounded.
+fn longest(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a?.max(b?))
+}
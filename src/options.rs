@@ -0,0 +1,839 @@
+//! Options that control how a template is compiled and rendered.
+
+use crate::{
+    Filter, RenderContext, Schema, TemplateError, Value,
+    arg::{Resolvable, eq_ignore_case},
+};
+use std::borrow::Cow;
+use std::fmt;
+
+/// What to render when a directive references a variable that is missing
+/// from the context.
+///
+/// The policy applies to the whole directive: if any variable it resolves is
+/// missing, the entire directive is replaced according to the policy. Other
+/// errors, such as type mismatches, are always returned.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "Hello {name}!"   Context: (empty)
+///
+/// MissingPolicy::Error                  -> Err(DirectiveError::NotFound { .. })
+/// MissingPolicy::Empty                  -> "Hello !"
+/// MissingPolicy::Keep                   -> "Hello {name}!"
+/// MissingPolicy::Placeholder("?".into()) -> "Hello ?!"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MissingPolicy {
+    /// Abort rendering with `DirectiveError::NotFound` (the default).
+    #[default]
+    Error,
+    /// Render the directive as an empty string.
+    Empty,
+    /// Render the directive's original source text, delimiters included.
+    Keep,
+    /// Render the given text in place of the directive.
+    Placeholder(String),
+}
+
+/// What to render for a directive the parser didn't recognize, such as
+/// `{name:bogus(1)}` or `{% raw %}`.
+///
+/// Unrecognized directives still compile; the policy decides what they
+/// render. Empty directives, `{}`, follow `EmptyPolicy` instead.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "Hi {% if admin %}!"
+///
+/// UnknownPolicy::Error -> Err(DirectiveError::UnknownDirective { .. })
+/// UnknownPolicy::Keep  -> "Hi {% if admin %}!"
+/// UnknownPolicy::Empty -> "Hi !"
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum UnknownPolicy {
+    /// Abort rendering with `DirectiveError::UnknownDirective` (the default).
+    #[default]
+    Error,
+    /// Render the directive's original source text, delimiters included, so
+    /// that another tool can process it later.
+    Keep,
+    /// Render the directive as an empty string.
+    Empty,
+}
+
+/// What a directive holding nothing but whitespace, such as `{}` or `{   }`,
+/// compiles to, set with `CompileOptions::empty_directives`.
+///
+/// The policy applies wherever directives are compiled, including the
+/// sub-templates in the branches of conditionals and switches.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "fn main() {}"
+///
+/// EmptyPolicy::Error -> Err(TemplateError::EmptyDirective { position: 10 })
+/// EmptyPolicy::Keep  -> "fn main() {}"
+/// EmptyPolicy::Empty -> "fn main() "
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum EmptyPolicy {
+    /// Fail compiling with `TemplateError::EmptyDirective` (the default).
+    #[default]
+    Error,
+    /// Compile the directive as text, delimiters and whitespace included.
+    Keep,
+    /// Compile the directive as one that renders nothing, `EmptyDirective`
+    /// with `DefaultParser`.
+    Empty,
+}
+
+/// What a filter does with a value of a type it doesn't accept, such as
+/// `{count:len}` with a number or `{id:hex}` with a string.
+///
+/// The policy applies uniformly to every filter, to the errors they report
+/// as `DirectiveError::FilterTypeError`. Other filter errors, such as a
+/// non-finite float given to `round`, are always returned.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "{id:hex} {count:len}"   Context: id = "255", count = 42
+///
+/// FilterMismatch::Error  -> Err(DirectiveError::FilterTypeError { .. })
+/// FilterMismatch::Skip   -> "255 42"
+/// FilterMismatch::Coerce -> "ff 2"
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FilterMismatch {
+    /// Abort rendering with `DirectiveError::FilterTypeError` (the default).
+    #[default]
+    Error,
+    /// Skip the filter, passing the value on unchanged. A value that has no
+    /// text form, such as `Value::Bytes`, still fails to render if no later
+    /// filter accepts it.
+    Skip,
+    /// Convert the value and apply the filter again: strings are parsed as
+    /// numbers or booleans, bytes are decoded as UTF-8 with invalid sequences
+    /// replaced, and other values are turned into the text they render as.
+    /// If the converted value doesn't fit either, the filter is skipped.
+    Coerce,
+}
+
+impl FilterMismatch {
+    /// Returns what a filter that rejected `value` produces under this
+    /// policy, given that the policy is not `Error`.
+    pub(crate) fn recover<'v>(
+        self,
+        filter: &dyn Filter,
+        value: Value<'v>,
+        ctx: &RenderContext<'_>,
+    ) -> Value<'v> {
+        if self == Self::Coerce
+            && let Some(coerced) = Self::coerce(&value, ctx.number_format())
+            && let Ok(result) = filter.apply_in(coerced, ctx)
+        {
+            return result;
+        }
+
+        value
+    }
+
+    /// Converts a value for `FilterMismatch::Coerce`, or returns `None` if
+    /// there is nothing to convert it to.
+    fn coerce<'v>(value: &Value<'v>, format: NumberFormat) -> Option<Value<'v>> {
+        match value {
+            Value::Str(s) => s
+                .parse()
+                .ok()
+                .filter(|parsed: &Value| !matches!(parsed, Value::Str(_))),
+            Value::Bytes(bytes) => Some(Value::owned_str(
+                String::from_utf8_lossy(bytes).into_owned(),
+            )),
+            _ => format.render(value).map(Value::Str),
+        }
+    }
+}
+
+/// The line breaks `FormatOptions::newline` writes the output with.
+///
+/// Both `\n` and `\r\n` count as line breaks, while a lone `\r` is left
+/// alone. Every line break of the output is converted, those in the
+/// template's text as well as those inside substituted values.
+///
+/// # Examples
+///
+/// ```text
+/// Template: "a\r\nb\n{note}"   Context: note = "c\r\nd"
+///
+/// NewlineStyle::AsIs -> "a\r\nb\nc\r\nd"
+/// NewlineStyle::Lf   -> "a\nb\nc\nd"
+/// NewlineStyle::CrLf -> "a\r\nb\r\nc\r\nd"
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum NewlineStyle {
+    /// Write line breaks as they are (the default).
+    #[default]
+    AsIs,
+    /// Write every line break as `\n`, as on Unix.
+    Lf,
+    /// Write every line break as `\r\n`, as on Windows.
+    CrLf,
+}
+
+/// Options passed to `Template::format_with_options`.
+///
+/// The default options render exactly like `Template::format`: missing
+/// variables are errors and the output is limited to
+/// `FormatOptions::DEFAULT_MAX_OUTPUT_LEN` bytes.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, FormatOptions, MissingPolicy, Template};
+///
+/// let tmpl = Template::<'{', '}'>::compile("Hello {name}!").unwrap();
+/// let options = FormatOptions {
+///     missing: MissingPolicy::Keep,
+///     ..Default::default()
+/// };
+///
+/// let output = tmpl.format_with_options(&Context::new(), &options).unwrap();
+/// assert_eq!(output, "Hello {name}!");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatOptions {
+    /// What to render in place of directives that reference missing variables
+    pub missing: MissingPolicy,
+    /// What to render in place of directives the parser didn't recognize
+    pub unknown: UnknownPolicy,
+    /// The maximum length of the rendered output, in bytes
+    ///
+    /// Rendering stops with `DirectiveError::OutputLimitExceeded` as soon as
+    /// the output would grow past this length. This bounds the work done for
+    /// untrusted templates or counts, such as `{'x':n}` with a huge `n`. Use
+    /// `usize::MAX` to disable the limit.
+    pub max_output_len: usize,
+    /// The maximum number of directives evaluated in one render
+    ///
+    /// Every directive counts, including those nested in the branches of
+    /// conditionals and switches and those of included partials, so this
+    /// bounds the work done for templates that expand many directives
+    /// without writing much. Rendering stops with
+    /// `DirectiveError::DirectiveLimitExceeded` before evaluating the
+    /// directive past the limit. `usize::MAX`, the default, disables it.
+    pub max_directives: usize,
+    /// How close two floats must be for `==` and `!=` to treat them as equal
+    pub float_tolerance: FloatTolerance,
+    /// The separators numbers are rendered with
+    pub number_format: NumberFormat,
+    /// Whether a directive that renders nothing on a line of its own removes
+    /// the whole line
+    ///
+    /// The line's leading whitespace and its line break are dropped along
+    /// with the directive, so that conditional sections of a config file
+    /// leave no blank lines behind. Lines holding anything but whitespace
+    /// and a single directive are unaffected, as are directives that render
+    /// text.
+    pub trim_blocks: bool,
+    /// The text rendered in place of a variable substituted by a plain
+    /// directive, such as `{name}`, that is `Null` or an empty string
+    ///
+    /// This keeps blank cells visible in reports and tables, for example with
+    /// `Some("N/A".into())`. Only the output of replace directives changes:
+    /// conditionals, switches and filters see the value as it is, literals
+    /// such as `{''}` are written unchanged and missing variables follow
+    /// `missing`. `None`, the default, renders such values as nothing.
+    pub empty_placeholder: Option<String>,
+    /// What filters do with values of a type they don't accept
+    pub filter_mismatch: FilterMismatch,
+    /// The filters variables render with when substituted without a spec of
+    /// their own
+    ///
+    /// `{price}` renders with the filters the schema sets for `price`, while
+    /// a directive with a spec, such as `{price:round}`, renders with only
+    /// its own. The default schema sets no filters.
+    pub schema: Schema,
+    /// Strings that conditions read as `true` or `false`, such as `"yes"`
+    /// and `"off"`, instead of coercing them by whether they are empty
+    ///
+    /// The default sets none, so every non-empty string is true.
+    pub bool_keywords: BoolKeywords,
+    /// The line breaks the output is written with
+    ///
+    /// Line breaks in literal text and in substituted values alike are
+    /// converted, and `max_output_len` counts the converted output.
+    /// `NewlineStyle::AsIs`, the default, leaves them unchanged.
+    pub newline: NewlineStyle,
+}
+
+impl FormatOptions {
+    /// The default output limit: 64 MiB.
+    pub const DEFAULT_MAX_OUTPUT_LEN: usize = 64 * 1024 * 1024;
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            missing: MissingPolicy::default(),
+            unknown: UnknownPolicy::default(),
+            max_output_len: Self::DEFAULT_MAX_OUTPUT_LEN,
+            max_directives: usize::MAX,
+            float_tolerance: FloatTolerance::default(),
+            number_format: NumberFormat::default(),
+            trim_blocks: false,
+            empty_placeholder: None,
+            filter_mismatch: FilterMismatch::default(),
+            schema: Schema::default(),
+            bool_keywords: BoolKeywords::default(),
+            newline: NewlineStyle::default(),
+        }
+    }
+}
+
+/// How `==` and `!=` compare floats.
+///
+/// Float arithmetic is inexact, so a value computed as `0.1 + 0.2` is not
+/// bit-for-bit equal to `0.3`. A tolerance lets `{ratio == 0.3 ? ...}` hold
+/// anyway. It applies when at least one side of the comparison is a float,
+/// whether from the context, a literal such as `0.3` or a computation such
+/// as `{x + 0.2 == 0.3}`, and the other side is a number; ordering operators
+/// such as `<` always compare exactly.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::FloatTolerance;
+///
+/// assert!(FloatTolerance::default().equal(0.1 + 0.2, 0.3));
+/// assert!(!FloatTolerance::Exact.equal(0.1 + 0.2, 0.3));
+/// assert!(FloatTolerance::Absolute(0.01).equal(1.0, 1.005));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatTolerance {
+    /// Floats are equal only if they are exactly equal.
+    Exact,
+    /// Floats are equal if they differ by at most the given amount.
+    Absolute(f64),
+    /// Floats are equal if they differ by at most the given fraction of the
+    /// larger magnitude. Only an exact zero equals zero under this tolerance.
+    Relative(f64),
+}
+
+impl FloatTolerance {
+    /// The default relative tolerance, the same as Python's `math.isclose`.
+    pub const DEFAULT_RELATIVE: f64 = 1e-9;
+
+    /// Returns whether `a` and `b` are equal under this tolerance.
+    ///
+    /// `NaN` is never equal to anything, and infinities are only equal to
+    /// themselves.
+    pub fn equal(&self, a: f64, b: f64) -> bool {
+        if a == b {
+            return true;
+        }
+
+        if !a.is_finite() || !b.is_finite() {
+            return false;
+        }
+
+        let diff = (a - b).abs();
+
+        match *self {
+            Self::Exact => false,
+            Self::Absolute(epsilon) => diff <= epsilon,
+            Self::Relative(epsilon) => diff <= epsilon * a.abs().max(b.abs()),
+        }
+    }
+}
+
+impl Default for FloatTolerance {
+    fn default() -> Self {
+        Self::Relative(Self::DEFAULT_RELATIVE)
+    }
+}
+
+/// The separators and precision used to render numbers.
+///
+/// Applies wherever a template turns a number into text: plain values such as
+/// `{price}`, sums joined with strings, filters like `{price:>10}` and the
+/// `currency` filter, which also groups digits. Plain numbers are never
+/// grouped. Numbers written inside a template, such as the `2.5` in
+/// `{x > 2.5 ? ...}`, are always parsed with a `.`.
+///
+/// Floats render in their shortest form that parses back to the same value,
+/// and always with a fractional part, so `8.50` renders `8.5` and `108.0`
+/// renders `108.0`, keeping it apart from the integer `108`. Very large and
+/// very small floats use exponents, as in `1e+21` and `1e-7`.
+/// `max_fraction_digits` rounds away the noise of arithmetic such as
+/// `0.1 + 0.2`.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{NumberFormat, Value};
+///
+/// let value = Value::Float(1234.5);
+///
+/// assert_eq!(NumberFormat::US.render(&value).unwrap(), "1234.5");
+/// assert_eq!(NumberFormat::EUROPEAN.render(&value).unwrap(), "1234,5");
+/// assert_eq!(NumberFormat::EUROPEAN.group("1234567"), "1.234.567");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NumberFormat {
+    /// The separator between the integer and the fractional part
+    pub decimal_sep: char,
+    /// The separator between groups of digits in the integer part
+    pub group_sep: char,
+    /// How many digits make a group, `0` for no grouping
+    pub group_size: usize,
+    /// The most digits a float renders after the decimal separator, `None`
+    /// for as many as its shortest form needs
+    ///
+    /// Floats with more digits are rounded and their trailing zeros dropped,
+    /// keeping at least one, so with `Some(2)` `0.1 + 0.2` renders `0.3` and
+    /// `107.999` renders `108.0`. Floats with a negative exponent are
+    /// rounded too, while those with a positive exponent have no decimals to
+    /// round. Only the default rendering is affected: filters with a
+    /// precision of their own, such as `currency`, ignore this.
+    pub max_fraction_digits: Option<usize>,
+}
+
+impl NumberFormat {
+    /// `1,234.5`, the default.
+    pub const US: Self = Self {
+        decimal_sep: '.',
+        group_sep: ',',
+        group_size: 3,
+        max_fraction_digits: None,
+    };
+
+    /// `1.234,5`, as used in most of continental Europe.
+    pub const EUROPEAN: Self = Self {
+        decimal_sep: ',',
+        group_sep: '.',
+        group_size: 3,
+        max_fraction_digits: None,
+    };
+
+    /// Converts a value to the text a template renders for it, with this
+    /// decimal separator and precision. Returns `None` for `Value::Bytes` and
+    /// `Value::Array`, which have no text form.
+    pub fn render<'v>(&self, value: &Value<'v>) -> Option<Cow<'v, str>> {
+        // Strings keep their borrow, the rest is rendered into new text
+        if let Value::Str(s) = value {
+            return Some(s.clone());
+        }
+
+        let text = Cow::<'static, str>::from_value(value)?;
+
+        Some(match value {
+            Value::Float(f) => self.localize(self.round(*f, text)),
+            _ => text,
+        })
+    }
+
+    /// Writes a value as `render` would, without allocating for anything
+    /// but floats with a decimal separator other than `.` or a precision.
+    pub(crate) fn write(&self, value: &Value, out: &mut dyn fmt::Write) -> fmt::Result {
+        match value {
+            Value::Float(_) if self.decimal_sep != '.' || self.max_fraction_digits.is_some() => {
+                out.write_str(&self.render(value).unwrap_or_default())
+            }
+            _ => write!(out, "{}", value),
+        }
+    }
+
+    /// Rounds the shortest form of a float, `text`, to `max_fraction_digits`
+    /// if it has more digits than that.
+    fn round(&self, value: f64, text: Cow<'static, str>) -> Cow<'static, str> {
+        let Some(max) = self.max_fraction_digits else {
+            return text;
+        };
+
+        let too_precise = match text.split_once('e') {
+            Some((_, exponent)) => exponent.starts_with('-'),
+            None => text
+                .split_once('.')
+                .is_some_and(|(_, fraction)| fraction.len() > max),
+        };
+
+        if !too_precise {
+            return text;
+        }
+
+        let mut rounded = format!("{:.*}", max, value);
+        if rounded.contains('.') {
+            rounded.truncate(rounded.trim_end_matches('0').len());
+        } else {
+            rounded.push('.');
+        }
+        if rounded.ends_with('.') {
+            rounded.push('0');
+        }
+
+        Cow::Owned(rounded)
+    }
+
+    /// Replaces the `.` of a number written by Rust with this decimal
+    /// separator.
+    pub(crate) fn localize(&self, number: Cow<'static, str>) -> Cow<'static, str> {
+        match self.decimal_sep {
+            '.' => number,
+            sep => Cow::Owned(number.replacen('.', sep.encode_utf8(&mut [0; 4]), 1)),
+        }
+    }
+
+    /// Inserts the group separator into a run of integer digits, e.g.
+    /// `1234567` into `1,234,567`.
+    pub fn group(&self, digits: &str) -> String {
+        let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && self.group_size > 0 && (digits.len() - i).is_multiple_of(self.group_size) {
+                out.push(self.group_sep);
+            }
+            out.push(digit);
+        }
+
+        out
+    }
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self::US
+    }
+}
+
+/// Strings that conditions read as `true` or `false`, for data that encodes
+/// booleans as text, such as `"yes"` and `"off"`.
+///
+/// Conditions coerce strings with `Value::is_truthy` by default, so any
+/// non-empty string is true, `"no"` included. A string matching one of
+/// these keywords, ignoring case, is read as the boolean it stands for
+/// instead; other strings keep their usual truthiness. This applies to the
+/// condition of a conditional and to the operands of `!`, `&&` and `||`,
+/// when the string comes from the context or an expression. Literals in the
+/// template, filters such as `bool(yes, no)` and switches are unaffected.
+///
+/// The default sets no keywords.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{BoolKeywords, Context, FormatOptions, Template, Value};
+///
+/// let options = FormatOptions {
+///     bool_keywords: BoolKeywords::new()
+///         .truthy(["yes", "on", "1"])
+///         .falsy(["no", "off", "0"]),
+///     ..Default::default()
+/// };
+///
+/// let tmpl = Template::<'{', '}'>::compile("{active ? 'enabled' : 'disabled'}").unwrap();
+///
+/// let mut ctx = Context::new();
+/// ctx.insert("active", Value::static_str("Off"));
+///
+/// assert_eq!(tmpl.format_with_options(&ctx, &options).unwrap(), "disabled");
+/// assert_eq!(tmpl.format(&ctx).unwrap(), "enabled");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BoolKeywords {
+    truthy: Vec<Cow<'static, str>>,
+    falsy: Vec<Cow<'static, str>>,
+}
+
+impl BoolKeywords {
+    /// Creates a set with no keywords.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds strings read as `true`.
+    pub fn truthy<S: Into<Cow<'static, str>>>(
+        mut self,
+        words: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.truthy.extend(words.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds strings read as `false`.
+    pub fn falsy<S: Into<Cow<'static, str>>>(mut self, words: impl IntoIterator<Item = S>) -> Self {
+        self.falsy.extend(words.into_iter().map(Into::into));
+        self
+    }
+
+    /// Returns the boolean `text` stands for, ignoring case, or `None` if it
+    /// is not a keyword. A string that is both truthy and falsy is `true`.
+    pub fn get(&self, text: &str) -> Option<bool> {
+        let matches =
+            |words: &[Cow<'static, str>]| words.iter().any(|word| eq_ignore_case(word, text));
+
+        if matches(&self.truthy) {
+            Some(true)
+        } else if matches(&self.falsy) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether no keywords are set.
+    pub fn is_empty(&self) -> bool {
+        self.truthy.is_empty() && self.falsy.is_empty()
+    }
+}
+
+/// Options passed to `Template::compile_with_options`.
+///
+/// The default options are used by `Template::compile`.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{CompileOptions, Template, TemplateError};
+///
+/// let options = CompileOptions {
+///     max_depth: 1,
+///     ..Default::default()
+/// };
+///
+/// assert!(Template::<'{', '}'>::compile_with_options("{name}", &options).is_ok());
+/// assert!(matches!(
+///     Template::<'{', '}'>::compile_with_options("{a {b}}", &options),
+///     Err(TemplateError::NestingTooDeep(1))
+/// ));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// The maximum number of nested delimiter levels
+    ///
+    /// A top-level directive such as `{name}` has depth 1. Templates nested
+    /// deeper fail to compile with `TemplateError::NestingTooDeep`, which
+    /// protects against pathological input from untrusted sources.
+    ///
+    /// A directive may also hold at most this many `?`, as the steps of an
+    /// else-if ladder such as `{a ? x : b ? y : z}` do, and its parentheses
+    /// may nest at most this deep.
+    /// Compiling, rendering and dropping templates recurse once per level,
+    /// so only raise the limit for trusted input.
+    pub max_depth: usize,
+    /// Whether literal text interprets `\n`, `\t`, `\r` and `\u{XXXX}`
+    ///
+    /// Off by default, since it changes the output of templates that contain
+    /// these sequences. Escapes are only interpreted in the text around
+    /// directives, and `\u{...}` fails with `TemplateError::InvalidEscape`
+    /// unless it holds 1 to 6 hex digits naming a Unicode scalar value. A
+    /// backslash before any other character is kept as-is, as are `\{`,
+    /// `\}` and `\\`, which escape delimiters either way.
+    pub text_escapes: bool,
+    /// Whether a switch with a case that can never be selected fails to
+    /// compile
+    ///
+    /// A case is unreachable when an earlier case of the same switch has the
+    /// same label, as in `{[x](a:1)(a:2)}`, or is a default, `*`. Off by
+    /// default, so such templates compile and `Template::unreachable_cases`
+    /// can report them as warnings instead. When on, compiling fails with
+    /// `TemplateError::UnreachableCase`.
+    pub deny_unreachable_cases: bool,
+    /// The characters that separate conditionals and switches
+    ///
+    /// See `Markers`. Invalid markers fail compiling with
+    /// `TemplateError::InvalidMarker`.
+    pub markers: Markers,
+    /// Whether a byte order mark, `U+FEFF`, at the start of the source is
+    /// left out of the template
+    ///
+    /// On by default, since editors on Windows often save UTF-8 files with
+    /// one and it would otherwise be rendered as the first character of the
+    /// output. Spans still point into the source as given, BOM included.
+    /// Line breaks need no option: `\r\n` ends a line wherever `\n` does,
+    /// as for `FormatOptions::trim_blocks`, and is rendered as written.
+    pub strip_bom: bool,
+    /// What empty directives, such as `{}` or `{   }`, compile to
+    ///
+    /// A directive holding nothing but whitespace is more often text that
+    /// happens to contain the delimiters, like the `{}` of a JSON snippet,
+    /// than a mistake, so it can be kept as text instead. By default
+    /// compiling fails with `TemplateError::EmptyDirective`.
+    pub empty_directives: EmptyPolicy,
+}
+
+impl CompileOptions {
+    /// The default nesting limit.
+    pub const DEFAULT_MAX_DEPTH: usize = 64;
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            text_escapes: false,
+            deny_unreachable_cases: false,
+            markers: Markers::default(),
+            strip_bom: true,
+            empty_directives: EmptyPolicy::default(),
+        }
+    }
+}
+
+/// The characters of the separators in conditionals and switches, for
+/// `CompileOptions::markers`.
+///
+/// Templates whose directives hold these characters, such as a `:` in a
+/// case label, can pick others instead. A marker takes over its character
+/// everywhere within directives, so with `colon: ';'` filters follow a `;`
+/// too, as in `{name;upper}`, and a character that is not a marker anymore
+/// has no meaning of its own. The markers must be distinct ASCII
+/// punctuation, other than `_`, a quote, a backtick or a backslash.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{CompileOptions, Context, Markers, Template};
+///
+/// let options = CompileOptions {
+///     markers: Markers { question: '#', colon: '|', ..Default::default() },
+///     ..Default::default()
+/// };
+///
+/// let template =
+///     Template::<'{', '}'>::compile_with_options("{ok # 'a?' | 'b:'}", &options).unwrap();
+/// let mut ctx = Context::new();
+/// ctx.insert("ok", true.into());
+/// assert_eq!(template.format(&ctx).unwrap(), "a?");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Markers {
+    /// Follows the condition of a conditional, `?` by default
+    pub question: char,
+    /// Separates the branches of a conditional and a case label from its
+    /// body, `:` by default
+    pub colon: char,
+    /// Opens the scrutinee of a switch, `[` by default
+    pub switch_open: char,
+    /// Closes the scrutinee of a switch, `]` by default
+    pub switch_close: char,
+    /// Opens a switch case, `(` by default
+    pub case_open: char,
+    /// Closes a switch case, `)` by default
+    pub case_close: char,
+}
+
+impl Markers {
+    /// The markers in the order `TemplateLexer` matches them.
+    pub(crate) fn chars(&self) -> [char; 6] {
+        [
+            self.question,
+            self.colon,
+            self.switch_open,
+            self.switch_close,
+            self.case_open,
+            self.case_close,
+        ]
+    }
+
+    /// Returns the error for the first marker that can't be used, if any.
+    pub(crate) fn check(&self) -> Result<(), TemplateError> {
+        let chars = self.chars();
+
+        for (i, &marker) in chars.iter().enumerate() {
+            let reason = match marker {
+                '\\' | '\'' | '"' | '`' => "it quotes or escapes text",
+                '_' => "it collides with identifiers",
+                c if !c.is_ascii_punctuation() => "it is not ASCII punctuation",
+                c if chars[..i].contains(&c) => "it is already another marker",
+                _ => continue,
+            };
+
+            return Err(TemplateError::InvalidMarker { marker, reason });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Markers {
+    fn default() -> Self {
+        Self {
+            question: '?',
+            colon: ':',
+            switch_open: '[',
+            switch_close: ']',
+            case_open: '(',
+            case_close: ')',
+        }
+    }
+}
+
+/// Delimiter strings chosen at runtime, for `Template::with_delimiters`.
+///
+/// Unlike the `O` and `C` parameters of `Template`, runtime delimiters may be
+/// several characters long, e.g. `${` and `}` or `<%` and `%>`. Doubling the
+/// opening delimiter (`${${`) or the closing one (`}}`) escapes it, as does a
+/// backslash before it.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Delimiters, TemplateError};
+///
+/// assert!(Delimiters::new("<%", "%>").is_ok());
+/// assert!(matches!(
+///     Delimiters::new("", "}"),
+///     Err(TemplateError::InvalidDelimiter { .. })
+/// ));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Delimiters {
+    open: Box<str>,
+    close: Box<str>,
+}
+
+impl Delimiters {
+    /// Creates a pair of delimiters.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TemplateError::InvalidDelimiter` if either delimiter is empty
+    /// or contains a character rejected by `Template::validate_delimiters`.
+    pub fn new(open: impl Into<String>, close: impl Into<String>) -> Result<Self, TemplateError> {
+        let (open, close) = (open.into(), close.into());
+
+        for delimiter in [&open, &close] {
+            let problem = if delimiter.is_empty() {
+                Some("it is empty")
+            } else {
+                delimiter.chars().find_map(crate::delimiter_problem)
+            };
+
+            if let Some(reason) = problem {
+                return Err(TemplateError::InvalidDelimiter {
+                    delimiter: delimiter.clone(),
+                    reason,
+                });
+            }
+        }
+
+        Ok(Self {
+            open: open.into(),
+            close: close.into(),
+        })
+    }
+
+    /// Returns the opening delimiter.
+    pub fn open(&self) -> &str {
+        &self.open
+    }
+
+    /// Returns the closing delimiter.
+    pub fn close(&self) -> &str {
+        &self.close
+    }
+}
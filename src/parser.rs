@@ -4,14 +4,31 @@
 //! expressions into executable directives. The parser uses pattern matching
 //! on token sequences to recognize different template syntaxes.
 
+#[cfg(feature = "base64")]
+use crate::filter::{Base64Alphabet, Base64Filter};
+#[cfg(feature = "digest")]
+use crate::filter::{DigestAlgorithm, DigestFilter};
 use crate::{
-    arg::{Argument, ComparisonOp},
+    arg::{Argument, ComparisonOp, Function, TypeTest},
+    context::{LOOP_COUNT, LOOP_FIRST, LOOP_INDEX, LOOP_ITEM, LOOP_LAST, MATCHED_VALUE},
     directive::{
-        ConditionalDirective, Directive, EmptyDirective, RepeatDirective, ReplaceDirective,
+        CaseLabel, ColumnDirective, ConditionalDirective, Directive, EmptyDirective,
+        ErrorMessageDirective, FallbackDirective, FilterDirective, IncludeDirective, LoopDirective,
+        RegionDirective, RegionEndDirective, RepeatDirective, ReplaceDirective, SwitchCase,
+        SwitchDirective, UnknownDirective,
     },
-    lexer::Token,
+    filter::{
+        AlignFilter, Alignment, BoolFilter, Currency, CurrencyFilter, Filter, IndentFilter,
+        LengthFilter, MarkdownEscapeFilter, NegativeStyle, NumberFilter, PercentFilter, Radix,
+        RadixFilter, RepeatFilter, ReplaceFilter, ReverseFilter, RoundFilter, RoundingMode,
+        ShellEscapeFilter, SliceFilter, SquishFilter, StripFilter, StripSide, SymbolPosition,
+        TruncateFilter, UrlEncodeFilter, UrlEncoding,
+    },
+    fragment::Fragment,
+    lexer::{TemplateLexer, Token},
 };
 use std::borrow::Cow;
+use std::sync::Arc;
 
 /// A parser that converts token sequences into executable directives.
 ///
@@ -19,28 +36,64 @@ use std::borrow::Cow;
 /// patterns and building the corresponding directive objects. Different parser
 /// implementations can support different template syntaxes.
 ///
-/// Note: This parser never return `None`, it just returns an empty directive which does nothring.
+/// A parser is only used while compiling, through
+/// `Template::compile_with_parser` and its variants; the compiled template
+/// holds the directives it returned and renders the same whichever parser
+/// built them.
+///
+/// # Contract
+///
+/// Compiling finds each directive in the source and calls `parse` with the
+/// tokens of its contents, lexed by `TemplateLexer`, without the delimiters.
+/// Text outside directives and escaped delimiters never reach the parser.
+///
+/// - Returning `Some(directive)` places the directive in the template. The
+///   default parser returns `UnknownDirective` for tokens it doesn't
+///   recognize, which renders according to `FormatOptions::unknown`; its
+///   source text is filled in by the compiler.
+/// - Returning `None` fails compiling with `TemplateError::DirectiveParsing`,
+///   holding the directive's contents.
+/// - After parsing, every `Argument::Literal` returned by
+///   `Directive::nested_arguments_mut` that contains the opening delimiter
+///   is compiled as a sub-template with the same parser, as conditional
+///   branches are.
+///
+/// `DefaultParser` is a `Parser` like any other, so a custom parser can
+/// handle its own patterns and delegate the rest to `DefaultParser::parse`
+/// to extend the default syntax, or return `None` for anything outside a
+/// restricted subset.
 ///
 /// # Examples
 ///
-/// The default parser recognizes patterns like:
-/// - `{name}` → Variable replacement
-/// - `{pattern:count}` → Repeat directive
-/// - `{cond ? true : false}` → Conditional directive
+/// ```rust
+/// use figura::{DefaultParser, Directive, LiteralDirective, Parser, Template, Token};
+/// use std::borrow::Cow;
 ///
-/// Custom parsers can be implemented to support alternative syntaxes or
-/// additional features.
+/// /// The default syntax, plus `{@}` for an at sign.
+/// struct AtParser;
+///
+/// impl Parser for AtParser {
+///     fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+///         match tokens {
+///             [Token::Unknown('@')] => Some(Box::new(LiteralDirective(Cow::Borrowed("@")))),
+///             _ => DefaultParser::parse(tokens),
+///         }
+///     }
+/// }
+///
+/// let tmpl = Template::<'{', '}'>::compile_with_parser::<AtParser>("ada{@}example.com").unwrap();
+/// ```
 pub trait Parser {
-    /// Parses a token sequence into a directive.
+    /// Parses the tokens of a directive's contents into a directive.
     ///
     /// # Arguments
     ///
-    /// * `tokens` - A slice of tokens to parse
+    /// * `tokens` - The tokens between the directive's delimiters
     ///
     /// # Returns
     ///
     /// * `Some(Box<dyn Directive>)` - Successfully parsed directive
-    /// * `None` - Parse failed (invalid syntax)
+    /// * `None` - Parse failed (invalid syntax), which fails compiling
     ///
     /// # Examples
     ///
@@ -58,13 +111,55 @@ pub trait Parser {
 ///
 /// Supports the standard Figura template syntax including:
 /// - **Variable substitution**: `{name}` - Replaces with context value
-/// - **Literal values**: `{"text"}` or `{42}` - Uses literal values
+/// - **Quoted names**: `` {`content-type`} `` - Names any key, lexed as an identifier
+/// - **Literal values**: `{"text"}` - Uses literal values
+/// - **Positional arguments**: `{0}`, `{1:>5}` - Names the variable `"0"`, for `Template::format_args`
 /// - **Repeat patterns**: `{pattern:count}` - Repeats pattern N times
+/// - **Alignment**: `{name:<10}`, `{name:>10}`, `{name:*^10}` - Pads to a fixed width
+/// - **Padding**: `{id:pad_start(6, 0)}`, `{name:pad_end(20)}` - Pads a value to a length, as named alignment filters
+/// - **Truncation**: `{bio:trunc(80)}`, `{bio:trunc(80, '...')}` - Shortens long values
+/// - **Slicing**: `{code:slice(0, 4)}`, `{code:slice(-2)}` - Extracts a range of chars
+/// - **Length**: `{name:len}` - Renders the number of chars in a string
+/// - **Reversing**: `{name:reverse}` - Reverses a string by grapheme clusters
+/// - **Repeating**: `{sep:repeat(3)}` - Repeats a value, as a chainable filter
+/// - **Squishing**: `{comment:squish}` - Trims a value and collapses its whitespace runs to single spaces
+/// - **Replacing**: `{path:replace(\\, /)}` - Replaces every occurrence of a substring, with unquoted arguments
+/// - **Stripping**: `{path:rstrip(/)}`, `{code:lstrip(0)}`, `{s:strip(*)}` - Strips a set of chars from the ends of a value
+/// - **Indenting**: `{block:indent(4)}`, `{block:indent(4, first)}`, `{block:indent}` - Indents the lines of a multi-line value, by default to the directive's column
+/// - **URL encoding**: `{q:urlenc}`, `{q:urlenc(form)}` - Percent-encodes a value
+/// - **Markdown and shell escaping**: `{text:md}`, `{arg:sh}` - Escapes Markdown syntax or quotes a shell word
+/// - **Base64**: `{data:base64}`, `{data:base64(url)}` - Encodes a value, with the `base64` feature
+/// - **Digests**: `{content:sha256}`, `{path:crc32}`, `{content:sha256(8)}` - Hashes a value, with the `digest` feature
+/// - **Number bases**: `{mask:hex}`, `{mask:#HEX}`, `{perm:oct}`, `{bits:bin}` - Renders integers in another base
+/// - **Signs and zero-padding**: `{delta:+}`, `{id:05}`, `{delta:+05}` - Formats numbers
+/// - **Rounding**: `{x:round}`, `{x:floor}`, `{x:ceil}`, `{x:round(2)}` - Rounds floats, halfway cases away from zero
+/// - **Booleans**: `{active:bool(Yes, No)}` - Renders one of two texts
+/// - **Currency**: `{total:currency(USD)}`, `{total:currency(EUR, prefix, parens)}` - Renders money amounts
+/// - **Percentages**: `{ratio:percent}`, `{ratio:percent(1)}`, `{share:percent(scaled)}` - Renders ratios as percentages
+/// - **Filter chains**: `{name | trunc(20) | >24}` - Applies filters from left to right
 /// - **Simple conditionals**: `{condition ? true_value : false_value}`
 /// - **Comparison conditionals**: `{x == 5 ? "yes" : "no"}`
+/// - **Range conditionals**: `{age in 13..19 ? "teen" : "other"}` - Both bounds included
+/// - **List conditionals**: `{role in ['admin', 'editor'] ? "staff"}` - Items compared like `==`
+/// - **Else-if ladders**: `{x > 9 ? "big" : x > 0 ? "small" : "none"}` - Right-associative chains
 /// - **Logical NOT**: `{!active ? "inactive" : "active"}`
+/// - **Presence tests**: `{defined(coupon) ? "saved" : "full price"}` - Holds for any non-null value
+/// - **Type tests**: `{is_number(x) ? "n" : "s"}` - Tests the type of a value, also `is_string`, `is_int`, `is_float`, `is_bool`, `is_bytes`, `is_array` and `is_null`
+/// - **Coalescing**: `{nick ?? name ?? "anonymous"}` - Takes the first value that is present and not null
+/// - **Regions**: `{@region:header}..{@end}` - Marks a part of the template to render on its own
+/// - **Error messages**: `{price !! "price is required"}` - Replaces the error of any directive
+/// - **Regex matches**: `{email =~ '^.+@.+$' ? "valid" : "invalid"}`, with the `regex` feature
+/// - **Switches**: `{[tier](gold:Welcome {name})(*:Hello)}` - Renders the first matching case
+/// - **Bucketing switches**: `{[score](>=90:A)(>=80:B)(*:F)}` - Renders the first case whose comparison holds
+/// - **Switch defaults**: `{[status|unknown](active:On)(unknown:?)}` - Matches a label when the value is missing
+/// - **Matched values**: `{[status](active:You are {@value})}` - Renders the value a switch matched, in its case bodies
+/// - **Functions**: `{max(a, b)}`, `{min(score, 100)}`, `{abs(delta)}`, `{len(items)}` - Computes numbers, also in conditions
+/// - **Includes**: `{>header}` - Renders a partial from a `TemplateRegistry`
+/// - **Columns**: `{@col(30)}` - Pads the current output line with spaces up to a column
+/// - **Addition and concatenation**: `{first + ' ' + last}`, `{count + 1}` - Joins strings or adds numbers
 ///
-/// Supported comparison operators: `==`, `!=`, `>`, `<`, `>=`, `<=`
+/// Supported comparison operators: `==`, `!=`, `~=`, `>`, `<`, `>=`, `<=`,
+/// `*=`, `^=`, `$=`
 ///
 /// # Examples
 ///
@@ -72,13 +167,13 @@ pub trait Parser {
 /// use figura::{Template, DefaultParser};
 ///
 /// // Variable substitution
-/// let t1 = Template::<'{', '}'>::compile::<DefaultParser>("{name}").unwrap();
+/// let t1 = Template::<'{', '}'>::compile_with_parser::<DefaultParser>("{name}").unwrap();
 ///
 /// // Repeat pattern
-/// let t2 = Template::<'{', '}'>::compile::<DefaultParser>("{'*':3}").unwrap();
+/// let t2 = Template::<'{', '}'>::compile_with_parser::<DefaultParser>("{'*':3}").unwrap();
 ///
 /// // Conditional with comparison
-/// let t3 = Template::<'{', '}'>::compile::<DefaultParser>(
+/// let t3 = Template::<'{', '}'>::compile_with_parser::<DefaultParser>(
 ///     "{age >= 18 ? 'adult' : 'minor'}"
 /// ).unwrap();
 /// ```
@@ -106,6 +201,1183 @@ fn token_to_argument(token: &Token) -> Argument {
     }
 }
 
+/// Splits a leading scoped variable off the tokens: `@value` within a switch
+/// case body, or `.`, `@index`, `@count`, `@first` and `@last` within a loop
+/// body.
+fn scoped_variable<'t, 'a>(tokens: &'t [Token<'a>]) -> Option<(Argument, &'t [Token<'a>])> {
+    let (name, rest) = match tokens {
+        [Token::Unknown('.'), rest @ ..] => (LOOP_ITEM, rest),
+        [Token::Unknown('@'), Token::Ident(name), rest @ ..] => {
+            let name = match *name {
+                "value" => MATCHED_VALUE,
+                "index" => LOOP_INDEX,
+                "count" => LOOP_COUNT,
+                "first" => LOOP_FIRST,
+                "last" => LOOP_LAST,
+                _ => return None,
+            };
+
+            (name, rest)
+        }
+        _ => return None,
+    };
+
+    Some((Argument::variable(Cow::Borrowed(name)), rest))
+}
+
+/// Converts a token into the alignment it denotes, if any.
+fn token_to_alignment(token: &Token) -> Option<Alignment> {
+    match token {
+        Token::LessThan => Some(Alignment::Left),
+        Token::GreaterThan => Some(Alignment::Right),
+        Token::Caret => Some(Alignment::Center),
+        _ => None,
+    }
+}
+
+/// Converts a token into a single fill character, if it spans exactly one char.
+fn token_to_fill(token: &Token) -> Option<char> {
+    let single = |s: &str| {
+        let mut chars = s.chars();
+        chars.next().filter(|_| chars.next().is_none())
+    };
+
+    match token {
+        Token::Ident(s) | Token::Int(s) => single(s),
+        Token::Literal(s) => single(s),
+        Token::Unknown(c) => Some(*c),
+        Token::Assign => Some('='),
+        Token::Colon => Some(':'),
+        Token::Comma => Some(','),
+        Token::Semicolon => Some(';'),
+        Token::Question => Some('?'),
+        Token::Pipe => Some('|'),
+        Token::Underscore => Some('_'),
+        Token::Caret => Some('^'),
+        Token::Not => Some('!'),
+        Token::Plus => Some('+'),
+        Token::Minus => Some('-'),
+        Token::Star => Some('*'),
+        Token::Slash => Some('/'),
+        Token::LessThan => Some('<'),
+        Token::GreaterThan => Some('>'),
+        Token::LParen => Some('('),
+        Token::RParen => Some(')'),
+        Token::LSquare => Some('['),
+        Token::RSquare => Some(']'),
+        Token::LCurly => Some('{'),
+        Token::RCurly => Some('}'),
+        _ => None,
+    }
+}
+
+/// Parses an alignment spec such as `<10`, `>10` or `*^10`.
+///
+/// The spec is an optional fill character, an alignment flag and a width.
+fn parse_align_spec(spec: &[Token]) -> Option<AlignFilter> {
+    let (fill, align, width) = match spec {
+        [align, Token::Int(width)] => (' ', align, width),
+        [fill, align, Token::Int(width)] => (token_to_fill(fill)?, align, width),
+        _ => return None,
+    };
+
+    Some(AlignFilter {
+        align: token_to_alignment(align)?,
+        width: width.parse().ok()?,
+        fill,
+    })
+}
+
+/// Converts a filter argument token into the text it stands for.
+///
+/// Quoted literals are taken as-is; bare words and numbers stand for
+/// themselves, so `bool(Yes, No)` needs no quotes.
+fn token_to_text(token: &Token) -> Option<Cow<'static, str>> {
+    match token {
+        Token::Literal(s) => Some(Cow::Owned(s.to_string())),
+        Token::Ident(s) | Token::Int(s) | Token::Float(s) => Some(Cow::Owned(s.to_string())),
+        _ => None,
+    }
+}
+
+/// Converts the name of a number base filter into its radix and case.
+fn name_to_radix(name: &str) -> Option<(Radix, bool)> {
+    match name {
+        "bin" => Some((Radix::Binary, false)),
+        "oct" => Some((Radix::Octal, false)),
+        "hex" => Some((Radix::Hexadecimal, false)),
+        "HEX" => Some((Radix::Hexadecimal, true)),
+        _ => None,
+    }
+}
+
+/// Returns whether a single-token spec names a filter rather than a repeat
+/// count, e.g. the `hex` in `{mask:hex}` or the `len` in `{name:len}`.
+///
+/// Counts with a leading zero, such as the `05` in `{id:05}`, are zero-padding
+/// widths rather than counts.
+fn is_filter_name(token: &Token) -> bool {
+    match token {
+        Token::Ident(name) => {
+            matches!(
+                *name,
+                "len"
+                    | "reverse"
+                    | "squish"
+                    | "indent"
+                    | "urlenc"
+                    | "md"
+                    | "sh"
+                    | "round"
+                    | "floor"
+                    | "ceil"
+                    | "base64"
+                    | "sha256"
+                    | "crc32"
+                    | "percent"
+            ) || name_to_radix(name).is_some()
+        }
+        Token::Int(n) => n.len() > 1 && n.starts_with('0'),
+        _ => false,
+    }
+}
+
+/// Parses a zero-padding width such as `05`, which must have a leading zero.
+fn parse_zero_pad(width: &str) -> Option<usize> {
+    width
+        .strip_prefix('0')
+        .filter(|digits| !digits.is_empty())?
+        .parse()
+        .ok()
+}
+
+/// Parses a possibly negative integer index, e.g. the `-3` in `slice(-3)`.
+fn parse_index(tokens: &[Token]) -> Option<i64> {
+    match tokens {
+        [Token::Int(n)] => n.parse().ok(),
+        [Token::Minus, Token::Int(n)] => n.parse::<i64>().ok().map(|n| -n),
+        _ => None,
+    }
+}
+
+/// Parses the spec following the `:` of a filtered value.
+///
+/// Named filters such as `trunc(80)` are tried first, then alignment specs.
+fn parse_filter(spec: &[Token]) -> Option<Box<dyn Filter>> {
+    match spec {
+        [
+            Token::Ident("trunc"),
+            Token::LParen,
+            Token::Int(width),
+            Token::RParen,
+        ] => Some(Box::new(TruncateFilter {
+            width: width.parse().ok()?,
+            ellipsis: Cow::Borrowed(TruncateFilter::DEFAULT_ELLIPSIS),
+        })),
+
+        [
+            Token::Ident("trunc"),
+            Token::LParen,
+            Token::Int(width),
+            Token::Comma,
+            Token::Literal(ellipsis),
+            Token::RParen,
+        ] => Some(Box::new(TruncateFilter {
+            width: width.parse().ok()?,
+            ellipsis: Cow::Owned(ellipsis.to_string()),
+        })),
+
+        [
+            Token::Ident("slice"),
+            Token::LParen,
+            args @ ..,
+            Token::RParen,
+        ] => {
+            let mut args = args.split(|token| matches!(token, Token::Comma));
+            let start = parse_index(args.next()?)?;
+            let end = match args.next() {
+                Some(end) => Some(parse_index(end)?),
+                None => None,
+            };
+
+            if args.next().is_some() {
+                return None;
+            }
+
+            Some(Box::new(SliceFilter { start, end }))
+        }
+
+        [
+            Token::Ident("bool"),
+            Token::LParen,
+            if_true,
+            Token::Comma,
+            if_false,
+            Token::RParen,
+        ] => Some(Box::new(BoolFilter {
+            if_true: token_to_text(if_true)?,
+            if_false: token_to_text(if_false)?,
+        })),
+
+        [
+            Token::Ident("currency"),
+            Token::LParen,
+            Token::Ident(code),
+            options @ ..,
+            Token::RParen,
+        ] => {
+            let mut filter = CurrencyFilter::new(Currency::from_code(code)?);
+            let (mut position, mut negative) = (None, None);
+
+            for option in options.chunks(2) {
+                match option {
+                    [Token::Comma, Token::Ident("prefix")] if position.is_none() => {
+                        position = Some(SymbolPosition::Prefix);
+                    }
+                    [Token::Comma, Token::Ident("suffix")] if position.is_none() => {
+                        position = Some(SymbolPosition::Suffix);
+                    }
+                    [Token::Comma, Token::Ident("parens")] if negative.is_none() => {
+                        negative = Some(NegativeStyle::Parentheses);
+                    }
+                    _ => return None,
+                }
+            }
+
+            filter.position = position.unwrap_or(filter.position);
+            filter.negative = negative.unwrap_or(filter.negative);
+
+            Some(Box::new(filter))
+        }
+
+        [Token::Ident("percent"), args @ ..] => {
+            let mut filter = PercentFilter::default();
+
+            match args {
+                [] => {}
+                [Token::LParen, Token::Ident("scaled"), Token::RParen] => filter.scaled = true,
+                [Token::LParen, Token::Int(places), Token::RParen] => {
+                    filter.places = places.parse().ok()?;
+                }
+                [
+                    Token::LParen,
+                    Token::Int(places),
+                    Token::Comma,
+                    Token::Ident("scaled"),
+                    Token::RParen,
+                ] => {
+                    filter.places = places.parse().ok()?;
+                    filter.scaled = true;
+                }
+                _ => return None,
+            }
+
+            Some(Box::new(filter))
+        }
+
+        [Token::Ident("len")] => Some(Box::new(LengthFilter)),
+
+        [Token::Ident("reverse")] => Some(Box::new(ReverseFilter)),
+
+        [Token::Ident("squish")] => Some(Box::new(SquishFilter)),
+
+        [
+            Token::Ident("replace"),
+            Token::LParen,
+            Token::Literal(from),
+            Token::Comma,
+            Token::Literal(to),
+            Token::RParen,
+        ] if !from.is_empty() => Some(Box::new(ReplaceFilter {
+            from: Cow::Owned(from.to_string()),
+            to: Cow::Owned(to.to_string()),
+        })),
+
+        [
+            Token::Ident(name @ ("strip" | "lstrip" | "rstrip")),
+            Token::LParen,
+            Token::Literal(chars),
+            Token::RParen,
+        ] if !chars.is_empty() => Some(Box::new(StripFilter {
+            side: match *name {
+                "lstrip" => StripSide::Start,
+                "rstrip" => StripSide::End,
+                _ => StripSide::Both,
+            },
+            chars: Cow::Owned(chars.to_string()),
+        })),
+
+        [Token::Ident("indent"), args @ ..] => {
+            let (width, first) = match args {
+                [] => (None, false),
+                [Token::LParen, Token::Int(width), Token::RParen] => (Some(width), false),
+                [
+                    Token::LParen,
+                    Token::Int(width),
+                    Token::Comma,
+                    Token::Ident("first"),
+                    Token::RParen,
+                ] => (Some(width), true),
+                _ => return None,
+            };
+
+            Some(Box::new(IndentFilter {
+                width: width.map(|width| width.parse()).transpose().ok()?,
+                first,
+            }))
+        }
+
+        [
+            Token::Ident("repeat"),
+            Token::LParen,
+            Token::Int(count),
+            Token::RParen,
+        ] => Some(Box::new(RepeatFilter {
+            count: count.parse().ok()?,
+        })),
+
+        [
+            Token::Ident(name @ ("pad_start" | "pad_end")),
+            Token::LParen,
+            Token::Int(width),
+            fill @ ..,
+            Token::RParen,
+        ] => Some(Box::new(AlignFilter {
+            align: match *name {
+                "pad_start" => Alignment::Right,
+                _ => Alignment::Left,
+            },
+            width: width.parse().ok()?,
+            fill: match fill {
+                [] => ' ',
+                [Token::Comma, fill] => token_to_fill(fill)?,
+                _ => return None,
+            },
+        })),
+
+        [Token::Ident("urlenc")] => Some(Box::new(UrlEncodeFilter {
+            encoding: UrlEncoding::Rfc3986,
+        })),
+
+        [
+            Token::Ident("urlenc"),
+            Token::LParen,
+            Token::Ident("form"),
+            Token::RParen,
+        ] => Some(Box::new(UrlEncodeFilter {
+            encoding: UrlEncoding::Form,
+        })),
+
+        [Token::Ident("md")] => Some(Box::new(MarkdownEscapeFilter)),
+
+        [Token::Ident("sh")] => Some(Box::new(ShellEscapeFilter)),
+
+        [
+            Token::Ident(name @ ("round" | "floor" | "ceil")),
+            places @ ..,
+        ] => {
+            let places = match places {
+                [] => 0,
+                [Token::LParen, Token::Int(n), Token::RParen] => n.parse().ok()?,
+                _ => return None,
+            };
+
+            Some(Box::new(RoundFilter {
+                mode: match *name {
+                    "round" => RoundingMode::Round,
+                    "floor" => RoundingMode::Floor,
+                    _ => RoundingMode::Ceil,
+                },
+                places,
+            }))
+        }
+
+        #[cfg(feature = "base64")]
+        [Token::Ident("base64")] => Some(Box::new(Base64Filter {
+            alphabet: Base64Alphabet::Standard,
+        })),
+
+        #[cfg(feature = "base64")]
+        [
+            Token::Ident("base64"),
+            Token::LParen,
+            Token::Ident("url"),
+            Token::RParen,
+        ] => Some(Box::new(Base64Filter {
+            alphabet: Base64Alphabet::UrlSafe,
+        })),
+
+        #[cfg(feature = "digest")]
+        [Token::Ident(name @ ("sha256" | "crc32")), length @ ..] => {
+            let length = match length {
+                [] => None,
+                [Token::LParen, Token::Int(n), Token::RParen] | [Token::Colon, Token::Int(n)] => {
+                    Some(n.parse().ok()?)
+                }
+                _ => return None,
+            };
+
+            Some(Box::new(DigestFilter {
+                algorithm: match *name {
+                    "sha256" => DigestAlgorithm::Sha256,
+                    _ => DigestAlgorithm::Crc32,
+                },
+                length,
+            }))
+        }
+
+        [Token::Plus] => Some(Box::new(NumberFilter {
+            plus: true,
+            zero_pad: 0,
+        })),
+
+        [Token::Int(width)] => Some(Box::new(NumberFilter {
+            plus: false,
+            zero_pad: parse_zero_pad(width)?,
+        })),
+
+        [Token::Plus, Token::Int(width)] => Some(Box::new(NumberFilter {
+            plus: true,
+            zero_pad: parse_zero_pad(width)?,
+        })),
+
+        [Token::Ident(name)] | [Token::Unknown('#'), Token::Ident(name)] => {
+            let (radix, uppercase) = name_to_radix(name)?;
+
+            Some(Box::new(RadixFilter {
+                radix,
+                uppercase,
+                prefix: spec.len() == 2,
+            }))
+        }
+
+        _ => parse_align_spec(spec).map(|filter| Box::new(filter) as Box<dyn Filter>),
+    }
+}
+
+/// Parses a chain of filters from the text after the `:` of a directive,
+/// such as `"trunc(20) | >24"`, as `Schema::field` takes it.
+pub(crate) fn parse_filter_chain(spec: &str) -> Option<Vec<Box<dyn Filter>>> {
+    // Lexed after a separator, so that filters taking text arguments such as
+    // `replace` read them as text
+    let source = format!(":{}", spec);
+    let tokens: Vec<Token> = TemplateLexer::new(&source).collect();
+
+    match tokens.as_slice() {
+        [Token::Colon, specs @ ..] if !specs.is_empty() => parse_filters(specs),
+        _ => None,
+    }
+}
+
+/// Parses a chain of filter specs separated by pipes, e.g. the
+/// `trunc(20) | >24` in `{name | trunc(20) | >24}`.
+///
+/// A pipe at the start of a spec is a fill character rather than a separator,
+/// so `{name:|^10}` still pads with pipes. Returns `None` if any spec is
+/// invalid.
+fn parse_filters(specs: &[Token]) -> Option<Vec<Box<dyn Filter>>> {
+    let mut filters = Vec::new();
+    let mut start = 0;
+
+    for (i, token) in specs.iter().enumerate() {
+        if *token == Token::Pipe && i > start {
+            filters.push(parse_filter(&specs[start..i])?);
+            start = i + 1;
+        }
+    }
+
+    filters.push(parse_filter(&specs[start..])?);
+
+    Some(filters)
+}
+
+/// Converts a comparison operator token into its `ComparisonOp`.
+fn token_to_comparison_op(token: &Token) -> Option<ComparisonOp> {
+    match token {
+        Token::Equals => Some(ComparisonOp::Equals),
+        Token::NotEquals => Some(ComparisonOp::NotEquals),
+        Token::EqualsIgnoreCase => Some(ComparisonOp::EqualsIgnoreCase),
+        Token::GreaterThan => Some(ComparisonOp::GreaterThan),
+        Token::LessThan => Some(ComparisonOp::LessThan),
+        Token::GreaterThanEquals => Some(ComparisonOp::GreaterThanEquals),
+        Token::LessThanEquals => Some(ComparisonOp::LessThanEquals),
+        Token::Contains => Some(ComparisonOp::Contains),
+        Token::StartsWith => Some(ComparisonOp::StartsWith),
+        Token::EndsWith => Some(ComparisonOp::EndsWith),
+        _ => None,
+    }
+}
+
+/// Returns the position of the first token outside parentheses, brackets
+/// and braces matching `pred`, or `None` if there is none or they are
+/// unbalanced.
+fn find_top_level(tokens: &[Token], pred: impl Fn(&Token) -> bool) -> Option<usize> {
+    let mut depth = 0usize;
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LParen | Token::LCurly | Token::LSquare => depth += 1,
+            Token::RParen | Token::RCurly | Token::RSquare => depth = depth.checked_sub(1)?,
+            _ if depth == 0 && pred(token) => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Returns the position of the last token outside parentheses, brackets and
+/// braces matching `pred`, like `find_top_level`.
+fn rfind_top_level(tokens: &[Token], pred: impl Fn(&Token) -> bool) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut found = None;
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LParen | Token::LCurly | Token::LSquare => depth += 1,
+            Token::RParen | Token::RCurly | Token::RSquare => depth = depth.checked_sub(1)?,
+            _ if depth == 0 && pred(token) => found = Some(i),
+            _ => {}
+        }
+    }
+
+    found
+}
+
+/// Splits `tokens` at every separator outside parentheses, brackets and
+/// braces, like `find_top_level`. Unbalanced tokens are a single part.
+fn split_top_level<'t, 'a>(tokens: &'t [Token<'a>], separator: &Token) -> Vec<&'t [Token<'a>]> {
+    let mut parts = Vec::new();
+    let mut rest = tokens;
+
+    while let Some(end) = find_top_level(rest, |t| t == separator) {
+        parts.push(&rest[..end]);
+        rest = &rest[end + 1..];
+    }
+
+    parts.push(rest);
+    parts
+}
+
+/// Returns the tokens inside parentheses enclosing all of `tokens`, as in
+/// `(a || b)` but not `(a) || (b)`.
+fn strip_parens<'t, 'a>(tokens: &'t [Token<'a>]) -> Option<&'t [Token<'a>]> {
+    let [Token::LParen, inner @ .., Token::RParen] = tokens else {
+        return None;
+    };
+
+    let mut depth = 0usize;
+    for token in inner {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+
+    (depth == 0).then_some(inner)
+}
+
+/// Returns whether the parentheses of `tokens` are balanced, each `)`
+/// closing an earlier `(`.
+fn balanced_parens(tokens: &[Token]) -> bool {
+    let mut depth = 0usize;
+
+    for token in tokens {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => match depth.checked_sub(1) {
+                Some(outer) => depth = outer,
+                None => return false,
+            },
+            _ => {}
+        }
+    }
+
+    depth == 0
+}
+
+/// Parses a value in an expression: a sum, or a coalescing chain of sums
+/// joined by `??`, which binds more loosely than `+`.
+///
+/// Examples:
+///   score              → Variable("score")
+///   nick ?? name ?? 'anon' → Coalesce([Variable("nick"), Variable("name"), Literal("anon")])
+fn parse_operand(tokens: &[Token]) -> Option<Argument> {
+    let mut rest = tokens;
+    let mut args = Vec::new();
+
+    loop {
+        let end = find_top_level(rest, |t| *t == Token::Coalesce);
+        args.push(parse_sum(&rest[..end.unwrap_or(rest.len())])?);
+
+        match end {
+            Some(coalesce) => rest = &rest[coalesce + 1..],
+            None => break,
+        }
+    }
+
+    match args.len() {
+        1 => args.pop(),
+        _ => Some(Argument::coalesce(args)),
+    }
+}
+
+/// Parses a term or a sum of terms joined by `+`, grouped from the left.
+///
+/// Examples:
+///   score              → Variable("score")
+///   first + ' ' + last → Add(Add(Variable("first"), Literal(" ")), Variable("last"))
+fn parse_sum(tokens: &[Token]) -> Option<Argument> {
+    let mut rest = tokens;
+    let mut sum: Option<Argument> = None;
+
+    loop {
+        let end = find_top_level(rest, |t| *t == Token::Plus);
+        let term = parse_term(&rest[..end.unwrap_or(rest.len())])?;
+
+        sum = Some(match sum {
+            Some(left) => Argument::add(left, term),
+            None => term,
+        });
+
+        match end {
+            Some(plus) => rest = &rest[plus + 1..],
+            None => return sum,
+        }
+    }
+}
+
+/// Parses an index into an array, `value[index]`, whose value and index are
+/// terms, so that indices can be negative and indexes can be chained as in
+/// `rows[i][-1]`.
+fn parse_item(tokens: &[Token]) -> Option<Argument> {
+    let [rest @ .., Token::RSquare] = tokens else {
+        return None;
+    };
+
+    // The bracket opening the index, skipping those nested in it
+    let mut depth = 0usize;
+    let mut open = None;
+    for (i, token) in rest.iter().enumerate().rev() {
+        match token {
+            Token::RParen | Token::RCurly | Token::RSquare => depth += 1,
+            Token::LSquare if depth == 0 => {
+                open = Some(i);
+                break;
+            }
+            Token::LParen | Token::LCurly | Token::LSquare => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+
+    let open = open?;
+    if open == 0 {
+        return None;
+    }
+
+    Some(Argument::index(
+        parse_term(&rest[..open])?,
+        parse_term(&rest[open + 1..])?,
+    ))
+}
+
+/// Parses a single term in an expression: a variable, a literal, a negative
+/// number, a function call, a switch used as a value or an array item.
+///
+/// Examples:
+///   score          → Variable("score")
+///   -5             → Literal("-5")
+///   max(a, 0)      → Call(Max, [Variable("a"), Literal("0")])
+///   [tier](gold:2)(*:0) → Switch(..)
+///   items[-1]      → Index(Variable("items"), Literal("-1"))
+///   @value         → Variable("@value"), in a switch case body
+///   @index         → Variable("@index"), in a loop body
+fn parse_term(tokens: &[Token]) -> Option<Argument> {
+    if let Some((scoped, [])) = scoped_variable(tokens) {
+        return Some(scoped);
+    }
+
+    match tokens {
+        [Token::LSquare, ..] => parse_switch(tokens).map(Argument::switch),
+        [.., Token::RSquare] => parse_item(tokens),
+        [value @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_))] => {
+            Some(token_to_argument(value))
+        }
+        [Token::Minus, number @ (Token::Int(_) | Token::Float(_))] => Some(Argument::literal(
+            Cow::Owned(format!("-{}", token_to_text(number)?)),
+        )),
+        _ => parse_call(tokens),
+    }
+}
+
+/// Parses a call to a built-in function, `name(arg, ..)`, or a type test,
+/// `is_number(arg)`, whose arguments are operands themselves. Returns `None`
+/// for unknown functions and calls with the wrong number of arguments.
+fn parse_call(tokens: &[Token]) -> Option<Argument> {
+    let [
+        Token::Ident(name),
+        Token::LParen,
+        args_tokens @ ..,
+        Token::RParen,
+    ] = tokens
+    else {
+        return None;
+    };
+
+    if let Some(test) = TypeTest::from_name(name) {
+        return Some(Argument::is_type(test, parse_operand(args_tokens)?));
+    }
+
+    let function = Function::from_name(name)?;
+    let mut rest = args_tokens;
+    let mut args = Vec::new();
+
+    loop {
+        let end = find_top_level(rest, |t| *t == Token::Comma);
+        args.push(parse_operand(&rest[..end.unwrap_or(rest.len())])?);
+
+        match end {
+            Some(comma) => rest = &rest[comma + 1..],
+            None => break,
+        }
+    }
+
+    function
+        .accepts(args.len())
+        .then(|| Argument::call(function, args))
+}
+
+/// Parses a function call, a sum, a coalescing chain or a switch followed by
+/// filters used as a directive: `{max(a, b)}`, `{first + ' ' + last}`,
+/// `{nick ?? name}`, `{[tier](gold:2)(*:0) + 1}`, optionally followed by
+/// filters, `{abs(delta):05}`, `{[tier](gold:2)(*:0):05}`.
+fn parse_expression_directive(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+    let end = find_top_level(tokens, |t| matches!(t, Token::Colon | Token::Pipe));
+    let head = &tokens[..end.unwrap_or(tokens.len())];
+
+    // Plain values are left to the other patterns, e.g. `{name}` or `{x:3}`
+    let value = if head.contains(&Token::Plus)
+        || head.contains(&Token::Coalesce)
+        || head.first() == Some(&Token::LSquare)
+        || head.last() == Some(&Token::RSquare)
+    {
+        parse_operand(head)?
+    } else {
+        parse_call(head)?
+    };
+
+    match end {
+        None => Some(Box::new(ReplaceDirective(value))),
+        Some(end) => match parse_filters(&tokens[end + 1..]) {
+            Some(filters) => Some(Box::new(FilterDirective(value, filters))),
+            None => Some(Box::new(UnknownDirective::default())),
+        },
+    }
+}
+
+/// Parses a range test, `value in low..high`, whose parts are operands.
+fn parse_range(tokens: &[Token]) -> Option<Argument> {
+    let keyword = find_top_level(tokens, |t| *t == Token::Ident("in"))?;
+    let bounds = &tokens[keyword + 1..];
+    let dots = find_top_level(bounds, |t| *t == Token::Range)?;
+
+    Some(Argument::in_range(
+        parse_operand(&tokens[..keyword])?,
+        parse_operand(&bounds[..dots])?,
+        parse_operand(&bounds[dots + 1..])?,
+    ))
+}
+
+/// Parses a list membership test, `value in [a, b, c]`, whose value and
+/// items are operands. The list may be empty.
+fn parse_list_test(tokens: &[Token]) -> Option<Argument> {
+    let keyword = find_top_level(tokens, |t| *t == Token::Ident("in"))?;
+    let [Token::LSquare, list @ .., Token::RSquare] = &tokens[keyword + 1..] else {
+        return None;
+    };
+
+    let items = match list {
+        [] => Vec::new(),
+        _ => split_top_level(list, &Token::Comma)
+            .into_iter()
+            .map(parse_operand)
+            .collect::<Option<Vec<_>>>()?,
+    };
+
+    Some(Argument::in_list(parse_operand(&tokens[..keyword])?, items))
+}
+
+/// Parses the condition of a ternary, i.e. the tokens before the `?`: tests
+/// joined by `||` and `&&`, negated by `!` and grouped by parentheses.
+///
+/// From tightest to loosest, operators bind as follows, so that
+/// `!a == b && c || d` is `((!a) == b && c) || d`:
+///
+/// 1. `!`
+/// 2. comparisons and the other tests, see `parse_test`
+/// 3. `&&`
+/// 4. `||`
+///
+/// Examples:
+///   a || b && c      → Or([Variable("a"), And([Variable("b"), Variable("c")])])
+///   (a || b) && !c   → And([Or([..]), Not(Variable("c"))])
+fn parse_condition(tokens: &[Token]) -> Option<Argument> {
+    let mut any = split_top_level(tokens, &Token::Or)
+        .into_iter()
+        .map(parse_conjunction)
+        .collect::<Option<Vec<_>>>()?;
+
+    match any.len() {
+        1 => any.pop(),
+        _ => Some(Argument::or(any)),
+    }
+}
+
+/// Parses tests joined by `&&`, for `parse_condition`.
+fn parse_conjunction(tokens: &[Token]) -> Option<Argument> {
+    let mut all = split_top_level(tokens, &Token::And)
+        .into_iter()
+        .map(parse_test)
+        .collect::<Option<Vec<_>>>()?;
+
+    match all.len() {
+        1 => all.pop(),
+        _ => Some(Argument::and(all)),
+    }
+}
+
+/// Parses a side of a comparison, or a negated or parenthesized condition.
+/// `!` binds more tightly than comparisons, so `!a == b` compares `!a`.
+fn parse_comparand(tokens: &[Token]) -> Option<Argument> {
+    match tokens {
+        [Token::Not, rest @ ..] => Some(Argument::not(parse_comparand(rest)?)),
+        _ => match strip_parens(tokens) {
+            Some(inner) => parse_condition(inner).or_else(|| parse_operand(inner)),
+            None => parse_operand(tokens),
+        },
+    }
+}
+
+/// Parses a single test of a condition.
+///
+/// Examples:
+///   active           → Variable("active")
+///   !active          → Not(Variable("active"))
+///   age >= 18        → Comparison(Variable("age"), GreaterThanEquals, Literal("18"))
+///   email =~ '@'     → Matches(Variable("email"), /@/)
+///   max(a, b) > 10   → Comparison(Call(Max, [..]), GreaterThan, Literal("10"))
+///   age in 13..19    → InRange(Variable("age"), Literal("13"), Literal("19"))
+///   role in [a, 'b'] → InList(Variable("role"), [Variable("a"), Literal("b")])
+///   defined(coupon)  → Defined("coupon")
+///   is_null(x)       → IsType(Null, Variable("x"))
+///   !(a || b)        → Not(Or([Variable("a"), Variable("b")]))
+fn parse_test(tokens: &[Token]) -> Option<Argument> {
+    match tokens {
+        [cond @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_))] => {
+            Some(token_to_argument(cond))
+        }
+
+        [
+            Token::Not,
+            cond @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
+        ] => Some(Argument::not(token_to_argument(cond))),
+
+        [Token::Ident("defined"), Token::LParen, .., Token::RParen] => parse_defined(tokens),
+
+        [
+            Token::Not,
+            Token::Ident("defined"),
+            Token::LParen,
+            ..,
+            Token::RParen,
+        ] => parse_defined(&tokens[1..]).map(Argument::not),
+
+        #[cfg(feature = "regex")]
+        [
+            left @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_)),
+            Token::Matches,
+            Token::Literal(pattern),
+        ] => Some(Argument::matches(
+            token_to_argument(left),
+            regex::Regex::new(pattern).ok()?,
+        )),
+
+        [
+            left @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_)),
+            op,
+            right @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_)),
+        ] => Some(Argument::comparison(
+            token_to_argument(left),
+            token_to_comparison_op(op)?,
+            token_to_argument(right),
+        )),
+
+        [.., Token::RSquare] if tokens.contains(&Token::Ident("in")) => parse_list_test(tokens),
+
+        _ if tokens.contains(&Token::Range) => parse_range(tokens),
+
+        // Comparisons involving function calls, negative numbers, negations
+        // or groups, or a lone function call, negation, group or scoped
+        // variable tested for truthiness
+        _ => match find_top_level(tokens, |t| token_to_comparison_op(t).is_some()) {
+            Some(op) => Some(Argument::comparison(
+                parse_comparand(&tokens[..op])?,
+                token_to_comparison_op(&tokens[op])?,
+                parse_comparand(&tokens[op + 1..])?,
+            )),
+            None if matches!(tokens.first(), Some(Token::Not | Token::LSquare))
+                || strip_parens(tokens).is_some() =>
+            {
+                parse_comparand(tokens)
+            }
+            None => parse_term(tokens),
+        },
+    }
+}
+
+/// Parses a presence test, `defined(name)`, whose name is a variable or a
+/// scoped one such as `.`.
+fn parse_defined(tokens: &[Token]) -> Option<Argument> {
+    let [
+        Token::Ident("defined"),
+        Token::LParen,
+        name @ ..,
+        Token::RParen,
+    ] = tokens
+    else {
+        return None;
+    };
+
+    match parse_term(name)? {
+        Argument::Variable(name) => Some(Argument::defined(name)),
+        _ => None,
+    }
+}
+
+/// Parses a directive in braces, such as the `{name}` in `{nick | {name}}`,
+/// into a sub-template holding it. Braced directives can nest.
+fn parse_braced(tokens: &[Token]) -> Option<Argument> {
+    let [Token::LCurly, inner @ .., Token::RCurly] = tokens else {
+        return None;
+    };
+
+    // The braces must enclose everything, unlike in `{a} : {b}`
+    let mut depth = 0usize;
+    for token in inner {
+        match token {
+            Token::LCurly => depth += 1,
+            Token::RCurly => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+
+    if depth != 0 {
+        return None;
+    }
+
+    let directive = DefaultParser::parse(inner)?;
+
+    Some(Argument::Template(Arc::new(Fragment::single(directive))))
+}
+
+/// Parses a single branch of a ternary: a variable, a literal, a number or a
+/// braced directive.
+fn parse_branch(tokens: &[Token]) -> Option<Argument> {
+    match tokens {
+        [branch @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_))] => {
+            Some(token_to_argument(branch))
+        }
+        _ => parse_braced(tokens),
+    }
+}
+
+/// Parses a fallback, `directive | fallback`, whose fallback is a literal or
+/// a braced directive: `{nick | 'anonymous'}`, `{nick | {name}}`.
+///
+/// The last pipe is split at, so in a chain such as `{a | {b} | 'c'}` the
+/// wrapped directive is `a | {b}`, itself a fallback. Pipes before it may
+/// separate filters: `{bio | trunc(20) | 'No bio'}`.
+fn parse_fallback(tokens: &[Token]) -> Option<FallbackDirective> {
+    let pipe = rfind_top_level(tokens, |t| *t == Token::Pipe)?;
+    let (directive, fallback) = (&tokens[..pipe], &tokens[pipe + 1..]);
+
+    let fallback = match fallback {
+        [Token::Literal(text)] => Argument::literal(Cow::Owned(text.to_string())),
+        _ => parse_braced(fallback)?,
+    };
+
+    if directive.is_empty() {
+        return None;
+    }
+
+    Some(FallbackDirective {
+        directive: DefaultParser::parse(directive)?,
+        fallback,
+    })
+}
+
+/// Parses a loop, `items*body`, over the array a term resolves to. The body
+/// is a literal or a braced directive, as a fallback is, so loops nest:
+/// `{tags*'#{.} '}`, `{rows*{.*{.}}}`.
+fn parse_loop(tokens: &[Token]) -> Option<LoopDirective> {
+    let star = find_top_level(tokens, |t| *t == Token::Star)?;
+    let (items, body) = (&tokens[..star], &tokens[star + 1..]);
+
+    let body = match body {
+        [Token::Literal(text)] => Argument::literal(Cow::Owned(text.to_string())),
+        _ => parse_braced(body)?,
+    };
+
+    Some(LoopDirective {
+        items: parse_term(items)?,
+        body,
+    })
+}
+
+/// Parses a ternary conditional: `{condition ? if_true : if_false}` or
+/// `{condition ? if_true}`.
+///
+/// The else branch is optional: `yes : no` has both, `yes` only the first.
+/// Branches in braces are directives, as in `{nick} : {name}`. An else
+/// branch holding another `?` continues an else-if ladder, as in
+/// `gold : tier == 'silver' ? silver : bronze`, whose steps are added to the
+/// same conditional, making the chain right-associative.
+///
+/// The steps of a ladder are parsed in a loop, so parsing takes linear time
+/// and constant stack however long it is.
+///
+/// Returns `None` if the tokens don't form a conditional, e.g. when the only
+/// `?` is the fill character of an alignment spec.
+fn parse_conditional(tokens: &[Token]) -> Option<ConditionalDirective> {
+    let mut branches = Vec::new();
+    let mut rest = tokens;
+
+    let if_false = loop {
+        let question = rest.iter().position(|t| *t == Token::Question)?;
+        let cond = parse_condition(&rest[..question])?;
+        let tail = &rest[question + 1..];
+
+        let Some(colon) = find_top_level(tail, |t| *t == Token::Colon) else {
+            branches.push((cond, parse_branch(tail)?));
+            break None;
+        };
+
+        branches.push((cond, parse_branch(&tail[..colon])?));
+        rest = &tail[colon + 1..];
+
+        if let Some(if_false) = parse_branch(rest) {
+            break Some(if_false);
+        }
+    };
+
+    Some(ConditionalDirective { branches, if_false })
+}
+
+/// Parses the label of a switch case, i.e. the tokens between `(` and `:`.
+fn parse_case_label(tokens: &[Token]) -> Option<CaseLabel> {
+    match tokens {
+        [Token::Star] => Some(CaseLabel::Default),
+        [label] => token_to_text(label).map(CaseLabel::Value),
+        [label, Token::Star] => token_to_text(label).map(CaseLabel::Prefix),
+        [Token::Minus, number @ (Token::Int(_) | Token::Float(_))] => Some(CaseLabel::Value(
+            Cow::Owned(format!("-{}", token_to_text(number)?)),
+        )),
+        [op, bound @ ..] => {
+            // Only ordering operators bucket numbers; `==` is a plain label
+            let op = token_to_comparison_op(op).filter(|op| {
+                matches!(
+                    op,
+                    ComparisonOp::GreaterThan
+                        | ComparisonOp::GreaterThanEquals
+                        | ComparisonOp::LessThan
+                        | ComparisonOp::LessThanEquals
+                )
+            })?;
+
+            let bound = match bound {
+                [number @ (Token::Int(_) | Token::Float(_))] => token_to_text(number)?,
+                [Token::Minus, number @ (Token::Int(_) | Token::Float(_))] => {
+                    Cow::Owned(format!("-{}", token_to_text(number)?))
+                }
+                _ => return None,
+            };
+
+            Some(CaseLabel::Comparison(op, bound))
+        }
+        _ => None,
+    }
+}
+
+/// Parses a switch, `[scrutinee](label:body)..` or, with a default for a
+/// missing scrutinee, `[scrutinee|default](label:body)..`, returning `None`
+/// if the tokens are not a switch.
+fn parse_switch(tokens: &[Token]) -> Option<SwitchDirective> {
+    let [Token::LSquare, rest @ ..] = tokens else {
+        return None;
+    };
+
+    // The bracket closing the value, skipping those of an index in it
+    let mut depth = 0usize;
+    let close = rest.iter().position(|token| match token {
+        Token::LParen | Token::LCurly | Token::LSquare => {
+            depth += 1;
+            false
+        }
+        Token::RParen | Token::RCurly | Token::RSquare if depth > 0 => {
+            depth -= 1;
+            false
+        }
+        token => *token == Token::RSquare,
+    })?;
+    let (value, cases_tokens) = (&rest[..close], &rest[close + 1..]);
+
+    let (scrutinee, default) = match value {
+        [
+            scrutinee @ ..,
+            Token::Pipe,
+            default @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_)),
+        ] => {
+            // The default is a label, so a bare word is text, not a variable
+            let default = match default {
+                Token::Ident(label) => Argument::literal(Cow::Owned(label.to_string())),
+                default => token_to_argument(default),
+            };
+            (scrutinee, Some(default))
+        }
+        _ => (value, None),
+    };
+    let scrutinee = parse_term(scrutinee)?;
+
+    let mut rest = cases_tokens;
+    let mut cases = Vec::new();
+
+    while !rest.is_empty() {
+        let Token::LParen = rest[0] else {
+            return None;
+        };
+
+        let colon = rest.iter().position(|t| *t == Token::Colon)?;
+        let label = parse_case_label(&rest[1..colon])?;
+
+        let [Token::Text(body), Token::RParen, tail @ ..] = &rest[colon + 1..] else {
+            return None;
+        };
+
+        cases.push(SwitchCase {
+            label,
+            body: Argument::literal(Cow::Owned(body.to_string())),
+        });
+        rest = tail;
+    }
+
+    Some(SwitchDirective {
+        scrutinee,
+        cases,
+        default,
+    })
+}
+
 impl Parser for DefaultParser {
     /// Parses tokens into directives using pattern matching.
     ///
@@ -118,24 +1390,150 @@ impl Parser for DefaultParser {
     /// 1. **Variable replacement**: `[Ident]` → `{name}`
     /// 2. **Literal value**: `[Literal]` → `{"text"}`
     /// 3. **Repeat pattern**: `[Pattern, Colon, Count]` → `{pattern:count}`
-    /// 4. **Simple conditional**: `[Cond, Question, True, Colon, False]` → `{cond ? true : false}`
-    /// 5. **Comparison conditional**: `[Left, Op, Right, Question, True, Colon, False]` → `{x == 5 ? yes : no}`
-    /// 6. **NOT conditional**: `[Not, Cond, Question, True, Colon, False]` → `{!cond ? yes : no}`
+    /// 4. **Filtered value**: `[Value, Colon, Spec..]` → `{name:<10}`, `{bio:trunc(80)}`,
+    ///    or a pipeline: `[Value, Pipe, Spec.., Pipe, Spec..]` → `{bio | trunc(80) | >90}`
+    /// 5. **Simple conditional**: `[Cond, Question, True, Colon, False]` → `{cond ? true : false}`
+    /// 6. **Comparison conditional**: `[Left, Op, Right, Question, True, Colon, False]` → `{x == 5 ? yes : no}`
+    /// 7. **NOT conditional**: `[Not, Cond, Question, True, Colon, False]` → `{!cond ? yes : no}`
+    /// 8. **Regex conditional**: `[Value, Matches, Literal, Question, ..]` → `{email =~ '^.+@.+$' ? valid}`
+    /// 9. **Switch**: `[LSquare, Term, RSquare, (LParen, Label, Colon, Text, RParen)..]` → `{[x](a:one)(*:other)}`,
+    ///    where a label followed by `Star` matches a prefix → `{[level](ERR*:error)}` and an
+    ///    ordering operator followed by a number is a comparison → `{[score](>=90:A)(*:F)}`,
+    ///    and `Pipe, Label` after the value is a default for a missing one → `{[x|none](none:-)}`
+    /// 10. **Range conditional**: `[Value, Ident("in"), Low, Range, High, Question, ..]` → `{age in 13..19 ? teen}`
+    ///     or a list test: `[Value, Ident("in"), LSquare, Item, (Comma, Item).., RSquare, Question, ..]`
+    ///     → `{role in ['admin', 'staff'] ? yes}`
+    /// 11. **Function call**: `[Ident, LParen, Arg, (Comma, Arg).., RParen]` → `{max(a, b)}`,
+    ///     optionally followed by filters → `{abs(delta):+}`
+    /// 12. **Sum**: `[Term, (Plus, Term)..]` → `{first + ' ' + last}`, optionally followed by filters
+    /// 13. **Include**: `[GreaterThan, Name]` → `{>header}`
+    /// 14. **Else-if ladder**: `[Cond, Question, True, Colon, Cond, Question, ..]` → `{a ? x : b ? y : z}`
+    /// 15. **Presence test**: `[Ident("defined"), LParen, Name, RParen, Question, ..]` → `{defined(coupon) ? yes}`
+    /// 16. **Error message**: `[Directive.., Not, Not, Literal]` → `{price !! 'price is required'}`
+    /// 17. **Fallback**: `[Directive.., Pipe, Literal]` or `[Directive.., Pipe, LCurly, Directive.., RCurly]`
+    ///     → `{nick | 'anonymous'}`, `{nick | {name}}`
+    /// 18. **Column**: `[Unknown('@'), Ident("col"), LParen, Int, RParen]` → `{@col(30)}`
+    /// 19. **Coalescing**: `[Sum, (Coalesce, Sum)..]` → `{nick ?? name ?? 'anonymous'}`, optionally
+    ///     followed by filters, and usable wherever a sum is, such as in conditions
+    /// 20. **Region**: `[Unknown('@'), Ident("region"), Colon, Name]` → `{@region:header}`, closed by
+    ///     `[Unknown('@'), Ident("end")]` → `{@end}`, optionally followed by `Colon, Name`
+    /// 21. **Logical operators**: `[Cond, (And | Or, Cond)..]` in a condition, grouped by
+    ///     `LParen, .., RParen` → `{(a || b) && !c ? yes : no}`
+    /// 22. **Switch as a value**: a switch wherever a term is, in conditions, sums and function
+    ///     arguments → `{[tier](gold:2)(*:0) >= 1 ? member}`, `{[tier](gold:5)(*:0) + bonus}`
+    /// 23. **Type test**: `[Ident("is_number"), LParen, Arg, RParen]` wherever a function
+    ///     call is → `{is_null(x) ? none}`, see `TypeTest` for the other names
+    /// 24. **Positional argument**: `[Int]` → `{0}`, optionally followed by filters → `{1:>5}`,
+    ///     names the variable `"0"`, as `` {`0`} `` does in expressions
+    /// 25. **Matched value**: `[Unknown('@'), Ident("value")]` wherever a term is, or followed by
+    ///     filters → `{[status](active:You are {@value})}`, the value a switch matched, within
+    ///     the body of the selected case
+    /// 26. **Loop**: `[Term, Star, Literal]` or `[Term, Star, LCurly, Directive.., RCurly]` →
+    ///     `{tags*'#{.} '}`, `{rows*{.*{.}}}`, the body rendered once per item of an array,
+    ///     where `.` is the item and `@index`, `@count`, `@first` and `@last` are scoped like
+    ///     `@value`, wherever a term is or followed by filters
+    ///
+    /// In conditions, operators bind from tightest to loosest as follows,
+    /// and parentheses group:
+    ///
+    /// | Operators | Example |
+    /// |-----------|---------|
+    /// | `!` | `!a == b` is `(!a) == b` |
+    /// | comparisons, `in`, `=~` | `a == b && c` is `(a == b) && c` |
+    /// | `&&` | `a \|\| b && c` is `a \|\| (b && c)` |
+    /// | `\|\|` | |
+    ///
+    /// Error messages are stripped first, then fallbacks, then switches are
+    /// recognized, then conditionals, then function calls and sums. Branches
+    /// of a conditional may be braced directives too: `{vip ? {nick} : {name}}`. A conditional's
+    /// `Colon, False` tail is optional: `{cond ? true}` renders nothing when
+    /// the condition is false.
     ///
     /// # Returns
     ///
-    /// Returns `Some(directive)` if parsing succeeds, or `Some(EmptyDirective)` if
-    /// the token sequence doesn't match any known pattern. Returns `None` only if
-    /// a critical parsing error occurs (currently never happens in practice).
+    /// Returns `Some(directive)` if parsing succeeds, `Some(EmptyDirective)` for
+    /// an empty directive, or `Some(UnknownDirective)` if the token sequence
+    /// doesn't match any known pattern. Returns `None` if a regex match (`=~`)
+    /// can't be parsed, such as when its pattern is invalid, or if the
+    /// parentheses of a condition are unbalanced.
     fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+        // Simple variable replacement: {name}
+        // The most common directive matches none of the patterns tried
+        // before the others, so it skips them.
+        // Example: {username} → ReplaceDirective(Variable("username"))
+        if let [Token::Ident(ident)] = tokens {
+            return Some(Box::new(ReplaceDirective(Argument::variable(Cow::Owned(
+                ident.to_string(),
+            )))));
+        }
+
+        // Unbalanced parentheses in a condition are an error. Elsewhere a
+        // parenthesis may be the fill character of an alignment spec, as in
+        // `{name:(<10}`
+        if let Some(question) = tokens.iter().position(|t| *t == Token::Question)
+            && !balanced_parens(&tokens[..question])
+        {
+            return None;
+        }
+
+        // Custom error message: {directive !! "message"}
+        if let [rest @ .., Token::Not, Token::Not, Token::Literal(message)] = tokens
+            && !rest.is_empty()
+        {
+            return Some(Box::new(ErrorMessageDirective {
+                directive: Self::parse(rest)?,
+                message: Cow::Owned(message.to_string()),
+            }));
+        }
+
+        if let Some(fallback) = parse_fallback(tokens) {
+            return Some(Box::new(fallback));
+        }
+
+        if let Some(switch) = parse_switch(tokens) {
+            return Some(Box::new(switch));
+        }
+
+        if let Some(looping) = parse_loop(tokens) {
+            return Some(Box::new(looping));
+        }
+
+        if let Some(conditional) = parse_conditional(tokens) {
+            return Some(Box::new(conditional));
+        }
+
+        if let Some(expression) = parse_expression_directive(tokens) {
+            return Some(expression);
+        }
+
+        // Scoped variable: {@value} within a switch case body, or {.}, {@index},
+        // {@count}, {@first} and {@last} within a loop body
+        // Examples:
+        //   {@value}   → ReplaceDirective(Variable("@value"))
+        //   {@index:>5} → FilterDirective(Variable("@index"), [AlignFilter(Right, 5, ' ')])
+        match scoped_variable(tokens) {
+            Some((value, [])) => return Some(Box::new(ReplaceDirective(value))),
+            Some((value, [Token::Colon | Token::Pipe, spec @ ..])) => {
+                return match parse_filters(spec) {
+                    Some(filters) => Some(Box::new(FilterDirective(value, filters))),
+                    None => Some(Box::new(UnknownDirective::default())),
+                };
+            }
+            _ => {}
+        }
+
         match tokens {
-            // Simple variable replacement: {name}
-            // Example: {username} → ReplaceDirective(Variable("username"))
-            [Token::Ident(ident)] => Some(Box::new(ReplaceDirective(Argument::variable(
-                Cow::Owned(ident.to_string()),
+            // Empty directive: {}
+            [] => Some(Box::new(EmptyDirective)),
+
+            // Positional argument: {0}
+            // Example: {1} → ReplaceDirective(Variable("1")), see `Template::format_args`
+            [Token::Int(index)] => Some(Box::new(ReplaceDirective(Argument::variable(
+                Cow::Owned(index.to_string()),
             )))),
 
             // Repeat pattern: {pattern:count}
+            // Filter names such as `hex` take precedence over a count variable.
             // Examples:
             //   {'*':3} → RepeatDirective(Literal("*"), Literal("3"))
             //   {char:n} → RepeatDirective(Variable("char"), Variable("n"))
@@ -143,7 +1541,7 @@ impl Parser for DefaultParser {
                 p @ (Token::Ident(_) | Token::Literal(_)),
                 Token::Colon,
                 c @ (Token::Ident(_) | Token::Int(_)),
-            ] => {
+            ] if !is_filter_name(c) => {
                 let pattern = match p {
                     Token::Ident(s) => Argument::variable(Cow::Owned(s.to_string())),
                     Token::Literal(cow) => Argument::literal(Cow::Owned(cow.to_string())),
@@ -165,101 +1563,81 @@ impl Parser for DefaultParser {
                 Cow::Owned(lit.to_string()),
             )))),
 
-            // Ternary conditional: {condition ? if_true : if_false}
-            // The condition can be a variable, literal, or numeric value
+            // Filtered value: {value:spec} or a pipeline, {value | spec | spec}
+            // The spec never collides with a repeat count, which is a single token.
             // Examples:
-            //   {active ? "yes" : "no"} → ConditionalDirective with Variable("active")
-            //   {true ? "yes" : "no"} → ConditionalDirective with Literal("true")
+            //   {name:<10}      → FilterDirective(Variable("name"), [AlignFilter(Left, 10, ' ')])
+            //   {'x':*^5}       → FilterDirective(Literal("x"), [AlignFilter(Center, 5, '*')])
+            //   {bio:trunc(80)} → FilterDirective(Variable("bio"), [TruncateFilter(80, "…")])
+            //   {mask:#hex}     → FilterDirective(Variable("mask"), [RadixFilter(Hexadecimal)])
+            //   {name:len}      → FilterDirective(Variable("name"), [LengthFilter])
+            //   {id:+05}        → FilterDirective(Variable("id"), [NumberFilter(+, 5)])
+            //   {on:bool(Y, N)} → FilterDirective(Variable("on"), [BoolFilter("Y", "N")])
+            //   {name | len | +} → FilterDirective(Variable("name"), [LengthFilter, NumberFilter(+, 0)])
             [
-                cond @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-                Token::Question,
-                if_true @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-                Token::Colon,
-                if_false @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-            ] => {
-                let cond_arg = token_to_argument(cond);
-                let true_arg = token_to_argument(if_true);
-                let false_arg = token_to_argument(if_false);
-
-                Some(Box::new(ConditionalDirective {
-                    cond: cond_arg,
-                    if_true: true_arg,
-                    if_false: false_arg,
-                }))
-            }
+                value @ (Token::Ident(_) | Token::Literal(_)),
+                Token::Colon | Token::Pipe,
+                spec @ ..,
+            ] => match parse_filters(spec) {
+                Some(filters) => Some(Box::new(FilterDirective(token_to_argument(value), filters))),
+                None => Some(Box::new(UnknownDirective::default())),
+            },
 
-            // Conditional with comparison: {left op right ? if_true : if_false}
-            // Supports: ==, !=, >, <, >=, <=
-            // Examples:
-            //   {age >= 18 ? "adult" : "minor"}
-            //   {status == "active" ? "online" : "offline"}
-            //   {count > 0 ? items : "empty"}
+            // Filtered positional argument: {0:spec}
+            // Example: {1:>5} → FilterDirective(Variable("1"), [AlignFilter(Right, 5, ' ')])
+            [Token::Int(index), Token::Colon | Token::Pipe, spec @ ..] => match parse_filters(spec)
+            {
+                Some(filters) => Some(Box::new(FilterDirective(
+                    Argument::variable(Cow::Owned(index.to_string())),
+                    filters,
+                ))),
+                None => Some(Box::new(UnknownDirective::default())),
+            },
+
+            // Include: {>name} or {>'name'}
+            // Example: {>header} → IncludeDirective("header")
             [
-                left @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_)),
-                op @ (Token::Equals
-                | Token::NotEquals
-                | Token::GreaterThan
-                | Token::LessThan
-                | Token::GreaterThanEquals
-                | Token::LessThanEquals),
-                right @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_) | Token::Float(_)),
-                Token::Question,
-                if_true @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-                Token::Colon,
-                if_false @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-            ] => {
-                let left_arg = token_to_argument(left);
-                let right_arg = token_to_argument(right);
-
-                let comp_op = match op {
-                    Token::Equals => ComparisonOp::Equals,
-                    Token::NotEquals => ComparisonOp::NotEquals,
-                    Token::GreaterThan => ComparisonOp::GreaterThan,
-                    Token::LessThan => ComparisonOp::LessThan,
-                    Token::GreaterThanEquals => ComparisonOp::GreaterThanEquals,
-                    Token::LessThanEquals => ComparisonOp::LessThanEquals,
-                    _ => unreachable!(),
-                };
+                Token::GreaterThan,
+                name @ (Token::Ident(_) | Token::Literal(_)),
+            ] => Some(Box::new(IncludeDirective(token_to_text(name)?))),
 
-                let cond_arg = Argument::comparison(left_arg, comp_op, right_arg);
-                let true_arg = token_to_argument(if_true);
-                let false_arg = token_to_argument(if_false);
+            // Column: {@col(n)}
+            // Example: {@col(30)} → ColumnDirective(30)
+            [
+                Token::Unknown('@'),
+                Token::Ident("col"),
+                Token::LParen,
+                Token::Int(column),
+                Token::RParen,
+            ] => Some(Box::new(ColumnDirective(column.parse().ok()?))),
 
-                Some(Box::new(ConditionalDirective {
-                    cond: cond_arg,
-                    if_true: true_arg,
-                    if_false: false_arg,
-                }))
-            }
+            // Example: {@region:header} → RegionDirective("header")
+            [
+                Token::Unknown('@'),
+                Token::Ident("region"),
+                Token::Colon,
+                name @ (Token::Ident(_) | Token::Literal(_)),
+            ] => Some(Box::new(RegionDirective(token_to_text(name)?))),
 
-            // Logical NOT conditional: {!condition ? if_true : if_false}
-            // Examples:
-            //   {!active ? "disabled" : "enabled"}
-            //   {!0 ? "truthy" : "falsy"}
+            // Example: {@end} → RegionEndDirective(None)
+            [Token::Unknown('@'), Token::Ident("end")] => Some(Box::new(RegionEndDirective(None))),
+
+            // Example: {@end:header} → RegionEndDirective(Some("header"))
             [
-                Token::Not,
-                cond @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-                Token::Question,
-                if_true @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
+                Token::Unknown('@'),
+                Token::Ident("end"),
                 Token::Colon,
-                if_false @ (Token::Ident(_) | Token::Literal(_) | Token::Int(_)),
-            ] => {
-                let inner_arg = token_to_argument(cond);
-                let cond_arg = Argument::not(inner_arg);
-                let true_arg = token_to_argument(if_true);
-                let false_arg = token_to_argument(if_false);
-
-                Some(Box::new(ConditionalDirective {
-                    cond: cond_arg,
-                    if_true: true_arg,
-                    if_false: false_arg,
-                }))
-            }
+                name @ (Token::Ident(_) | Token::Literal(_)),
+            ] => Some(Box::new(RegionEndDirective(Some(token_to_text(name)?)))),
+
+            // A regex match that isn't a valid condition, e.g. because the
+            // pattern doesn't compile or the `regex` feature is disabled, is
+            // an error rather than a silent failure
+            _ if tokens.contains(&Token::Matches) => None,
 
-            // Unknown pattern: return empty directive (silent failure)
-            // This allows templates to compile even with unsupported expressions,
-            // which will simply produce no output rather than failing to compile.
-            _ => Some(Box::new(EmptyDirective)),
+            // Unknown pattern: templates still compile, and what the directive
+            // renders is up to `FormatOptions::unknown`
+            _ => Some(Box::new(UnknownDirective::default())),
         }
     }
 }
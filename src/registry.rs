@@ -0,0 +1,70 @@
+//! Named partial templates, included with `{>name}`.
+//!
+//! A `TemplateRegistry` holds compiled templates by name. Rendering with
+//! `Template::format_with` makes them available to include directives, so
+//! shared pieces such as headers and footers are written once.
+
+use crate::{Fragment, Template};
+use std::collections::HashMap;
+
+/// A set of named templates that other templates can include.
+///
+/// Partials are stored compiled, without their delimiters, so a template
+/// can include partials written with different delimiters. They are rendered
+/// against the context of the including template and can include other
+/// partials, as long as no partial ends up including itself.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, Template, TemplateRegistry, Value};
+///
+/// let mut registry = TemplateRegistry::new();
+/// registry.insert("header", Template::<'{', '}'>::compile("== {title} ==").unwrap());
+///
+/// let page = Template::<'{', '}'>::compile("{>header}\n{body}").unwrap();
+///
+/// let mut ctx = Context::new();
+/// ctx.insert("title", Value::static_str("News"));
+/// ctx.insert("body", Value::static_str("Nothing new."));
+///
+/// assert_eq!(
+///     page.format_with(&ctx, &registry).unwrap(),
+///     "== News ==\nNothing new."
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct TemplateRegistry {
+    partials: HashMap<String, Fragment>,
+}
+
+impl TemplateRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a partial, replacing any partial with the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name used to include the partial, as in `{>name}`
+    /// * `template` - The compiled partial
+    pub fn insert<const O: char, const C: char>(
+        &mut self,
+        name: impl Into<String>,
+        template: Template<O, C>,
+    ) {
+        self.partials.insert(name.into(), template.body);
+    }
+
+    /// Returns the partial with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&Fragment> {
+        self.partials.get(name)
+    }
+
+    /// Returns whether a partial has the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.partials.contains_key(name)
+    }
+}
@@ -0,0 +1,101 @@
+//! Reusable rendering state for templates rendered many times.
+//!
+//! A `Renderer` keeps a template's options and an output buffer between
+//! renders, for servers that render the same template for every request.
+
+use crate::{ContextLookup, DirectiveError, FormatOptions, Template, TemplateRegistry};
+use std::fmt;
+
+/// A template bound to its options, which renders it repeatedly into a
+/// buffer it keeps between calls. Created by `Template::renderer`.
+///
+/// This is the stateful counterpart of `Template::format_with_options`: the
+/// options are given once, and the output buffer starts with the template's
+/// estimated length and then keeps the capacity of the longest output, so
+/// rendering many contexts doesn't allocate once the buffer has grown. The
+/// work that doesn't depend on the context, such as interning repeated
+/// variables, is already done when the template is compiled.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, FormatOptions, Template, Value};
+///
+/// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+/// let mut renderer = tmpl.renderer(FormatOptions::default());
+///
+/// for name in ["Alice", "Bob"] {
+///     let ctx = Context::from([("name", Value::static_str(name))]);
+///     println!("{}", renderer.render(&ctx).unwrap());
+/// }
+/// ```
+pub struct Renderer<'a, const O: char, const C: char> {
+    template: &'a Template<O, C>,
+    options: FormatOptions,
+    registry: Option<&'a TemplateRegistry>,
+    buffer: String,
+}
+
+impl<'a, const O: char, const C: char> Renderer<'a, O, C> {
+    /// Creates a renderer for `template` with the given options.
+    pub fn new(template: &'a Template<O, C>, options: FormatOptions) -> Self {
+        Self {
+            template,
+            registry: None,
+            buffer: String::with_capacity(template.output_capacity(&options)),
+            options,
+        }
+    }
+
+    /// Renders include directives such as `{>header}` with the partials of
+    /// `registry`, like `Template::format_with`.
+    pub fn with_registry(mut self, registry: &'a TemplateRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Returns the template this renderer renders.
+    pub fn template(&self) -> &'a Template<O, C> {
+        self.template
+    }
+
+    /// Returns the options the template is rendered with.
+    pub fn options(&self) -> &FormatOptions {
+        &self.options
+    }
+
+    /// Renders the template into the renderer's buffer, replacing the
+    /// previous output, and returns it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `Template::format_with_options`, or those
+    /// of `Template::format_with` if the renderer has a registry.
+    pub fn render(&mut self, ctx: &dyn ContextLookup) -> Result<&str, DirectiveError> {
+        self.buffer.clear();
+        self.template
+            .render_into(ctx, &self.options, self.registry, &mut self.buffer)?;
+
+        Ok(&self.buffer)
+    }
+
+    /// Renders the template into `out` instead of the renderer's buffer.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `render`. Output written before a failing
+    /// directive stays in `out`.
+    pub fn render_to(
+        &self,
+        ctx: &dyn ContextLookup,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), DirectiveError> {
+        self.template
+            .render_into(ctx, &self.options, self.registry, out)
+    }
+
+    /// Consumes the renderer, returning its buffer with the last output.
+    pub fn into_buffer(self) -> String {
+        self.buffer
+    }
+}
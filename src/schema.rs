@@ -0,0 +1,98 @@
+//! Default filters for variables, see `Schema`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{Filter, TemplateError, parser::parse_filter_chain};
+
+/// Declares once how variables are formatted wherever a template renders
+/// them, instead of repeating a spec at every use.
+///
+/// A schema maps variable names to a chain of filters, written like the spec
+/// of a directive, and is set as `FormatOptions::schema`. A plain
+/// substitution such as `{price}` then renders as if written
+/// `{price:currency(USD)}`. A directive with a spec of its own, such as
+/// `{price:>10}`, keeps only its own filters, so an inline spec overrides the
+/// schema. Variables used in conditions, expressions or fallbacks are not
+/// filtered.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, FormatOptions, Schema, Template, Value};
+///
+/// let schema = Schema::new()
+///     .field("price", "round(2) | currency(USD)")
+///     .unwrap()
+///     .field("sku", "lstrip(0)")
+///     .unwrap();
+/// let options = FormatOptions { schema, ..Default::default() };
+///
+/// let tmpl = Template::<'{', '}'>::compile("{sku}: {price} ({price:round})").unwrap();
+///
+/// let mut ctx = Context::new();
+/// ctx.insert("sku", Value::static_str("00042"));
+/// ctx.insert("price", Value::Float(1234.5));
+///
+/// let output = tmpl.format_with_options(&ctx, &options).unwrap();
+/// assert_eq!(output, "42: $1,234.50 (1235)");
+/// ```
+#[derive(Clone, Default, PartialEq)]
+pub struct Schema {
+    fields: HashMap<String, Arc<[Box<dyn Filter>]>>,
+}
+
+impl Schema {
+    /// Creates a schema with no fields.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the filters `name` renders with, parsed from `spec`, the text
+    /// after the `:` of a directive, such as `"trunc(20) | >24"`. Replaces
+    /// any filters set for `name` before.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TemplateError::DirectiveParsing` if `spec` is not a chain of
+    /// filters, e.g. a repeat count such as `3`.
+    pub fn field(self, name: impl Into<String>, spec: &str) -> Result<Self, TemplateError> {
+        let filters = parse_filter_chain(spec)
+            .ok_or_else(|| TemplateError::DirectiveParsing(spec.to_string()))?;
+
+        Ok(self.field_filters(name, filters))
+    }
+
+    /// Sets the filters `name` renders with, such as custom `Filter`s.
+    /// Replaces any filters set for `name` before.
+    pub fn field_filters(mut self, name: impl Into<String>, filters: Vec<Box<dyn Filter>>) -> Self {
+        self.fields.insert(name.into(), filters.into());
+        self
+    }
+
+    /// Returns the filters `name` renders with, if any are set.
+    pub fn get(&self, name: &str) -> Option<&[Box<dyn Filter>]> {
+        self.fields.get(name).map(|filters| &**filters)
+    }
+
+    /// Returns whether no variable has filters set.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl fmt::Debug for Schema {
+    /// Lists each variable with its filters by their specs, sorted by name.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        fields.sort_unstable_by_key(|(name, _)| name.as_str());
+
+        f.debug_map()
+            .entries(fields.into_iter().map(|(name, filters)| {
+                let specs: Vec<_> = filters.iter().map(|filter| filter.describe()).collect();
+                (name, specs.join(" | "))
+            }))
+            .finish()
+    }
+}
@@ -0,0 +1,677 @@
+//! Serialization of compiled templates, behind the `serde` feature.
+//!
+//! Directives and filters are trait objects, so they are serialized through
+//! the `SerializedDirective` and `SerializedFilter` enums, which mirror the
+//! built-in types. Deserializing rebuilds the directives directly; the parser
+//! is not run again.
+
+#[cfg(feature = "base64")]
+use crate::Base64Filter;
+#[cfg(feature = "digest")]
+use crate::DigestFilter;
+use crate::fragment::Node;
+use crate::{
+    AlignFilter, Argument, BoolFilter, CaseLabel, ColumnDirective, ConditionalDirective,
+    CurrencyFilter, Delimiters, Directive, EmptyDirective, ErrorMessageDirective,
+    FallbackDirective, Filter, FilterDirective, Fragment, IncludeDirective, IndentFilter,
+    LengthFilter, LiteralDirective, LoopDirective, MarkdownEscapeFilter, NumberFilter,
+    PercentFilter, RadixFilter, RegionDirective, RegionEndDirective, RepeatDirective, RepeatFilter,
+    ReplaceDirective, ReplaceFilter, ReverseFilter, RoundFilter, SegmentKind, ShellEscapeFilter,
+    SliceFilter, SquishFilter, StripFilter, SwitchCase, SwitchDirective, Template, TruncateFilter,
+    UnknownDirective, UrlEncodeFilter,
+};
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, ser};
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+/// The serializable form of a built-in directive.
+///
+/// Returned by `Directive::to_serialized`. Custom directives can't be
+/// serialized unless they map themselves onto one of these variants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SerializedDirective {
+    /// An `EmptyDirective`
+    Empty,
+    /// A `LiteralDirective`
+    Literal(Cow<'static, str>),
+    /// A `ReplaceDirective`
+    Replace(Argument),
+    /// A `FilterDirective`
+    Filter(Argument, Vec<SerializedFilter>),
+    /// A `RepeatDirective`
+    Repeat(Argument, Argument),
+    /// A `ConditionalDirective`
+    Conditional {
+        branches: Vec<(Argument, Argument)>,
+        if_false: Option<Argument>,
+    },
+    /// A `SwitchDirective`, with its cases in order
+    Switch {
+        scrutinee: Argument,
+        cases: Vec<(CaseLabel, Argument)>,
+        #[serde(default)]
+        default: Option<Argument>,
+    },
+    /// A `LoopDirective`
+    Loop { items: Argument, body: Argument },
+    /// An `IncludeDirective`, holding the partial's name
+    Include(Cow<'static, str>),
+    /// A `ColumnDirective`, holding its column
+    Column(usize),
+    /// A `RegionDirective`, holding the region's name
+    Region(Cow<'static, str>),
+    /// A `RegionEndDirective`, holding the name of the region it closes
+    RegionEnd(Option<Cow<'static, str>>),
+    /// An `ErrorMessageDirective`
+    ErrorMessage {
+        directive: Box<Self>,
+        message: Cow<'static, str>,
+    },
+    /// A `FallbackDirective`
+    Fallback {
+        directive: Box<Self>,
+        fallback: Argument,
+    },
+    /// An `UnknownDirective`, holding its source text
+    Unknown(Cow<'static, str>),
+}
+
+impl SerializedDirective {
+    /// Rebuilds the directive this was serialized from.
+    pub fn into_directive(self) -> Box<dyn Directive> {
+        match self {
+            Self::Empty => Box::new(EmptyDirective),
+            Self::Literal(text) => Box::new(LiteralDirective(text)),
+            Self::Unknown(source) => Box::new(UnknownDirective(source)),
+            Self::Replace(arg) => Box::new(ReplaceDirective(arg)),
+            Self::Filter(arg, filters) => Box::new(FilterDirective(
+                arg,
+                filters
+                    .into_iter()
+                    .map(SerializedFilter::into_filter)
+                    .collect(),
+            )),
+            Self::Repeat(pattern, count) => Box::new(RepeatDirective(pattern, count)),
+            Self::Conditional { branches, if_false } => {
+                Box::new(ConditionalDirective { branches, if_false })
+            }
+            Self::Switch {
+                scrutinee,
+                cases,
+                default,
+            } => Box::new(SwitchDirective {
+                scrutinee,
+                cases: cases
+                    .into_iter()
+                    .map(|(label, body)| SwitchCase { label, body })
+                    .collect(),
+                default,
+            }),
+            Self::Loop { items, body } => Box::new(LoopDirective { items, body }),
+            Self::Include(name) => Box::new(IncludeDirective(name)),
+            Self::Column(column) => Box::new(ColumnDirective(column)),
+            Self::Region(name) => Box::new(RegionDirective(name)),
+            Self::RegionEnd(name) => Box::new(RegionEndDirective(name)),
+            Self::ErrorMessage { directive, message } => Box::new(ErrorMessageDirective {
+                directive: directive.into_directive(),
+                message,
+            }),
+            Self::Fallback {
+                directive,
+                fallback,
+            } => Box::new(FallbackDirective {
+                directive: directive.into_directive(),
+                fallback,
+            }),
+        }
+    }
+}
+
+/// The serializable form of a built-in filter.
+///
+/// Returned by `Filter::to_serialized`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SerializedFilter {
+    /// An `AlignFilter`
+    Align(AlignFilter),
+    /// A `TruncateFilter`
+    Truncate(TruncateFilter),
+    /// A `SliceFilter`
+    Slice(SliceFilter),
+    /// A `LengthFilter`
+    Length(LengthFilter),
+    /// A `ReverseFilter`
+    Reverse(ReverseFilter),
+    /// A `RepeatFilter`
+    Repeat(RepeatFilter),
+    /// A `SquishFilter`
+    Squish(SquishFilter),
+    /// A `ReplaceFilter`
+    Replace(ReplaceFilter),
+    /// A `StripFilter`
+    Strip(StripFilter),
+    /// An `IndentFilter`
+    Indent(IndentFilter),
+    /// A `RadixFilter`
+    Radix(RadixFilter),
+    /// A `NumberFilter`
+    Number(NumberFilter),
+    /// A `RoundFilter`
+    Round(RoundFilter),
+    /// A `CurrencyFilter`
+    Currency(CurrencyFilter),
+    /// A `PercentFilter`
+    Percent(PercentFilter),
+    /// A `BoolFilter`
+    Bool(BoolFilter),
+    /// A `UrlEncodeFilter`
+    UrlEncode(UrlEncodeFilter),
+    /// A `MarkdownEscapeFilter`
+    MarkdownEscape(MarkdownEscapeFilter),
+    /// A `ShellEscapeFilter`
+    ShellEscape(ShellEscapeFilter),
+    /// A `Base64Filter`, only available with the `base64` feature
+    #[cfg(feature = "base64")]
+    Base64(Base64Filter),
+    /// A `DigestFilter`, only available with the `digest` feature
+    #[cfg(feature = "digest")]
+    Digest(DigestFilter),
+}
+
+impl SerializedFilter {
+    /// Rebuilds the filter this was serialized from.
+    pub fn into_filter(self) -> Box<dyn Filter> {
+        match self {
+            Self::Align(filter) => Box::new(filter),
+            Self::Truncate(filter) => Box::new(filter),
+            Self::Slice(filter) => Box::new(filter),
+            Self::Length(filter) => Box::new(filter),
+            Self::Reverse(filter) => Box::new(filter),
+            Self::Repeat(filter) => Box::new(filter),
+            Self::Squish(filter) => Box::new(filter),
+            Self::Replace(filter) => Box::new(filter),
+            Self::Strip(filter) => Box::new(filter),
+            Self::Indent(filter) => Box::new(filter),
+            Self::Radix(filter) => Box::new(filter),
+            Self::Number(filter) => Box::new(filter),
+            Self::Round(filter) => Box::new(filter),
+            Self::Currency(filter) => Box::new(filter),
+            Self::Percent(filter) => Box::new(filter),
+            Self::Bool(filter) => Box::new(filter),
+            Self::UrlEncode(filter) => Box::new(filter),
+            Self::MarkdownEscape(filter) => Box::new(filter),
+            Self::ShellEscape(filter) => Box::new(filter),
+            #[cfg(feature = "base64")]
+            Self::Base64(filter) => Box::new(filter),
+            #[cfg(feature = "digest")]
+            Self::Digest(filter) => Box::new(filter),
+        }
+    }
+}
+
+/// The serialized form of a `Node`.
+#[derive(Serialize, Deserialize)]
+struct SerializedNode {
+    kind: SegmentKind,
+    span: Range<usize>,
+    source: Option<Box<str>>,
+    directive: SerializedDirective,
+}
+
+impl Serialize for Fragment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let nodes = self
+            .segments()
+            .map(|segment| {
+                let directive = segment.directive().to_serialized().ok_or_else(|| {
+                    S::Error::custom("template contains a directive that can't be serialized")
+                })?;
+
+                Ok(SerializedNode {
+                    kind: segment.kind(),
+                    span: segment.span(),
+                    source: segment.source().map(Box::from),
+                    directive,
+                })
+            })
+            .collect::<Result<Vec<_>, S::Error>>()?;
+
+        nodes.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fragment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let nodes = Vec::<SerializedNode>::deserialize(deserializer)?
+            .into_iter()
+            .map(|node| {
+                Node::from_parts(
+                    node.directive.into_directive(),
+                    node.kind,
+                    node.span,
+                    node.source,
+                )
+            })
+            .collect();
+
+        Ok(Self::new(nodes))
+    }
+}
+
+/// The serialized form of a `Template`: its delimiters and its body.
+#[derive(Serialize, Deserialize)]
+struct SerializedTemplate<T> {
+    delimiters: (String, String),
+    body: T,
+}
+
+impl<const O: char, const C: char> Serialize for Template<O, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let delimiters = match &self.delimiters {
+            Some(delimiters) => (delimiters.open().into(), delimiters.close().into()),
+            None => (O.to_string(), C.to_string()),
+        };
+
+        SerializedTemplate {
+            delimiters,
+            body: &self.body,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Fails if the serialized delimiters don't match the target type, since
+/// the template's spans and escapes were computed for the original ones.
+/// Templates compiled with `Template::with_delimiters` deserialize into
+/// `Template<'{', '}'>`, the type that constructor returns.
+impl<'de, const O: char, const C: char> Deserialize<'de> for Template<O, C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::validate_delimiters().map_err(D::Error::custom)?;
+        let template = SerializedTemplate::<Fragment>::deserialize(deserializer)?;
+        let (open, close) = template.delimiters;
+
+        let delimiters = if open.chars().eq([O]) && close.chars().eq([C]) {
+            None
+        } else if (O, C) == ('{', '}') {
+            Some(Delimiters::new(open, close).map_err(D::Error::custom)?)
+        } else {
+            return Err(D::Error::custom(format!(
+                "template was compiled with delimiters '{}' '{}', not '{}' '{}'",
+                open, close, O, C
+            )));
+        };
+
+        Ok(Self::from_body(template.body, delimiters, None))
+    }
+}
+
+/// Serializes a compiled regex as its pattern, compiling it again on the way
+/// back in.
+#[cfg(feature = "regex")]
+pub(crate) mod regex_serde {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        pattern: &regex::Regex,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(pattern.as_str())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<regex::Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+
+        regex::Regex::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+impl<const O: char, const C: char> Template<O, C> {
+    /// Returns the length, in bytes, of the template serialized with a
+    /// compact binary format, for reserving buffers before serializing many
+    /// templates.
+    ///
+    /// The hint is exact for formats that write integers as varints and
+    /// omit field names, like postcard, and close for fixed-width ones like
+    /// bincode. Text formats like JSON are larger. Nothing is allocated:
+    /// the template is walked once and the bytes are counted. Templates with
+    /// custom directives or filters that can't be serialized return 0.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Template;
+    ///
+    /// let short = Template::<'{', '}'>::compile("Hi {name}").unwrap();
+    /// let long = Template::<'{', '}'>::compile("Hi {name}, you have {count} new messages").unwrap();
+    ///
+    /// assert!(short.serialized_size_hint() > 0);
+    /// assert!(short.serialized_size_hint() < long.serialized_size_hint());
+    /// ```
+    pub fn serialized_size_hint(&self) -> usize {
+        let mut counter = SizeCounter(0);
+
+        match self.serialize(&mut counter) {
+            Ok(()) => counter.0,
+            Err(Unserializable) => 0,
+        }
+    }
+}
+
+/// The length of `n` as a LEB128 varint.
+fn varint_len(n: u128) -> usize {
+    let bits = 128 - (n | 1).leading_zeros() as usize;
+    bits.div_ceil(7)
+}
+
+/// The length of `n` as a zigzag-encoded varint.
+fn signed_varint_len(n: i128) -> usize {
+    varint_len(((n << 1) ^ (n >> 127)) as u128)
+}
+
+/// Something in the template couldn't be serialized.
+#[derive(Debug)]
+struct Unserializable;
+
+impl fmt::Display for Unserializable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unserializable")
+    }
+}
+
+impl std::error::Error for Unserializable {}
+
+impl serde::ser::Error for Unserializable {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        Self
+    }
+}
+
+/// A serializer that counts the bytes a postcard-style encoding would take,
+/// without writing them: varint integers and lengths, variant indices
+/// instead of names, and structs as their fields in order.
+struct SizeCounter(usize);
+
+impl SizeCounter {
+    fn add(&mut self, len: usize) -> Result<(), Unserializable> {
+        self.0 = self.0.saturating_add(len);
+        Ok(())
+    }
+}
+
+impl Serializer for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, _: bool) -> Result<(), Unserializable> {
+        self.add(1)
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<(), Unserializable> {
+        self.add(1)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Unserializable> {
+        self.add(signed_varint_len(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Unserializable> {
+        self.add(signed_varint_len(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Unserializable> {
+        self.add(signed_varint_len(v.into()))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Unserializable> {
+        self.add(signed_varint_len(v))
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<(), Unserializable> {
+        self.add(1)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Unserializable> {
+        self.add(varint_len(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Unserializable> {
+        self.add(varint_len(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Unserializable> {
+        self.add(varint_len(v.into()))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Unserializable> {
+        self.add(varint_len(v))
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<(), Unserializable> {
+        self.add(4)
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<(), Unserializable> {
+        self.add(8)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Unserializable> {
+        self.add(1 + v.len_utf8())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Unserializable> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Unserializable> {
+        self.add(varint_len(v.len() as u128) + v.len())
+    }
+
+    fn serialize_none(self) -> Result<(), Unserializable> {
+        self.add(1)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Unserializable> {
+        self.add(1)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Unserializable> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+    ) -> Result<(), Unserializable> {
+        self.serialize_u32(index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Unserializable> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Unserializable> {
+        self.add(varint_len(index.into()))?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Unserializable> {
+        self.add(varint_len(len.unwrap_or(0) as u128))?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self, Unserializable> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, Unserializable> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self, Unserializable> {
+        self.add(varint_len(index.into()))?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Unserializable> {
+        self.serialize_seq(len)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Unserializable> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, Unserializable> {
+        self.serialize_tuple_variant(name, index, variant, len)
+    }
+}
+
+impl ser::SerializeSeq for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Unserializable> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut SizeCounter {
+    type Ok = ();
+    type Error = Unserializable;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Unserializable> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Unserializable> {
+        Ok(())
+    }
+}
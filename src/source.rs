@@ -0,0 +1,500 @@
+//! Writing compiled templates back as source.
+//!
+//! `Template::to_source` writes a template in the syntax of `DefaultParser`.
+//! Text and escapes are written by the template, and directives write their
+//! contents through `Directive::to_source`.
+
+use crate::{
+    Argument, CaseLabel, Expression, Filter, Fragment, LoopDirective, SegmentKind, SwitchDirective,
+    context::is_scoped, lexer::is_plain_ident,
+};
+
+/// A writer for the source returned by `Template::to_source`.
+///
+/// It knows the delimiters of the template being written, which quoted
+/// literals and nested sub-templates have to escape. Directives write their
+/// arguments with `value` and `condition`, and text with `quote`.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceWriter<'d> {
+    open: &'d str,
+    close: &'d str,
+}
+
+impl<'d> SourceWriter<'d> {
+    /// Creates a writer for a template with the given delimiters.
+    pub(crate) fn new(open: &'d str, close: &'d str) -> Self {
+        Self { open, close }
+    }
+
+    /// Writes text as a single-quoted literal, escaping quotes, backslashes
+    /// and the delimiters.
+    pub fn quote(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len() + 2);
+
+        out.push('\'');
+        self.escape(text, &[self.open, self.close, "\\", "'"], &mut out);
+        out.push('\'');
+
+        out
+    }
+
+    /// Writes the source of a sub-template as a single-quoted literal. Its
+    /// escaped delimiters are written as they are, since a literal keeps
+    /// them for the sub-template, while other backslashes and quotes are
+    /// escaped.
+    fn quote_template(&self, source: &str) -> String {
+        let mut out = String::with_capacity(source.len() + 2);
+        let mut rest = source;
+
+        out.push('\'');
+        while let Some(c) = rest.chars().next() {
+            let delimiter = rest.strip_prefix('\\').and_then(|escaped| {
+                [self.open, self.close]
+                    .into_iter()
+                    .find(|delimiter| escaped.starts_with(delimiter))
+            });
+
+            if let Some(delimiter) = delimiter {
+                out.push('\\');
+                out.push_str(delimiter);
+                rest = &rest[1 + delimiter.len()..];
+                continue;
+            }
+
+            if matches!(c, '\\' | '\'') {
+                out.push('\\');
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        out.push('\'');
+
+        out
+    }
+
+    /// Writes a variable name, in backticks unless it is a plain identifier
+    /// or a scoped one such as `@value`. Returns `None` for names holding a
+    /// backtick, which can't be quoted.
+    pub fn name(&self, name: &str) -> Option<String> {
+        match is_plain_ident(name) || is_scoped(name) {
+            true => Some(name.to_string()),
+            false if name.contains('`') => None,
+            false => Some(format!("`{}`", name)),
+        }
+    }
+
+    /// Writes an argument as a value, as in `{name}`, `{first + last}` or the
+    /// arguments of a function call. Sub-templates are written as quoted
+    /// literals holding their source.
+    ///
+    /// Returns `None` for expressions that are only valid in conditions, such
+    /// as comparisons, and for sums whose grouping can't be written.
+    pub fn value(&self, arg: &Argument) -> Option<String> {
+        let Argument::Expression(expr) = arg else {
+            return self.term(arg);
+        };
+
+        match expr.as_ref() {
+            Expression::Add { left, right } => {
+                let left = match left {
+                    Argument::Expression(expr) if matches!(**expr, Expression::Add { .. }) => {
+                        self.value(left)?
+                    }
+                    _ => self.term(left)?,
+                };
+
+                Some(format!("{} + {}", left, self.term(right)?))
+            }
+            Expression::Coalesce(args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Argument::Expression(expr) if matches!(**expr, Expression::Add { .. }) => {
+                            self.value(arg)
+                        }
+                        _ => self.term(arg),
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+
+                Some(args.join(" ?? "))
+            }
+            _ => self.term(arg),
+        }
+    }
+
+    /// Writes the value a directive renders, as in `{name}` or
+    /// `{first + last:len}`, where a lone literal is quoted even if it is a
+    /// number.
+    pub(crate) fn subject(&self, arg: &Argument) -> Option<String> {
+        match arg {
+            Argument::Literal(text) => Some(self.quote(text)),
+            _ => self.value(arg),
+        }
+    }
+
+    /// Writes a term of a sum: a variable, a number, a literal, a
+    /// sub-template, a call or a switch.
+    fn term(&self, arg: &Argument) -> Option<String> {
+        match arg {
+            Argument::Variable(name) => self.name(name),
+            Argument::Literal(text) if is_number(text) => Some(text.to_string()),
+            Argument::Literal(text) => Some(self.quote(text)),
+            Argument::Template(fragment) => Some(self.quote_template(&self.fragment(fragment)?)),
+            Argument::Expression(expr) => match expr.as_ref() {
+                Expression::Call { function, args } => {
+                    let args = args
+                        .iter()
+                        .map(|arg| self.value(arg))
+                        .collect::<Option<Vec<_>>>()?;
+
+                    Some(format!("{}({})", function.name(), args.join(", ")))
+                }
+                Expression::IsType { test, value } => {
+                    Some(format!("{}({})", test.name(), self.value(value)?))
+                }
+                Expression::Switch(switch) => self.switch(switch),
+                Expression::Index { value, index } => {
+                    Some(format!("{}[{}]", self.term(value)?, self.term(index)?))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Writes an argument as the condition of a conditional, as in
+    /// `{a || b && !c ? yes}`, with parentheses where grouping requires them.
+    pub fn condition(&self, arg: &Argument) -> Option<String> {
+        match arg {
+            Argument::Expression(expr) => match expr.as_ref() {
+                Expression::Or(args) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| match arg {
+                            Argument::Expression(expr) if matches!(**expr, Expression::Or(_)) => {
+                                Some(format!("({})", self.condition(arg)?))
+                            }
+                            _ => self.condition(arg),
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+
+                    Some(args.join(" || "))
+                }
+                Expression::And(args) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| match arg {
+                            Argument::Expression(expr)
+                                if matches!(**expr, Expression::And(_) | Expression::Or(_)) =>
+                            {
+                                Some(format!("({})", self.condition(arg)?))
+                            }
+                            _ => self.test(arg),
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+
+                    Some(args.join(" && "))
+                }
+                _ => self.test(arg),
+            },
+            _ => self.test(arg),
+        }
+    }
+
+    /// Writes a single test of a condition, such as `age >= 18`.
+    fn test(&self, arg: &Argument) -> Option<String> {
+        let expr = match arg {
+            Argument::Expression(expr) => expr,
+            // Not every number is a test of its own, but every literal is
+            Argument::Literal(text) => return Some(self.quote(text)),
+            _ => return self.term(arg),
+        };
+
+        match expr.as_ref() {
+            Expression::Comparison { left, op, right } => Some(format!(
+                "{} {} {}",
+                self.comparand(left)?,
+                op.symbol(),
+                self.comparand(right)?
+            )),
+            Expression::Not(_) => self.comparand(arg),
+            #[cfg(feature = "regex")]
+            Expression::Matches { left, pattern } => {
+                let left = match left {
+                    Argument::Variable(name) => self.name(name)?,
+                    Argument::Literal(text) => self.quote(text),
+                    _ => return None,
+                };
+
+                Some(format!("{} =~ {}", left, self.quote(pattern.as_str())))
+            }
+            Expression::InRange { value, low, high } => Some(format!(
+                "{} in {}..{}",
+                self.value(value)?,
+                self.value(low)?,
+                self.value(high)?
+            )),
+            Expression::InList { value, items } => {
+                let items = items
+                    .iter()
+                    .map(|item| self.value(item))
+                    .collect::<Option<Vec<_>>>()?;
+
+                Some(format!("{} in [{}]", self.value(value)?, items.join(", ")))
+            }
+            Expression::Defined(name) => Some(format!("defined({})", self.name(name)?)),
+            Expression::Add { .. } | Expression::Coalesce(_) => {
+                Some(format!("({})", self.value(arg)?))
+            }
+            Expression::And(_) | Expression::Or(_) => Some(format!("({})", self.condition(arg)?)),
+            _ => self.term(arg),
+        }
+    }
+
+    /// Writes a side of a comparison or the operand of `!`, in parentheses
+    /// unless it is a value or a negation.
+    fn comparand(&self, arg: &Argument) -> Option<String> {
+        let Argument::Expression(expr) = arg else {
+            return self.term(arg);
+        };
+
+        match expr.as_ref() {
+            Expression::Not(negated) => match negated {
+                Argument::Expression(expr)
+                    if matches!(**expr, Expression::Add { .. } | Expression::Coalesce(_)) =>
+                {
+                    Some(format!("!({})", self.value(negated)?))
+                }
+                _ => Some(format!("!{}", self.comparand(negated)?)),
+            },
+            Expression::Call { .. }
+            | Expression::IsType { .. }
+            | Expression::Switch(_)
+            | Expression::Index { .. }
+            | Expression::Add { .. }
+            | Expression::Coalesce(_) => self.value(arg),
+            _ => Some(format!("({})", self.condition(arg)?)),
+        }
+    }
+
+    /// Writes an argument that must be a single token, such as a branch of
+    /// a conditional: a variable, or a literal or sub-template, quoted.
+    pub(crate) fn branch(&self, arg: &Argument) -> Option<String> {
+        match arg {
+            Argument::Literal(text) => Some(self.quote(text)),
+            Argument::Expression(_) => None,
+            _ => self.term(arg),
+        }
+    }
+
+    /// Writes a switch, `[scrutinee|default](label:body)..`.
+    pub(crate) fn switch(&self, switch: &SwitchDirective) -> Option<String> {
+        let mut text = format!("[{}", self.branch(&switch.scrutinee)?);
+
+        if let Some(default) = &switch.default {
+            text.push('|');
+            text.push_str(&self.branch(default)?);
+        }
+        text.push(']');
+
+        for case in &switch.cases {
+            let label = match &case.label {
+                CaseLabel::Value(label) => self.label(label),
+                CaseLabel::Prefix(prefix) => format!("{}*", self.label(prefix)),
+                label => label.to_string(),
+            };
+
+            let body = match &case.body {
+                Argument::Literal(text) => text.to_string(),
+                Argument::Template(fragment) => self.fragment(fragment)?,
+                _ => return None,
+            };
+
+            text.push_str(&format!("({}:{})", label, self.case_body(&body)));
+        }
+
+        Some(text)
+    }
+
+    /// Writes a loop, `items*body`, with its body quoted.
+    pub(crate) fn loop_over(&self, looping: &LoopDirective) -> Option<String> {
+        Some(format!(
+            "{}*{}",
+            self.term(&looping.items)?,
+            self.branch(&looping.body)?
+        ))
+    }
+
+    /// Writes text that may be a bare word, such as a case label or the
+    /// name of a partial, quoted unless it is a single word or number.
+    pub(crate) fn word(&self, text: &str) -> String {
+        let word = text
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+            && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && (text.chars().all(|c| c.is_ascii_digit())
+                || !text.starts_with(|c: char| c.is_ascii_digit()));
+
+        match word {
+            true => text.to_string(),
+            false => self.quote(text),
+        }
+    }
+
+    /// Writes a case label, escaping `:`, `(`, `)` and `\` with a backslash
+    /// when the rest of it is a word, such as `a\:b`, or else as a word.
+    fn label(&self, text: &str) -> String {
+        let special = |c: char| matches!(c, ':' | '(' | ')' | '\\');
+        let escapable = text.contains(special)
+            && !self.open.contains(special)
+            && !self.close.contains(special)
+            && text.starts_with(|c: char| c.is_ascii_alphanumeric() || special(c))
+            && text
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || special(c));
+
+        if !escapable {
+            return self.word(text);
+        }
+
+        let mut out = String::with_capacity(text.len() + 2);
+
+        for c in text.chars() {
+            if special(c) {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+
+        out
+    }
+
+    /// Returns the contents of a directive's source, without the delimiters
+    /// around it, or `None` if it isn't enclosed in them.
+    pub(crate) fn contents<'t>(&self, source: &'t str) -> Option<&'t str> {
+        source.strip_prefix(self.open)?.strip_suffix(self.close)
+    }
+
+    /// Escapes the source of a case body, which is raw text up to the
+    /// closing parenthesis. Parentheses are escaped, and so are backslashes
+    /// that would otherwise escape one, while escaped delimiters are kept.
+    fn case_body(&self, body: &str) -> String {
+        let mut out = String::with_capacity(body.len());
+        let mut rest = body;
+
+        while let Some(c) = rest.chars().next() {
+            let after = &rest[c.len_utf8()..];
+            rest = after;
+
+            if c != '\\' {
+                if matches!(c, '(' | ')') {
+                    out.push('\\');
+                }
+                out.push(c);
+                continue;
+            }
+
+            if let Some(unit) = ["\\", self.open, self.close]
+                .into_iter()
+                .find(|unit| after.starts_with(unit))
+            {
+                // An escaped backslash is escaped as two, and escaped
+                // delimiters are kept
+                match unit {
+                    "\\" => out.push_str("\\\\\\\\"),
+                    delimiter => {
+                        out.push('\\');
+                        out.push_str(delimiter);
+                    }
+                }
+                rest = &after[unit.len()..];
+            } else if after.is_empty() || after.starts_with(['(', ')']) {
+                out.push_str("\\\\");
+            } else {
+                out.push('\\');
+            }
+        }
+
+        out
+    }
+
+    /// Writes a chain of filters by their `Filter::describe` specs, joined
+    /// by pipes, escaping the delimiters in them.
+    pub(crate) fn filters(&self, filters: &[Box<dyn Filter>]) -> String {
+        let specs: Vec<_> = filters
+            .iter()
+            .map(|filter| {
+                let mut spec = String::new();
+                self.escape(&filter.describe(), &[self.open, self.close], &mut spec);
+                spec
+            })
+            .collect();
+
+        specs.join(" | ")
+    }
+
+    /// Writes the source of a fragment: text with its delimiters and
+    /// backslashes escaped, escapes, and directives between delimiters.
+    pub(crate) fn fragment(&self, fragment: &Fragment) -> Option<String> {
+        let mut out = String::new();
+
+        for segment in fragment.segments() {
+            let directive = segment.directive();
+
+            match segment.kind() {
+                // A lone closing delimiter or backslash is only text when
+                // written as-is; escaped, it would compile as an escape
+                SegmentKind::Text => match directive.literal()? {
+                    text if text == self.close || text == "\\" => out.push_str(text),
+                    text => self.escape(text, &[self.open, self.close, "\\"], &mut out),
+                },
+                SegmentKind::Escape => {
+                    let text = directive.literal()?;
+
+                    if [self.open, self.close, "\\"].contains(&text) {
+                        out.push('\\');
+                    }
+                    out.push_str(text);
+                }
+                SegmentKind::Directive => {
+                    out.push_str(self.open);
+                    out.push_str(&directive.to_source(self)?);
+                    out.push_str(self.close);
+                }
+            }
+        }
+
+        Some(out)
+    }
+
+    /// Writes `text` to `out`, with a backslash before every occurrence of
+    /// `escaped`.
+    fn escape(&self, text: &str, escaped: &[&str], out: &mut String) {
+        let mut rest = text;
+
+        while let Some(c) = rest.chars().next() {
+            match escaped.iter().find(|escaped| rest.starts_with(**escaped)) {
+                Some(escaped) => {
+                    out.push('\\');
+                    out.push_str(escaped);
+                    rest = &rest[escaped.len()..];
+                }
+                None => {
+                    out.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether `text` is written as a number, such as `42`, `-5` or
+/// `1.5`, rather than quoted.
+fn is_number(text: &str) -> bool {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, "0"));
+
+    [int, frac]
+        .iter()
+        .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
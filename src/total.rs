@@ -0,0 +1,111 @@
+//! Templates whose rendering can't fail.
+//!
+//! Most directives can fail while rendering, on a missing variable or a value
+//! of the wrong type, so `Template::format` returns a `Result`. Templates made
+//! only of text and plain substitutions can be rendered without one, by
+//! rendering something in place of the values that would fail.
+
+use crate::{
+    Argument, ContextLookup, FormatOptions, RenderContext, RenderState, ReplaceDirective,
+    SegmentKind, Template,
+};
+use std::any::Any;
+use std::borrow::Cow;
+
+/// A view of a template that renders without errors. Created by
+/// `Template::total`, which only offers it for templates made of:
+///
+/// - literal text and escaped delimiters, such as `{{`;
+/// - plain substitutions of a variable, `{name}`, or of a literal, `{'-'}`.
+///
+/// Any other directive, including filters, conditionals, includes and
+/// directives the parser didn't recognize, can fail in ways that have no
+/// sensible replacement, so templates containing one are not total.
+/// `Template::optimize` first turns constant directives such as `{'-':3}`
+/// into text, which can make a template total.
+///
+/// A substitution can then only fail when its variable is missing, or holds
+/// `Value::Bytes` or `Value::Array`, which have no text form. `format`
+/// renders the missing text in place of such substitutions: nothing by
+/// default, or the text given to `with_missing`.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::{Context, Template, Value};
+///
+/// let tmpl = Template::<'{', '}'>::compile("Hi {name}!").unwrap();
+/// let total = tmpl.total().unwrap().with_missing("?");
+///
+/// let ctx = Context::from([("name", Value::static_str("Ada"))]);
+/// assert_eq!(total.format(&ctx), "Hi Ada!");
+/// assert_eq!(total.format(&Context::new()), "Hi ?!");
+/// ```
+pub struct TotalTemplate<'a, const O: char, const C: char> {
+    template: &'a Template<O, C>,
+    missing: Cow<'a, str>,
+}
+
+impl<'a, const O: char, const C: char> TotalTemplate<'a, O, C> {
+    /// Returns a view of `template` if it is total, see `TotalTemplate`.
+    pub(crate) fn new(template: &'a Template<O, C>) -> Option<Self> {
+        template
+            .iter()
+            .all(|segment| {
+                let any: &dyn Any = segment.directive();
+
+                segment.kind() != SegmentKind::Directive
+                    || matches!(
+                        any.downcast_ref(),
+                        Some(ReplaceDirective(
+                            Argument::Variable(_) | Argument::Literal(_)
+                        ))
+                    )
+            })
+            .then_some(Self {
+                template,
+                missing: Cow::Borrowed(""),
+            })
+    }
+
+    /// Sets the text rendered in place of missing variables and of variables
+    /// holding bytes.
+    pub fn with_missing(mut self, missing: impl Into<Cow<'a, str>>) -> Self {
+        self.missing = missing.into();
+        self
+    }
+
+    /// Returns the template this view renders.
+    pub fn template(&self) -> &'a Template<O, C> {
+        self.template
+    }
+
+    /// Returns the text rendered in place of substitutions that fail.
+    pub fn missing(&self) -> &str {
+        &self.missing
+    }
+
+    /// Renders the template with `ctx`.
+    ///
+    /// Values are rendered as by `Template::format`, with the default
+    /// `FormatOptions`.
+    pub fn format(&self, ctx: &dyn ContextLookup) -> String {
+        let state = RenderState::unlimited();
+        let ctx = RenderContext::new(ctx, &state);
+        let capacity = self
+            .template
+            .estimated_len()
+            .min(FormatOptions::DEFAULT_MAX_OUTPUT_LEN);
+        let mut output = String::with_capacity(capacity);
+
+        for segment in self.template.iter() {
+            // Substitutions fail before writing anything, and writing to a
+            // `String` never fails
+            if segment.directive().write_to(&ctx, &mut output).is_err() {
+                output.push_str(&self.missing);
+            }
+        }
+
+        output
+    }
+}
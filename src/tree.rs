@@ -0,0 +1,174 @@
+//! Indented tree dumps of compiled templates.
+//!
+//! `Template::debug_tree` renders the parsed representation of a template as
+//! an indented tree, one node per line. Directives describe themselves through
+//! `Directive::write_tree`.
+
+use crate::{Argument, Expression, Fragment};
+
+/// The number of spaces each tree level is indented by.
+const INDENT: usize = 2;
+
+/// A writer for the indented tree returned by `Template::debug_tree`.
+///
+/// Directives write one line per node with `leaf`, open nested levels with
+/// `branch` and describe their arguments with `argument`, which expands
+/// nested sub-templates into their own subtrees.
+#[derive(Debug, Default)]
+pub struct DebugTree {
+    out: String,
+    depth: usize,
+}
+
+impl DebugTree {
+    /// Creates an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes a single node at the current level.
+    pub fn leaf(&mut self, text: impl AsRef<str>) {
+        for _ in 0..self.depth * INDENT {
+            self.out.push(' ');
+        }
+
+        self.out.push_str(text.as_ref());
+        self.out.push('\n');
+    }
+
+    /// Writes a node and the children written by `children` one level deeper.
+    pub fn branch(&mut self, text: impl AsRef<str>, children: impl FnOnce(&mut Self)) {
+        self.leaf(text);
+        self.depth += 1;
+        children(self);
+        self.depth -= 1;
+    }
+
+    /// Writes an argument as `label: value`.
+    ///
+    /// Variables are written by name and literals quoted. Sub-templates are
+    /// written as a `label: Template` branch with their directives below it.
+    pub fn argument(&mut self, label: &str, arg: &Argument) {
+        match arg {
+            Argument::Template(fragment) => self.branch(format!("{}: Template", label), |tree| {
+                tree.fragment(fragment)
+            }),
+            _ => self.leaf(format!("{}: {}", label, describe_argument(arg))),
+        }
+    }
+
+    /// Writes every directive of a fragment at the current level.
+    pub(crate) fn fragment(&mut self, fragment: &Fragment) {
+        for directive in fragment.directives() {
+            directive.write_tree(self);
+        }
+    }
+
+    /// Returns the tree written so far.
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Describes an operand of `!`, `&&` or `||`, in parentheses if it is itself
+/// a conjunction or a disjunction, so that the grouping shows.
+fn describe_operand(arg: &Argument) -> String {
+    match arg {
+        Argument::Expression(expr) if matches!(**expr, Expression::And(_) | Expression::Or(_)) => {
+            format!("({})", describe_argument(arg))
+        }
+        _ => describe_argument(arg),
+    }
+}
+
+/// Describes an argument on a single line, close to its template syntax.
+fn describe_argument(arg: &Argument) -> String {
+    match arg {
+        Argument::Variable(name) => name.to_string(),
+        Argument::Literal(text) => format!("{:?}", text),
+        Argument::Expression(expr) => match expr.as_ref() {
+            Expression::Comparison { left, op, right } => format!(
+                "{} {} {}",
+                describe_argument(left),
+                op.symbol(),
+                describe_argument(right)
+            ),
+            Expression::Not(arg) => match arg {
+                // Comparisons and the like bind more loosely than `!`
+                Argument::Expression(negated)
+                    if !matches!(
+                        **negated,
+                        Expression::Not(_)
+                            | Expression::Call { .. }
+                            | Expression::Defined(_)
+                            | Expression::IsType { .. }
+                            | Expression::Index { .. }
+                    ) =>
+                {
+                    format!("!({})", describe_argument(arg))
+                }
+                _ => format!("!{}", describe_argument(arg)),
+            },
+            #[cfg(feature = "regex")]
+            Expression::Matches { left, pattern } => {
+                format!("{} =~ {:?}", describe_argument(left), pattern.as_str())
+            }
+            Expression::Call { function, args } => {
+                let args: Vec<_> = args.iter().map(describe_argument).collect();
+                format!("{}({})", function.name(), args.join(", "))
+            }
+            Expression::Add { left, right } => {
+                format!("{} + {}", describe_argument(left), describe_argument(right))
+            }
+            Expression::Index { value, index } => {
+                format!("{}[{}]", describe_argument(value), describe_argument(index))
+            }
+            Expression::InRange { value, low, high } => format!(
+                "{} in {}..{}",
+                describe_argument(value),
+                describe_argument(low),
+                describe_argument(high)
+            ),
+            Expression::InList { value, items } => {
+                let items: Vec<_> = items.iter().map(describe_argument).collect();
+                format!("{} in [{}]", describe_argument(value), items.join(", "))
+            }
+            Expression::Defined(name) => format!("defined({})", name),
+            Expression::IsType { test, value } => {
+                format!("{}({})", test.name(), describe_argument(value))
+            }
+            Expression::Coalesce(args) => {
+                let args: Vec<_> = args.iter().map(describe_argument).collect();
+                args.join(" ?? ")
+            }
+            Expression::And(args) => {
+                let args: Vec<_> = args.iter().map(describe_operand).collect();
+                args.join(" && ")
+            }
+            Expression::Or(args) => {
+                let args: Vec<_> = args.iter().map(describe_operand).collect();
+                args.join(" || ")
+            }
+            Expression::Switch(switch) => {
+                // Defaults and case bodies are written as in templates
+                let raw = |arg: &Argument| match arg {
+                    Argument::Literal(text) => text.to_string(),
+                    arg => describe_argument(arg),
+                };
+
+                let mut text = format!("[{}", describe_argument(&switch.scrutinee));
+                if let Some(default) = &switch.default {
+                    text.push_str(&format!("|{}", raw(default)));
+                }
+                text.push(']');
+
+                for case in &switch.cases {
+                    text.push_str(&format!("({}:{})", case.label, raw(&case.body)));
+                }
+
+                text
+            }
+        },
+        Argument::Template(_) => "<template>".to_string(),
+    }
+}
@@ -0,0 +1,768 @@
+//! Runtime values stored in the template context.
+//!
+//! This module defines the `Value` type along with the conversions that let
+//! plain Rust values be inserted into a context with `.into()`.
+
+use crate::ValueTypeError;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::Infallible;
+use std::fmt;
+use std::num::TryFromIntError;
+use std::str::FromStr;
+
+/// A runtime value that can be stored in the template context.
+///
+/// Values can be strings, integers, floats, booleans, bytes, arrays, or null. The type system
+/// automatically handles conversions where appropriate (e.g., converting
+/// integers to strings for display).
+///
+/// Most Rust primitives convert into a `Value` with `From`/`Into`. Integer
+/// types that may not fit in an `i64` (`u64`, `usize`, `isize`, `i128`, `u128`)
+/// only implement `TryFrom`, so an out-of-range value is reported instead of
+/// silently wrapping.
+///
+/// The other way around, `i64`, `f64`, `String` and `bool` implement
+/// `TryFrom<Value>` and `TryFrom<&Value>`. These conversions are strict, like
+/// `as_int` and its siblings, and fail with a `ValueTypeError` naming both
+/// types. `to_int_lossy`, `to_float_lossy`, `to_str_lossy` and `is_truthy`
+/// convert leniently, the way directives do.
+///
+/// # Examples
+///
+/// ```rust
+/// use figura::Value;
+///
+/// let s = Value::static_str("hello");
+/// let i = Value::Int(42);
+/// let f = Value::Float(3.14);
+/// let b = Value::Bool(true);
+///
+/// let s: Value = "hello".into();
+/// let i: Value = 42u8.into();
+/// let n: Value = None::<i32>.into();
+/// let a: Value = vec![1, 2, 3].into();
+/// let big = Value::try_from(u64::MAX);
+/// assert!(big.is_err());
+///
+/// assert_eq!(i64::try_from(Value::Int(42)), Ok(42));
+/// assert_eq!(f64::try_from(&Value::Int(2)), Ok(2.0));
+/// assert!(bool::try_from(Value::Int(1)).is_err());
+/// ```
+///
+/// The default value is `Null`, which renders as an empty string and is
+/// falsy, so structs holding a `Value` can derive `Default` and resolvers
+/// can fall back with `unwrap_or_default()`:
+///
+/// ```rust
+/// use figura::Value;
+///
+/// assert!(Value::default().is_null());
+/// assert_eq!(Value::default().to_string(), "");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub enum Value<'a> {
+    /// A string value (can be borrowed or owned)
+    ///
+    /// This is the only string variant: `Value::static_str` borrows a
+    /// `&'static str`, `Value::borrowed_str` any string that outlives the
+    /// value and `Value::owned_str` takes a `String`, but they only differ
+    /// in whether the text was copied. Directives, filters and
+    /// comparisons look at the contents alone.
+    Str(Cow<'a, str>),
+    /// A 64-bit signed integer
+    Int(i64),
+    /// A 64-bit floating point number
+    Float(f64),
+    /// A boolean value
+    Bool(bool),
+    /// Binary data, such as a hash or a token
+    ///
+    /// Bytes have no default text form: templates render them only through
+    /// an encoding filter, `{sig:hex}` or `{sig:base64}`, and fail with a
+    /// type error when they are used bare, except with
+    /// `Template::format_bytes`, which writes them as raw bytes.
+    Bytes(Vec<u8>),
+    /// A list of values, such as the items of an order
+    ///
+    /// Like bytes, arrays have no default text form and fail with a type
+    /// error when they are used bare. They are true when non-empty and
+    /// `{items:len}` counts their elements.
+    Array(Vec<Self>),
+    /// The absence of a value
+    ///
+    /// Renders as an empty string and is falsy in conditionals. This is
+    /// the default value.
+    #[default]
+    Null,
+}
+
+impl<'a> Value<'a> {
+    /// Create a static string value (zero-cost)
+    pub fn static_str(s: &'static str) -> Self {
+        Self::Str(Cow::Borrowed(s))
+    }
+
+    /// Create an owned string value
+    pub fn owned_str(s: String) -> Self {
+        Self::Str(Cow::Owned(s))
+    }
+
+    /// Create a string value borrowing `s` for the value's lifetime, such as
+    /// a slice of a larger buffer, without copying it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::{Template, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let line = String::from("id=42;name=Ada");
+    ///
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert("name", Value::borrowed_str(&line[11..]));
+    ///
+    /// let tmpl = Template::<'{', '}'>::compile("Hi {name}").unwrap();
+    /// assert_eq!(tmpl.format(&ctx).unwrap(), "Hi Ada");
+    /// ```
+    pub fn borrowed_str(s: &'a str) -> Self {
+        Self::Str(Cow::Borrowed(s))
+    }
+
+    /// Converts the value into one that owns its data, copying a borrowed
+    /// string, so it can outlive what it borrowed from.
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Self::Str(s) => Value::Str(Cow::Owned(s.into_owned())),
+            Self::Int(i) => Value::Int(i),
+            Self::Float(f) => Value::Float(f),
+            Self::Bool(b) => Value::Bool(b),
+            Self::Bytes(bytes) => Value::Bytes(bytes),
+            Self::Array(items) => Value::Array(items.into_iter().map(Value::into_owned).collect()),
+            Self::Null => Value::Null,
+        }
+    }
+
+    /// Returns a human-readable name for the value's type.
+    ///
+    /// Used primarily in error messages to indicate type mismatches.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Str(_) => "string",
+            Self::Int(_) => "integer",
+            Self::Float(_) => "float",
+            Self::Bool(_) => "boolean",
+            Self::Bytes(_) => "bytes",
+            Self::Array(_) => "array",
+            Self::Null => "null",
+        }
+    }
+
+    /// Returns whether the value is `Null`.
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    /// Returns the string of a `Str`, or `None` for any other variant.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the integer of an `Int`, or `None` for any other variant.
+    /// See `to_int_lossy` to convert floats, strings and booleans too.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of a `Float` or an `Int`, or `None` for any other
+    /// variant. Integers beyond 2^53 are rounded to the nearest float. See
+    /// `to_float_lossy` to convert strings and booleans too.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Self::Float(f) => Some(*f),
+            Self::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    /// Returns the boolean of a `Bool`, or `None` for any other variant.
+    /// See `is_truthy` to test any value.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Returns the data of `Bytes`, or `None` for any other variant.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Returns the items of an `Array`, or `None` for any other variant.
+    pub fn as_array(&self) -> Option<&[Self]> {
+        match self {
+            Self::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Converts the value to an integer the way directives taking an integer
+    /// do, such as the count of `{'-':n}`.
+    ///
+    /// - `Int` is its own value
+    /// - `Float` is truncated toward zero, saturating at the bounds of `i64`,
+    ///   and `NaN` becomes 0
+    /// - `Str` is parsed as an integer, so `"42"` converts but `"4.2"` and
+    ///   `" 42"` don't
+    /// - `Bool` becomes 0 or 1
+    /// - `Bytes`, `Array` and `Null` don't convert
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Value;
+    ///
+    /// assert_eq!(Value::Float(-2.9).to_int_lossy(), Some(-2));
+    /// assert_eq!(Value::static_str("42").to_int_lossy(), Some(42));
+    /// assert_eq!(Value::static_str("4.2").to_int_lossy(), None);
+    /// assert_eq!(Value::Null.to_int_lossy(), None);
+    /// ```
+    pub fn to_int_lossy(&self) -> Option<i64> {
+        match self {
+            Self::Str(s) => s.parse().ok(),
+            Self::Int(i) => Some(*i),
+            Self::Float(f) => Some(*f as i64),
+            Self::Bool(b) => Some(i64::from(*b)),
+            Self::Bytes(_) | Self::Array(_) | Self::Null => None,
+        }
+    }
+
+    /// Converts the value to a float the way directives taking a float do,
+    /// such as the arguments of `{min(a, b)}`.
+    ///
+    /// - `Float` is its own value
+    /// - `Int` is converted, rounding integers beyond 2^53
+    /// - `Str` is parsed as a float, so `"4.2"`, `"1e5"` and `"inf"` convert
+    ///   but `" 4.2"` doesn't
+    /// - `Bool` becomes 0.0 or 1.0
+    /// - `Bytes`, `Array` and `Null` don't convert
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Value;
+    ///
+    /// assert_eq!(Value::Int(3).to_float_lossy(), Some(3.0));
+    /// assert_eq!(Value::static_str("4.5").to_float_lossy(), Some(4.5));
+    /// assert_eq!(Value::Bool(true).to_float_lossy(), Some(1.0));
+    /// assert_eq!(Value::static_str("abc").to_float_lossy(), None);
+    /// ```
+    pub fn to_float_lossy(&self) -> Option<f64> {
+        match self {
+            Self::Str(s) => s.parse().ok(),
+            Self::Int(i) => Some(*i as f64),
+            Self::Float(f) => Some(*f),
+            Self::Bool(b) => Some(f64::from(u8::from(*b))),
+            Self::Bytes(_) | Self::Array(_) | Self::Null => None,
+        }
+    }
+
+    /// Converts the value to text the way templates render it, or returns
+    /// `None` for `Bytes` and `Array`, which have no text form. `Str` is borrowed and
+    /// `Null` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Value;
+    ///
+    /// assert_eq!(Value::Float(2.5).to_str_lossy().as_deref(), Some("2.5"));
+    /// assert_eq!(Value::Null.to_str_lossy().as_deref(), Some(""));
+    /// assert_eq!(Value::Bytes(vec![1]).to_str_lossy(), None);
+    /// ```
+    pub fn to_str_lossy(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Self::Str(s) => Some(Cow::Borrowed(s)),
+            Self::Bytes(_) | Self::Array(_) => None,
+            _ => Some(Cow::Owned(self.to_string())),
+        }
+    }
+
+    /// Returns whether the value counts as true in a boolean context, such
+    /// as the condition of `{value ? 'yes' : 'no'}`, a negation or the `bool`
+    /// filter.
+    ///
+    /// - `Bool` is its own value
+    /// - `Int` and `Float` are true when non-zero (`NaN` is true)
+    /// - `Str` is true when non-empty, so `"false"` and `"0"` are true
+    /// - `Bytes` and `Array` are true when non-empty
+    /// - `Null` is false
+    ///
+    /// Literals in templates, such as the `0` in `{0 ? 'a' : 'b'}`, are typed
+    /// with `Value::from_str` first, so that literal is false.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Value;
+    ///
+    /// assert!(Value::Int(3).is_truthy());
+    /// assert!(!Value::static_str("").is_truthy());
+    /// assert!(Value::static_str("false").is_truthy());
+    /// assert!(!Value::Array(Vec::new()).is_truthy());
+    /// assert!(!Value::Null.is_truthy());
+    /// ```
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Self::Str(s) => !s.is_empty(),
+            Self::Int(i) => *i != 0,
+            Self::Float(f) => *f != 0.0,
+            Self::Bool(b) => *b,
+            Self::Bytes(b) => !b.is_empty(),
+            Self::Array(items) => !items.is_empty(),
+            Self::Null => false,
+        }
+    }
+
+    /// Returns the length `{value:len}` renders: the number of chars of a
+    /// `Str`, of bytes of `Bytes` and of items of an `Array`, or `None` for
+    /// any other variant.
+    pub(crate) fn length(&self) -> Option<usize> {
+        match self {
+            Self::Str(s) => Some(s.chars().count()),
+            Self::Bytes(bytes) => Some(bytes.len()),
+            Self::Array(items) => Some(items.len()),
+            _ => None,
+        }
+    }
+
+    /// Compares two values in a total order, used by the `Ord` impl and
+    /// agreeing with the ordering operators of templates.
+    ///
+    /// - `Int` and `Float` compare numerically with each other, exactly, so
+    ///   `Int(1) == Float(1.0)` and `Int(i64::MAX) > Float(9e18)`. `NaN` is
+    ///   greater than every other number and equal to itself, and `-0.0`
+    ///   equals `0.0`
+    /// - `Str` compare lexicographically, by their bytes
+    /// - `Bool` compare with `false` before `true`
+    /// - `Bytes` compare lexicographically
+    /// - `Array` compare lexicographically, item by item in this order
+    /// - `Null` equals `Null`
+    /// - Values of different types are ordered by type: `Null`, then `Bool`,
+    ///   then numbers, then `Str`, then `Bytes`, then `Array`
+    ///
+    /// In templates, `{a > b}` and the other ordering operators agree with
+    /// this order for two numbers, two booleans or two byte strings. They
+    /// differ where this order has to be total: values of different types
+    /// don't compare in templates, `NaN` and `Null` are unordered, and two
+    /// strings that both parse as numbers compare as numbers, so `"10" > "9"`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Value;
+    ///
+    /// let mut values = vec![
+    ///     Value::static_str("b"),
+    ///     Value::Float(1.5),
+    ///     Value::Null,
+    ///     Value::Int(2),
+    ///     Value::Bool(true),
+    ///     Value::static_str("a"),
+    /// ];
+    /// values.sort();
+    ///
+    /// assert_eq!(values, [
+    ///     Value::Null,
+    ///     Value::Bool(true),
+    ///     Value::Float(1.5),
+    ///     Value::Int(2),
+    ///     Value::static_str("a"),
+    ///     Value::static_str("b"),
+    /// ]);
+    /// ```
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Str(l), Self::Str(r)) => l.cmp(r),
+            (Self::Bool(l), Self::Bool(r)) => l.cmp(r),
+            (Self::Bytes(l), Self::Bytes(r)) => l.cmp(r),
+            (Self::Array(l), Self::Array(r)) => l.cmp(r),
+            (Self::Null, Self::Null) => Ordering::Equal,
+            (Self::Int(_) | Self::Float(_), Self::Int(_) | Self::Float(_)) => {
+                // Only NaN is unordered, and sorts after every other number
+                compare_numbers(self, other).unwrap_or_else(|| self.is_nan().cmp(&other.is_nan()))
+            }
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
+
+    /// Returns whether two values are the same variant holding the same
+    /// data, so that they render alike. Stricter than `==`, for which
+    /// `Int(1)` equals `Float(1.0)` and `-0.0` equals `0.0`.
+    pub(crate) fn identical(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Float(l), Self::Float(r)) => l.to_bits() == r.to_bits(),
+            (Self::Int(_), Self::Float(_)) | (Self::Float(_), Self::Int(_)) => false,
+            (Self::Array(l), Self::Array(r)) => {
+                l.len() == r.len() && l.iter().zip(r).all(|(l, r)| l.identical(r))
+            }
+            _ => self == other,
+        }
+    }
+
+    /// The position of the value's type in the order of `total_cmp`.
+    fn type_rank(&self) -> u8 {
+        match self {
+            Self::Null => 0,
+            Self::Bool(_) => 1,
+            Self::Int(_) | Self::Float(_) => 2,
+            Self::Str(_) => 3,
+            Self::Bytes(_) => 4,
+            Self::Array(_) => 5,
+        }
+    }
+
+    fn is_nan(&self) -> bool {
+        matches!(self, Self::Float(f) if f.is_nan())
+    }
+
+    /// The error of converting the value into a type it doesn't have.
+    fn type_error(&self, expected: &'static str) -> ValueTypeError {
+        ValueTypeError {
+            expected,
+            found: self.type_name(),
+        }
+    }
+}
+
+/// Compares two numbers exactly, or returns `None` if either is `NaN` or not
+/// a number.
+///
+/// An `Int` and a `Float` are compared without converting the integer to a
+/// float, which would round integers beyond 2^53.
+pub(crate) fn compare_numbers(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => Some(l.cmp(r)),
+        (Value::Float(l), Value::Float(r)) => l.partial_cmp(r),
+        (Value::Int(l), Value::Float(r)) => compare_int_float(*l, *r),
+        (Value::Float(l), Value::Int(r)) => compare_int_float(*r, *l).map(Ordering::reverse),
+        _ => None,
+    }
+}
+
+/// Compares an integer with a float exactly.
+fn compare_int_float(int: i64, float: f64) -> Option<Ordering> {
+    // Every i64 lies in [-2^63, 2^63)
+    const BOUND: f64 = 9_223_372_036_854_775_808.0;
+
+    if float.is_nan() {
+        None
+    } else if float >= BOUND {
+        Some(Ordering::Less)
+    } else if float < -BOUND {
+        Some(Ordering::Greater)
+    } else {
+        // The integer part of the float fits in an i64, and the fraction
+        // breaks ties
+        let whole = float.trunc();
+        let ordering = int
+            .cmp(&(whole as i64))
+            .then_with(|| 0.0.partial_cmp(&(float - whole)).unwrap_or(Ordering::Equal));
+
+        Some(ordering)
+    }
+}
+
+/// Values are equal when `Value::total_cmp` finds them equal, so
+/// `Int(1) == Float(1.0)` and `NaN` equals itself.
+impl PartialEq for Value<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value<'_> {}
+
+impl PartialOrd for Value<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders values with `Value::total_cmp`.
+impl Ord for Value<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.total_cmp(other)
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    /// Renders the value exactly as a template would output it.
+    ///
+    /// Strings are written bare, numbers in their shortest round-trip form,
+    /// booleans as `true`/`false`, and `Null` as an empty string. Templates
+    /// refuse to render `Bytes` and `Array` bare, but `Display` writes bytes
+    /// as lowercase hex and arrays as their items in brackets, separated by
+    /// commas, as in `[1, a, true]`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Str(s) => f.write_str(s),
+            Self::Int(i) => f.write_str(itoa::Buffer::new().format(*i)),
+            Self::Float(v) => f.write_str(zmij::Buffer::new().format(*v)),
+            Self::Bool(b) => f.write_str(if *b { "true" } else { "false" }),
+            Self::Bytes(bytes) => bytes.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+            Self::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Self::Null => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Value<'_> {
+    type Err = Infallible;
+
+    /// Infers the most specific value type for a string.
+    ///
+    /// - `"true"` and `"false"` become `Bool`
+    /// - Integers in canonical form become `Int` (`"42"`, `"-7"`)
+    /// - Other numbers containing a digit become `Float` (`"3.14"`, `"1e5"`)
+    /// - Everything else stays a `Str`
+    ///
+    /// Integers with leading zeros or an explicit `+` sign (`"007"`, `"+5"`) are
+    /// kept as strings so that identifiers such as zip codes round-trip through
+    /// `to_string()` unchanged, as are integers outside the `i64` range. Likewise
+    /// `"inf"` and `"NaN"` stay strings, since they contain no digit. `Null` is
+    /// never produced.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use figura::Value;
+    ///
+    /// let v: Value = "42".parse().unwrap();
+    /// assert_eq!(v.to_string(), "42");
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true" => return Ok(Self::Bool(true)),
+            "false" => return Ok(Self::Bool(false)),
+            _ => {}
+        }
+
+        if let Ok(i) = s.parse::<i64>()
+            && itoa::Buffer::new().format(i) == s
+        {
+            return Ok(Self::Int(i));
+        }
+
+        let is_integer_like = s
+            .trim_start_matches(['+', '-'])
+            .bytes()
+            .all(|b| b.is_ascii_digit());
+
+        if !is_integer_like
+            && s.bytes().any(|b| b.is_ascii_digit())
+            && let Ok(f) = s.parse::<f64>()
+        {
+            return Ok(Self::Float(f));
+        }
+
+        Ok(Self::owned_str(s.to_string()))
+    }
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    /// Borrows the string without copying it.
+    fn from(s: &'a str) -> Self {
+        Self::borrowed_str(s)
+    }
+}
+
+impl From<String> for Value<'_> {
+    fn from(s: String) -> Self {
+        Self::owned_str(s)
+    }
+}
+
+impl<'a> From<Cow<'a, str>> for Value<'a> {
+    fn from(s: Cow<'a, str>) -> Self {
+        Self::Str(s)
+    }
+}
+
+impl From<bool> for Value<'_> {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<f32> for Value<'_> {
+    fn from(f: f32) -> Self {
+        Self::Float(f as f64)
+    }
+}
+
+impl From<f64> for Value<'_> {
+    fn from(f: f64) -> Self {
+        Self::Float(f)
+    }
+}
+
+impl From<Vec<u8>> for Value<'_> {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Value<'_> {
+    fn from(bytes: &[u8]) -> Self {
+        Self::Bytes(bytes.to_vec())
+    }
+}
+
+impl<'a> From<Vec<Self>> for Value<'a> {
+    fn from(items: Vec<Self>) -> Self {
+        Self::Array(items)
+    }
+}
+
+/// Implements `From<Vec<T>>` for the element types that convert into a
+/// `Value`, as an `Array` of them. `Vec<u8>` converts into `Bytes` instead,
+/// which is why this can't be a blanket impl.
+macro_rules! impl_from_vec {
+    ($($t:ty),*) => {
+        $(
+            impl<'a> From<Vec<$t>> for Value<'a> {
+                fn from(items: Vec<$t>) -> Self {
+                    Self::Array(items.into_iter().map(Into::into).collect())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_vec!(
+    &'a str,
+    String,
+    Cow<'a, str>,
+    bool,
+    f32,
+    f64,
+    i8,
+    i16,
+    i32,
+    i64,
+    u16,
+    u32
+);
+
+impl<'a, T: Into<Self>> From<Option<T>> for Value<'a> {
+    /// Maps `None` to `Value::Null`.
+    fn from(opt: Option<T>) -> Self {
+        opt.map_or(Self::Null, Into::into)
+    }
+}
+
+/// Implements `From` for integer types that always fit in an `i64`.
+macro_rules! impl_from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Value<'_> {
+                fn from(i: $t) -> Self {
+                    Self::Int(i64::from(i))
+                }
+            }
+        )*
+    };
+}
+
+/// Implements `TryFrom` for integer types that may overflow an `i64`.
+macro_rules! impl_try_from_int {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<$t> for Value<'_> {
+                type Error = TryFromIntError;
+
+                fn try_from(i: $t) -> Result<Self, Self::Error> {
+                    i64::try_from(i).map(Self::Int)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(i8, i16, i32, i64, u8, u16, u32);
+impl_try_from_int!(isize, usize, u64, i128, u128);
+
+/// Implements `TryFrom<Value>` and `TryFrom<&Value>` for a primitive, with the
+/// strict accessor of the same type.
+macro_rules! impl_try_into_primitive {
+    ($($t:ty => $expected:literal, $accessor:ident;)*) => {
+        $(
+            impl TryFrom<&Value<'_>> for $t {
+                type Error = ValueTypeError;
+
+                fn try_from(value: &Value<'_>) -> Result<Self, Self::Error> {
+                    value.$accessor().ok_or_else(|| value.type_error($expected))
+                }
+            }
+
+            impl TryFrom<Value<'_>> for $t {
+                type Error = ValueTypeError;
+
+                fn try_from(value: Value<'_>) -> Result<Self, Self::Error> {
+                    Self::try_from(&value)
+                }
+            }
+        )*
+    };
+}
+
+impl_try_into_primitive! {
+    i64 => "integer", as_int;
+    f64 => "float", as_float;
+    bool => "boolean", as_bool;
+}
+
+impl TryFrom<&Value<'_>> for String {
+    type Error = ValueTypeError;
+
+    /// Copies the string of a `Str`. See `Value::to_str_lossy` to convert
+    /// other values to text too.
+    fn try_from(value: &Value<'_>) -> Result<Self, Self::Error> {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| value.type_error("string"))
+    }
+}
+
+impl TryFrom<Value<'_>> for String {
+    type Error = ValueTypeError;
+
+    /// Takes the string of a `Str`, without copying it if it is owned.
+    fn try_from(value: Value<'_>) -> Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s.into_owned()),
+            other => Err(other.type_error("string")),
+        }
+    }
+}
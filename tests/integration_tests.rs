@@ -1,4 +1,18 @@
-use figura::{Context, Template, Value};
+#![allow(clippy::approx_constant)]
+
+use figura::{
+    AlignFilter, Alignment, Argument, BoolKeywords, BorrowedContext, CaseLabel, CompileOptions,
+    CompileStats, Context, ContextBuilder, ContextLookup, ContextStack, DefaultParser, Delimiters,
+    Directive, DirectiveError, EmptyPolicy, EnvContext, Filter, FilterMismatch, FloatTolerance,
+    FormatOptions, Function, HighlightKind, IndentFilter, LazyContext, LiteralDirective, Markers,
+    MissingPolicy, NewlineStyle, NumberFormat, OwnedContext, Parser, RenderContext,
+    ReplaceDirective, Schema, SegmentKind, SwitchCase, Template, TemplateBuilder, TemplateCache,
+    TemplateChange, TemplateError, TemplateLexer, TemplateRegistry, Token, TruncateFilter,
+    UnknownPolicy, Value, ValueTypeError,
+};
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::Arc;
 
 type CBTemplate = Template<'{', '}'>;
 type ParenTemplate = Template<'(', ')'>;
@@ -92,6 +106,199 @@ fn test_both_escaped_delimiters() {
     assert_eq!(result, "{escaped} middle {both}");
 }
 
+#[test]
+fn test_backslash_escaped_delimiters() {
+    let template = CBTemplate::compile(r"\{not a directive\} {name} \{{name}\}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "{not a directive} Ada {Ada}"
+    );
+    assert_eq!(template.variables(), vec!["name"]);
+}
+
+#[test]
+fn test_backslash_escapes_mixed_with_doubling() {
+    let template = CBTemplate::compile(r"{{a}} \{b\} \\{name} C:\Users").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("x"));
+
+    // `\\` is one backslash; other backslashes are kept as-is
+    assert_eq!(template.format(&ctx).unwrap(), r"{a} {b} \x C:\Users");
+}
+
+#[test]
+fn test_escapes_merge_with_adjacent_text() {
+    let template = CBTemplate::compile("abc{{def").unwrap();
+    let segments: Vec<_> = template.iter().collect();
+
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].kind(), SegmentKind::Text);
+    assert_eq!(segments[0].span(), 0..8);
+    assert_eq!(segments[0].directive().literal(), Some("abc{def"));
+
+    // An escape between directives stays a segment of its own
+    let template = CBTemplate::compile(r"{a}\}{b}").unwrap();
+    let kinds: Vec<_> = template.iter().map(|s| s.kind()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            SegmentKind::Directive,
+            SegmentKind::Escape,
+            SegmentKind::Directive
+        ]
+    );
+}
+
+#[test]
+fn test_backslash_escape_other_delimiters() {
+    let template = SquareTemplate::compile(r"\[x\] [x] \{").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Int(1));
+
+    assert_eq!(template.format(&ctx).unwrap(), r"[x] 1 \{");
+}
+
+#[test]
+fn test_trailing_backslash_is_literal() {
+    let template = CBTemplate::compile(r"end\").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), r"end\");
+}
+
+fn with_text_escapes(source: &str) -> Result<CBTemplate, TemplateError> {
+    let options = CompileOptions {
+        text_escapes: true,
+        ..Default::default()
+    };
+    CBTemplate::compile_with_options(source, &options)
+}
+
+#[test]
+fn test_text_escapes() {
+    let template = with_text_escapes(r"Line1\nLine2\tTab\r\nCafe\u{301} \u{1F600}\\n").unwrap();
+    assert_eq!(
+        template.format(&Context::new()).unwrap(),
+        "Line1\nLine2\tTab\r\nCafe\u{301} \u{1F600}\\n"
+    );
+}
+
+#[test]
+fn test_text_escapes_are_off_by_default() {
+    let template = CBTemplate::compile(r"a\nb\tc\r").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), r"a\nb\tc\r");
+}
+
+#[test]
+fn test_text_escapes_around_directives() {
+    let template = with_text_escapes(r"{name}\n\{{name}\}\u{21} C:\Users\q").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Ada\n{Ada}! C:\\Users\\q");
+}
+
+#[test]
+fn test_text_escapes_invalid_unicode() {
+    for (source, escape) in [
+        (r"a\u{}", r"\u{}"),
+        (r"a\u{zz}", r"\u{zz}"),
+        (r"a\u{+41}", r"\u{+41}"),
+        (r"a\u{d800}", r"\u{d800}"),
+        (r"a\u{1234567}", r"\u{1234567}"),
+        (r"a\u{41", r"\u{41"),
+    ] {
+        match with_text_escapes(source) {
+            Err(TemplateError::InvalidEscape {
+                position,
+                escape: found,
+            }) => {
+                assert_eq!(position, 1, "source: {source}");
+                assert_eq!(found, escape, "source: {source}");
+            }
+            other => panic!("expected InvalidEscape for {source}, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_raw_block_is_verbatim() {
+    let template =
+        CBTemplate::compile("Hi {name}: {@raw}write {name} or {ok ? {name} : 'x'}{@endraw}!")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "Hi Ada: write {name} or {ok ? {name} : 'x'}!"
+    );
+    assert_eq!(template.variables(), vec!["name"]);
+}
+
+#[test]
+fn test_raw_block_keeps_unbalanced_delimiters_and_escapes() {
+    let template = CBTemplate::compile(r"{@raw}{{ } {a \{ \\n {x{@endraw}.").unwrap();
+    assert_eq!(
+        template.format(&Context::new()).unwrap(),
+        r"{{ } {a \{ \\n {x."
+    );
+
+    let template = with_text_escapes(r"{@raw}\n{@endraw}\n").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), "\\n\n");
+}
+
+#[test]
+fn test_raw_block_segments_and_source() {
+    let template = CBTemplate::compile("a{@raw}{b}{@endraw}{c}").unwrap();
+    let kinds: Vec<_> = template.iter().map(|s| (s.kind(), s.span())).collect();
+    assert_eq!(
+        kinds,
+        vec![(SegmentKind::Text, 0..19), (SegmentKind::Directive, 19..22)]
+    );
+
+    let source = template.to_source().unwrap();
+    assert_eq!(source, r"a\{b\}{c}");
+    assert_eq!(CBTemplate::compile(&source).unwrap(), template);
+}
+
+#[test]
+fn test_raw_block_with_multi_char_delimiters() {
+    let delimiters = Delimiters::new("<%", "%>").unwrap();
+    let template =
+        CBTemplate::with_delimiters("<%@raw%><% name %> {x}<%@endraw%> <%name%>", delimiters)
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "<% name %> {x} Ada");
+}
+
+#[test]
+fn test_raw_block_markers_allow_whitespace() {
+    let template = CBTemplate::compile("{ @raw }{name}{\t@endraw\n}|{@raw}{ @endraw }").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), "{name}|");
+
+    // Whitespace inside the marker name is not padding
+    assert!(CBTemplate::compile("{@raw}x{@end raw}").is_err());
+}
+
+#[test]
+fn test_raw_block_unclosed() {
+    assert!(matches!(
+        CBTemplate::compile("ok {@raw}{name}"),
+        Err(TemplateError::MissingDelimiter { position: 3, delimiter }) if delimiter == "{@endraw}"
+    ));
+
+    // Raw blocks don't nest: the first end marker closes the block
+    let template = CBTemplate::compile("{@raw}{@raw}{@endraw}{@endraw}").unwrap();
+    assert!(matches!(
+        template.format(&Context::new()),
+        Err(DirectiveError::UnknownDirective { directive }) if directive == "{@endraw}"
+    ));
+}
+
 #[test]
 fn test_repeat_directive_with_literal() {
     let template = CBTemplate::compile("{'ABC':5}").unwrap();
@@ -132,6 +339,27 @@ fn test_repeat_directive_large_count() {
     assert_eq!(result, "A".repeat(1000));
 }
 
+#[test]
+fn test_repeat_pattern_with_colon_and_close_delimiter() {
+    let template = CBTemplate::compile(r"{': ':3}|{'\:':2}|{'a\}b':2}|{'\{x\}':n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(2));
+
+    assert_eq!(template.format(&ctx).unwrap(), ": : : |::|a}ba}b|{x}{x}");
+
+    // The source escapes the delimiters again, so it compiles back alike
+    let source = template.to_source().unwrap();
+    assert_eq!(source, r"{': ':3}|{':':2}|{'a\}b':2}|{'\{x\}':n}");
+    assert_eq!(CBTemplate::compile(&source).unwrap(), template);
+}
+
+#[test]
+fn test_repeat_pattern_with_custom_close_delimiter() {
+    let template = Template::<'<', '>'>::compile(r"<'\>':3> <'a\:b':2>").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), ">>> a:ba:b");
+    assert_eq!(template.to_source().unwrap(), r"<'\>':3> <'a:b':2>");
+}
+
 #[test]
 fn test_mixed_literals_and_variables() {
     let template = CBTemplate::compile("Start {var1} middle {var2} end {var3} finish").unwrap();
@@ -186,6 +414,170 @@ fn test_parentheses_escaped() {
     assert_eq!(result, "Use (parens) like this");
 }
 
+#[test]
+fn test_invalid_delimiters_are_rejected() {
+    assert!(matches!(
+        Template::<'1', '2'>::compile("1name2"),
+        Err(TemplateError::InvalidDelimiter { delimiter, .. }) if delimiter == "1"
+    ));
+    assert!(matches!(
+        Template::<'{', 'x'>::compile("{name x"),
+        Err(TemplateError::InvalidDelimiter { delimiter, .. }) if delimiter == "x"
+    ));
+    assert!(matches!(
+        Template::<'\'', '\''>::compile("'name'"),
+        Err(TemplateError::InvalidDelimiter { delimiter, .. }) if delimiter == "'"
+    ));
+    assert!(Template::<' ', ' '>::validate_delimiters().is_err());
+    assert!(Template::<'\\', '/'>::validate_delimiters().is_err());
+
+    let err = Template::<'_', '_'>::compile("").unwrap_err();
+    assert!(err.to_string().contains("'_' can't be used as a delimiter"));
+}
+
+#[test]
+fn test_valid_custom_delimiters() {
+    assert!(Template::<'<', '>'>::validate_delimiters().is_ok());
+    assert!(Template::<'%', '%'>::validate_delimiters().is_ok());
+    assert!(Template::<'«', '»'>::validate_delimiters().is_ok());
+    assert!(ParenTemplate::validate_delimiters().is_ok());
+}
+
+#[test]
+fn test_same_char_delimiters_do_not_nest() {
+    let template = Template::<'%', '%'>::compile("%outer %inner%%").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("outer", Value::static_str("O"));
+
+    // The second `%` closes the first directive, so `inner` is plain text
+    assert_eq!(template.format(&ctx).unwrap(), "Oinner%");
+    assert_eq!(template.variables(), vec!["outer"]);
+}
+
+#[test]
+fn test_multibyte_char_delimiters() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Zoë 日本"));
+    ctx.insert("n", Value::Int(3));
+
+    let template = Template::<'«', '»'>::compile(
+        "Héllo «name»! ««ü»» \\«x\\» «n > 2 ? 'bïg «name»' : 'small'» «'é':n» «[n](3:três)(*:–)»",
+    )
+    .unwrap();
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "Héllo Zoë 日本! «ü» «x» bïg Zoë 日本 ééé três"
+    );
+    assert_eq!(template.variables(), vec!["name", "n"]);
+
+    let template = Template::<'「', '」'>::compile(
+        "「name」さん、「「こんにちは」」「n == 3 ? 'はい、「n」'」",
+    )
+    .unwrap();
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "Zoë 日本さん、「こんにちは」はい、3"
+    );
+}
+
+#[test]
+fn test_multibyte_delimiters_in_surrounding_text() {
+    // `«` is C2 AB and `»` is C2 BB in UTF-8: chars sharing their lead byte
+    // must not be mistaken for them
+    let template = Template::<'«', '»'>::compile("ª « name » ¬ ´").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("·"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ª · ¬ ´");
+    assert!(matches!(
+        Template::<'«', '»'>::compile("ª «name ¬"),
+        Err(TemplateError::MissingDelimiter { position: 3, delimiter }) if delimiter == "»"
+    ));
+}
+
+#[test]
+fn test_multi_char_delimiters() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("World"));
+    ctx.insert("vip", Value::Bool(true));
+
+    for (open, close, source) in [
+        ("${", "}", "Hello ${name}! ${vip ? 'Hi ${name}'}"),
+        ("<%", "%>", "Hello <% name %>! <% vip ? 'Hi <%name%>' %>"),
+        ("{{", "}}", "Hello {{name}}! {{vip ? 'Hi {{name}}'}}"),
+    ] {
+        let delimiters = Delimiters::new(open, close).unwrap();
+        let template = CBTemplate::with_delimiters(source, delimiters).unwrap();
+
+        assert_eq!(template.format(&ctx).unwrap(), "Hello World! Hi World");
+        assert_eq!(template.variables(), vec!["name", "vip"]);
+    }
+}
+
+#[test]
+fn test_multi_char_delimiter_escapes() {
+    let delimiters = Delimiters::new("<%", "%>").unwrap();
+    let template =
+        CBTemplate::with_delimiters(r"<%<% and %>%> and \<% and { } and <% name %>", delimiters)
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("x"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "<% and %> and <% and { } and x"
+    );
+
+    let kinds: Vec<_> = template.iter().map(|s| (s.kind(), s.span())).collect();
+    assert_eq!(
+        kinds,
+        vec![(SegmentKind::Text, 0..34), (SegmentKind::Directive, 34..44)]
+    );
+}
+
+#[test]
+fn test_multi_char_delimiter_errors() {
+    let delimiters = Delimiters::new("${", "}").unwrap();
+    assert!(matches!(
+        CBTemplate::with_delimiters("Hello ${name", delimiters.clone()),
+        Err(TemplateError::MissingDelimiter { position: 6, delimiter }) if delimiter == "}"
+    ));
+
+    let options = CompileOptions {
+        max_depth: 1,
+        ..Default::default()
+    };
+    assert!(matches!(
+        CBTemplate::with_delimiters_parser_and_options::<figura::DefaultParser>(
+            "${a ${b}}",
+            delimiters,
+            &options
+        ),
+        Err(TemplateError::NestingTooDeep(1))
+    ));
+
+    assert!(matches!(
+        Delimiters::new("", "}"),
+        Err(TemplateError::InvalidDelimiter { delimiter, .. }) if delimiter.is_empty()
+    ));
+    assert!(matches!(
+        Delimiters::new("{", "a}"),
+        Err(TemplateError::InvalidDelimiter { delimiter, .. }) if delimiter == "a}"
+    ));
+}
+
+#[test]
+fn test_multi_char_delimiters_debug() {
+    let delimiters = Delimiters::new("${", "}").unwrap();
+    let template = CBTemplate::with_delimiters("${name}", delimiters).unwrap();
+
+    assert_eq!(format!("{:?}", template), r#"Template<"${", "}">"#);
+    assert_eq!(
+        format!("{:?}", CBTemplate::compile("{name}").unwrap()),
+        "Template<'{', '}'>"
+    );
+}
+
 #[test]
 fn test_unclosed_delimiter_error() {
     let result = CBTemplate::compile("Hello {name");
@@ -198,6 +590,141 @@ fn test_unclosed_delimiter_error() {
     );
 }
 
+#[test]
+fn test_nested_directives_balance_delimiters() {
+    let mut ctx = Context::new();
+    ctx.insert("is_x", Value::Bool(true));
+    ctx.insert("ok", Value::Bool(false));
+    ctx.insert("y", Value::static_str("Y"));
+    ctx.insert("k", Value::Int(1));
+
+    // Two levels: the inner `}` closes the nested directive, not the outer one
+    let cases = [
+        ("{is_x?{y}:z}", "Y"),
+        ("{is_x ? '{y}!' : 'z'}", "Y!"),
+        ("{ok ? {y} : {k}}", "1"),
+        ("{[k](1:{y})(*:none)}", "Y"),
+        // Three levels
+        ("{is_x ? {ok ? n : {is_x ? {y} : q}} : z}", "Y"),
+        ("{is_x ? '{ok ? \"n\" : \"{y}{k}\"}' : 'z'}", "Y1"),
+        ("{[k](1:{is_x ? {y} : n})(*:w)} after", "Y after"),
+    ];
+
+    for (source, expected) in cases {
+        let template = CBTemplate::compile(source).unwrap();
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", source);
+    }
+}
+
+#[test]
+fn test_unbalanced_nested_directive_position() {
+    // The nested directives close, the one around them doesn't
+    assert!(matches!(
+        CBTemplate::compile("ab {is_x ? {y} : {ok ? {k} : z}"),
+        Err(TemplateError::MissingDelimiter { position: 3, delimiter }) if delimiter == "}"
+    ));
+
+    // An unpaired brace in a branch opens a directive too, unless escaped
+    let error = CBTemplate::compile("{y} {is_x ? '{y' : 'z'}").unwrap_err();
+    assert!(matches!(
+        error,
+        TemplateError::MissingDelimiter { position: 4, .. }
+    ));
+    assert!(error.to_string().contains("at byte 4"));
+
+    let template = CBTemplate::compile(r"{is_x ? '\{y' : 'z'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("is_x", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "{y");
+}
+
+#[test]
+fn test_template_from_str_and_try_from() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("World"));
+
+    let parsed: CBTemplate = "Hello {name}!".parse().unwrap();
+    assert_eq!(parsed.format(&ctx).unwrap(), "Hello World!");
+
+    let converted = SquareTemplate::try_from("Hello [name]!").unwrap();
+    assert_eq!(converted.format(&ctx).unwrap(), "Hello World!");
+
+    let from_str = ParenTemplate::from_str("Hi (name)").unwrap();
+    assert_eq!(from_str.format(&ctx).unwrap(), "Hi World");
+}
+
+#[test]
+fn test_template_from_str_error() {
+    fn compile(source: &str) -> Result<CBTemplate, TemplateError> {
+        let template = source.parse()?;
+        Ok(template)
+    }
+
+    assert!(matches!(
+        compile("Hello {name"),
+        Err(TemplateError::MissingDelimiter { position: 6, delimiter }) if delimiter == "}"
+    ));
+    assert!(CBTemplate::try_from("{a").is_err());
+}
+
+#[test]
+fn test_compile_all_errors_reports_every_directive() {
+    let source = "{a =~ '('} ok {name} {b =~ ')'} {[t](a:x {c =~ '['})} {d";
+    let errors = CBTemplate::compile_all_errors(source).unwrap_err();
+
+    let spans: Vec<_> = errors.iter().map(|e| e.span.clone()).collect();
+    assert_eq!(spans, vec![0..10, 21..31, 32..53, 54..56]);
+
+    assert!(matches!(
+        errors[3].error,
+        TemplateError::MissingDelimiter { position: 54, ref delimiter } if delimiter == "}"
+    ));
+    assert!(errors[3].to_string().contains("at bytes 54..56"));
+
+    // The fail-fast compile stops at the first of them
+    assert!(CBTemplate::compile(source).is_err());
+}
+
+#[test]
+fn test_compile_all_errors_reports_every_unclosed_directive() {
+    let source = "Hello {name, welcome to {place}. Bye {now";
+    let errors = CBTemplate::compile_all_errors(source).unwrap_err();
+
+    let positions: Vec<_> = errors
+        .iter()
+        .map(|e| match &e.error {
+            TemplateError::MissingDelimiter {
+                position,
+                delimiter,
+            } if delimiter == "}" => *position,
+            other => panic!("unexpected error {other:?}"),
+        })
+        .collect();
+    assert_eq!(positions, vec![6, 37]);
+    assert_eq!(errors[1].span, 37..source.len());
+
+    // Directives after an unclosed delimiter are still checked
+    let errors = CBTemplate::compile_all_errors("{name {b) ? 'y'} {c").unwrap_err();
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[1].span, 6..16);
+    assert!(matches!(
+        errors[1].error,
+        TemplateError::UnbalancedParenthesis { position: 8, .. }
+    ));
+    assert!(matches!(
+        errors[2].error,
+        TemplateError::MissingDelimiter { position: 17, .. }
+    ));
+}
+
+#[test]
+fn test_compile_all_errors_accepts_valid_templates() {
+    let source = "Hi {name}! {vip ? 'dear {name}'} {'-':3}";
+    let template = CBTemplate::compile_all_errors(source).unwrap();
+
+    assert_eq!(template, CBTemplate::compile(source).unwrap());
+}
+
 #[test]
 fn test_missing_variable_in_context() {
     let template = CBTemplate::compile("Hello, {name}!").unwrap();
@@ -390,13 +917,10 @@ fn test_clone_template() {
 
 #[test]
 fn test_empty_variable_name() {
-    let template = CBTemplate::compile("{}").unwrap();
-    let ctx = Context::new();
-
-    // Should compile but produce empty output or error when formatting
-    let result = template.format(&ctx);
-    // The actual behavior depends on implementation - just ensure it doesn't panic
-    let _ = result;
+    assert!(matches!(
+        CBTemplate::compile("{}"),
+        Err(TemplateError::EmptyDirective { position: 0 })
+    ));
 }
 
 #[test]
@@ -590,6 +1114,72 @@ fn test_conditional_comparing_two_variables() {
     assert_eq!(result, "Same");
 }
 
+#[test]
+fn test_conditional_comparing_two_int_variables() {
+    let template = CBTemplate::compile("{score > threshold ? 'Pass' : 'Fail'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("score", Value::Int(9));
+    ctx.insert("threshold", Value::Int(10));
+    assert_eq!(template.format(&ctx).unwrap(), "Fail");
+
+    ctx.insert("score", Value::Int(11));
+    assert_eq!(template.format(&ctx).unwrap(), "Pass");
+}
+
+#[test]
+fn test_conditional_comparing_two_float_variables() {
+    let template = CBTemplate::compile("{a <= b ? 'le' : 'gt'} {a == c ? 'eq' : 'ne'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Float(2.5));
+    ctx.insert("b", Value::Float(10.0));
+    ctx.insert("c", Value::Float(2.5));
+
+    assert_eq!(template.format(&ctx).unwrap(), "le eq");
+}
+
+#[test]
+fn test_conditional_comparing_int_and_float_variables() {
+    let template = CBTemplate::compile("{a == b ? 'same' : 'diff'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(1));
+    ctx.insert("b", Value::Float(1.0));
+
+    assert_eq!(template.format(&ctx).unwrap(), "same");
+}
+
+#[test]
+fn test_conditional_comparing_two_string_variables() {
+    let template = CBTemplate::compile("{a == b ? 'same' : 'diff'} {a < b ? 'lt' : 'ge'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("apple"));
+    ctx.insert("b", Value::static_str("banana"));
+    assert_eq!(template.format(&ctx).unwrap(), "diff lt");
+
+    ctx.insert("b", Value::static_str("apple"));
+    assert_eq!(template.format(&ctx).unwrap(), "same ge");
+}
+
+#[test]
+fn test_conditional_comparing_mismatched_variables_errors() {
+    let template = CBTemplate::compile("{a == b ? 'same' : 'diff'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("42"));
+    ctx.insert("b", Value::Int(42));
+
+    match template.format(&ctx) {
+        Err(DirectiveError::TypeError {
+            name,
+            expected,
+            found,
+        }) => {
+            assert_eq!(name, "b");
+            assert_eq!(expected, "string");
+            assert_eq!(found, "integer");
+        }
+        other => panic!("expected a type error, got {:?}", other),
+    }
+}
+
 #[test]
 fn test_conditional_comparing_variable_and_literal() {
     let template =
@@ -769,3 +1359,11072 @@ fn test_conditional_integer_falsy_zero() {
     let result = template.format(&ctx).unwrap();
     assert_eq!(result, "Empty");
 }
+
+#[test]
+fn test_conditional_without_else() {
+    let template = CBTemplate::compile("[{is_admin ? 'Admin'}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("is_admin", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "[Admin]");
+
+    ctx.insert("is_admin", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "[]");
+}
+
+#[test]
+fn test_conditional_without_else_with_comparison_and_not() {
+    let template =
+        CBTemplate::compile("{age >= 18 ? 'adult'}|{!banned ? 'welcome'}|{name ^= 'Dr' ? title}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("age", Value::Int(17));
+    ctx.insert("banned", Value::Bool(false));
+    ctx.insert("name", Value::static_str("Dr Who"));
+    ctx.insert("title", Value::static_str("Doctor"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "|welcome|Doctor");
+}
+
+#[test]
+fn test_conditional_without_else_missing_variable() {
+    let template = CBTemplate::compile("{is_admin ? 'Admin'}").unwrap();
+    let ctx = Context::new();
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_conditional_without_else_differs_from_explicit_empty_else() {
+    let template = CBTemplate::compile("{flag ? 'on'}").unwrap();
+    assert_eq!(template.variables(), vec!["flag"]);
+
+    // An explicit else branch is still an argument of its own
+    let template = CBTemplate::compile("{flag ? 'on' : other}").unwrap();
+    assert_eq!(template.variables(), vec!["flag", "other"]);
+}
+
+#[test]
+fn test_conditional_branches_with_nested_directives() {
+    let template =
+        CBTemplate::compile(r#"{vip ? 'Welcome {name}!' : 'Hello{" hello":2}'}"#).unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(true));
+    ctx.insert("name", Value::static_str("Ada"));
+    assert_eq!(template.format(&ctx).unwrap(), "Welcome Ada!");
+
+    ctx.insert("vip", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "Hello hello hello");
+}
+
+#[test]
+fn test_conditional_nested_branches_multiple_levels() {
+    let template = CBTemplate::compile(
+        r#"{a ? "A{b ? ' and B{c ? \" and {name}\" : \"\"}' : ''}." : 'none'}"#,
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Bool(true));
+    ctx.insert("b", Value::Bool(true));
+    ctx.insert("c", Value::Bool(true));
+    ctx.insert("name", Value::static_str("C"));
+    assert_eq!(template.format(&ctx).unwrap(), "A and B and C.");
+
+    ctx.insert("c", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "A and B.");
+
+    ctx.insert("a", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "none");
+}
+
+#[test]
+fn test_conditional_nested_branch_escaped_delimiters() {
+    let template = CBTemplate::compile("{flag ? '{{{name}}}' : 'plain'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("flag", Value::Bool(true));
+    ctx.insert("name", Value::static_str("x"));
+    assert_eq!(template.format(&ctx).unwrap(), "{x}");
+}
+
+#[test]
+fn test_conditional_branch_escaped_closing_delimiter() {
+    let template = CBTemplate::compile(r"{flag ? 'x => x \}' : '{name} \}'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("x"));
+
+    ctx.insert("flag", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "x => x }");
+
+    ctx.insert("flag", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "x }");
+
+    let template = SquareTemplate::compile(r"[flag ? 'a\]' : 'b']").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "b");
+    ctx.insert("flag", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "a]");
+}
+
+#[test]
+fn test_conditional_branch_escaped_opening_delimiter() {
+    let template = CBTemplate::compile(r"{s ? 'a\{b' : 'c'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    ctx.insert("s", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "a{b");
+
+    let template = CBTemplate::compile(r"{s ? '\{{name}\} it\'s' : 'c'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "{Ada} it's");
+    assert_eq!(
+        template.to_source().unwrap(),
+        r"{s ? '\{{name}\} it\'s' : 'c'}"
+    );
+}
+
+#[test]
+fn test_nested_template_errors_point_into_the_template() {
+    assert!(matches!(
+        CBTemplate::compile(r"ab {s ? 'x\n\{ {}' : 'c'}"),
+        Err(TemplateError::EmptyDirective { position: 15 })
+    ));
+    assert!(matches!(
+        CBTemplate::compile(r"{s ? 'x\n {(b ? c}' : 'y'}"),
+        Err(TemplateError::UnbalancedParenthesis { position: 11, .. })
+    ));
+    assert!(matches!(
+        CBTemplate::compile(r"{[x](a:\( {})}"),
+        Err(TemplateError::EmptyDirective { position: 10 })
+    ));
+
+    let source = r"{x} {s ? 'x {}' : 'y'}";
+    let errors = CBTemplate::compile_all_errors(source).unwrap_err();
+    assert_eq!(errors[0].span, 4..source.len());
+    assert!(matches!(
+        errors[0].error,
+        TemplateError::EmptyDirective { position: 12 }
+    ));
+}
+
+#[test]
+fn test_conditional_nested_branch_variables() {
+    let template = CBTemplate::compile("{vip ? 'Welcome {name}!' : 'Hi {guest}'}").unwrap();
+    assert_eq!(template.variables(), vec!["vip", "name", "guest"]);
+}
+
+#[test]
+fn test_conditional_nested_branch_missing_variable() {
+    let template = CBTemplate::compile("{vip ? 'Welcome {name}!' : 'Hello'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "Hello");
+
+    // Only the selected branch is rendered
+    ctx.insert("vip", Value::Bool(true));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { name, .. }) if name == "name"
+    ));
+}
+
+#[test]
+fn test_conditional_ladder_three_arms() {
+    let template =
+        CBTemplate::compile("{score >= 90 ? 'Gold' : score >= 50 ? 'Silver' : 'Bronze'}").unwrap();
+    let mut ctx = Context::new();
+
+    for (score, expected) in [(95, "Gold"), (90, "Gold"), (70, "Silver"), (10, "Bronze")] {
+        ctx.insert("score", Value::Int(score));
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_conditional_ladder_four_arms() {
+    let template = CBTemplate::compile(
+        "{status == 'ok' ? 'Up' : status == 'slow' ? 'Degraded' : status == 'down' ? 'Down' : 'Unknown'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+
+    for (status, expected) in [
+        ("ok", "Up"),
+        ("slow", "Degraded"),
+        ("down", "Down"),
+        ("other", "Unknown"),
+    ] {
+        ctx.insert("status", Value::static_str(status));
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_conditional_ladder_matches_nested_conditionals() {
+    let ladder = CBTemplate::compile("{a ? x : b ? y : z}").unwrap();
+    let nested = CBTemplate::compile("{a ? x : '{b ? y : z}'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::static_str("X"));
+    ctx.insert("y", Value::static_str("Y"));
+    ctx.insert("z", Value::static_str("Z"));
+
+    for (a, b) in [(true, true), (true, false), (false, true), (false, false)] {
+        ctx.insert("a", Value::Bool(a));
+        ctx.insert("b", Value::Bool(b));
+        assert_eq!(ladder.format(&ctx).unwrap(), nested.format(&ctx).unwrap());
+    }
+}
+
+#[test]
+fn test_conditional_ladder_nested_substitutions() {
+    let template = CBTemplate::compile(
+        "{vip ? 'Welcome back, {name}!' : member ? 'Hi {name}, {points} points' : 'Hello {guest}'}",
+    )
+    .unwrap();
+    assert_eq!(
+        template.variables(),
+        vec!["vip", "name", "member", "points", "guest"]
+    );
+
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(false));
+    ctx.insert("member", Value::Bool(true));
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("points", Value::Int(42));
+    assert_eq!(template.format(&ctx).unwrap(), "Hi Ada, 42 points");
+
+    ctx.insert("vip", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "Welcome back, Ada!");
+
+    // Arms that aren't selected may reference missing variables
+    ctx.insert("vip", Value::Bool(false));
+    ctx.insert("member", Value::Bool(false));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { name, .. }) if name == "guest"
+    ));
+}
+
+#[test]
+fn test_conditional_ladder_without_default() {
+    let template = CBTemplate::compile("{a ? 'A' : b ? 'B'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Bool(false));
+    ctx.insert("b", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "");
+
+    ctx.insert("b", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "B");
+}
+
+#[test]
+fn test_conditional_question_mark_in_literal_is_not_a_ladder() {
+    let template = CBTemplate::compile("{a ? 'why?' : 'b ? c : d'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "b ? c : d");
+
+    ctx.insert("a", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "why?");
+}
+
+#[test]
+fn test_conditional_untaken_branch_is_not_resolved() {
+    let mut ctx = Context::new();
+    ctx.insert("have_data", Value::Bool(false));
+
+    let sources = [
+        ("{have_data ? {data} : 'No data'}", "No data"),
+        ("{have_data ? '{data:>5} rows' : 'No data'}", "No data"),
+        ("{!have_data ? 'No data' : {data}}", "No data"),
+        (
+            "{have_data ? {[data](1:one)(*:{data})} : 'No data'}",
+            "No data",
+        ),
+        (
+            "{have_data ? {data} : have_data ? '{data}' : 'No data'}",
+            "No data",
+        ),
+    ];
+
+    for (source, expected) in sources {
+        let mut template = CBTemplate::compile(source).unwrap();
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", source);
+
+        let mut bytes = Vec::new();
+        template.format_to(&ctx, &mut bytes).unwrap();
+        assert_eq!(bytes, expected.as_bytes(), "{}", source);
+
+        template.optimize();
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", source);
+    }
+
+    // Taking the branch does resolve it
+    ctx.insert("have_data", Value::Bool(true));
+    let template = CBTemplate::compile(sources[0].0).unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { name, .. }) if name == "data"
+    ));
+}
+
+#[test]
+fn test_conditional_untaken_branch_is_not_evaluated() {
+    // There's no division, but adding a boolean fails the same way at render
+    let template = CBTemplate::compile("{ok ? {n + flag} : 'skipped'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("ok", Value::Bool(false));
+    ctx.insert("n", Value::Int(1));
+    ctx.insert("flag", Value::Bool(true));
+
+    assert_eq!(template.format(&ctx).unwrap(), "skipped");
+    assert_eq!(template.format_spanned(&ctx).unwrap(), "skipped");
+
+    ctx.insert("ok", Value::Bool(true));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { name, .. }) if name == "flag"
+    ));
+}
+
+// ============================================
+// Logical Operator Tests
+// ============================================
+
+/// Renders `source` with the given boolean variables.
+fn logic(source: &str, vars: &[(&'static str, bool)]) -> String {
+    let mut ctx = Context::new();
+    for &(name, value) in vars {
+        ctx.insert(name, Value::Bool(value));
+    }
+
+    CBTemplate::compile(source).unwrap().format(&ctx).unwrap()
+}
+
+#[test]
+fn test_logical_and_or() {
+    for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+        let vars = [("a", a), ("b", b)];
+        let expected = |value: bool| if value { "yes" } else { "no" };
+
+        assert_eq!(logic("{a && b ? 'yes' : 'no'}", &vars), expected(a && b));
+        assert_eq!(logic("{a || b ? 'yes' : 'no'}", &vars), expected(a || b));
+    }
+}
+
+#[test]
+fn test_logical_and_binds_tighter_than_or() {
+    // a || (b && c), not (a || b) && c
+    let vars = [("a", true), ("b", false), ("c", false)];
+    assert_eq!(logic("{a || b && c ? 'yes' : 'no'}", &vars), "yes");
+    assert_eq!(logic("{b && c || a ? 'yes' : 'no'}", &vars), "yes");
+    assert_eq!(logic("{(a || b) && c ? 'yes' : 'no'}", &vars), "no");
+}
+
+#[test]
+fn test_logical_grouping_with_negation() {
+    let source = "{(a || b) && !c ? 'yes' : 'no'}";
+
+    assert_eq!(
+        logic(source, &[("a", true), ("b", false), ("c", false)]),
+        "yes"
+    );
+    assert_eq!(
+        logic(source, &[("a", false), ("b", true), ("c", false)]),
+        "yes"
+    );
+    assert_eq!(
+        logic(source, &[("a", true), ("b", true), ("c", true)]),
+        "no"
+    );
+    assert_eq!(
+        logic(source, &[("a", false), ("b", false), ("c", false)]),
+        "no"
+    );
+
+    // Negating a group negates all of it
+    let source = "{!(a || b) ? 'neither' : 'some'}";
+    assert_eq!(logic(source, &[("a", false), ("b", false)]), "neither");
+    assert_eq!(logic(source, &[("a", false), ("b", true)]), "some");
+}
+
+#[test]
+fn test_logical_not_binds_tighter_than_comparison() {
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Bool(true));
+    ctx.insert("b", Value::Bool(false));
+
+    // (!a) == b
+    let template = CBTemplate::compile("{!a == b ? 'yes' : 'no'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "yes");
+
+    // !(a == b)
+    let template = CBTemplate::compile("{!(a == b) ? 'yes' : 'no'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "yes");
+
+    ctx.insert("b", Value::Bool(true));
+    let template = CBTemplate::compile("{!a == b ? 'yes' : 'no'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+    let template = CBTemplate::compile("{!(a == b) ? 'yes' : 'no'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+}
+
+#[test]
+fn test_logical_operators_with_comparisons() {
+    let template =
+        CBTemplate::compile("{age >= 18 && country == 'IT' || admin ? 'allowed' : 'denied'}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("age", Value::Int(20));
+    ctx.insert("country", Value::static_str("IT"));
+    ctx.insert("admin", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "allowed");
+
+    ctx.insert("age", Value::Int(16));
+    assert_eq!(template.format(&ctx).unwrap(), "denied");
+
+    ctx.insert("admin", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "allowed");
+}
+
+#[test]
+fn test_logical_operators_short_circuit() {
+    // The right-hand side is never looked up, so its absence isn't an error
+    assert_eq!(
+        logic("{a && missing ? 'yes' : 'no'}", &[("a", false)]),
+        "no"
+    );
+    assert_eq!(
+        logic("{a || missing ? 'yes' : 'no'}", &[("a", true)]),
+        "yes"
+    );
+
+    let template = CBTemplate::compile("{a && missing ? 'yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Bool(true));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { name, .. }) if name == "missing"
+    ));
+}
+
+#[test]
+fn test_logical_operators_in_ladders() {
+    let source = "{a && b ? 'both' : a || b ? 'one' : 'none'}";
+
+    assert_eq!(logic(source, &[("a", true), ("b", true)]), "both");
+    assert_eq!(logic(source, &[("a", false), ("b", true)]), "one");
+    assert_eq!(logic(source, &[("a", false), ("b", false)]), "none");
+}
+
+#[test]
+fn test_logical_operators_debug_tree() {
+    let template = CBTemplate::compile("{(a || b) && !c ? 'yes'}").unwrap();
+    assert!(template.debug_tree().contains("if: (a || b) && !c"));
+
+    let template = CBTemplate::compile("{a || b && c ? 'yes'}").unwrap();
+    assert!(template.debug_tree().contains("if: a || (b && c)"));
+
+    let template = CBTemplate::compile("{!(a == b) ? 'yes'}").unwrap();
+    assert!(template.debug_tree().contains("if: !(a == b)"));
+}
+
+#[test]
+fn test_logical_operator_variables() {
+    let template = CBTemplate::compile("{(a || b) && !c ? 'yes'}").unwrap();
+    let mut variables = template.variables();
+    variables.sort();
+    assert_eq!(variables, ["a", "b", "c"]);
+}
+
+#[test]
+fn test_unbalanced_parenthesis_positions() {
+    assert!(matches!(
+        CBTemplate::compile("Hi {(a || b ? 'x'}"),
+        Err(TemplateError::UnbalancedParenthesis {
+            position: 4,
+            paren: '('
+        })
+    ));
+    assert!(matches!(
+        CBTemplate::compile("{a || b) ? 'x'}"),
+        Err(TemplateError::UnbalancedParenthesis {
+            position: 7,
+            paren: ')'
+        })
+    ));
+
+    // A stray `)` is reported before an unclosed `(`
+    assert!(matches!(
+        CBTemplate::compile("{((a) || b)) && (c ? 'x'}"),
+        Err(TemplateError::UnbalancedParenthesis {
+            position: 11,
+            paren: ')'
+        })
+    ));
+
+    // The earliest unclosed `(` is reported
+    let error = CBTemplate::compile("{((a || b ? 'x'}").unwrap_err();
+    assert_eq!(error.to_string(), "Unmatched '(' at byte 1");
+}
+
+#[test]
+fn test_unbalanced_parenthesis_spans() {
+    let source = "{(a ? 'x'} {ok} {b) ? 'y'}";
+    let errors = CBTemplate::compile_all_errors(source).unwrap_err();
+
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].span, 0..10);
+    assert!(matches!(
+        errors[0].error,
+        TemplateError::UnbalancedParenthesis {
+            position: 1,
+            paren: '('
+        }
+    ));
+    assert_eq!(errors[1].span, 16..26);
+    assert!(matches!(
+        errors[1].error,
+        TemplateError::UnbalancedParenthesis {
+            position: 18,
+            paren: ')'
+        }
+    ));
+}
+
+#[test]
+fn test_parentheses_outside_conditions_need_not_balance() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("ab"));
+    ctx.insert("a", Value::Bool(true));
+
+    // Parentheses are fill characters of alignment specs here
+    let template = CBTemplate::compile("{name:(<5}|{name:)>5}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "ab(((|)))ab");
+
+    let template = CBTemplate::compile("{a ? {name:(<4}}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "ab((");
+}
+
+#[test]
+fn test_parenthesis_depth_counts_against_max_depth() {
+    let options = CompileOptions {
+        max_depth: 3,
+        ..Default::default()
+    };
+
+    assert!(CBTemplate::compile_with_options("{(((a))) ? 'x'}", &options).is_ok());
+    assert!(matches!(
+        CBTemplate::compile_with_options("{((((a)))) ? 'x'}", &options),
+        Err(TemplateError::NestingTooDeep(3))
+    ));
+
+    let deep = format!("{{{}a{} ? 'x'}}", "(".repeat(100_000), ")".repeat(100_000));
+    assert!(matches!(
+        CBTemplate::compile(&deep),
+        Err(TemplateError::NestingTooDeep(_))
+    ));
+}
+
+// ============================================
+// Whitespace Tests
+// ============================================
+
+#[test]
+fn test_spaced_directives_render_like_compact_ones() {
+    let mut registry = TemplateRegistry::new();
+    registry.insert("header", CBTemplate::compile("=").unwrap());
+
+    let pairs = [
+        (
+            "{age>=18&&country=='US'?'Adult':'Minor'}",
+            "{ age >= 18 && country == 'US' ? 'Adult' : 'Minor' }",
+        ),
+        (
+            "{!(age<18||vip)?'no':'yes'}",
+            "{\t! ( age < 18 || vip )\n? 'no'\n: 'yes' }",
+        ),
+        ("{age in 13..19?'teen'}", "{ age in 13 .. 19 ? 'teen' }"),
+        ("{max(age,3)+1:>5}", "{ max( age , 3 ) + 1 : >5 }"),
+        ("{name|'anon'}", "{ name | 'anon' }"),
+        ("{score:round(1)|>6}", "{ score : round( 1 ) | >6 }"),
+        (
+            "{[country](US:yes)(*:no)}",
+            "{ [ country ] ( US :yes) ( * :no) }",
+        ),
+        ("{'-':age}", "{ '-' : age }"),
+        ("{>header}", "{ > header }"),
+        ("{defined(vip)?'set'}", "{ defined ( vip ) ? 'set' }"),
+    ];
+
+    for (age, country, vip) in [(21, "US", false), (15, "US", true), (40, "FR", false)] {
+        let mut ctx = Context::new();
+        ctx.insert("age", Value::Int(age));
+        ctx.insert("country", Value::static_str(country));
+        ctx.insert("vip", Value::Bool(vip));
+        ctx.insert("name", Value::static_str("Ada"));
+        ctx.insert("score", Value::Float(2.345));
+
+        for (compact, spaced) in pairs {
+            let compact_output = CBTemplate::compile(compact)
+                .unwrap()
+                .format_with(&ctx, &registry)
+                .unwrap();
+            let spaced_output = CBTemplate::compile(spaced)
+                .unwrap()
+                .format_with(&ctx, &registry)
+                .unwrap();
+
+            assert_eq!(compact_output, spaced_output, "{} vs {}", compact, spaced);
+        }
+    }
+}
+
+#[test]
+fn test_whitespace_in_text_is_kept() {
+    let template = CBTemplate::compile(
+        "[{ vip ? ' VIP ' : 'none' }] [{ [tier] ( gold : Welcome ) ( * :other) }] [{ `first name` }]",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(true));
+    ctx.insert("tier", Value::static_str("gold"));
+    ctx.insert("first name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[ VIP ] [ Welcome ] [Ada]");
+}
+
+#[test]
+fn test_whitespace_does_not_split_tokens() {
+    for source in ["{age > = 18 ? 'a' : 'b'}", "{n:0 5}", "{a & & b ? 'y'}"] {
+        let template = CBTemplate::compile(source).unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("age", Value::Int(20));
+        ctx.insert("n", Value::Int(3));
+        ctx.insert("a", Value::Bool(true));
+        ctx.insert("b", Value::Bool(true));
+
+        assert!(
+            matches!(
+                template.format(&ctx),
+                Err(DirectiveError::UnknownDirective { .. })
+            ),
+            "{}",
+            source
+        );
+    }
+}
+
+// ============================================
+// Switch Tests
+// ============================================
+
+#[test]
+fn test_switch_selects_matching_case() {
+    let template = CBTemplate::compile("[{[tier](gold:G)(silver:S)(*:other)}]").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("tier", Value::static_str("gold"));
+    assert_eq!(template.format(&ctx).unwrap(), "[G]");
+
+    ctx.insert("tier", Value::static_str("silver"));
+    assert_eq!(template.format(&ctx).unwrap(), "[S]");
+
+    ctx.insert("tier", Value::static_str("bronze"));
+    assert_eq!(template.format(&ctx).unwrap(), "[other]");
+}
+
+#[test]
+fn test_switch_without_match_renders_nothing() {
+    let template = CBTemplate::compile("[{[tier](gold:G)}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("bronze"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[]");
+}
+
+#[test]
+fn test_switch_first_matching_case_wins() {
+    let template = CBTemplate::compile("{[x](*:any)(a:A)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::static_str("a"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "any");
+}
+
+#[test]
+fn test_switch_numeric_labels() {
+    let template = CBTemplate::compile("{[code](200:OK)(404:Not Found)(-1:Unknown)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("code", Value::Int(404));
+    assert_eq!(template.format(&ctx).unwrap(), "Not Found");
+
+    ctx.insert("code", Value::Int(-1));
+    assert_eq!(template.format(&ctx).unwrap(), "Unknown");
+}
+
+#[test]
+fn test_switch_int_and_string_scrutinees_share_cases() {
+    let template =
+        CBTemplate::compile("{[status](200:OK)(404:Not Found)(500.0:Error)(*:Other)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (status, expected) in [
+        (Value::Int(404), "Not Found"),
+        (Value::static_str("404"), "Not Found"),
+        (Value::Int(500), "Error"),
+        (Value::static_str("500.0"), "Error"),
+        (Value::static_str("500"), "Other"),
+        (Value::static_str("404.0"), "Other"),
+        (Value::Float(200.0), "OK"),
+        (Value::Bool(true), "Other"),
+    ] {
+        ctx.insert("status", status.clone());
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{status:?}");
+    }
+}
+
+#[test]
+fn test_switch_float_scrutinee_uses_float_tolerance() {
+    let template = CBTemplate::compile("{[ratio](0.3:third-ish)(*:other)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("ratio", Value::Float(0.1 + 0.2));
+
+    assert_eq!(template.format(&ctx).unwrap(), "third-ish");
+
+    let exact = FormatOptions {
+        float_tolerance: FloatTolerance::Exact,
+        ..Default::default()
+    };
+    assert_eq!(template.format_with_options(&ctx, &exact).unwrap(), "other");
+}
+
+#[test]
+fn test_switch_prefix_labels() {
+    let template = CBTemplate::compile("{[level](ERROR*:🔴)(WARN*:🟡)(*:⚪)} {msg}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("msg", Value::static_str("disk full"));
+
+    for (level, expected) in [
+        ("ERROR", "🔴 disk full"),
+        ("ERROR_FATAL", "🔴 disk full"),
+        ("WARNING", "🟡 disk full"),
+        ("INFO", "⚪ disk full"),
+        ("error", "⚪ disk full"),
+        ("ERR", "⚪ disk full"),
+    ] {
+        ctx.insert("level", Value::static_str(level));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{level}");
+    }
+}
+
+#[test]
+fn test_switch_overlapping_prefixes_first_wins() {
+    let template = CBTemplate::compile("{[path]('api/v2'*:v2)(api*:api)(a*:a)(*:other)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (path, expected) in [
+        ("api/v2/users", "v2"),
+        ("api/v1/users", "api"),
+        ("assets", "a"),
+        ("blog", "other"),
+    ] {
+        ctx.insert("path", Value::static_str(path));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{path}");
+    }
+}
+
+#[test]
+fn test_switch_exact_label_beats_prefix() {
+    let template = CBTemplate::compile("{[level](WARN*:prefix)(WARN:exact)(*:other)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("level", Value::static_str("WARN"));
+    assert_eq!(template.format(&ctx).unwrap(), "exact");
+
+    ctx.insert("level", Value::static_str("WARNING"));
+    assert_eq!(template.format(&ctx).unwrap(), "prefix");
+
+    let default_first = CBTemplate::compile("{[level](*:any)(WARN:exact)}").unwrap();
+    ctx.insert("level", Value::static_str("WARN"));
+    assert_eq!(default_first.format(&ctx).unwrap(), "any");
+}
+
+#[test]
+fn test_switch_prefix_labels_match_rendered_numbers() {
+    let template = CBTemplate::compile("{[status](2*:success)(4*:client error)(*:other)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("status", Value::Int(204));
+    assert_eq!(template.format(&ctx).unwrap(), "success");
+
+    ctx.insert("status", Value::Int(404));
+    assert_eq!(template.format(&ctx).unwrap(), "client error");
+
+    ctx.insert("status", Value::Int(500));
+    assert_eq!(template.format(&ctx).unwrap(), "other");
+}
+
+#[test]
+fn test_switch_bytes_scrutinee_is_a_type_error() {
+    let template = CBTemplate::compile("{[data](*:any)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("data", Value::from(vec![1u8, 2]));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { .. })
+    ));
+}
+
+#[test]
+fn test_switch_case_body_keeps_whitespace_and_punctuation() {
+    let template = CBTemplate::compile("{[x](a: one, two; three (four) )}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::static_str("a"));
+
+    assert_eq!(template.format(&ctx).unwrap(), " one, two; three (four) ");
+}
+
+#[test]
+fn test_switch_case_body_escaped_parentheses() {
+    let template = CBTemplate::compile(r"{[x](a:smile :\))(b:\(open)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("x", Value::static_str("a"));
+    assert_eq!(template.format(&ctx).unwrap(), "smile :)");
+
+    ctx.insert("x", Value::static_str("b"));
+    assert_eq!(template.format(&ctx).unwrap(), "(open");
+}
+
+#[test]
+fn test_switch_case_body_escaped_closing_delimiter() {
+    let template =
+        CBTemplate::compile(r"{[x](js:function() \{ return 1; \})(tpl:{name}\})(*:\\)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    ctx.insert("x", Value::static_str("js"));
+    assert_eq!(template.format(&ctx).unwrap(), "function() { return 1; }");
+
+    ctx.insert("x", Value::static_str("tpl"));
+    assert_eq!(template.format(&ctx).unwrap(), "Ada}");
+
+    ctx.insert("x", Value::static_str("other"));
+    assert_eq!(template.format(&ctx).unwrap(), r"\");
+}
+
+#[test]
+fn test_switch_case_label_with_escaped_colon() {
+    let template = CBTemplate::compile(r"{[x](a\:b:yes)(*:no)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("x", Value::static_str("a:b"));
+    assert_eq!(template.format(&ctx).unwrap(), "yes");
+
+    ctx.insert("x", Value::static_str("a"));
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+
+    ctx.insert("x", Value::static_str(r"a\:b"));
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+}
+
+#[test]
+fn test_switch_case_label_with_escaped_parenthesis() {
+    let template = CBTemplate::compile(r"{[x](x\)y:yes)(x\(*:open)(*:no)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("x", Value::static_str("x)y"));
+    assert_eq!(template.format(&ctx).unwrap(), "yes");
+
+    ctx.insert("x", Value::static_str("x(z"));
+    assert_eq!(template.format(&ctx).unwrap(), "open");
+
+    ctx.insert("x", Value::static_str("xy"));
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+}
+
+#[test]
+fn test_switch_escaped_case_labels_round_trip() {
+    let source = r"{[x](a\:b:yes)(x\)y:paren)(c\\d:slash)(*:no)}";
+    let template = CBTemplate::compile(source).unwrap();
+    assert_eq!(template.to_source().unwrap(), source);
+
+    // Quoted labels are written back escaped
+    let quoted = CBTemplate::compile("{[x]('a:b':yes)('a b':space)}").unwrap();
+    assert_eq!(quoted.to_source().unwrap(), r"{[x](a\:b:yes)('a b':space)}");
+
+    let reparsed = CBTemplate::compile(quoted.to_source().unwrap()).unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::static_str("a:b"));
+    assert_eq!(reparsed.format(&ctx).unwrap(), "yes");
+}
+
+#[test]
+fn test_switch_case_bodies_with_nested_directives() {
+    let template =
+        CBTemplate::compile("{[tier](gold:Welcome {name}, gold member)(silver:Hi {name})}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    ctx.insert("tier", Value::static_str("gold"));
+    assert_eq!(template.format(&ctx).unwrap(), "Welcome Ada, gold member");
+
+    ctx.insert("tier", Value::static_str("silver"));
+    assert_eq!(template.format(&ctx).unwrap(), "Hi Ada");
+}
+
+#[test]
+fn test_switch_nested_switch_and_conditional() {
+    let template = CBTemplate::compile(
+        "{[tier](gold:{[lang](en:Welcome)(*:Benvenuto)} {name}{vip ? '!'})(*:Hello)}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("gold"));
+    ctx.insert("lang", Value::static_str("it"));
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("vip", Value::Bool(true));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Benvenuto Ada!");
+
+    ctx.insert("lang", Value::static_str("en"));
+    ctx.insert("vip", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "Welcome Ada");
+
+    ctx.insert("tier", Value::static_str("silver"));
+    assert_eq!(template.format(&ctx).unwrap(), "Hello");
+}
+
+#[test]
+fn test_switch_variables_include_case_bodies() {
+    let template = CBTemplate::compile("{[tier](gold:Hi {name})(*:Hello {guest})}").unwrap();
+    assert_eq!(template.variables(), vec!["tier", "name", "guest"]);
+}
+
+#[test]
+fn test_switch_missing_variables() {
+    let template = CBTemplate::compile("{[tier](gold:Hi {name})(*:Hello)}").unwrap();
+    let mut ctx = Context::new();
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { name, .. }) if name == "tier"
+    ));
+
+    // Only the selected case is rendered
+    ctx.insert("tier", Value::static_str("silver"));
+    assert_eq!(template.format(&ctx).unwrap(), "Hello");
+
+    ctx.insert("tier", Value::static_str("gold"));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::NotFound { name, .. }) if name == "name"
+    ));
+}
+
+#[test]
+fn test_switch_default_for_missing_scrutinee() {
+    let template = CBTemplate::compile("{[status|unknown](active:On)(unknown:?)(*:Off)}").unwrap();
+    let mut ctx = Context::new();
+
+    assert_eq!(template.format(&ctx).unwrap(), "?");
+
+    ctx.insert("status", Value::Null);
+    assert_eq!(template.format(&ctx).unwrap(), "?");
+
+    ctx.insert("status", Value::static_str("active"));
+    assert_eq!(template.format(&ctx).unwrap(), "On");
+
+    ctx.insert("status", Value::static_str("paused"));
+    assert_eq!(template.format(&ctx).unwrap(), "Off");
+
+    // The default is a label even if a variable has its name
+    ctx.remove("status");
+    ctx.insert("unknown", Value::static_str("active"));
+    assert_eq!(template.format(&ctx).unwrap(), "?");
+}
+
+#[test]
+fn test_switch_default_literals_and_numbers() {
+    let template = CBTemplate::compile("{[code|0](0:none)(>=400:error)(*:ok)}").unwrap();
+    let mut ctx = Context::new();
+
+    assert_eq!(template.format(&ctx).unwrap(), "none");
+
+    ctx.insert("code", Value::Int(404));
+    assert_eq!(template.format(&ctx).unwrap(), "error");
+
+    let template = CBTemplate::compile("{[tier|'no tier']('no tier':-)(gold:G)}").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), "-");
+}
+
+#[test]
+fn test_switch_default_keeps_other_errors() {
+    let template = CBTemplate::compile("{[data|none](none:-)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("data", Value::Bytes(vec![1, 2]));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { name, .. }) if name == "data"
+    ));
+
+    // Without a default, a missing scrutinee is still an error
+    let template = CBTemplate::compile("{[data](none:-)}").unwrap();
+    assert!(template.format(&Context::new()).is_err());
+    assert_eq!(template.variables(), vec!["data"]);
+}
+
+#[test]
+fn test_switch_format_to() {
+    let template = CBTemplate::compile("{[x](a:<{y}>)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::static_str("a"));
+    ctx.insert("y", Value::Int(1));
+
+    let mut out = Vec::new();
+    template.format_to(&ctx, &mut out).unwrap();
+    assert_eq!(out, b"<1>");
+}
+
+#[test]
+fn test_switch_comparison_buckets() {
+    let template = CBTemplate::compile("{[score](>=90:A)(>=80:B)(>=70:C)(*:F)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (score, grade) in [
+        (Value::Int(100), "A"),
+        (Value::Int(90), "A"),
+        (Value::Float(89.9), "B"),
+        (Value::Int(80), "B"),
+        (Value::Float(70.0), "C"),
+        (Value::Int(69), "F"),
+        (Value::Int(-5), "F"),
+        (Value::static_str("85"), "B"),
+        (Value::static_str("n/a"), "F"),
+        (Value::Null, "F"),
+    ] {
+        ctx.insert("score", score.clone());
+        assert_eq!(template.format(&ctx).unwrap(), grade, "{:?}", score);
+    }
+}
+
+#[test]
+fn test_switch_comparisons_are_order_sensitive() {
+    let template = CBTemplate::compile("{[score](>=70:C)(>=90:A)(*:F)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("score", Value::Int(95));
+
+    // The first comparison that holds wins, even if a later one is tighter
+    assert_eq!(template.format(&ctx).unwrap(), "C");
+
+    let template = CBTemplate::compile("{[t](<0:freezing)(<=20:cold)(>30:hot)}").unwrap();
+    for (t, expected) in [
+        (-3.5, "freezing"),
+        (0.0, "cold"),
+        (20.0, "cold"),
+        (25.0, ""),
+        (31.0, "hot"),
+    ] {
+        ctx.insert("t", Value::Float(t));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", t);
+    }
+}
+
+#[test]
+fn test_switch_comparisons_mix_with_labels() {
+    let template =
+        CBTemplate::compile("{[n](0:none)(< -10:debt)(<0:owed)(>= 1.5:many)(*:one)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (n, expected) in [
+        (0, "none"),
+        (-20, "debt"),
+        (-1, "owed"),
+        (1, "one"),
+        (2, "many"),
+    ] {
+        ctx.insert("n", Value::Int(n));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", n);
+    }
+
+    assert!(template.debug_tree().contains("case <-10"));
+    assert!(template.debug_tree().contains("case >=1.5"));
+}
+
+#[test]
+fn test_switch_comparison_labels_need_ordering_and_numbers() {
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(1));
+
+    for source in ["{[n](>=x:big)}", "{[n](*=1:big)}", "{[n](>=:big)}"] {
+        assert!(
+            matches!(
+                CBTemplate::compile(source).unwrap().format(&ctx),
+                Err(DirectiveError::UnknownDirective { .. })
+            ),
+            "{}",
+            source
+        );
+    }
+}
+
+fn deny_unreachable() -> CompileOptions {
+    CompileOptions {
+        deny_unreachable_cases: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_switch_duplicate_case_labels() {
+    let source = "{[x](a:1)(b:2)(a:3)(ERR*:4)(>=5:5)(ERR*:6)(>=5:7)}";
+
+    // Allowed by default, and reported as warnings
+    let template = CBTemplate::compile(source).unwrap();
+    let cases: Vec<_> = template
+        .unreachable_cases()
+        .into_iter()
+        .map(|case| (case.label.to_string(), case.position, case.shadowed_by))
+        .collect();
+    assert_eq!(
+        cases,
+        vec![
+            ("a".to_string(), 3, 1),
+            ("ERR*".to_string(), 6, 4),
+            (">=5".to_string(), 7, 5)
+        ]
+    );
+    assert!(
+        template
+            .unreachable_cases()
+            .iter()
+            .all(|case| !case.after_default)
+    );
+
+    let err = CBTemplate::compile_with_options(source, &deny_unreachable()).unwrap_err();
+    assert!(matches!(
+        err,
+        TemplateError::UnreachableCase {
+            ref label,
+            position: 3,
+            shadowed_by: 1,
+            ..
+        } if label == "a"
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Switch case 3, 'a', is unreachable: case 1 has the same label"
+    );
+}
+
+#[test]
+fn test_switch_misplaced_default_case() {
+    let source = "{[x](a:1)(*:any)(b:2)}";
+
+    let template = CBTemplate::compile(source).unwrap();
+    let cases = template.unreachable_cases();
+    assert_eq!(cases.len(), 1);
+    assert_eq!(cases[0].label, CaseLabel::Value(Cow::Borrowed("b")));
+    assert_eq!((cases[0].position, cases[0].shadowed_by), (3, 2));
+    assert!(cases[0].after_default);
+
+    assert!(matches!(
+        CBTemplate::compile_with_options(source, &deny_unreachable()),
+        Err(TemplateError::UnreachableCase {
+            position: 3,
+            shadowed_by: 2,
+            reason: "is a default, which matches every value",
+            ..
+        })
+    ));
+
+    // A default as the last case, or different labels, are fine
+    let valid = "{[x](a:1)(A:2)(a*:3)(>=1:4)(>1:5)(*:any)}";
+    assert!(
+        CBTemplate::compile(valid)
+            .unwrap()
+            .unreachable_cases()
+            .is_empty()
+    );
+    assert!(CBTemplate::compile_with_options(valid, &deny_unreachable()).is_ok());
+}
+
+#[test]
+fn test_switch_unreachable_cases_in_sub_templates() {
+    let source = "{ok ? '{[x](a:1)(a:2)}'} {[y](b:{[z](*:-)(c:3)})(*:no)}";
+
+    let template = CBTemplate::compile(source).unwrap();
+    let positions: Vec<_> = template
+        .unreachable_cases()
+        .iter()
+        .map(|case| (case.position, case.after_default))
+        .collect();
+    assert_eq!(positions, vec![(2, false), (2, true)]);
+
+    for source in ["{ok ? '{[x](a:1)(a:2)}'}", "{[y](b:{[z](*:-)(c:3)})(*:no)}"] {
+        assert!(matches!(
+            CBTemplate::compile_with_options(source, &deny_unreachable()),
+            Err(TemplateError::UnreachableCase { position: 2, .. })
+        ));
+    }
+}
+
+#[test]
+fn test_switch_matched_value_in_case_body() {
+    let template = CBTemplate::compile(
+        "{[status|guest](active:You are {@value})(ERR*:Failed with {@value:>6})(*:[{@value}])}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("status", Value::static_str("active"));
+    assert_eq!(template.format(&ctx).unwrap(), "You are active");
+
+    ctx.insert("status", Value::static_str("ERR42"));
+    assert_eq!(template.format(&ctx).unwrap(), "Failed with  ERR42");
+
+    // The default stands in for a missing value
+    assert_eq!(template.format(&Context::new()).unwrap(), "[guest]");
+
+    ctx.insert("status", Value::Int(7));
+    assert_eq!(template.format(&ctx).unwrap(), "[7]");
+
+    assert_eq!(template.variables(), vec!["status"]);
+}
+
+#[test]
+fn test_switch_matched_value_in_expressions() {
+    let mut ctx = Context::new();
+    ctx.insert("score", Value::Int(85));
+    ctx.insert("tier", Value::static_str("gold"));
+
+    let template = CBTemplate::compile(
+        "{[score](>=80:{@value >= 90 ? 'top' : 'high'} {@value + 5})(*:low)} \
+         {[tier](gold:{[score](>=80:{@value})(*:-)}/{tier})(*:-)} \
+         {[score](>=80:{@value})(*:0) + 1}",
+    )
+    .unwrap();
+
+    // Inner switches shadow the value of outer ones
+    assert_eq!(template.format(&ctx).unwrap(), "high 90 85/gold 86");
+}
+
+#[test]
+fn test_switch_matched_value_outside_switch() {
+    let ctx = Context::new();
+
+    for source in ["{@value}", "{ok ? '{@value}'}", "{[x](a:1)(*:2)}{@value}"] {
+        let mut ctx = ctx.clone();
+        ctx.insert("ok", Value::Bool(true));
+        ctx.insert("x", Value::static_str("a"));
+
+        assert!(
+            matches!(
+                CBTemplate::compile(source).unwrap().format(&ctx),
+                Err(DirectiveError::NotFound { .. })
+            ),
+            "{}",
+            source
+        );
+    }
+
+    let template = CBTemplate::compile("{[x](a:{@value})}").unwrap();
+    assert_eq!(
+        CBTemplate::compile(template.to_source().unwrap()).unwrap(),
+        template
+    );
+}
+
+fn alternative_markers() -> CompileOptions {
+    CompileOptions {
+        markers: Markers {
+            question: '#',
+            colon: ';',
+            case_open: '<',
+            case_close: '>',
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_markers_alternative_set() {
+    let source = "{ok # 'a?b:c' ; 'none'} {[op]<call;f(x) ? y: z><gt;1 \\> 0><*;other>}";
+    let template = CBTemplate::compile_with_options(source, &alternative_markers()).unwrap();
+
+    let mut ctx = Context::new();
+    ctx.insert("ok", Value::Bool(true));
+    ctx.insert("op", Value::Str("call".into()));
+    assert_eq!(template.format(&ctx).unwrap(), "a?b:c f(x) ? y: z");
+
+    ctx.insert("ok", Value::Bool(false));
+    ctx.insert("op", Value::Str("gt".into()));
+    assert_eq!(template.format(&ctx).unwrap(), "none 1 > 0");
+
+    // The replaced characters lose their meaning
+    let template =
+        CBTemplate::compile_with_options("{ok ? 'a' : 'b'}", &alternative_markers()).unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::UnknownDirective { .. })
+    ));
+    // Filters follow the new colon
+    let template = CBTemplate::compile_with_options("[{name;^7}]", &alternative_markers()).unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::Str("ada".into()));
+    assert_eq!(template.format(&ctx).unwrap(), "[  ada  ]");
+}
+
+#[test]
+fn test_markers_lexing() {
+    let markers = Markers {
+        switch_open: '<',
+        switch_close: '>',
+        ..Default::default()
+    };
+    let tokens: Vec<_> = TemplateLexer::with_markers("<x>(a:[b])", &markers).collect();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::LSquare,
+            Token::Ident("x"),
+            Token::RSquare,
+            Token::LParen,
+            Token::Ident("a"),
+            Token::Colon,
+            Token::Text("[b]".into()),
+            Token::RParen,
+        ]
+    );
+    assert_eq!(
+        TemplateLexer::with_markers("[", &markers).collect::<Vec<_>>(),
+        vec![Token::Unknown('[')]
+    );
+}
+
+#[test]
+fn test_markers_invalid() {
+    let cases = [
+        (
+            Markers {
+                question: 'q',
+                ..Default::default()
+            },
+            'q',
+        ),
+        (
+            Markers {
+                colon: '\\',
+                ..Default::default()
+            },
+            '\\',
+        ),
+        (
+            Markers {
+                case_open: '_',
+                ..Default::default()
+            },
+            '_',
+        ),
+        (
+            Markers {
+                colon: 'é',
+                ..Default::default()
+            },
+            'é',
+        ),
+        (
+            Markers {
+                case_close: '?',
+                ..Default::default()
+            },
+            '?',
+        ),
+    ];
+
+    for (markers, expected) in cases {
+        let options = CompileOptions {
+            markers,
+            ..Default::default()
+        };
+        assert!(
+            matches!(
+                CBTemplate::compile_with_options("text", &options),
+                Err(TemplateError::InvalidMarker { marker, .. }) if marker == expected
+            ),
+            "{:?}",
+            markers
+        );
+    }
+}
+
+// ============================================
+// Loop Tests
+// ============================================
+
+fn items_ctx() -> Context {
+    let mut ctx = Context::new();
+    ctx.insert("items", Value::from(vec!["apple", "pear", "fig"]));
+    ctx
+}
+
+#[test]
+fn test_loop_renders_body_per_item() {
+    let ctx = items_ctx();
+
+    for (source, expected) in [
+        ("{items*'{.} '}", "apple pear fig "),
+        ("{items*{.}}", "applepearfig"),
+        (
+            "{items*'{@index}:{.}{@last ? \"\" : \", \"}'}",
+            "0:apple, 1:pear, 2:fig",
+        ),
+        (
+            "{items*'{!@first ? \" | \"}{@index + 1}/{@count}'}",
+            "1/3 | 2/3 | 3/3",
+        ),
+        ("{items*{.:>5}}", "apple pear  fig"),
+        ("{items * '-'}", "---"),
+        ("{items*''}", ""),
+        ("<{ items*{.}}>", "<applepearfig>"),
+        ("{items*{. == 'pear' ? 'P' : {.:len}}}", "5P3"),
+    ] {
+        assert_eq!(
+            CBTemplate::compile(source).unwrap().format(&ctx).unwrap(),
+            expected,
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_loop_over_empty_array() {
+    let mut ctx = Context::new();
+    ctx.insert("items", Value::Array(Vec::new()));
+
+    let template = CBTemplate::compile("[{items*{.}}]").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "[]");
+}
+
+#[test]
+fn test_loop_renders_constant_body_once() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RENDERS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Renders `-`, counting how many times it does.
+    struct Tick;
+
+    impl Directive for Tick {
+        fn exec(&self, _: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+            RENDERS.fetch_add(1, Ordering::Relaxed);
+            Ok(Cow::Borrowed("-"))
+        }
+
+        fn is_constant(&self) -> bool {
+            true
+        }
+    }
+
+    /// The default syntax, plus `{tick}`.
+    struct TickParser;
+
+    impl Parser for TickParser {
+        fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+            match tokens {
+                [Token::Ident("tick")] => Some(Box::new(Tick)),
+                _ => DefaultParser::parse(tokens),
+            }
+        }
+    }
+
+    let ctx = items_ctx();
+    let template = CBTemplate::compile_with_parser::<TickParser>("{items*'{tick} '}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "- - - ");
+    assert_eq!(RENDERS.load(Ordering::Relaxed), 1);
+
+    // A body referencing the item is rendered for each
+    let template =
+        CBTemplate::compile_with_parser::<TickParser>("{items*'{tick}{.:len}'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "-5-4-3");
+    assert_eq!(RENDERS.load(Ordering::Relaxed), 4);
+}
+
+#[test]
+fn test_nested_loops_scope_their_variables() {
+    let mut ctx = Context::new();
+    ctx.insert(
+        "rows",
+        Value::from(vec![Value::from(vec![1, 2]), Value::from(vec![3])]),
+    );
+    ctx.insert("cols", Value::from(vec!["a", "b"]));
+
+    let template =
+        CBTemplate::compile("{rows*'{@index}=[{.:len}]{cols*\"{.}{@index}\"} '}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "0=[2]a0b1 1=[1]a0b1 ");
+}
+
+#[test]
+fn test_nested_loops_over_nested_arrays() {
+    let mut ctx = Context::new();
+    ctx.insert(
+        "rows",
+        Value::from(vec![Value::from(vec![1, 2]), Value::from(vec![3])]),
+    );
+
+    let template = CBTemplate::compile("{rows*{.*{.}}}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "123");
+
+    let template = CBTemplate::compile("{rows*'[{.*\"{@index}:{.} \"}]'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "[0:1 1:2 ][0:3 ]");
+
+    let template = CBTemplate::compile("{rows[0]*{.}} {rows*{.[-1]}}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "12 23");
+}
+
+#[test]
+fn test_scoped_variables_wherever_a_term_is() {
+    let ctx = items_ctx();
+
+    for (source, expected) in [
+        ("{items*{[.](pear:P)(*:-)}}", "-P-"),
+        ("{items*'{[@index](0:first)(*:{.})}'}", "firstpearfig"),
+        ("{items*{[@last|x](true:!)}}", "!"),
+        ("{items*{defined(.) ? 'y' : 'n'}}", "yyy"),
+        ("{items*{!defined(@index) ? 'n' : 'y'}}", "yyy"),
+        ("{defined(.) ? 'y' : 'n'}", "n"),
+        ("{items*{max(@index, 1)}}", "112"),
+    ] {
+        assert_eq!(
+            CBTemplate::compile(source).unwrap().format(&ctx).unwrap(),
+            expected,
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_loop_leaves_lexing_to_the_parser() {
+    // Custom parsers see the tokens after a `*` like any others
+    let tokens: Vec<_> = TemplateLexer::new("items*{.} x").collect();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Ident("items"),
+            Token::Star,
+            Token::LCurly,
+            Token::Unknown('.'),
+            Token::RCurly,
+            Token::Ident("x"),
+        ]
+    );
+
+    // A body that is neither quoted nor a single braced directive is not a loop
+    let template = CBTemplate::compile("{items*{.} x}").unwrap();
+    assert_eq!(template.metrics().loops, 0);
+}
+
+#[test]
+fn test_loop_errors() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert!(matches!(
+        CBTemplate::compile("{missing*'x'}").unwrap().format(&ctx),
+        Err(DirectiveError::NotFound { .. })
+    ));
+    assert!(matches!(
+        CBTemplate::compile("{name*'x'}").unwrap().format(&ctx),
+        Err(DirectiveError::TypeError {
+            expected: "array",
+            ..
+        })
+    ));
+
+    for source in ["{.}", "{@index}", "{@first ? 'x'}", "{items*'x'}{@last}"] {
+        let mut ctx = items_ctx();
+        ctx.insert("name", Value::static_str("Ada"));
+
+        assert!(
+            matches!(
+                CBTemplate::compile(source).unwrap().format(&ctx),
+                Err(DirectiveError::NotFound { .. })
+            ),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_loop_variables_and_source() {
+    let template =
+        CBTemplate::compile("{items*'{@index}: {.}{@last ? \"\" : {sep}}'} {rows*{.*{.}}}")
+            .unwrap();
+
+    assert_eq!(template.variables(), vec!["items", "sep", "rows"]);
+    assert_eq!(template.metrics().loops, 3);
+    assert_eq!(
+        template.to_source().unwrap(),
+        "{items*'{@index}: {.}{@last ? \\'\\' : \\'{sep}\\'}'} {rows*'{.*\\'{.}\\'}'}"
+    );
+    assert_eq!(
+        CBTemplate::compile(template.to_source().unwrap()).unwrap(),
+        template
+    );
+}
+
+#[test]
+fn test_index_with_literal_index() {
+    let ctx = items_ctx();
+
+    for (source, expected) in [
+        ("{items[0]}", "apple"),
+        ("{items[2]}", "fig"),
+        ("{items[-1]}", "fig"),
+        ("{items[-3]}", "apple"),
+        ("{items[1]:>6}", "  pear"),
+        ("{items[0] + '!'}", "apple!"),
+        ("{items[0] == 'apple' ? 'yes' : 'no'}", "yes"),
+        ("{len(items[1])}", "4"),
+        ("{items*{items[-1 ]}}", "figfigfig"),
+    ] {
+        assert_eq!(
+            CBTemplate::compile(source).unwrap().format(&ctx).unwrap(),
+            expected,
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_index_with_variable_index() {
+    let mut ctx = items_ctx();
+    ctx.insert("i", Value::Int(1));
+    ctx.insert("back", Value::Int(-2));
+    ctx.insert(
+        "rows",
+        Value::from(vec![Value::from(vec![1, 2]), Value::from(vec![3])]),
+    );
+
+    for (source, expected) in [
+        ("{items[i]}", "pear"),
+        ("{items[back]}", "pear"),
+        ("{rows[i][0] + rows[0][-1]}", "5"),
+        ("{items*{items[@index]}}", "applepearfig"),
+    ] {
+        assert_eq!(
+            CBTemplate::compile(source).unwrap().format(&ctx).unwrap(),
+            expected,
+            "{}",
+            source
+        );
+    }
+
+    let template = CBTemplate::compile("{items[i]} {rows[i][-1]}").unwrap();
+    assert_eq!(template.variables(), vec!["items", "i", "rows"]);
+    assert_eq!(
+        CBTemplate::compile(template.to_source().unwrap()).unwrap(),
+        template
+    );
+}
+
+#[test]
+fn test_index_out_of_range() {
+    let ctx = items_ctx();
+
+    for (source, index) in [("{items[3]}", 3), ("{items[-4]}", -4)] {
+        match CBTemplate::compile(source).unwrap().format(&ctx) {
+            Err(DirectiveError::IndexOutOfRange {
+                name,
+                index: i,
+                len,
+            }) => {
+                assert_eq!((name.as_str(), i, len), ("items", index, 3), "{}", source)
+            }
+            other => panic!("{}: {:?}", source, other),
+        }
+    }
+
+    // Out of range indices are treated like missing variables
+    let options = FormatOptions {
+        missing: MissingPolicy::Empty,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile("[{items[5]}]").unwrap();
+    assert_eq!(template.format_with_options(&ctx, &options).unwrap(), "[]");
+
+    let template = CBTemplate::compile("{items[5] | 'none'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "none");
+}
+
+#[test]
+fn test_index_type_errors() {
+    let mut ctx = items_ctx();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert!(matches!(
+        CBTemplate::compile("{items[name]}").unwrap().format(&ctx),
+        Err(DirectiveError::TypeError {
+            expected: "integer",
+            ..
+        })
+    ));
+    assert!(matches!(
+        CBTemplate::compile("{name[0]}").unwrap().format(&ctx),
+        Err(DirectiveError::TypeError {
+            expected: "array",
+            ..
+        })
+    ));
+    assert!(matches!(
+        CBTemplate::compile("{missing[0]}").unwrap().format(&ctx),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+// ============================================
+// Switch Expression Tests
+// ============================================
+
+#[test]
+fn test_switch_feeds_conditional() {
+    let template =
+        CBTemplate::compile("{[tier](gold:3)(silver:2)(*:0) >= 2 ? 'High' : 'Low'}").unwrap();
+
+    for (tier, expected) in [("gold", "High"), ("silver", "High"), ("bronze", "Low")] {
+        let mut ctx = Context::new();
+        ctx.insert("tier", Value::static_str(tier));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", tier);
+    }
+}
+
+#[test]
+fn test_switch_as_truthy_condition() {
+    let template = CBTemplate::compile("{[role](admin:true)(*:false) ? 'Edit' : 'View'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("role", Value::static_str("admin"));
+    assert_eq!(template.format(&ctx).unwrap(), "Edit");
+
+    ctx.insert("role", Value::static_str("guest"));
+    assert_eq!(template.format(&ctx).unwrap(), "View");
+
+    // No case matches, so the switch is null, which is falsy
+    let template = CBTemplate::compile("{[role](admin:1) ? 'Edit' : 'View'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "View");
+}
+
+#[test]
+fn test_switch_in_logical_conditions() {
+    let template =
+        CBTemplate::compile("{[tier](gold:2)(*:0) > 1 && !banned ? 'VIP' : 'Regular'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("gold"));
+    ctx.insert("banned", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "VIP");
+
+    ctx.insert("banned", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "Regular");
+}
+
+#[test]
+fn test_arithmetic_on_switch_result() {
+    let template = CBTemplate::compile("{[tier](gold:10)(silver:5)(*:0) + bonus}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("silver"));
+    ctx.insert("bonus", Value::Int(2));
+    assert_eq!(template.format(&ctx).unwrap(), "7");
+
+    ctx.insert("bonus", Value::Float(0.5));
+    assert_eq!(template.format(&ctx).unwrap(), "5.5");
+
+    // Text bodies concatenate
+    let template = CBTemplate::compile("{[tier](gold:Gold)(*:Basic) + ' plan'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "Basic plan");
+}
+
+#[test]
+fn test_switch_result_in_functions_and_filters() {
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("gold"));
+
+    let template = CBTemplate::compile("{max([tier](gold:4)(*:0), 2)}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "4");
+
+    let template = CBTemplate::compile("{[tier](gold:42)(*:0):05}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "00042");
+
+    let template = CBTemplate::compile("{[tier](gold:3)(*:0) + 1 | >4}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "   4");
+}
+
+#[test]
+fn test_switch_result_coalesces_when_nothing_matches() {
+    let template = CBTemplate::compile("{[tier](gold:Gold) ?? 'none'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("tier", Value::static_str("gold"));
+    assert_eq!(template.format(&ctx).unwrap(), "Gold");
+
+    ctx.insert("tier", Value::static_str("bronze"));
+    assert_eq!(template.format(&ctx).unwrap(), "none");
+}
+
+#[test]
+fn test_switch_expression_with_default_scrutinee() {
+    let template = CBTemplate::compile("{[tier|none](none:1)(*:2) + 10}").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), "11");
+
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("gold"));
+    assert_eq!(template.format(&ctx).unwrap(), "12");
+}
+
+#[test]
+fn test_switch_expression_bodies_hold_directives() {
+    let template =
+        CBTemplate::compile("{[tier](gold:{bonus})(*:0) + 1 > 5 ? 'yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tier", Value::static_str("gold"));
+    ctx.insert("bonus", Value::Int(9));
+    assert_eq!(template.format(&ctx).unwrap(), "yes");
+
+    ctx.insert("bonus", Value::Int(1));
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+
+    let mut variables = template.variables();
+    variables.sort();
+    assert_eq!(variables, ["bonus", "tier"]);
+}
+
+#[test]
+fn test_switch_expression_missing_scrutinee() {
+    let template = CBTemplate::compile("{[tier](gold:1)(*:0) + 1}").unwrap();
+    assert!(matches!(
+        template.format(&Context::new()),
+        Err(DirectiveError::NotFound { name, .. }) if name == "tier"
+    ));
+}
+
+#[test]
+fn test_switch_expression_debug_tree_and_optimize() {
+    let template = CBTemplate::compile("{[tier|none](gold:1)(*:0) >= 1 ? 'yes'}").unwrap();
+    assert!(
+        template
+            .debug_tree()
+            .contains("if: [tier|none](gold:1)(*:0) >= \"1\"")
+    );
+
+    let mut template = CBTemplate::compile("{['gold'](gold:2)(*:0) + 1}").unwrap();
+    template.optimize();
+    assert!(matches!(
+        template.iter().next().unwrap().kind(),
+        SegmentKind::Text
+    ));
+    assert_eq!(template.format(&Context::new()).unwrap(), "3");
+}
+
+// ============================================
+// Include Tests
+// ============================================
+
+fn registry() -> TemplateRegistry {
+    let mut registry = TemplateRegistry::new();
+    registry.insert("header", CBTemplate::compile("<h1>{title}</h1>").unwrap());
+    registry.insert(
+        "footer",
+        ParenTemplate::compile("(year) (>'site/sig')").unwrap(),
+    );
+    registry.insert("site/sig", CBTemplate::compile("by {author}").unwrap());
+    registry
+}
+
+#[test]
+fn test_include_renders_partials_with_same_context() {
+    let template = CBTemplate::compile("{>header}\n{body}\n{>footer}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("title", Value::static_str("News"));
+    ctx.insert("body", Value::static_str("Hello"));
+    ctx.insert("year", Value::Int(2026));
+    ctx.insert("author", Value::static_str("Ada"));
+
+    assert_eq!(
+        template.format_with(&ctx, &registry()).unwrap(),
+        "<h1>News</h1>\nHello\n2026 by Ada"
+    );
+}
+
+#[test]
+fn test_include_inside_branches() {
+    let template = CBTemplate::compile("{show ? '{>header}' : 'none'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("title", Value::static_str("T"));
+
+    ctx.insert("show", Value::Bool(true));
+    assert_eq!(
+        template.format_with(&ctx, &registry()).unwrap(),
+        "<h1>T</h1>"
+    );
+
+    ctx.insert("show", Value::Bool(false));
+    assert_eq!(template.format_with(&ctx, &registry()).unwrap(), "none");
+}
+
+#[test]
+fn test_include_missing_partial() {
+    let template = CBTemplate::compile("{>sidebar}").unwrap();
+
+    assert!(matches!(
+        template.format_with(&Context::new(), &registry()),
+        Err(DirectiveError::PartialNotFound { name }) if name == "sidebar"
+    ));
+
+    // Without a registry, no partial exists
+    assert!(matches!(
+        CBTemplate::compile("{>header}")
+            .unwrap()
+            .format(&Context::new()),
+        Err(DirectiveError::PartialNotFound { .. })
+    ));
+}
+
+#[test]
+fn test_include_detects_cycles() {
+    let mut registry = TemplateRegistry::new();
+    registry.insert("a", CBTemplate::compile("a{>b}").unwrap());
+    registry.insert("b", CBTemplate::compile("b{>a}").unwrap());
+    registry.insert("self", CBTemplate::compile("{>self}").unwrap());
+    registry.insert("twice", CBTemplate::compile("{>leaf}{>leaf}").unwrap());
+    registry.insert("leaf", CBTemplate::compile("x").unwrap());
+
+    for (source, name) in [("{>a}", "a"), ("{>b}", "b"), ("{>self}", "self")] {
+        let template = CBTemplate::compile(source).unwrap();
+
+        match template.format_with(&Context::new(), &registry) {
+            Err(DirectiveError::CyclicInclude { name: cycle }) => assert_eq!(cycle, name),
+            other => panic!("{}: unexpected result {:?}", source, other),
+        }
+    }
+
+    // Including the same partial twice side by side is not a cycle
+    let template = CBTemplate::compile("{>twice}{>leaf}").unwrap();
+    assert_eq!(
+        template.format_with(&Context::new(), &registry).unwrap(),
+        "xxx"
+    );
+}
+
+#[test]
+fn test_include_tree_and_builder() {
+    let template = CBTemplate::compile("Hi {>header}").unwrap();
+
+    assert!(template.debug_tree().contains("Include \"header\""));
+    assert!(template.variables().is_empty());
+
+    let built: CBTemplate = TemplateBuilder::new()
+        .literal("Hi ")
+        .include("header")
+        .build();
+    assert_eq!(built, template);
+}
+
+// ============================================
+// Regex Match Tests
+// ============================================
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_anchored_pattern() {
+    let template = CBTemplate::compile("{email =~ '^.+@.+$' ? 'valid' : 'invalid'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("email", Value::static_str("ada@example.com"));
+    assert_eq!(template.format(&ctx).unwrap(), "valid");
+
+    ctx.insert("email", Value::static_str("ada.example.com"));
+    assert_eq!(template.format(&ctx).unwrap(), "invalid");
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_unanchored_pattern_matches_anywhere() {
+    let template = CBTemplate::compile(r"{code =~ '\\d{3}' ? 'has digits' : 'no digits'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("code", Value::static_str("ORD-2024-17"));
+    assert_eq!(template.format(&ctx).unwrap(), "has digits");
+
+    ctx.insert("code", Value::static_str("ORD-17"));
+    assert_eq!(template.format(&ctx).unwrap(), "no digits");
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_matches_string_form_of_numbers() {
+    let template = CBTemplate::compile("{n =~ '^-?[0-9]+$' ? 'int'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(-42));
+
+    assert_eq!(template.format(&ctx).unwrap(), "int");
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_invalid_pattern_is_compile_error() {
+    let result = CBTemplate::compile("Hi {name}, {email =~ '(unclosed' ? 'ok'}");
+
+    match result {
+        Err(TemplateError::InvalidRegex {
+            position, pattern, ..
+        }) => {
+            assert_eq!(position, 11);
+            assert_eq!(pattern, "(unclosed");
+        }
+        other => panic!("expected an invalid regex error, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_variables_and_debug_tree() {
+    let template = CBTemplate::compile("{email =~ '@' ? 'yes'}").unwrap();
+
+    assert_eq!(template.variables(), vec!["email"]);
+    assert!(template.debug_tree().contains(r#"email =~ "@""#));
+}
+
+#[cfg(not(feature = "regex"))]
+#[test]
+fn test_regex_requires_feature() {
+    assert!(matches!(
+        CBTemplate::compile("{email =~ '@' ? 'yes'}"),
+        Err(TemplateError::DirectiveParsing(_))
+    ));
+}
+
+// ============================================
+// String Membership Tests
+// ============================================
+
+#[test]
+fn test_membership_contains() {
+    let template =
+        CBTemplate::compile("{email *= '@company.com' ? 'Internal' : 'External'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("email", Value::static_str("jane@company.com"));
+    assert_eq!(template.format(&ctx).unwrap(), "Internal");
+
+    ctx.insert("email", Value::static_str("jane@example.org"));
+    assert_eq!(template.format(&ctx).unwrap(), "External");
+}
+
+#[test]
+fn test_membership_starts_with_and_ends_with() {
+    let template =
+        CBTemplate::compile("{path ^= '/api' ? 'api' : 'web'} {file $= '.rs' ? 'rust' : 'other'}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("path", Value::static_str("/api/users"));
+    ctx.insert("file", Value::static_str("main.rs"));
+    assert_eq!(template.format(&ctx).unwrap(), "api rust");
+
+    ctx.insert("path", Value::static_str("/home/api"));
+    ctx.insert("file", Value::static_str("main.rs.bak"));
+    assert_eq!(template.format(&ctx).unwrap(), "web other");
+}
+
+#[test]
+fn test_membership_with_variable_operand() {
+    let template = CBTemplate::compile("{title *= query ? 'match' : 'no match'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("title", Value::static_str("The Rust Book"));
+    ctx.insert("query", Value::static_str("Rust"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "match");
+}
+
+#[test]
+fn test_membership_is_case_sensitive() {
+    let template = CBTemplate::compile("{role *= 'admin' ? 'yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("role", Value::static_str("Admin"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "no");
+}
+
+#[test]
+fn test_membership_stringifies_numbers() {
+    let template = CBTemplate::compile("{code ^= 40 ? 'client error' : 'other'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::Int(404));
+
+    assert_eq!(template.format(&ctx).unwrap(), "client error");
+}
+
+#[test]
+fn test_membership_operators_do_not_break_fill_chars() {
+    let template = CBTemplate::compile("[{'x':*^5}] [{'x':^^5}]").unwrap();
+    let ctx = Context::new();
+
+    assert_eq!(template.format(&ctx).unwrap(), "[**x**] [^^x^^]");
+}
+
+// ============================================
+// Case-Insensitive Equality Tests
+// ============================================
+
+#[test]
+fn test_equals_ignore_case_ascii() {
+    let template = CBTemplate::compile("{role ~= 'admin' ? 'Full' : 'Limited'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("role", Value::static_str("Admin"));
+    assert_eq!(template.format(&ctx).unwrap(), "Full");
+
+    ctx.insert("role", Value::static_str("ADMINISTRATOR"));
+    assert_eq!(template.format(&ctx).unwrap(), "Limited");
+}
+
+#[test]
+fn test_equals_ignore_case_unicode() {
+    let template = CBTemplate::compile("{city ~= other ? 'same' : 'diff'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("city", Value::static_str("ZÜRICH"));
+    ctx.insert("other", Value::static_str("zürich"));
+    assert_eq!(template.format(&ctx).unwrap(), "same");
+
+    ctx.insert("city", Value::static_str("Ωμέγα"));
+    ctx.insert("other", Value::static_str("ωΜΈΓΑ"));
+    assert_eq!(template.format(&ctx).unwrap(), "same");
+}
+
+#[test]
+fn test_equals_ignore_case_numbers_compare_text() {
+    let template =
+        CBTemplate::compile("{n ~= '42' ? 'a' : 'b'} {n ~= '42.0' ? 'a' : 'b'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(42));
+
+    assert_eq!(template.format(&ctx).unwrap(), "a b");
+}
+
+// ============================================
+// Range Tests
+// ============================================
+
+#[test]
+fn test_range_includes_both_bounds() {
+    let template = CBTemplate::compile("{age in 13..19 ? 'teen' : 'other'}").unwrap();
+    let mut ctx = Context::new();
+
+    for (age, expected) in [
+        (12, "other"),
+        (13, "teen"),
+        (16, "teen"),
+        (19, "teen"),
+        (20, "other"),
+    ] {
+        ctx.insert("age", Value::Int(age));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", age);
+    }
+}
+
+#[test]
+fn test_range_with_floats() {
+    let template = CBTemplate::compile("{t in -0.5..37.5 ? 'ok' : 'out'}").unwrap();
+    let mut ctx = Context::new();
+
+    for (t, expected) in [
+        (Value::Float(-0.5), "ok"),
+        (Value::Float(37.6), "out"),
+        (Value::Int(20), "ok"),
+        (Value::Float(f64::NAN), "out"),
+    ] {
+        ctx.insert("t", t);
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_range_with_variable_bounds() {
+    let template = CBTemplate::compile("{x in lo..max(lo, hi) ? 'in' : 'out'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("lo", Value::Int(5));
+    ctx.insert("hi", Value::Float(7.5));
+
+    ctx.insert("x", Value::Int(7));
+    assert_eq!(template.format(&ctx).unwrap(), "in");
+
+    ctx.insert("x", Value::Int(8));
+    assert_eq!(template.format(&ctx).unwrap(), "out");
+
+    assert_eq!(template.variables(), vec!["x", "lo", "hi"]);
+}
+
+#[test]
+fn test_range_rejects_non_numbers() {
+    let template = CBTemplate::compile("{age in 13..19 ? 'teen'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("age", Value::static_str("15"));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError {
+            expected: "number",
+            ..
+        })
+    ));
+
+    let template = CBTemplate::compile("{age in 'a'..19 ? 'teen'}").unwrap();
+    ctx.insert("age", Value::Int(15));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::ParseError {
+            type_name: "number",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_range_invalid_syntax_renders_empty() {
+    let template = CBTemplate::compile("[{age in 13.. ? 'a'}][{age in 1..2..3 ? 'b'}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("age", Value::Int(2));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+            .unwrap(),
+        "[][]"
+    );
+}
+
+#[test]
+fn test_range_tree_and_equality() {
+    let template = CBTemplate::compile("{age in 13..19 ? 'teen'}").unwrap();
+
+    assert!(template.debug_tree().contains("age in \"13\"..\"19\""));
+    assert_eq!(
+        template,
+        CBTemplate::compile("{age  in 13 .. 19?'teen'}").unwrap()
+    );
+}
+
+// ============================================
+// List Membership Tests
+// ============================================
+
+#[test]
+fn test_in_list_of_literals() {
+    let template = CBTemplate::compile("{role in ['admin', 'editor'] ? 'grant' : 'deny'}").unwrap();
+    let mut ctx = Context::new();
+
+    for (role, expected) in [("editor", "grant"), ("admin", "grant"), ("guest", "deny")] {
+        ctx.insert("role", Value::static_str(role));
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_in_list_of_numbers_and_variables() {
+    let template = CBTemplate::compile("{code in [200, 204, fallback] ? 'ok' : 'error'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("fallback", Value::Int(304));
+
+    for (code, expected) in [(204, "ok"), (304, "ok"), (404, "error")] {
+        ctx.insert("code", Value::Int(code));
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_in_list_mismatched_types() {
+    // Literals compare as text, so a mismatched literal just doesn't match
+    let template = CBTemplate::compile("{code in ['two', 3] ? 'yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::Int(3));
+    assert_eq!(template.format(&ctx).unwrap(), "yes");
+
+    // Variables keep their types, as with `==`
+    let template = CBTemplate::compile("{code in [name] ? 'yes' : 'no'}").unwrap();
+    ctx.insert("name", Value::static_str("three"));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { name, .. }) if name == "name"
+    ));
+}
+
+#[test]
+fn test_in_list_combined_and_negated() {
+    let template =
+        CBTemplate::compile("{!(role in ['banned']) && tier in ['gold', 'silver'] ? 'vip' : 'std'} {x in [] ? 'never' : 'empty'}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("role", Value::static_str("user"));
+    ctx.insert("tier", Value::static_str("gold"));
+    ctx.insert("x", Value::Int(1));
+    assert_eq!(template.format(&ctx).unwrap(), "vip empty");
+
+    ctx.insert("role", Value::static_str("banned"));
+    assert_eq!(template.format(&ctx).unwrap(), "std empty");
+}
+
+#[test]
+fn test_in_list_round_trips_and_inspects() {
+    let source = "{role in ['admin', level, 3] ? 'yes'}";
+    let template = CBTemplate::compile(source).unwrap();
+
+    assert_eq!(template.to_source().as_deref(), Some(source));
+    assert!(
+        template
+            .debug_tree()
+            .contains("if: role in [\"admin\", level, \"3\"]")
+    );
+    assert_eq!(template.variables(), vec!["role", "level"]);
+    assert_eq!(
+        template,
+        TemplateBuilder::new()
+            .conditional(
+                Argument::in_list(var("role"), vec![lit("admin"), var("level"), lit("3")]),
+                lit("yes"),
+                None
+            )
+            .build()
+    );
+}
+
+#[test]
+fn test_in_list_invalid_syntax_renders_nothing() {
+    let template = CBTemplate::compile("[{a in [1, ] ? 'x'}][{a in [1 ? 'x'}]").unwrap();
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &with_unknown(UnknownPolicy::Empty))
+            .unwrap(),
+        "[][]"
+    );
+}
+
+// ============================================
+// Presence Tests
+// ============================================
+
+#[test]
+fn test_defined_present_but_false() {
+    let template =
+        CBTemplate::compile("{defined(coupon) ? 'Coupon applied' : 'No coupon'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("coupon", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "Coupon applied");
+
+    ctx.insert("coupon", Value::Int(0));
+    assert_eq!(template.format(&ctx).unwrap(), "Coupon applied");
+
+    ctx.insert("coupon", Value::static_str(""));
+    assert_eq!(template.format(&ctx).unwrap(), "Coupon applied");
+}
+
+#[test]
+fn test_defined_present_null_and_absent() {
+    let template =
+        CBTemplate::compile("{defined(coupon) ? 'Coupon applied' : 'No coupon'}").unwrap();
+    let mut ctx = Context::new();
+    assert_eq!(template.format(&ctx).unwrap(), "No coupon");
+
+    ctx.insert("coupon", Value::Null);
+    assert_eq!(template.format(&ctx).unwrap(), "No coupon");
+}
+
+#[test]
+fn test_defined_negated() {
+    let template = CBTemplate::compile("{!defined(name) ? 'anonymous' : 'Hi {name}'}").unwrap();
+    let mut ctx = Context::new();
+    assert_eq!(template.format(&ctx).unwrap(), "anonymous");
+
+    ctx.insert("name", Value::static_str("Ada"));
+    assert_eq!(template.format(&ctx).unwrap(), "Hi Ada");
+}
+
+#[test]
+fn test_defined_differs_from_truthiness() {
+    let template = CBTemplate::compile("{defined(n) ? 'set'}/{n ? 'truthy'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(0));
+    assert_eq!(template.format(&ctx).unwrap(), "set/");
+}
+
+#[test]
+fn test_defined_with_layered_contexts() {
+    let template = CBTemplate::compile("{defined(theme) ? theme : 'default'}").unwrap();
+    let mut defaults = Context::new();
+    defaults.insert("theme", Value::static_str("dark"));
+    let overrides = Context::new();
+
+    let result = template.format_layered(&[&overrides, &defaults]).unwrap();
+    assert_eq!(result, "dark");
+}
+
+#[test]
+fn test_defined_counts_as_a_variable_reference() {
+    let template = CBTemplate::compile("{defined(coupon) ? 'yes'}").unwrap();
+    assert_eq!(template.variables(), vec!["coupon"]);
+    assert!(template.debug_tree().contains("defined(coupon)"));
+
+    let mut ctx = Context::new();
+    ctx.insert("coupon", Value::Int(1));
+    assert!(template.unused_keys(&ctx).is_empty());
+}
+
+// ============================================
+// Type Test Tests
+// ============================================
+
+#[test]
+fn test_type_tests_against_every_variant() {
+    let tests = [
+        "is_string",
+        "is_number",
+        "is_int",
+        "is_float",
+        "is_bool",
+        "is_bytes",
+        "is_array",
+        "is_null",
+    ];
+    let source: Vec<String> = tests
+        .iter()
+        .map(|test| format!("{{{test}(x) ? 'y' : 'n'}}"))
+        .collect();
+    let template = CBTemplate::compile(source.join("")).unwrap();
+
+    for (value, expected) in [
+        (Value::static_str("5"), "ynnnnnnn"),
+        (Value::Int(5), "nyynnnnn"),
+        (Value::Float(0.5), "nynynnnn"),
+        (Value::Bool(false), "nnnnynnn"),
+        (Value::from(vec![1u8, 2]), "nnnnnynn"),
+        (Value::from(vec![1, 2]), "nnnnnnyn"),
+        (Value::Null, "nnnnnnny"),
+    ] {
+        let mut ctx = Context::new();
+        ctx.insert("x", value.clone());
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{:?}", value);
+    }
+
+    // A missing variable counts as null, even with `MissingPolicy::Error`
+    assert_eq!(template.format(&Context::new()).unwrap(), "nnnnnnny");
+}
+
+#[test]
+fn test_type_tests_compose_with_logical_operators() {
+    let template = CBTemplate::compile(
+        "{is_number(x) && x > 10 ? 'big' : !is_null(x) && !is_number(x) ? 'text' : 'other'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("x", Value::Int(42));
+    assert_eq!(template.format(&ctx).unwrap(), "big");
+
+    ctx.insert("x", Value::Int(2));
+    assert_eq!(template.format(&ctx).unwrap(), "other");
+
+    ctx.insert("x", Value::static_str("hello"));
+    assert_eq!(template.format(&ctx).unwrap(), "text");
+
+    assert_eq!(template.format(&Context::new()).unwrap(), "other");
+}
+
+#[test]
+fn test_type_tests_of_expressions() {
+    let template =
+        CBTemplate::compile("{is_string(a + b) ? 's' : 'n'}{is_float(max(a, 0.5)) ? 'f'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("a", Value::Int(1));
+    ctx.insert("b", Value::Int(2));
+    assert_eq!(template.format(&ctx).unwrap(), "nf");
+
+    ctx.insert("a", Value::Float(0.1));
+    ctx.insert("b", Value::static_str("x"));
+    assert_eq!(template.format(&ctx).unwrap(), "sf");
+
+    // Only a bare variable counts as null when missing
+    assert!(matches!(
+        template.format(&Context::new()),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_type_tests_render_and_inspect() {
+    let template = CBTemplate::compile("{is_null(x)} {!is_int(x) ? 'no int'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Float(1.0));
+
+    assert_eq!(template.format(&ctx).unwrap(), "false no int");
+    assert_eq!(template.variables(), vec!["x"]);
+    assert!(template.debug_tree().contains("if: !is_int(x)"));
+
+    // Type tests take a single operand
+    let template = CBTemplate::compile("{is_null(a, b) ? 'x'}").unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::UnknownDirective { .. })
+    ));
+}
+
+// ============================================
+// Fallback Tests
+// ============================================
+
+#[test]
+fn test_fallback_to_variable() {
+    let template = CBTemplate::compile("Hi {nick | {name}}!").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Hi Ada!");
+
+    ctx.insert("nick", Value::static_str("ada99"));
+    assert_eq!(template.format(&ctx).unwrap(), "Hi ada99!");
+}
+
+#[test]
+fn test_fallback_chain_of_variables() {
+    let flat = CBTemplate::compile("{nick | {name} | {login} | 'anonymous'}").unwrap();
+    let nested = CBTemplate::compile("{nick|{name|{login|'anonymous'}}}").unwrap();
+    let mut ctx = Context::new();
+
+    for (key, expected) in [
+        (None, "anonymous"),
+        (Some(("login", "ada_l")), "ada_l"),
+        (Some(("name", "Ada")), "Ada"),
+        (Some(("nick", "ada99")), "ada99"),
+    ] {
+        if let Some((key, value)) = key {
+            ctx.insert(key, Value::static_str(value));
+        }
+
+        assert_eq!(flat.format(&ctx).unwrap(), expected);
+        assert_eq!(nested.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_fallback_to_literal_and_sub_template() {
+    let template = CBTemplate::compile("{nick | 'Dear {name}'}, {title | 'untitled'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Dear Ada, untitled");
+}
+
+#[test]
+fn test_fallback_to_directive_with_filters() {
+    let template = CBTemplate::compile("{bio | trunc(8) | {name | >6}}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "   Ada");
+
+    ctx.insert("bio", Value::static_str("Wrote the first program"));
+    assert_eq!(template.format(&ctx).unwrap(), "Wrote t…");
+}
+
+#[test]
+fn test_fallback_only_covers_missing_variables() {
+    let template = CBTemplate::compile("{count:hex | 'none'}").unwrap();
+    let mut ctx = Context::new();
+
+    assert_eq!(template.format(&ctx).unwrap(), "none");
+
+    ctx.insert("count", Value::static_str("many"));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError { .. })
+    ));
+
+    // Null is a value, not a missing variable
+    let template = CBTemplate::compile("[{nick | 'anonymous'}]").unwrap();
+    ctx.insert("nick", Value::Null);
+    assert_eq!(template.format(&ctx).unwrap(), "[]");
+}
+
+#[test]
+fn test_fallback_missing_at_the_end_of_the_chain() {
+    let template = CBTemplate::compile("[{nick | {name}}]").unwrap();
+
+    assert!(matches!(
+        template.format(&Context::new()),
+        Err(DirectiveError::NotFound { ref name, .. }) if name == "name"
+    ));
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &with_missing(MissingPolicy::Empty))
+            .unwrap(),
+        "[]"
+    );
+}
+
+#[test]
+fn test_fallback_replaces_output_before_a_nested_missing_variable() {
+    let template = CBTemplate::compile("[{s ? 'pre {missing} post' : 'n' | 'FB'}]").unwrap();
+    let ctx = Context::from([("s", Value::Bool(true))]);
+
+    assert_eq!(template.format(&ctx).unwrap(), "[FB]");
+
+    let mut bytes = Vec::new();
+    template.format_to(&ctx, &mut bytes).unwrap();
+    assert_eq!(bytes, b"[FB]");
+
+    let nested =
+        CBTemplate::compile("[{t ? {s ? 'pre {missing} post' : 'n' | 'FB'} : 'x'}]").unwrap();
+    let ctx = Context::from([("s", Value::Bool(true)), ("t", Value::Bool(true))]);
+    assert_eq!(nested.format(&ctx).unwrap(), "[FB]");
+}
+
+#[test]
+fn test_conditional_branches_as_directives() {
+    let template = CBTemplate::compile("{has_nick ? {nick} : {name}}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("nick", Value::static_str("ada99"));
+    ctx.insert("name", Value::static_str("Ada"));
+
+    ctx.insert("has_nick", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "ada99");
+
+    ctx.insert("has_nick", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "Ada");
+}
+
+#[test]
+fn test_conditional_branches_nest_directives() {
+    let template =
+        CBTemplate::compile("{vip ? {nick | {name:trunc(3)}} : {guest ? 'Dear {name}' : {'-':3}}}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada Lovelace"));
+    ctx.insert("guest", Value::Bool(false));
+
+    ctx.insert("vip", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "Ad…");
+
+    ctx.insert("vip", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "---");
+
+    ctx.insert("guest", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "Dear Ada Lovelace");
+}
+
+#[test]
+fn test_braced_branches_in_else_if_ladder() {
+    let template = CBTemplate::compile("{a ? {x} : b ? {y | 'no y'} : 'none'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Bool(false));
+    ctx.insert("b", Value::Bool(true));
+
+    assert_eq!(template.format(&ctx).unwrap(), "no y");
+}
+
+#[test]
+fn test_fallback_variables_and_structure() {
+    let template = CBTemplate::compile("{nick | {name} | 'x'} {vip ? {title}}").unwrap();
+    let mut variables = template.variables();
+    variables.sort();
+
+    assert_eq!(variables, vec!["name", "nick", "title", "vip"]);
+    assert_eq!(
+        template,
+        CBTemplate::compile("{nick|{ name }|'x'} {vip?{title}}").unwrap()
+    );
+    assert!(template.debug_tree().contains("Fallback\n"));
+}
+
+#[test]
+fn test_unbalanced_braced_branch_is_not_a_directive() {
+    // `{a} : {b}` as a whole is not one braced directive
+    let template = CBTemplate::compile("[{flag ? {a} {b}}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("flag", Value::Bool(true));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::UnknownDirective { ref directive }) if directive == "{flag ? {a} {b}}"
+    ));
+}
+
+// ============================================
+// Coalescing Tests
+// ============================================
+
+#[test]
+fn test_coalesce_picks_first_present_value() {
+    let template =
+        CBTemplate::compile("{nickname ?? display_name ?? username ?? \"Anonymous\"}").unwrap();
+    let mut ctx = Context::new();
+
+    // All absent
+    assert_eq!(template.format(&ctx).unwrap(), "Anonymous");
+
+    // Middle present
+    ctx.insert("display_name", Value::static_str("Ada L."));
+    assert_eq!(template.format(&ctx).unwrap(), "Ada L.");
+
+    // First present
+    ctx.insert("username", Value::static_str("ada"));
+    ctx.insert("nickname", Value::static_str("ada99"));
+    assert_eq!(template.format(&ctx).unwrap(), "ada99");
+}
+
+#[test]
+fn test_coalesce_skips_nulls_but_not_falsy_values() {
+    let template = CBTemplate::compile("{a ?? b ?? 'none'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("a", Value::Null);
+    ctx.insert("b", Value::Null);
+    assert_eq!(template.format(&ctx).unwrap(), "none");
+
+    for (value, expected) in [
+        (Value::Bool(false), "false"),
+        (Value::Int(0), "0"),
+        (Value::static_str(""), ""),
+    ] {
+        ctx.insert("b", value);
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_coalesce_without_present_value_is_null() {
+    let template = CBTemplate::compile("[{a ?? b}]").unwrap();
+
+    assert_eq!(template.format(&Context::new()).unwrap(), "[]");
+
+    let template = CBTemplate::compile("[{a ?? b ?? 'x':len}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("b", Value::Null);
+    assert_eq!(template.format(&ctx).unwrap(), "[1]");
+}
+
+#[test]
+fn test_coalesce_in_expressions() {
+    let template = CBTemplate::compile(
+        "{nick ?? name:>6}|{count ?? 0 + 1}|{role ?? 'guest' == 'admin' ? 'A' : 'U'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    // `??` binds more loosely than `+`, so the default is `0 + 1`
+    assert_eq!(template.format(&ctx).unwrap(), "   Ada|1|U");
+
+    ctx.insert("count", Value::Int(5));
+    ctx.insert("role", Value::static_str("admin"));
+    assert_eq!(template.format(&ctx).unwrap(), "   Ada|5|A");
+}
+
+#[test]
+fn test_coalesce_keeps_other_errors_and_lists_variables() {
+    let template = CBTemplate::compile("{a ?? max(b, 1)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("b", Value::static_str("x"));
+
+    assert!(template.format(&ctx).is_err());
+    assert_eq!(template.variables(), vec!["a", "b"]);
+
+    let tokens: Vec<_> = TemplateLexer::new("a ?? b ? c").collect();
+    assert_eq!(
+        tokens,
+        [
+            Token::Ident("a"),
+            Token::Coalesce,
+            Token::Ident("b"),
+            Token::Question,
+            Token::Ident("c")
+        ]
+    );
+}
+
+// ============================================
+// Error Message Tests
+// ============================================
+
+#[test]
+fn test_error_message_replaces_missing_variable_error() {
+    let template = CBTemplate::compile("Total: {price !! \"price is required\"}").unwrap();
+    let err = template.format(&Context::new()).unwrap_err();
+
+    assert_eq!(err.to_string(), "price is required");
+    assert!(matches!(
+        &err,
+        DirectiveError::CustomMessage { source, .. }
+            if matches!(**source, DirectiveError::NotFound { .. })
+    ));
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+#[test]
+fn test_error_message_replaces_type_error() {
+    let template = CBTemplate::compile("{id:hex !! 'id must be a number'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("id", Value::static_str("abc"));
+
+    let err = template.format(&ctx).unwrap_err();
+    assert_eq!(err.to_string(), "id must be a number");
+
+    ctx.insert("id", Value::Int(255));
+    assert_eq!(template.format(&ctx).unwrap(), "ff");
+}
+
+#[test]
+fn test_error_message_is_optional() {
+    let template = CBTemplate::compile("{price}").unwrap();
+    let err = template.format(&Context::new()).unwrap_err();
+    assert!(matches!(err, DirectiveError::NotFound { .. }));
+}
+
+#[test]
+fn test_error_message_on_conditional() {
+    let template =
+        CBTemplate::compile("{vip ? 'Welcome {name}!' : 'Hello' !! 'vip flag missing'}").unwrap();
+    let mut ctx = Context::new();
+    assert_eq!(
+        template.format(&ctx).unwrap_err().to_string(),
+        "vip flag missing"
+    );
+
+    ctx.insert("vip", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "Hello");
+}
+
+#[test]
+fn test_error_message_keeps_missing_policy() {
+    let template = CBTemplate::compile("[{price !! 'price is required'}]").unwrap();
+    let result = template
+        .format_with_options(&Context::new(), &with_missing(MissingPolicy::Empty))
+        .unwrap();
+    assert_eq!(result, "[]");
+}
+
+#[test]
+fn test_error_message_structural_equality() {
+    let a = CBTemplate::compile("{price !! 'required'}").unwrap();
+    let b = CBTemplate::compile("{ price!!'required' }").unwrap();
+    let c = CBTemplate::compile("{price !! 'missing'}").unwrap();
+
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_eq!(a.variables(), vec!["price"]);
+    assert!(a.debug_tree().contains("ErrorMessage \"required\""));
+}
+
+// ============================================
+// Diagnostic Tests
+// ============================================
+
+#[test]
+fn test_format_spanned_points_at_failing_directive() {
+    let template = CBTemplate::compile("Hello {name},\nyou owe {amount:hex} coins").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("amount", Value::Float(1.5));
+
+    let error = template.format_spanned(&ctx).unwrap_err();
+    assert_eq!(error.span, 22..34);
+    assert!(matches!(
+        error.error,
+        DirectiveError::FilterTypeError { .. }
+    ));
+    assert_eq!(
+        error.diagnostic(),
+        "error: Filter 'hex' expected a value of type 'integer' but got 'float'\n \
+         --> 2:9\n  \
+         |\n\
+         2 | you owe {amount:hex} coins\n  \
+         |         ^^^^^^^^^^^^"
+    );
+
+    ctx.insert("amount", Value::Int(255));
+    assert_eq!(
+        template.format_spanned(&ctx).unwrap(),
+        "Hello Ada,\nyou owe ff coins"
+    );
+}
+
+#[test]
+fn test_format_spanned_matches_format_errors() {
+    let template = CBTemplate::compile("Hi {name}!").unwrap();
+    let error = template.format_spanned(&Context::new()).unwrap_err();
+
+    assert_eq!(error.span, 3..9);
+    assert_eq!(
+        error.error.to_string(),
+        template.format(&Context::new()).unwrap_err().to_string()
+    );
+    assert_eq!(
+        error.to_string(),
+        format!("{} (at bytes 3..9)", error.error)
+    );
+}
+
+#[test]
+fn test_format_spanned_nested_error_points_at_enclosing_directive() {
+    let template = CBTemplate::compile("A {vip ? 'Dear {name}' : 'Hi'} B").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(true));
+
+    let error = template.format_spanned(&ctx).unwrap_err();
+    assert_eq!(error.span, 2..30);
+    assert!(matches!(error.error, DirectiveError::NotFound { ref name, .. } if name == "name"));
+}
+
+#[test]
+fn test_diagnostic_keeps_tabs_and_counts_chars() {
+    let template = CBTemplate::compile("\tévé {missing}").unwrap();
+    let error = template.format_spanned(&Context::new()).unwrap_err();
+
+    assert!(
+        error
+            .diagnostic()
+            .ends_with("1 | \tévé {missing}\n  | \t    ^^^^^^^^^")
+    );
+}
+
+#[test]
+fn test_diagnostic_with_runtime_delimiters() {
+    let delimiters = Delimiters::new("${", "}").unwrap();
+    let template = Template::with_delimiters("Hi ${name}", delimiters).unwrap();
+    let error = template.format_spanned(&Context::new()).unwrap_err();
+
+    assert_eq!(error.span, 3..10);
+    assert!(
+        error
+            .diagnostic()
+            .ends_with("1 | Hi ${name}\n  |    ^^^^^^^")
+    );
+}
+
+#[test]
+fn test_diagnostic_without_source() {
+    let template: CBTemplate = TemplateBuilder::new().literal("Hi ").var("name").build();
+    let error = template.format_spanned(&Context::new()).unwrap_err();
+
+    assert_eq!(error.span, 0..0);
+    assert_eq!(
+        error.diagnostic(),
+        "error: Variable 'name' was not found in the context while being used as 'string'"
+    );
+}
+
+#[test]
+fn test_error_unifies_compile_and_render_failures() {
+    fn render(source: &str, ctx: &Context) -> Result<String, figura::Error> {
+        Ok(CBTemplate::compile(source)?.format(ctx)?)
+    }
+
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Float(1.5));
+
+    assert!(matches!(
+        render("Hi {name", &ctx),
+        Err(figura::Error::Template(
+            TemplateError::MissingDelimiter { .. }
+        ))
+    ));
+    assert!(matches!(
+        render("Hi {name}", &ctx),
+        Err(figura::Error::Directive(DirectiveError::NotFound { .. }))
+    ));
+    assert!(matches!(
+        render("{n:hex}", &ctx),
+        Err(figura::Error::Directive(
+            DirectiveError::FilterTypeError { .. }
+        ))
+    ));
+    assert_eq!(render("{n}", &ctx).unwrap(), "1.5");
+}
+
+#[test]
+fn test_render_compiles_and_formats_in_one_call() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(
+        figura::render_default("Hi {name}!", &ctx).unwrap(),
+        "Hi Ada!"
+    );
+    assert_eq!(
+        figura::render::<'[', ']'>("Hi [name]! {name}", &ctx).unwrap(),
+        "Hi Ada! {name}"
+    );
+
+    assert!(matches!(
+        figura::render_default("Hi {name", &ctx),
+        Err(figura::Error::Template(
+            TemplateError::MissingDelimiter { .. }
+        ))
+    ));
+    assert!(matches!(
+        figura::render_default("Hi {nick}", &ctx),
+        Err(figura::Error::Directive(DirectiveError::NotFound { .. }))
+    ));
+}
+
+#[test]
+fn test_error_display_is_the_wrapped_error() {
+    let err = figura::Error::from(TemplateError::NestingTooDeep(4));
+    assert_eq!(
+        err.to_string(),
+        "Directives are nested deeper than the limit of 4"
+    );
+
+    let err = figura::Error::from(DirectiveError::UnknownDirective {
+        directive: "{x:bogus(1)}".to_string(),
+    });
+    assert_eq!(err.to_string(), "Unknown directive '{x:bogus(1)}'");
+    assert!(std::error::Error::source(&err).is_none());
+}
+
+#[test]
+fn test_format_spanned_output_limit() {
+    let template = CBTemplate::compile("ok {'x':100}").unwrap();
+    let options = FormatOptions {
+        max_output_len: 10,
+        ..Default::default()
+    };
+
+    let error = template
+        .format_spanned_with_options(&Context::new(), &options)
+        .unwrap_err();
+    assert_eq!(error.span, 3..12);
+    assert!(matches!(
+        error.error,
+        DirectiveError::OutputLimitExceeded { limit: 10 }
+    ));
+}
+
+// ============================================
+// Validation Tests
+// ============================================
+
+#[test]
+fn test_validate_catches_missing_variables() {
+    let template = CBTemplate::compile("Dear {title} {name}, {greeting}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    let errors = template
+        .validate(&ctx, &FormatOptions::default())
+        .unwrap_err();
+    let spans: Vec<_> = errors.iter().map(|e| e.span.clone()).collect();
+    assert_eq!(spans, vec![5..12, 21..31]);
+    assert!(
+        errors
+            .iter()
+            .all(|e| matches!(e.error, DirectiveError::NotFound { .. }))
+    );
+
+    // Missing variables are fine when the policy renders them
+    let options = FormatOptions {
+        missing: MissingPolicy::Empty,
+        ..Default::default()
+    };
+    assert!(template.validate(&ctx, &options).is_ok());
+}
+
+#[test]
+fn test_validate_catches_type_errors() {
+    let template =
+        CBTemplate::compile("{id:hex} {count > limit ? 'over'} {'-':width} {ok}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("id", Value::static_str("abc"));
+    ctx.insert("count", Value::Int(3));
+    ctx.insert("limit", Value::static_str("three"));
+    ctx.insert("width", Value::static_str("wide"));
+    ctx.insert("ok", Value::Int(1));
+
+    let errors = template
+        .validate(&ctx, &FormatOptions::default())
+        .unwrap_err();
+    assert_eq!(errors.len(), 3);
+    assert!(matches!(
+        errors[0].error,
+        DirectiveError::FilterTypeError { .. }
+    ));
+    assert!(matches!(&errors[1].error, DirectiveError::TypeError { name, .. } if name == "limit"));
+    assert!(matches!(errors[2].error, DirectiveError::TypeError { .. }));
+
+    // Each error is the one rendering reports first
+    assert_eq!(
+        errors[0].error.to_string(),
+        template.format(&ctx).unwrap_err().to_string()
+    );
+}
+
+#[test]
+fn test_validate_does_not_repeat() {
+    let template = CBTemplate::compile("{'ab':count}{pattern:3}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("count", Value::Int(i64::MAX));
+    ctx.insert("pattern", Value::static_str("x"));
+
+    // The output is far too long to build, but nothing is built
+    assert!(template.validate(&ctx, &FormatOptions::default()).is_ok());
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::OutputLimitExceeded { .. })
+    ));
+
+    ctx.insert("pattern", Value::Bytes(vec![1, 2]));
+    let errors = template
+        .validate(&ctx, &FormatOptions::default())
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].span, 12..23);
+}
+
+#[test]
+fn test_validate_checks_selected_branches() {
+    let template =
+        CBTemplate::compile("{vip ? '{title:hex}' : '{name}'} {[tier](gold:{'*':stars})(*:-)}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(false));
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("tier", Value::static_str("silver"));
+
+    // Neither `title` nor `stars` is needed for the branches taken
+    assert!(template.validate(&ctx, &FormatOptions::default()).is_ok());
+
+    ctx.insert("vip", Value::Bool(true));
+    ctx.insert("tier", Value::static_str("gold"));
+    let errors = template
+        .validate(&ctx, &FormatOptions::default())
+        .unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(
+        errors
+            .iter()
+            .all(|e| matches!(e.error, DirectiveError::NotFound { .. }))
+    );
+
+    ctx.insert("title", Value::Int(10));
+    ctx.insert("stars", Value::Int(1_000_000_000_000));
+    assert!(template.validate(&ctx, &FormatOptions::default()).is_ok());
+}
+
+// ============================================
+// Alignment Tests
+// ============================================
+
+#[test]
+fn test_align_left() {
+    let template = CBTemplate::compile("[{name:<8}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Bob"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[Bob     ]");
+}
+
+#[test]
+fn test_align_right() {
+    let template = CBTemplate::compile("[{count:>5}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("count", Value::Int(42));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[   42]");
+}
+
+#[test]
+fn test_align_center_with_fill() {
+    let template = CBTemplate::compile("[{name:*^8}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Bob"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[**Bob***]");
+}
+
+#[test]
+fn test_align_zero_fill_number() {
+    let template = CBTemplate::compile("{id:0>4}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("id", Value::Int(7));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "0007");
+}
+
+#[test]
+fn test_align_counts_chars_not_bytes() {
+    let template = CBTemplate::compile("[{word:<6}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("word", Value::static_str("héllo"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[héllo ]");
+}
+
+#[test]
+fn test_align_does_not_truncate() {
+    let template = CBTemplate::compile("[{name:>3}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Alexander"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[Alexander]");
+}
+
+#[test]
+fn test_align_does_not_change_repeat() {
+    let template = CBTemplate::compile("{'-':3}{'ab':<4}|").unwrap();
+    let ctx = Context::new();
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "---ab  |");
+}
+
+#[test]
+fn test_align_width_past_output_limit_fails() {
+    let ctx = Context::from([("s", Value::static_str("x"))]);
+    let too_wide = |source: &str| {
+        let error = CBTemplate::compile(source)
+            .unwrap()
+            .format(&ctx)
+            .unwrap_err();
+        assert!(
+            matches!(error, DirectiveError::OutputLimitExceeded { .. }),
+            "{source}: {error:?}"
+        );
+    };
+
+    too_wide("{s:*^1000000000000}");
+    too_wide("{s:>18446744073709551615}");
+    too_wide("{s:pad_start(99999999999)}");
+    too_wide("{s:pad_end(18446744073709551615, 'é')}");
+
+    let options = FormatOptions {
+        max_output_len: 4,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile("{s:>8}").unwrap();
+    assert!(matches!(
+        template.format_with_options(&ctx, &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 4 })
+    ));
+
+    let filter = AlignFilter {
+        align: Alignment::Left,
+        width: usize::MAX,
+        fill: ' ',
+    };
+    assert!(matches!(
+        filter.apply(Value::static_str("x")),
+        Err(DirectiveError::OutputLimitExceeded {
+            limit: FormatOptions::DEFAULT_MAX_OUTPUT_LEN
+        })
+    ));
+}
+
+// ============================================
+// Column Tests
+// ============================================
+
+#[test]
+fn test_column_aligns_varying_width_rows() {
+    let template = CBTemplate::compile("{name}{@col(10)}{role}\n").unwrap();
+    let mut table = String::new();
+
+    for (name, role) in [("Ada", "admin"), ("Grace", "dev"), ("Linus", "ops")] {
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::static_str(name));
+        ctx.insert("role", Value::static_str(role));
+        table.push_str(&template.format(&ctx).unwrap());
+    }
+
+    assert_eq!(table, "Ada       admin\nGrace     dev\nLinus     ops\n");
+}
+
+#[test]
+fn test_column_counts_from_the_last_newline() {
+    let template = CBTemplate::compile("{a}{@col(6)}|\n{b}{@col(6)}|").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("x"));
+    ctx.insert("b", Value::static_str("two\nabc"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "x     |\ntwo\nabc   |");
+}
+
+#[test]
+fn test_column_past_the_line_adds_nothing() {
+    let template = CBTemplate::compile("{name}{@col(4)}|").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Alexander"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Alexander|");
+}
+
+#[test]
+fn test_column_expands_tabs_to_multiples_of_eight() {
+    let template = CBTemplate::compile("\t{@col(10)}|\nab\tc{@col(12)}|").unwrap();
+    let ctx = Context::new();
+
+    assert_eq!(template.format(&ctx).unwrap(), "\t  |\nab\tc   |");
+}
+
+#[test]
+fn test_column_counts_chars_not_bytes() {
+    let template = CBTemplate::compile("{word}{@col(8)}|").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("word", Value::static_str("héllo"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "héllo   |");
+}
+
+#[test]
+fn test_column_inside_conditional_branch() {
+    let template = CBTemplate::compile("{id}{ok ? '{@col(5)}yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("id", Value::Int(42));
+    ctx.insert("ok", Value::Bool(true));
+
+    assert_eq!(template.format(&ctx).unwrap(), "42   yes");
+
+    let ladder = CBTemplate::compile("{id}{a ? 'a' : ok ? '{@col(4)}b' : 'c'}").unwrap();
+    ctx.insert("a", Value::Bool(false));
+
+    assert_eq!(ladder.format(&ctx).unwrap(), "42  b");
+}
+
+#[test]
+fn test_column_in_chunks_and_render_to() {
+    let template = CBTemplate::compile("{name}{@col(8)}{role}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("role", Value::static_str("admin"));
+
+    let chunks: Vec<_> = template.chunks(&ctx).collect::<Result<_, _>>().unwrap();
+    assert_eq!(chunks.concat(), "Ada     admin");
+
+    let mut out = String::from("prefix ");
+    let renderer = template.renderer(FormatOptions::default());
+    renderer.render_to(&ctx, &mut out).unwrap();
+    assert_eq!(out, "prefix Ada     admin");
+}
+
+#[test]
+fn test_column_debug_tree_and_at_fill() {
+    let template = CBTemplate::compile("{@col(30)}{name:@>6}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert!(template.debug_tree().contains("Column 30"));
+    assert!(template.format(&ctx).unwrap().ends_with("@@@Ada"));
+}
+
+#[test]
+fn test_column_past_output_limit_fails() {
+    for source in ["{@col(99999999999)}", "{@col(18446744073709551615)}"] {
+        let template = CBTemplate::compile(source).unwrap();
+        assert_eq!(
+            template.estimated_len(),
+            FormatOptions::DEFAULT_MAX_OUTPUT_LEN
+        );
+        assert!(matches!(
+            template.format(&Context::new()),
+            Err(DirectiveError::OutputLimitExceeded { .. })
+        ));
+    }
+
+    let options = FormatOptions {
+        max_output_len: 6,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile("ab{@col(6)}").unwrap();
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &options)
+            .unwrap(),
+        "ab    "
+    );
+    let template = CBTemplate::compile("{@col(7)}").unwrap();
+    assert!(matches!(
+        template.format_with_options(&Context::new(), &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 6 })
+    ));
+}
+
+// ============================================
+// Truncation Tests
+// ============================================
+
+#[test]
+fn test_trunc_long_value() {
+    let template = CBTemplate::compile("{bio:trunc(8)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("bio", Value::static_str("Hello, world"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "Hello, …");
+}
+
+#[test]
+fn test_trunc_short_value_unchanged() {
+    let template = CBTemplate::compile("{bio:trunc(12)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("bio", Value::static_str("Hello, world"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "Hello, world");
+}
+
+#[test]
+fn test_trunc_custom_ellipsis_counts_toward_width() {
+    let template = CBTemplate::compile("{bio:trunc(8, '...')}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("bio", Value::static_str("Hello, world"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "Hello...");
+}
+
+#[test]
+fn test_trunc_empty_ellipsis() {
+    let template = CBTemplate::compile("{bio:trunc(5, '')}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("bio", Value::static_str("Hello, world"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "Hello");
+}
+
+#[test]
+fn test_trunc_multibyte_characters() {
+    let template = CBTemplate::compile("{text:trunc(4)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("text", Value::static_str("🦀🦀🦀🦀🦀"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "🦀🦀🦀…");
+}
+
+// ============================================
+// Slice Tests
+// ============================================
+
+#[test]
+fn test_slice_start_and_end() {
+    let template = CBTemplate::compile("{code:slice(0, 4)}|{code:slice(4,8)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::static_str("ORD-2024-17"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "ORD-|2024");
+}
+
+#[test]
+fn test_slice_negative_indices() {
+    let template =
+        CBTemplate::compile("{code:slice(-2)}|{code:slice(4, -3)}|{code:slice(-100, 3)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::static_str("ORD-2024-17"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "17|2024|ORD");
+}
+
+#[test]
+fn test_slice_out_of_range_clamps() {
+    let template =
+        CBTemplate::compile("[{code:slice(2, 100)}][{code:slice(50)}][{code:slice(3, 1)}]")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::static_str("abcd"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[cd][][]");
+}
+
+#[test]
+fn test_slice_multibyte_characters() {
+    let template = CBTemplate::compile("{text:slice(1, 3)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("text", Value::static_str("é🦀ü!"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "🦀ü");
+}
+
+#[test]
+fn test_slice_non_string_values() {
+    let template = CBTemplate::compile("{n:slice(0, 2)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(12345));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "12");
+}
+
+#[test]
+fn test_slice_invalid_arguments_render_nothing() {
+    let template =
+        CBTemplate::compile("[{code:slice()}][{code:slice(1, 2, 3)}][{code:slice(a)}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::static_str("abcd"));
+
+    let result = template
+        .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+        .unwrap();
+    assert_eq!(result, "[][][]");
+}
+
+// ============================================
+// Length Tests
+// ============================================
+
+#[test]
+fn test_len_counts_chars() {
+    let template = CBTemplate::compile("{name:len} {text:len} {empty:len}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("text", Value::static_str("héllo🦀"));
+    ctx.insert("empty", Value::static_str(""));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "3 6 0");
+}
+
+#[test]
+fn test_len_of_literal() {
+    let template = CBTemplate::compile("{'abcd':len}").unwrap();
+
+    let result = template.format(&Context::new()).unwrap();
+    assert_eq!(result, "4");
+}
+
+#[test]
+fn test_len_rejects_non_strings() {
+    let template = CBTemplate::compile("{n:len}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(1000));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "len",
+            expected: "string",
+            found: "integer",
+        })
+    ));
+}
+
+// ============================================
+// Reverse and Repeat Filter Tests
+// ============================================
+
+#[test]
+fn test_reverse_filter() {
+    let template = CBTemplate::compile("{s:reverse}").unwrap();
+    let mut ctx = Context::new();
+
+    for (input, expected) in [
+        ("Ada", "adA"),
+        ("", ""),
+        ("héllo", "olléh"),
+        ("🦀ab", "ba🦀"),
+        ("Zoe\u{301}!", "!e\u{301}oZ"),
+        ("a\u{308}\u{301}b", "ba\u{308}\u{301}"),
+        ("x👍🏽y", "y👍🏽x"),
+        ("1👨\u{200D}👩\u{200D}👧2", "2👨\u{200D}👩\u{200D}👧1"),
+        ("🇫🇷🇩🇪", "🇩🇪🇫🇷"),
+        ("a\r\nb", "b\r\na"),
+        ("❤\u{FE0F}!", "!❤\u{FE0F}"),
+    ] {
+        ctx.insert("s", Value::owned_str(input.to_string()));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{:?}", input);
+    }
+
+    ctx.insert("s", Value::Int(1230));
+    assert_eq!(template.format(&ctx).unwrap(), "0321");
+
+    ctx.insert("s", Value::from(vec![1u8, 2]));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "reverse",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_reverse_is_not_a_repeat_count() {
+    let template = CBTemplate::compile("{s:reverse}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("ab"));
+    ctx.insert("reverse", Value::Int(3));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ba");
+}
+
+#[test]
+fn test_repeat_filter() {
+    let mut ctx = Context::new();
+    ctx.insert("sep", Value::static_str("-="));
+    ctx.insert("n", Value::Int(7));
+
+    for (source, expected) in [
+        ("{sep:repeat(3)}", "-=-=-="),
+        ("{sep:repeat(0)}", ""),
+        ("{n:repeat(2)}", "77"),
+        ("{sep | repeat(2) | >6}", "  -=-="),
+        ("{sep | repeat(2) | reverse}", "=-=-"),
+        ("[{sep:repeat(-1)}]", "[]"),
+        ("[{sep:repeat(x)}]", "[]"),
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        assert_eq!(
+            template
+                .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+                .unwrap(),
+            expected,
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_repeat_filter_is_capped() {
+    let template = CBTemplate::compile("{sep:repeat(100000000)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sep", Value::static_str("-="));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::OutputLimitExceeded { .. })
+    ));
+
+    let template = CBTemplate::compile("{sep:repeat(10)}").unwrap();
+    let options = FormatOptions {
+        max_output_len: 5,
+        ..FormatOptions::default()
+    };
+    assert!(matches!(
+        template.format_with_options(&ctx, &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 5 })
+    ));
+}
+
+#[test]
+fn test_pad_start_and_pad_end() {
+    let template =
+        CBTemplate::compile("[{s:pad_start(6, 0)}] [{s:pad_end(6)}] [{s:pad_end(6, '.')}]")
+            .unwrap();
+    let mut ctx = Context::new();
+
+    // Under length
+    ctx.insert("s", Value::Int(42));
+    assert_eq!(template.format(&ctx).unwrap(), "[000042] [42    ] [42....]");
+
+    // Exact length
+    ctx.insert("s", Value::static_str("abcdef"));
+    assert_eq!(template.format(&ctx).unwrap(), "[abcdef] [abcdef] [abcdef]");
+
+    // Over length, never truncated
+    ctx.insert("s", Value::static_str("abcdefgh"));
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "[abcdefgh] [abcdefgh] [abcdefgh]"
+    );
+}
+
+#[test]
+fn test_pad_counts_chars_and_accepts_unicode_fill() {
+    let template = CBTemplate::compile("[{s:pad_start(5, '·')}] [{s:pad_end(4, *)}]").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("s", Value::static_str("héé"));
+    assert_eq!(template.format(&ctx).unwrap(), "[··héé] [héé*]");
+
+    ctx.insert("s", Value::static_str("日本語です"));
+    assert_eq!(template.format(&ctx).unwrap(), "[日本語です] [日本語です]");
+}
+
+#[test]
+fn test_pad_chains_and_matches_alignment() {
+    let template = CBTemplate::compile("{s | trunc(4) | pad_end(6, -)}|").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("abcdefgh"));
+    assert_eq!(template.format(&ctx).unwrap(), "abc…--|");
+
+    assert_eq!(
+        CBTemplate::compile("{s:pad_start(6, 0)}").unwrap(),
+        CBTemplate::compile("{s:0>6}").unwrap()
+    );
+}
+
+#[test]
+fn test_pad_invalid_arguments_render_nothing() {
+    let template = CBTemplate::compile(
+        "[{s:pad_start()}][{s:pad_end(6, ab)}][{s:pad_end(6, 0, 1)}][{s:pad_end(x)}]",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("abcd"));
+
+    let result = template
+        .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+        .unwrap();
+    assert_eq!(result, "[][][][]");
+}
+
+// ============================================
+// Squish Tests
+// ============================================
+
+#[test]
+fn test_squish_filter() {
+    let template = CBTemplate::compile("[{s:squish}]").unwrap();
+    let mut ctx = Context::new();
+
+    for (input, expected) in [
+        ("  padded  ", "[padded]"),
+        ("too   many    spaces", "[too many spaces]"),
+        ("tab\tseparated\t\tvalues", "[tab separated values]"),
+        ("line\nbreaks\r\n\n here\n", "[line breaks here]"),
+        (
+            "no-break\u{A0}\u{A0}and\u{3000}ideographic",
+            "[no-break and ideographic]",
+        ),
+        ("\u{2003}em\u{2028}spaces\u{85}", "[em spaces]"),
+        (" \t\n ", "[]"),
+        ("", "[]"),
+    ] {
+        ctx.insert("s", Value::owned_str(input.to_string()));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{:?}", input);
+    }
+
+    ctx.insert("s", Value::from(vec![1u8, 2]));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "squish",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_squish_leaves_single_spaced_strings_unchanged() {
+    let template = CBTemplate::compile("{s:squish}").unwrap();
+    let mut ctx = Context::new();
+
+    for input in ["one", "already single spaced", "héllo wörld 🦀"] {
+        ctx.insert("s", Value::owned_str(input.to_string()));
+        assert_eq!(template.format(&ctx).unwrap(), input);
+    }
+
+    ctx.insert("s", Value::Int(42));
+    assert_eq!(template.format(&ctx).unwrap(), "42");
+}
+
+#[test]
+fn test_squish_chains_with_other_filters() {
+    let template = CBTemplate::compile("[{s | squish | trunc(9)}] {s:squish | len}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("  a  long\n\ncomment "));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[a long c…] 14");
+}
+
+// ============================================
+// Replace Filter Tests
+// ============================================
+
+#[test]
+fn test_replace_filter() {
+    let template = CBTemplate::compile("{text:replace(foo, bar)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (input, expected) in [
+        ("foo", "bar"),
+        ("foo foo, food", "bar bar, bard"),
+        ("no match", "no match"),
+        ("", ""),
+    ] {
+        ctx.insert("text", Value::owned_str(input.to_string()));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{:?}", input);
+    }
+}
+
+#[test]
+fn test_replace_filter_windows_paths() {
+    let template = CBTemplate::compile(r"{path:replace(\\,/)} {path:replace(\\, '\\\\')}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("path", Value::static_str(r"C:\Users\ada"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        r"C:/Users/ada C:\\Users\\ada"
+    );
+}
+
+#[test]
+fn test_replace_filter_overlapping_patterns() {
+    let template = CBTemplate::compile("{s:replace(aa, b)}|{s:replace(aba, x)}").unwrap();
+    let mut ctx = Context::new();
+
+    // Matches are found from the left and never overlap
+    ctx.insert("s", Value::static_str("aaaaa"));
+    assert_eq!(template.format(&ctx).unwrap(), "bba|aaaaa");
+
+    ctx.insert("s", Value::static_str("ababa"));
+    assert_eq!(template.format(&ctx).unwrap(), "ababa|xba");
+
+    // The replacement is not searched again
+    let template = CBTemplate::compile("{s:replace(a, aa)}").unwrap();
+    ctx.insert("s", Value::static_str("aba"));
+    assert_eq!(template.format(&ctx).unwrap(), "aabaa");
+}
+
+#[test]
+fn test_replace_filter_escaped_arguments() {
+    let template = CBTemplate::compile(
+        r"{csv:replace(\,, ' | ')} {csv:replace(\(x\), y)} {csv:replace({\}, [])}",
+    );
+    // An unescaped opening delimiter nests a directive, so it must be escaped
+    assert!(template.is_err());
+
+    let template = CBTemplate::compile(
+        r"{csv:replace(\,, ' | ')} {csv:replace(\(x\), y)} {csv:replace(\{\}, [])}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("csv", Value::static_str("a,(x),{}"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "a | (x) | {} a,y,{} a,(x),[]"
+    );
+}
+
+#[test]
+fn test_replace_filter_removes_with_empty_replacement() {
+    let template = CBTemplate::compile("{phone:replace(-,)}{phone:replace(' ', '')}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("phone", Value::static_str("555-01 23"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "55501 23555-0123");
+
+    // Replacing the empty string is rejected
+    assert!(matches!(
+        CBTemplate::compile("{phone:replace(, x)}")
+            .unwrap()
+            .format(&ctx),
+        Err(DirectiveError::UnknownDirective { .. })
+    ));
+}
+
+#[test]
+fn test_replace_filter_chains_with_other_filters() {
+    let template =
+        CBTemplate::compile("[{slug | replace(-, ' ') | trunc(9)}] {slug:replace(-, _) | len}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("slug", Value::static_str("hello-big-world"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[hello bi…] 15");
+}
+
+#[test]
+fn test_replace_filter_non_strings() {
+    let template = CBTemplate::compile("{n:replace(0, o)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(1000));
+    assert_eq!(template.format(&ctx).unwrap(), "1ooo");
+
+    ctx.insert("n", Value::from(b"100".as_slice()));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "replace",
+            ..
+        })
+    ));
+
+    let options = FormatOptions {
+        filter_mismatch: FilterMismatch::Coerce,
+        ..Default::default()
+    };
+    assert_eq!(template.format_with_options(&ctx, &options).unwrap(), "1oo");
+}
+
+#[test]
+fn test_replace_filter_tokens_and_debug_tree() {
+    let tokens: Vec<Token> = TemplateLexer::new(r"s:replace(a\,b , 'c ')").collect();
+    assert_eq!(
+        tokens[2..],
+        [
+            Token::Ident("replace"),
+            Token::LParen,
+            Token::Literal("a,b".into()),
+            Token::Comma,
+            Token::Literal("c ".into()),
+            Token::RParen,
+        ]
+    );
+
+    // Outside a filter, `replace` is an ordinary name
+    let tokens: Vec<Token> = TemplateLexer::new("replace(a, b)").collect();
+    assert_eq!(tokens[2], Token::Ident("a"));
+
+    let template = CBTemplate::compile("{s:replace(a, b)}").unwrap();
+    assert!(template.debug_tree().contains(r#"replace("a", "b")"#));
+}
+
+// ============================================
+// Strip Filter Tests
+// ============================================
+
+#[test]
+fn test_strip_filters() {
+    let template = CBTemplate::compile("{path:rstrip(/)} {code:lstrip(0)} {s:strip(*)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("path", Value::static_str("/srv/www///"));
+    ctx.insert("code", Value::static_str("000420"));
+    ctx.insert("s", Value::static_str("**bold**"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "/srv/www 420 bold");
+}
+
+#[test]
+fn test_strip_filter_char_set() {
+    // Every char of the set is stripped, in any order, not the set as a string
+    let template =
+        CBTemplate::compile("[{s:rstrip(/.)}] [{s:lstrip(./)}] [{s:strip(./-)}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("./-dir-/./"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[./-dir-] [-dir-/./] [dir]");
+}
+
+#[test]
+fn test_strip_filter_empty_result() {
+    let template = CBTemplate::compile("[{s:strip(0)}] [{s:lstrip(0)}] [{t:rstrip(-=)}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("0000"));
+    ctx.insert("t", Value::static_str("=-=-"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[] [] []");
+}
+
+#[test]
+fn test_strip_filter_unicode_set() {
+    let template = CBTemplate::compile("{s:strip('«» ')} {t:rstrip(。!)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("« « café » »"));
+    ctx.insert("t", Value::static_str("こんにちは。。!"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "café こんにちは");
+}
+
+#[test]
+fn test_strip_filter_quoted_and_escaped_sets() {
+    let template = CBTemplate::compile(r"[{s:strip(' *')}] [{t:rstrip(\,\))}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str(" * note * "));
+    ctx.insert("t", Value::static_str("(a, b),)"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[note] [(a, b]");
+
+    // An empty set is rejected
+    assert!(matches!(
+        CBTemplate::compile("{s:strip()}").unwrap().format(&ctx),
+        Err(DirectiveError::UnknownDirective { .. })
+    ));
+}
+
+#[test]
+fn test_strip_filter_non_strings_and_chains() {
+    let template = CBTemplate::compile("{n:rstrip(0)} {n | rstrip(0) | >6}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(1200));
+    assert_eq!(template.format(&ctx).unwrap(), "12     12");
+
+    ctx.insert("n", Value::from(b"100".as_slice()));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "rstrip",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_strip_filter_round_trip() {
+    let template = CBTemplate::compile(r"{s:lstrip(\,)}").unwrap();
+    let source = template.to_source().unwrap();
+    assert_eq!(source, r#"{s:lstrip(",")}"#);
+
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str(",,a,"));
+    let reparsed = CBTemplate::compile(&source).unwrap();
+    assert_eq!(reparsed.format(&ctx).unwrap(), "a,");
+    assert_eq!(reparsed, template);
+}
+
+// ============================================
+// Indent Filter Tests
+// ============================================
+
+#[test]
+fn test_indent_filter_explicit_width() {
+    let template = CBTemplate::compile("spec:\n  run: {cmd:indent(4)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("cmd", Value::static_str("make\nmake test"));
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "spec:\n  run: make\n    make test"
+    );
+
+    ctx.insert("cmd", Value::static_str("cd src\nmake\nmake test"));
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "spec:\n  run: cd src\n    make\n    make test"
+    );
+}
+
+#[test]
+fn test_indent_filter_auto_width() {
+    let template = CBTemplate::compile("items:\n  - {item:indent}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("item", Value::static_str("name: a\nsize: 1"));
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "items:\n  - name: a\n    size: 1"
+    );
+
+    ctx.insert("item", Value::static_str("name: a\nsize: 1\ntags: []"));
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "items:\n  - name: a\n    size: 1\n    tags: []"
+    );
+}
+
+#[test]
+fn test_indent_filter_auto_width_in_branches_and_chains() {
+    let template =
+        CBTemplate::compile("{x ? 'x: {v:indent}'}\n\t{v | strip(' ') | indent}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Bool(true));
+    ctx.insert("v", Value::static_str(" a\nb "));
+
+    // A tab advances to the next tab stop, like `{@col(n)}` counts it
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "x:  a\n   b \n\ta\n        b"
+    );
+}
+
+#[test]
+fn test_indent_filter_first_line_and_blank_lines() {
+    let template = CBTemplate::compile("{v:indent(2, first)}|{v:indent(2)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("v", Value::static_str("a\n\nb\r\n\r\nc"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "  a\n\n  b\r\n\r\n  c|a\n\n  b\r\n\r\n  c"
+    );
+
+    // A single line is only indented with `first`
+    ctx.insert("v", Value::Int(42));
+    assert_eq!(template.format(&ctx).unwrap(), "  42|42");
+}
+
+#[test]
+fn test_indent_filter_outside_templates() {
+    let auto = IndentFilter {
+        width: None,
+        first: false,
+    };
+    let value = auto.apply(Value::static_str("a\nb")).unwrap();
+    assert_eq!(value.to_string(), "a\nb");
+
+    let fixed = IndentFilter {
+        width: Some(1),
+        first: true,
+    };
+    let value = fixed.apply(Value::static_str("a\nb")).unwrap();
+    assert_eq!(value.to_string(), " a\n b");
+}
+
+#[test]
+fn test_indent_filter_past_output_limit_fails() {
+    let ctx = Context::from([("ml", Value::static_str("a\nb\nc"))]);
+
+    let template = CBTemplate::compile("{ml:indent(99999999999999)}").unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::OutputLimitExceeded { .. })
+    ));
+    let template = CBTemplate::compile("{ml:indent(18446744073709551615, first)}").unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::OutputLimitExceeded { .. })
+    ));
+
+    // Two lines are indented, so indenting by 2 adds 4 bytes to the 5 of the value
+    let options = FormatOptions {
+        max_output_len: 9,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile("{ml:indent(2)}").unwrap();
+    assert_eq!(
+        template.format_with_options(&ctx, &options).unwrap(),
+        "a\n  b\n  c"
+    );
+    let template = CBTemplate::compile("{ml:indent(3)}").unwrap();
+    assert!(matches!(
+        template.format_with_options(&ctx, &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 9 })
+    ));
+}
+
+#[test]
+fn test_indent_filter_round_trip() {
+    let template = CBTemplate::compile("{a:indent} {b:indent(3)} {c:indent(3, first)}").unwrap();
+    let source = template.to_source().unwrap();
+    assert_eq!(source, "{a:indent} {b:indent(3)} {c:indent(3, first)}");
+    assert_eq!(CBTemplate::compile(&source).unwrap(), template);
+    assert_eq!(template.filters_used(), vec!["indent"]);
+
+    // Unknown options are rejected
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("a"));
+    assert!(matches!(
+        CBTemplate::compile("{a:indent(3, last)}")
+            .unwrap()
+            .format(&ctx),
+        Err(DirectiveError::UnknownDirective { .. })
+    ));
+}
+
+// ============================================
+// Encoding Tests
+// ============================================
+
+/// Reverses percent-encoding, treating `+` as a space when `form` is set.
+fn percent_decode(s: &str, form: bool) -> String {
+    let mut bytes = Vec::new();
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().unwrap(), iter.next().unwrap()];
+                let hex = std::str::from_utf8(&hex).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+            }
+            b'+' if form => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn test_urlenc_reserved_characters() {
+    let template = CBTemplate::compile("{q:urlenc}|{q:urlenc(form)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("q", Value::static_str("a b&c=d/e?f#g+h*i~j"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(
+        result,
+        "a%20b%26c%3Dd%2Fe%3Ff%23g%2Bh%2Ai~j|a+b%26c%3Dd%2Fe%3Ff%23g%2Bh*i%7Ej"
+    );
+}
+
+#[test]
+fn test_urlenc_unreserved_unchanged() {
+    let template = CBTemplate::compile("{q:urlenc}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("q", Value::static_str("AZaz09-._~"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "AZaz09-._~");
+}
+
+#[test]
+fn test_urlenc_round_trip() {
+    let template = CBTemplate::compile("{q:urlenc}").unwrap();
+    let form = CBTemplate::compile("{q:urlenc(form)}").unwrap();
+
+    for input in ["", "plain", "héllo wörld 🦀", "100% & more", "a+b c\n\t"] {
+        let mut ctx = Context::new();
+        ctx.insert("q", Value::owned_str(input.to_string()));
+
+        assert_eq!(
+            percent_decode(&template.format(&ctx).unwrap(), false),
+            input
+        );
+        assert_eq!(percent_decode(&form.format(&ctx).unwrap(), true), input);
+    }
+}
+
+#[test]
+fn test_urlenc_multibyte_is_utf8() {
+    let template = CBTemplate::compile("{q:urlenc}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("q", Value::static_str("é"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "%C3%A9");
+}
+
+#[test]
+fn test_markdown_escape() {
+    let template = CBTemplate::compile("**{title:md}**").unwrap();
+    let mut ctx = Context::new();
+
+    for (input, expected) in [
+        ("plain text, 100%", "plain text, 100%"),
+        ("*new* _big_ `code`", r"\*new\* \_big\_ \`code\`"),
+        (
+            "[click](javascript:alert(1))",
+            r"\[click\]\(javascript:alert\(1\)\)",
+        ),
+        ("![img](x.png)", r"\!\[img\]\(x\.png\)"),
+        ("<script>&amp;", r"\<script\>\&amp;"),
+        ("a\\*b", r"a\\\*b"),
+        (
+            "# Title\n- item\n1. one\n===",
+            "\\# Title\n\\- item\n1\\. one\n\\=\\=\\=",
+        ),
+        ("| a | b |", r"\| a \| b \|"),
+        ("~~gone~~ {x} +1", r"\~\~gone\~\~ \{x\} \+1"),
+    ] {
+        ctx.insert("title", Value::static_str(input));
+        assert_eq!(
+            template.format(&ctx).unwrap(),
+            format!("**{}**", expected),
+            "{input:?}"
+        );
+    }
+}
+
+#[test]
+fn test_markdown_escape_numbers_and_chains() {
+    let template = CBTemplate::compile("{n:md} {s | trunc(4, '...') | md}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Float(-1.5));
+    ctx.insert("s", Value::static_str("abcdef"));
+
+    assert_eq!(template.format(&ctx).unwrap(), r"\-1\.5 a\.\.\.");
+}
+
+#[test]
+fn test_shell_escape() {
+    let template = CBTemplate::compile("rm -- {file:sh}").unwrap();
+    let mut ctx = Context::new();
+
+    for (input, expected) in [
+        ("notes.txt", "'notes.txt'"),
+        ("", "''"),
+        ("it's", r"'it'\''s'"),
+        ("'", r"''\'''"),
+        ("''", r"''\'''\'''"),
+        ("$HOME; rm -rf / #", "'$HOME; rm -rf / #'"),
+        ("`id` $(id) \\ \"q\"", "'`id` $(id) \\ \"q\"'"),
+        ("a\nb", "'a\nb'"),
+        ("-rf", "'-rf'"),
+    ] {
+        ctx.insert("file", Value::static_str(input));
+        assert_eq!(
+            template.format(&ctx).unwrap(),
+            format!("rm -- {}", expected),
+            "{input:?}"
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_shell_escape_round_trips_through_sh() {
+    let template = CBTemplate::compile("printf '%s' {arg:sh}").unwrap();
+
+    for input in [
+        "plain",
+        "",
+        "it's a 'test'",
+        "$HOME $(echo pwned) `echo pwned`",
+        "; echo pwned; '",
+        "\\ \" * ? [a] ~ {a,b} \n\t!",
+        "é 🦀",
+    ] {
+        let mut ctx = Context::new();
+        ctx.insert("arg", Value::static_str(input));
+        let script = template.format(&ctx).unwrap();
+
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), input, "{script}");
+    }
+}
+
+#[test]
+fn test_escape_filter_names_are_not_repeat_counts() {
+    let template = CBTemplate::compile("{s:md}{s:sh}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("x"));
+    ctx.insert("md", Value::Int(3));
+    ctx.insert("sh", Value::Int(3));
+
+    assert_eq!(template.format(&ctx).unwrap(), "x'x'");
+    assert!(template.debug_tree().contains("md"));
+}
+
+#[cfg(feature = "base64")]
+#[test]
+fn test_base64_rfc4648_vectors() {
+    let template = CBTemplate::compile("{s:base64}").unwrap();
+
+    for (input, expected) in [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ] {
+        let mut ctx = Context::new();
+        ctx.insert("s", Value::static_str(input));
+
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[cfg(feature = "base64")]
+#[test]
+fn test_base64_url_safe_alphabet() {
+    let template = CBTemplate::compile("{s:base64}|{s:base64(url)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("hi?>"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "aGk/Pg==|aGk_Pg");
+}
+
+// ============================================
+// Digest Tests
+// ============================================
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_sha256_known_vectors() {
+    let template = CBTemplate::compile("{s:sha256}").unwrap();
+
+    for (input, expected) in [
+        (
+            "",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ),
+        (
+            "abc",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        ),
+    ] {
+        let mut ctx = Context::new();
+        ctx.insert("s", Value::static_str(input));
+
+        assert_eq!(template.format(&ctx).unwrap(), expected, "input: {input:?}");
+    }
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_crc32_known_vectors() {
+    let template = CBTemplate::compile("{s:crc32}").unwrap();
+
+    for (input, expected) in [
+        ("", "00000000"),
+        ("abc", "352441c2"),
+        ("123456789", "cbf43926"),
+    ] {
+        let mut ctx = Context::new();
+        ctx.insert("s", Value::static_str(input));
+
+        assert_eq!(template.format(&ctx).unwrap(), expected, "input: {input:?}");
+    }
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_digest_truncation() {
+    let template =
+        CBTemplate::compile("style.css?v={s:sha256:8} {s:sha256(12)} {s:crc32(100)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("abc"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "style.css?v=ba7816bf ba7816bf8f01 352441c2"
+    );
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_digest_of_bytes_and_numbers() {
+    let template = CBTemplate::compile("{b:crc32} {n:crc32}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("b", Value::from(b"123456789".to_vec()));
+    ctx.insert("n", Value::Int(123456789));
+
+    assert_eq!(template.format(&ctx).unwrap(), "cbf43926 cbf43926");
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_digest_chains_and_describes() {
+    let template = CBTemplate::compile("{s | sha256(4) | >6}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("abc"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "  ba78");
+    assert!(template.debug_tree().contains("sha256(4)"));
+}
+
+#[test]
+fn test_digest_names_are_not_repeat_counts() {
+    let template = CBTemplate::compile("{s:sha256}{s:crc32}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("x"));
+    ctx.insert("sha256", Value::Int(3));
+    ctx.insert("crc32", Value::Int(3));
+
+    // Without the `digest` feature the filters are unknown directives
+    assert!(!matches!(template.format(&ctx), Ok(output) if output == "xxxxxx"));
+}
+
+// ============================================
+// Number Base Tests
+// ============================================
+
+#[test]
+fn test_radix_hex_oct_bin() {
+    let template = CBTemplate::compile("{n:hex} {n:HEX} {perm:oct} {bits:bin}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(255));
+    ctx.insert("perm", Value::Int(0o755));
+    ctx.insert("bits", Value::Int(5));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ff FF 755 101");
+}
+
+#[test]
+fn test_radix_with_prefix() {
+    let template = CBTemplate::compile("{n:#hex} {n:#HEX} {n:#oct} {n:#bin}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(10));
+
+    assert_eq!(template.format(&ctx).unwrap(), "0xa 0xA 0o12 0b1010");
+}
+
+#[test]
+fn test_radix_negative_is_sign_prefixed() {
+    let template = CBTemplate::compile("{n:hex} {n:#bin} {min:#hex}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(-255));
+    ctx.insert("min", Value::Int(i64::MIN));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "-ff -0b11111111 -0x8000000000000000"
+    );
+}
+
+#[test]
+fn test_radix_rejects_non_integers() {
+    let template = CBTemplate::compile("{v:hex}").unwrap();
+
+    for (value, type_name) in [
+        (Value::Float(1.5), "float"),
+        (Value::static_str("255"), "string"),
+        (Value::Bool(true), "boolean"),
+    ] {
+        let mut ctx = Context::new();
+        ctx.insert("v", value);
+
+        match template.format(&ctx) {
+            Err(DirectiveError::FilterTypeError {
+                filter,
+                expected,
+                found,
+            }) => {
+                assert_eq!(filter, "hex");
+                assert_eq!(expected, "integer");
+                assert_eq!(found, type_name);
+            }
+            other => panic!("expected a filter type error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_radix_names_take_precedence_over_repeat() {
+    let template = CBTemplate::compile("{'ab':hex}|{'-':count}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("hex", Value::Int(3));
+    ctx.insert("count", Value::Int(3));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError { .. })
+    ));
+
+    let template = CBTemplate::compile("{'-':count}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "---");
+}
+
+// ============================================
+// Sign and Zero-Padding Tests
+// ============================================
+
+#[test]
+fn test_zero_pad_integers() {
+    let template = CBTemplate::compile("{id:05}|{neg:05}|{big:03}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("id", Value::Int(42));
+    ctx.insert("neg", Value::Int(-42));
+    ctx.insert("big", Value::Int(123456));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "00042|-0042|123456");
+}
+
+#[test]
+fn test_plus_sign() {
+    let template = CBTemplate::compile("{a:+} {b:+} {c:+} {f:+}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(3));
+    ctx.insert("b", Value::Int(0));
+    ctx.insert("c", Value::Int(-3));
+    ctx.insert("f", Value::Float(1.5));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "+3 +0 -3 +1.5");
+}
+
+#[test]
+fn test_plus_sign_with_zero_pad() {
+    let template = CBTemplate::compile("{a:+05} {b:+05} {f:+07}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(3));
+    ctx.insert("b", Value::Int(-3));
+    ctx.insert("f", Value::Float(-2.5));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "+0003 -0003 -0002.5");
+}
+
+#[test]
+fn test_zero_pad_does_not_replace_repeat_count() {
+    let template = CBTemplate::compile("{'ab':3}{'-':0}").unwrap();
+
+    let result = template.format(&Context::new()).unwrap();
+    assert_eq!(result, "ababab");
+}
+
+#[test]
+fn test_zero_pad_non_finite_floats() {
+    let template = CBTemplate::compile("{f:+05}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("f", Value::Float(f64::INFINITY));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "+inf");
+}
+
+#[test]
+fn test_zero_pad_past_output_limit_fails() {
+    let ctx = Context::from([("n", Value::Int(-42))]);
+
+    let template = CBTemplate::compile("{n:099999999999}").unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::OutputLimitExceeded { .. })
+    ));
+
+    let options = FormatOptions {
+        max_output_len: 4,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile("{n:05}").unwrap();
+    assert!(matches!(
+        template.format_with_options(&ctx, &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 4 })
+    ));
+    let template = CBTemplate::compile("{n:04}").unwrap();
+    assert_eq!(
+        template.format_with_options(&ctx, &options).unwrap(),
+        "-042"
+    );
+}
+
+#[test]
+fn test_sign_rejects_non_numbers() {
+    let template = CBTemplate::compile("{name:05}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("42"));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            expected: "number",
+            found: "string",
+            ..
+        })
+    ));
+}
+
+// ============================================
+// Rounding Tests
+// ============================================
+
+#[test]
+fn test_round_floor_ceil_to_integers() {
+    let template = CBTemplate::compile("{x:round} {x:floor} {x:ceil}").unwrap();
+    let mut ctx = Context::new();
+
+    for (x, expected) in [
+        (2.5, "3 2 3"),
+        (-2.5, "-3 -3 -2"),
+        (2.4, "2 2 3"),
+        (-0.4, "0 -1 0"),
+        (7.0, "7 7 7"),
+    ] {
+        ctx.insert("x", Value::Float(x));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", x);
+    }
+}
+
+#[test]
+fn test_round_to_decimal_places() {
+    let template = CBTemplate::compile("{x:round(2)} {x:floor(1)} {x:ceil(1)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (x, expected) in [
+        (3.14159, "3.14 3.1 3.2"),
+        (1.21, "1.21 1.2 1.3"),
+        (2.5, "2.5 2.5 2.5"),
+        (-0.001, "0.0 -0.1 0.0"),
+    ] {
+        ctx.insert("x", Value::Float(x));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", x);
+    }
+}
+
+#[test]
+fn test_round_passes_through_integers_and_non_finite() {
+    let template = CBTemplate::compile("{x:round(2)} {x:floor}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("x", Value::Int(-7));
+    assert_eq!(template.format(&ctx).unwrap(), "-7 -7");
+
+    ctx.insert("x", Value::Float(f64::INFINITY));
+    assert_eq!(template.format(&ctx).unwrap(), "inf inf");
+
+    ctx.insert("x", Value::Float(1e300));
+    let template = CBTemplate::compile("{x:round(20)}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "1e+300");
+}
+
+#[test]
+fn test_round_composes_with_filters_and_number_format() {
+    let template = CBTemplate::compile("{x | round | 05} {x | round(1) | >6}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Float(41.96));
+
+    assert_eq!(template.format(&ctx).unwrap(), "00042   42.0");
+
+    let template = CBTemplate::compile("{x:round(1)}").unwrap();
+    ctx.insert("x", Value::Float(1234.56));
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(NumberFormat::EUROPEAN))
+            .unwrap(),
+        "1234,6"
+    );
+}
+
+#[test]
+fn test_round_rejects_strings() {
+    let template = CBTemplate::compile("{x:ceil}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::static_str("2.5"));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "ceil",
+            expected: "number",
+            found: "string",
+        })
+    ));
+}
+
+#[test]
+fn test_round_invalid_places() {
+    let template = CBTemplate::compile("{x:round(-1)} {x:floor(a)}").unwrap();
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &with_unknown(UnknownPolicy::Keep))
+            .unwrap(),
+        "{x:round(-1)} {x:floor(a)}"
+    );
+}
+
+// ============================================
+// Currency Tests
+// ============================================
+
+#[test]
+fn test_currency_symbols_and_grouping() {
+    let template = CBTemplate::compile(
+        "{a:currency(USD)}|{a:currency(EUR)}|{a:currency(GBP)}|{a:currency(JPY)}|{a:currency(CHF)}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Float(1234.56));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "$1,234.56|1,234.56 €|£1,234.56|¥1,235|CHF 1,234.56");
+}
+
+#[test]
+fn test_currency_integers_and_rounding() {
+    let template =
+        CBTemplate::compile("{a:currency(USD)} {b:currency(USD)} {c:currency(usd)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(1234567));
+    ctx.insert("b", Value::Float(0.005));
+    ctx.insert("c", Value::Int(999));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "$1,234,567.00 $0.01 $999.00");
+}
+
+#[test]
+fn test_currency_symbol_position() {
+    let template =
+        CBTemplate::compile("{a:currency(EUR, prefix)} {a:currency(USD, suffix)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(5));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "€5.00 5.00 $");
+}
+
+#[test]
+fn test_currency_negative_styles() {
+    let template = CBTemplate::compile(
+        "{a:currency(USD)} {a:currency(USD, parens)} {a:currency(EUR, parens)} {z:currency(USD)}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Float(-1234.5));
+    ctx.insert("z", Value::Float(-0.001));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "-$1,234.50 ($1,234.50) (1,234.50 €) $0.00");
+}
+
+#[test]
+fn test_currency_invalid_specs_render_empty() {
+    for source in [
+        "{a:currency(XYZ)}",
+        "{a:currency()}",
+        "{a:currency(USD, parens, parens)}",
+        "{a:currency(USD, prefix, suffix)}",
+        "{a:currency(USD, left)}",
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("a", Value::Int(1));
+        assert!(
+            matches!(
+                template.format(&ctx),
+                Err(DirectiveError::UnknownDirective { .. })
+            ),
+            "{}",
+            source
+        );
+        assert_eq!(
+            template
+                .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+                .unwrap(),
+            "",
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_currency_rejects_non_numbers() {
+    let template = CBTemplate::compile("{a:currency(USD)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("12"));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "currency",
+            found: "string",
+            ..
+        })
+    ));
+
+    ctx.insert("a", Value::Float(f64::NAN));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            expected: "finite number",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_currency_requires_finite_numbers() {
+    let mut ctx = Context::new();
+
+    for source in ["{x:currency(EUR)}", "{x:currency(JPY, parens)}"] {
+        let template = CBTemplate::compile(source).unwrap();
+
+        for x in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            ctx.insert("x", Value::Float(x));
+            let error = template.format(&ctx).unwrap_err();
+            assert!(
+                error.to_string().contains("'finite number'"),
+                "{source} with {x}: {error}"
+            );
+        }
+    }
+}
+
+// ============================================
+// Percentage Tests
+// ============================================
+
+#[test]
+fn test_percent_scales_ratios() {
+    let template =
+        CBTemplate::compile("{r:percent}|{r:percent(1)}|{r:percent(0)}|{r:percent(4)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (ratio, expected) in [
+        (Value::Float(0.1234), "12.34%|12.3%|12%|12.3400%"),
+        (Value::Float(1.5), "150.00%|150.0%|150%|150.0000%"),
+        (Value::Float(-0.25), "-25.00%|-25.0%|-25%|-25.0000%"),
+        (Value::Int(2), "200.00%|200.0%|200%|200.0000%"),
+        (Value::Float(0.0000001), "0.00%|0.0%|0%|0.0000%"),
+    ] {
+        ctx.insert("r", ratio.clone());
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{:?}", ratio);
+    }
+}
+
+#[test]
+fn test_percent_rounds_halfway_away_from_zero() {
+    let template = CBTemplate::compile("{r:percent(0)} {r:percent(1)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (ratio, expected) in [
+        (0.005, "1% 0.5%"),
+        (0.0049, "0% 0.5%"),
+        (0.00049, "0% 0.0%"),
+        (0.00005, "0% 0.0%"),
+        (-0.005, "-1% -0.5%"),
+        (-0.0004, "0% 0.0%"),
+        (0.99995, "100% 100.0%"),
+        (0.9995, "100% 100.0%"),
+        (0.125, "13% 12.5%"),
+    ] {
+        ctx.insert("r", Value::Float(ratio));
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", ratio);
+    }
+}
+
+#[test]
+fn test_percent_scaled_values() {
+    let template = CBTemplate::compile("{s:percent(scaled)} {s:percent(0, scaled)}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("s", Value::Int(42));
+    assert_eq!(template.format(&ctx).unwrap(), "42.00% 42%");
+
+    ctx.insert("s", Value::Float(99.5));
+    assert_eq!(template.format(&ctx).unwrap(), "99.50% 100%");
+}
+
+#[test]
+fn test_percent_uses_decimal_separator() {
+    let template = CBTemplate::compile("{r:percent(1)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("r", Value::Float(0.1234));
+
+    let options = with_numbers(NumberFormat::EUROPEAN);
+    assert_eq!(
+        template.format_with_options(&ctx, &options).unwrap(),
+        "12,3%"
+    );
+}
+
+#[test]
+fn test_percent_rejects_non_numbers() {
+    let template = CBTemplate::compile("{r:percent}").unwrap();
+    let mut ctx = Context::new();
+
+    for (value, found) in [
+        (Value::static_str("0.5"), "string"),
+        (Value::Bool(true), "boolean"),
+        (Value::Float(f64::INFINITY), "non-finite float"),
+    ] {
+        ctx.insert("r", value);
+        assert!(matches!(
+            template.format(&ctx),
+            Err(DirectiveError::FilterTypeError { filter: "percent", found: f, .. }) if f == found
+        ));
+    }
+
+    // Non-finite floats need a finite number, not another type
+    for r in [f64::NAN, f64::NEG_INFINITY] {
+        ctx.insert("r", Value::Float(r));
+        let error = template.format(&ctx).unwrap_err();
+        assert!(
+            error.to_string().contains("'finite number'"),
+            "{r}: {error}"
+        );
+    }
+
+    for source in [
+        "{r:percent(x)}",
+        "{r:percent(1, 2)}",
+        "{r:percent(scaled, 1)}",
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        ctx.insert("r", Value::Float(0.5));
+        assert!(
+            matches!(
+                template.format(&ctx),
+                Err(DirectiveError::UnknownDirective { .. })
+            ),
+            "{}",
+            source
+        );
+    }
+}
+
+// ============================================
+// Boolean Rendering Tests
+// ============================================
+
+#[test]
+fn test_bool_filter_both_branches() {
+    let template = CBTemplate::compile("{active:bool(Yes, No)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("active", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "Yes");
+
+    ctx.insert("active", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "No");
+}
+
+#[test]
+fn test_bool_filter_literals_with_spaces() {
+    let template = CBTemplate::compile("[{on:bool('✓ enabled', \"✗ disabled\")}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("on", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "[✓ enabled]");
+
+    ctx.insert("on", Value::Bool(false));
+    assert_eq!(template.format(&ctx).unwrap(), "[✗ disabled]");
+}
+
+#[test]
+fn test_bool_filter_coerces_truthiness() {
+    let template =
+        CBTemplate::compile("{n:bool(1, 0)} {s:bool(on, off)} {z:bool(on, off)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(7));
+    ctx.insert("s", Value::static_str(""));
+    ctx.insert("z", Value::Null);
+
+    assert_eq!(template.format(&ctx).unwrap(), "1 off off");
+}
+
+#[test]
+fn test_bool_filter_non_empty_strings_are_true() {
+    let template = CBTemplate::compile("{s:bool(Yes, No)} {f:bool(Yes, No)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("maybe"));
+    ctx.insert("f", Value::static_str("false"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Yes Yes");
+}
+
+// ============================================
+// Truthiness Tests
+// ============================================
+
+#[test]
+fn test_truthiness_matrix() {
+    let cases = [
+        (Value::Bool(true), true),
+        (Value::Bool(false), false),
+        (Value::Int(0), false),
+        (Value::Int(-1), true),
+        (Value::Float(0.0), false),
+        (Value::Float(-0.0), false),
+        (Value::Float(0.5), true),
+        (Value::Float(f64::NAN), true),
+        (Value::static_str(""), false),
+        (Value::static_str(" "), true),
+        (Value::static_str("0"), true),
+        (Value::static_str("false"), true),
+        (Value::Null, false),
+    ];
+
+    let template = CBTemplate::compile("{v ? 'T' : 'F'}{!v ? 'F' : 'T'}{v:bool(T, F)}").unwrap();
+
+    for (value, truthy) in cases {
+        assert_eq!(value.is_truthy(), truthy, "{:?}", value);
+
+        let mut ctx = Context::new();
+        ctx.insert("v", value.clone());
+
+        let expected = if truthy { "TTT" } else { "FFF" };
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{:?}", value);
+    }
+}
+
+#[test]
+fn test_truthiness_of_literal_conditions() {
+    let template =
+        CBTemplate::compile("{0 ? 'T' : 'F'}{1 ? 'T' : 'F'}{'' ? 'T' : 'F'}{'no' ? 'T' : 'F'}")
+            .unwrap();
+
+    assert_eq!(template.format(&Context::new()).unwrap(), "FTFT");
+}
+
+#[test]
+fn test_truthiness_bare_count_condition() {
+    let template = CBTemplate::compile("{count ? 'has items' : 'empty'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("count", Value::Int(3));
+    assert_eq!(template.format(&ctx).unwrap(), "has items");
+
+    ctx.insert("count", Value::Int(0));
+    assert_eq!(template.format(&ctx).unwrap(), "empty");
+}
+
+// ============================================
+// Value Conversion Tests
+// ============================================
+
+#[test]
+fn test_value_from_primitives() {
+    let template = CBTemplate::compile("{s} {o} {i} {u} {f} {b}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", "static".into());
+    ctx.insert("o", String::from("owned").into());
+    ctx.insert("i", (-8i8).into());
+    ctx.insert("u", 40_000u32.into());
+    ctx.insert("f", 1.5f32.into());
+    ctx.insert("b", true.into());
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "static owned -8 40000 1.5 true");
+}
+
+#[test]
+fn test_value_from_option() {
+    let template = CBTemplate::compile("[{some}] [{none}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("some", Some(3).into());
+    ctx.insert("none", None::<i32>.into());
+
+    assert!(matches!(ctx["none"], Value::Null));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "[3] []");
+}
+
+#[test]
+fn test_value_default_is_empty_and_falsy() {
+    #[derive(Default)]
+    struct Row {
+        cell: Value<'static>,
+    }
+
+    let template = CBTemplate::compile("[{cell}] {cell ? 'yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("cell", Row::default().cell);
+
+    assert!(ctx["cell"].is_null());
+    assert_eq!(template.format(&ctx).unwrap(), "[] no");
+
+    let missing = ctx.get("missing").cloned();
+    assert!(missing.unwrap_or_default().is_null());
+}
+
+#[test]
+fn test_value_try_from_wide_integers() {
+    assert!(matches!(Value::try_from(42u64), Ok(Value::Int(42))));
+    assert!(matches!(Value::try_from(-1isize), Ok(Value::Int(-1))));
+    assert!(Value::try_from(u64::MAX).is_err());
+    assert!(Value::try_from(i128::MIN).is_err());
+}
+
+#[test]
+fn test_null_is_falsy_in_conditional() {
+    let template = CBTemplate::compile("{flag ? 'yes' : 'no'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("flag", Value::Null);
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "no");
+}
+
+#[test]
+fn test_value_display_matches_template_output() {
+    let values = [
+        Value::static_str("text"),
+        Value::Int(-42),
+        Value::Float(2.5),
+        Value::Bool(false),
+        Value::Null,
+    ];
+
+    for value in values {
+        let template = CBTemplate::compile("{v}").unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("v", value.clone());
+
+        assert_eq!(value.to_string(), template.format(&ctx).unwrap());
+    }
+}
+
+#[test]
+fn test_value_strict_accessors() {
+    let values = [
+        Value::static_str("42"),
+        Value::Int(42),
+        Value::Float(4.5),
+        Value::Bool(true),
+        Value::Bytes(vec![1, 2]),
+        Value::Null,
+    ];
+
+    let strs: Vec<_> = values.iter().map(Value::as_str).collect();
+    assert_eq!(strs, [Some("42"), None, None, None, None, None]);
+
+    let ints: Vec<_> = values.iter().map(Value::as_int).collect();
+    assert_eq!(ints, [None, Some(42), None, None, None, None]);
+
+    let floats: Vec<_> = values.iter().map(Value::as_float).collect();
+    assert_eq!(floats, [None, Some(42.0), Some(4.5), None, None, None]);
+
+    let bools: Vec<_> = values.iter().map(Value::as_bool).collect();
+    assert_eq!(bools, [None, None, None, Some(true), None, None]);
+
+    let bytes: Vec<_> = values.iter().map(Value::as_bytes).collect();
+    assert_eq!(bytes, [None, None, None, None, Some(&[1u8, 2][..]), None]);
+
+    let nulls: Vec<_> = values.iter().map(Value::is_null).collect();
+    assert_eq!(nulls, [false, false, false, false, false, true]);
+}
+
+#[test]
+fn test_value_to_int_lossy() {
+    let cases = [
+        (Value::Int(-7), Some(-7)),
+        (Value::Float(2.9), Some(2)),
+        (Value::Float(-2.9), Some(-2)),
+        (Value::Float(1e300), Some(i64::MAX)),
+        (Value::Float(f64::NAN), Some(0)),
+        (Value::static_str("42"), Some(42)),
+        (Value::static_str("-42"), Some(-42)),
+        (Value::static_str("4.2"), None),
+        (Value::static_str(" 42"), None),
+        (Value::static_str("abc"), None),
+        (Value::Bool(true), Some(1)),
+        (Value::Bool(false), Some(0)),
+        (Value::Bytes(vec![1]), None),
+        (Value::Null, None),
+    ];
+
+    for (value, expected) in cases {
+        assert_eq!(value.to_int_lossy(), expected, "{:?}", value);
+    }
+}
+
+#[test]
+fn test_value_to_float_lossy() {
+    let cases = [
+        (Value::Float(4.5), Some(4.5)),
+        (Value::Int(-3), Some(-3.0)),
+        (Value::static_str("4.2"), Some(4.2)),
+        (Value::static_str("1e3"), Some(1000.0)),
+        (Value::static_str("inf"), Some(f64::INFINITY)),
+        (Value::static_str(" 4.2"), None),
+        (Value::static_str("abc"), None),
+        (Value::Bool(true), Some(1.0)),
+        (Value::Bool(false), Some(0.0)),
+        (Value::Bytes(vec![1]), None),
+        (Value::Null, None),
+    ];
+
+    for (value, expected) in cases {
+        assert_eq!(value.to_float_lossy(), expected, "{:?}", value);
+    }
+}
+
+#[test]
+fn test_value_to_str_lossy_matches_rendering() {
+    let values = [
+        Value::static_str("text"),
+        Value::Int(-42),
+        Value::Float(2.5),
+        Value::Bool(false),
+        Value::Null,
+    ];
+
+    for value in values {
+        let template = CBTemplate::compile("{v}").unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("v", value.clone());
+
+        let text = value.to_str_lossy().unwrap();
+        assert_eq!(text, template.format(&ctx).unwrap());
+    }
+
+    assert!(matches!(
+        Value::static_str("borrowed").to_str_lossy(),
+        Some(Cow::Borrowed("borrowed"))
+    ));
+    assert_eq!(Value::Bytes(vec![0xff]).to_str_lossy(), None);
+}
+
+#[test]
+fn test_value_lossy_conversions_match_directives() {
+    // The count of a repeat converts like `to_int_lossy`
+    let template = CBTemplate::compile("{'-':n}").unwrap();
+    for value in [Value::Float(3.7), Value::static_str("3"), Value::Bool(true)] {
+        let mut ctx = Context::new();
+        ctx.insert("n", value.clone());
+
+        let count = value.to_int_lossy().unwrap() as usize;
+        assert_eq!(template.format(&ctx).unwrap(), "-".repeat(count));
+    }
+}
+
+#[test]
+fn test_value_try_into_primitives() {
+    fn mismatch<T>(expected: &'static str, found: &'static str) -> Result<T, ValueTypeError> {
+        Err(ValueTypeError { expected, found })
+    }
+
+    let values = [
+        Value::static_str("42"),
+        Value::Int(42),
+        Value::Float(2.5),
+        Value::Bool(true),
+        Value::Bytes(vec![1]),
+        Value::Null,
+    ];
+    let ints = [
+        mismatch("integer", "string"),
+        Ok(42),
+        mismatch("integer", "float"),
+        mismatch("integer", "boolean"),
+        mismatch("integer", "bytes"),
+        mismatch("integer", "null"),
+    ];
+    let floats = [
+        mismatch("float", "string"),
+        Ok(42.0),
+        Ok(2.5),
+        mismatch("float", "boolean"),
+        mismatch("float", "bytes"),
+        mismatch("float", "null"),
+    ];
+    let strings = [
+        Ok("42".to_string()),
+        mismatch("string", "integer"),
+        mismatch("string", "float"),
+        mismatch("string", "boolean"),
+        mismatch("string", "bytes"),
+        mismatch("string", "null"),
+    ];
+    let bools = [
+        mismatch("boolean", "string"),
+        mismatch("boolean", "integer"),
+        mismatch("boolean", "float"),
+        Ok(true),
+        mismatch("boolean", "bytes"),
+        mismatch("boolean", "null"),
+    ];
+
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(i64::try_from(value), ints[i], "{:?}", value);
+        assert_eq!(i64::try_from(value.clone()), ints[i], "{:?}", value);
+        assert_eq!(f64::try_from(value), floats[i], "{:?}", value);
+        assert_eq!(f64::try_from(value.clone()), floats[i], "{:?}", value);
+        assert_eq!(String::try_from(value), strings[i], "{:?}", value);
+        assert_eq!(String::try_from(value.clone()), strings[i], "{:?}", value);
+        assert_eq!(bool::try_from(value), bools[i], "{:?}", value);
+        assert_eq!(bool::try_from(value.clone()), bools[i], "{:?}", value);
+    }
+}
+
+#[test]
+fn test_value_try_into_error_message() {
+    let err = i64::try_from(Value::static_str("42")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Expected a value of type 'integer' but got 'string'"
+    );
+
+    // The lossy conversions accept what the strict ones reject
+    assert_eq!(Value::static_str("42").to_int_lossy(), Some(42));
+}
+
+#[test]
+fn test_value_from_str_infers_type() {
+    assert!(matches!(Value::from_str("42"), Ok(Value::Int(42))));
+    assert!(matches!(Value::from_str("-7"), Ok(Value::Int(-7))));
+    assert!(matches!("3.25".parse::<Value>(), Ok(Value::Float(f)) if f == 3.25));
+    assert!(matches!("1e5".parse::<Value>(), Ok(Value::Float(f)) if f == 1e5));
+    assert!(matches!("true".parse::<Value>(), Ok(Value::Bool(true))));
+    assert!(matches!("false".parse::<Value>(), Ok(Value::Bool(false))));
+    assert!(matches!("hello".parse::<Value>(), Ok(Value::Str(s)) if s == "hello"));
+}
+
+#[test]
+fn test_value_from_str_ambiguous_inputs_stay_strings() {
+    for input in [
+        "007",
+        "+5",
+        "inf",
+        "NaN",
+        "99999999999999999999",
+        "",
+        "True",
+    ] {
+        let value: Value = input.parse().unwrap();
+        assert!(matches!(&value, Value::Str(s) if s == input), "{input}");
+        assert_eq!(value.to_string(), input);
+    }
+}
+
+#[test]
+fn test_borrowed_and_owned_strings_render_identically() {
+    let sources = [
+        "{s}",
+        "{s:3}",
+        "{'x':n}",
+        "{s:<8}|{s:*^9}",
+        "{s:trunc(3)} {s:slice(1, 3)} {s:len} {s:reverse} {s:urlenc}",
+        "{s ? 'yes' : 'no'} {!s ? 'x'}",
+        "{s == t ? 'same' : 'different'} {s == 'a b' ? 'lit'} {s ~= 'A B' ? 'ci'}",
+        "{s *= 'b' ? 'has b'} {s ^= 'a' ? 'starts'} {s $= 'b' ? 'ends'} {s > t ? 'gt'}",
+        "{[s](a b:matched)(*:other)}",
+        "{s + t} {s + n}",
+        "{s:bool(Y, N)}",
+    ];
+
+    let context = |owned: bool| {
+        let text = |s: &'static str| {
+            if owned {
+                Value::owned_str(s.to_string())
+            } else {
+                Value::static_str(s)
+            }
+        };
+
+        let mut ctx = Context::new();
+        ctx.insert("s", text("a b"));
+        // The other operand of comparisons is always of the other kind
+        ctx.insert(
+            "t",
+            if owned {
+                Value::static_str("a b")
+            } else {
+                Value::owned_str("a b".to_string())
+            },
+        );
+        ctx.insert("n", text("3"));
+        ctx
+    };
+
+    for source in sources {
+        let template = CBTemplate::compile(source).unwrap();
+        let borrowed = template.format(&context(false));
+        let owned = template.format(&context(true));
+
+        assert_eq!(
+            format!("{:?}", borrowed),
+            format!("{:?}", owned),
+            "{}",
+            source
+        );
+    }
+
+    let template = CBTemplate::compile("{s == t ? 'same' : 'different'}").unwrap();
+    assert_eq!(template.format(&context(false)).unwrap(), "same");
+}
+
+// ============================================
+// Value Ordering Tests
+// ============================================
+
+#[test]
+fn test_value_order_ranks_types() {
+    let mut values = vec![
+        Value::Bytes(vec![0]),
+        Value::static_str("a"),
+        Value::Int(-5),
+        Value::Bool(false),
+        Value::Null,
+        Value::Float(f64::NAN),
+        Value::Bool(true),
+        Value::static_str(""),
+    ];
+    values.sort();
+
+    assert_eq!(
+        values,
+        [
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Int(-5),
+            Value::Float(f64::NAN),
+            Value::static_str(""),
+            Value::static_str("a"),
+            Value::Bytes(vec![0]),
+        ]
+    );
+}
+
+#[test]
+fn test_value_order_compares_numbers_exactly() {
+    use std::cmp::Ordering;
+
+    assert_eq!(Value::Int(1), Value::Float(1.0));
+    assert_eq!(Value::Float(-0.0), Value::Int(0));
+    assert!(Value::Int(2) < Value::Float(2.5));
+    assert!(Value::Int(-2) > Value::Float(-2.5));
+    assert!(Value::Int(i64::MAX) < Value::Float(f64::INFINITY));
+    assert!(Value::Int(i64::MIN) > Value::Float(f64::NEG_INFINITY));
+    assert!(Value::Int(i64::MAX) < Value::Float(9_223_372_036_854_775_808.0));
+
+    // Beyond 2^53 an i64 doesn't round to the float it's compared with
+    let big = 9_007_199_254_740_993i64;
+    assert!(Value::Int(big) > Value::Float(big as f64));
+    assert!(Value::Float(big as f64) < Value::Int(big));
+
+    assert_eq!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+    assert!(Value::Float(f64::NAN) > Value::Float(f64::INFINITY));
+    assert_eq!(
+        Value::Float(f64::NAN).total_cmp(&Value::Int(i64::MAX)),
+        Ordering::Greater
+    );
+}
+
+#[test]
+fn test_value_order_strings_are_lexicographic() {
+    assert!(Value::static_str("10") < Value::static_str("9"));
+    assert!(Value::static_str("B") < Value::static_str("a"));
+    assert_eq!(Value::static_str("x"), Value::owned_str("x".to_string()));
+    assert_ne!(Value::static_str("1"), Value::Int(1));
+}
+
+#[test]
+fn test_value_order_agrees_with_template_operators() {
+    let template = CBTemplate::compile("{a > b ? 'gt' : a < b ? 'lt' : 'eq'}").unwrap();
+    let pairs = [
+        (Value::Int(3), Value::Float(2.5)),
+        (Value::Float(-1.0), Value::Int(-1)),
+        (
+            Value::Int(9_007_199_254_740_993),
+            Value::Float(9_007_199_254_740_992.0),
+        ),
+        (Value::Bool(false), Value::Bool(true)),
+        (Value::Bytes(vec![1, 2]), Value::Bytes(vec![1])),
+    ];
+
+    for (a, b) in pairs {
+        let expected = match a.cmp(&b) {
+            std::cmp::Ordering::Greater => "gt",
+            std::cmp::Ordering::Less => "lt",
+            std::cmp::Ordering::Equal => "eq",
+        };
+        let mut ctx = Context::new();
+        ctx.insert("a", a);
+        ctx.insert("b", b);
+
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+// ============================================
+// Bytes Tests
+// ============================================
+
+#[test]
+fn test_bytes_render_with_hex() {
+    let template = CBTemplate::compile("{sig:hex} {sig:HEX} {sig:#hex}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sig", Value::from(vec![0xdeu8, 0xad, 0x01]));
+
+    assert_eq!(template.format(&ctx).unwrap(), "dead01 DEAD01 0xdead01");
+}
+
+#[test]
+fn test_bytes_bare_is_an_error() {
+    let mut ctx = Context::new();
+    ctx.insert("sig", Value::from(&[1u8, 2, 3][..]));
+
+    for source in ["{sig}", "{sig:<10}", "{on ? sig}", "{sig:bin}"] {
+        ctx.insert("on", Value::Bool(true));
+        let template = CBTemplate::compile(source).unwrap();
+
+        assert!(
+            matches!(
+                template.format(&ctx),
+                Err(DirectiveError::TypeError { .. } | DirectiveError::FilterTypeError { .. })
+            ),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_format_bytes_text() {
+    let template = CBTemplate::compile("Héllo {name:>6}, {n + 1} {{ok}}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("wörld"));
+    ctx.insert("n", Value::Int(41));
+
+    let bytes = template.format_bytes(&ctx).unwrap();
+    assert_eq!(bytes, template.format(&ctx).unwrap().into_bytes());
+    assert_eq!(bytes, "Héllo  wörld, 42 {ok}".as_bytes());
+}
+
+#[test]
+fn test_format_bytes_writes_raw_bytes() {
+    let template =
+        CBTemplate::compile("[{blob}] {on ? '<{blob}>'} {blob:hex} {missing | {blob}}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("blob", Value::from(vec![0xffu8, 0x00, b'a', 0xf4]));
+    ctx.insert("on", Value::Bool(true));
+
+    let mut expected: Vec<u8> = Vec::new();
+    expected.extend(b"[\xff\x00a\xf4] <\xff\x00a\xf4> ");
+    expected.extend(b"ff0061f4 ");
+    expected.extend(b"\xff\x00a\xf4");
+    assert_eq!(template.format_bytes(&ctx).unwrap(), expected);
+
+    // Only `format_bytes` lets bytes through
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { .. })
+    ));
+
+    for source in ["{blob:<10}", "{on ? blob}", "{blob + 'x'}"] {
+        assert!(
+            CBTemplate::compile(source)
+                .unwrap()
+                .format_bytes(&ctx)
+                .is_err(),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_format_bytes_keeps_private_use_text() {
+    let mut ctx = Context::new();
+    ctx.insert("text", Value::static_str("\u{10FF41}\u{10FFFF}"));
+    ctx.insert("blob", Value::from(vec![0x41u8, 0xff]));
+    ctx.insert("on", Value::Bool(true));
+
+    for source in [
+        "{text}{blob}",
+        "{on ? '{text}{blob}'}",
+        "{text}{missing | {blob}}",
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        let mut expected = "\u{10FF41}\u{10FFFF}".as_bytes().to_vec();
+        expected.extend([0x41, 0xff]);
+        assert_eq!(template.format_bytes(&ctx).unwrap(), expected, "{}", source);
+
+        let options = FormatOptions {
+            missing: MissingPolicy::Empty,
+            ..Default::default()
+        };
+        assert_eq!(
+            template.format_bytes_with_options(&ctx, &options).unwrap(),
+            expected,
+            "{}",
+            source
+        );
+    }
+
+    // Bytes count as bytes against the output limit, not as the chars they
+    // travel as
+    let options = FormatOptions {
+        max_output_len: 10,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile("{text}{blob}").unwrap();
+    assert_eq!(
+        template
+            .format_bytes_with_options(&ctx, &options)
+            .unwrap()
+            .len(),
+        10
+    );
+
+    // Bytes in a sub-template rendered to a string are still text
+    let template = CBTemplate::compile("{[on](true:{blob})(*:x) + 'y'}").unwrap();
+    assert!(matches!(
+        template.format_bytes(&ctx),
+        Err(DirectiveError::TypeError { .. })
+    ));
+}
+
+#[test]
+fn test_bytes_len_and_truthiness() {
+    let template = CBTemplate::compile("{sig:len} {sig ? 'set' : 'empty'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("sig", Value::from(vec![1u8, 2, 3, 4]));
+    assert_eq!(template.format(&ctx).unwrap(), "4 set");
+
+    ctx.insert("sig", Value::Bytes(Vec::new()));
+    assert_eq!(template.format(&ctx).unwrap(), "0 empty");
+}
+
+#[test]
+fn test_array_len_and_truthiness() {
+    let template = CBTemplate::compile(
+        "{items:len} {items ? 'some' : 'none'} {len(items) > 1 ? 'many' : 'few'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("items", vec![1, 2, 3].into());
+    assert_eq!(template.format(&ctx).unwrap(), "3 some many");
+
+    ctx.insert("items", vec!["apple"].into());
+    assert_eq!(template.format(&ctx).unwrap(), "1 some few");
+
+    ctx.insert("items", Value::Array(Vec::new()));
+    assert_eq!(template.format(&ctx).unwrap(), "0 none few");
+
+    let template = CBTemplate::compile("{!items ? 'empty'}{is_array(items) ? '!'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "empty!");
+    let template = CBTemplate::compile("{len(n) > 0 ? 'x'}").unwrap();
+    ctx.insert("n", Value::Int(3));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FunctionArgumentError {
+            function: "len",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_array_conversions() {
+    assert_eq!(
+        Value::from(vec![1, 2]),
+        Value::Array(vec![Value::Int(1), Value::Int(2)])
+    );
+    assert_eq!(
+        Value::from(vec![String::from("a")]),
+        Value::Array(vec![Value::static_str("a")])
+    );
+    assert_eq!(
+        Value::from(vec![true, false]).as_array(),
+        Some(&[Value::Bool(true), Value::Bool(false)][..])
+    );
+    assert_eq!(
+        Value::from(vec![Value::Null, Value::Float(1.5)]),
+        Value::Array(vec![Value::Null, Value::Float(1.5)])
+    );
+
+    // Bytes stay bytes
+    assert!(matches!(Value::from(vec![1u8, 2]), Value::Bytes(_)));
+
+    let array = Value::from(vec![1, 2]);
+    assert_eq!(array.type_name(), "array");
+    assert_eq!(array.to_string(), "[1, 2]");
+    assert_eq!(array.to_str_lossy(), None);
+    assert_eq!(array.to_int_lossy(), None);
+    assert!(Value::from(vec![1, 2]) < Value::from(vec![1, 3]));
+    assert!(Value::from(vec![1]) < Value::from(vec![1, 0]));
+}
+
+#[test]
+fn test_array_has_no_text_form() {
+    let mut ctx = Context::new();
+    ctx.insert("items", vec![1, 2].into());
+    ctx.insert("other", vec![1, 2].into());
+
+    for source in [
+        "{items}",
+        "{items:upper}",
+        "{items + 'x'}",
+        "[{items}](*:x)",
+    ] {
+        let result = CBTemplate::compile(source).unwrap().format(&ctx);
+        assert!(
+            matches!(
+                result,
+                Err(DirectiveError::TypeError { .. } | DirectiveError::FilterTypeError { .. })
+            ),
+            "{}: {:?}",
+            source,
+            result
+        );
+    }
+
+    let template = CBTemplate::compile("{items == other ? 'same'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "same");
+}
+
+#[test]
+fn test_bytes_compare_byte_wise() {
+    let template = CBTemplate::compile("{a == b ? 'same' : 'different'}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("a", Value::from(vec![1u8, 2, 3]));
+    ctx.insert("b", Value::from(&[1u8, 2, 3][..]));
+    assert_eq!(template.format(&ctx).unwrap(), "same");
+
+    ctx.insert("b", Value::from(vec![1u8, 2, 4]));
+    assert_eq!(template.format(&ctx).unwrap(), "different");
+
+    ctx.insert("b", Value::static_str("010203"));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { .. })
+    ));
+}
+
+#[cfg(feature = "base64")]
+#[test]
+fn test_bytes_render_with_base64() {
+    let template = CBTemplate::compile("{token:base64} {token:base64(url)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("token", Value::from(vec![0xfbu8, 0xff, 0x00]));
+
+    assert_eq!(template.format(&ctx).unwrap(), "+/8A -_8A");
+}
+
+// ============================================
+// Function Tests
+// ============================================
+
+#[test]
+fn test_functions_preserve_int_and_float() {
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(3));
+    ctx.insert("b", Value::Int(7));
+    ctx.insert("f", Value::Float(2.5));
+    ctx.insert("delta", Value::Int(-4));
+
+    for (source, expected) in [
+        ("{max(a, b)}", "7"),
+        ("{min(a, b)}", "3"),
+        ("{max(a, f)}", "3.0"),
+        ("{min(a, f)}", "2.5"),
+        ("{abs(delta)}", "4"),
+        ("{abs(-1.5)}", "1.5"),
+        ("{max(a, b, 10)}", "10"),
+        ("{min(a)}", "3"),
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", source);
+    }
+
+    let template = CBTemplate::compile("{max(a, f)}").unwrap();
+    let mut out = Vec::new();
+    template.format_to(&ctx, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "3.0");
+}
+
+#[test]
+fn test_functions_nest_to_clamp() {
+    let template = CBTemplate::compile("{min(max(score, 0), 100)}").unwrap();
+    let mut ctx = Context::new();
+
+    for (score, expected) in [(-20, "0"), (42, "42"), (250, "100")] {
+        ctx.insert("score", Value::Int(score));
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_functions_in_conditions() {
+    let template =
+        CBTemplate::compile("{abs(delta) > 10 ? 'big' : 'small'} {max(a, b) ? 'some'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("delta", Value::Int(-15));
+    ctx.insert("a", Value::Int(0));
+    ctx.insert("b", Value::Int(0));
+
+    assert_eq!(template.format(&ctx).unwrap(), "big ");
+
+    ctx.insert("delta", Value::Int(5));
+    ctx.insert("b", Value::Int(1));
+    assert_eq!(template.format(&ctx).unwrap(), "small some");
+}
+
+#[test]
+fn test_functions_with_filters() {
+    let template = CBTemplate::compile("{abs(delta):05} {max(a, b) | +}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("delta", Value::Int(-42));
+    ctx.insert("a", Value::Int(1));
+    ctx.insert("b", Value::Int(2));
+
+    assert_eq!(template.format(&ctx).unwrap(), "00042 +2");
+}
+
+#[test]
+fn test_function_names_remain_variables() {
+    let template = CBTemplate::compile("{max} {max:2}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("max", Value::static_str("ab"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ab abab");
+}
+
+#[test]
+fn test_function_rejects_non_numbers() {
+    let template = CBTemplate::compile("{max(a, b)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(1));
+    ctx.insert("b", Value::static_str("two"));
+
+    let err = template.format(&ctx).unwrap_err();
+    assert!(matches!(
+        err,
+        DirectiveError::FunctionArgumentError {
+            function: "max",
+            expected: "a number",
+            ..
+        }
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Function 'max' expected a number but got a value of type 'string'"
+    );
+}
+
+#[test]
+fn test_function_invalid_calls_render_empty() {
+    let template = CBTemplate::compile("[{abs(a, b)}][{max()}][{min(a b)}]").unwrap();
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &with_unknown(UnknownPolicy::Empty))
+            .unwrap(),
+        "[][][]"
+    );
+}
+
+#[test]
+fn test_function_variables_and_tree() {
+    let template = CBTemplate::compile("{min(max(x, 0), limit)}").unwrap();
+
+    assert_eq!(template.variables(), vec!["x", "limit"]);
+    assert!(template.debug_tree().contains("min(max(x, \"0\"), limit)"));
+}
+
+#[test]
+fn test_function_built_in_code() {
+    let built: CBTemplate = TemplateBuilder::new()
+        .replace(Argument::call(Function::Max, vec![var("a"), lit("0")]))
+        .build();
+
+    assert_eq!(built, CBTemplate::compile("{max(a, 0)}").unwrap());
+
+    let empty: CBTemplate = TemplateBuilder::new()
+        .replace(Argument::call(Function::Abs, Vec::new()))
+        .build();
+
+    assert!(matches!(
+        empty.format(&Context::new()),
+        Err(DirectiveError::FunctionArgumentError {
+            function: "abs",
+            ..
+        })
+    ));
+}
+
+// ============================================
+// Addition and Concatenation Tests
+// ============================================
+
+#[test]
+fn test_plus_concatenates_strings() {
+    let template = CBTemplate::compile("{first + ' ' + last}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("first", Value::static_str("Ada"));
+    ctx.insert("last", Value::static_str("Lovelace"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Ada Lovelace");
+}
+
+#[test]
+fn test_plus_operand_combinations() {
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("n"));
+    ctx.insert("i", Value::Int(2));
+    ctx.insert("f", Value::Float(0.5));
+    ctx.insert("b", Value::Bool(true));
+    ctx.insert("null", Value::Null);
+    ctx.insert("big", Value::Int(i64::MAX));
+
+    for (source, expected) in [
+        ("{s + s}", "nn"),
+        ("{s + i}", "n2"),
+        ("{i + s}", "2n"),
+        ("{s + f}", "n0.5"),
+        ("{s + b}", "ntrue"),
+        ("{s + null}", "n"),
+        ("{i + i}", "4"),
+        ("{i + f}", "2.5"),
+        ("{f + f}", "1.0"),
+        ("{i + 1}", "3"),
+        ("{i + -3}", "-1"),
+        ("{i + i + s}", "4n"),
+        ("{s + i + i}", "n22"),
+        ("{big + -1}", "9223372036854775806"),
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", source);
+    }
+}
+
+#[test]
+fn test_float_sum_compares_within_tolerance() {
+    let template =
+        CBTemplate::compile("{a + b == c ? 'eq' : 'ne'} {a + b != c ? 'ne' : 'eq'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Float(0.1));
+    ctx.insert("b", Value::Float(0.2));
+    ctx.insert("c", Value::Float(0.3));
+
+    assert_eq!(template.format(&ctx).unwrap(), "eq eq");
+}
+
+#[test]
+fn test_plus_rejects_other_types() {
+    let mut ctx = Context::new();
+    ctx.insert("i", Value::Int(2));
+    ctx.insert("b", Value::Bool(true));
+    ctx.insert("s", Value::static_str("x"));
+    ctx.insert("bytes", Value::from(vec![1u8, 2]));
+
+    for (source, name, found) in [
+        ("{i + b}", "b", "boolean"),
+        ("{b + i}", "b", "boolean"),
+        ("{s + bytes}", "bytes", "bytes"),
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+
+        match template.format(&ctx) {
+            Err(DirectiveError::TypeError {
+                name: err_name,
+                expected: "number or string",
+                found: err_found,
+            }) => {
+                assert_eq!(err_name, name, "{}", source);
+                assert_eq!(err_found, found, "{}", source);
+            }
+            other => panic!("{}: unexpected result {:?}", source, other),
+        }
+    }
+}
+
+#[test]
+fn test_integer_overflow_is_an_error() {
+    let mut ctx = Context::new();
+    ctx.insert("big", Value::Int(i64::MAX));
+    ctx.insert("min", Value::Int(i64::MIN));
+
+    for (source, expression) in [
+        ("{big + 1}", "9223372036854775807 + 1"),
+        ("{big + big}", "9223372036854775807 + 9223372036854775807"),
+        ("{min + -1}", "-9223372036854775808 + -1"),
+        ("{abs(min)}", "abs(-9223372036854775808)"),
+        ("{big + 1 > 0 ? 'yes' : 'no'}", "9223372036854775807 + 1"),
+        ("{max(big + 1, 0)}", "9223372036854775807 + 1"),
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+
+        match template.format(&ctx) {
+            Err(DirectiveError::ArithmeticOverflow { expression: e }) => {
+                assert_eq!(e, expression, "{}", source)
+            }
+            other => panic!("{}: expected ArithmeticOverflow, got {:?}", source, other),
+        }
+    }
+
+    // Results at the edges of the range still fit
+    let template = CBTemplate::compile("{big + 0} {min + 1} {abs(min + 1)} {big + 1.0}").unwrap();
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "9223372036854775807 -9223372036854775807 9223372036854775807 9.223372036854776e+18"
+    );
+}
+
+#[test]
+fn test_plus_in_conditions_functions_and_filters() {
+    let template = CBTemplate::compile(
+        "{a + b > 10 ? 'big' : 'small'} {max(a + b, 0)} {first + last:>8} {a + b:+}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(4));
+    ctx.insert("b", Value::Int(9));
+    ctx.insert("first", Value::static_str("Jo"));
+    ctx.insert("last", Value::static_str("Ng"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "big 13     JoNg +13");
+}
+
+#[test]
+fn test_plus_tree_and_variables() {
+    let template = CBTemplate::compile("{first + ' ' + last}").unwrap();
+
+    assert_eq!(template.variables(), vec!["first", "last"]);
+    assert!(template.debug_tree().contains("first + \" \" + last"));
+
+    let built: CBTemplate = TemplateBuilder::new()
+        .replace(Argument::add(
+            Argument::add(var("first"), lit(" ")),
+            var("last"),
+        ))
+        .build();
+    assert_eq!(built, template);
+}
+
+// ============================================
+// Filter Chain Tests
+// ============================================
+
+#[test]
+fn test_filter_chain_applies_left_to_right() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Alexander"));
+
+    for (source, expected) in [
+        ("{name | slice(0, 4) | >6}", "  Alex"),
+        ("{name:slice(0, 4) | >6}", "  Alex"),
+        ("{name | >12 | slice(0, 4)}", "   A"),
+        ("{name | len | +05}", "+0009"),
+        ("{name|trunc(4, '.')|*^7}", "*Ale.**"),
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        assert_eq!(template.format(&ctx).unwrap(), expected, "{}", source);
+    }
+}
+
+#[test]
+fn test_filter_chain_pipe_as_fill() {
+    let template = CBTemplate::compile("{name:|^7} {name | |>5}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Bob"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "||Bob|| ||Bob");
+}
+
+#[test]
+fn test_filter_chain_invalid_stage_renders_empty() {
+    let template = CBTemplate::compile("[{name | len | nope}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Bob"));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+            .unwrap(),
+        "[]"
+    );
+}
+
+#[test]
+fn test_filter_chain_reports_failing_filter() {
+    let template = CBTemplate::compile("{name | len | +05 | hex}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Bob"));
+
+    match template.format(&ctx) {
+        Err(DirectiveError::FilterChainError {
+            position,
+            filter,
+            source,
+        }) => {
+            assert_eq!(position, 3);
+            assert_eq!(filter, "hex");
+            assert!(matches!(
+                *source,
+                DirectiveError::FilterTypeError {
+                    filter: "hex",
+                    expected: "integer",
+                    found: "string",
+                }
+            ));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_single_filter_error_is_not_wrapped() {
+    let template = CBTemplate::compile("{name | hex}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Bob"));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError { filter: "hex", .. })
+    ));
+}
+
+// ============================================
+// Filter Mismatch Tests
+// ============================================
+
+fn with_mismatch(filter_mismatch: FilterMismatch) -> FormatOptions {
+    FormatOptions {
+        filter_mismatch,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_filter_mismatch_string_filter_on_each_variant() {
+    let template = CBTemplate::compile("{v:len}").unwrap();
+
+    // (value, Skip, Coerce), `None` where rendering fails
+    let cases = [
+        (Value::static_str("abc"), Some("3"), Some("3")),
+        (Value::Int(4200), Some("4200"), Some("4")),
+        (Value::Float(1.5), Some("1.5"), Some("3")),
+        (Value::Bool(true), Some("true"), Some("4")),
+        (Value::Bytes(vec![1, 2]), Some("2"), Some("2")),
+        (Value::Null, Some(""), Some("0")),
+    ];
+
+    for (value, skip, coerce) in cases {
+        let ctx = Context::from([("v", value.clone())]);
+        let error = template.format_with_options(&ctx, &with_mismatch(FilterMismatch::Error));
+        let render = |policy| {
+            template
+                .format_with_options(&ctx, &with_mismatch(policy))
+                .ok()
+        };
+
+        match &value {
+            Value::Str(_) | Value::Bytes(_) => assert!(error.is_ok(), "{value:?}"),
+            _ => assert!(
+                matches!(
+                    error,
+                    Err(DirectiveError::FilterTypeError { filter: "len", .. })
+                ),
+                "{value:?}"
+            ),
+        }
+        assert_eq!(render(FilterMismatch::Skip).as_deref(), skip, "{value:?}");
+        assert_eq!(
+            render(FilterMismatch::Coerce).as_deref(),
+            coerce,
+            "{value:?}"
+        );
+    }
+}
+
+#[test]
+fn test_filter_mismatch_text_filter_on_bytes() {
+    let template = CBTemplate::compile("{v:squish}").unwrap();
+    let ctx = Context::from([("v", Value::Bytes(b"  a   b ".to_vec()))]);
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::FilterTypeError {
+            filter: "squish",
+            ..
+        })
+    ));
+    // Skipped, the bytes reach the output, which has no text form for them
+    assert!(matches!(
+        template.format_with_options(&ctx, &with_mismatch(FilterMismatch::Skip)),
+        Err(DirectiveError::TypeError { .. })
+    ));
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_mismatch(FilterMismatch::Coerce))
+            .unwrap(),
+        "a b"
+    );
+}
+
+#[test]
+fn test_filter_mismatch_number_filter_on_strings() {
+    let template = CBTemplate::compile("{id:hex} {price:round(1)} {name | hex | len}").unwrap();
+    let ctx = Context::from([
+        ("id", Value::static_str("255")),
+        ("price", Value::static_str("2.25")),
+        ("name", Value::static_str("Bob")),
+    ]);
+
+    assert!(template.format(&ctx).is_err());
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_mismatch(FilterMismatch::Skip))
+            .unwrap(),
+        "255 2.25 3"
+    );
+    // Strings that aren't numbers are skipped even when coercing
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_mismatch(FilterMismatch::Coerce))
+            .unwrap(),
+        "ff 2.3 3"
+    );
+}
+
+#[test]
+fn test_filter_mismatch_keeps_other_errors() {
+    let template = CBTemplate::compile("{v:repeat(100000000)}").unwrap();
+    let ctx = Context::from([("v", Value::static_str("ab"))]);
+
+    for policy in [FilterMismatch::Skip, FilterMismatch::Coerce] {
+        let options = with_mismatch(policy);
+        assert!(matches!(
+            template.format_with_options(&ctx, &options),
+            Err(DirectiveError::OutputLimitExceeded { .. })
+        ));
+
+        let template = CBTemplate::compile("{missing:len}").unwrap();
+        assert!(matches!(
+            template.format_with_options(&Context::new(), &options),
+            Err(DirectiveError::NotFound { .. })
+        ));
+    }
+}
+
+// ============================================
+// Context Lookup Tests
+// ============================================
+
+#[test]
+fn test_owned_context_with_runtime_keys() {
+    let template = CBTemplate::compile("{item0}-{item1}-{item2}").unwrap();
+    let mut ctx = OwnedContext::new();
+
+    for i in 0..3 {
+        ctx.insert(format!("item{}", i), Value::Int(i * 10));
+    }
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "0-10-20");
+}
+
+#[test]
+fn test_borrowed_key_context() {
+    let key = String::from("name");
+    let template = CBTemplate::compile("Hi {name}").unwrap();
+    let mut ctx = std::collections::HashMap::new();
+    ctx.insert(key.as_str(), Value::static_str("Ann"));
+
+    let result = template.format(&ctx).unwrap();
+    assert_eq!(result, "Hi Ann");
+}
+
+#[test]
+fn test_borrowed_string_values() {
+    let buffer = String::from("id=7;user=Ada Lovelace;role=admin");
+    let user = &buffer[10..22];
+    let role = &buffer[28..];
+
+    let mut ctx = BorrowedContext::new();
+    ctx.insert("user", Value::borrowed_str(user));
+    ctx.insert("role", role.into());
+
+    // The context holds the slices themselves, not copies
+    assert!(matches!(ctx["user"], Value::Str(Cow::Borrowed(s)) if std::ptr::eq(s, user)));
+
+    let template = CBTemplate::compile(
+        "{user} {user:trunc(4)} {[role](admin:*)(*:-)} {role == 'admin' ? 'ok'}",
+    )
+    .unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "Ada Lovelace Ada… * ok");
+
+    // Filters that keep a value as it is pass the borrow on
+    let filter = TruncateFilter {
+        width: 20,
+        ellipsis: Cow::Borrowed("…"),
+    };
+    let kept = filter.apply(Value::borrowed_str(user)).unwrap();
+    assert!(matches!(kept, Value::Str(Cow::Borrowed(s)) if std::ptr::eq(s, user)));
+
+    let owned: Value<'static> = Value::borrowed_str(user).into_owned();
+    drop(ctx);
+    assert_eq!(owned, Value::static_str("Ada Lovelace"));
+}
+
+#[test]
+fn test_array_context() {
+    let template = CBTemplate::compile("{name} x{qty}").unwrap();
+    let ctx = [
+        ("name", Value::static_str("pen")),
+        ("qty", Value::Int(3)),
+        ("name", Value::static_str("shadowed")),
+    ];
+
+    assert_eq!(template.format(&ctx).unwrap(), "pen x3");
+    assert!(matches!(
+        CBTemplate::compile("{missing}").unwrap().format(&ctx),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_map_contexts_render_deterministically() {
+    let source = (0..32)
+        .map(|i| format!("{{k{}}}", i))
+        .collect::<Vec<_>>()
+        .join(",");
+    let template = CBTemplate::compile(format!("{} {{k3 > 2 ? 'big'}}", source)).unwrap();
+    let expected = template
+        .format(&figura::SortedContext::from_iter(
+            (0..40).map(|i| (format!("k{}", i), Value::Int(i))),
+        ))
+        .unwrap();
+
+    // Every fresh `HashMap` gets its own random seed, so its iteration order
+    // changes between runs while the output doesn't
+    for _ in 0..20 {
+        let ctx: OwnedContext = (0..40)
+            .map(|i| (format!("k{}", i), Value::Int(i)))
+            .collect();
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+        assert_eq!(
+            template.unused_keys(&ctx),
+            (32..40).map(|i| format!("k{}", i)).collect::<Vec<_>>()
+        );
+    }
+
+    let mut sorted = std::collections::BTreeMap::new();
+    sorted.insert("k1", Value::Int(1));
+    sorted.insert("k0", Value::Int(0));
+    assert_eq!(
+        CBTemplate::compile("{k0}{k1}")
+            .unwrap()
+            .format(&sorted)
+            .unwrap(),
+        "01"
+    );
+}
+
+#[test]
+fn test_custom_context_lookup() {
+    struct Row {
+        id: Value<'static>,
+        title: Value<'static>,
+    }
+
+    impl ContextLookup for Row {
+        fn get(&self, key: &str) -> Option<&Value<'_>> {
+            match key {
+                "id" => Some(&self.id),
+                "title" => Some(&self.title),
+                _ => None,
+            }
+        }
+    }
+
+    let row = Row {
+        id: Value::Int(7),
+        title: Value::static_str("Draft"),
+    };
+
+    let template = CBTemplate::compile("#{id}: {title}").unwrap();
+    assert_eq!(template.format(&row).unwrap(), "#7: Draft");
+    assert!(
+        CBTemplate::compile("{missing}")
+            .unwrap()
+            .format(&row)
+            .is_err()
+    );
+}
+
+/// Computes `n<digits>` as the square of the number, counting lookups.
+struct Squares {
+    lookups: std::cell::RefCell<Vec<String>>,
+}
+
+impl Squares {
+    fn new() -> Self {
+        Self {
+            lookups: std::cell::RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl ContextLookup for Squares {
+    fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+        self.lookups.borrow_mut().push(key.to_string());
+        let n: i64 = key.strip_prefix('n')?.parse().ok()?;
+        Some(Cow::Owned(Value::Int(n * n)))
+    }
+}
+
+#[test]
+fn test_resolve_computes_values_on_demand() {
+    let template =
+        CBTemplate::compile("{n3} {n4:>4} {n2 + 1} {n5 > 20 ? 'big'} {defined(n1) ? 'set'}")
+            .unwrap();
+    let squares = Squares::new();
+
+    assert_eq!(template.format(&squares).unwrap(), "9   16 5 big set");
+    assert!(matches!(
+        CBTemplate::compile("{x}").unwrap().format(&squares),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_resolve_is_lazy() {
+    let template = CBTemplate::compile("{n1 == 1 ? {n2} : {n3}} {[n1](1:one)(*:{n4})}").unwrap();
+    let squares = Squares::new();
+
+    assert_eq!(template.format(&squares).unwrap(), "4 one");
+    let lookups = squares.lookups.borrow();
+    assert!(lookups.iter().any(|key| key == "n2"));
+    assert!(!lookups.iter().any(|key| key == "n3" || key == "n4"));
+
+    let repeated = CBTemplate::compile("{n7}-{n7}-{n7}").unwrap();
+    let squares = Squares::new();
+    assert_eq!(repeated.format(&squares).unwrap(), "49-49-49");
+    assert_eq!(*squares.lookups.borrow(), ["n7"]);
+}
+
+#[test]
+fn test_resolve_from_json_document() {
+    struct Json(serde_json::Value);
+
+    impl ContextLookup for Json {
+        fn resolve(&self, key: &str) -> Option<Cow<'_, Value<'_>>> {
+            let value = match self.0.get(key)? {
+                serde_json::Value::String(s) => Value::owned_str(s.clone()),
+                serde_json::Value::Bool(b) => Value::Bool(*b),
+                serde_json::Value::Number(n) => match n.as_i64() {
+                    Some(i) => Value::Int(i),
+                    None => Value::Float(n.as_f64()?),
+                },
+                serde_json::Value::Null => Value::Null,
+                _ => return None,
+            };
+            Some(Cow::Owned(value))
+        }
+    }
+
+    let doc = Json(serde_json::json!({
+        "name": "Ada",
+        "age": 36,
+        "score": 9.5,
+        "admin": true,
+        "nick": null,
+    }));
+    let template =
+        CBTemplate::compile("{name} ({age}) {score} {admin ? 'admin'} {nick | {name}}").unwrap();
+
+    assert_eq!(template.format(&doc).unwrap(), "Ada (36) 9.5 admin ");
+
+    let mut overrides = Context::new();
+    overrides.insert("name", Value::static_str("Grace"));
+    let stack = ContextStack::new(&doc).with(&overrides);
+    assert_eq!(template.format(&stack).unwrap(), "Grace (36) 9.5 admin ");
+    assert_eq!(
+        template.format_layered(&[&overrides, &doc]).unwrap(),
+        "Grace (36) 9.5 admin "
+    );
+}
+
+/// A lazy context whose `summary` counts how often it is computed.
+fn lazy_report(admin: bool) -> (LazyContext, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = std::sync::Arc::clone(&calls);
+
+    let mut ctx = LazyContext::new();
+    ctx.insert("admin", Value::Bool(admin));
+    ctx.insert_lazy("summary", move || {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Value::static_str("3 errors")
+    });
+
+    (ctx, calls)
+}
+
+#[test]
+fn test_lazy_context_skips_untaken_branches() {
+    let template = CBTemplate::compile("{admin ? 'Report: {summary}' : 'Welcome'}").unwrap();
+    let (ctx, calls) = lazy_report(false);
+
+    assert_eq!(template.format(&ctx).unwrap(), "Welcome");
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    assert!(!ctx.is_computed("summary"));
+    assert!(ctx.is_computed("admin"));
+}
+
+#[test]
+fn test_lazy_context_computes_once() {
+    let template = CBTemplate::compile(
+        "{admin ? 'Report: {summary}'} [{summary:>9}] {summary *= 'err' ? '!'}",
+    )
+    .unwrap();
+    let (ctx, calls) = lazy_report(true);
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "Report: 3 errors [ 3 errors] !"
+    );
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "Report: 3 errors [ 3 errors] !"
+    );
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert!(ctx.is_computed("summary"));
+    assert_eq!(ctx.len(), 2);
+}
+
+#[test]
+fn test_lazy_context_shared_across_threads() {
+    let template = CBTemplate::compile("{summary}").unwrap();
+    let (ctx, calls) = lazy_report(true);
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| assert_eq!(template.format(&ctx).unwrap(), "3 errors"));
+        }
+    });
+
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_lazy_context_as_layer() {
+    let template = CBTemplate::compile("{summary} {missing | 'none'}").unwrap();
+    let (lazy, calls) = lazy_report(true);
+
+    let mut overrides = Context::new();
+    overrides.insert("summary", Value::static_str("cached"));
+
+    assert_eq!(
+        template
+            .format(&ContextStack::new(&lazy).with(&overrides))
+            .unwrap(),
+        "cached none"
+    );
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    assert_eq!(
+        template.format_layered(&[&lazy, &overrides]).unwrap(),
+        "3 errors none"
+    );
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_context_with_custom_hasher() {
+    use std::collections::HashMap;
+    use std::hash::{BuildHasherDefault, DefaultHasher};
+
+    let template = CBTemplate::compile("{a} {b}").unwrap();
+
+    let mut ctx: HashMap<&str, Value, BuildHasherDefault<DefaultHasher>> = HashMap::default();
+    ctx.insert("a", Value::Int(1));
+    ctx.insert("b", Value::Int(2));
+    assert_eq!(template.format(&ctx).unwrap(), "1 2");
+
+    let mut owned: HashMap<String, Value, BuildHasherDefault<DefaultHasher>> = HashMap::default();
+    owned.insert("a".to_string(), Value::Int(3));
+    owned.insert("b".to_string(), Value::Int(4));
+    assert_eq!(template.format(&owned).unwrap(), "3 4");
+}
+
+#[test]
+fn test_context_stack_top_layer_wins() {
+    let template = CBTemplate::compile("{greeting}, {name} - {brand}").unwrap();
+
+    let mut defaults = Context::new();
+    defaults.insert("brand", Value::static_str("Figura"));
+    defaults.insert("greeting", Value::static_str("Hello"));
+    defaults.insert("name", Value::static_str("guest"));
+
+    let mut locale = OwnedContext::new();
+    locale.insert("greeting".to_string(), Value::static_str("Ciao"));
+
+    let mut user = Context::new();
+    user.insert("name", Value::static_str("Ada"));
+
+    let mut stack = ContextStack::new(&defaults);
+    assert_eq!(template.format(&stack).unwrap(), "Hello, guest - Figura");
+
+    stack.push(&locale);
+    stack.push(&user);
+    assert_eq!(stack.len(), 3);
+    assert_eq!(template.format(&stack).unwrap(), "Ciao, Ada - Figura");
+
+    stack.pop();
+    assert_eq!(template.format(&stack).unwrap(), "Ciao, guest - Figura");
+}
+
+#[test]
+fn test_context_stack_missing_in_all_layers() {
+    let template = CBTemplate::compile("{missing}").unwrap();
+    let base = Context::new();
+    let top = Context::new();
+
+    assert!(matches!(
+        template.format(&ContextStack::new(&base).with(&top)),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_format_layered_first_hit_wins() {
+    let template = CBTemplate::compile("{a}{b}{c}").unwrap();
+
+    let mut first = Context::new();
+    first.insert("a", Value::Int(1));
+
+    let mut second = Context::new();
+    second.insert("a", Value::Int(9));
+    second.insert("b", Value::Int(2));
+
+    let mut third = Context::new();
+    third.insert("b", Value::Int(9));
+    third.insert("c", Value::Int(3));
+
+    assert_eq!(
+        template.format_layered(&[&first, &second, &third]).unwrap(),
+        "123"
+    );
+    assert!(template.format_layered(&[&first, &second]).is_err());
+    assert!(template.format_layered(&[]).is_err());
+}
+
+#[test]
+fn test_format_args_positional() {
+    let template = CBTemplate::compile("{0}: {1:>4}|{2 | 'none'}").unwrap();
+    let args = [Value::static_str("total"), Value::Int(42)];
+    assert_eq!(template.format_args(&args).unwrap(), "total:   42|none");
+
+    // A name made of digits is positional in any context
+    let mut ctx = Context::new();
+    ctx.insert("0", Value::static_str("key"));
+    ctx.insert("1", Value::Int(1));
+    assert_eq!(template.format(&ctx).unwrap(), "key:    1|none");
+}
+
+#[test]
+fn test_format_args_with_conditionals() {
+    let template = CBTemplate::compile(
+        "{0} {`1` == 1 ? 'file' : 'files'}{`2` ? ' in {2}'}{[`1`](0:, none)(*:)}",
+    )
+    .unwrap();
+
+    let args = [
+        Value::static_str("Found"),
+        Value::Int(3),
+        Value::static_str("src"),
+    ];
+    assert_eq!(template.format_args(&args).unwrap(), "Found files in src");
+
+    let args = [
+        Value::static_str("Found"),
+        Value::Int(1),
+        Value::Bool(false),
+    ];
+    assert_eq!(template.format_args(&args).unwrap(), "Found file");
+
+    let args = [
+        Value::static_str("Found"),
+        Value::Int(0),
+        Value::Bool(false),
+    ];
+    assert_eq!(template.format_args(&args).unwrap(), "Found files, none");
+
+    // Bare numbers in conditions stay literals
+    let template = CBTemplate::compile("{1 ? 'yes' : 'no'}").unwrap();
+    assert_eq!(template.format_args(&[]).unwrap(), "yes");
+}
+
+#[test]
+fn test_format_args_out_of_range() {
+    let template = CBTemplate::compile("[{0}|{1}]").unwrap();
+
+    assert!(matches!(
+        template.format_args(&[Value::Int(1)]),
+        Err(DirectiveError::NotFound { .. })
+    ));
+
+    let options = FormatOptions {
+        missing: MissingPolicy::Empty,
+        ..Default::default()
+    };
+    assert_eq!(
+        template
+            .format_args_with_options(&[Value::Int(1)], &options)
+            .unwrap(),
+        "[1|]"
+    );
+    assert_eq!(
+        template.format_args_with_options(&[], &options).unwrap(),
+        "[|]"
+    );
+}
+
+#[test]
+fn test_env_context_resolves_environment_variables() {
+    // Cargo sets this for test binaries
+    let template = CBTemplate::compile("{CARGO_PKG_NAME}").unwrap();
+    let env = EnvContext::capture();
+
+    assert_eq!(template.format(&env).unwrap(), "figura");
+}
+
+#[test]
+fn test_env_context_as_lowest_layer() {
+    let template = CBTemplate::compile("{CARGO_PKG_NAME} {name}").unwrap();
+    let env = EnvContext::capture();
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    assert_eq!(
+        template
+            .format(&ContextStack::new(&env).with(&ctx))
+            .unwrap(),
+        "figura Ada"
+    );
+
+    ctx.insert("CARGO_PKG_NAME", Value::static_str("shadowed"));
+    assert_eq!(
+        template
+            .format(&ContextStack::new(&env).with(&ctx))
+            .unwrap(),
+        "shadowed Ada"
+    );
+}
+
+#[test]
+fn test_env_context_with_prefix() {
+    let env = EnvContext::with_prefix("CARGO_PKG_");
+
+    let template = CBTemplate::compile("{NAME}").unwrap();
+    assert_eq!(template.format(&env).unwrap(), "figura");
+
+    let template = CBTemplate::compile("{CARGO_PKG_NAME}").unwrap();
+    assert!(matches!(
+        template.format(&env),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_context_builder_round_trips_through_format() {
+    let ctx = ContextBuilder::new()
+        .str("name", "World")
+        .str("greeting", String::from("Hello"))
+        .int("age", 30)
+        .float("balance", 2.0)
+        .bool("admin", true)
+        .build();
+
+    let template =
+        CBTemplate::compile("{greeting}, {name}! {age} {balance} {admin ? 'admin'}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "Hello, World! 30 2.0 admin");
+
+    assert_eq!(ctx.get("age"), Some(&Value::Int(30)));
+    assert_eq!(ctx.get("balance"), Some(&Value::Float(2.0)));
+}
+
+#[test]
+fn test_context_builder_set_and_overwrite() {
+    let ctx = ContextBuilder::new()
+        .set("count", 3u8)
+        .set("nick", None::<&str>)
+        .int("n", 1)
+        .str("n", "one")
+        .build();
+
+    assert_eq!(ctx.len(), 3);
+    assert_eq!(ctx.get("nick"), Some(&Value::Null));
+
+    let template = CBTemplate::compile("{count} {nick ?? 'anon'} {n}").unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "3 anon one");
+    assert!(ContextBuilder::new().build().is_empty());
+}
+
+// ============================================
+// Custom Parser Tests
+// ============================================
+
+/// Renders its argument twice.
+struct TwiceDirective(Argument);
+
+impl Directive for TwiceDirective {
+    fn exec(&self, ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+        let text: Cow<'static, str> = self.0.resolve_as(ctx)?;
+        Ok(Cow::Owned(text.repeat(2)))
+    }
+
+    fn arguments(&self) -> Vec<&Argument> {
+        vec![&self.0]
+    }
+
+    fn nested_arguments_mut(&mut self) -> Vec<&mut Argument> {
+        vec![&mut self.0]
+    }
+}
+
+/// The default syntax, plus `{twice 'text'}` and `{@}`.
+struct ExtendedParser;
+
+impl Parser for ExtendedParser {
+    fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+        match tokens {
+            [Token::Ident("twice"), Token::Literal(text)] => Some(Box::new(TwiceDirective(
+                Argument::literal(Cow::Owned(text.to_string())),
+            ))),
+            [Token::Unknown('@')] => Some(Box::new(LiteralDirective(Cow::Borrowed("@")))),
+            _ => DefaultParser::parse(tokens),
+        }
+    }
+}
+
+/// Only plain variables.
+struct VariablesOnlyParser;
+
+impl Parser for VariablesOnlyParser {
+    fn parse(tokens: &[Token]) -> Option<Box<dyn Directive>> {
+        match tokens {
+            [Token::Ident(name)] => Some(Box::new(ReplaceDirective(Argument::variable(
+                Cow::Owned(name.to_string()),
+            )))),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_custom_parser_extends_default_syntax() {
+    let template = CBTemplate::compile_with_parser::<ExtendedParser>(
+        "{user}{@}{domain:trunc(4)} {twice 'ha'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("user", Value::static_str("ada"));
+    ctx.insert("domain", Value::static_str("example.com"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ada@exa… haha");
+}
+
+#[test]
+fn test_custom_parser_compiles_nested_literals_with_itself() {
+    let template =
+        CBTemplate::compile_with_parser::<ExtendedParser>("{twice '{name}{@}'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ada@ada@");
+    assert_eq!(template.variables(), vec!["name"]);
+}
+
+#[test]
+fn test_custom_directive_renders_with_options_through_context_stack() {
+    let template = CBTemplate::compile_with_parser::<ExtendedParser>("{twice '{n} '}").unwrap();
+    let mut base = Context::new();
+    base.insert("n", Value::Float(1.5));
+    let ctx = ContextStack::new(&base);
+    let options = FormatOptions {
+        number_format: NumberFormat::EUROPEAN,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        template.format_with_options(&ctx, &options).unwrap(),
+        "1,5 1,5 "
+    );
+}
+
+#[test]
+fn test_restricted_parser_rejects_other_syntax() {
+    let template = CBTemplate::compile_with_parser::<VariablesOnlyParser>("Hi {name}!").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    assert_eq!(template.format(&ctx).unwrap(), "Hi Ada!");
+
+    let result = CBTemplate::compile_with_parser::<VariablesOnlyParser>("{{ok}} {'*':3}");
+    assert!(matches!(
+        result,
+        Err(TemplateError::DirectiveParsing(ref contents)) if contents == "'*':3"
+    ));
+}
+
+#[test]
+fn test_custom_parser_with_runtime_delimiters_and_options() {
+    let template = Template::<'<', '>'>::compile_with_parser_and_options::<ExtendedParser>(
+        "<twice 'x'><@>",
+        &CompileOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(template.format(&Context::new()).unwrap(), "xx@");
+}
+
+// ============================================
+// Builder Tests
+// ============================================
+
+fn var(name: &'static str) -> Argument {
+    Argument::variable(Cow::Borrowed(name))
+}
+
+fn lit(text: &'static str) -> Argument {
+    Argument::literal(Cow::Borrowed(text))
+}
+
+#[test]
+fn test_builder_matches_parser() {
+    let source = "Hi {name}! {vip ? 'Welcome back, {name}' : 'Hello'} \
+                  {[tier](gold:Gold {name})(*:Basic)} {'-':3} {bio | trunc(10) | >12}";
+
+    let built: CBTemplate = TemplateBuilder::new()
+        .literal("Hi ")
+        .var("name")
+        .literal("! ")
+        .conditional(
+            var("vip"),
+            TemplateBuilder::new().literal("Welcome back, ").var("name"),
+            Some(lit("Hello")),
+        )
+        .literal(" ")
+        .switch(
+            var("tier"),
+            vec![
+                SwitchCase {
+                    label: CaseLabel::Value(Cow::Borrowed("gold")),
+                    body: TemplateBuilder::new().literal("Gold ").var("name").into(),
+                },
+                SwitchCase {
+                    label: CaseLabel::Default,
+                    body: TemplateBuilder::new().literal("Basic").into(),
+                },
+            ],
+        )
+        .literal(" ")
+        .repeat(lit("-"), lit("3"))
+        .literal(" ")
+        .filter(
+            var("bio"),
+            vec![
+                Box::new(TruncateFilter {
+                    width: 10,
+                    ellipsis: Cow::Borrowed(TruncateFilter::DEFAULT_ELLIPSIS),
+                }),
+                Box::new(AlignFilter {
+                    align: Alignment::Right,
+                    width: 12,
+                    fill: ' ',
+                }),
+            ],
+        )
+        .build();
+
+    let parsed = CBTemplate::compile(source).unwrap();
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("vip", Value::Bool(true));
+    ctx.insert("tier", Value::static_str("gold"));
+    ctx.insert("bio", Value::static_str("Mathematician and writer"));
+
+    assert_eq!(built.format(&ctx).unwrap(), parsed.format(&ctx).unwrap());
+    assert_eq!(built.variables(), parsed.variables());
+
+    assert_eq!(built.debug_tree(), parsed.debug_tree());
+    assert_eq!(built, parsed);
+}
+
+#[test]
+fn test_builder_does_not_parse_literals() {
+    let template: CBTemplate = TemplateBuilder::new()
+        .literal("{name} is ")
+        .var("name")
+        .conditional(var("on"), lit(" {on}"), None)
+        .build();
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("on", Value::Bool(true));
+
+    assert_eq!(template.format(&ctx).unwrap(), "{name} is Ada {on}");
+    assert_eq!(template.variables(), vec!["name", "on"]);
+}
+
+#[test]
+fn test_builder_merges_consecutive_literals() {
+    let template: CBTemplate = TemplateBuilder::new()
+        .literal("a")
+        .literal("b")
+        .var("x")
+        .literal("c")
+        .build();
+
+    let kinds: Vec<_> = template.iter().map(|segment| segment.kind()).collect();
+
+    assert_eq!(
+        kinds,
+        vec![SegmentKind::Text, SegmentKind::Directive, SegmentKind::Text]
+    );
+    assert_eq!(template.estimated_len(), 3 + 8);
+}
+
+// ============================================
+// Equality Tests
+// ============================================
+
+#[test]
+fn test_equality_ignores_directive_whitespace() {
+    let a = CBTemplate::compile("Hi { name }, {x>1 ? 'a' : 'b'} {bio : trunc( 5 )}").unwrap();
+    let b = CBTemplate::compile("Hi {name}, {x > 1 ? 'a' : 'b'} {bio:trunc(5)}").unwrap();
+
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_equality_compares_structure() {
+    let base = CBTemplate::compile("Hi {name}! {n:trunc(5)} {[t](a:A {name})(*:B)}").unwrap();
+
+    for other in [
+        "Hi  {name}! {n:trunc(5)} {[t](a:A {name})(*:B)}",
+        "Hi {nom}! {n:trunc(5)} {[t](a:A {name})(*:B)}",
+        "Hi {name}! {n:trunc(6)} {[t](a:A {name})(*:B)}",
+        "Hi {name}! {n:trunc(5) | >5} {[t](a:A {name})(*:B)}",
+        "Hi {name}! {n:trunc(5)} {[t](a:A {nom})(*:B)}",
+        "Hi {name}! {n:trunc(5)} {[t](b:A {name})(*:B)}",
+        "Hi {name}! {n:trunc(5)} {[t](a*:A {name})(*:B)}",
+        "Hi {'name'}! {n:trunc(5)} {[t](a:A {name})(*:B)}",
+    ] {
+        assert_ne!(base, CBTemplate::compile(other).unwrap(), "{}", other);
+    }
+}
+
+#[test]
+fn test_equality_compares_runtime_delimiters() {
+    let braces = CBTemplate::compile("Hi {name}").unwrap();
+    let dollars =
+        CBTemplate::with_delimiters("Hi ${name}", Delimiters::new("${", "}").unwrap()).unwrap();
+
+    assert_ne!(braces, dollars);
+    assert_eq!(
+        dollars,
+        CBTemplate::with_delimiters("Hi ${ name }", Delimiters::new("${", "}").unwrap()).unwrap()
+    );
+}
+
+#[test]
+fn test_equal_templates_hash_equally() {
+    use std::collections::HashMap;
+
+    let mut cache = HashMap::new();
+    cache.insert(
+        CBTemplate::compile("{a == 1 ? 'one {b}' : 'other'}").unwrap(),
+        1,
+    );
+    cache.insert(
+        CBTemplate::compile("{ a == 1 ? 'one {b}' : 'other' }").unwrap(),
+        2,
+    );
+    cache.insert(
+        CBTemplate::compile("{a == 1 ? 'one {c}' : 'other'}").unwrap(),
+        3,
+    );
+
+    assert_eq!(cache.len(), 2);
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_equality_compares_regex_patterns() {
+    let a = CBTemplate::compile("{s =~ '^a+$' ? 'y'}").unwrap();
+
+    assert_eq!(a, CBTemplate::compile("{ s =~ '^a+$' ? 'y' }").unwrap());
+    assert_ne!(a, CBTemplate::compile("{s =~ '^a*$' ? 'y'}").unwrap());
+}
+
+#[test]
+fn test_diff_equal_templates_is_empty() {
+    let a = CBTemplate::compile("Hi { name }! {n > 1 ? 'many' : 'one'}").unwrap();
+    let b = CBTemplate::compile("Hi {name}! {n>1 ? 'many' : 'one'}").unwrap();
+
+    assert!(a.diff(&b).is_empty());
+    assert!(a.diff(&a).is_empty());
+}
+
+#[test]
+fn test_diff_added_and_removed_segments() {
+    let old = CBTemplate::compile("Hi {name}!").unwrap();
+    let new = CBTemplate::compile("Hi {title}{name}!").unwrap();
+
+    assert_eq!(
+        old.diff(&new),
+        vec![TemplateChange::Added {
+            index: 1,
+            span: 3..10
+        }]
+    );
+    assert_eq!(
+        new.diff(&old),
+        vec![TemplateChange::Removed {
+            index: 1,
+            span: 3..10
+        }]
+    );
+}
+
+#[test]
+fn test_diff_pairs_replacements_as_modified() {
+    let old = CBTemplate::compile("{a} and {b}").unwrap();
+    let new = CBTemplate::compile("{a} or {b:upper}{c}").unwrap();
+
+    assert_eq!(
+        old.diff(&new),
+        vec![
+            TemplateChange::Modified {
+                old_index: 1,
+                old_span: 3..8,
+                new_index: 1,
+                new_span: 3..7,
+            },
+            TemplateChange::Modified {
+                old_index: 2,
+                old_span: 8..11,
+                new_index: 2,
+                new_span: 7..16,
+            },
+            TemplateChange::Added {
+                index: 3,
+                span: 16..19
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_diff_keeps_common_segments_in_the_middle() {
+    let old = CBTemplate::compile("{a}-{b}-{c}").unwrap();
+    let new = CBTemplate::compile("{x}-{b}-{c}{d}").unwrap();
+
+    let changes = old.diff(&new);
+    assert_eq!(changes.len(), 2);
+    assert!(matches!(
+        changes[0],
+        TemplateChange::Modified {
+            old_index: 0,
+            new_index: 0,
+            ..
+        }
+    ));
+    assert!(matches!(changes[1], TemplateChange::Added { index: 5, .. }));
+}
+
+// ============================================
+// Template Inspection Tests
+// ============================================
+
+#[test]
+fn test_variables_first_seen_order() {
+    let template = CBTemplate::compile("{b} {a} {b} {c:<5}").unwrap();
+    assert_eq!(template.variables(), vec!["b", "a", "c"]);
+}
+
+#[test]
+fn test_variables_in_conditionals_and_repeats() {
+    let template = CBTemplate::compile(
+        "{score > threshold ? pass_msg : 'fail'} {!active ? 'x' : 'y'} {pattern:count} {'-':3}",
+    )
+    .unwrap();
+
+    assert_eq!(
+        template.variables(),
+        vec![
+            "score",
+            "threshold",
+            "pass_msg",
+            "active",
+            "pattern",
+            "count"
+        ]
+    );
+}
+
+#[test]
+fn test_variables_literal_only_template() {
+    let template = CBTemplate::compile("Just {'text'} and {{braces}}").unwrap();
+    assert!(template.variables().is_empty());
+}
+
+#[test]
+fn test_filters_used() {
+    let template = CBTemplate::compile(
+        "{name | trunc(10) | >12} {bio:squish} {vip ? '{title:sh}' : '{name:trunc(3)}'} {[tier](gold:{id:hex})(*:{id:pad_end(4)})}",
+    )
+    .unwrap();
+
+    assert_eq!(
+        template.filters_used(),
+        vec!["trunc", "align", "squish", "sh", "hex"]
+    );
+
+    let template = CBTemplate::compile("{name} {a > b ? 'yes'}").unwrap();
+    assert!(template.filters_used().is_empty());
+}
+
+#[test]
+fn test_functions_used() {
+    let template =
+        CBTemplate::compile("{min(max(score, 0), 100)} {abs(delta) > 10 ? 'big'} {max(a, b)}")
+            .unwrap();
+    assert_eq!(template.functions_used(), vec!["min", "max", "abs"]);
+
+    // Calls inside sub-templates count too
+    let template = CBTemplate::compile("{vip ? '{abs(x)}'} {max}").unwrap();
+    assert_eq!(template.functions_used(), vec!["abs"]);
+}
+
+#[test]
+fn test_unused_keys_sorted() {
+    let template = CBTemplate::compile("{name} {vip ? 'hi {nick}'} {[tier](a:x)}").unwrap();
+
+    let mut ctx = Context::new();
+    for key in ["tier", "zeta", "name", "nick", "alpha", "vip"] {
+        ctx.insert(key, Value::Null);
+    }
+
+    assert_eq!(template.unused_keys(&ctx), vec!["alpha", "zeta"]);
+}
+
+#[test]
+fn test_unused_keys_owned_context() {
+    let template = CBTemplate::compile("{a}").unwrap();
+
+    let mut ctx = OwnedContext::new();
+    ctx.insert("a".to_string(), Value::Int(1));
+    assert!(template.unused_keys(&ctx).is_empty());
+
+    ctx.insert("b".to_string(), Value::Int(2));
+    assert_eq!(template.unused_keys(&ctx), vec!["b"]);
+}
+
+#[test]
+fn test_affected_by_ignores_unrelated_keys() {
+    let template = CBTemplate::compile("{user}: {online ? '{count} new' : 'away'}").unwrap();
+
+    let mut old = Context::new();
+    old.insert("user", Value::static_str("ada"));
+    old.insert("online", Value::Bool(false));
+    old.insert("count", Value::Int(3));
+    old.insert("clock", Value::Int(100));
+
+    let mut new = old.clone();
+    assert!(!template.affected_by(&old, &new));
+
+    new.insert("clock", Value::Int(101));
+    new.insert("extra", Value::Bool(true));
+    assert!(!template.affected_by(&old, &new));
+
+    // Unchanged values in a different map still match
+    let owned: OwnedContext = new
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+    assert!(!template.affected_by(&old, &owned));
+}
+
+#[test]
+fn test_affected_by_referenced_keys() {
+    let template = CBTemplate::compile("{user}: {online ? '{count} new' : 'away'}").unwrap();
+
+    let mut old = Context::new();
+    old.insert("user", Value::static_str("ada"));
+    old.insert("online", Value::Bool(false));
+    old.insert("count", Value::Int(3));
+
+    // A key in a branch counts even while the branch isn't taken
+    let mut new = old.clone();
+    new.insert("count", Value::Int(4));
+    assert!(template.affected_by(&old, &new));
+
+    // Equal numbers of another type render differently
+    let mut new = old.clone();
+    new.insert("count", Value::Float(3.0));
+    assert!(template.affected_by(&old, &new));
+
+    let mut new = old.clone();
+    new.remove("user");
+    assert!(template.affected_by(&old, &new));
+    assert!(template.affected_by(&new, &old));
+
+    let mut new = old.clone();
+    new.insert("user", Value::static_str("bob"));
+    assert!(template.affected_by(&old, &new));
+}
+
+#[test]
+fn test_affected_by_with_includes() {
+    let template = CBTemplate::compile("{>header} {body}").unwrap();
+    let ctx = Context::new();
+
+    // Partials may read any variable
+    assert!(template.affected_by(&ctx, &ctx));
+    assert!(
+        !CBTemplate::compile("{body}")
+            .unwrap()
+            .affected_by(&ctx, &ctx)
+    );
+}
+
+#[test]
+fn test_estimated_len_is_exact_for_literals_and_literal_repeats() {
+    let template = CBTemplate::compile("Hello {{world}} {'ab':3} {'x'}").unwrap();
+    let output = template.format(&Context::new()).unwrap();
+
+    assert_eq!(template.estimated_len(), output.len());
+}
+
+#[test]
+fn test_estimated_len_for_context_values() {
+    let template = CBTemplate::compile("{name}").unwrap();
+    assert!(template.estimated_len() > 0);
+
+    // The longer branch is assumed
+    let template = CBTemplate::compile("{ok ? 'yes' : 'no, not at all'}").unwrap();
+    assert_eq!(template.estimated_len(), "no, not at all".len());
+
+    let template = CBTemplate::compile("").unwrap();
+    assert_eq!(template.estimated_len(), 0);
+}
+
+#[test]
+fn test_iter_segments_cover_source() {
+    let source = "Hi {name}, {{ok}} {vip ? 'Welcome {user}!' : 'Hello'}";
+    let template = CBTemplate::compile(source).unwrap();
+
+    let kinds: Vec<_> = template.iter().map(|segment| segment.kind()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            SegmentKind::Text,
+            SegmentKind::Directive,
+            SegmentKind::Text,
+            SegmentKind::Directive,
+        ]
+    );
+
+    let rebuilt: String = template.iter().map(|s| &source[s.span()]).collect();
+    assert_eq!(rebuilt, source);
+
+    let directives: Vec<_> = template.iter().filter_map(|s| s.source()).collect();
+    assert_eq!(
+        directives,
+        vec!["{name}", "{vip ? 'Welcome {user}!' : 'Hello'}"]
+    );
+}
+
+#[test]
+fn test_iter_segment_variables() {
+    let template = CBTemplate::compile("{a} and {x > y ? x : 'none'}").unwrap();
+    let variables: Vec<_> = (&template).into_iter().map(|s| s.variables()).collect();
+
+    assert_eq!(variables, vec![vec!["a"], vec![], vec!["x", "y"]]);
+}
+
+#[test]
+fn test_iter_multibyte_spans() {
+    let source = "héllo {näme}!";
+    let template = CBTemplate::compile(source).unwrap();
+    let spans: Vec<_> = template.iter().map(|s| s.span()).collect();
+
+    assert_eq!(spans, vec![0..7, 7..14, 14..15]);
+    assert_eq!(template.iter().len(), 3);
+}
+
+#[test]
+fn test_debug_tree_all_directives() {
+    let template = CBTemplate::compile_with_options(
+        "{{{name:*^10}{'-':n}{a >= 3 ? 'x'}{[t](a:A {b:#hex})(*:Z)}{!ok ? y : 'no'}{}",
+        &empty_directives(EmptyPolicy::Empty),
+    )
+    .unwrap();
+
+    let expected = r#"Template
+  Literal "{"
+  Filter
+    value: name
+    filter: *^10
+  Repeat
+    pattern: "-"
+    count: n
+  Conditional
+    if: a >= "3"
+    then: "x"
+  Switch
+    on: t
+    case "a": Template
+      Literal "A "
+      Filter
+        value: b
+        filter: #hex
+    case *: "Z"
+  Conditional
+    if: !ok
+    then: y
+    else: "no"
+  Empty
+"#;
+
+    assert_eq!(template.debug_tree(), expected);
+}
+
+#[test]
+fn test_debug_tree_empty_template() {
+    let template = CBTemplate::compile("").unwrap();
+    assert_eq!(template.debug_tree(), "Template\n");
+}
+
+#[test]
+fn test_to_source_round_trips() {
+    let sources = [
+        "",
+        "plain text",
+        "Hi {name}!",
+        "{{ {{name}} }} \\{ \\} \\\\ a\\b \\",
+        "{name}}{x} and } lone {y}",
+        "{} {`content-type`} {_x} {'quoted'} {'*':3} {char:n}",
+        "{name:<10} {bio:trunc(80)} {code:slice(-2)} {n:#HEX} {d:+05} {x:ceil(2)}",
+        "{total:currency(EUR, parens)} {r:percent(1)} {on:bool(Yes, No)} {q:urlenc(form)}",
+        "{name | trunc(20, '..') | >24} {path:replace(\\\\, /)} {s:len | reverse} {x:*^9}",
+        "{age >= 18 ? 'adult' : 'minor'} {vip ? 'dear {name}'} {c ? 1 : 0}",
+        "{a ? x : b ? 'y {z}' : c ? {d} : 'e'}",
+        "{(a || b) && !c ? yes : no} {a || (b || c) ? x} {(a && b) && c ? y}",
+        "{!(a == b) ? x} {!!x ? y} {!max(a, b) ? z} {!(a + b) ? w} {!defined(x) ? v}",
+        "{age in 13..19 ? teen} {x in -1.5..max(a, 2) ? y} {-5 < x ? neg}",
+        "{defined(coupon) ? yes} {is_null(x) ? none} {(a ?? b) ? c}",
+        "{min(max(score, 0), 100)} {first + ' ' + last} {nick ?? name ?? 'anon'}",
+        "{abs(delta):05} {1.5 + 1} {1 + a ?? 'x' ? d} {'7':len}",
+        "{[tier](gold:Welcome {name}, (gold) member)(silver:Hi \\(x)(*:Hello)}",
+        "{[score](>=90:A)(< -5:F)} {[level](ERR*:error)} {[x|'n a']('n a':-)(x y*:+)}",
+        "{[t](a:x \\\\)(b:\\\\\\\\{y})(c:\\{ {z:len} \\})}",
+        "{[tier](gold:2)(*:0) + bonus} {[tier](gold:2)(*:0) >= 1 ? member}",
+        "{price !! 'it\\'s {bad}'} {nick | 'anonymous'} {a | {b} | 'c'} {x | 'f' !! 'm'}",
+        "{c ? '{a ? \\'{b}\\' : \\'x\\'}' : 'z'} {vip ? {nick} : {name}}",
+        "{>header} {>'page head'} {@col(30)} {@region:head}x{@end:head}{@region:a}{@end}",
+        "{x:bogus(1)} {% if admin %}",
+    ];
+
+    let options = empty_directives(EmptyPolicy::Empty);
+
+    for source in sources {
+        let template = CBTemplate::compile_with_options(source, &options).unwrap();
+        let written = template
+            .to_source()
+            .unwrap_or_else(|| panic!("{:?} can't be written back", source));
+
+        assert_eq!(
+            CBTemplate::compile_with_options(&written, &options).unwrap(),
+            template,
+            "{}",
+            written
+        );
+        assert_eq!(template.to_source().as_ref(), Some(&written));
+    }
+}
+
+#[test]
+fn test_to_source_is_canonical() {
+    let template =
+        CBTemplate::compile("{ vip?\"dear {name}\" :there } {{x}} {a>=18&&( b||!c )?1}").unwrap();
+
+    assert_eq!(
+        template.to_source().unwrap(),
+        "{vip ? 'dear {name}' : there} \\{x\\} {a >= 18 && (b || !c) ? '1'}"
+    );
+}
+
+#[test]
+fn test_to_source_with_other_delimiters() {
+    let template = SquareTemplate::compile("[a ? 'x [b]' : 'c'] \\] [[").unwrap();
+    let written = template.to_source().unwrap();
+
+    assert_eq!(written, "[a ? 'x [b]' : 'c'] \\] \\[");
+    assert_eq!(SquareTemplate::compile(&written).unwrap(), template);
+
+    let delimiters = Delimiters::new("<%", "%>").unwrap();
+    let template = CBTemplate::with_delimiters("<%<% <% name %> %>", delimiters.clone()).unwrap();
+    let written = template.to_source().unwrap();
+
+    assert_eq!(written, "\\<% <%name%> \\%>");
+    assert_eq!(
+        CBTemplate::with_delimiters(&written, delimiters).unwrap(),
+        template
+    );
+}
+
+#[test]
+fn test_reserialize_round_trips_between_delimiters() {
+    let template =
+        CBTemplate::compile("Hi {name}, {vip ? 'dear {name}' : 'you'} <b> {{x}}").unwrap();
+
+    let angled = template.reserialize::<'<', '>'>().unwrap();
+    assert_eq!(
+        angled,
+        "Hi <name>, <vip ? 'dear <name>' : 'you'> \\<b\\> {x}"
+    );
+
+    let parsed = Template::<'<', '>'>::compile(&angled).unwrap();
+    let back = parsed.reserialize::<'{', '}'>().unwrap();
+    assert_eq!(back, template.to_source().unwrap());
+    assert_eq!(CBTemplate::compile(&back).unwrap(), template);
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("vip", Value::Bool(true));
+    assert_eq!(parsed.format(&ctx).unwrap(), template.format(&ctx).unwrap());
+}
+
+#[test]
+fn test_reserialize_rejects_invalid_delimiters() {
+    let template = CBTemplate::compile("Hi {name}").unwrap();
+
+    assert_eq!(template.reserialize::<'a', 'b'>(), None);
+    assert_eq!(template.reserialize::<'{', '}'>(), template.to_source());
+}
+
+#[test]
+fn test_to_source_without_source_syntax() {
+    struct Shout;
+
+    impl Directive for Shout {
+        fn exec(&self, _ctx: &RenderContext<'_>) -> Result<Cow<'static, str>, DirectiveError> {
+            Ok(Cow::Borrowed("!"))
+        }
+    }
+
+    let template: CBTemplate = TemplateBuilder::new()
+        .literal("Hi ")
+        .directive(Shout)
+        .build();
+    assert_eq!(template.to_source(), None);
+
+    // Nested unknown directives keep no source text to write back
+    let template = CBTemplate::compile("{ok ? {x:bogus(1)}}").unwrap();
+    assert_eq!(template.to_source(), None);
+}
+
+#[test]
+fn test_conditions_include_nested_ones() {
+    let template = CBTemplate::compile(
+        "{a ? 'x' : b ? 'y' : 'z'} {[t](1:{c ? 'w'})(*:-)} {d ? '{e > 1 ? \"v\"}'}",
+    )
+    .unwrap();
+
+    let conditions: Vec<_> = template
+        .conditions()
+        .into_iter()
+        .map(|condition| match condition {
+            Argument::Variable(name) => name.to_string(),
+            Argument::Expression(_) => "expression".to_string(),
+            other => panic!("unexpected condition {other:?}"),
+        })
+        .collect();
+    assert_eq!(conditions, vec!["a", "b", "c", "d", "expression"]);
+}
+
+#[test]
+fn test_const_conditions() {
+    let template = CBTemplate::compile(
+        "{1 < 2 ? 'a'} {x > 1 ? 'b'} {!'' ? 'c'} {'0' ? 'd'} {x ? '{2 == 3 ? \"e\"}'} \
+         {9223372036854775807 + 1 > 0 ? 'f'}",
+    )
+    .unwrap();
+
+    let values: Vec<_> = template
+        .const_conditions()
+        .iter()
+        .map(|condition| condition.value)
+        .collect();
+    assert_eq!(values, vec![true, true, false, false]);
+    assert_eq!(
+        template.const_conditions()[2].condition,
+        Argument::literal(Cow::Borrowed("0"))
+    );
+
+    assert!(
+        CBTemplate::compile("{vip ? 'dear'}")
+            .unwrap()
+            .const_conditions()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_metrics_count_directives_by_kind() {
+    let template = CBTemplate::compile_with_options(
+        "{name} {bio:trunc(5)} {'-':3} {vip ? 'a' : 'b'} {[t](x:X)(*:Y)} {>header} \
+         {nick | 'anon'} {price !! 'required'} {}",
+        &empty_directives(EmptyPolicy::Empty),
+    )
+    .unwrap();
+    let metrics = template.metrics();
+
+    assert_eq!(metrics.directives, 9);
+    assert_eq!(
+        (metrics.replacements, metrics.filters, metrics.repeats),
+        (1, 1, 1)
+    );
+    assert_eq!((metrics.conditionals, metrics.switches), (1, 1));
+    assert_eq!((metrics.includes, metrics.fallbacks), (1, 1));
+    assert_eq!((metrics.error_messages, metrics.other), (1, 1));
+    assert_eq!(metrics.unknown, 0);
+    assert_eq!(metrics.variables, 6);
+}
+
+#[test]
+fn test_metrics_nesting_depth() {
+    assert_eq!(CBTemplate::compile("plain").unwrap().metrics().max_depth, 0);
+    assert_eq!(
+        CBTemplate::compile("{a} {b}").unwrap().metrics().max_depth,
+        1
+    );
+
+    let template = CBTemplate::compile("{a ? 'x {b ? \"y {c}\" : \"z\"}' : 'w'} {d}").unwrap();
+    let metrics = template.metrics();
+
+    assert_eq!(metrics.max_depth, 3);
+    assert_eq!(metrics.directives, 4);
+    assert_eq!(metrics.conditionals, 2);
+    assert_eq!(metrics.variables, 4);
+}
+
+#[test]
+fn test_metrics_max_output_len() {
+    let template = CBTemplate::compile("Hi {'ab':1000}{sep:repeat(3)}!").unwrap();
+    assert_eq!(template.metrics().max_output_len, Some(3 + 2000 + 24 + 1));
+
+    let template = CBTemplate::compile("{ok ? 'short' : 'much longer'}").unwrap();
+    assert_eq!(template.metrics().max_output_len, Some(11));
+
+    let template = CBTemplate::compile("{'-':n}").unwrap();
+    assert_eq!(template.metrics().max_output_len, None);
+}
+
+#[test]
+fn test_metrics_flag_huge_repeats_without_rendering() {
+    let template = CBTemplate::compile("{'x':1000000000}").unwrap();
+    let metrics = template.metrics();
+
+    assert_eq!(metrics.repeats, 1);
+    assert!(metrics.max_output_len.unwrap() > FormatOptions::DEFAULT_MAX_OUTPUT_LEN);
+}
+
+#[test]
+fn test_metrics_count_unknown_directives() {
+    let metrics = CBTemplate::compile("{name:bogus(1)} {x}")
+        .unwrap()
+        .metrics();
+
+    assert_eq!(metrics.unknown, 1);
+    assert_eq!(metrics.directives, 2);
+}
+
+#[test]
+fn test_metrics_count_nodes() {
+    assert_eq!(CBTemplate::compile("").unwrap().metrics().nodes, 0);
+    assert_eq!(CBTemplate::compile("plain").unwrap().metrics().nodes, 1);
+
+    // The escape merges into the text before the conditional, whose branch
+    // holds two nodes of its own
+    let metrics = CBTemplate::compile("a {{ {ok ? 'Hi {name}'} b")
+        .unwrap()
+        .metrics();
+    assert_eq!(metrics.nodes, 5);
+    assert_eq!(metrics.directives, 2);
+}
+
+#[test]
+fn test_compile_stats_count_tokens_and_nesting() {
+    let source = "{greeting} {vip ? 'dear {name}' : 'there'}!";
+    let (template, stats) =
+        CBTemplate::compile_with_stats(source, &CompileOptions::default()).unwrap();
+
+    // The branch is compiled again as a sub-template of its own
+    let lexed = ["greeting", "vip ? 'dear {name}' : 'there'", "name"];
+    let tokens: usize = lexed.iter().map(|s| TemplateLexer::new(s).count()).sum();
+
+    assert_eq!(stats.directives, 3);
+    assert_eq!(stats.tokens, tokens);
+    assert_eq!(stats.tokens, 7);
+    assert_eq!(stats.max_depth, 2);
+    assert_eq!(stats.max_depth, template.metrics().max_depth);
+    assert_eq!(stats.bytes_scanned, source.len() + "dear {name}".len());
+    assert_eq!(template, CBTemplate::compile(source).unwrap());
+}
+
+#[test]
+fn test_compile_stats_token_buffer_growths() {
+    let options = CompileOptions::default();
+
+    // Later directives with fewer tokens reuse the buffer, which holds
+    // them inline with the `smallvec` feature
+    let (_, stats) = CBTemplate::compile_with_stats("{a + b + c} {x} {y}", &options).unwrap();
+    assert_eq!(
+        stats.token_buffer_growths,
+        usize::from(!cfg!(feature = "smallvec"))
+    );
+
+    let long = "{a + b + c + d + e + f + g + h + i} {x}";
+    let (_, stats) = CBTemplate::compile_with_stats(long, &options).unwrap();
+    assert_eq!((stats.tokens, stats.token_buffer_growths), (18, 1));
+
+    let (_, stats) = CBTemplate::compile_with_stats("plain text", &options).unwrap();
+    assert_eq!(
+        stats,
+        CompileStats {
+            bytes_scanned: 10,
+            ..Default::default()
+        }
+    );
+
+    assert!(matches!(
+        CBTemplate::compile_with_stats("{a", &options),
+        Err(TemplateError::MissingDelimiter { .. })
+    ));
+}
+
+// ============================================
+// Quoted Identifier Tests
+// ============================================
+
+#[test]
+fn test_quoted_identifiers_with_operator_characters() {
+    let template =
+        CBTemplate::compile("{`content-type`} v{`app:version`} by {`full name`}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("content-type", Value::static_str("text/html"));
+    ctx.insert("app:version", Value::static_str("1.2"));
+    ctx.insert("full name", Value::static_str("Ada Lovelace"));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "text/html v1.2 by Ada Lovelace"
+    );
+    assert_eq!(
+        template.variables(),
+        vec!["content-type", "app:version", "full name"]
+    );
+}
+
+#[test]
+fn test_quoted_identifiers_in_expressions_and_filters() {
+    let template = CBTemplate::compile(
+        "{`x-retries` > 2 ? 'many' : 'few'} {`user:name`:trunc(3)} {`user:name` | >5} \
+         {`a-b` | 'none'} {`-`:`n-times`}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x-retries", Value::Int(3));
+    ctx.insert("user:name", Value::static_str("Ada"));
+    ctx.insert("-", Value::static_str("ab"));
+    ctx.insert("n-times", Value::Int(2));
+
+    assert_eq!(template.format(&ctx).unwrap(), "many Ada   Ada none abab");
+}
+
+#[test]
+fn test_quoted_identifier_is_a_variable_not_a_literal() {
+    let template = CBTemplate::compile("{`name`} {'name'} {\"name\"}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "Ada name name");
+    assert_eq!(
+        template,
+        CBTemplate::compile("{name} {'name'} {\"name\"}").unwrap()
+    );
+}
+
+#[test]
+fn test_quoted_identifier_tokens() {
+    let source = "{`content-type` == 'json'}";
+    let tokens = CBTemplate::tokenize(source);
+
+    assert_eq!(tokens[0].token, Token::Ident("content-type"));
+    assert_eq!(&source[tokens[0].span.clone()], "`content-type`");
+    assert_eq!(tokens[1].token, Token::Equals);
+}
+
+#[test]
+fn test_unclosed_quoted_identifier_is_unknown() {
+    let template = CBTemplate::compile("{`content-type}").unwrap();
+
+    assert!(matches!(
+        template.format(&Context::new()),
+        Err(DirectiveError::UnknownDirective { .. })
+    ));
+}
+
+// ============================================
+// Tokenizer Tests
+// ============================================
+
+#[test]
+fn test_tokenize_spans_point_into_source() {
+    let source = "Hi {name:>10}, {count > 1 ? 'items' : 'item'}";
+    let tokens = CBTemplate::tokenize(source);
+
+    let kinds: Vec<_> = tokens.iter().map(|t| t.token.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            Token::Ident("name"),
+            Token::Colon,
+            Token::GreaterThan,
+            Token::Int("10"),
+            Token::Ident("count"),
+            Token::GreaterThan,
+            Token::Int("1"),
+            Token::Question,
+            Token::Literal(Cow::Borrowed("items")),
+            Token::Colon,
+            Token::Literal(Cow::Borrowed("item")),
+        ]
+    );
+
+    let texts: Vec<_> = tokens.iter().map(|t| &source[t.span.clone()]).collect();
+    assert_eq!(
+        texts,
+        vec![
+            "name", ":", ">", "10", "count", ">", "1", "?", "'items'", ":", "'item'"
+        ]
+    );
+}
+
+#[test]
+fn test_tokenize_skips_text_and_escapes() {
+    let tokens = CBTemplate::tokenize("{{x}} \\{y\\} }} {z}");
+
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].token, Token::Ident("z"));
+    assert_eq!(tokens[0].span, 16..17);
+}
+
+#[test]
+fn test_tokenize_nested_directives_and_case_bodies() {
+    let source = "{[tier](gold:Hi {name})(*:-)} {nick | {name}}";
+    let tokens = CBTemplate::tokenize(source);
+
+    let body = tokens
+        .iter()
+        .find(|t| matches!(t.token, Token::Text(_)))
+        .unwrap();
+    assert_eq!(&source[body.span.clone()], "Hi {name}");
+    assert!(tokens.iter().any(|t| t.token == Token::LCurly));
+    assert_eq!(tokens.last().unwrap().token, Token::RCurly);
+}
+
+#[test]
+fn test_tokenize_unclosed_directive_and_literal() {
+    let tokens = CBTemplate::tokenize("ok {name ? 'unterminated");
+
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[2].span, 11..24);
+    assert!(CBTemplate::tokenize("plain text").is_empty());
+}
+
+#[test]
+fn test_lexer_reads_multibyte_chars_whole() {
+    let tokens: Vec<_> = TemplateLexer::new(r"'bï\'g\n日本' ü 'é'")
+        .spanned()
+        .collect();
+
+    assert_eq!(tokens[0].token, Token::Literal(Cow::Borrowed("bï'g\n日本")));
+    assert_eq!(tokens[1].token, Token::Unknown('ü'));
+    assert_eq!(tokens[1].span, 17..19);
+    assert_eq!(tokens[2].token, Token::Literal(Cow::Borrowed("é")));
+
+    let template = CBTemplate::compile(r"{'«\'ï\\»'}").unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), r"«'ï\»");
+}
+
+#[test]
+fn test_tokenize_other_delimiters() {
+    let tokens = Template::<'«', '»'>::tokenize("é «a» «b:3»");
+
+    let texts: Vec<_> = tokens.iter().map(|t| t.token.clone()).collect();
+    assert_eq!(
+        texts,
+        vec![
+            Token::Ident("a"),
+            Token::Ident("b"),
+            Token::Colon,
+            Token::Int("3")
+        ]
+    );
+    assert_eq!(tokens[0].span, 5..6);
+}
+
+#[test]
+fn test_tokens_takes_first_tokens_of_large_input() {
+    let source = format!(
+        "{{first}} {{{{ {{second:3}} {}",
+        "{name:trunc(20)} ".repeat(200_000)
+    );
+
+    let first: Vec<_> = CBTemplate::tokens(&source).take(4).collect();
+    let tokens: Vec<_> = first.iter().map(|t| t.token.clone()).collect();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Ident("first"),
+            Token::Ident("second"),
+            Token::Colon,
+            Token::Int("3")
+        ]
+    );
+    assert_eq!(first[1].span, 12..18);
+}
+
+#[test]
+fn test_tokens_matches_tokenize() {
+    let source = "a {x ? '{y}' : z} \\{ }} {[t](a:{b})(*:c)} {unclosed 'str";
+
+    let mut tokens = CBTemplate::tokens(source);
+    let lazy: Vec<_> = tokens.by_ref().collect();
+    assert_eq!(lazy, CBTemplate::tokenize(source));
+    assert_eq!(tokens.next(), None);
+
+    assert_eq!(CBTemplate::tokens("no directives {{ here }}").next(), None);
+}
+
+#[test]
+fn test_lexer_spanned_matches_iteration() {
+    let input = "  first + ' ' + last ";
+    let plain: Vec<_> = TemplateLexer::new(input).collect();
+    let spanned: Vec<_> = TemplateLexer::new(input).spanned().collect();
+
+    assert_eq!(
+        spanned.iter().map(|t| t.token.clone()).collect::<Vec<_>>(),
+        plain
+    );
+    assert_eq!(spanned[0].span, 2..7);
+    assert_eq!(&input[spanned[2].span.clone()], "' '");
+}
+
+// ============================================
+// Highlight Tests
+// ============================================
+
+/// The source text and kind of every highlighted span.
+fn highlights(source: &str) -> Vec<(&str, HighlightKind)> {
+    CBTemplate::highlight(source)
+        .into_iter()
+        .map(|h| (&source[h.span], h.kind))
+        .collect()
+}
+
+#[test]
+fn test_highlight_classifies_directives_and_text() {
+    use HighlightKind::*;
+
+    assert_eq!(
+        highlights("Hi {count > 1 ? 'items' : 'item'}!"),
+        vec![
+            ("Hi ", Text),
+            ("{", Open),
+            ("count", Ident),
+            (">", Operator),
+            ("1", Number),
+            ("?", Operator),
+            ("'items'", String),
+            (":", Punctuation),
+            ("'item'", String),
+            ("}", Close),
+            ("!", Text),
+        ]
+    );
+}
+
+#[test]
+fn test_highlight_escapes_and_stray_delimiters() {
+    use HighlightKind::*;
+
+    assert_eq!(
+        highlights("{{a}} \\{b\\} } {c}"),
+        vec![
+            ("{{", Escape),
+            ("a", Text),
+            ("}}", Escape),
+            (" ", Text),
+            ("\\{", Escape),
+            ("b", Text),
+            ("\\}", Escape),
+            (" } ", Text),
+            ("{", Open),
+            ("c", Ident),
+            ("}", Close),
+        ]
+    );
+}
+
+#[test]
+fn test_highlight_spans_cover_source_in_order() {
+    let source = "«x» {[tier](gold:Hi {name})(*:-)} {nick | {name}} {@col(9)}{s:>4}";
+    let spans = CBTemplate::highlight(source);
+
+    for pair in spans.windows(2) {
+        assert!(pair[0].span.end <= pair[1].span.start);
+    }
+
+    // Only whitespace inside directives is left out
+    let mut covered = vec![false; source.len()];
+    for h in &spans {
+        covered[h.span.clone()].fill(true);
+    }
+    for (i, byte) in source.bytes().enumerate() {
+        assert!(covered[i] || byte == b' ', "byte {i} not covered");
+    }
+
+    let kinds: Vec<_> = highlights(source).into_iter().map(|(_, k)| k).collect();
+    assert!(kinds.contains(&HighlightKind::CaseBody));
+    assert!(!kinds.contains(&HighlightKind::Error));
+}
+
+#[test]
+fn test_highlight_marks_errors() {
+    use HighlightKind::*;
+
+    // A directive that doesn't parse keeps its tokens
+    assert_eq!(
+        highlights("{a b} ok"),
+        vec![
+            ("{", Error),
+            ("a", Ident),
+            ("b", Ident),
+            ("}", Error),
+            (" ok", Text),
+        ]
+    );
+
+    // Unknown chars of a directive that doesn't parse
+    let spans = highlights("{a # b}");
+    assert!(spans.contains(&("#", Error)));
+
+    // An unclosed directive with an unterminated string
+    assert_eq!(
+        highlights("x {a ? 'yes"),
+        vec![
+            ("x ", Text),
+            ("{", Error),
+            ("a", Ident),
+            ("?", Operator),
+            ("'yes", Error),
+        ]
+    );
+}
+
+#[test]
+fn test_highlight_strings_with_escaped_quotes() {
+    assert!(highlights(r"{'it\'s'}").contains(&(r"'it\'s'", HighlightKind::String)));
+    assert!(highlights(r"{'a\\'}").contains(&(r"'a\\'", HighlightKind::String)));
+    assert!(highlights(r"{'a\'").contains(&(r"'a\'", HighlightKind::Error)));
+}
+
+#[test]
+fn test_highlight_other_delimiters() {
+    use HighlightKind::*;
+
+    assert_eq!(
+        ParenTemplate::highlight("a (b) ((c"),
+        vec![
+            figura::Highlight {
+                kind: Text,
+                span: 0..2
+            },
+            figura::Highlight {
+                kind: Open,
+                span: 2..3
+            },
+            figura::Highlight {
+                kind: Ident,
+                span: 3..4
+            },
+            figura::Highlight {
+                kind: Close,
+                span: 4..5
+            },
+            figura::Highlight {
+                kind: Text,
+                span: 5..6
+            },
+            figura::Highlight {
+                kind: Escape,
+                span: 6..8
+            },
+            figura::Highlight {
+                kind: Text,
+                span: 8..9
+            },
+        ]
+    );
+}
+
+// ============================================
+// Float Tolerance Tests
+// ============================================
+
+#[test]
+fn test_float_equality_within_default_tolerance() {
+    let template = CBTemplate::compile(
+        "{sum == 0.3 ? 'eq' : 'ne'} {sum != 0.3 ? 'ne' : 'eq'} {sum == third ? 'eq' : 'ne'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sum", Value::Float(0.1 + 0.2));
+    ctx.insert("third", Value::Float(0.3));
+
+    assert_eq!(template.format(&ctx).unwrap(), "eq eq eq");
+}
+
+#[test]
+fn test_float_equality_of_literals_and_sums() {
+    let template = CBTemplate::compile(
+        "{0.1 + 0.2 == 0.3 ? 'eq' : 'ne'} {0.30000000000000004 == 0.3 ? 'eq' : 'ne'} \
+         {x + 0.2 == 0.3 ? 'eq' : 'ne'} {0.3 != x + 0.2 ? 'ne' : 'eq'}",
+    )
+    .unwrap();
+    let ctx = Context::from([("x", Value::Float(0.1))]);
+
+    assert_eq!(template.format(&ctx).unwrap(), "eq eq eq eq");
+
+    let options = FormatOptions {
+        float_tolerance: FloatTolerance::Exact,
+        ..Default::default()
+    };
+    assert_eq!(
+        template.format_with_options(&ctx, &options).unwrap(),
+        "ne ne ne ne"
+    );
+}
+
+#[test]
+fn test_float_equality_of_calls_and_items() {
+    let template = CBTemplate::compile(
+        "{min(f, 1) == 0.3 ? 'eq' : 'ne'} {items[0] == 0.3 ? 'eq' : 'ne'} \
+         {items[0] in [0.1, 0.3] ? 'in' : 'out'} {items[1] == 2 ? 'eq' : 'ne'}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("f", Value::Float(0.1 + 0.2));
+    ctx.insert("items", vec![Value::Float(0.1 + 0.2), Value::Int(2)].into());
+
+    assert_eq!(template.format(&ctx).unwrap(), "eq eq in eq");
+}
+
+#[test]
+fn test_float_equality_exact_opt_in() {
+    let template = CBTemplate::compile("{sum == 0.3 ? 'eq' : 'ne'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sum", Value::Float(0.1 + 0.2));
+
+    let options = FormatOptions {
+        float_tolerance: FloatTolerance::Exact,
+        ..Default::default()
+    };
+
+    assert_eq!(template.format_with_options(&ctx, &options).unwrap(), "ne");
+}
+
+#[test]
+fn test_float_equality_absolute_tolerance() {
+    let template = CBTemplate::compile("{price == 10 ? 'ten' : 'other'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("price", Value::Float(10.004));
+
+    let options = FormatOptions {
+        float_tolerance: FloatTolerance::Absolute(0.01),
+        ..Default::default()
+    };
+
+    assert_eq!(template.format_with_options(&ctx, &options).unwrap(), "ten");
+    assert_eq!(template.format(&ctx).unwrap(), "other");
+}
+
+#[test]
+fn test_float_ordering_stays_exact() {
+    let template = CBTemplate::compile("{sum > 0.3 ? 'gt' : 'le'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sum", Value::Float(0.1 + 0.2));
+
+    assert_eq!(template.format(&ctx).unwrap(), "gt");
+}
+
+#[test]
+fn test_float_tolerance_does_not_affect_other_types() {
+    let template = CBTemplate::compile("{code == 7 ? 'eq' : 'ne'} {n == 7 ? 'eq' : 'ne'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("code", Value::static_str("007"));
+    ctx.insert("n", Value::Int(7));
+
+    assert_eq!(template.format(&ctx).unwrap(), "ne eq");
+}
+
+#[test]
+fn test_float_tolerance_edge_cases() {
+    let tolerance = FloatTolerance::default();
+
+    assert!(!tolerance.equal(f64::NAN, f64::NAN));
+    assert!(tolerance.equal(f64::INFINITY, f64::INFINITY));
+    assert!(!tolerance.equal(f64::INFINITY, f64::MAX));
+    assert!(!tolerance.equal(1e-20, 0.0));
+    assert!(FloatTolerance::Absolute(1e-12).equal(1e-20, 0.0));
+}
+
+// ============================================
+// Number Format Tests
+// ============================================
+
+fn with_numbers(number_format: NumberFormat) -> FormatOptions {
+    FormatOptions {
+        number_format,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_number_format_us_and_european() {
+    let template = CBTemplate::compile("{price} {price:>8} {price:+} {'€' + price} {n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("price", Value::Float(1234.5));
+    ctx.insert("n", Value::Int(1234567));
+
+    let us = template
+        .format_with_options(&ctx, &with_numbers(NumberFormat::US))
+        .unwrap();
+    let eu = template
+        .format_with_options(&ctx, &with_numbers(NumberFormat::EUROPEAN))
+        .unwrap();
+
+    assert_eq!(us, "1234.5   1234.5 +1234.5 €1234.5 1234567");
+    assert_eq!(eu, "1234,5   1234,5 +1234,5 €1234,5 1234567");
+    assert_eq!(template.format(&ctx).unwrap(), us);
+}
+
+#[test]
+fn test_number_format_currency_separators() {
+    let template =
+        CBTemplate::compile("{a:currency(EUR)} {a:currency(USD)} {b:currency(JPY)}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Float(1234567.891));
+    ctx.insert("b", Value::Int(1500));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(NumberFormat::US))
+            .unwrap(),
+        "1,234,567.89 € $1,234,567.89 ¥1,500"
+    );
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(NumberFormat::EUROPEAN))
+            .unwrap(),
+        "1.234.567,89 € $1.234.567,89 ¥1.500"
+    );
+
+    let swiss = NumberFormat {
+        decimal_sep: '.',
+        group_sep: '\'',
+        group_size: 3,
+        max_fraction_digits: None,
+    };
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(swiss))
+            .unwrap(),
+        "1'234'567.89 € $1'234'567.89 ¥1'500"
+    );
+}
+
+#[test]
+fn test_number_format_does_not_affect_literals() {
+    let template =
+        CBTemplate::compile("{price > 2.5 ? 'high' : 'low'} {'2.5'} {price == 3.5 ? '=' : '≠'}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("price", Value::Float(3.5));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(NumberFormat::EUROPEAN))
+            .unwrap(),
+        "high 2.5 ="
+    );
+}
+
+#[test]
+fn test_number_format_in_branches_and_repeated_variables() {
+    let template = CBTemplate::compile("{x} {x} {on ? x : 'none'} {on ? 'x = {x}'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Float(0.25));
+    ctx.insert("on", Value::Bool(true));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(NumberFormat::EUROPEAN))
+            .unwrap(),
+        "0,25 0,25 0,25 x = 0,25"
+    );
+}
+
+#[test]
+fn test_number_format_keeps_float_expressions_unfolded() {
+    let mut template = CBTemplate::compile("{1.5 + 1} {1 + 1}").unwrap();
+    template.optimize();
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &with_numbers(NumberFormat::EUROPEAN))
+            .unwrap(),
+        "2,5 2"
+    );
+}
+
+#[test]
+fn test_number_format_grouping() {
+    assert_eq!(NumberFormat::US.group("1234567"), "1,234,567");
+    assert_eq!(NumberFormat::US.group("123"), "123");
+
+    let ungrouped = NumberFormat {
+        group_size: 0,
+        ..NumberFormat::US
+    };
+    assert_eq!(ungrouped.group("1234567"), "1234567");
+
+    let myriads = NumberFormat {
+        group_size: 4,
+        ..NumberFormat::US
+    };
+    assert_eq!(myriads.group("123456789"), "1,2345,6789");
+}
+
+#[test]
+fn test_float_rendering_is_shortest_round_trip() {
+    for (value, expected) in [
+        (8.50, "8.5"),
+        (108.0, "108.0"),
+        (-0.0, "-0.0"),
+        (0.1 + 0.2, "0.30000000000000004"),
+        (1.0 / 3.0, "0.3333333333333333"),
+        (0.000025, "0.000025"),
+        (1e-7, "1e-7"),
+        (1e21, "1e+21"),
+        (f64::INFINITY, "inf"),
+        (f64::NAN, "NaN"),
+    ] {
+        assert_eq!(Value::Float(value).to_string(), expected);
+        assert_eq!(
+            NumberFormat::US.render(&Value::Float(value)).unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_number_format_max_fraction_digits() {
+    let cents = NumberFormat {
+        max_fraction_digits: Some(2),
+        ..NumberFormat::US
+    };
+
+    for (value, expected) in [
+        (0.1 + 0.2, "0.3"),
+        (8.50, "8.5"),
+        (19.999, "20.0"),
+        (108.0, "108.0"),
+        (2.675, "2.67"),
+        (-1.005, "-1.0"),
+        (0.125, "0.12"),
+        (1e-7, "0.0"),
+        (1e21, "1e+21"),
+        (f64::INFINITY, "inf"),
+    ] {
+        assert_eq!(
+            cents.render(&Value::Float(value)).unwrap(),
+            expected,
+            "{value}"
+        );
+    }
+
+    let whole = NumberFormat {
+        max_fraction_digits: Some(0),
+        ..NumberFormat::US
+    };
+    assert_eq!(whole.render(&Value::Float(2.75)).unwrap(), "3.0");
+    assert_eq!(whole.render(&Value::Float(4.0)).unwrap(), "4.0");
+    assert_eq!(whole.render(&Value::Int(7)).unwrap(), "7");
+}
+
+#[test]
+fn test_number_format_max_fraction_digits_in_templates() {
+    let template = CBTemplate::compile("{sum} [{sum:>6}] {'total: ' + sum} {n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sum", Value::Float(0.1 + 0.2));
+    ctx.insert("n", Value::Int(12));
+
+    let format = NumberFormat {
+        max_fraction_digits: Some(3),
+        ..NumberFormat::EUROPEAN
+    };
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_numbers(format))
+            .unwrap(),
+        "0,3 [   0,3] total: 0,3 12"
+    );
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "0.30000000000000004 [0.30000000000000004] total: 0.30000000000000004 12"
+    );
+}
+
+// ============================================
+// Missing Variable Policy Tests
+// ============================================
+
+fn with_missing(missing: MissingPolicy) -> FormatOptions {
+    FormatOptions {
+        missing,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_missing_policy_default_is_error() {
+    let template = CBTemplate::compile("Hello {name}!").unwrap();
+    let ctx = Context::new();
+
+    assert_eq!(FormatOptions::default().missing, MissingPolicy::Error);
+    assert!(matches!(
+        template.format_with_options(&ctx, &FormatOptions::default()),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_missing_policy_empty() {
+    let template = CBTemplate::compile("Hello {name}!").unwrap();
+    let ctx = Context::new();
+
+    let output = template
+        .format_with_options(&ctx, &with_missing(MissingPolicy::Empty))
+        .unwrap();
+    assert_eq!(output, "Hello !");
+}
+
+#[test]
+fn test_missing_policy_keep() {
+    let template = CBTemplate::compile("Hi {name}, {x > 5 ? 'big' : 'small'} {{ok}}").unwrap();
+    let ctx = Context::new();
+
+    let output = template
+        .format_with_options(&ctx, &with_missing(MissingPolicy::Keep))
+        .unwrap();
+    assert_eq!(output, "Hi {name}, {x > 5 ? 'big' : 'small'} {ok}");
+}
+
+#[test]
+fn test_missing_policy_keep_custom_delimiters() {
+    let template = Template::<'<', '>'>::compile("Hi <name>!").unwrap();
+    let ctx = Context::new();
+
+    let output = template
+        .format_with_options(&ctx, &with_missing(MissingPolicy::Keep))
+        .unwrap();
+    assert_eq!(output, "Hi <name>!");
+}
+
+#[test]
+fn test_missing_policy_placeholder() {
+    let template = CBTemplate::compile("{a} and {b} and {'-':n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("A"));
+
+    let output = template
+        .format_with_options(&ctx, &with_missing(MissingPolicy::Placeholder("?".into())))
+        .unwrap();
+    assert_eq!(output, "A and ? and ?");
+}
+
+#[test]
+fn test_missing_policy_replaces_partial_output_of_nested_missing_variables() {
+    let template = CBTemplate::compile("[{s ? 'pre {missing} post'}]{@col(12)}|").unwrap();
+    let ctx = Context::from([("s", Value::Bool(true))]);
+    let render = |missing| template.format_with_options(&ctx, &with_missing(missing));
+
+    assert_eq!(
+        render(MissingPolicy::Keep).unwrap(),
+        "[{s ? 'pre {missing} post'}]|"
+    );
+    assert_eq!(render(MissingPolicy::Empty).unwrap(), "[]          |");
+    assert_eq!(
+        render(MissingPolicy::Placeholder("?".into())).unwrap(),
+        "[?]         |"
+    );
+
+    let ctx = Context::from([
+        ("s", Value::Bool(true)),
+        ("missing", Value::static_str("x")),
+    ]);
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_missing(MissingPolicy::Empty))
+            .unwrap(),
+        "[pre x post]|"
+    );
+}
+
+#[test]
+fn test_missing_policy_keeps_type_errors() {
+    let template = CBTemplate::compile("{'-':n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::static_str("three"));
+
+    assert!(matches!(
+        template.format_with_options(&ctx, &with_missing(MissingPolicy::Empty)),
+        Err(DirectiveError::TypeError { .. })
+    ));
+}
+
+// ============================================
+// Unknown Directive Policy Tests
+// ============================================
+
+fn with_unknown(unknown: UnknownPolicy) -> FormatOptions {
+    FormatOptions {
+        unknown,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_unknown_policy_default_is_error() {
+    let template = CBTemplate::compile("Hi {% if admin %}{name}!").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    let err = template.format(&ctx).unwrap_err();
+    assert!(matches!(
+        err,
+        DirectiveError::UnknownDirective { ref directive } if directive == "{% if admin %}"
+    ));
+    assert_eq!(err.to_string(), "Unknown directive '{% if admin %}'");
+
+    let spanned = template.format_spanned(&ctx).unwrap_err();
+    assert_eq!(spanned.span, 3..17);
+}
+
+#[test]
+fn test_unknown_policy_keep_passes_source_through() {
+    let template =
+        CBTemplate::compile("Hi {% if admin %}{name}{name:bogus(1)}{%endif%} {{x}}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Keep))
+            .unwrap(),
+        "Hi {% if admin %}Ada{name:bogus(1)}{%endif%} {x}"
+    );
+}
+
+#[test]
+fn test_unknown_policy_keep_with_runtime_delimiters() {
+    let template =
+        CBTemplate::with_delimiters("${name} ${% raw %}", Delimiters::new("${", "}").unwrap())
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("ci"));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Keep))
+            .unwrap(),
+        "ci ${% raw %}"
+    );
+}
+
+#[test]
+fn test_unknown_policy_empty_drops_directive() {
+    let template = CBTemplate::compile_with_options(
+        "[{name:bogus(1)}][{}][{name}]",
+        &empty_directives(EmptyPolicy::Empty),
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+            .unwrap(),
+        "[][][Ada]"
+    );
+}
+
+#[test]
+fn test_empty_directive_is_not_unknown() {
+    let template =
+        CBTemplate::compile_with_options("[{}]", &empty_directives(EmptyPolicy::Empty)).unwrap();
+
+    assert_eq!(template.format(&Context::new()).unwrap(), "[]");
+}
+
+#[test]
+fn test_unknown_policy_applies_to_nested_templates() {
+    let template = CBTemplate::compile("{on ? 'a {x y} b'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("on", Value::Bool(true));
+
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::UnknownDirective { ref directive }) if directive == "{x y}"
+    ));
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Keep))
+            .unwrap(),
+        "a {x y} b"
+    );
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Empty))
+            .unwrap(),
+        "a  b"
+    );
+}
+
+#[test]
+fn test_unknown_directive_is_not_optimized_away() {
+    let mut template = CBTemplate::compile("{'-':2}{x y}").unwrap();
+    template.optimize();
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &with_unknown(UnknownPolicy::Keep))
+            .unwrap(),
+        "--{x y}"
+    );
+    assert!(template.debug_tree().contains("Unknown \"{x y}\""));
+}
+
+// ============================================
+// Empty Directive Tests
+// ============================================
+
+fn empty_directives(policy: EmptyPolicy) -> CompileOptions {
+    CompileOptions {
+        empty_directives: policy,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_empty_directives_fail_by_default() {
+    for (source, position) in [("{}", 0), ("a {   } b", 2), ("{\t\n}", 0), ("{x}{}", 3)] {
+        assert!(
+            matches!(
+                CBTemplate::compile(source),
+                Err(TemplateError::EmptyDirective { position: p }) if p == position
+            ),
+            "{:?}",
+            source
+        );
+    }
+
+    assert_eq!(
+        CompileOptions::default().empty_directives,
+        EmptyPolicy::Error
+    );
+}
+
+#[test]
+fn test_empty_directives_kept_as_text() {
+    let options = empty_directives(EmptyPolicy::Keep);
+    let template =
+        CBTemplate::compile_with_options("fn {name}() {} [{   }] {{}}", &options).unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("main"));
+
+    assert_eq!(template.format(&ctx).unwrap(), "fn main() {} [{   }] {}");
+    assert_eq!(template.metrics().directives, 1);
+
+    let template = SquareTemplate::compile_with_options("[] [ ]", &options).unwrap();
+    assert_eq!(template.format(&Context::new()).unwrap(), "[] [ ]");
+}
+
+#[test]
+fn test_empty_directives_render_nothing() {
+    let options = empty_directives(EmptyPolicy::Empty);
+    let template = CBTemplate::compile_with_options("[{}][{   }]", &options).unwrap();
+
+    assert_eq!(template.format(&Context::new()).unwrap(), "[][]");
+    assert_eq!(template.metrics().other, 2);
+}
+
+#[test]
+fn test_empty_directive_policy_applies_to_nested_templates() {
+    let source = "{on ? 'x {} y' : '{ }'}";
+    let mut ctx = Context::new();
+    ctx.insert("on", Value::Bool(true));
+
+    assert!(matches!(
+        CBTemplate::compile(source),
+        Err(TemplateError::EmptyDirective { position: 9 })
+    ));
+
+    let render = |policy| {
+        CBTemplate::compile_with_options(source, &empty_directives(policy))
+            .unwrap()
+            .format(&ctx)
+            .unwrap()
+    };
+    assert_eq!(render(EmptyPolicy::Keep), "x {} y");
+    assert_eq!(render(EmptyPolicy::Empty), "x  y");
+}
+
+#[test]
+fn test_empty_directives_are_reported_with_other_errors() {
+    let errors = CBTemplate::compile_all_errors("{} {name} {   } {(a ? b}").unwrap_err();
+
+    let positions: Vec<_> = errors
+        .iter()
+        .filter_map(|e| match e.error {
+            TemplateError::EmptyDirective { position } => Some(position),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(positions, vec![0, 10]);
+    assert_eq!(errors.len(), 3);
+}
+
+// ============================================
+// Block Trimming Tests
+// ============================================
+
+fn trimmed() -> FormatOptions {
+    FormatOptions {
+        trim_blocks: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_trim_blocks_removes_empty_block_lines() {
+    let template = CBTemplate::compile("server:\n  {tls ? 'tls: on'}\n  port: 80\n").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tls", Value::Bool(false));
+
+    assert_eq!(template.format(&ctx).unwrap(), "server:\n  \n  port: 80\n");
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "server:\n  port: 80\n"
+    );
+
+    ctx.insert("tls", Value::Bool(true));
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "server:\n  tls: on\n  port: 80\n"
+    );
+}
+
+#[test]
+fn test_trim_blocks_nested_conditionals_cascade() {
+    let template = CBTemplate::compile(
+        "a: 1\n{outer ? 'b:\n  {inner ? \"c: 2\"}\n  {deep ? \"d: 3\"}\n'}\n{last ? 'e: 4'}\nf: 5",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("outer", Value::Bool(true));
+    ctx.insert("inner", Value::Bool(false));
+    ctx.insert("deep", Value::Bool(false));
+    ctx.insert("last", Value::Bool(false));
+
+    assert_eq!(template.format(&ctx).unwrap(), "a: 1\nb:\n  \n  \n\n\nf: 5");
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "a: 1\nb:\n\nf: 5"
+    );
+
+    ctx.insert("outer", Value::Bool(false));
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "a: 1\nf: 5"
+    );
+
+    ctx.insert("outer", Value::Bool(true));
+    ctx.insert("deep", Value::Bool(true));
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "a: 1\nb:\n  d: 3\n\nf: 5"
+    );
+}
+
+#[test]
+fn test_trim_blocks_keeps_lines_with_other_content() {
+    let template = CBTemplate::compile("a {x ? 'y'}\n{x ? 'y'} b\n{x ? 'y'}{x ? 'y'}\nc").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Bool(false));
+
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "a \n b\n\nc"
+    );
+}
+
+#[test]
+fn test_trim_blocks_at_template_ends_and_crlf() {
+    let template = CBTemplate::compile("  {x ? 'y'}\r\nmiddle\r\n\t{x ? 'y'}  ").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Bool(false));
+
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "middle\r\n"
+    );
+}
+
+#[test]
+fn test_trim_blocks_crlf_in_branches_after_bom() {
+    let template = CBTemplate::compile(
+        "\u{feff}{tls ? 'tls: on'}\r\n{on ? 'a:\r\n  {tls ? 1}\r\nb: 2'}\r\nc: 3\r\n",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("tls", Value::Bool(false));
+    ctx.insert("on", Value::Bool(true));
+
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "a:\r\nb: 2\r\nc: 3\r\n"
+    );
+}
+
+#[test]
+fn test_strip_bom() {
+    let source = "\u{feff}Hi {name}";
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    let template = CBTemplate::compile(source).unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "Hi Ada");
+    // Spans still point into the source
+    let spans: Vec<_> = template.iter().map(|s| s.span()).collect();
+    assert_eq!(spans, vec![3..6, 6..12]);
+    assert_eq!(&source[spans[1].clone()], "{name}");
+
+    let options = CompileOptions {
+        strip_bom: false,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile_with_options(source, &options).unwrap();
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        source.replace("{name}", "Ada")
+    );
+
+    // Only a mark at the very start is stripped
+    let template = CBTemplate::compile("{ok ? '\u{feff}{name}'} \u{feff}").unwrap();
+    ctx.insert("ok", Value::Bool(true));
+    assert_eq!(template.format(&ctx).unwrap(), "\u{feff}Ada \u{feff}");
+
+    let delimiters = Delimiters::new("<%", "%>").unwrap();
+    let template = CBTemplate::with_delimiters("\u{feff}<%name%>", delimiters).unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "Ada");
+}
+
+#[test]
+fn test_compile_bytes() {
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+
+    let template = CBTemplate::compile_bytes("\u{feff}Grüße, {name}!".as_bytes()).unwrap();
+    assert_eq!(template.format(&ctx).unwrap(), "Grüße, Ada!");
+    assert_eq!(template, CBTemplate::compile("Grüße, {name}!").unwrap());
+}
+
+#[test]
+fn test_compile_bytes_reports_invalid_utf8_offset() {
+    // A stray continuation byte after the two-byte `ü`
+    let err = CBTemplate::compile_bytes(b"Gr\xc3\xbc\x80e {name}").unwrap_err();
+    assert!(matches!(err, TemplateError::InvalidUtf8 { offset: 4 }));
+    assert_eq!(err.to_string(), "Invalid UTF-8 at byte 4");
+
+    // Inside a directive, and a sequence cut short at the end
+    let err = CBTemplate::compile_bytes(b"Hi {na\xffme}").unwrap_err();
+    assert!(matches!(err, TemplateError::InvalidUtf8 { offset: 6 }));
+    let err = CBTemplate::compile_bytes(b"{name} \xe2\x82").unwrap_err();
+    assert!(matches!(err, TemplateError::InvalidUtf8 { offset: 7 }));
+}
+
+#[test]
+fn test_trim_blocks_with_missing_policy() {
+    let template = CBTemplate::compile("a\n    {missing}\nb").unwrap();
+    let options = FormatOptions {
+        missing: MissingPolicy::Empty,
+        ..trimmed()
+    };
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &options)
+            .unwrap(),
+        "a\nb"
+    );
+
+    let options = FormatOptions {
+        missing: MissingPolicy::Placeholder("?".into()),
+        ..trimmed()
+    };
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &options)
+            .unwrap(),
+        "a\n    ?\nb"
+    );
+}
+
+// ============================================
+// Empty Placeholder Tests
+// ============================================
+
+fn with_placeholder(placeholder: &str) -> FormatOptions {
+    FormatOptions {
+        empty_placeholder: Some(placeholder.into()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_empty_placeholder_replaces_null_and_empty_strings() {
+    let template = CBTemplate::compile("|{name}|{email}|{age}|").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::Null);
+    ctx.insert("email", Value::static_str(""));
+    ctx.insert("age", Value::Int(0));
+
+    assert_eq!(template.format(&ctx).unwrap(), "|||0|");
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_placeholder("N/A"))
+            .unwrap(),
+        "|N/A|N/A|0|"
+    );
+}
+
+#[test]
+fn test_empty_placeholder_leaves_other_values() {
+    let template = CBTemplate::compile("{name} {flag} {ratio} {' '}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Alice"));
+    ctx.insert("flag", Value::Bool(false));
+    ctx.insert("ratio", Value::Float(0.0));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_placeholder("—"))
+            .unwrap(),
+        "Alice false 0.0  "
+    );
+}
+
+#[test]
+fn test_empty_placeholder_applies_to_repeated_variables() {
+    let template = CBTemplate::compile("{a}-{a}-{b}-{b}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::owned_str(String::new()));
+    ctx.insert("b", Value::static_str("x"));
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_placeholder("—"))
+            .unwrap(),
+        "—-—-x-x"
+    );
+}
+
+#[test]
+fn test_empty_placeholder_does_not_affect_other_directives() {
+    let template = CBTemplate::compile("{name ? 'set' : 'unset'} {name:>3}|{''}|").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::Null);
+
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_placeholder("N/A"))
+            .unwrap(),
+        "unset    ||"
+    );
+
+    // A null variable is not missing, so it renders the placeholder rather
+    // than falling back
+    let template = CBTemplate::compile("{name | 'anon'}").unwrap();
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_placeholder("N/A"))
+            .unwrap(),
+        "N/A"
+    );
+}
+
+#[test]
+fn test_empty_placeholder_does_not_affect_missing_variables() {
+    let template = CBTemplate::compile("[{name}]").unwrap();
+    let options = FormatOptions {
+        missing: MissingPolicy::Empty,
+        ..with_placeholder("N/A")
+    };
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &options)
+            .unwrap(),
+        "[]"
+    );
+    assert!(
+        template
+            .format_with_options(&Context::new(), &with_placeholder("N/A"))
+            .is_err()
+    );
+}
+
+// ============================================
+// Newline Style Tests
+// ============================================
+
+fn with_newline(newline: NewlineStyle) -> FormatOptions {
+    FormatOptions {
+        newline,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_newline_styles_on_mixed_endings() {
+    let template = CBTemplate::compile("a\r\nb\nc\rd {note}\n").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("note", Value::static_str("x\r\ny\nz"));
+
+    let cases = [
+        (NewlineStyle::AsIs, "a\r\nb\nc\rd x\r\ny\nz\n"),
+        (NewlineStyle::Lf, "a\nb\nc\rd x\ny\nz\n"),
+        (NewlineStyle::CrLf, "a\r\nb\r\nc\rd x\r\ny\r\nz\r\n"),
+    ];
+
+    for (style, expected) in cases {
+        let output = template
+            .format_with_options(&ctx, &with_newline(style))
+            .unwrap();
+        assert_eq!(output, expected, "{style:?}");
+    }
+
+    assert_eq!(template.format(&ctx).unwrap(), "a\r\nb\nc\rd x\r\ny\nz\n");
+}
+
+#[test]
+fn test_newline_style_across_writes() {
+    // The `\r` ends the literal and the `\n` starts the value
+    let template = CBTemplate::compile("one\r{rest}\r").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("rest", Value::static_str("\ntwo"));
+
+    let output = template
+        .format_with_options(&ctx, &with_newline(NewlineStyle::Lf))
+        .unwrap();
+    assert_eq!(output, "one\ntwo\r");
+
+    let output = template
+        .format_with_options(&ctx, &with_newline(NewlineStyle::CrLf))
+        .unwrap();
+    assert_eq!(output, "one\r\ntwo\r");
+}
+
+#[test]
+fn test_newline_style_in_branches_and_filters() {
+    let template = CBTemplate::compile("{vip ? 'dear\n'}{name:>6}\r\n").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("vip", Value::Bool(true));
+    ctx.insert("name", Value::static_str("a\nb"));
+
+    let output = template
+        .format_with_options(&ctx, &with_newline(NewlineStyle::CrLf))
+        .unwrap();
+    assert_eq!(output, "dear\r\n   a\r\nb\r\n");
+
+    let mut out = Vec::new();
+    template
+        .format_to_with_options(&ctx, &mut out, &with_newline(NewlineStyle::Lf))
+        .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "dear\n   a\nb\n");
+}
+
+#[test]
+fn test_newline_style_counts_toward_output_limit() {
+    let template = CBTemplate::compile("a\nb").unwrap();
+    let options = FormatOptions {
+        max_output_len: 3,
+        ..with_newline(NewlineStyle::CrLf)
+    };
+
+    assert!(matches!(
+        template.format_with_options(&Context::new(), &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 3 })
+    ));
+}
+
+#[test]
+fn test_newline_style_in_render_value() {
+    let value = Value::static_str("a\r\nb");
+    let output = figura::render_value(&value, &[], &with_newline(NewlineStyle::Lf)).unwrap();
+    assert_eq!(output, "a\nb");
+}
+
+// ============================================
+// Schema Tests
+// ============================================
+
+fn with_schema(schema: Schema) -> FormatOptions {
+    FormatOptions {
+        schema,
+        ..Default::default()
+    }
+}
+
+fn price_context(price: f64) -> Context {
+    let mut ctx = Context::new();
+    ctx.insert("price", Value::Float(price));
+    ctx.insert("name", Value::static_str("Widget"));
+    ctx
+}
+
+#[test]
+fn test_schema_default_filters_apply() {
+    let template = CBTemplate::compile("{name}: {price}").unwrap();
+    let schema = Schema::new().field("price", "currency(USD)").unwrap();
+    let ctx = price_context(1234.5);
+
+    assert_eq!(template.format(&ctx).unwrap(), "Widget: 1234.5");
+    assert_eq!(
+        template
+            .format_with_options(&ctx, &with_schema(schema))
+            .unwrap(),
+        "Widget: $1,234.50"
+    );
+}
+
+#[test]
+fn test_schema_default_filter_chain() {
+    let template = CBTemplate::compile("[{name}] [{price}]").unwrap();
+    let schema = Schema::new()
+        .field("name", "reverse | >8")
+        .unwrap()
+        .field("price", "round(1)")
+        .unwrap();
+
+    assert_eq!(
+        template
+            .format_with_options(&price_context(2.345), &with_schema(schema))
+            .unwrap(),
+        "[  tegdiW] [2.3]"
+    );
+}
+
+#[test]
+fn test_inline_spec_overrides_schema() {
+    let template = CBTemplate::compile("{price} {price:round} {price:>8}|").unwrap();
+    let schema = Schema::new().field("price", "currency(USD)").unwrap();
+
+    assert_eq!(
+        template
+            .format_with_options(&price_context(9.75), &with_schema(schema))
+            .unwrap(),
+        "$9.75 10     9.75|"
+    );
+}
+
+#[test]
+fn test_schema_applies_in_branches_but_not_to_conditions() {
+    let template = CBTemplate::compile("{price > 100 ? 'Sale: {price}' : 'Only {price}'}").unwrap();
+    let schema = Schema::new().field("price", "currency(EUR)").unwrap();
+    let options = with_schema(schema);
+
+    assert_eq!(
+        template
+            .format_with_options(&price_context(150.0), &options)
+            .unwrap(),
+        "Sale: 150.00 €"
+    );
+    assert_eq!(
+        template
+            .format_with_options(&price_context(20.0), &options)
+            .unwrap(),
+        "Only 20.00 €"
+    );
+}
+
+#[test]
+fn test_schema_filter_errors_and_mismatches() {
+    let template = CBTemplate::compile("{name}").unwrap();
+    let schema = Schema::new().field("name", "currency(USD)").unwrap();
+    let ctx = price_context(1.0);
+
+    assert!(matches!(
+        template.format_with_options(&ctx, &with_schema(schema.clone())),
+        Err(DirectiveError::FilterTypeError { .. })
+    ));
+
+    let options = FormatOptions {
+        filter_mismatch: FilterMismatch::Skip,
+        ..with_schema(schema)
+    };
+    assert_eq!(
+        template.format_with_options(&ctx, &options).unwrap(),
+        "Widget"
+    );
+}
+
+#[test]
+fn test_schema_rejects_invalid_specs() {
+    assert!(matches!(
+        Schema::new().field("price", "3"),
+        Err(TemplateError::DirectiveParsing(_))
+    ));
+    assert!(Schema::new().field("price", "").is_err());
+    assert!(Schema::new().field("price", "frobnicate").is_err());
+
+    let schema = Schema::new()
+        .field("price", "round(2) | >8")
+        .unwrap()
+        .field_filters(
+            "name",
+            vec![Box::new(TruncateFilter {
+                width: 3,
+                ellipsis: Cow::Borrowed(TruncateFilter::DEFAULT_ELLIPSIS),
+            })],
+        );
+    assert_eq!(schema.get("price").map(<[_]>::len), Some(2));
+    assert_eq!(schema.get("name").map(<[_]>::len), Some(1));
+    assert!(schema.get("other").is_none());
+}
+
+// ============================================
+// Bool Keyword Tests
+// ============================================
+
+fn with_bool_keywords() -> FormatOptions {
+    FormatOptions {
+        bool_keywords: BoolKeywords::new()
+            .truthy(["yes", "on", "1"])
+            .falsy(["no", "off", "0"]),
+        ..Default::default()
+    }
+}
+
+fn render_condition(source: &str, active: &'static str, options: &FormatOptions) -> String {
+    let mut ctx = Context::new();
+    ctx.insert("active", Value::static_str(active));
+
+    CBTemplate::compile(source)
+        .unwrap()
+        .format_with_options(&ctx, options)
+        .unwrap()
+}
+
+#[test]
+fn test_bool_keywords_in_conditions() {
+    let source = "{active ? 'on' : 'off'}";
+    let options = with_bool_keywords();
+
+    assert_eq!(render_condition(source, "yes", &options), "on");
+    assert_eq!(render_condition(source, "off", &options), "off");
+    assert_eq!(render_condition(source, "0", &options), "off");
+    assert_eq!(render_condition(source, "1", &options), "on");
+}
+
+#[test]
+fn test_bool_keywords_ignore_case() {
+    let source = "{active ? 'on' : 'off'}";
+    let options = with_bool_keywords();
+
+    assert_eq!(render_condition(source, "YES", &options), "on");
+    assert_eq!(render_condition(source, "Off", &options), "off");
+}
+
+#[test]
+fn test_bool_keywords_default_to_truthiness() {
+    let source = "{active ? 'on' : 'off'}";
+    let options = FormatOptions::default();
+
+    // Without keywords, any non-empty string is true
+    assert_eq!(render_condition(source, "off", &options), "on");
+    assert_eq!(render_condition(source, "0", &options), "on");
+
+    // Strings that aren't keywords keep their truthiness
+    let options = with_bool_keywords();
+    assert_eq!(render_condition(source, "maybe", &options), "on");
+    assert_eq!(render_condition(source, "", &options), "off");
+}
+
+#[test]
+fn test_bool_keywords_in_logical_operators() {
+    let options = with_bool_keywords();
+
+    assert_eq!(
+        render_condition("{!active ? 'off'}", "off", &options),
+        "off"
+    );
+    assert_eq!(
+        render_condition("{active && active ? 'on' : 'off'}", "no", &options),
+        "off"
+    );
+    assert_eq!(
+        render_condition("{active || active ? 'on' : 'off'}", "0", &options),
+        "off"
+    );
+}
+
+#[test]
+fn test_bool_keywords_leave_literals_and_filters() {
+    let options = with_bool_keywords();
+
+    assert_eq!(
+        render_condition("{'off' ? 'on' : 'off'}", "", &options),
+        "on"
+    );
+    assert_eq!(
+        render_condition("{active:bool(Y, N)}", "off", &options),
+        "Y"
+    );
+}
+
+#[test]
+fn test_bool_keywords_lookup() {
+    let keywords = BoolKeywords::new().truthy(["yes"]).falsy(["no", "YES"]);
+
+    assert_eq!(keywords.get("Yes"), Some(true));
+    assert_eq!(keywords.get("NO"), Some(false));
+    assert_eq!(keywords.get("maybe"), None);
+    assert!(!keywords.is_empty());
+    assert!(BoolKeywords::default().is_empty());
+}
+
+// ============================================
+// Single Value Rendering Tests
+// ============================================
+
+/// Renders `value` with `figura::render_value` and with a template
+/// substituting it as `{value:spec}`, or `{value}` for an empty spec.
+fn render_both(value: Value, spec: &str, options: &FormatOptions) -> (String, String) {
+    let (schema, source) = match spec {
+        "" => (Schema::new(), "{value}".to_string()),
+        _ => (
+            Schema::new().field("value", spec).unwrap(),
+            format!("{{value:{}}}", spec),
+        ),
+    };
+    let filters = schema.get("value").unwrap_or_default();
+    let direct = figura::render_value(&value, filters, options).unwrap();
+
+    let mut ctx = Context::new();
+    ctx.insert("value", value);
+    let templated = CBTemplate::compile(&source)
+        .unwrap()
+        .format_with_options(&ctx, options)
+        .unwrap();
+
+    (direct, templated)
+}
+
+#[test]
+fn test_render_value_matches_templates() {
+    let european = FormatOptions {
+        number_format: NumberFormat::EUROPEAN,
+        ..Default::default()
+    };
+    let cases = [
+        (Value::static_str("Ada"), ""),
+        (Value::Int(42), ""),
+        (Value::Float(1234.5), ""),
+        (Value::Bool(true), ""),
+        (Value::static_str("Ada"), ">8"),
+        (Value::static_str("Lovelace"), "trunc(5) | *^9"),
+        (Value::Float(1234.5), "currency(USD)"),
+        (Value::Float(2.345), "round(2) | >8"),
+        (Value::Int(255), "hex"),
+        (Value::Int(-7), "05"),
+        (Value::Float(0.25), "percent"),
+        (Value::static_str("a b&c"), "urlenc"),
+        (Value::static_str("  x  y "), "squish | rstrip(y)"),
+        (Value::Bool(false), "bool(on, off)"),
+    ];
+
+    for options in [FormatOptions::default(), european] {
+        for (value, spec) in cases.clone() {
+            let (direct, templated) = render_both(value, spec, &options);
+            assert_eq!(direct, templated, "{}", spec);
+        }
+    }
+}
+
+#[test]
+fn test_render_value_follows_options() {
+    let options = FormatOptions {
+        empty_placeholder: Some("N/A".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        render_both(Value::Null, "", &options),
+        ("N/A".to_string(), "N/A".to_string())
+    );
+
+    // The schema can't apply to a value without a name
+    let options = FormatOptions {
+        schema: Schema::new().field("value", ">5").unwrap(),
+        ..Default::default()
+    };
+    assert_eq!(
+        figura::render_value(&Value::Int(1), &[], &options).unwrap(),
+        "1"
+    );
+
+    let options = FormatOptions {
+        filter_mismatch: FilterMismatch::Coerce,
+        ..Default::default()
+    };
+    let schema = Schema::new().field("value", "hex").unwrap();
+    assert_eq!(
+        figura::render_value(
+            &Value::static_str("255"),
+            schema.get("value").unwrap(),
+            &options
+        )
+        .unwrap(),
+        "ff"
+    );
+
+    let options = FormatOptions {
+        max_output_len: 4,
+        ..Default::default()
+    };
+    assert!(matches!(
+        figura::render_value(&Value::static_str("hello"), &[], &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 4 })
+    ));
+}
+
+#[test]
+fn test_render_value_errors() {
+    let options = FormatOptions::default();
+
+    assert!(matches!(
+        figura::render_value(&Value::from(vec![1u8, 2]), &[], &options),
+        Err(DirectiveError::TypeError { name, .. }) if name == "value"
+    ));
+
+    let schema = Schema::new().field("value", "hex").unwrap();
+    assert!(matches!(
+        figura::render_value(
+            &Value::static_str("x"),
+            schema.get("value").unwrap(),
+            &options
+        ),
+        Err(DirectiveError::FilterTypeError { .. })
+    ));
+}
+
+// ============================================
+// Batch Formatting Tests
+// ============================================
+
+fn batch_contexts() -> Vec<Context> {
+    (0..100)
+        .map(|i| {
+            let mut ctx = Context::new();
+            ctx.insert("name", Value::owned_str(format!("user{}", i)));
+            ctx.insert("n", Value::Int(i));
+            ctx
+        })
+        .collect()
+}
+
+#[test]
+fn test_format_all_matches_format() {
+    let template = CBTemplate::compile("Dear {name}, you are #{n}{n > 50 ? '!' : '.'}").unwrap();
+    let contexts = batch_contexts();
+
+    let results = template.format_all(&contexts);
+    assert_eq!(results.len(), contexts.len());
+
+    for (ctx, result) in contexts.iter().zip(results) {
+        assert_eq!(result.unwrap(), template.format(ctx).unwrap());
+    }
+}
+
+#[test]
+fn test_format_all_errors_are_per_context() {
+    let template = CBTemplate::compile("{name}").unwrap();
+    let mut contexts = batch_contexts();
+    contexts[1].remove("name");
+
+    let results = template.format_all(&contexts);
+    assert_eq!(results[0].as_deref().unwrap(), "user0");
+    assert!(matches!(results[1], Err(DirectiveError::NotFound { .. })));
+    assert_eq!(results[2].as_deref().unwrap(), "user2");
+}
+
+#[test]
+fn test_format_all_owned_contexts_and_empty_slice() {
+    let template = CBTemplate::compile("{a}").unwrap();
+
+    let mut owned = OwnedContext::new();
+    owned.insert("a".to_string(), Value::Int(1));
+    let results = template.format_all(&[owned]);
+    assert_eq!(results[0].as_deref().unwrap(), "1");
+
+    assert!(template.format_all::<Context>(&[]).is_empty());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_format_all_par_preserves_order() {
+    let template = CBTemplate::compile("{name}:{n}").unwrap();
+    let contexts = batch_contexts();
+
+    let sequential: Vec<String> = template
+        .format_all(&contexts)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    let parallel: Vec<String> = template
+        .format_all_par(&contexts)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+
+    assert_eq!(sequential, parallel);
+}
+
+#[test]
+fn test_shared_template_across_threads() {
+    let template = CBTemplate::compile("{name}: {n > 1 ? 'many' : 'one'}").unwrap();
+
+    let outputs: Vec<String> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|n| {
+                let template = &template;
+                scope.spawn(move || {
+                    let mut ctx = Context::new();
+                    ctx.insert("name", Value::static_str("worker"));
+                    ctx.insert("n", Value::Int(n));
+                    template.format(&ctx).unwrap()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert_eq!(
+        outputs,
+        ["worker: one", "worker: one", "worker: many", "worker: many"]
+    );
+
+    let shared = std::sync::Arc::new(template);
+    let handles: Vec<_> = (0..4)
+        .map(|n| {
+            let template = std::sync::Arc::clone(&shared);
+            std::thread::spawn(move || {
+                let mut ctx = OwnedContext::new();
+                ctx.insert("name".to_string(), Value::owned_str(format!("w{n}")));
+                ctx.insert("n".to_string(), Value::Int(n));
+                template.format(&ctx).unwrap()
+            })
+        })
+        .collect();
+    let outputs: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(outputs, ["w0: one", "w1: one", "w2: many", "w3: many"]);
+}
+
+// ============================================
+// Renderer Tests
+// ============================================
+
+#[test]
+fn test_renderer_reuses_buffer_across_contexts() {
+    let template = CBTemplate::compile("Hi {name}, you have {count} messages").unwrap();
+    let mut renderer = template.renderer(FormatOptions::default());
+
+    for (name, count) in [("Alice", 3), ("Bob", 12), ("Christopher", 0)] {
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::static_str(name));
+        ctx.insert("count", Value::Int(count));
+
+        assert_eq!(
+            renderer.render(&ctx).unwrap(),
+            template.format(&ctx).unwrap()
+        );
+    }
+
+    assert_eq!(
+        renderer.into_buffer(),
+        "Hi Christopher, you have 0 messages"
+    );
+}
+
+#[test]
+fn test_renderer_applies_options() {
+    let template = CBTemplate::compile("{greeting}, {name}!").unwrap();
+    let mut renderer = template.renderer(with_missing(MissingPolicy::Keep));
+    let mut ctx = Context::new();
+    ctx.insert("greeting", Value::static_str("Hello"));
+
+    assert_eq!(renderer.render(&ctx).unwrap(), "Hello, {name}!");
+    assert_eq!(renderer.options().missing, MissingPolicy::Keep);
+    assert!(std::ptr::eq(renderer.template(), &template));
+}
+
+#[test]
+fn test_renderer_recovers_after_errors() {
+    let template = CBTemplate::compile("[{a}{b}]").unwrap();
+    let mut renderer = template.renderer(FormatOptions::default());
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("x"));
+
+    assert!(matches!(
+        renderer.render(&ctx),
+        Err(DirectiveError::NotFound { .. })
+    ));
+
+    ctx.insert("b", Value::static_str("y"));
+    assert_eq!(renderer.render(&ctx).unwrap(), "[xy]");
+}
+
+#[test]
+fn test_renderer_with_registry() {
+    let template = CBTemplate::compile("{>header} {body}").unwrap();
+    let registry = registry();
+    let mut renderer = template
+        .renderer(FormatOptions::default())
+        .with_registry(&registry);
+    let mut ctx = Context::new();
+    ctx.insert("title", Value::static_str("News"));
+    ctx.insert("body", Value::static_str("Hello"));
+
+    assert_eq!(renderer.render(&ctx).unwrap(), "<h1>News</h1> Hello");
+    assert!(matches!(
+        template.renderer(FormatOptions::default()).render(&ctx),
+        Err(DirectiveError::PartialNotFound { .. })
+    ));
+}
+
+#[test]
+fn test_renderer_render_to_sink() {
+    let template = CBTemplate::compile("{n}-").unwrap();
+    let renderer = template.renderer(FormatOptions::default());
+    let mut out = String::new();
+
+    for n in 1..=3 {
+        let mut ctx = Context::new();
+        ctx.insert("n", Value::Int(n));
+        renderer.render_to(&ctx, &mut out).unwrap();
+    }
+
+    assert_eq!(out, "1-2-3-");
+    assert_eq!(renderer.into_buffer(), "");
+}
+
+// ============================================
+// Template Cache Tests
+// ============================================
+
+#[test]
+fn test_template_cache_hits_and_misses() {
+    let cache = TemplateCache::<'{', '}'>::new(4);
+    assert!(cache.is_empty());
+
+    let first = cache.get_or_parse("Hi {name}!").unwrap();
+    let again = cache.get_or_parse("Hi {name}!").unwrap();
+    assert!(Arc::ptr_eq(&first, &again));
+
+    let other = cache.get_or_parse("Bye {name}!").unwrap();
+    assert!(!Arc::ptr_eq(&first, &other));
+    assert_eq!(cache.len(), 2);
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ann"));
+    assert_eq!(again.format(&ctx).unwrap(), "Hi Ann!");
+    assert_eq!(other.format(&ctx).unwrap(), "Bye Ann!");
+}
+
+#[test]
+fn test_template_cache_evicts_least_recently_used() {
+    let cache = TemplateCache::<'{', '}'>::new(2);
+    let a = cache.get_or_parse("{a}").unwrap();
+    cache.get_or_parse("{b}").unwrap();
+
+    // Using `{a}` again leaves `{b}` as the least recently used
+    cache.get_or_parse("{a}").unwrap();
+    cache.get_or_parse("{c}").unwrap();
+
+    assert_eq!(cache.len(), 2);
+    assert!(cache.contains("{a}") && cache.contains("{c}"));
+    assert!(!cache.contains("{b}"));
+    assert!(Arc::ptr_eq(&a, &cache.get_or_parse("{a}").unwrap()));
+
+    // Evicted templates are compiled again, and stay usable meanwhile
+    let b = cache.get_or_parse("{b}").unwrap();
+    assert!(!cache.contains("{c}"));
+    assert_eq!(b.variables(), vec!["b"]);
+}
+
+#[test]
+fn test_template_cache_does_not_cache_errors() {
+    let cache = TemplateCache::<'{', '}'>::new(2);
+
+    assert!(matches!(
+        cache.get_or_parse("Hi {name"),
+        Err(TemplateError::MissingDelimiter { .. })
+    ));
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_template_cache_with_options_and_zero_capacity() {
+    let options = CompileOptions {
+        empty_directives: EmptyPolicy::Keep,
+        ..Default::default()
+    };
+    let cache = TemplateCache::<'{', '}'>::with_options(1, options);
+    let tmpl = cache.get_or_parse("fn main() {}").unwrap();
+    assert_eq!(tmpl.format(&Context::new()).unwrap(), "fn main() {}");
+
+    let cache = TemplateCache::<'{', '}'>::new(0);
+    let first = cache.get_or_parse("{a}").unwrap();
+    assert!(!Arc::ptr_eq(&first, &cache.get_or_parse("{a}").unwrap()));
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_template_cache_shared_between_threads() {
+    let cache = TemplateCache::<'{', '}'>::new(8);
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for name in ["{a}", "{b}", "{c}"] {
+                    cache.get_or_parse(name).unwrap();
+                }
+            });
+        }
+    });
+
+    assert_eq!(cache.len(), 3);
+    cache.clear();
+    assert!(cache.is_empty());
+}
+
+// ============================================
+// Total Template Tests
+// ============================================
+
+#[test]
+fn test_total_template_formats_without_result() {
+    let template = CBTemplate::compile("{{{name}}} is {age}, {'ok'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("age", Value::Int(36));
+
+    let total = template.total().unwrap();
+
+    assert_eq!(total.format(&ctx), "{Ada} is 36, ok");
+    assert_eq!(total.format(&ctx), template.format(&ctx).unwrap());
+}
+
+#[test]
+fn test_total_template_renders_missing_text_for_failing_values() {
+    let template = CBTemplate::compile("[{name}] [{sig}] [{z}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("sig", Value::Bytes(vec![1, 2]));
+    ctx.insert("z", Value::Null);
+
+    assert_eq!(template.total().unwrap().format(&ctx), "[] [] []");
+
+    let total = template.total().unwrap().with_missing("?");
+    assert_eq!(total.missing(), "?");
+    assert_eq!(total.format(&ctx), "[?] [?] []");
+}
+
+#[test]
+fn test_total_template_not_offered_for_fallible_directives() {
+    for source in [
+        "{name:trunc(3)}",
+        "{ok ? 'yes' : 'no'}",
+        "{'-':n}",
+        "{[tier](gold:G)(*:-)}",
+        "{>header}",
+        "{nick | 'anon'}",
+        "{name !! 'required'}",
+        "{% raw %}",
+    ] {
+        let template = CBTemplate::compile(source).unwrap();
+        assert!(template.total().is_none(), "{source}");
+    }
+}
+
+#[test]
+fn test_total_template_after_optimize() {
+    let mut template = CBTemplate::compile("{'-':3} {name}").unwrap();
+    assert!(template.total().is_none());
+
+    template.optimize();
+    let ctx = Context::from([("name", Value::static_str("Ada"))]);
+
+    assert_eq!(template.total().unwrap().format(&ctx), "--- Ada");
+}
+
+// ============================================
+// Chunk Tests
+// ============================================
+
+#[test]
+fn test_chunks_concatenate_to_format() {
+    let template =
+        CBTemplate::compile("{s} {i} [{z}] {'-':3} {i > 1 ? 'big {s}' : 'small'} {s:>6} {{x}}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("str"));
+    ctx.insert("i", Value::Int(42));
+    ctx.insert("z", Value::Null);
+
+    let chunks: Vec<_> = template.chunks(&ctx).collect::<Result<_, _>>().unwrap();
+
+    assert_eq!(chunks.concat(), template.format(&ctx).unwrap());
+    assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+}
+
+#[test]
+fn test_chunks_borrow_literals() {
+    let template = CBTemplate::compile("Hello {name}!").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("World"));
+
+    let chunks: Vec<_> = template.chunks(&ctx).map(Result::unwrap).collect();
+
+    assert_eq!(chunks, vec!["Hello ", "World", "!"]);
+    assert!(matches!(chunks[0], Cow::Borrowed(_)));
+    assert!(matches!(chunks[2], Cow::Borrowed(_)));
+}
+
+#[test]
+fn test_chunks_stop_after_error() {
+    let template = CBTemplate::compile("before {missing} after").unwrap();
+    let ctx = Context::new();
+    let mut chunks = template.chunks(&ctx);
+
+    assert_eq!(chunks.next().unwrap().unwrap(), "before ");
+    assert!(matches!(
+        chunks.next(),
+        Some(Err(DirectiveError::NotFound { .. }))
+    ));
+    assert!(chunks.next().is_none());
+}
+
+// ============================================
+// Repeated Variable Tests
+// ============================================
+
+#[test]
+fn test_repeated_variables_render_every_occurrence() {
+    let template =
+        CBTemplate::compile("{n} {s} {f} {b} [{null}] {n:>4} {n} {s} {f} {b} [{null}] {s:len}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(42));
+    ctx.insert("s", Value::static_str("hey"));
+    ctx.insert("f", Value::Float(1.5));
+    ctx.insert("b", Value::Bool(false));
+    ctx.insert("null", Value::Null);
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "42 hey 1.5 false []   42 42 hey 1.5 false [] 3"
+    );
+
+    let mut out = Vec::new();
+    template.format_to(&ctx, &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        template.format(&ctx).unwrap()
+    );
+}
+
+#[test]
+fn test_repeated_variables_respect_each_render() {
+    let template = CBTemplate::compile("{n}-{n}").unwrap();
+    let mut ctx = Context::new();
+
+    ctx.insert("n", Value::Int(1));
+    assert_eq!(template.format(&ctx).unwrap(), "1-1");
+
+    ctx.insert("n", Value::Int(2));
+    assert_eq!(template.format(&ctx).unwrap(), "2-2");
+}
+
+#[test]
+fn test_repeated_missing_and_invalid_variables() {
+    let template = CBTemplate::compile("{x}/{x}").unwrap();
+    let options = FormatOptions {
+        missing: MissingPolicy::Keep,
+        ..FormatOptions::default()
+    };
+
+    assert_eq!(
+        template
+            .format_with_options(&Context::new(), &options)
+            .unwrap(),
+        "{x}/{x}"
+    );
+
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::from(vec![1u8, 2]));
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::TypeError { .. })
+    ));
+}
+
+#[test]
+fn test_repeated_variables_in_conditions_and_branches() {
+    let template = CBTemplate::compile(
+        "{name}: {name ? 'Dear {name}, {title} {name}' : 'Hi'} {title}{name}{title} {name == 'Ada' ? name : title}",
+    )
+    .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("title", Value::Int(7));
+
+    assert_eq!(
+        template.format(&ctx).unwrap(),
+        "Ada: Dear Ada, 7 Ada 7Ada7 Ada"
+    );
+
+    ctx.insert("name", Value::static_str(""));
+    assert_eq!(template.format(&ctx).unwrap(), ": Hi 77 7");
+}
+
+// ============================================
+// Optimization Tests
+// ============================================
+
+#[test]
+fn test_optimize_matches_unoptimized_output() {
+    let sources = [
+        "{'=':5} {name} {1 < 2 ? 'yes' : 'no'}",
+        "{max(3, -7)} {abs(-2) + 1} {'a' + 'b'} {5 in 1..9 ? 'in' : 'out'}",
+        "{'hello':>8} {abs(-7):05} {'abc':reverse} {3 > 1 ? 'big {name}' : 'small'}",
+        "{['gold'](gold:G)(*:other)} {{literal}} {!0 ? 'not'} {0 ? 'x' : 1 ? 'y' : 'z'}",
+        "{name:>6} and {'-':count} {'' : 3}",
+    ];
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("count", Value::Int(4));
+
+    for source in sources {
+        let template = CBTemplate::compile(source).unwrap();
+        let mut optimized = CBTemplate::compile(source).unwrap();
+        optimized.optimize();
+
+        assert_eq!(
+            optimized.format(&ctx).unwrap(),
+            template.format(&ctx).unwrap(),
+            "{}",
+            source
+        );
+        assert!(optimized.iter().count() <= template.iter().count());
+    }
+}
+
+#[test]
+fn test_optimize_merges_folded_directives_and_text() {
+    let source = "a{'b'}c{{d{1 < 2 ? 'e'}";
+    let mut template = CBTemplate::compile(source).unwrap();
+    template.optimize();
+
+    let segments: Vec<_> = template.iter().collect();
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].kind(), SegmentKind::Text);
+    assert_eq!(segments[0].span(), 0..source.len());
+    assert_eq!(segments[0].directive().literal(), Some("abc{de"));
+}
+
+#[test]
+fn test_optimize_keeps_context_dependent_directives() {
+    let mut template = CBTemplate::compile("Hi {name}, {'-':n} {a > 1 ? x} {>header}!").unwrap();
+    template.optimize();
+
+    let kinds: Vec<_> = template.iter().map(|segment| segment.kind()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            SegmentKind::Text,
+            SegmentKind::Directive,
+            SegmentKind::Text,
+            SegmentKind::Directive,
+            SegmentKind::Text,
+            SegmentKind::Directive,
+            SegmentKind::Text,
+            SegmentKind::Directive,
+            SegmentKind::Text,
+        ]
+    );
+    assert_eq!(template.variables(), vec!["name", "n", "a", "x"]);
+}
+
+#[test]
+fn test_optimize_keeps_failing_and_large_directives() {
+    let mut template = CBTemplate::compile("{'abc':hex}").unwrap();
+    template.optimize();
+    assert_eq!(
+        template.iter().next().unwrap().kind(),
+        SegmentKind::Directive
+    );
+    assert!(matches!(
+        template.format(&Context::new()),
+        Err(DirectiveError::FilterTypeError { .. })
+    ));
+
+    let mut template = CBTemplate::compile("{'x':100000}").unwrap();
+    template.optimize();
+    assert_eq!(
+        template.iter().next().unwrap().kind(),
+        SegmentKind::Directive
+    );
+    assert_eq!(template.format(&Context::new()).unwrap().len(), 100000);
+}
+
+#[test]
+fn test_optimize_drops_empty_directives() {
+    let mut template = CBTemplate::compile("{name}{0 ? 'x'}{name}").unwrap();
+    template.optimize();
+
+    assert_eq!(template.iter().count(), 2);
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    assert_eq!(template.format(&ctx).unwrap(), "AdaAda");
+}
+
+#[test]
+fn test_literal_template_is_static() {
+    let template = CBTemplate::compile("Hello, {{world}}! {'-':3} {1 < 2 ? 'on' : 'off'}").unwrap();
+
+    assert!(template.is_static());
+    assert_eq!(template.render_static(), Some("Hello, {world}! --- on"));
+    assert_eq!(
+        template.format(&Context::new()).unwrap(),
+        template.render_static().unwrap()
+    );
+    assert_eq!(CBTemplate::compile("").unwrap().render_static(), Some(""));
+}
+
+#[test]
+fn test_text_only_template_follows_options() {
+    let template = CBTemplate::compile("one\r\ntwo {{three}}").unwrap();
+    assert_eq!(template.render_static(), Some("one\r\ntwo {three}"));
+
+    let output = template
+        .format_with_options(&Context::new(), &with_newline(NewlineStyle::Lf))
+        .unwrap();
+    assert_eq!(output, "one\ntwo {three}");
+
+    let options = FormatOptions {
+        max_output_len: 4,
+        ..Default::default()
+    };
+    assert!(
+        template
+            .format_with_options(&Context::new(), &options)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_template_with_variable_is_not_static() {
+    let template = CBTemplate::compile("Hello, {name}!").unwrap();
+    assert!(!template.is_static());
+    assert_eq!(template.render_static(), None);
+
+    // Variables nested in branches count too, as do includes
+    assert!(
+        !CBTemplate::compile("{1 < 2 ? 'Hi {name}'}")
+            .unwrap()
+            .is_static()
+    );
+    assert!(!CBTemplate::compile("{>header}").unwrap().is_static());
+}
+
+#[test]
+fn test_failing_constant_template_is_not_static() {
+    let template = CBTemplate::compile("{'abc':hex}").unwrap();
+
+    assert!(!template.is_static());
+    assert!(template.format(&Context::new()).is_err());
+}
+
+#[test]
+fn test_optimized_template_stays_static() {
+    let mut template = CBTemplate::compile("a{'b'}c{2 > 1 ? 'd'}").unwrap();
+    assert_eq!(template.render_static(), Some("abcd"));
+
+    template.optimize();
+    assert_eq!(template.iter().count(), 1);
+    assert_eq!(template.render_static(), Some("abcd"));
+}
+
+#[test]
+fn test_repeat_resolves_its_pattern_once() {
+    use std::cell::Cell;
+
+    // Counts the lookups of every variable
+    struct Counting(Cell<usize>);
+
+    impl ContextLookup for Counting {
+        fn get(&self, _: &str) -> Option<&Value<'_>> {
+            static VALUE: Value = Value::Str(Cow::Borrowed("<x>"));
+            self.0.set(self.0.get() + 1);
+            Some(&VALUE)
+        }
+    }
+
+    let template = CBTemplate::compile("{item:1000}").unwrap();
+    let ctx = Counting(Cell::new(0));
+
+    assert_eq!(template.format(&ctx).unwrap(), "<x>".repeat(1000));
+    assert_eq!(ctx.0.get(), 1);
+
+    let mut out = Vec::new();
+    template.format_to(&ctx, &mut out).unwrap();
+    assert_eq!(out, "<x>".repeat(1000).into_bytes());
+    assert_eq!(ctx.0.get(), 2);
+}
+
+// ============================================
+// Writer Output Tests
+// ============================================
+
+#[test]
+fn test_format_to_matches_format() {
+    let template =
+        CBTemplate::compile("{s} {i} {f} {b} [{z}] {'-':3} {i > 1 ? 'big' : 'small'} {s:>6} {{x}}")
+            .unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("s", Value::static_str("str"));
+    ctx.insert("i", Value::Int(-42));
+    ctx.insert("f", Value::Float(2.5));
+    ctx.insert("b", Value::Bool(true));
+    ctx.insert("z", Value::Null);
+
+    let mut out = Vec::new();
+    template.format_to(&ctx, &mut out).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        template.format(&ctx).unwrap()
+    );
+}
+
+#[test]
+fn test_format_to_directive_error_keeps_prior_output() {
+    let template = CBTemplate::compile("before {missing} after").unwrap();
+    let ctx = Context::new();
+
+    let mut out = Vec::new();
+    let err = template.format_to(&ctx, &mut out).unwrap_err();
+
+    assert_eq!(out, b"before ");
+    assert_eq!(err.kind(), std::io::ErrorKind::Other);
+    assert!(matches!(
+        err.into_inner().unwrap().downcast_ref::<DirectiveError>(),
+        Some(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_format_to_returns_writer_errors() {
+    struct FullWriter;
+
+    impl std::io::Write for FullWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "disk full",
+            ))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let template = CBTemplate::compile("Hello {name}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("World"));
+
+    let err = template.format_to(&ctx, &mut FullWriter).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+}
+
+#[test]
+fn test_format_to_streams_large_repeats() {
+    /// Counts the bytes written and remembers the largest single write
+    #[derive(Default)]
+    struct CountingSink {
+        total: usize,
+        largest_write: usize,
+    }
+
+    impl std::io::Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.total += buf.len();
+            self.largest_write = self.largest_write.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let template = CBTemplate::compile("[{'abc':n}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(50_000_000));
+
+    let options = FormatOptions {
+        max_output_len: usize::MAX,
+        ..Default::default()
+    };
+
+    let mut sink = CountingSink::default();
+    template
+        .format_to_with_options(&ctx, &mut sink, &options)
+        .unwrap();
+
+    assert_eq!(sink.total, 150_000_002);
+    assert!(sink.largest_write <= 64 * 1024);
+}
+
+#[test]
+fn test_display_streams_into_format_machinery() {
+    use std::fmt::Write;
+
+    let template = CBTemplate::compile("{name} has {n} {n > 1 ? 'items' : 'item'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("Ada"));
+    ctx.insert("n", Value::Int(3));
+
+    let display = template.display(&ctx).unwrap();
+    assert_eq!(format!("<{}>", display), "<Ada has 3 items>");
+    assert_eq!(display.to_string(), template.format(&ctx).unwrap());
+
+    let mut out = String::from("log: ");
+    write!(out, "{} / {}", display, display).unwrap();
+    assert_eq!(out, "log: Ada has 3 items / Ada has 3 items");
+}
+
+#[test]
+fn test_display_in_a_display_impl() {
+    struct Greeting<'a> {
+        template: &'a CBTemplate,
+        ctx: &'a Context,
+    }
+
+    impl std::fmt::Display for Greeting<'_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self.template.display(self.ctx) {
+                Ok(display) => write!(f, "> {}", display),
+                Err(_) => f.write_str("> (unavailable)"),
+            }
+        }
+    }
+
+    let template = CBTemplate::compile("Hi {name}").unwrap();
+    let mut ctx = Context::new();
+
+    assert_eq!(
+        Greeting {
+            template: &template,
+            ctx: &ctx
+        }
+        .to_string(),
+        "> (unavailable)"
+    );
+
+    ctx.insert("name", Value::static_str("Ada"));
+    assert_eq!(
+        Greeting {
+            template: &template,
+            ctx: &ctx
+        }
+        .to_string(),
+        "> Hi Ada"
+    );
+}
+
+#[test]
+fn test_display_reports_errors_up_front() {
+    let template = CBTemplate::compile("before {missing} after").unwrap();
+
+    assert!(matches!(
+        template.display(&Context::new()),
+        Err(DirectiveError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_display_fails_if_the_context_changes() {
+    use std::cell::Cell;
+    use std::fmt::Write;
+
+    // Answers the first lookup only
+    struct Once(Cell<bool>);
+
+    impl ContextLookup for Once {
+        fn get(&self, _: &str) -> Option<&Value<'_>> {
+            static VALUE: Value = Value::Int(1);
+            (!self.0.replace(true)).then_some(&VALUE)
+        }
+    }
+
+    let template = CBTemplate::compile("n = {n}").unwrap();
+    let ctx = Once(Cell::new(false));
+    let display = template.display(&ctx).unwrap();
+
+    let mut out = String::new();
+    assert!(write!(out, "{}", display).is_err());
+}
+
+// ============================================
+// Limit Tests
+// ============================================
+
+#[test]
+fn test_output_limit_stops_huge_repeats() {
+    let template = CBTemplate::compile("{'abc':n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(i64::MAX));
+
+    let options = FormatOptions {
+        max_output_len: 1024,
+        ..Default::default()
+    };
+
+    assert!(FormatOptions::default().max_output_len < usize::MAX);
+    assert!(matches!(
+        template.format_with_options(&ctx, &options),
+        Err(DirectiveError::OutputLimitExceeded { limit: 1024 })
+    ));
+}
+
+#[test]
+fn test_huge_literal_repeat_does_not_reserve_its_estimate() {
+    // The estimate is the full repeated length, far more than can be
+    // allocated, so reserving it up front would abort
+    let template = CBTemplate::compile("{'-':99999999999}").unwrap();
+    assert_eq!(template.estimated_len(), 99999999999);
+
+    let ctx = Context::new();
+    let options = FormatOptions {
+        max_output_len: 1024,
+        ..Default::default()
+    };
+    let limited = |result| {
+        assert!(
+            matches!(result, Err(DirectiveError::OutputLimitExceeded { .. })),
+            "{result:?}"
+        );
+    };
+
+    limited(template.format(&ctx).map(drop));
+    limited(template.format_with_options(&ctx, &options).map(drop));
+    limited(template.format_spanned(&ctx).map(drop).map_err(|e| e.error));
+    limited(template.format_bytes(&ctx).map(drop));
+    limited(template.renderer(options.clone()).render(&ctx).map(drop));
+    limited(
+        template
+            .format_all(std::slice::from_ref(&ctx))
+            .remove(0)
+            .map(drop),
+    );
+
+    let registry = TemplateRegistry::new();
+    limited(template.format_with(&ctx, &registry).map(drop));
+}
+
+#[test]
+fn test_repeat_count_overflow_is_an_error() {
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(i64::MAX));
+
+    // Too large for a usize on any target
+    let template = CBTemplate::compile("{'x':99999999999999999999}").unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::ArithmeticOverflow { expression })
+            if expression == "repeat count 99999999999999999999"
+    ));
+
+    // Fits a usize on 64-bit targets, where only the output limit stops it
+    let template = CBTemplate::compile("{'x':n}").unwrap();
+    assert!(matches!(
+        template.format(&ctx),
+        Err(DirectiveError::OutputLimitExceeded { .. } | DirectiveError::ArithmeticOverflow { .. })
+    ));
+
+    // Negative counts still render nothing
+    ctx.insert("n", Value::Int(i64::MIN));
+    assert_eq!(template.format(&ctx).unwrap(), "");
+}
+
+#[test]
+fn test_output_limit_is_configurable() {
+    let template = CBTemplate::compile("{a}{b}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("1234"));
+    ctx.insert("b", Value::static_str("5678"));
+
+    let options = |max_output_len| FormatOptions {
+        max_output_len,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        template.format_with_options(&ctx, &options(8)).unwrap(),
+        "12345678"
+    );
+    assert!(matches!(
+        template.format_with_options(&ctx, &options(7)),
+        Err(DirectiveError::OutputLimitExceeded { limit: 7 })
+    ));
+
+    let mut out = Vec::new();
+    let err = template
+        .format_to_with_options(&ctx, &mut out, &options(6))
+        .unwrap_err();
+    assert!(matches!(
+        err.into_inner().unwrap().downcast_ref::<DirectiveError>(),
+        Some(DirectiveError::OutputLimitExceeded { limit: 6 })
+    ));
+    assert_eq!(out, b"1234");
+}
+
+#[test]
+fn test_chunks_stop_huge_repeats_at_the_default_limit() {
+    let template = CBTemplate::compile("{'abc':n}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(i64::MAX));
+
+    let mut chunks = template.chunks(&ctx);
+    assert!(matches!(
+        chunks.next(),
+        Some(Err(DirectiveError::OutputLimitExceeded {
+            limit: FormatOptions::DEFAULT_MAX_OUTPUT_LEN
+        }))
+    ));
+}
+
+#[test]
+fn test_directive_limit_counts_top_level_directives() {
+    let template = CBTemplate::compile("{a}-{b}-{c}").unwrap();
+    let mut ctx = Context::new();
+    for name in ["a", "b", "c"] {
+        ctx.insert(name, Value::static_str("x"));
+    }
+
+    let options = |max_directives| FormatOptions {
+        max_directives,
+        ..Default::default()
+    };
+
+    assert_eq!(FormatOptions::default().max_directives, usize::MAX);
+    assert_eq!(
+        template.format_with_options(&ctx, &options(3)).unwrap(),
+        "x-x-x"
+    );
+    assert!(matches!(
+        template.format_with_options(&ctx, &options(2)),
+        Err(DirectiveError::DirectiveLimitExceeded { limit: 2 })
+    ));
+
+    // Rendering stops before the directive past the limit
+    let mut out = Vec::new();
+    assert!(
+        template
+            .format_to_with_options(&ctx, &mut out, &options(2))
+            .is_err()
+    );
+    assert_eq!(out, b"x-x-");
+}
+
+#[test]
+fn test_directive_limit_counts_nested_directives() {
+    let template = CBTemplate::compile("{show ? '{a}{b}' : 'none'}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::static_str("1"));
+    ctx.insert("b", Value::static_str("2"));
+
+    let options = |max_directives| FormatOptions {
+        max_directives,
+        ..Default::default()
+    };
+
+    // The conditional and both directives of its branch
+    ctx.insert("show", Value::Bool(true));
+    assert_eq!(
+        template.format_with_options(&ctx, &options(3)).unwrap(),
+        "12"
+    );
+    assert!(matches!(
+        template.format_with_options(&ctx, &options(2)),
+        Err(DirectiveError::DirectiveLimitExceeded { limit: 2 })
+    ));
+
+    // Branches that aren't taken cost nothing
+    ctx.insert("show", Value::Bool(false));
+    assert_eq!(
+        template.format_with_options(&ctx, &options(1)).unwrap(),
+        "none"
+    );
+}
+
+#[test]
+fn test_directive_limit_counts_included_partials() {
+    let mut registry = TemplateRegistry::new();
+    registry.insert("row", CBTemplate::compile("[{x}]").unwrap());
+
+    let template = CBTemplate::compile("{>row}{>row}{>row}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("x", Value::Int(1));
+
+    let renderer = |max_directives| {
+        let options = FormatOptions {
+            max_directives,
+            ..Default::default()
+        };
+        let mut renderer = template.renderer(options).with_registry(&registry);
+        renderer.render(&ctx).map(str::to_string)
+    };
+
+    // Each include and the directive of its partial
+    assert_eq!(renderer(6).unwrap(), "[1][1][1]");
+    assert!(matches!(
+        renderer(5),
+        Err(DirectiveError::DirectiveLimitExceeded { limit: 5 })
+    ));
+}
+
+#[test]
+fn test_directive_limit_is_per_render() {
+    let template = CBTemplate::compile("{a}{a}").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("a", Value::Int(1));
+
+    let options = FormatOptions {
+        max_directives: 2,
+        ..Default::default()
+    };
+    let mut renderer = template.renderer(options);
+
+    for _ in 0..3 {
+        assert_eq!(renderer.render(&ctx).unwrap(), "11");
+    }
+}
+
+#[test]
+fn test_negative_repeat_count_renders_nothing() {
+    let template = CBTemplate::compile("[{'x':n}]").unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("n", Value::Int(-3));
+
+    assert_eq!(template.format(&ctx).unwrap(), "[]");
+}
+
+#[test]
+fn test_nesting_depth_limit() {
+    let nested = format!("{}x{}", "{a ".repeat(100), "}".repeat(100));
+
+    assert!(matches!(
+        CBTemplate::compile(&nested),
+        Err(TemplateError::NestingTooDeep(
+            CompileOptions::DEFAULT_MAX_DEPTH
+        ))
+    ));
+
+    let options = CompileOptions {
+        max_depth: 200,
+        ..Default::default()
+    };
+    assert!(CBTemplate::compile_with_options(&nested, &options).is_ok());
+
+    let options = CompileOptions {
+        max_depth: 1,
+        ..Default::default()
+    };
+    assert!(CBTemplate::compile_with_options("{a} {b}", &options).is_ok());
+    assert!(matches!(
+        CBTemplate::compile_with_options("{a {b}}", &options),
+        Err(TemplateError::NestingTooDeep(1))
+    ));
+}
+
+/// Builds `{v0 == 0 ? 'r0' : v0 == 1 ? 'r1' : ... : 'rest'}` with `steps`
+/// conditions.
+fn ladder(steps: usize) -> String {
+    let conditions: String = (0..steps)
+        .map(|i| format!("v == {i} ? 'r{i}' : "))
+        .collect();
+    format!("{{{conditions}'rest'}}")
+}
+
+#[test]
+fn test_else_if_ladder_renders_each_step() {
+    let template = CBTemplate::compile(ladder(60)).unwrap();
+
+    for (v, expected) in [(0, "r0"), (37, "r37"), (59, "r59"), (60, "rest")] {
+        let ctx = Context::from([("v", Value::Int(v))]);
+        assert_eq!(template.format(&ctx).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_else_if_ladder_counts_against_depth_limit() {
+    let source = ladder(CompileOptions::DEFAULT_MAX_DEPTH + 1);
+
+    assert!(matches!(
+        CBTemplate::compile(&source),
+        Err(TemplateError::NestingTooDeep(
+            CompileOptions::DEFAULT_MAX_DEPTH
+        ))
+    ));
+
+    let errors = CBTemplate::compile_all_errors(format!("a {source} b")).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0].error,
+        TemplateError::NestingTooDeep(CompileOptions::DEFAULT_MAX_DEPTH)
+    ));
+
+    let options = CompileOptions {
+        max_depth: 200,
+        ..Default::default()
+    };
+    let template = CBTemplate::compile_with_options(ladder(200), &options).unwrap();
+    let ctx = Context::from([("v", Value::Int(199))]);
+    assert_eq!(template.format(&ctx).unwrap(), "r199");
+}
+
+#[test]
+fn test_deep_else_if_ladder_stays_flat() {
+    // The steps of a ladder are kept side by side, so compiling, rendering
+    // and dropping a long one neither recurses per step nor takes quadratic
+    // time, even on a small stack
+    let handle = std::thread::Builder::new()
+        .stack_size(512 * 1024)
+        .spawn(|| {
+            let options = CompileOptions {
+                max_depth: 20_000,
+                ..Default::default()
+            };
+            let template = CBTemplate::compile_with_options(ladder(20_000), &options).unwrap();
+            assert_eq!(template.metrics().conditionals, 20_000);
+
+            for (v, expected) in [(0, "r0"), (19_999, "r19999"), (20_000, "rest")] {
+                let ctx = Context::from([("v", Value::Int(v))]);
+                assert_eq!(template.format(&ctx).unwrap(), expected);
+            }
+
+            drop(template);
+        })
+        .unwrap();
+
+    assert!(handle.join().is_ok());
+}
+
+#[test]
+fn test_fuzzed_nesting_does_not_overflow_stack() {
+    const PIECES: [&str; 16] = [
+        "{", "}", "{{", "a", " ? ", " : ", "'", "(", ")", "|", "[", "]", "\\", "!", " + ", ",",
+    ];
+
+    // Compiling runs on a small stack, so input nesting past the depth limit
+    // would overflow it if it were followed
+    let handle = std::thread::Builder::new()
+        .stack_size(512 * 1024)
+        .spawn(|| {
+            let mut seed: u64 = 0x5eed;
+            let mut next = move || {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 33) as usize
+            };
+
+            for _ in 0..500 {
+                let len = next() % 400;
+                let source: String = (0..len).map(|_| PIECES[next() % PIECES.len()]).collect();
+                let _ = CBTemplate::compile(&source);
+                let _ = CBTemplate::compile_all_errors(&source);
+            }
+
+            for depth in [100, 10_000] {
+                let _ = CBTemplate::compile("{".repeat(depth));
+                let _ = CBTemplate::compile("{a ? ".repeat(depth));
+                let _ = CBTemplate::compile(ladder(depth));
+                let _ =
+                    CBTemplate::compile(format!("{{{}x{}}}", "(".repeat(depth), ")".repeat(depth)));
+            }
+        })
+        .unwrap();
+
+    assert!(handle.join().is_ok());
+}
+
+// ============================================
+// Region Tests
+// ============================================
+
+#[test]
+fn test_region_renders_its_directives() {
+    let template = CBTemplate::compile(
+        "<html>{@region:header}<h1>{title:squish}</h1>{user ? '<p>{user}</p>'}{@end}<main>{body}</main></html>",
+    )
+    .unwrap();
+    let ctx = Context::from([
+        ("title", Value::static_str("  news  ")),
+        ("user", Value::static_str("ada")),
+    ]);
+
+    assert_eq!(
+        template.format_region("header", &ctx).unwrap(),
+        "<h1>news</h1><p>ada</p>"
+    );
+
+    // The markers render nothing in the full output, and `body` is only
+    // needed outside the region
+    let mut full = ctx.clone();
+    full.insert("body", Value::static_str("text"));
+    assert_eq!(
+        template.format(&full).unwrap(),
+        "<html><h1>news</h1><p>ada</p><main>text</main></html>"
+    );
+}
+
+#[test]
+fn test_region_nested_and_named_ends() {
+    let template = CBTemplate::compile(
+        "{@region:page}a{@region:'side bar'}b{x}{@end:'side bar'}c{@end:page}d",
+    )
+    .unwrap();
+    let ctx = Context::from([("x", Value::Int(1))]);
+
+    assert_eq!(template.format_region("page", &ctx).unwrap(), "ab1c");
+    assert_eq!(template.format_region("side bar", &ctx).unwrap(), "b1");
+    assert_eq!(template.format(&ctx).unwrap(), "ab1cd");
+}
+
+#[test]
+fn test_region_unknown_name() {
+    let template = CBTemplate::compile("{@region:header}x{@end} {missing}").unwrap();
+
+    let err = template
+        .format_region("footer", &Context::new())
+        .unwrap_err();
+    assert!(matches!(err, DirectiveError::RegionNotFound { ref name } if name == "footer"));
+    assert_eq!(
+        err.to_string(),
+        "Region 'footer' was not found in the template"
+    );
+
+    // Regions inside sub-templates are not found
+    let template = CBTemplate::compile("{ok ? '{@region:inner}x{@end}'}").unwrap();
+    assert!(matches!(
+        template.format_region("inner", &Context::new()),
+        Err(DirectiveError::RegionNotFound { .. })
+    ));
+}
+
+#[test]
+fn test_region_invalid_structure() {
+    assert!(matches!(
+        CBTemplate::compile("{@region:a}{@region:b}{@end:a}{@end:b}"),
+        Err(TemplateError::InvalidRegion { ref name, .. }) if name == "a"
+    ));
+    assert!(matches!(
+        CBTemplate::compile("{@region:a}x{@end}{@region:a}y{@end}"),
+        Err(TemplateError::InvalidRegion { ref name, reason: "is already defined" }) if name == "a"
+    ));
+    assert!(matches!(
+        CBTemplate::compile("{@region:a}x"),
+        Err(TemplateError::InvalidRegion {
+            reason: "is never closed",
+            ..
+        })
+    ));
+    assert!(matches!(
+        CBTemplate::compile("x{@end}"),
+        Err(TemplateError::UnopenedRegionEnd)
+    ));
+    assert!(matches!(
+        CBTemplate::compile("x{@end:a}"),
+        Err(TemplateError::InvalidRegion { ref name, .. }) if name == "a"
+    ));
+
+    let errors = CBTemplate::compile_all_errors("{@region:a}{@end}{@end} {@region:b}").unwrap_err();
+    let spans: Vec<_> = errors.iter().map(|e| e.span.clone()).collect();
+    assert_eq!(spans, vec![17..23, 24..35]);
+}
+
+#[test]
+fn test_region_trim_blocks() {
+    let template =
+        CBTemplate::compile("<ul>\n{@region:items}\n  <li>{a}</li>\n{@end}\n</ul>\n").unwrap();
+    let ctx = Context::from([("a", Value::Int(1))]);
+
+    assert_eq!(
+        template
+            .format_region_with_options("items", &ctx, &trimmed())
+            .unwrap(),
+        "  <li>1</li>\n"
+    );
+    assert_eq!(
+        template.format_with_options(&ctx, &trimmed()).unwrap(),
+        "<ul>\n  <li>1</li>\n</ul>\n"
+    );
+    assert_eq!(
+        template.format_region("items", &ctx).unwrap(),
+        "\n  <li>1</li>\n"
+    );
+}
+
+// ============================================
+// Serialization Tests
+// ============================================
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip_renders_identically() {
+    let source = "{{{name:*^10}}} {'-':n} {a >= 3 ? 'big {a:#hex}' : 'small'} \
+                  {[tier](gold:Hi {name:trunc(3)})(si*:S)(*:{ok:bool(Y, N)})} {!ok ? 'x'} {>header} {name:reverse | repeat(2)} {a:currency(EUR, parens)} {title !! 'untitled'} {nick | {name} | 'anon'} {ok ? {title} : {name}} {name:md} {title | sh} {a:round(1)} {title:squish} {name:rstrip(ae)} {[a](>=2:big)(< -1:neg)(*:small)} {[status|none](none:N)(*:Y)} {a:percent(1)} {nick ?? title ?? 'anon'} {@region:r}x{@end:r} {[tier](gold:2)(*:{a}) + a > 4 ? 'big'} {@col(300)}|";
+    let template = CBTemplate::compile(source).unwrap();
+
+    let json = serde_json::to_string(&template).unwrap();
+    let restored: CBTemplate = serde_json::from_str(&json).unwrap();
+
+    for (a, tier, ok) in [(5, "gold", true), (1, "silver", false)] {
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::static_str("Ada Lovelace"));
+        ctx.insert("n", Value::Int(3));
+        ctx.insert("a", Value::Int(a));
+        ctx.insert("tier", Value::static_str(tier));
+        ctx.insert("ok", Value::Bool(ok));
+        ctx.insert("title", Value::static_str("T"));
+
+        assert_eq!(
+            restored.format_with(&ctx, &registry()).unwrap(),
+            template.format_with(&ctx, &registry()).unwrap()
+        );
+    }
+
+    assert_eq!(restored.debug_tree(), template.debug_tree());
+    assert_eq!(restored.variables(), template.variables());
+    assert_eq!(restored.estimated_len(), template.estimated_len());
+
+    let spans = |t: &CBTemplate| t.iter().map(|s| (s.kind(), s.span())).collect::<Vec<_>>();
+    assert_eq!(spans(&restored), spans(&template));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_diagnostic_reports_byte_span() {
+    let template = CBTemplate::compile("Hi {name}!").unwrap();
+    let json = serde_json::to_string(&template).unwrap();
+    let restored: CBTemplate = serde_json::from_str(&json).unwrap();
+
+    let error = restored.format_spanned(&Context::new()).unwrap_err();
+    assert_eq!(error.span, 3..9);
+    assert!(error.diagnostic().ends_with("\n --> bytes 3..9"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_keeps_sources_for_missing_policy() {
+    let template = CBTemplate::compile("Hi {name}!").unwrap();
+    let restored: CBTemplate =
+        serde_json::from_str(&serde_json::to_string(&template).unwrap()).unwrap();
+
+    let options = FormatOptions {
+        missing: MissingPolicy::Keep,
+        ..Default::default()
+    };
+    assert_eq!(
+        restored
+            .format_with_options(&Context::new(), &options)
+            .unwrap(),
+        "Hi {name}!"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip_unknown_directives() {
+    let template = CBTemplate::compile("{% raw %}{on ? '{x y}'}").unwrap();
+    let restored: CBTemplate =
+        serde_json::from_str(&serde_json::to_string(&template).unwrap()).unwrap();
+    let mut ctx = Context::new();
+    ctx.insert("on", Value::Bool(true));
+
+    assert_eq!(restored, template);
+    assert_eq!(
+        restored
+            .format_with_options(&ctx, &with_unknown(UnknownPolicy::Keep))
+            .unwrap(),
+        "{% raw %}{x y}"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_rejects_other_delimiters() {
+    let template = CBTemplate::compile("Hi {name}").unwrap();
+    let json = serde_json::to_string(&template).unwrap();
+
+    assert!(serde_json::from_str::<SquareTemplate>(&json).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip_multi_char_delimiters() {
+    let delimiters = Delimiters::new("<%", "%>").unwrap();
+    let template = CBTemplate::with_delimiters("<%<% <% name %>", delimiters).unwrap();
+
+    let json = serde_json::to_string(&template).unwrap();
+    let restored: CBTemplate = serde_json::from_str(&json).unwrap();
+
+    let mut ctx = Context::new();
+    ctx.insert("name", Value::static_str("x"));
+    assert_eq!(restored.format(&ctx).unwrap(), "<% x");
+    assert_eq!(format!("{:?}", restored), r#"Template<"<%", "%>">"#);
+
+    assert!(serde_json::from_str::<ParenTemplate>(&json).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialized_size_hint_is_close_to_binary_length() {
+    let source = "Hello {name}! {vip ? 'Dear {name}' : 'Hi'} {'=':40} \
+                  {price:round(2) | currency(USD)} {[tier](gold:G)(*:-)}";
+    let template = CBTemplate::compile(source).unwrap();
+    let hint = template.serialized_size_hint();
+    let json = serde_json::to_string(&template).unwrap().len();
+
+    // JSON spells out field and variant names, so a binary encoding of the
+    // same tree is several times smaller, but still holds every source
+    assert!(hint * 3 < json, "hint {} vs JSON {}", hint, json);
+    assert!(hint * 10 > json, "hint {} vs JSON {}", hint, json);
+    assert!(hint > source.len());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialized_size_hint_grows_with_template() {
+    let hint = |source: &str| CBTemplate::compile(source).unwrap().serialized_size_hint();
+
+    assert!(hint("") > 0);
+    assert!(hint("{a}") < hint("{a} {b}"));
+    assert!(hint("{a}") < hint("{a:trunc(5)}"));
+    assert_eq!(
+        hint("x".repeat(200).as_str()) - hint("x".repeat(10).as_str()),
+        190 + 1 + 1,
+        "190 more bytes of text, and its length and end take a varint byte more"
+    );
+}